@@ -0,0 +1,216 @@
+//! Matmuls dispatched to a system BLAS library. The backend is picked at build time with the
+//! `openblas`, `mkl` or `accelerate` features. Without any of them, `BlasCompiler` does nothing and
+//! the native matmul kernels are used.
+
+use luminal::prelude::*;
+
+#[cfg(any(feature = "openblas", feature = "mkl", feature = "accelerate"))]
+pub use ops::*;
+
+/// Swap `MatMul2D` and `BatchedMatMul2D` ops for BLAS sgemm calls. Must run after `MatMulCompiler`.
+#[derive(Debug, Default)]
+pub struct BlasCompiler;
+
+#[cfg(not(any(feature = "openblas", feature = "mkl", feature = "accelerate")))]
+impl Compiler for BlasCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {}
+}
+
+#[cfg(any(feature = "openblas", feature = "mkl", feature = "accelerate"))]
+impl Compiler for BlasCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for op in graph.graph.node_weights_mut() {
+            if op.as_any().is::<crate::matmul::MatMul2D>() {
+                *op = Box::new(BlasMatMul2D);
+            } else if op.as_any().is::<crate::matmul::BatchedMatMul2D>() {
+                *op = Box::new(BlasBatchedMatMul2D);
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "openblas", feature = "mkl", feature = "accelerate"))]
+mod ops {
+    use luminal::{
+        op::{InputTensor, Operator},
+        prelude::*,
+    };
+
+    use crate::matmul::{BatchedMatMul2D, MatMul2D};
+
+    const ROW_MAJOR: i32 = 101;
+    const NO_TRANS: i32 = 111;
+    const TRANS: i32 = 112;
+
+    #[cfg_attr(feature = "openblas", link(name = "openblas"))]
+    extern "C" {
+        fn cblas_sgemm(
+            layout: i32,
+            trans_a: i32,
+            trans_b: i32,
+            m: i32,
+            n: i32,
+            k: i32,
+            alpha: f32,
+            a: *const f32,
+            lda: i32,
+            b: *const f32,
+            ldb: i32,
+            beta: f32,
+            c: *mut f32,
+            ldc: i32,
+        );
+    }
+
+    /// Work out the transpose flag and leading dimension of a (rows, cols) matrix with the given
+    /// strides, or None if BLAS can't read it directly (broadcasted or non-unit strides)
+    fn blas_layout(rows: usize, cols: usize, strides: &[BigExpression]) -> Option<(i32, i32)> {
+        let (row_stride, col_stride) = (strides[0].to_usize()?, strides[1].to_usize()?);
+        if (col_stride == 1 || cols == 1) && (row_stride >= cols || rows == 1) {
+            Some((NO_TRANS, row_stride.max(cols).max(1) as i32))
+        } else if (row_stride == 1 || rows == 1) && (col_stride >= rows || cols == 1) {
+            Some((TRANS, col_stride.max(rows).max(1) as i32))
+        } else {
+            None
+        }
+    }
+
+    /// C (m x n) = A (m x k) * B (k x n), all row-major
+    unsafe fn sgemm(
+        (m, k, n): (usize, usize, usize),
+        a: *const f32,
+        (trans_a, lda): (i32, i32),
+        b: *const f32,
+        (trans_b, ldb): (i32, i32),
+        c: &mut [f32],
+    ) {
+        cblas_sgemm(
+            ROW_MAJOR,
+            trans_a,
+            trans_b,
+            m as i32,
+            n as i32,
+            k as i32,
+            1.0,
+            a,
+            lda,
+            b,
+            ldb,
+            0.0,
+            c.as_mut_ptr(),
+            n.max(1) as i32,
+        );
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct BlasMatMul2D;
+
+    impl Operator for BlasMatMul2D {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let (a_shape, b_shape) = (inp[0].1.shape_usize(), inp[1].1.shape_usize());
+            let (m, k, n) = (a_shape[0], a_shape[1], b_shape[1]);
+            let (Some(a_layout), Some(b_layout)) = (
+                blas_layout(m, k, &inp[0].1.strides()),
+                blas_layout(k, n, &inp[1].1.strides()),
+            ) else {
+                return MatMul2D.process(inp);
+            };
+            let a_data = inp[0].0.borrowed().f32_data().unwrap();
+            let b_data = inp[1].0.borrowed().f32_data().unwrap();
+            let mut c = luminal::op::output_buffer(m * n);
+            if m * n > 0 {
+                unsafe {
+                    sgemm(
+                        (m, k, n),
+                        a_data.as_ptr(),
+                        a_layout,
+                        b_data.as_ptr(),
+                        b_layout,
+                        &mut c,
+                    )
+                };
+            }
+            vec![Tensor::new(c)]
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct BlasBatchedMatMul2D;
+
+    // ABCxCD -> ABD
+    impl Operator for BlasBatchedMatMul2D {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let (a_shape, b_shape) = (inp[0].1.shape_usize(), inp[1].1.shape_usize());
+            let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
+            let (batch, m, k, n) = (a_shape[0], a_shape[1], a_shape[2], b_shape[1]);
+            let (Some(batch_stride), Some(a_layout), Some(b_layout)) = (
+                a_strides[0].to_usize(),
+                blas_layout(m, k, &a_strides[1..]),
+                blas_layout(k, n, &b_strides),
+            ) else {
+                return BatchedMatMul2D.process(inp);
+            };
+            let a_data = inp[0].0.borrowed().f32_data().unwrap();
+            let b_data = inp[1].0.borrowed().f32_data().unwrap();
+            let mut c = luminal::op::output_buffer(batch * m * n);
+            if m * n > 0 {
+                for (i, c_mat) in c.chunks_mut(m * n).enumerate() {
+                    unsafe {
+                        sgemm(
+                            (m, k, n),
+                            a_data.as_ptr().add(i * batch_stride),
+                            a_layout,
+                            b_data.as_ptr(),
+                            b_layout,
+                            c_mat,
+                        )
+                    };
+                }
+            }
+            vec![Tensor::new(c)]
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    any(feature = "openblas", feature = "mkl", feature = "accelerate")
+))]
+mod tests {
+    use luminal::prelude::*;
+
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_blas_matmul() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6 * 5);
+        let b_data = random_vec(5 * 7);
+        let batch_data = random_vec(3 * 4 * 5);
+        let a = cx.tensor::<R2<6, 5>>().set(a_data.clone());
+        let b = cx.tensor::<R2<5, 7>>().set(b_data.clone());
+        let batch = cx.tensor::<R3<3, 4, 5>>().set(batch_data.clone());
+        let mut c = (
+            a.matmul(b).retrieve(),
+            a.matmul(a.permute::<_, LAxes2<1, 0>>()).retrieve(),
+            batch.matmul(b).retrieve(),
+        );
+        cx.compile(CPUCompiler::default(), &mut c);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<super::BlasMatMul2D>()));
+        cx.execute();
+
+        let d_dev = dfdx::prelude::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<6>, DConst::<5>));
+        let d_b = d_dev.tensor_from_vec(b_data, (DConst::<5>, DConst::<7>));
+        let d_batch = d_dev.tensor_from_vec(batch_data, (DConst::<3>, DConst::<4>, DConst::<5>));
+        assert_close(&c.0.data(), &d_a.clone().matmul(d_b.clone()).as_vec());
+        assert_close(&c.1.data(), &d_a.clone().matmul(d_a.permute()).as_vec());
+        assert_close(&c.2.data(), &d_batch.matmul(d_b).as_vec());
+    }
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+
+use luminal::{
+    op::{Convolution2D, InputTensor, Operator},
+    prelude::*,
+};
+use rayon::prelude::*;
+
+/// Swap `Convolution2D` ops for an im2col + matmul implementation
+#[derive(Debug, Default)]
+pub struct Conv2DCompiler;
+
+impl Compiler for Conv2DCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for op in graph.graph.node_weights_mut() {
+            if let Some(conv) = op.as_any().downcast_ref::<Convolution2D>() {
+                *op = Box::new(Im2ColConv2D(*conv));
+            }
+        }
+    }
+}
+
+/// Read a tensor's elements in logical order, only copying if it isn't already laid out that way
+fn contiguous<'a>(tensor: &'a InputTensor, shape: &ShapeTracker) -> Cow<'a, [f32]> {
+    let data = tensor.borrowed().f32_data().unwrap();
+    if !shape.is_reshaped() {
+        return data;
+    }
+    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+    let mut stack = vec![];
+    Cow::Owned(
+        (0..shape.n_elements().to_usize().unwrap())
+            .map(|i| {
+                if val.exec_single_var_stack(i, &mut stack) != 0 {
+                    data[ind.exec_single_var_stack(i, &mut stack)]
+                } else {
+                    0.
+                }
+            })
+            .collect(),
+    )
+}
+
+/// A `Convolution2D` done as one matmul per image and group. The input windows are unrolled into the columns of a
+/// (channels_in / groups * kernel_height * kernel_width, out_height * out_width) matrix, which the flattened weight
+/// multiplies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Im2ColConv2D(pub Convolution2D);
+
+impl Operator for Im2ColConv2D {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let conv = self.0;
+        let [batch, ch_in, height, width] = inp[0].1.shape_usize()[..] else {
+            panic!("Convolution2D input should be 4D");
+        };
+        let [ch_out, group_ch_in, kh, kw] = inp[1].1.shape_usize()[..] else {
+            panic!("Convolution2D weight should be 4D");
+        };
+        assert_eq!(
+            ch_in,
+            group_ch_in * conv.groups,
+            "Mismatched input channels"
+        );
+        let group_ch_out = ch_out / conv.groups;
+        let (oh, ow) = conv.output_size((height, width), (kh, kw));
+        let (oh, ow) = (oh.to_usize().unwrap(), ow.to_usize().unwrap());
+        let (k, pixels) = (group_ch_in * kh * kw, oh * ow);
+        let input = contiguous(&inp[0].0, &inp[0].1);
+        let weight = contiguous(&inp[1].0, &inp[1].1);
+
+        let mut out = luminal::op::output_buffer(batch * ch_out * pixels);
+        if out.is_empty() {
+            return vec![Tensor::new(out)];
+        }
+        // Each image and group writes its own block of output channels
+        out.par_chunks_mut(group_ch_out * pixels)
+            .enumerate()
+            .for_each(|(i, out_block)| {
+                let (n, group) = (i / conv.groups, i % conv.groups);
+                let mut cols = vec![0.; k * pixels];
+                for (row, col_row) in cols.chunks_mut(pixels).enumerate() {
+                    let (ci, ky, kx) = (row / (kh * kw), (row / kw) % kh, row % kw);
+                    let channel = &input[(n * ch_in + group * group_ch_in + ci) * height * width..]
+                        [..height * width];
+                    for oy in 0..oh {
+                        let Some(iy) = (oy * conv.stride.0 + ky * (conv.dilation.0 + 1))
+                            .checked_sub(conv.padding.0)
+                            .filter(|iy| *iy < height)
+                        else {
+                            continue;
+                        };
+                        for ox in 0..ow {
+                            if let Some(ix) = (ox * conv.stride.1 + kx * (conv.dilation.1 + 1))
+                                .checked_sub(conv.padding.1)
+                                .filter(|ix| *ix < width)
+                            {
+                                col_row[oy * ow + ox] = channel[iy * width + ix];
+                            }
+                        }
+                    }
+                }
+                unsafe {
+                    matrixmultiply::sgemm(
+                        group_ch_out,
+                        k,
+                        pixels,
+                        1.0,
+                        weight.as_ptr().add(group * group_ch_out * k),
+                        k as isize,
+                        1,
+                        cols.as_ptr(),
+                        pixels as isize,
+                        1,
+                        0.0,
+                        out_block.as_mut_ptr(),
+                        pixels as isize,
+                        1,
+                    );
+                }
+            });
+        vec![Tensor::new(out)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{op::Convolution2D, prelude::*};
+
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_im2col_conv2d() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R4<2, 4, 7, 6>>().set(random_vec(2 * 4 * 7 * 6));
+        let transposed = cx.tensor::<R4<2, 4, 6, 7>>().set(random_vec(2 * 4 * 6 * 7));
+        let weight = cx.tensor::<R4<6, 2, 3, 2>>().set(random_vec(6 * 2 * 3 * 2));
+        let conv = Convolution2D {
+            stride: (2, 1),
+            padding: (1, 2),
+            dilation: (1, 0),
+            groups: 2,
+        };
+        let mut out = (
+            input
+                .conv2d::<_, _, _, _, LConst<3>, LConst<9>>(weight, conv)
+                .retrieve(),
+            // Strided input
+            transposed
+                .permute::<_, LAxes4<0, 1, 3, 2>>()
+                .conv2d::<_, _, _, _, LConst<3>, LConst<9>>(weight, conv)
+                .retrieve(),
+        );
+        cx.execute();
+        let expected = (out.0.data(), out.1.data());
+        cx.drop_tensors(out);
+
+        cx.compile(CPUCompiler::default(), &mut out);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<super::Im2ColConv2D>()));
+        cx.execute();
+        assert_close(&out.0.data(), &expected.0);
+        assert_close(&out.1.data(), &expected.1);
+    }
+}
@@ -1,6 +1,11 @@
 mod binary;
+pub mod blas;
+mod conv;
 mod matmul;
 mod other;
+mod precision;
+mod quantized;
+pub mod simd;
 
 use std::any::Any;
 
@@ -12,15 +17,27 @@ use luminal::{
     prelude::*,
 };
 
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+pub use conv::*;
+pub use precision::*;
+pub use quantized::*;
+
 // Ops and compilers specific to CPU execution
 
 pub type CPUCompiler = (
     matmul::MatMulCompiler,
+    blas::BlasCompiler,
+    Conv2DCompiler,
     binary::SubtractionCompiler,
     binary::EqualCompiler,
     other::ARangeCompiler,
-    binary::GatherCompiler,
+    ElementwiseFusion,
     UnaryFusionCompiler,
+    simd::SimdCompiler,
 );
 
 pub(crate) fn constant(num: f32) -> SelectGraph {
@@ -174,6 +191,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_chained_subtraction() {
+        let mut cx = Graph::new();
+        let (a_data, b_data, c_data) = (random_vec(6), random_vec(6), random_vec(6));
+        let a = cx.tensor::<R1<6>>().set(a_data.clone());
+        let b = cx.tensor::<R1<6>>().set(b_data.clone());
+        let c = cx.tensor::<R1<6>>().set(c_data.clone());
+        // Removed node indexes get reused, so the outer subtraction ends up with lower indexes than the inner one and
+        // is matched after it. Rewriting the inner one removes a node the outer match holds.
+        let placeholders = (0..3).map(|_| cx.constant(0.).id).collect::<Vec<_>>();
+        let inner = a - b;
+        for node in placeholders {
+            cx.graph.remove_node(node);
+        }
+        let mut out = (inner - c).exp().retrieve();
+        cx.compile(CPUCompiler::default(), &mut out);
+        cx.execute();
+
+        let expected = (0..6)
+            .map(|i| (a_data[i] - b_data[i] - c_data[i]).exp())
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_equal() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<6>>().set(vec![1., 2., 3., -1., 0., 5.]);
+        let b = cx.tensor::<R1<6>>().set(vec![1., 3., 2., -1., 0., 4.]);
+        let mut out = a.equals(b).retrieve();
+        cx.compile(CPUCompiler::default(), &mut out);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::binary::Equal>()));
+        cx.execute();
+
+        assert_exact(&out.data(), &[1., 0., 0., 1., 1., 0.]);
+    }
+
+    #[test]
+    fn test_parallel_matmul() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(130 * 200);
+        let b_data = random_vec(200 * 90);
+        let batch_data = random_vec(8 * 30 * 200);
+        let a = cx.tensor::<R2<130, 200>>().set(a_data.clone());
+        let b = cx.tensor::<R2<200, 90>>().set(b_data.clone());
+        let batch = cx.tensor::<R3<8, 30, 200>>().set(batch_data.clone());
+        let mut c = (a.matmul(b).retrieve(), batch.matmul(b).retrieve());
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute_parallel();
+
+        let d_dev = dfdx::prelude::Cpu::default();
+        let d_b = d_dev.tensor_from_vec(b_data, (DConst::<200>, DConst::<90>));
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<130>, DConst::<200>));
+        let d_batch = d_dev.tensor_from_vec(batch_data, (DConst::<8>, DConst::<30>, DConst::<200>));
+        assert_close(&c.0.data(), &d_a.matmul(d_b.clone()).as_vec());
+        assert_close(&c.1.data(), &d_batch.matmul(d_b).as_vec());
+    }
+
     #[test]
     fn test_cpu_matmul_2d_2() {
         let mut cx = Graph::new();
@@ -190,4 +268,46 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_borrowed_inputs() {
+        // The compiled kernels read borrowed data in place
+        let a_data: &'static [f32] = Vec::leak(random_vec(6 * 5));
+        let b_data: &'static [f32] = Vec::leak(random_vec(5 * 4));
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<6, 5>>().set_borrowed(a_data);
+        let b = cx.tensor::<R2<5, 4>>().set_borrowed(b_data);
+        let mut c = ((a.matmul(b) + 1.) * a.matmul(b)).retrieve();
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute();
+
+        let d_dev = dfdx::prelude::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data.to_vec(), (DConst::<6>, DConst::<5>));
+        let d_b = d_dev.tensor_from_vec(b_data.to_vec(), (DConst::<5>, DConst::<4>));
+        let d_c = d_a.matmul(d_b);
+        assert_close(&c.data(), &((d_c.clone() + 1.) * d_c).as_vec());
+    }
+
+    #[test]
+    fn test_half_precision_matmul() {
+        // Half precision weights are converted for each matmul rather than keeping an f32 copy around
+        let (a_data, b_data) = (random_vec(4 * 8), random_vec(8 * 3));
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>().set(a_data.clone());
+        let b = cx
+            .tensor::<R2<8, 3>>()
+            .set(b_data.iter().map(|v| f16::from_f32(*v)).collect::<Vec<_>>())
+            .keep();
+        let mut c = a.matmul(b).retrieve();
+        cx.compile(CPUCompiler::default(), &mut c);
+        for _ in 0..2 {
+            cx.execute();
+            assert_eq!(cx.get_tensor_ref(b.id, 0).unwrap().size_bytes(), 8 * 3 * 2);
+        }
+
+        let d_dev = dfdx::prelude::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<4>, DConst::<8>));
+        let d_b = d_dev.tensor_from_vec(b_data, (DConst::<8>, DConst::<3>));
+        assert_close_precision(&c.data(), &d_a.matmul(d_b).as_vec(), 1e-2);
+    }
 }
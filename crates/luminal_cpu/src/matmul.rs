@@ -2,6 +2,7 @@ use luminal::{
     op::{InputTensor, Mul, Operator, SumReduce},
     prelude::*,
 };
+use rayon::prelude::*;
 
 pub type MatMulCompiler = (MatMul2DCompiler, BatchMatMul2DCompiler);
 
@@ -63,28 +64,48 @@ pub struct MatMul2D;
 
 impl Operator for MatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
+        let (a_shape, b_shape) = (inp[0].1.shape_usize(), inp[1].1.shape_usize());
         let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let mut c = vec![0.; a_shape[0].to_usize().unwrap() * b_shape[1].to_usize().unwrap()];
-        unsafe {
+        let a_data = inp[0].0.borrowed().f32_data().unwrap();
+        let b_data = inp[1].0.borrowed().f32_data().unwrap();
+        let (m, k, n) = (a_shape[0], a_shape[1], b_shape[1]);
+        let mut c = luminal::op::output_buffer(m * n);
+        let (a_row_stride, a_col_stride) = (
+            a_strides[0].to_usize().unwrap(),
+            a_strides[1].to_usize().unwrap() as isize,
+        );
+        let (b_row_stride, b_col_stride) = (
+            b_strides[0].to_usize().unwrap() as isize,
+            b_strides[1].to_usize().unwrap() as isize,
+        );
+        // Large matmuls are split into blocks of rows, one for each thread
+        let rows_per_block = if m * k * n >= PARALLEL_THRESHOLD {
+            m.div_ceil(rayon::current_num_threads()).max(1)
+        } else {
+            m.max(1)
+        };
+        let multiply_block = |(i, c_block): (usize, &mut [f32])| unsafe {
             matrixmultiply::sgemm(
-                a_shape[0].to_usize().unwrap(),
-                a_shape[1].to_usize().unwrap(),
-                b_shape[1].to_usize().unwrap(),
+                c_block.len() / n,
+                k,
+                n,
                 1.0,
-                a_data.as_ptr(),
-                a_strides[0].to_usize().unwrap() as isize,
-                a_strides[1].to_usize().unwrap() as isize,
+                a_data.as_ptr().add(i * rows_per_block * a_row_stride),
+                a_row_stride as isize,
+                a_col_stride,
                 b_data.as_ptr(),
-                b_strides[0].to_usize().unwrap() as isize,
-                b_strides[1].to_usize().unwrap() as isize,
+                b_row_stride,
+                b_col_stride,
                 0.0,
-                c.as_mut_ptr(),
-                b_shape[1].to_usize().unwrap() as isize,
+                c_block.as_mut_ptr(),
+                n as isize,
                 1,
             );
+        };
+        if n > 0 {
+            c.par_chunks_mut(rows_per_block * n)
+                .enumerate()
+                .for_each(multiply_block);
         }
 
         vec![Tensor::new(c)]
@@ -153,34 +174,41 @@ impl Operator for BatchedMatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
         let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let mut c = vec![
-            0.;
+        let a_data = inp[0].0.borrowed().f32_data().unwrap();
+        let b_data = inp[1].0.borrowed().f32_data().unwrap();
+        let mut c = luminal::op::output_buffer(
             a_shape[0].to_usize().unwrap()
                 * a_shape[1].to_usize().unwrap()
-                * b_shape[1].to_usize().unwrap()
-        ];
+                * b_shape[1].to_usize().unwrap(),
+        );
 
         let mat_size = a_shape[1].to_usize().unwrap() * b_shape[1].to_usize().unwrap();
-        for i in 0..a_shape[0].to_usize().unwrap() {
-            unsafe {
-                matrixmultiply::sgemm(
-                    a_shape[1].to_usize().unwrap(),
-                    a_shape[2].to_usize().unwrap(),
-                    b_shape[1].to_usize().unwrap(),
-                    1.0,
-                    a_data.as_ptr().add(i * a_strides[0].to_usize().unwrap()),
-                    a_strides[1].to_usize().unwrap() as isize,
-                    a_strides[2].to_usize().unwrap() as isize,
-                    b_data.as_ptr(),
-                    b_strides[0].to_usize().unwrap() as isize,
-                    b_strides[1].to_usize().unwrap() as isize,
-                    0.0,
-                    c.as_mut_ptr().add(i * mat_size),
-                    b_shape[1].to_usize().unwrap() as isize,
-                    1,
-                );
+        let multiply_batch = |(i, c_mat): (usize, &mut [f32])| unsafe {
+            matrixmultiply::sgemm(
+                a_shape[1].to_usize().unwrap(),
+                a_shape[2].to_usize().unwrap(),
+                b_shape[1].to_usize().unwrap(),
+                1.0,
+                a_data.as_ptr().add(i * a_strides[0].to_usize().unwrap()),
+                a_strides[1].to_usize().unwrap() as isize,
+                a_strides[2].to_usize().unwrap() as isize,
+                b_data.as_ptr(),
+                b_strides[0].to_usize().unwrap() as isize,
+                b_strides[1].to_usize().unwrap() as isize,
+                0.0,
+                c_mat.as_mut_ptr(),
+                b_shape[1].to_usize().unwrap() as isize,
+                1,
+            );
+        };
+        if mat_size > 0 {
+            // Each matrix in the batch is multiplied on its own thread when there's enough work
+            if c.len() * a_shape[2].to_usize().unwrap() >= PARALLEL_THRESHOLD {
+                c.par_chunks_mut(mat_size)
+                    .enumerate()
+                    .for_each(multiply_batch);
+            } else {
+                c.chunks_mut(mat_size).enumerate().for_each(multiply_batch);
             }
         }
 
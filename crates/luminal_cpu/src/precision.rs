@@ -0,0 +1,398 @@
+use std::{any::Any, fmt::Debug};
+
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use luminal::{
+    op::{
+        Add, Constant, ConstantValue, Contiguous, Exp2, F64Data, Function, InputTensor, LessThan,
+        Log2, MaxReduce, Mod, Mul, Operator, ProdReduce, Recip, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// Run the graph's primitive ops in double precision, to get a high precision baseline when debugging numerical drift.
+///
+/// Use this instead of `CPUCompiler`. Loading functions still run in f32, with casts inserted after them, as do ops
+/// with a `PrecisionHint` other than F64. Other ops without an f64 version (like custom ops) make compiling fail,
+/// unless the compiler is made with `allow_f32`, which runs them in f32 with casts around them too. Outputs are stored
+/// as `F64Data`, so read them with `GraphTensor::data_f64`.
+///
+/// Compiling returns the ops other than loading functions left running in f32.
+#[derive(Debug, Default)]
+pub struct F64Compiler {
+    /// Run ops without an f64 version in f32 instead of failing
+    pub allow_f32: bool,
+}
+
+impl F64Compiler {
+    /// Run ops without an f64 version in f32 instead of failing, which loses precision through them
+    pub fn allow_f32() -> Self {
+        Self { allow_f32: true }
+    }
+}
+
+/// The f64 version of a primitive op
+fn f64_op(op: &dyn Any) -> Option<Box<dyn Operator>> {
+    Some(if op.is::<Contiguous>() {
+        Box::new(F64Unary("Contiguous", |a| a))
+    } else if op.is::<Log2>() {
+        Box::new(F64Unary("Log2", f64::log2))
+    } else if op.is::<Exp2>() {
+        Box::new(F64Unary("Exp2", f64::exp2))
+    } else if op.is::<Sin>() {
+        Box::new(F64Unary("Sin", f64::sin))
+    } else if op.is::<Recip>() {
+        Box::new(F64Unary("Recip", f64::recip))
+    } else if op.is::<Sqrt>() {
+        Box::new(F64Unary("Sqrt", f64::sqrt))
+    } else if op.is::<Add>() {
+        Box::new(F64Binary("Add", |a, b| a + b))
+    } else if op.is::<Mul>() {
+        Box::new(F64Binary("Mul", |a, b| a * b))
+    } else if op.is::<Mod>() {
+        Box::new(F64Binary("Mod", |a, b| a % b))
+    } else if op.is::<LessThan>() {
+        Box::new(F64Binary("LessThan", |a, b| (a < b) as i32 as f64))
+    } else if let Some(SumReduce(dim)) = op.downcast_ref() {
+        Box::new(F64Reduce("SumReduce", *dim, 0., |a, b| a + b))
+    } else if let Some(MaxReduce(dim)) = op.downcast_ref() {
+        Box::new(F64Reduce("MaxReduce", *dim, f64::NEG_INFINITY, f64::max))
+    } else if let Some(ProdReduce(dim)) = op.downcast_ref() {
+        Box::new(F64Reduce("ProdReduce", *dim, 1., |a, b| a * b))
+    } else if let Some(Constant(val, dyn_map)) = op.downcast_ref() {
+        Box::new(F64Constant(val.clone(), *dyn_map))
+    } else {
+        return None;
+    })
+}
+
+impl Compiler for F64Compiler {
+    type Output = Vec<NodeIndex>;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) -> Vec<NodeIndex> {
+        let mut replacements = vec![];
+        let (mut unsupported, mut hinted) = (vec![], vec![]);
+        for id in graph.node_indices().collect::<Vec<_>>() {
+            if graph
+                .attribute::<PrecisionHint>(id)
+                .is_some_and(|h| *h != PrecisionHint::F64)
+            {
+                hinted.push(id);
+                continue;
+            }
+            match f64_op(graph.node_weight(id).unwrap().as_any()) {
+                Some(op) => replacements.push((id, op)),
+                None => unsupported.push(id),
+            }
+        }
+        let mut f32_ops = unsupported
+            .iter()
+            .copied()
+            .filter(|id| {
+                !graph.check_node_type::<Function>(*id)
+                    || graph
+                        .edges_directed(*id, Direction::Incoming)
+                        .next()
+                        .is_some()
+            })
+            .collect::<Vec<_>>();
+        if !self.allow_f32 && !f32_ops.is_empty() {
+            panic!(
+                "F64Compiler has no f64 version of {:?}. Use F64Compiler::allow_f32() to run them in f32",
+                f32_ops
+                    .iter()
+                    .map(|id| graph.node_weight(*id).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // Swap primitive ops for f64 ones
+        for (id, op) in replacements {
+            *graph.node_weight_mut(id).unwrap() = op;
+        }
+        f32_ops.extend(&hinted);
+        unsupported.extend(hinted);
+
+        // Cast around ops still running in f32, where they meet ops running in f64
+        let f32_nodes = unsupported.iter().copied().collect::<FxHashSet<_>>();
+        for id in unsupported {
+            for (edge, src, (input_order, output_order, shape)) in graph
+                .edges_directed(id, Direction::Incoming)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.id(), e.source(), d)))
+                .collect::<Vec<_>>()
+            {
+                if f32_nodes.contains(&src) {
+                    continue;
+                }
+                let cast = graph.add_op(ToF32).input(src, output_order, shape).finish();
+                graph.remove_edge(edge);
+                graph.add_edge(
+                    cast,
+                    id,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+            let mut casts = FxHashMap::default();
+            for (edge, dest, (input_order, output_order, shape)) in graph
+                .edges_directed(id, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.id(), e.target(), d)))
+                .collect::<Vec<_>>()
+            {
+                if f32_nodes.contains(&dest) {
+                    continue;
+                }
+                let cast = *casts
+                    .entry(output_order)
+                    .or_insert_with(|| graph.add_op(ToF64).input(id, output_order, shape).finish());
+                graph.remove_edge(edge);
+                graph.add_edge(
+                    cast,
+                    dest,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+            if graph.no_delete.contains(&id) {
+                graph.no_delete.extend(casts.into_values());
+            }
+        }
+        f32_ops
+    }
+}
+
+/// Cast an f32 tensor to f64, leaving its layout untouched
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToF64;
+impl Operator for ToF64 {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let data = inp[0].0.borrowed().f32_data().unwrap();
+        vec![Tensor::new(F64Data(
+            data.iter().map(|i| *i as f64).collect(),
+        ))]
+    }
+}
+
+/// Cast an f64 tensor to f32, leaving its layout untouched
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToF32;
+impl Operator for ToF32 {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let F64Data(data) = inp[0].0.borrowed().downcast_ref().unwrap();
+        vec![Tensor::new(
+            data.iter().map(|i| *i as f32).collect::<Vec<_>>(),
+        )]
+    }
+}
+
+#[derive(Clone)]
+pub struct F64Unary(&'static str, fn(f64) -> f64);
+impl Debug for F64Unary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "F64{}", self.0)
+    }
+}
+impl Operator for F64Unary {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let out_data = (0..inp[0].1.n_elements().to_usize().unwrap())
+            .map(|i| (self.1)(get_index(inp_data, &expr, &mut stack, i)))
+            .collect();
+        vec![Tensor::new(F64Data(out_data))]
+    }
+}
+
+#[derive(Clone)]
+pub struct F64Binary(&'static str, fn(f64, f64) -> f64);
+impl Debug for F64Binary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "F64{}", self.0)
+    }
+}
+impl Operator for F64Binary {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let mut stack = vec![];
+        let out_data = (0..inp[0].1.n_elements().to_usize().unwrap())
+            .map(|i| {
+                (self.1)(
+                    get_index(lhs, &lexpr, &mut stack, i),
+                    get_index(rhs, &rexpr, &mut stack, i),
+                )
+            })
+            .collect();
+        vec![Tensor::new(F64Data(out_data))]
+    }
+}
+
+/// Reduce a dimension, starting from an initial value
+#[derive(Clone)]
+pub struct F64Reduce(&'static str, usize, f64, fn(f64, f64) -> f64);
+impl Debug for F64Reduce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "F64{}({})", self.0, self.1)
+    }
+}
+impl Operator for F64Reduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.1).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.1 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.1];
+        let mut result = vec![self.2; front_size * back_size];
+        let input = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                for k in 0..dim_size {
+                    let orig_index = i * dim_size * back_size + k * back_size + j;
+                    let new_index = i * back_size + j;
+                    result[new_index] = (self.3)(
+                        result[new_index],
+                        get_index(input, &expr, &mut stack, orig_index),
+                    );
+                }
+            }
+        }
+        vec![Tensor::new(F64Data(result))]
+    }
+}
+
+#[derive(Clone)]
+pub struct F64Constant(ConstantValue, *const FxHashMap<char, usize>);
+impl Debug for F64Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "F64Constant({:?})", self.0)
+    }
+}
+impl Operator for F64Constant {
+    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![Tensor::new(F64Data(vec![match &self.0 {
+            ConstantValue::Expression(e) => {
+                e.exec(unsafe { self.1.as_ref().unwrap() }).unwrap() as f64
+            }
+            ConstantValue::Float(f) => *f as f64,
+        }]))]
+    }
+}
+
+fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a [f64] {
+    &tensor
+        .borrowed()
+        .downcast_ref::<F64Data>()
+        .expect("Expected an f64 tensor")
+        .0
+}
+
+fn get_index(
+    data: &[f64],
+    (ind, val): &(BigExpression, BigExpression),
+    stack: &mut Vec<i64>,
+    index: usize,
+) -> f64 {
+    if val.exec_single_var_stack(index, stack) != 0 {
+        data[ind.exec_single_var_stack(index, stack)]
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        op::{InputTensor, Operator},
+        prelude::{Tensor, *},
+    };
+
+    use super::F64Compiler;
+    luminal::test_imports!();
+
+    /// A custom op with no f64 version
+    #[derive(Debug)]
+    struct Double;
+    impl Operator for Double {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let data = inp[0].0.borrowed().f32_data().unwrap();
+            vec![Tensor::new(data.iter().map(|i| i * 2.).collect::<Vec<_>>())]
+        }
+    }
+
+    #[test]
+    fn test_f64_precision_hint() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let b = cx.tensor::<R1<3>>().set(vec![1e-8, 1e-8, 1e-8]);
+        let sum = (a + b).with_attribute(PrecisionHint::F32);
+        let mut c = (sum - a).retrieve();
+        let mut d = ((a + b) - a).retrieve();
+
+        let f32_ops = cx.compile(F64Compiler::default(), (&mut c, &mut d));
+        assert_eq!(f32_ops, [sum.id]);
+        cx.execute();
+        // The hinted add rounds away the small values, the other one keeps them
+        assert_eq!(c.data_f64(), [0.; 3]);
+        assert!(d.data_f64().iter().all(|i| (i - 1e-8).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_f64_custom_op() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let a = (a + 1e-8).exp();
+        let doubled = cx.add_op(Double).input(a.id, 0, a.shape).finish();
+        let mut b = (GraphTensor::<R1<3>>::from_id(doubled, a.shape, &mut cx) + 1.).retrieve();
+
+        // Custom ops aren't silently left in f32
+        let Err(LuminalError::CompileFailed(message)) =
+            cx.try_compile(F64Compiler::default(), &mut b)
+        else {
+            panic!("Compiling a custom op to f64 should fail");
+        };
+        assert!(message.contains("Double"), "{message}");
+        assert!(cx.check_node_type::<Double>(doubled));
+
+        // Unless asked to, with casts around them
+        let f32_ops = cx.compile(F64Compiler::allow_f32(), &mut b);
+        assert_eq!(f32_ops, [doubled]);
+        cx.execute();
+        assert_close(&b.data(), &[1., 2., 3.].map(|i: f32| i.exp() * 2. + 1.));
+    }
+
+    #[test]
+    fn test_f64_precision() {
+        let mut cx = Graph::new();
+        // 1 + 1e-8 isn't representable in f32
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let b = cx.tensor::<R1<3>>().set(vec![1e-8, 1e-8, 1e-8]);
+        let mut c = ((a + b) - a).sum_reduce().retrieve();
+        let m = cx.tensor::<R2<3, 3>>().set(random_vec(9));
+        let mut d = (a.matmul(m) / 7.).retrieve();
+        cx.execute();
+        assert_exact(&c.data(), &[0.]);
+        let d_f32 = d.data();
+        c.drop();
+        d.drop();
+
+        cx.compile(F64Compiler::default(), (&mut c, &mut d));
+        cx.execute();
+        let c = c.data_f64();
+        assert!((c[0] - 3e-8).abs() < 1e-12, "{c:?}");
+        assert_close(&d.data(), &d_f32);
+        assert!(d
+            .data_f64()
+            .iter()
+            .zip(&d_f32)
+            .all(|(a, b)| (a - *b as f64).abs() < 1e-5));
+    }
+}
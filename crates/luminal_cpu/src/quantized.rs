@@ -0,0 +1,880 @@
+use std::any::Any;
+
+use petgraph::{stable_graph::EdgeIndex, visit::EdgeRef, Direction};
+use rayon::prelude::*;
+
+use luminal::{
+    op::{Data, Function, InputTensor, Operator, QuantizedVec, PARALLEL_THRESHOLD},
+    prelude::*,
+};
+
+use crate::matmul::{BatchedMatMul2D, MatMul2D};
+
+/// An int8 weight matrix stored row-major as (out_features, in_features), with a scale and zero point per output channel.
+///
+/// Each weight dequantizes to `scales[row] * (data[row, col] - zero_points[row])`
+#[derive(Debug, Clone)]
+pub struct Int8Matrix {
+    pub data: Vec<i8>,
+    pub scales: Vec<f32>,
+    pub zero_points: Vec<i32>,
+    /// Sum of the quantized values in each row, used to correct for unsigned activations
+    row_sums: Vec<i32>,
+}
+
+impl Int8Matrix {
+    pub fn new(data: Vec<i8>, scales: Vec<f32>, zero_points: Vec<i32>) -> Self {
+        assert_eq!(scales.len(), zero_points.len());
+        assert_eq!(data.len() % scales.len(), 0);
+        let row_sums = data
+            .chunks_exact(data.len() / scales.len())
+            .map(|row| row.iter().map(|i| *i as i32).sum())
+            .collect();
+        Self {
+            data,
+            scales,
+            zero_points,
+            row_sums,
+        }
+    }
+
+    /// Quantize a row-major (out_features, in_features) f32 matrix with asymmetric per-row ranges
+    pub fn quantize(weights: &[f32], rows: usize) -> Self {
+        let cols = weights.len() / rows;
+        let mut data = Vec::with_capacity(weights.len());
+        let (mut scales, mut zero_points) = (Vec::with_capacity(rows), Vec::with_capacity(rows));
+        for row in weights.chunks_exact(cols) {
+            let min = row.iter().fold(0_f32, |a, b| a.min(*b));
+            let max = row.iter().fold(0_f32, |a, b| a.max(*b));
+            let scale = if max > min { (max - min) / 255. } else { 1. };
+            let zero_point = (-128. - min / scale).round().clamp(-128., 127.) as i32;
+            data.extend(
+                row.iter()
+                    .map(|w| ((w / scale).round() as i32 + zero_point).clamp(-128, 127) as i8),
+            );
+            scales.push(scale);
+            zero_points.push(zero_point);
+        }
+        Self::new(data, scales, zero_points)
+    }
+
+    /// Number of output channels
+    pub fn rows(&self) -> usize {
+        self.scales.len()
+    }
+
+    /// Number of input features
+    pub fn cols(&self) -> usize {
+        self.data.len() / self.scales.len()
+    }
+}
+
+impl Data for Int8Matrix {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.data.len()
+            + self.scales.len() * std::mem::size_of::<f32>()
+            + (self.zero_points.len() + self.row_sums.len()) * std::mem::size_of::<i32>()
+    }
+}
+
+/// Multiplies a (..., M, K) float matrix with a (K, N) int8 matrix. Activation rows are dynamically quantized to int8,
+/// accumulated in i32 and dequantized with the row and channel scales in the epilogue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int8MatMul;
+
+impl Operator for Int8MatMul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            !inp[1].1.is_contiguous(),
+            "Weight matrix must be column-major"
+        );
+        let weights = inp[1]
+            .0
+            .borrowed()
+            .downcast_ref::<Int8Matrix>()
+            .expect("Int8MatMul weights must be an Int8Matrix");
+        let a_data = inp[0].0.borrowed().f32_data().unwrap();
+        let (k, n) = (weights.cols(), weights.rows());
+        assert_eq!(
+            inp[0].1.shape().last().unwrap().to_usize().unwrap(),
+            k,
+            "Activation and weight inner dimensions don't match"
+        );
+        let m = inp[0].1.n_elements().to_usize().unwrap() / k;
+        let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+
+        let mut out = luminal::op::output_buffer(m * n);
+        let mut row = vec![0.; k];
+        // Activations are offset by 128 to be unsigned, which is what VNNI expects
+        let mut quantized_row = vec![0_u8; k];
+        for (i, out_row) in out.chunks_exact_mut(n).enumerate() {
+            for (j, a) in row.iter_mut().enumerate() {
+                let index = i * k + j;
+                *a = if val.exec_single_var(index) != 0 {
+                    a_data[ind.exec_single_var(index)]
+                } else {
+                    0.
+                };
+            }
+            let a_max = row.iter().fold(0_f32, |a, b| a.max(b.abs()));
+            if a_max == 0. {
+                continue;
+            }
+            let a_scale = a_max / 127.;
+            let mut a_sum = 0;
+            for (q, a) in quantized_row.iter_mut().zip(&row) {
+                let v = (a / a_scale).round() as i32;
+                a_sum += v;
+                *q = (v + 128) as u8;
+            }
+            for (c, o) in out_row.iter_mut().enumerate() {
+                let w = &weights.data[c * k..(c + 1) * k];
+                let dot = dot_u8_i8(&quantized_row, w) - 128 * weights.row_sums[c];
+                *o = (dot - weights.zero_points[c] * a_sum) as f32 * a_scale * weights.scales[c];
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Dot product of unsigned and signed bytes, accumulated in i32
+fn dot_u8_i8(a: &[u8], b: &[i8]) -> i32 {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avxvnni") {
+        return unsafe { dot_u8_i8_vnni(a, b) };
+    }
+    a.iter().zip(b).map(|(a, b)| *a as i32 * *b as i32).sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,avxvnni")]
+unsafe fn dot_u8_i8_vnni(a: &[u8], b: &[i8]) -> i32 {
+    use std::arch::x86_64::*;
+    let mut acc = _mm256_setzero_si256();
+    let chunks = a.len() / 32;
+    for i in 0..chunks {
+        let va = _mm256_loadu_si256(a.as_ptr().add(i * 32) as *const __m256i);
+        let vb = _mm256_loadu_si256(b.as_ptr().add(i * 32) as *const __m256i);
+        acc = _mm256_dpbusd_avx_epi32(acc, va, vb);
+    }
+    let mut lanes = [0_i32; 8];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+    lanes.iter().sum::<i32>()
+        + a[chunks * 32..]
+            .iter()
+            .zip(&b[chunks * 32..])
+            .map(|(a, b)| *a as i32 * *b as i32)
+            .sum::<i32>()
+}
+
+/// Runs int8 matmuls for the given weights, which should be set to `Int8Matrix` data
+#[derive(Default, Debug)]
+pub struct Int8Compiler(Vec<NodeIndex>);
+
+impl Int8Compiler {
+    pub fn new<To: ToIds>(weights: To) -> Self {
+        Self(weights.to_ids())
+    }
+}
+
+impl Compiler for Int8Compiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let mut weight_ids = self.0.clone();
+        let mut local_remap = remap.to_ids_mut();
+        for w in &mut weight_ids {
+            local_remap.push(w);
+        }
+        // Normal compilation
+        graph.compile(crate::CPUCompiler::default(), &mut local_remap);
+        // Modify ops directly downstream of weights
+        for weight in downstream(&weight_ids, graph) {
+            for (target, (inp_ind, _, _)) in graph
+                .edges_directed(weight, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|i| (e.target(), i)))
+                .collect::<Vec<_>>()
+            {
+                assert_eq!(inp_ind, 1, "Int8 weight {target:?} is the wrong input!");
+                let op_node = graph.node_weight_mut(target).unwrap();
+                if op_node.as_any().is::<MatMul2D>() || op_node.as_any().is::<BatchedMatMul2D>() {
+                    *op_node = Box::new(Int8MatMul);
+                } else {
+                    panic!("Int8 weight {target:?} is an input to a node that isn't a matmul ({op_node:?})");
+                }
+            }
+        }
+    }
+}
+
+/// An int4 weight matrix stored row-major as (out_features, in_features), with a symmetric scale per output channel.
+///
+/// Two weights are packed in each byte (low nibble first) offset by 8, and each dequantizes to `scales[row] * q`
+#[derive(Debug, Clone)]
+pub struct Int4Matrix {
+    pub data: Vec<u8>,
+    pub scales: Vec<f32>,
+    cols: usize,
+}
+
+impl Int4Matrix {
+    /// Quantize a row-major (out_features, in_features) f32 matrix with symmetric per-row ranges
+    pub fn quantize(weights: &[f32], rows: usize) -> Self {
+        let cols = weights.len() / rows;
+        let mut data = Vec::with_capacity(rows * cols.div_ceil(2));
+        let mut scales = Vec::with_capacity(rows);
+        for row in weights.chunks_exact(cols) {
+            let max = row.iter().fold(0_f32, |a, b| a.max(b.abs()));
+            let scale = if max > 0. { max / 7. } else { 1. };
+            let q = row
+                .iter()
+                .map(|w| ((w / scale).round().clamp(-8., 7.) as i8 + 8) as u8)
+                .collect::<Vec<_>>();
+            // Rows start on a byte boundary
+            data.extend(q.chunks(2).map(|p| p[0] | (p.get(1).unwrap_or(&8) << 4)));
+            scales.push(scale);
+        }
+        Self { data, scales, cols }
+    }
+
+    /// Number of output channels
+    pub fn rows(&self) -> usize {
+        self.scales.len()
+    }
+
+    /// Number of input features
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+}
+
+impl Data for Int4Matrix {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.data.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+}
+
+/// Quantized weight matrices that can be dequantized one output channel at a time
+trait QuantizedRows: Sync {
+    fn shape(&self) -> (usize, usize);
+    fn dequantize_row(&self, row: usize, out: &mut [f32]);
+}
+
+impl QuantizedRows for Int8Matrix {
+    fn shape(&self) -> (usize, usize) {
+        (self.rows(), self.cols())
+    }
+    fn dequantize_row(&self, row: usize, out: &mut [f32]) {
+        let (scale, zero_point) = (self.scales[row], self.zero_points[row]);
+        let k = self.cols();
+        for (o, q) in out.iter_mut().zip(&self.data[row * k..(row + 1) * k]) {
+            *o = (*q as i32 - zero_point) as f32 * scale;
+        }
+    }
+}
+
+impl QuantizedRows for Int4Matrix {
+    fn shape(&self) -> (usize, usize) {
+        (self.rows(), self.cols)
+    }
+    fn dequantize_row(&self, row: usize, out: &mut [f32]) {
+        let scale = self.scales[row];
+        let bytes = &self.data[row * self.cols.div_ceil(2)..];
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = (((bytes[i / 2] >> (4 * (i % 2))) & 0xF) as i32 - 8) as f32 * scale;
+        }
+    }
+}
+
+/// Read a tensor as an `Int8Matrix` or `Int4Matrix`
+fn quantized_rows<'a>(tensor: &'a Tensor, op: &str) -> &'a dyn QuantizedRows {
+    if let Some(w) = tensor.downcast_ref::<Int8Matrix>() {
+        w
+    } else if let Some(w) = tensor.downcast_ref::<Int4Matrix>() {
+        w
+    } else {
+        panic!("{op} weights must be an Int8Matrix or Int4Matrix")
+    }
+}
+
+/// Multiplies a (..., M, K) float matrix with a (K, N) quantized weight matrix (an `Int8Matrix` or `Int4Matrix`),
+/// dequantizing one output channel at a time so the full f32 matrix is never materialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DequantMatMul;
+
+impl Operator for DequantMatMul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let weights = quantized_rows(inp[1].0.borrowed(), "DequantMatMul");
+        let (n, k) = weights.shape();
+        assert_eq!(
+            inp[0].1.shape().last().unwrap().to_usize().unwrap(),
+            k,
+            "Activation and weight inner dimensions don't match"
+        );
+        let m = inp[0].1.n_elements().to_usize().unwrap() / k.max(1);
+        let a_data = inp[0].0.borrowed().f32_data().unwrap();
+        let a = if inp[0].1.is_reshaped() {
+            let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+            (0..m * k)
+                .map(|i| {
+                    if val.exec_single_var(i) != 0 {
+                        a_data[ind.exec_single_var(i)]
+                    } else {
+                        0.
+                    }
+                })
+                .collect()
+        } else {
+            a_data[..m * k].to_vec()
+        };
+
+        // Each output channel is dequantized once and dotted with every activation row
+        let channels = (0..n)
+            .into_par_iter()
+            .with_min_len((PARALLEL_THRESHOLD / (m * k).max(1)).max(1))
+            .map_init(
+                || vec![0.; k],
+                |row, c| {
+                    weights.dequantize_row(c, row);
+                    a.chunks_exact(k.max(1))
+                        .map(|a_row| a_row.iter().zip(row.iter()).map(|(a, w)| a * w).sum())
+                        .collect::<Vec<f32>>()
+                },
+            )
+            .collect::<Vec<_>>();
+        let mut out = luminal::op::output_buffer(m * n);
+        for (c, channel) in channels.into_iter().enumerate() {
+            for (i, v) in channel.into_iter().enumerate() {
+                out[i * n + c] = v;
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Dequantizes a whole `Int8Matrix` or `Int4Matrix` back to its f32 (rows, cols) layout, for ops that read a
+/// quantized weight other than through a `DequantMatMul`, like the backward pass of a frozen layer. The f32 matrix
+/// only lives until those ops have run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dequantize;
+
+impl Operator for Dequantize {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let weights = quantized_rows(inp[0].0.borrowed(), "Dequantize");
+        let (n, k) = weights.shape();
+        let mut out = luminal::op::output_buffer(n * k);
+        out.par_chunks_mut(k.max(1))
+            .with_min_len((PARALLEL_THRESHOLD / k.max(1)).max(1))
+            .enumerate()
+            .for_each(|(row, out)| weights.dequantize_row(row, out));
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Quantizes linear weights to int8 or int4 with per-channel scales as they're loaded, and runs the matmuls using them
+/// as `DequantMatMul`s. Should run after `CPUCompiler`.
+///
+/// Weights are expected to be stored as (out_features, in_features) and read transposed. Weights that aren't read that
+/// way by any matmul are left in f32. Other reads of a quantized weight, like the backward pass's when training
+/// adapters on a frozen quantized model, go through a `Dequantize`. Quantized weights can't be trained themselves,
+/// since the optimizer's f32 updates would replace them.
+#[derive(Debug)]
+pub struct WeightQuantCompiler {
+    weights: Vec<NodeIndex>,
+    bits: u8,
+}
+
+impl WeightQuantCompiler {
+    pub fn new<To: ToIds>(weights: To, bits: u8) -> Self {
+        assert!(
+            bits == 8 || bits == 4,
+            "Weights can be quantized to 8 or 4 bits"
+        );
+        Self {
+            weights: weights.to_ids(),
+            bits,
+        }
+    }
+}
+
+impl Compiler for WeightQuantCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for &weight in &self.weights {
+            if !graph.contains_node(weight) {
+                continue;
+            }
+            let consumers = graph
+                .edges_directed(weight, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.target(), d, e.id())))
+                .collect::<Vec<_>>();
+            let is_linear = |(target, (input, _, shape), _): &(
+                NodeIndex,
+                (u8, u8, ShapeTracker),
+                EdgeIndex,
+            )| {
+                let op = graph.node_weight(*target).unwrap().as_any();
+                (op.is::<MatMul2D>() || op.is::<BatchedMatMul2D>())
+                    && *input == 1
+                    && shape.len() == 2
+                    && shape.indexes[..2] == [1, 0]
+                    && !shape.is_padded()
+                    && shape.fake.iter().all(|f| !f)
+            };
+            let (linear, other): (Vec<_>, Vec<_>) = consumers.into_iter().partition(is_linear);
+            let Some((_, (_, _, shape), _)) = linear.first() else {
+                continue;
+            };
+            let (Some(rows), Some(cols)) = (
+                shape.dims[0].exec(&graph.dyn_map),
+                shape.dims[1].exec(&graph.dyn_map),
+            ) else {
+                continue;
+            };
+            let bits = self.bits;
+            let quantize = move |tensor: Tensor| {
+                let data = tensor
+                    .f32_data()
+                    .expect("Only f32 weights can be quantized");
+                if bits == 8 {
+                    Tensor::new(Int8Matrix::quantize(&data, rows))
+                } else {
+                    Tensor::new(Int4Matrix::quantize(&data, rows))
+                }
+            };
+
+            if graph.node_weight(weight).unwrap().as_any().is::<Function>() {
+                // Wrap the loader so data gets quantized as it's loaded
+                let name = format!("{:?} (Int{bits})", graph.node_weight(weight).unwrap());
+                let loader = std::cell::RefCell::new(std::mem::replace(
+                    graph.graph.node_weight_mut(weight).unwrap(),
+                    Box::new(Function(String::new(), Box::new(|_| vec![]))),
+                ));
+                *graph.graph.node_weight_mut(weight).unwrap() = Box::new(Function(
+                    name,
+                    Box::new(move |inp| {
+                        let mut tensors = loader.borrow_mut().process(inp);
+                        let loaded = tensors.remove(0);
+                        tensors.insert(0, quantize(loaded));
+                        tensors
+                    }),
+                ));
+            }
+            // Quantize data that's already been loaded
+            if let Some(tensor) = graph.tensors.remove(&(weight, 0)) {
+                graph.tensors.insert((weight, 0), quantize(tensor));
+            }
+            for (target, _, _) in linear {
+                *graph.graph.node_weight_mut(target).unwrap() = Box::new(DequantMatMul);
+            }
+            if !other.is_empty() {
+                let dequantized = graph
+                    .add_op(Dequantize)
+                    .input(weight, 0, ShapeTracker::new(&[rows.into(), cols.into()]))
+                    .finish();
+                for (target, (input_order, output_order, shape), edge) in other {
+                    graph.graph.remove_edge(edge);
+                    graph.add_edge(
+                        dequantized,
+                        target,
+                        Dependency::Data {
+                            input_order,
+                            output_order,
+                            shape,
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Holds KV caches quantized to 8 or 4 bits between executions, cutting their memory by 4-8x.
+///
+/// Pass in the new cache tensors the graph outputs, which must be made by concatenating new rows onto the previous
+/// cache. The concat is replaced with an op quantizing only the new rows onto the end of the previous quantized cache,
+/// and ops reading the cache dequantize values as they load them, so no f32 copy of the cache is ever made.
+///
+/// This needs to run before other compilers, which can fuse the concat into the ops reading it. Caches are quantized
+/// on the CPU, so GPU backends can't store them quantized.
+#[derive(Debug)]
+pub struct QuantizedKVCacheCompiler {
+    caches: Vec<NodeIndex>,
+    bits: u8,
+    group_size: usize,
+}
+
+impl QuantizedKVCacheCompiler {
+    pub fn new<To: ToIds>(caches: To, bits: u8) -> Self {
+        assert!(
+            bits == 8 || bits == 4,
+            "KV caches can be quantized to 8 or 4 bits"
+        );
+        Self {
+            caches: caches.to_ids(),
+            bits,
+            group_size: 32,
+        }
+    }
+
+    /// Set how many consecutive values in a row share a scale. Defaults to 32.
+    pub fn with_group_size(mut self, group_size: usize) -> Self {
+        self.group_size = group_size;
+        self
+    }
+}
+
+impl Compiler for QuantizedKVCacheCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        for &cache in &self.caches {
+            // Walk back through the copies making the cache contiguous, which the append makes redundant
+            let (mut concat, mut copies) = (cache, vec![]);
+            while graph.check_node_type::<Contiguous>(concat) {
+                let (src, _, shape) = graph.get_sources(concat)[0];
+                if shape.is_reshaped() {
+                    break;
+                }
+                copies.push(concat);
+                concat = src;
+            }
+            let Some((dim, inputs)) = concat_inputs(graph, concat) else {
+                panic!(
+                    "KV cache {cache:?} isn't made by concatenating new rows onto the previous cache. \
+                    QuantizedKVCacheCompiler needs to run before other compilers, which can fuse the concat away."
+                );
+            };
+
+            // Inputs the op can't read as laid out are copied into contiguous buffers first
+            let inputs = inputs.map(|(src, output, shape)| {
+                if shape.is_reshaped() {
+                    let contiguous = graph.add_op(Contiguous).input(src, output, shape).finish();
+                    (contiguous, 0, shape.contiguous())
+                } else {
+                    (src, output, shape)
+                }
+            });
+            let append = graph
+                .add_op(AppendQuantized {
+                    dim,
+                    bits: self.bits,
+                    group_size: self.group_size,
+                })
+                .input(inputs[0].0, inputs[0].1, inputs[0].2)
+                .input(inputs[1].0, inputs[1].1, inputs[1].2)
+                .finish();
+
+            // The appended cache has the concat's layout, so its consumers can read it unchanged
+            for node in [concat].into_iter().chain(copies.iter().copied()) {
+                for (weight, target) in graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .filter(|e| !copies.contains(&e.target()))
+                    .map(|e| (*e.weight(), e.target()))
+                    .collect::<Vec<_>>()
+                {
+                    graph.add_edge(append, target, weight);
+                }
+                remap(node, append, &mut ids, graph);
+                graph.remove_node(node);
+            }
+        }
+    }
+}
+
+/// A node output read through a shape
+type Source = (NodeIndex, u8, ShapeTracker);
+
+/// The dimension a node concatenates along, and its previous cache and new rows inputs, if it's a two input `Concat`
+/// or the padded `Add` made by `concat_along`
+fn concat_inputs(graph: &Graph, node: NodeIndex) -> Option<(usize, [Source; 2])> {
+    let [a, b]: [_; 2] = graph.get_sources(node).try_into().ok()?;
+    if let Some(Concat(dim)) = graph.try_get_op::<Concat>(node) {
+        return Some((*dim, [a, b]));
+    }
+    if !graph.check_node_type::<Add>(node) {
+        return None;
+    }
+    let is_zero = |e: Expression| e.to_usize() == Some(0);
+    for (mut prev, mut new) in [(a, b), (b, a)] {
+        // The previous cache is padded after its rows and the new rows before theirs, along the same dimension
+        let Some(dim) = (0..prev.2.len()).find(|d| {
+            let (p, n) = (prev.2.indexes[*d], new.2.indexes[*d]);
+            is_zero(prev.2.padding[p].0)
+                && !is_zero(prev.2.padding[p].1)
+                && !is_zero(new.2.padding[n].0)
+                && is_zero(new.2.padding[n].1)
+        }) else {
+            continue;
+        };
+        for (_, _, shape) in [&mut prev, &mut new] {
+            let ind = shape.indexes[dim];
+            shape.padding[ind] = (0.into(), 0.into());
+        }
+        if !prev.2.is_padded() && !new.2.is_padded() {
+            return Some((dim, [prev, new]));
+        }
+    }
+    None
+}
+
+/// Appends rows to a quantized cache along a dimension, quantizing only the new rows. The cache is extended in place
+/// when this op owns it, and a cache that isn't quantized yet, like the empty one fed to the first step, is quantized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppendQuantized {
+    dim: usize,
+    bits: u8,
+    group_size: usize,
+}
+
+impl Operator for AppendQuantized {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (rows, rows_shape) = inp.pop().unwrap();
+        let (cache, _) = inp.pop().unwrap();
+        let sh = rows_shape.shape_usize();
+        let slices = sh[..self.dim].iter().product::<usize>();
+        let row_len = sh[self.dim + 1..].iter().product::<usize>();
+        let matches = cache
+            .borrowed()
+            .downcast_ref::<QuantizedVec>()
+            .is_some_and(|q| {
+                q.bits() == self.bits
+                    && q.group_size() == self.group_size
+                    && q.slices() == slices
+                    && q.row_len() == row_len
+            });
+        let mut cache = if matches {
+            // Only copies the cache if we don't own it
+            cache.cloned()
+        } else {
+            let mut quantized = QuantizedVec::new(slices, row_len, self.bits, self.group_size);
+            quantized.append(&cache.borrowed().f32_data().unwrap());
+            Tensor::new(quantized)
+        };
+        cache
+            .downcast_mut::<QuantizedVec>()
+            .unwrap()
+            .append(&rows.borrowed().f32_data().unwrap());
+        vec![cache]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use luminal::prelude::{Axes4, Axis, Const, *};
+
+    use super::{
+        DequantMatMul, Dequantize, Int8Compiler, Int8Matrix, QuantizedKVCacheCompiler,
+        WeightQuantCompiler,
+    };
+    luminal::test_imports!();
+
+    #[test]
+    fn test_quantized_kv_cache() {
+        for bits in [8, 4] {
+            let mut cx = Graph::new();
+            let cache_in = cx.named_tensor::<(Dyn<'p'>, Const<64>)>("Cache");
+            let new = cx.named_tensor::<R2<1, 64>>("New");
+            let mut cache_out = cache_in
+                .concat_along::<(Dyn<'t'>, Const<64>), Axis<0>, _>(new)
+                .contiguous()
+                .keep();
+            let out = cache_out.sum_reduce::<_, Axis<0>>().retrieve();
+            cx.compile(
+                QuantizedKVCacheCompiler::new(cache_out, bits),
+                &mut cache_out,
+            );
+            assert!(!cx.graph.node_weights().any(|op| op.as_any().is::<Add>()));
+
+            let rows = (0..4).map(|_| random_vec(64)).collect::<Vec<_>>();
+            cache_in.set_dyn(vec![], &[0, 64]);
+            let mut expected = vec![0.; 64];
+            for (step, row) in rows.iter().enumerate() {
+                if step > 0 {
+                    // Feed the quantized cache back in
+                    delete_inputs(cache_in, &mut cx);
+                    transfer_data_same_graph(cache_out, cache_in, &mut cx);
+                }
+                new.set(row.clone());
+                cx.set_dyn_dim('p', step);
+                cx.set_dyn_dim('t', step + 1);
+                cx.execute();
+                for (e, v) in expected.iter_mut().zip(row) {
+                    *e += v;
+                }
+                assert_close_precision(&out.data(), &expected, if bits == 8 { 1e-2 } else { 0.2 });
+                out.drop();
+
+                let stored = cx.get_tensor_ref(cache_out.id, 0).unwrap();
+                let cache = stored.downcast_ref::<QuantizedVec>().unwrap();
+                // Each row is quantized once, on its own, and kept as is by later steps
+                let requantized = rows[..=step]
+                    .iter()
+                    .flat_map(|r| QuantizedVec::quantize(r, bits, 32).dequantize())
+                    .collect::<Vec<_>>();
+                assert_eq!(cache.dequantize(), requantized);
+                // Room for rows grows by doubling, so the cache isn't reallocated every step
+                let capacity = (step + 1).next_power_of_two();
+                assert_eq!(
+                    stored.size_bytes(),
+                    capacity * (64 * bits as usize / 8 + 2 * 4)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_heads() {
+        // Appends to the cache of each head, with the new rows permuted like in attention
+        let mut rng = StdRng::seed_from_u64(0);
+        let steps = [3, 1, 1];
+        let rows = steps
+            .iter()
+            .map(|s| random_vec_rng(s * 2 * 8, &mut rng))
+            .collect::<Vec<_>>();
+        let query = random_vec_rng(2 * 8, &mut rng);
+        let mut results = vec![];
+        for quantize in [false, true] {
+            let mut cx = Graph::new();
+            let cache_in = cx.named_tensor::<(Const<1>, Const<2>, Dyn<'p'>, Const<8>)>("Cache");
+            let new = cx.named_tensor::<(Const<1>, Dyn<'s'>, Const<2>, Const<8>)>("New");
+            let q = cx
+                .named_tensor::<R4<1, 2, 1, 8>>("Query")
+                .set(query.clone());
+            let cache = cache_in
+                .concat_along::<(Const<1>, Const<2>, Dyn<'t'>, Const<8>), Axis<2>, _>(
+                    new.permute::<_, Axes4<0, 2, 1, 3>>(),
+                );
+            let out = q.matmul(cache.permute()).retrieve();
+            let mut cache_out = cache.contiguous().keep();
+            if quantize {
+                cx.compile(
+                    (
+                        QuantizedKVCacheCompiler::new(cache_out, 8).with_group_size(4),
+                        crate::CPUCompiler::default(),
+                    ),
+                    &mut cache_out,
+                );
+            } else {
+                cx.compile(crate::CPUCompiler::default(), &mut cache_out);
+            }
+
+            cache_in.set_dyn(vec![], &[1, 2, 0, 8]);
+            let mut prev = 0;
+            for (step, (s, row)) in steps.iter().zip(&rows).enumerate() {
+                if step > 0 {
+                    delete_inputs(cache_in, &mut cx);
+                    transfer_data_same_graph(cache_out, cache_in, &mut cx);
+                }
+                new.set_dyn(row.clone(), &[1, *s, 2, 8]);
+                cx.set_dyn_dim('p', prev);
+                cx.set_dyn_dim('t', prev + s);
+                cx.execute();
+                results.push(out.data());
+                out.drop();
+                prev += s;
+            }
+            let stored = cx.get_tensor_ref(cache_out.id, 0).unwrap();
+            assert_eq!(stored.is::<QuantizedVec>(), quantize);
+        }
+        let (f32_results, quantized_results) = results.split_at(steps.len());
+        for (a, b) in f32_results.iter().zip(quantized_results) {
+            assert_close_precision(a, b, 5e-2);
+        }
+    }
+
+    #[test]
+    fn test_int8_matmul() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let weight_data = random_vec_rng(48 * 70, &mut rng);
+        let inp_data = random_vec_rng(2 * 3 * 70, &mut rng);
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R2<48, 70>>().keep();
+        let inp = cx.tensor::<R3<2, 3, 70>>().set(inp_data.clone());
+        let mut out = inp.matmul(weights.permute()).retrieve();
+
+        cx.tensors.insert(
+            (weights.id, 0),
+            luminal::op::Tensor::new(Int8Matrix::quantize(&weight_data, 48)),
+        );
+        cx.compile(Int8Compiler::new(weights), &mut out);
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_w = d_dev.tensor_from_vec(weight_data, (DConst::<48>, DConst::<70>));
+        let d_inp = d_dev.tensor_from_vec(inp_data, (DConst::<2>, DConst::<3>, DConst::<70>));
+        let d_out = d_inp.matmul(d_w.permute());
+        assert_close_precision(&out.data(), &d_out.as_vec(), 5e-2);
+    }
+
+    #[test]
+    fn test_weight_quantization() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let weight_data = random_vec_rng(48 * 70, &mut rng);
+        let inp_data = random_vec_rng(2 * 3 * 70, &mut rng);
+        let d_dev = Cpu::default();
+        let d_w = d_dev.tensor_from_vec(weight_data.clone(), (DConst::<48>, DConst::<70>));
+        let d_inp =
+            d_dev.tensor_from_vec(inp_data.clone(), (DConst::<2>, DConst::<3>, DConst::<70>));
+        let d_out = d_inp.clone().matmul(d_w.clone().permute());
+        let d_row = d_inp.sum::<_, DAxis<0>>().matmul(d_w.clone().permute());
+        // Like the backward pass of a linear layer, which reads the weights untransposed
+        let grad_data = random_vec_rng(3 * 48, &mut rng);
+        let d_grad = d_dev.tensor_from_vec(grad_data.clone(), (DConst::<3>, DConst::<48>));
+        let d_back = d_grad.matmul(d_w);
+        for (bits, precision) in [(8, 2e-2), (4, 0.3)] {
+            let mut cx = Graph::new();
+            let weights = cx.tensor::<R2<48, 70>>().set(weight_data.clone()).keep();
+            let inp = cx.tensor::<R3<2, 3, 70>>().set(inp_data.clone());
+            let grad = cx.tensor::<R2<3, 48>>().set(grad_data.clone());
+            let mut out = (
+                inp.matmul(weights.permute()).retrieve(),
+                inp.sum_reduce::<_, Axis<0>>()
+                    .matmul(weights.permute())
+                    .retrieve(),
+                grad.matmul(weights).retrieve(),
+            );
+            cx.compile(
+                (
+                    crate::CPUCompiler::default(),
+                    WeightQuantCompiler::new(weights, bits),
+                ),
+                &mut out,
+            );
+            assert_eq!(
+                cx.graph
+                    .node_weights()
+                    .filter(|op| op.as_any().is::<DequantMatMul>())
+                    .count(),
+                2
+            );
+            assert_eq!(
+                cx.graph
+                    .node_weights()
+                    .filter(|op| op.as_any().is::<Dequantize>())
+                    .count(),
+                1
+            );
+            cx.execute();
+
+            // Weights are stored quantized, with a scale per output channel
+            let stored = cx.get_tensor_ref(weights.id, 0).unwrap().size_bytes();
+            assert!(stored < 48 * 70 * bits as usize / 8 * 2, "{stored}");
+            assert_close_precision(&out.0.data(), &d_out.as_vec(), precision);
+            assert_close_precision(&out.1.data(), &d_row.as_vec(), precision * 2.);
+            assert_close_precision(&out.2.data(), &d_back.as_vec(), precision);
+        }
+    }
+}
@@ -0,0 +1,495 @@
+// Vectorized kernels for the hot elementwise ops and reductions, picked at runtime from the widest instruction set the
+// CPU supports. Matmuls already go through matrixmultiply, which does its own runtime detection.
+
+use std::sync::OnceLock;
+
+use itertools::Itertools;
+use rayon::prelude::*;
+
+use luminal::{
+    op::{Add, InputTensor, MaxReduce, Mul, Operator, SumReduce, PARALLEL_THRESHOLD},
+    prelude::*,
+};
+
+use crate::binary::Sub;
+
+/// The instruction set used by the vectorized kernels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimdLevel {
+    Scalar,
+    Avx2,
+    Avx512,
+    Neon,
+}
+
+impl SimdLevel {
+    /// Whether this CPU supports the instruction set
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdLevel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            SimdLevel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+/// The widest instruction set available on this CPU, detected once
+pub fn simd_level() -> SimdLevel {
+    static LEVEL: OnceLock<SimdLevel> = OnceLock::new();
+    *LEVEL.get_or_init(|| {
+        [SimdLevel::Avx512, SimdLevel::Avx2, SimdLevel::Neon]
+            .into_iter()
+            .find(|l| l.is_supported())
+            .unwrap_or(SimdLevel::Scalar)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Mul,
+    Sub,
+}
+
+impl BinaryOp {
+    fn scalar(self, a: f32, b: f32) -> f32 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Sub => a - b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Max,
+}
+
+impl ReduceOp {
+    fn init(self) -> f32 {
+        match self {
+            ReduceOp::Sum => 0.,
+            ReduceOp::Max => f32::NEG_INFINITY,
+        }
+    }
+
+    fn scalar(self, a: f32, b: f32) -> f32 {
+        match self {
+            ReduceOp::Sum => a + b,
+            ReduceOp::Max => a.max(b),
+        }
+    }
+}
+
+/// One side of a binary kernel: a run of values, or one value repeated
+#[derive(Debug, Clone, Copy)]
+pub enum Operand<'a> {
+    Slice(&'a [f32]),
+    Splat(f32),
+}
+
+impl Operand<'_> {
+    fn get(&self, i: usize) -> f32 {
+        match self {
+            Operand::Slice(s) => s[i],
+            Operand::Splat(v) => *v,
+        }
+    }
+}
+
+/// Apply a binary op elementwise, writing `out.len()` results. Slice operands must be at least as long as `out`.
+pub fn binary(level: SimdLevel, op: BinaryOp, a: Operand, b: Operand, out: &mut [f32]) {
+    for operand in [a, b] {
+        if let Operand::Slice(s) = operand {
+            assert!(s.len() >= out.len(), "Operand is shorter than the output");
+        }
+    }
+    match level {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx512 => unsafe { avx512::binary(op, a, b, out) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { avx2::binary(op, a, b, out) },
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { neon::binary(op, a, b, out) },
+        _ => {
+            for (i, o) in out.iter_mut().enumerate() {
+                *o = op.scalar(a.get(i), b.get(i));
+            }
+        }
+    }
+}
+
+/// Reduce a run of values to one
+pub fn reduce(level: SimdLevel, op: ReduceOp, values: &[f32]) -> f32 {
+    match level {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx512 => unsafe { avx512::reduce(op, values) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { avx2::reduce(op, values) },
+        #[cfg(target_arch = "aarch64")]
+        SimdLevel::Neon => unsafe { neon::reduce(op, values) },
+        _ => values.iter().fold(op.init(), |acc, v| op.scalar(acc, *v)),
+    }
+}
+
+/// Kernels for one instruction set, given its vector width and intrinsics
+macro_rules! simd_kernels {
+    (
+        $module:ident, $arch:literal, $feature:literal, $width:literal,
+        $load:ident, $store:ident, $splat:ident, $add:ident, $mul:ident, $sub:ident, $max:ident
+    ) => {
+        #[cfg(target_arch = $arch)]
+        mod $module {
+            #[cfg(target_arch = "aarch64")]
+            use std::arch::aarch64::*;
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::*;
+
+            use super::{BinaryOp, Operand, ReduceOp};
+
+            #[target_feature(enable = $feature)]
+            pub unsafe fn binary(op: BinaryOp, a: Operand, b: Operand, out: &mut [f32]) {
+                macro_rules! lanes {
+                    ($f:ident) => {{
+                        let n = out.len() - out.len() % $width;
+                        let o = out.as_mut_ptr();
+                        match (a, b) {
+                            (Operand::Slice(a), Operand::Slice(b)) => {
+                                for i in (0..n).step_by($width) {
+                                    $store(
+                                        o.add(i),
+                                        $f($load(a.as_ptr().add(i)), $load(b.as_ptr().add(i))),
+                                    );
+                                }
+                            }
+                            (Operand::Slice(a), Operand::Splat(b)) => {
+                                let b = $splat(b);
+                                for i in (0..n).step_by($width) {
+                                    $store(o.add(i), $f($load(a.as_ptr().add(i)), b));
+                                }
+                            }
+                            (Operand::Splat(a), Operand::Slice(b)) => {
+                                let a = $splat(a);
+                                for i in (0..n).step_by($width) {
+                                    $store(o.add(i), $f(a, $load(b.as_ptr().add(i))));
+                                }
+                            }
+                            (Operand::Splat(a), Operand::Splat(b)) => {
+                                let v = $f($splat(a), $splat(b));
+                                for i in (0..n).step_by($width) {
+                                    $store(o.add(i), v);
+                                }
+                            }
+                        }
+                        for i in n..out.len() {
+                            out[i] = op.scalar(a.get(i), b.get(i));
+                        }
+                    }};
+                }
+                match op {
+                    BinaryOp::Add => lanes!($add),
+                    BinaryOp::Mul => lanes!($mul),
+                    BinaryOp::Sub => lanes!($sub),
+                }
+            }
+
+            #[target_feature(enable = $feature)]
+            pub unsafe fn reduce(op: ReduceOp, values: &[f32]) -> f32 {
+                macro_rules! lanes {
+                    ($f:ident) => {{
+                        let n = values.len() - values.len() % $width;
+                        let mut acc = $splat(op.init());
+                        for i in (0..n).step_by($width) {
+                            acc = $f(acc, $load(values.as_ptr().add(i)));
+                        }
+                        let mut lanes = [0.; $width];
+                        $store(lanes.as_mut_ptr(), acc);
+                        lanes
+                            .into_iter()
+                            .chain(values[n..].iter().copied())
+                            .fold(op.init(), |a, b| op.scalar(a, b))
+                    }};
+                }
+                match op {
+                    ReduceOp::Sum => lanes!($add),
+                    ReduceOp::Max => lanes!($max),
+                }
+            }
+        }
+    };
+}
+
+simd_kernels!(
+    avx512,
+    "x86_64",
+    "avx512f",
+    16,
+    _mm512_loadu_ps,
+    _mm512_storeu_ps,
+    _mm512_set1_ps,
+    _mm512_add_ps,
+    _mm512_mul_ps,
+    _mm512_sub_ps,
+    _mm512_max_ps
+);
+simd_kernels!(
+    avx2,
+    "x86_64",
+    "avx2",
+    8,
+    _mm256_loadu_ps,
+    _mm256_storeu_ps,
+    _mm256_set1_ps,
+    _mm256_add_ps,
+    _mm256_mul_ps,
+    _mm256_sub_ps,
+    _mm256_max_ps
+);
+simd_kernels!(
+    neon,
+    "aarch64",
+    "neon",
+    4,
+    vld1q_f32,
+    vst1q_f32,
+    vdupq_n_f32,
+    vaddq_f32,
+    vmulq_f32,
+    vsubq_f32,
+    vmaxq_f32
+);
+
+/// How an input of an elementwise op is laid out relative to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Same layout as the output
+    Contiguous,
+    /// A single value broadcast everywhere
+    Scalar,
+    /// Leading dimensions are broadcast, so the data repeats (like a weight applied to each row)
+    Repeat,
+    /// Only the last dimension is broadcast, so there's one value per row (like a row's norm)
+    PerRow,
+}
+
+impl Layout {
+    /// Find the layout of an input shape, if the vectorized kernels can handle it
+    pub fn of(shape: &ShapeTracker) -> Option<Self> {
+        if shape.is_sliced()
+            || shape.is_padded()
+            || shape.indexes.iter().enumerate().any(|(a, b)| a != *b)
+        {
+            return None;
+        }
+        let fake = shape.fake.iter().copied().collect::<Vec<_>>();
+        let n_fake = fake.iter().filter(|f| **f).count();
+        if n_fake == 0 {
+            Some(Layout::Contiguous)
+        } else if n_fake == fake.len() {
+            Some(Layout::Scalar)
+        } else if fake.iter().take(n_fake).all(|f| *f) {
+            Some(Layout::Repeat)
+        } else if n_fake == 1 && fake[fake.len() - 1] {
+            Some(Layout::PerRow)
+        } else {
+            None
+        }
+    }
+}
+
+/// An elementwise op on inputs with vectorizable layouts
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimdBinary {
+    pub op: BinaryOp,
+    pub layouts: [Layout; 2],
+}
+
+impl Operator for SimdBinary {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1.shape_usize();
+        let mut out = luminal::op::output_buffer(shape.iter().product());
+        let data = [0, 1].map(|i| inp[i].0.borrowed().f32_data().unwrap());
+        let level = simd_level();
+        if self
+            .layouts
+            .iter()
+            .all(|l| matches!(l, Layout::Contiguous | Layout::Scalar))
+        {
+            let [a, b] = [0, 1].map(|i| operand(self.layouts[i], &data[i], 0, 0));
+            binary(level, self.op, a, b, &mut out);
+        } else {
+            // Go row by row, since at least one input is broadcast along rows
+            let row = shape.last().copied().unwrap_or(1).max(1);
+            for (r, out_row) in out.chunks_mut(row).enumerate() {
+                let [a, b] = [0, 1].map(|i| operand(self.layouts[i], &data[i], r, row));
+                binary(level, self.op, a, b, out_row);
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// The operand for row `r` of an input, where rows are `row` long
+fn operand(layout: Layout, data: &[f32], r: usize, row: usize) -> Operand<'_> {
+    match layout {
+        Layout::Contiguous => Operand::Slice(&data[r * row..]),
+        Layout::Scalar => Operand::Splat(data[0]),
+        Layout::Repeat => Operand::Slice(&data[(r * row) % data.len()..]),
+        Layout::PerRow => Operand::Splat(data[r]),
+    }
+}
+
+/// A sum or max reduction along the last dimension of a contiguous input
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimdReduce(pub ReduceOp);
+
+impl Operator for SimdReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = inp[0].1.shape_usize();
+        let row = *shape.last().unwrap();
+        let data = inp[0].0.borrowed().f32_data().unwrap();
+        let mut out = vec![self.0.init(); shape.iter().rev().skip(1).product()];
+        if row > 0 {
+            let level = simd_level();
+            let reduce_row = |(o, r): (&mut f32, &[f32])| *o = reduce(level, self.0, r);
+            if out.len() * row >= PARALLEL_THRESHOLD {
+                out.par_iter_mut()
+                    .zip(data.par_chunks(row))
+                    .for_each(reduce_row);
+            } else {
+                out.iter_mut().zip(data.chunks(row)).for_each(reduce_row);
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Swap elementwise adds, muls and subtracts, and reductions along the last dimension, for vectorized kernels wherever
+/// the input layouts allow it
+#[derive(Debug, Default)]
+pub struct SimdCompiler;
+
+impl Compiler for SimdCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for id in graph.node_indices().collect::<Vec<_>>() {
+            let shapes = graph
+                .edges_directed(id, petgraph::Direction::Incoming)
+                .filter_map(|e| e.weight().as_data())
+                .sorted_by_key(|e| e.0)
+                .map(|e| e.2)
+                .collect::<Vec<_>>();
+            let op = graph.graph.node_weight(id).unwrap().as_any();
+            let binary_op = if op.is::<Add>() {
+                Some(BinaryOp::Add)
+            } else if op.is::<Mul>() {
+                Some(BinaryOp::Mul)
+            } else if op.is::<Sub>() {
+                Some(BinaryOp::Sub)
+            } else {
+                None
+            };
+            let new_op: Box<dyn Operator> = if let Some(op) = binary_op {
+                let (Some(a), Some(b)) = (Layout::of(&shapes[0]), Layout::of(&shapes[1])) else {
+                    continue;
+                };
+                Box::new(SimdBinary {
+                    op,
+                    layouts: [a, b],
+                })
+            } else {
+                let reduce_op = if let Some(SumReduce(dim)) = op.downcast_ref() {
+                    (ReduceOp::Sum, *dim)
+                } else if let Some(MaxReduce(dim)) = op.downcast_ref() {
+                    (ReduceOp::Max, *dim)
+                } else {
+                    continue;
+                };
+                if reduce_op.1 != shapes[0].len() - 1 || shapes[0].is_reshaped() {
+                    continue;
+                }
+                Box::new(SimdReduce(reduce_op.0))
+            };
+            *graph.graph.node_weight_mut(id).unwrap() = new_op;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binary, reduce, BinaryOp, Operand, ReduceOp, SimdLevel};
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_simd_kernels() {
+        let a = random_vec(1029);
+        let b = random_vec(1029);
+        for level in [SimdLevel::Avx512, SimdLevel::Avx2, SimdLevel::Neon]
+            .into_iter()
+            .filter(|l| l.is_supported())
+        {
+            check_level(level, &a, &b);
+        }
+    }
+
+    fn check_level(level: SimdLevel, a: &[f32], b: &[f32]) {
+        for op in [BinaryOp::Add, BinaryOp::Mul, BinaryOp::Sub] {
+            for (x, y) in [
+                (Operand::Slice(a), Operand::Slice(b)),
+                (Operand::Slice(a), Operand::Splat(0.3)),
+                (Operand::Splat(0.3), Operand::Slice(b)),
+            ] {
+                let mut expected = vec![0.; 1029];
+                binary(SimdLevel::Scalar, op, x, y, &mut expected);
+                let mut out = vec![0.; 1029];
+                binary(level, op, x, y, &mut out);
+                assert_exact(&out, &expected);
+            }
+        }
+        for op in [ReduceOp::Sum, ReduceOp::Max] {
+            for n in [0, 3, 16, 1029] {
+                assert_close(
+                    &[reduce(level, op, &a[..n])],
+                    &[reduce(SimdLevel::Scalar, op, &a[..n])],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_compiler() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R2<6, 37>>().set(random_vec(6 * 37));
+        let weight = cx.tensor::<R1<37>>().set(random_vec(37));
+        // RMSNorm and softmax are built from broadcast elementwise ops and reductions along the last dimension
+        let norm = (x.std_norm::<LAxis<1>, _>(1e-5) * weight.expand()).retrieve();
+        let softmax = x.softmax::<LAxis<1>>().retrieve();
+        let sub = (x - x.max_reduce::<_, LAxis<1>>().expand()).retrieve();
+        cx.execute();
+        let expected = (norm.data(), softmax.data(), sub.data());
+
+        let mut outputs = (norm, softmax, sub);
+        cx.compile(CPUCompiler::default(), &mut outputs);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<super::SimdBinary>()));
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<super::SimdReduce>()));
+        cx.execute();
+        assert_close(&outputs.0.data(), &expected.0);
+        assert_close(&outputs.1.data(), &expected.1);
+        assert_close(&outputs.2.data(), &expected.2);
+    }
+}
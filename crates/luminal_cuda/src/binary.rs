@@ -311,12 +311,12 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *weights
 impl<T: CudaFloat> Operator for CudaGather<T> {
     fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = inputs[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let indexes = inputs[0].0.borrowed().f32_data().unwrap();
         let weights = get_buffer_from_tensor::<T>(&inputs[1].0);
 
         let mut indexes_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
         self.device
-            .htod_copy_into(indexes.clone(), &mut indexes_buffer)
+            .htod_copy_into(indexes.to_vec(), &mut indexes_buffer)
             .unwrap();
         let mut out = self
             .device
@@ -368,6 +368,21 @@ impl<T: CudaFloat> Compiler for GatherCompiler<T> {
             if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
                 continue;
             }
+            // Make sure the embedding dimension is the one being reduced, otherwise this is a scatter
+            let dim = graph.get_op::<CudaSumReduce<T>>(s.get(&sum_reduce)).dim;
+            if graph
+                .edges_connecting(s.get(&mul), s.get(&sum_reduce))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2
+                .indexes[dim]
+                != 1
+            {
+                continue;
+            }
             let emb_shape = graph
                 .edges_connecting(s.get(&embeddings), s.get(&mul))
                 .next()
@@ -396,3 +411,211 @@ impl<T: CudaFloat> Compiler for GatherCompiler<T> {
         }
     }
 }
+
+/// Accumulate rows of a matrix into the rows of a larger matrix picked by an index vector (the gradient of a gather).
+///
+/// By default rows are accumulated in parallel with atomics, so the summation order (and therefore rounding) can vary
+/// between runs. The deterministic variant gives each thread a single embedding column and walks the tokens in order.
+#[derive(Clone)]
+pub struct CudaScatterAdd<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub n_embeddings: usize,
+    pub embed_dim: usize,
+    pub deterministic: bool,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaScatterAdd);
+
+impl<T: CudaFloat> CudaScatterAdd<T> {
+    pub fn new(
+        device: Arc<CudaDevice>,
+        n_embeddings: usize,
+        embed_dim: usize,
+        deterministic: bool,
+        grad_shape: ShapeTracker,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let type_name = T::type_name();
+        let (idx, valid) = get_idx_valid_exps(grad_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[grad_shape]);
+        let code = if deterministic {
+            format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *grads, const float *inp, int n_tokens, int embedding_dim{rendered}) {{
+    int y = blockIdx.x * blockDim.x + threadIdx.x;
+    if (y < embedding_dim) {{
+        for (int x = 0; x < n_tokens; x++) {{
+            int idx = x * embedding_dim + y;
+            if (({valid}) != 0) {{
+                int o = (int)inp[x] * embedding_dim + y;
+                out[o] = ({type_name})((float)out[o] + (float)grads[{idx}]);
+            }}
+        }}
+    }}
+}}")
+        } else {
+            format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *grads, const float *inp, int n_tokens, int embedding_dim{rendered}) {{
+    int x = blockIdx.x * blockDim.x + threadIdx.x;
+    int y = blockIdx.y * blockDim.y + threadIdx.y;
+    int idx = x * embedding_dim + y;
+    if (x < n_tokens && y < embedding_dim && ({valid}) != 0) {{
+        atomicAdd(&out[(int)inp[x] * embedding_dim + y], grads[{idx}]);
+    }}
+}}")
+        };
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            n_embeddings,
+            embed_dim,
+            deterministic,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaScatterAdd<T> {
+    fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
+        let indexes = inputs[0].0.borrowed().f32_data().unwrap();
+        let grads = get_buffer_from_tensor::<T>(&inputs[1].0);
+
+        let mut indexes_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
+        self.device
+            .htod_copy_into(indexes.to_vec(), &mut indexes_buffer)
+            .unwrap();
+        let out = self
+            .device
+            .alloc_zeros::<T>(self.n_embeddings * self.embed_dim)
+            .unwrap();
+        let (n_tokens, embed_dim) = (indexes.len() as i32, self.embed_dim as i32);
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            grads.as_kernel_param(),
+            (&indexes_buffer).as_kernel_param(),
+            n_tokens.as_kernel_param(),
+            embed_dim.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let config = if self.deterministic {
+            LaunchConfig::for_num_elems(self.embed_dim as u32)
+        } else {
+            LaunchConfig {
+                grid_dim: (
+                    indexes.len().div_ceil(16) as u32,
+                    self.embed_dim.div_ceil(16) as u32,
+                    1,
+                ),
+                block_dim: (16, 16, 1),
+                shared_mem_bytes: 0,
+            }
+        };
+        unsafe {
+            self.function.clone().launch(config, &mut params).unwrap();
+        }
+
+        vec![Tensor::new(CudaData(out))]
+    }
+}
+
+/// Replace the one-hot matmul produced by differentiating a gather with a scatter-add
+#[derive(Debug, Default)]
+pub struct ScatterAddCompiler<T: CudaFloat> {
+    /// Accumulate in a fixed order so gradients are bitwise reproducible, at the cost of parallelism
+    pub deterministic: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> ScatterAddCompiler<T> {
+    /// A scatter-add compiler that produces bitwise reproducible gradients
+    pub fn deterministic() -> Self {
+        Self {
+            deterministic: true,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Compiler for ScatterAddCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = CudaDevice::new(0).unwrap();
+        let indexes = node();
+        let ind_copy = unary::<CudaCopyToDevice<T>>(indexes.clone());
+        let equal = binary::<CudaEqual<T>>(op::<CudaARange<T>>(), ind_copy.clone());
+        let grad = node();
+        let mul = binary::<CudaMul<T>>(grad.clone(), equal.clone());
+        let sum_reduce = unary::<CudaSumReduce<T>>(mul.clone());
+        let mut s = sum_reduce.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[sum_reduce.id, grad.id, indexes.id]) {
+                continue;
+            }
+            // Make sure the batch dimension is the one being reduced (it may be permuted by autograd)
+            let dim = graph.get_op::<CudaSumReduce<T>>(s.get(&sum_reduce)).dim;
+            let reduce_shape = graph
+                .edges_connecting(s.get(&mul), s.get(&sum_reduce))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            if reduce_shape.indexes[dim] != 0
+                || reduce_shape
+                    .indexes
+                    .iter()
+                    .filter(|i| **i != 0)
+                    .ne([1, 2].iter())
+            {
+                continue;
+            }
+            let mut grad_shape = graph
+                .edges_connecting(s.get(&grad), s.get(&mul))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            // The grad should be broadcasted across the embeddings
+            if grad_shape.len() != 3 || !grad_shape.fake[grad_shape.indexes[1]] {
+                continue;
+            }
+            let sh = grad_shape.shape();
+            let (n_embeddings, embed_dim) = (sh[1].to_usize().unwrap(), sh[2].to_usize().unwrap());
+            grad_shape.remove_dim(1);
+            let index_shape = graph
+                .edges_connecting(s.get(&indexes), s.get(&ind_copy))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            let scatter = graph
+                .add_op(CudaScatterAdd::<T>::new(
+                    dev.clone(),
+                    n_embeddings,
+                    embed_dim,
+                    self.deterministic,
+                    grad_shape,
+                    &graph.dyn_map,
+                ))
+                .input(s.get(&indexes), 0, index_shape)
+                .input(s.get(&grad), 0, grad_shape)
+                .finish();
+            move_outgoing_edge(s.get(&sum_reduce), scatter, graph);
+            remap(s.get(&sum_reduce), scatter, &mut ids, graph);
+            graph.remove_node(s.get(&sum_reduce));
+            s.try_delete();
+        }
+    }
+}
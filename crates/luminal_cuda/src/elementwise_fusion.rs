@@ -760,10 +760,11 @@ mod tests {
             prev_seq: BigExpression,
         ) -> GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)> {
             // Get freqs
-            let freqs =
-                (input.graph().arange::<Const<HEAD_DIM_OVER_2>>() * 2.0) / (HEAD_DIM as f32);
+            let freqs = (unsafe { input.with_graph(|cx| cx.arange::<Const<HEAD_DIM_OVER_2>>()) }
+                * 2.0)
+                / (HEAD_DIM as f32);
             let freqs = 1000000_f32.pow(freqs);
-            let pos = input.graph().arange::<Seq>() + prev_seq;
+            let pos = unsafe { input.with_graph(|cx| cx.arange::<Seq>()) } + prev_seq;
             let emb = pos.expand::<(_, Const<1>), _>().matmul(freqs.expand());
 
             // Split input into evens and odds
@@ -853,7 +854,8 @@ mod tests {
                     .matmul(repeated_keys.permute())
                     .div((HEAD_DIM as f32).sqrt());
 
-                let attention_mask = self.k_proj.graph().triu::<CurSeq>(1) * f16::MIN.to_f32();
+                let attention_mask = unsafe { self.k_proj.with_graph(|cx| cx.triu::<CurSeq>(1)) }
+                    * f16::MIN.to_f32();
                 attention_weights += attention_mask
                     .pad::<(CurSeq, TotSeq), _, _>(&[
                         (0.into(), Expression::from(0)),
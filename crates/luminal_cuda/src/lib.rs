@@ -13,13 +13,18 @@ mod tests;
 
 use itertools::Itertools;
 use luminal_cudarc::{
-    driver::{CudaDevice, CudaFunction, CudaSlice, DeviceRepr},
-    nvrtc::{compile_ptx_with_opts, CompileOptions},
+    driver::{
+        self, sys::CUdevice_attribute, CudaDevice, CudaFunction, CudaSlice, DeviceRepr, DeviceSlice,
+    },
+    nvrtc::{self, compile_ptx_with_opts, CompileOptions, Ptx},
 };
 use prim::CudaConstant;
 use rustc_hash::FxHashMap;
 
-use std::{collections::hash_map::DefaultHasher, ffi::c_void, fmt::Write, hash::Hasher, sync::Arc};
+use std::{
+    collections::hash_map::DefaultHasher, ffi::c_void, fmt::Write, hash::Hasher, path::PathBuf,
+    sync::Arc,
+};
 
 use luminal::{op::InputTensor, prelude::*};
 
@@ -37,6 +42,7 @@ pub type SpecialOpsCompiler<T> = (
     binary::EqualCompiler<T>,
     other::ARangeCompiler<T>,
     binary::GatherCompiler<T>,
+    binary::ScatterAddCompiler<T>,
     unary::CudaExpCompiler<T>,
     unary::CudaCosCompiler<T>,
     unary::MeanReduceCompiler<T>,
@@ -91,6 +97,14 @@ impl<T: CudaFloat> Data for CudaData<T> {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn size_bytes(&self) -> usize {
+        self.0.num_bytes()
+    }
+
+    fn device(&self) -> &'static str {
+        "CUDA"
+    }
 }
 
 impl CudaFloat for f16 {
@@ -231,18 +245,12 @@ fn input_dyn_dims(
 fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFunction {
     let name = format!("kernel_{}", hash(&code));
     code = code.replace("kernel", &name);
-    if !device.has_func(&name, &name) {
+    if device.has_func(&name, &name) {
+        record_kernel_cache_lookup(true);
+    } else {
         device
             .load_ptx(
-                compile_ptx_with_opts(
-                    code,
-                    CompileOptions {
-                        arch: Some("sm_75"),
-                        include_paths: vec!["/usr/local/cuda/include".to_string()],
-                        ..Default::default()
-                    },
-                )
-                .unwrap(),
+                compile_ptx_cached(code, device),
                 &name,
                 &[name.clone().leak()],
             )
@@ -251,6 +259,81 @@ fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFu
     device.get_func(&name, &name).unwrap()
 }
 
+const KERNEL_ARCH: &str = "sm_75";
+const CUDA_INCLUDE_PATH: &str = "/usr/local/cuda/include";
+
+/// Compile kernel source to PTX, reusing PTX cached on disk from previous runs when possible.
+///
+/// Cached kernels are keyed by their source, compile options, the device's compute capability, the driver and NVRTC
+/// versions and this crate's version, so upgrading the toolkit never loads PTX it didn't produce.
+/// The cache lives in `$LUMINAL_KERNEL_CACHE` (or `~/.cache/luminal/cuda_kernels`), and setting that variable to an
+/// empty string disables it.
+fn compile_ptx_cached(code: String, device: &Arc<CudaDevice>) -> Ptx {
+    let cache_file = kernel_cache_dir().map(|dir| {
+        let capability = [
+            CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+            CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR,
+        ]
+        .map(|a| device.attribute(a).unwrap_or_default());
+        let key = hash((
+            &code,
+            KERNEL_ARCH,
+            CUDA_INCLUDE_PATH,
+            capability,
+            toolkit_versions(),
+            env!("CARGO_PKG_VERSION"),
+        ));
+        dir.join(format!("{key:016x}.ptx"))
+    });
+    if let Some(src) = cache_file
+        .as_ref()
+        .and_then(|f| std::fs::read_to_string(f).ok())
+    {
+        record_kernel_cache_lookup(true);
+        return Ptx::from_src(src);
+    }
+    record_kernel_cache_lookup(false);
+    let ptx = compile_ptx_with_opts(
+        code,
+        CompileOptions {
+            arch: Some(KERNEL_ARCH),
+            include_paths: vec![CUDA_INCLUDE_PATH.to_string()],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    if let Some(file) = cache_file {
+        // Failing to write the cache shouldn't stop execution. Write to a temp file first so concurrent runs never read a partial kernel
+        let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+        if std::fs::create_dir_all(file.parent().unwrap()).is_ok()
+            && std::fs::write(&tmp, ptx.to_src()).is_ok()
+        {
+            let _ = std::fs::rename(tmp, file);
+        }
+    }
+    ptx
+}
+
+/// The CUDA driver version and the NVRTC (major, minor) version, or 0s if they can't be queried
+fn toolkit_versions() -> (i32, (i32, i32)) {
+    let (mut driver, mut major, mut minor) = (0, 0, 0);
+    unsafe {
+        driver::sys::cuDriverGetVersion(&mut driver);
+        nvrtc::sys::nvrtcVersion(&mut major, &mut minor);
+    }
+    (driver, (major, minor))
+}
+
+fn kernel_cache_dir() -> Option<PathBuf> {
+    match std::env::var("LUMINAL_KERNEL_CACHE") {
+        Ok(dir) if dir.is_empty() => None,
+        Ok(dir) => Some(dir.into()),
+        Err(_) => std::env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".cache/luminal/cuda_kernels")),
+    }
+}
+
 #[macro_export]
 macro_rules! debug_type {
     ($t: ident) => {
@@ -1,15 +1,19 @@
 use std::{marker::PhantomData, sync::Arc};
 
 use itertools::Itertools;
-use luminal::prelude::{petgraph::visit::EdgeRef, *};
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, LaunchAsync, LaunchConfig};
+use luminal::{
+    op::Sample,
+    prelude::{petgraph::visit::EdgeRef, *},
+};
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use rand::{rngs::StdRng, Rng};
 use rustc_hash::FxHashMap;
 
 use crate::{
     binary::CudaSub,
-    compile_and_load_kernel, constant,
+    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
     prim::{CudaAdd, CudaContiguous, CudaCopyFromDevice, CudaCopyToDevice, CudaSumReduce},
-    CudaData, CudaFloat,
+    render_dyn_dim_inputs, CudaData, CudaFloat,
 };
 
 #[derive(Clone)]
@@ -70,6 +74,303 @@ impl<T: CudaFloat> Operator for CudaARange<T> {
     }
 }
 
+/// Write rows into a buffer at a runtime offset. The buffer is updated in place when this op owns it.
+#[derive(Clone)]
+pub struct CudaAppendAt<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    dim: usize,
+    offset: BigExpression,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaAppendAt);
+
+impl<T: CudaFloat> CudaAppendAt<T> {
+    pub fn new(
+        dim: usize,
+        offset: BigExpression,
+        rows_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx, valid) = get_idx_valid_exps(rows_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[rows_shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *buffer, const {type_name} *rows, int n_elements, int rows_dim, int buffer_dim, int back_size, int offset{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < n_elements) {{
+        int a = idx / (rows_dim * back_size);
+        int b = (idx / back_size) % rows_dim;
+        int c = idx % back_size;
+        buffer[a * buffer_dim * back_size + (b + offset) * back_size + c] = (({valid}) != 0) ? rows[{idx}] : ({type_name})0.0;
+    }}
+}}"
+        );
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            dim,
+            offset,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaAppendAt<T> {
+    fn process(&mut self, mut tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (rows, rows_shape) = tensors.pop().unwrap();
+        let (buffer, buffer_shape) = tensors.pop().unwrap();
+        let offset = self
+            .offset
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        let buffer_sh = buffer_shape.shape_usize();
+        let rows_sh = rows_shape.shape_usize();
+        let back_size = buffer_sh.iter().skip(self.dim + 1).product::<usize>();
+        let (buffer_dim, rows_dim) = (buffer_sh[self.dim], rows_sh[self.dim]);
+        assert!(
+            offset + rows_dim <= buffer_dim,
+            "Writing {rows_dim} rows at offset {offset} overflows a buffer of {buffer_dim} rows"
+        );
+        let n_elements = rows_shape.n_elements().to_usize().unwrap();
+
+        // Only copies the buffer if we don't own it
+        let mut buffer = buffer.cloned();
+        let rows = get_buffer_from_tensor::<T>(&rows);
+        let out = &mut buffer.downcast_mut::<CudaData<T>>().unwrap().0;
+        let (n_elements_i, rows_dim, buffer_dim, back_size, offset) = (
+            n_elements as i32,
+            rows_dim as i32,
+            buffer_dim as i32,
+            back_size as i32,
+            offset as i32,
+        );
+        let mut params = vec![
+            out.as_kernel_param(),
+            rows.as_kernel_param(),
+            n_elements_i.as_kernel_param(),
+            rows_dim.as_kernel_param(),
+            buffer_dim.as_kernel_param(),
+            back_size.as_kernel_param(),
+            offset.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch(LaunchConfig::for_num_elems(n_elements as u32), &mut params)
+                .unwrap();
+        }
+        vec![buffer]
+    }
+}
+
+/// Fused temperature scaling, top-k / top-p filtering and multinomial sampling. One block handles one row of logits,
+/// so only the sampled token ids are written out.
+///
+/// Ids are always written as f32s, which hold every id below 2^24 exactly, where half precision would round ids above
+/// 2048. They're copied back to the host, like the ids the CPU op outputs.
+#[derive(Clone)]
+pub struct CudaSample<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    temperature: f32,
+    top_k: usize,
+    top_p: f32,
+    rng: StdRng,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaSample);
+
+const SAMPLE_BLOCK_SIZE: u32 = 256;
+
+impl<T: CudaFloat> CudaSample<T> {
+    pub fn new(
+        sample: &Sample,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+__device__ float block_sum(float v, float *buf) {{
+    buf[threadIdx.x] = v;
+    __syncthreads();
+    for (int s = blockDim.x / 2; s > 0; s >>= 1) {{
+        if (threadIdx.x < s) buf[threadIdx.x] += buf[threadIdx.x + s];
+        __syncthreads();
+    }}
+    float r = buf[0];
+    __syncthreads();
+    return r;
+}}
+__device__ float block_max(float v, float *buf) {{
+    buf[threadIdx.x] = v;
+    __syncthreads();
+    for (int s = blockDim.x / 2; s > 0; s >>= 1) {{
+        if (threadIdx.x < s) buf[threadIdx.x] = max(buf[threadIdx.x], buf[threadIdx.x + s]);
+        __syncthreads();
+    }}
+    float r = buf[0];
+    __syncthreads();
+    return r;
+}}
+__device__ float logit(const {type_name} *inp, int row, int vocab, int j, float inv_temp{rendered}) {{
+    int idx = row * vocab + j;
+    return (({valid}) != 0) ? (float)inp[{idx}] * inv_temp : 0.0f;
+}}
+extern \"C\" __global__ void kernel(float *out, const {type_name} *inp, const float *uniforms, const int vocab, const float inv_temp, const int top_k, const float top_p{rendered}) {{
+    __shared__ float buf[{SAMPLE_BLOCK_SIZE}];
+    __shared__ int chosen_chunk;
+    __shared__ float chosen_offset;
+    int row = blockIdx.x;
+    int chunk = (vocab + blockDim.x - 1) / blockDim.x;
+    int start = threadIdx.x * chunk;
+    int end = min(start + chunk, vocab);
+
+    float local = -__int_as_float(0x7f800000);
+    for (int j = start; j < end; j++) local = max(local, logit(inp, row, vocab, j, inv_temp{dyn_args}));
+    float mx = block_max(local, buf);
+    #define PROB(j) __expf(logit(inp, row, vocab, j, inv_temp{dyn_args}) - mx)
+
+    // Top-k: find the largest probability threshold that keeps at least top_k entries
+    float thresh = 0.0f;
+    if (top_k > 0 && top_k < vocab) {{
+        float lo = 0.0f, hi = 1.0f;
+        for (int it = 0; it < 32; it++) {{
+            float mid = 0.5f * (lo + hi);
+            float count = 0.0f;
+            for (int j = start; j < end; j++) count += PROB(j) >= mid ? 1.0f : 0.0f;
+            if (block_sum(count, buf) >= (float)top_k) lo = mid; else hi = mid;
+        }}
+        thresh = lo;
+    }}
+
+    // Top-p: raise the threshold while the kept mass stays above top_p of the top-k mass
+    float kept = 0.0f;
+    for (int j = start; j < end; j++) {{ float p = PROB(j); kept += p >= thresh ? p : 0.0f; }}
+    float mass = block_sum(kept, buf);
+    if (top_p < 1.0f) {{
+        float target = top_p * mass;
+        float lo = thresh, hi = 1.0f;
+        for (int it = 0; it < 32; it++) {{
+            float mid = 0.5f * (lo + hi);
+            float m = 0.0f;
+            for (int j = start; j < end; j++) {{ float p = PROB(j); m += p >= mid ? p : 0.0f; }}
+            if (block_sum(m, buf) >= target) lo = mid; else hi = mid;
+        }}
+        thresh = lo;
+        kept = 0.0f;
+        for (int j = start; j < end; j++) {{ float p = PROB(j); kept += p >= thresh ? p : 0.0f; }}
+        mass = block_sum(kept, buf);
+    }}
+
+    // Multinomial draw: find the chunk holding the target mass, then walk it
+    buf[threadIdx.x] = kept;
+    __syncthreads();
+    if (threadIdx.x == 0) {{
+        float target = uniforms[row] * mass;
+        chosen_chunk = blockDim.x - 1;
+        chosen_offset = 0.0f;
+        for (int t = 0; t < blockDim.x; t++) {{
+            if (target < buf[t]) {{ chosen_chunk = t; chosen_offset = target; break; }}
+            target -= buf[t];
+        }}
+        int s = chosen_chunk * chunk;
+        int e = min(s + chunk, vocab);
+        int token = -1;
+        float remaining = chosen_offset;
+        for (int j = s; j < e; j++) {{
+            float p = PROB(j);
+            if (p < thresh) continue;
+            token = j;
+            if (remaining < p) break;
+            remaining -= p;
+        }}
+        if (token < 0) {{
+            // Float rounding pushed us past the last kept entry, fall back to the most likely token
+            for (int j = 0; j < vocab; j++) if (PROB(j) >= 1.0f) {{ token = j; break; }}
+        }}
+        out[row] = (float)token;
+    }}
+}}",
+            dyn_args = dyn_symbols
+                .iter()
+                .fold(String::default(), |acc, c| format!("{acc}, {c}")),
+        );
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            temperature: sample.temperature,
+            top_k: sample.top_k,
+            top_p: sample.top_p,
+            rng: sample.rng.clone(),
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSample<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = tensors[0].1.shape_usize();
+        let vocab = *sh.last().unwrap();
+        let rows = sh.iter().rev().skip(1).product::<usize>();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        // Temperature 0 is greedy sampling, which is the same as keeping only the top token
+        let (inv_temp, top_k) = if self.temperature <= 0. {
+            (1., 1)
+        } else {
+            (1. / self.temperature, self.top_k)
+        };
+        let uniforms = self
+            .device
+            .htod_sync_copy(&(0..rows).map(|_| self.rng.gen::<f32>()).collect::<Vec<_>>())
+            .unwrap();
+        let out = self.device.alloc_zeros::<f32>(rows).unwrap();
+        let vocab = vocab as i32;
+        let top_k = top_k as i32;
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            (&uniforms).as_kernel_param(),
+            vocab.as_kernel_param(),
+            inv_temp.as_kernel_param(),
+            top_k.as_kernel_param(),
+            self.top_p.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch(
+                    LaunchConfig {
+                        grid_dim: (rows as u32, 1, 1),
+                        block_dim: (SAMPLE_BLOCK_SIZE, 1, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    &mut params,
+                )
+                .unwrap();
+        }
+        vec![Tensor::new(self.device.dtoh_sync_copy(&out).unwrap())]
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ARangeCompiler<T: CudaFloat>(PhantomData<T>);
 
@@ -1,4 +1,8 @@
-use crate::{compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims, CudaData, CudaFloat};
+use crate::{
+    compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims,
+    other::{CudaAppendAt, CudaSample},
+    CudaData, CudaFloat,
+};
 
 use super::{get_idx_valid_exps, render_dyn_dim_inputs};
 use itertools::Itertools;
@@ -34,7 +38,7 @@ impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
             // Already on device
             return vec![inp.pop().unwrap().0.cloned()];
         }
-        let cpu_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let cpu_data = inp[0].0.borrowed().f32_data().unwrap();
         let vec = cpu_data
             .iter()
             .copied()
@@ -1125,6 +1129,21 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                     dev.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(AppendAt(dim, offset, _)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(CudaAppendAt::<T>::new(
+                    *dim,
+                    offset.clone(),
+                    shapes[1],
+                    dev.clone(),
+                    &graph.dyn_map,
+                ));
+            } else if let Some(sample) = op_ref.as_any().downcast_ref::<Sample>() {
+                *op_ref = Box::new(CudaSample::<T>::new(
+                    sample,
+                    shapes[0],
+                    dev.clone(),
+                    &graph.dyn_map,
+                ));
             }
         }
     }
@@ -1,10 +1,12 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{
+    CudaDevice, CudaFunction, CudaSlice, DeviceRepr, DeviceSlice, LaunchAsync, LaunchConfig,
+};
 use petgraph::visit::EdgeRef;
 
 use luminal::{
-    op::{InputTensor, Operator},
+    op::{Data, InputTensor, Operator},
     prelude::*,
 };
 
@@ -205,10 +207,10 @@ extern \"C\" __global__ void kernel(const float* inp, const block_q8_0* weights,
 impl<T: CudaFloat> Operator for QuantizedGather<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Setup buffers
-        let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let indexes = tensors[0].0.borrowed().f32_data().unwrap();
         let mut index_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
         self.device
-            .htod_copy_into(indexes.clone(), &mut index_buffer)
+            .htod_copy_into(indexes.to_vec(), &mut index_buffer)
             .unwrap();
 
         let out = unsafe {
@@ -290,6 +292,285 @@ impl<T: CudaFloat + Default> Compiler for CudaQuantizedCompiler<T> {
     }
 }
 
+/// An int8 weight matrix on the device stored row-major as (out_features, in_features), with a scale and zero point
+/// per output channel. Each weight dequantizes to `scales[row] * (data[row, col] - zero_points[row])`
+pub struct CudaInt8Matrix {
+    pub data: CudaSlice<i8>,
+    pub scales: CudaSlice<f32>,
+    pub zero_points: CudaSlice<i32>,
+    pub rows: usize,
+    pub cols: usize,
+}
+
+impl CudaInt8Matrix {
+    pub fn new(
+        device: &Arc<CudaDevice>,
+        data: Vec<i8>,
+        scales: Vec<f32>,
+        zero_points: Vec<i32>,
+    ) -> Self {
+        assert_eq!(scales.len(), zero_points.len());
+        assert_eq!(data.len() % scales.len(), 0);
+        Self {
+            rows: scales.len(),
+            cols: data.len() / scales.len(),
+            data: device.htod_sync_copy(&data).unwrap(),
+            scales: device.htod_sync_copy(&scales).unwrap(),
+            zero_points: device.htod_sync_copy(&zero_points).unwrap(),
+        }
+    }
+}
+
+impl Clone for CudaInt8Matrix {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.try_clone().unwrap(),
+            scales: self.scales.try_clone().unwrap(),
+            zero_points: self.zero_points.try_clone().unwrap(),
+            rows: self.rows,
+            cols: self.cols,
+        }
+    }
+}
+
+impl std::fmt::Debug for CudaInt8Matrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaInt8Matrix({}x{})", self.rows, self.cols)
+    }
+}
+
+impl Data for CudaInt8Matrix {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.data.num_bytes() + self.scales.num_bytes() + self.zero_points.num_bytes()
+    }
+    fn device(&self) -> &'static str {
+        "CUDA"
+    }
+}
+
+/// Multiplies a (..., M, K) float matrix with a (K, N) int8 matrix. Activation rows are quantized to int8 on the fly,
+/// multiplied with DP4A and dequantized with the row and channel scales in the epilogue.
+#[derive(Clone)]
+pub struct Int8Matmul<T> {
+    quantize_function: CudaFunction,
+    matmul_function: CudaFunction,
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(Int8Matmul);
+
+impl<T: CudaFloat> Int8Matmul<T> {
+    fn new(device: Arc<CudaDevice>) -> Self {
+        let type_name = T::type_name();
+        Self {
+            quantize_function: compile_and_load_kernel(
+                format!(
+                    "
+#include \"cuda_fp16.h\"
+// One block per activation row: find the absmax, then quantize the row symmetrically
+extern \"C\" __global__ void kernel(const {type_name}* inp, char* quantized, float* row_scales, int* row_sums, int k) {{
+    __shared__ float buf[256];
+    int row = blockIdx.x;
+    inp += row * k;
+    quantized += row * k;
+    float local_max = 0.0;
+    for (int i = threadIdx.x; i < k; i += blockDim.x) local_max = fmaxf(local_max, fabsf((float)inp[i]));
+    buf[threadIdx.x] = local_max;
+    __syncthreads();
+    for (int s = blockDim.x / 2; s > 0; s >>= 1) {{
+        if (threadIdx.x < s) buf[threadIdx.x] = fmaxf(buf[threadIdx.x], buf[threadIdx.x + s]);
+        __syncthreads();
+    }}
+    float scale = buf[0] / 127.0;
+    __syncthreads();
+    float local_sum = 0.0;
+    for (int i = threadIdx.x; i < k; i += blockDim.x) {{
+        int q = scale == 0.0 ? 0 : __float2int_rn((float)inp[i] / scale);
+        quantized[i] = (char)q;
+        local_sum += (float)q;
+    }}
+    buf[threadIdx.x] = local_sum;
+    __syncthreads();
+    for (int s = blockDim.x / 2; s > 0; s >>= 1) {{
+        if (threadIdx.x < s) buf[threadIdx.x] += buf[threadIdx.x + s];
+        __syncthreads();
+    }}
+    if (threadIdx.x == 0) {{
+        row_scales[row] = scale;
+        row_sums[row] = (int)buf[0];
+    }}
+}}"
+                ),
+                &device,
+            ),
+            matmul_function: compile_and_load_kernel(
+                format!(
+                    "
+#include \"cuda_fp16.h\"
+__inline__ __device__ int warpReduceSum(int val) {{
+    for (int offset = warpSize/2; offset > 0; offset /= 2) {{
+        val += __shfl_down_sync(0xffffffff, val, offset);
+    }}
+    return val;
+}}
+
+// One warp per output element, 4 int8 values per DP4A instruction
+extern \"C\" __global__ void kernel(
+    const char* a, const float* a_scales, const int* a_sums,
+    const char* w, const float* w_scales, const int* w_zero_points,
+    {type_name}* out, int m, int n, int k
+) {{
+    int lane = threadIdx.x % warpSize;
+    int col = blockIdx.x * (blockDim.x / warpSize) + threadIdx.x / warpSize;
+    int row = blockIdx.y;
+    if (col >= n) return;
+    const char* a_row = a + row * k;
+    const char* w_row = w + col * k;
+    int acc = 0;
+    int k4 = k / 4;
+    for (int i = lane; i < k4; i += warpSize) {{
+        acc = __dp4a(((const int*)a_row)[i], ((const int*)w_row)[i], acc);
+    }}
+    for (int i = k4 * 4 + lane; i < k; i += warpSize) {{
+        acc += (int)a_row[i] * (int)w_row[i];
+    }}
+    acc = warpReduceSum(acc);
+    if (lane == 0) {{
+        out[row * n + col] = ({type_name})((float)(acc - w_zero_points[col] * a_sums[row]) * a_scales[row] * w_scales[col]);
+    }}
+}}"
+                ),
+                &device,
+            ),
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for Int8Matmul<T> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            !inp[1].1.is_contiguous(),
+            "Weight matrix must be column-major"
+        );
+        let weights = inp[1]
+            .0
+            .borrowed()
+            .downcast_ref::<CudaInt8Matrix>()
+            .expect("Int8Matmul weights must be a CudaInt8Matrix");
+        assert_eq!(
+            weights.cols % 4,
+            0,
+            "Int8 weight rows must be 4 byte aligned for DP4A"
+        );
+        let (k, n) = (weights.cols, weights.rows);
+        let m = inp[0].1.n_elements().to_usize().unwrap() / k;
+
+        let quantized = unsafe { self.device.alloc::<i8>(m * k).unwrap() };
+        let row_scales = unsafe { self.device.alloc::<f32>(m).unwrap() };
+        let row_sums = unsafe { self.device.alloc::<i32>(m).unwrap() };
+        let out = unsafe { self.device.alloc::<T>(m * n).unwrap() };
+        let (m_i, n_i, k_i) = (m as i32, n as i32, k as i32);
+
+        let mut params = vec![
+            get_buffer_from_tensor::<T>(&inp[0].0).as_kernel_param(),
+            (&quantized).as_kernel_param(),
+            (&row_scales).as_kernel_param(),
+            (&row_sums).as_kernel_param(),
+            k_i.as_kernel_param(),
+        ];
+        unsafe {
+            self.quantize_function
+                .clone()
+                .launch(
+                    LaunchConfig {
+                        grid_dim: (m as u32, 1, 1),
+                        block_dim: (256, 1, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        let mut params = vec![
+            (&quantized).as_kernel_param(),
+            (&row_scales).as_kernel_param(),
+            (&row_sums).as_kernel_param(),
+            (&weights.data).as_kernel_param(),
+            (&weights.scales).as_kernel_param(),
+            (&weights.zero_points).as_kernel_param(),
+            (&out).as_kernel_param(),
+            m_i.as_kernel_param(),
+            n_i.as_kernel_param(),
+            k_i.as_kernel_param(),
+        ];
+        unsafe {
+            self.matmul_function
+                .clone()
+                .launch(
+                    LaunchConfig {
+                        // 8 warps per block, one output element per warp
+                        grid_dim: (n.div_ceil(8) as u32, m as u32, 1),
+                        block_dim: (256, 1, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData(out))]
+    }
+}
+
+/// Runs int8 DP4A matmuls for the given weights, which should be set to `CudaInt8Matrix` data
+#[derive(Default, Debug)]
+pub struct CudaInt8Compiler<T>(Vec<NodeIndex>, PhantomData<T>);
+
+impl<T> CudaInt8Compiler<T> {
+    pub fn new<To: ToIds>(weights: To) -> Self {
+        Self(weights.to_ids(), Default::default())
+    }
+}
+
+impl<T: CudaFloat + Default> Compiler for CudaInt8Compiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let device = CudaDevice::new(0).unwrap();
+        let mut weight_ids = self.0.clone();
+        let mut local_remap = remap.to_ids_mut();
+        for w in &mut weight_ids {
+            local_remap.push(w);
+        }
+        // Normal compilation
+        graph.compile(crate::CudaCompiler::<T>::default(), &mut local_remap);
+        // Modify ops directly downstream of weights
+        for weight in downstream(&weight_ids, graph) {
+            for (target, (inp_ind, _, _)) in graph
+                .edges_directed(weight, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|i| (e.target(), i)))
+                .collect::<Vec<_>>()
+            {
+                assert_eq!(inp_ind, 1, "Int8 weight {target:?} is the wrong input!");
+                let op_node = graph.node_weight_mut(target).unwrap();
+                if op_node.as_any().is::<super::matmul::Matmul<T>>() {
+                    *op_node = Box::new(Int8Matmul::<T>::new(device.clone()));
+                } else {
+                    panic!("Int8 weight {target:?} is an input to a node that isn't a matmul ({op_node:?})");
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
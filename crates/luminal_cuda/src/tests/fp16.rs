@@ -782,3 +782,19 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_sample_large_vocab() {
+    // Ids above 2048 can't be represented exactly in half precision
+    let mut logits = vec![0.; 2 * 5000];
+    logits[3001] = 10.;
+    logits[5000 + 4097] = 10.;
+    let mut cx = Graph::new();
+    let inp = cx.tensor::<R2<2, 5000>>().set(logits);
+    let mut out = inp.sample(0., 0, 1., 0).retrieve();
+
+    cx.compile(CudaCompiler::<f16>::default(), &mut out);
+    cx.execute();
+
+    assert_exact(&out.data(), &[3001., 4097.]);
+}
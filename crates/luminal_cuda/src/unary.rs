@@ -728,6 +728,7 @@ impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
             unary::<CudaSub<T>>(max_reduce.clone()),
         ))));
 
+        let kernel = CudaSoftmax::<T>::new(dev.clone());
         let mut s = mul.clone().search(graph);
         while s.next_match() {
             if s.check_no_delete(&[mul.id]) {
@@ -736,10 +737,7 @@ impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
             }
             // Insert Softmax op
             let src = graph.get_sources(s.get(&max_reduce))[0];
-            let mean_reduce = graph
-                .add_op(CudaSoftmax::<T>::new(dev.clone()))
-                .input(src.0, 0, src.2)
-                .finish();
+            let mean_reduce = graph.add_op(kernel.clone()).input(src.0, 0, src.2).finish();
 
             // Create edges to dests
             let mul = s.get(&mul);
@@ -750,6 +748,16 @@ impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
             graph.remove_node(mul);
             s.try_delete();
         }
+
+        // Fused softmaxes along the last dim run on the same kernel
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let Some(luminal::op::Softmax(dim)) = graph.try_get_op(node).copied() else {
+                continue;
+            };
+            if dim + 1 == graph.get_sources(node)[0].2.len() {
+                *graph.graph.node_weight_mut(node).unwrap() = Box::new(kernel.clone());
+            }
+        }
     }
 }
 
@@ -72,6 +72,14 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn size_bytes(&self) -> usize {
+        self.0.length() as usize
+    }
+
+    fn device(&self) -> &'static str {
+        "Metal"
+    }
 }
 
 pub trait MetalFloat: Copy + Debug + PartialEq + 'static + Default {
@@ -237,6 +245,8 @@ impl MetalKernel for () {
 }
 
 fn compile_lib(device: &Device, source: &str) -> Library {
+    // Metal libraries aren't cached, so every lookup is a compile
+    record_kernel_cache_lookup(false);
     let options = CompileOptions::new();
     options.set_fast_math_enabled(true);
     // options.set_install_name(
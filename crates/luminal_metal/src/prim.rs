@@ -31,7 +31,7 @@ impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
         let mut data = inp[0]
             .0
             .borrowed()
-            .downcast_ref::<Vec<f32>>()
+            .f32_data()
             .unwrap()
             .iter()
             .copied()
@@ -63,7 +63,14 @@ impl<T: MetalFloat> Operator for MetalCopyFromDevice<T> {
             return vec![inp.pop().unwrap().0.cloned()];
         }
         let buffer = get_buffer_from_tensor(&inp[0].0);
-        let mut data = vec![0.0; buffer.length() as usize / std::mem::size_of::<T>()];
+        // Buffers can be larger than the tensor they hold, since they get reused across executions
+        let n_elements = inp[0]
+            .1
+            .n_physical_elements()
+            .to_usize()
+            .unwrap()
+            .min(buffer.length() as usize / std::mem::size_of::<T>());
+        let mut data = vec![0.0; n_elements];
         let ptr = buffer.contents() as *mut T;
         for (i, d) in data.iter_mut().enumerate() {
             *d = unsafe { *ptr.add(i) }.to_f32();
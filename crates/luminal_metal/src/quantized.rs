@@ -450,7 +450,7 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         autoreleasepool(|| {
             // Setup buffers
-            let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+            let indexes = tensors[0].0.borrowed().f32_data().unwrap();
             let index_buffer = self.device.new_buffer_with_data(
                 unsafe { std::mem::transmute(indexes.as_ptr()) },
                 (indexes.len() * std::mem::size_of::<f32>()) as u64,
@@ -327,15 +327,9 @@ impl Operator for AllocateMetalBuffers {
         } else {
             for (size, buffer) in self.buffer_sizes.iter().zip(buffers) {
                 let size = size.exec(dyn_map).unwrap() as u64;
-                if buffer.length() != size {
-                    // TODO: For some reason this causes bad outputs. Maybe we are relying on buffer length somewhere? We shouldn't be.
-                    // Also, it seems we are getting the benifits of this without actually doing it. Maybe metal is doing it in the background?
-                    // Similar allocation strategy to Rust's Vec
-                    // let mut length = buffer.length();
-                    // while length < size {
-                    //     length *= 2;
-                    // }
-                    let length = size;
+                if buffer.length() < size {
+                    // Grow like Rust's Vec so buffers stay allocated across executions as dyn dims increase
+                    let length = size.max(buffer.length() * 2);
                     *buffer = self
                         .dev
                         .new_buffer(length, MTLResourceOptions::StorageModeShared);
@@ -406,3 +400,23 @@ fn test_shared_buffers() {
 
     assert_close_precision(&e.data(), &e_unopt, 1e-2);
 }
+
+#[test]
+fn test_shared_buffers_growing() {
+    use luminal::prelude::*;
+    use luminal::tests::{assert_close_precision, random_vec};
+    let mut cx = Graph::new();
+    let mut a = cx.tensor::<(Dyn<'s'>,)>();
+    let mut b = (a.exp2() * a.sin()).retrieve();
+    cx.compile(crate::MetalCompiler::<f16>::default(), (&mut a, &mut b));
+
+    // Shrink then grow, so buffers get reused and outputs must not pick up stale tail elements
+    for len in [6, 2, 9] {
+        let data = random_vec(len);
+        a.set_dyn(data.clone(), &[len]);
+        cx.execute();
+        let expected = data.iter().map(|i| i.exp2() * i.sin()).collect::<Vec<_>>();
+        assert_close_precision(&b.data(), &expected, 1e-2);
+        b.drop();
+    }
+}
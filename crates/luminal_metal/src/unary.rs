@@ -878,6 +878,21 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
 
         let lib = compile_lib(&dev, include_str!("kernels/softmax.metal"));
         let type_name = if T::is_f32() { "float32" } else { "float16" };
+        let kernel = MetalSoftmax::<T> {
+            device: dev.clone(),
+            queue: queue.clone(),
+            _phantom: Default::default(),
+            single_row_pipeline: select_function_from_lib(
+                &lib,
+                &format!("softmax_{type_name}"),
+                &dev,
+            ),
+            looped_pipeline: select_function_from_lib(
+                &lib,
+                &format!("softmax_looped_{type_name}"),
+                &dev,
+            ),
+        };
         let mut s = mul.clone().search(graph);
         while s.next_match() {
             if s.check_no_delete(&[mul.id]) {
@@ -886,24 +901,7 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
             }
             // Insert Softmax op
             let src = graph.get_sources(s.get(&max_reduce))[0];
-            let mean_reduce = graph
-                .add_op(MetalSoftmax::<T> {
-                    device: dev.clone(),
-                    queue: queue.clone(),
-                    _phantom: Default::default(),
-                    single_row_pipeline: select_function_from_lib(
-                        &lib,
-                        &format!("softmax_{type_name}"),
-                        &dev,
-                    ),
-                    looped_pipeline: select_function_from_lib(
-                        &lib,
-                        &format!("softmax_looped_{type_name}"),
-                        &dev,
-                    ),
-                })
-                .input(src.0, 0, src.2)
-                .finish();
+            let mean_reduce = graph.add_op(kernel.clone()).input(src.0, 0, src.2).finish();
 
             // Create edges to dests
             let mul = s.get(&mul);
@@ -914,6 +912,16 @@ impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
             graph.remove_node(mul);
             s.try_delete();
         }
+
+        // Fused softmaxes along the last dim run on the same kernel
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let Some(luminal::op::Softmax(dim)) = graph.try_get_op(node).copied() else {
+                continue;
+            };
+            if dim + 1 == graph.get_sources(node)[0].2.len() {
+                *graph.graph.node_weight_mut(node).unwrap() = Box::new(kernel.clone());
+            }
+        }
     }
 }
 
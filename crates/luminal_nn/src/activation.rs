@@ -84,9 +84,39 @@ impl<S: ConstShape> Module<GraphTensor<S>> for Tanh {
     }
 }
 
+/// Gaussian Error Linear Unit activation function
+pub struct GeLU {
+    /// Use the tanh approximation (like GPT-2) rather than the exact erf form (like BERT)
+    pub tanh_approximation: bool,
+}
+
+impl InitModule for GeLU {
+    fn initialize(_: &mut Graph) -> Self {
+        Self {
+            tanh_approximation: false,
+        }
+    }
+}
+
+impl SerializeModule for GeLU {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl<S: Shape> Module<GraphTensor<S>> for GeLU {
+    type Output = GraphTensor<S>;
+
+    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+        if self.tanh_approximation {
+            input.gelu_tanh()
+        } else {
+            input.gelu()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ReLU;
+    use super::{GeLU, ReLU};
     use crate::Linear;
     use dfdx::prelude::{Module as DfdxModule, *};
     use luminal::{
@@ -154,4 +184,20 @@ mod tests {
         assert_close(&unoptimized_b, &out.as_vec());
         assert_close(&unoptimized_batch_out, &d_batch_out.as_vec());
     }
+
+    #[test]
+    fn test_gelu() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![-2., -0.5, 0.5, 2.]);
+        let mut model = GeLU::initialize(&mut cx);
+        let exact = model.forward(a).retrieve();
+        model.tanh_approximation = true;
+        let approx = model.forward(a).retrieve();
+        cx.execute();
+
+        let dev = Cpu::default();
+        let d_a = dev.tensor([-2., -0.5, 0.5, 2.]);
+        assert_close(&exact.data(), &d_a.clone().accurate_gelu().as_vec());
+        assert_close(&approx.data(), &d_a.fast_gelu().as_vec());
+    }
 }
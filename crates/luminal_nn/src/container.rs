@@ -0,0 +1,191 @@
+use std::ops::{Deref, DerefMut};
+
+use luminal::prelude::*;
+
+/// A layer that can be stored in a `Sequential`
+pub trait Layer<X>: Module<X, Output = X> + SerializeModule {}
+
+impl<X, T: Module<X, Output = X> + SerializeModule> Layer<X> for T {}
+
+/// Layers chosen at runtime, run one after another. Every layer takes and returns the same type, so the layers are
+/// boxed and can all be different modules.
+///
+/// Layers that change the input type can be chained with a tuple of modules instead, which runs them in order the same
+/// way and is built with `InitModule::initialize`.
+pub struct Sequential<X> {
+    pub layers: Vec<Box<dyn Layer<X>>>,
+}
+
+impl<X> Default for Sequential<X> {
+    fn default() -> Self {
+        Self { layers: vec![] }
+    }
+}
+
+impl<X> Sequential<X> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a layer to the end
+    pub fn with(mut self, layer: impl Layer<X> + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn push(&mut self, layer: impl Layer<X> + 'static) {
+        self.layers.push(Box::new(layer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+}
+
+impl<X> SerializeModule for Sequential<X> {
+    fn serialize(&self, s: &mut Serializer) {
+        for (i, l) in self.layers.iter().enumerate() {
+            s.module(&format!("layer{i}"), &BoxedLayer(l.as_ref()));
+        }
+    }
+}
+
+/// Lets a boxed layer be passed where a sized module is expected
+struct BoxedLayer<'a, X>(&'a dyn Layer<X>);
+
+impl<X> SerializeModule for BoxedLayer<'_, X> {
+    fn serialize(&self, s: &mut Serializer) {
+        self.0.serialize(s)
+    }
+}
+
+impl<X> Module<X> for Sequential<X> {
+    type Output = X;
+
+    fn forward(&self, mut input: X) -> Self::Output {
+        for l in &self.layers {
+            input = l.forward(input);
+        }
+        input
+    }
+}
+
+/// A list of modules of the same type with a length chosen at runtime. It runs the modules in order when they take and
+/// return the same type, and otherwise can be indexed and iterated like a `Vec`.
+///
+/// For a length known at compile time, `Repeated` can be built with `InitModule::initialize`.
+pub struct ModuleList<T> {
+    pub modules: Vec<T>,
+}
+
+impl<T> Default for ModuleList<T> {
+    fn default() -> Self {
+        Self { modules: vec![] }
+    }
+}
+
+impl<T: InitModule> ModuleList<T> {
+    /// Initialize `n` modules
+    pub fn new(n: usize, cx: &mut Graph) -> Self {
+        Self {
+            modules: (0..n).map(|_| T::initialize(cx)).collect(),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for ModuleList<T> {
+    fn from(modules: Vec<T>) -> Self {
+        Self { modules }
+    }
+}
+
+impl<T> FromIterator<T> for ModuleList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self {
+            modules: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<T> Deref for ModuleList<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.modules
+    }
+}
+
+impl<T> DerefMut for ModuleList<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.modules
+    }
+}
+
+impl<T: SerializeModule> SerializeModule for ModuleList<T> {
+    fn serialize(&self, s: &mut Serializer) {
+        for (i, l) in self.modules.iter().enumerate() {
+            s.module(&format!("layer{i}"), l);
+        }
+    }
+}
+
+impl<I, T: Module<I, Output = I>> Module<I> for ModuleList<T> {
+    type Output = I;
+
+    fn forward(&self, mut input: I) -> Self::Output {
+        for m in &self.modules {
+            input = m.forward(input);
+        }
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::{Module, *};
+
+    use super::{ModuleList, Sequential};
+    use crate::{Linear, ReLU, Tanh};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_containers() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let tuple: (Linear<3, 3>, ReLU, Linear<3, 3>, Tanh) = InitModule::initialize(&mut cx);
+        let list: ModuleList<Linear<3, 3>> = ModuleList::new(2, &mut cx);
+        for (a, b) in [
+            (tuple.0.weight, list[0].weight),
+            (tuple.2.weight, list[1].weight),
+        ] {
+            let weight = random_vec(9);
+            a.set(weight.clone());
+            b.set(weight);
+        }
+        let expected = tuple.forward(input).retrieve();
+        let chained = list.forward(input).retrieve();
+        let expected_chained = tuple.2.forward(tuple.0.forward(input)).retrieve();
+
+        let sequential = Sequential::new()
+            .with(tuple.0)
+            .with(ReLU)
+            .with(tuple.2)
+            .with(Tanh);
+        assert_eq!(sequential.len(), 4);
+        let out = sequential.forward(input).retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+        assert_close(&chained.data(), &expected_chained.data());
+        let mut names = param_dict(&sequential).into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["layer0/weight", "layer2/weight"]);
+        let mut names = param_dict(&list).into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["layer0/weight", "layer1/weight"]);
+    }
+}
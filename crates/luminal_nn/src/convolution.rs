@@ -1,5 +1,7 @@
-use luminal::prelude::*;
-use rand::{thread_rng, Rng};
+use luminal::{op::Convolution2D, prelude::*};
+use rand::thread_rng;
+
+use crate::{Fans, Init};
 
 pub struct Conv1D<
     const CHANNELS_IN: usize,
@@ -23,14 +25,12 @@ impl<
     for Conv1D<CHANNELS_IN, CHANNELS_OUT, KERNEL, STRIDE, DILATION, CHANNELS_IN_TIMES_KERNEL>
 {
     fn initialize(cx: &mut Graph) -> Self {
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
+        let weight = cx.named_tensor("Weight").with_attribute(Fans {
+            fan_in: CHANNELS_IN * KERNEL,
+            fan_out: CHANNELS_OUT * KERNEL,
+        });
         Self {
-            weight: cx.named_tensor("Weight").set(
-                (0..(CHANNELS_IN * CHANNELS_OUT * KERNEL))
-                    .map(|_| rng.gen_range(-1_f32..1_f32))
-                    .collect::<Vec<_>>(),
-            ),
+            weight: Init::default().init(weight, &mut thread_rng()),
         }
     }
 }
@@ -115,14 +115,12 @@ impl<
     >
 {
     fn initialize(cx: &mut Graph) -> Self {
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
+        let weight = cx.named_tensor("Weight").with_attribute(Fans {
+            fan_in: CHANNELS_IN * KERNELX * KERNELY,
+            fan_out: CHANNELS_OUT * KERNELX * KERNELY,
+        });
         Self {
-            weight: cx.named_tensor("Weight").set(
-                (0..(CHANNELS_IN * CHANNELS_OUT * KERNELX * KERNELY))
-                    .map(|_| rng.gen_range(-1_f32..1_f32))
-                    .collect::<Vec<_>>(),
-            ),
+            weight: Init::default().init(weight, &mut thread_rng()),
         }
     }
 }
@@ -210,9 +208,78 @@ impl<
     }
 }
 
+/// A 2D convolution run as a single `Convolution2D` op, which also supports padding and groups.
+///
+/// The weight is laid out like PyTorch's, as (CHANNELS_OUT, GROUP_CHANNELS_IN, KERNELX, KERNELY) where
+/// GROUP_CHANNELS_IN is the number of input channels in each group. Stride, padding, dilation and groups are set
+/// through `conv` after initializing.
+pub struct Conv2DLayer<
+    const GROUP_CHANNELS_IN: usize,
+    const CHANNELS_OUT: usize,
+    const KERNELX: usize,
+    const KERNELY: usize,
+> {
+    pub weight: GraphTensor<R4<CHANNELS_OUT, GROUP_CHANNELS_IN, KERNELX, KERNELY>>,
+    pub conv: Convolution2D,
+}
+
+impl<
+        const GROUP_CHANNELS_IN: usize,
+        const CHANNELS_OUT: usize,
+        const KERNELX: usize,
+        const KERNELY: usize,
+    > InitModule for Conv2DLayer<GROUP_CHANNELS_IN, CHANNELS_OUT, KERNELX, KERNELY>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        let weight = cx.named_tensor("Weight").with_attribute(Fans {
+            fan_in: GROUP_CHANNELS_IN * KERNELX * KERNELY,
+            fan_out: CHANNELS_OUT * KERNELX * KERNELY,
+        });
+        Self {
+            weight: Init::default().init(weight, &mut thread_rng()),
+            conv: Convolution2D::default(),
+        }
+    }
+}
+
+impl<
+        const GROUP_CHANNELS_IN: usize,
+        const CHANNELS_OUT: usize,
+        const KERNELX: usize,
+        const KERNELY: usize,
+    > SerializeModule for Conv2DLayer<GROUP_CHANNELS_IN, CHANNELS_OUT, KERNELX, KERNELY>
+{
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+impl<
+        const GROUP_CHANNELS_IN: usize,
+        const CHANNELS_OUT: usize,
+        const KERNELX: usize,
+        const KERNELY: usize,
+    > Conv2DLayer<GROUP_CHANNELS_IN, CHANNELS_OUT, KERNELX, KERNELY>
+{
+    /// Convolve a (batch, channels_in, dimx, dimy) input
+    pub fn forward<
+        Batch: Dimension,
+        ChannelsIn: Dimension,
+        DimXIn: Dimension,
+        DimYIn: Dimension,
+        DimXOut: Dimension,
+        DimYOut: Dimension,
+    >(
+        &self,
+        input: GraphTensor<(Batch, ChannelsIn, DimXIn, DimYIn)>,
+    ) -> GraphTensor<(Batch, Const<CHANNELS_OUT>, DimXOut, DimYOut)> {
+        input.conv2d(self.weight, self.conv)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Conv1D, Conv2D};
+    use super::{Conv1D, Conv2D, Conv2DLayer};
     use luminal::{prelude::*, tests::assert_close};
 
     #[test]
@@ -396,4 +463,33 @@ mod tests {
 
         assert_close(&out1.data(), &exp_out1.data())
     }
+
+    #[test]
+    fn test_conv2d_layer() {
+        let mut cx = Graph::new();
+        let input = cx
+            .tensor::<R3<3, 6, 5>>()
+            .set(luminal::tests::random_vec(3 * 6 * 5));
+
+        // Check against a pool-based convolution with the same weights, over an explicitly padded input
+        let pooled: Conv2D<3, 4, 3, 2, 2, 1, 0, 0, 18> = Conv2D::initialize(&mut cx);
+        let mut layer: Conv2DLayer<3, 4, 3, 2> = Conv2DLayer::initialize(&mut cx);
+        layer.weight = pooled.weight.reshape();
+        layer.conv.stride = (2, 1);
+        layer.conv.padding = (1, 1);
+
+        let out = layer
+            .forward::<_, _, _, _, Const<3>, Const<6>>(input.expand::<R4<1, 3, 6, 5>, _>())
+            .retrieve();
+        let expected = pooled
+            .forward::<8, 7, 3, 6, 18>(
+                input
+                    .pad::<R3<3, 8, 7>, usize, usize>(&[(0, 0), (1, 1), (1, 1)])
+                    .contiguous(),
+            )
+            .retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
 }
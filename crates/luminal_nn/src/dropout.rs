@@ -0,0 +1,50 @@
+use luminal::prelude::*;
+
+/// Randomly zeroes elements with probability `p` while the graph is training (see `Graph::set_training`), scaling the
+/// rest by `1 / (1 - p)`. At inference this passes its input through.
+pub struct Dropout {
+    pub p: f32,
+}
+
+impl InitModule for Dropout {
+    fn initialize(_: &mut Graph) -> Self {
+        Self { p: 0.5 }
+    }
+}
+
+impl SerializeModule for Dropout {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl<S: Shape> Module<GraphTensor<S>> for Dropout {
+    type Output = GraphTensor<S>;
+
+    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+        input.dropout(self.p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dropout;
+    use luminal::prelude::*;
+
+    #[test]
+    fn test_dropout() {
+        let mut cx = Graph::new();
+        let model = Dropout { p: 0.5 };
+        let a = cx.tensor::<R2<8, 8>>().set(vec![1.; 64]);
+        let b = model.forward(a).retrieve();
+
+        cx.set_training(true);
+        cx.execute();
+        let dropped = b.data().iter().filter(|x| **x == 0.).count();
+        assert!(dropped > 0 && dropped < 64);
+        assert!(b.data().iter().all(|x| *x == 0. || *x == 2.));
+
+        b.drop();
+        cx.set_training(false);
+        cx.execute();
+        assert_eq!(b.data(), vec![1.; 64]);
+    }
+}
@@ -36,9 +36,7 @@ impl<B: Dimension, S: Dimension, const N: usize, const DIM: usize> Module<GraphT
     type Output = GraphTensor<(B, S, Const<DIM>)>;
 
     fn forward(&self, input: GraphTensor<(B, S)>) -> Self::Output {
-        self.weight
-            .gather(input.dyn_reshape::<(Dyn<'-'>,)>(vec![B::const_size() * S::const_size()]))
-            .reshape()
+        self.weight.index_select::<_, Axis<0>, _>(input)
     }
 }
 
@@ -58,9 +56,7 @@ mod tests {
     #[test]
     fn test_embedding() {
         let mut cx = Graph::new();
-        let batch = cx
-            .tensor::<R2<2, 3>>()
-            .set(vec![1.0, 0.0, 2.0, 1.0, 0.0, 1.0]);
+        let batch = cx.tensor::<R2<2, 3>>().set(vec![1_u32, 0, 2, 1, 0, 1]);
         let a = cx.tensor::<R1<3>>().set(vec![1.0, 0.0, 1.0]).retrieve();
 
         let model: Embedding<3, 4> = InitModule::initialize(&mut cx);
@@ -0,0 +1,300 @@
+use std::f32::consts::TAU;
+
+use luminal::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How many inputs feed into and outputs are fed by each element of a weight, which Xavier and Kaiming initialization
+/// scale by. Weights not laid out (fan in, fan out, receptive field...) like `Linear` should carry this as an attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fans {
+    pub fan_in: usize,
+    pub fan_out: usize,
+}
+
+impl Fans {
+    /// The fans of a weight shaped (in, out, receptive field...). Vectors count their length as both fans.
+    pub fn from_shape(shape: &[usize]) -> Self {
+        match shape {
+            [] => Self {
+                fan_in: 1,
+                fan_out: 1,
+            },
+            [n] => Self {
+                fan_in: *n,
+                fan_out: *n,
+            },
+            [i, o, rest @ ..] => {
+                let receptive_field = rest.iter().product::<usize>();
+                Self {
+                    fan_in: i * receptive_field,
+                    fan_out: o * receptive_field,
+                }
+            }
+        }
+    }
+}
+
+/// Which fan Kaiming initialization preserves the variance of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FanMode {
+    /// Keep the variance of activations in the forward pass
+    #[default]
+    FanIn,
+    /// Keep the variance of gradients in the backward pass
+    FanOut,
+}
+
+/// A scheme for the starting values of a tensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    Constant(f32),
+    Uniform {
+        low: f32,
+        high: f32,
+    },
+    Normal {
+        mean: f32,
+        std: f32,
+    },
+    /// A normal distribution redrawing any sample outside `[low, high]`
+    TruncatedNormal {
+        mean: f32,
+        std: f32,
+        low: f32,
+        high: f32,
+    },
+    /// Uniform in `±gain * sqrt(6 / (fan_in + fan_out))`
+    XavierUniform {
+        gain: f32,
+    },
+    /// Normal with a std of `gain * sqrt(2 / (fan_in + fan_out))`
+    XavierNormal {
+        gain: f32,
+    },
+    /// Uniform in `±gain * sqrt(3 / fan)`. A gain of `sqrt(2)` suits ReLU.
+    KaimingUniform {
+        gain: f32,
+        mode: FanMode,
+    },
+    /// Normal with a std of `gain / sqrt(fan)`. A gain of `sqrt(2)` suits ReLU.
+    KaimingNormal {
+        gain: f32,
+        mode: FanMode,
+    },
+}
+
+impl Default for Init {
+    /// Uniform in `±1 / sqrt(fan_in)`, PyTorch's default for linear and convolution weights
+    fn default() -> Self {
+        Self::KaimingUniform {
+            gain: (1. / 3_f32).sqrt(),
+            mode: FanMode::FanIn,
+        }
+    }
+}
+
+impl Init {
+    /// Draw `n` values for a tensor with the given fans
+    pub fn sample(&self, n: usize, fans: Fans, rng: &mut impl Rng) -> Vec<f32> {
+        let fan = |mode| match mode {
+            FanMode::FanIn => fans.fan_in,
+            FanMode::FanOut => fans.fan_out,
+        } as f32;
+        let xavier = (2. / (fans.fan_in + fans.fan_out) as f32).sqrt();
+        let uniform = |bound: f32, rng: &mut _| sample_uniform(n, -bound, bound, rng);
+        match *self {
+            Init::Constant(c) => vec![c; n],
+            Init::Uniform { low, high } => sample_uniform(n, low, high, rng),
+            Init::Normal { mean, std } => (0..n).map(|_| mean + std * normal(rng)).collect(),
+            Init::TruncatedNormal {
+                mean,
+                std,
+                low,
+                high,
+            } => {
+                assert!(low < high, "Truncation bounds must leave a range to sample");
+                (0..n)
+                    .map(|_| loop {
+                        let x = mean + std * normal(rng);
+                        if (low..=high).contains(&x) {
+                            break x;
+                        }
+                    })
+                    .collect()
+            }
+            Init::XavierUniform { gain } => uniform(gain * xavier * 3_f32.sqrt(), rng),
+            Init::XavierNormal { gain } => (0..n).map(|_| gain * xavier * normal(rng)).collect(),
+            Init::KaimingUniform { gain, mode } => uniform(gain * (3. / fan(mode)).sqrt(), rng),
+            Init::KaimingNormal { gain, mode } => {
+                let std = gain / fan(mode).sqrt();
+                (0..n).map(|_| std * normal(rng)).collect()
+            }
+        }
+    }
+
+    /// Set a tensor's values, using its `Fans` attribute if it has one and its shape otherwise
+    pub fn init<S: Shape>(&self, tensor: GraphTensor<S>, rng: &mut impl Rng) -> GraphTensor<S> {
+        let (shape, fans) = unsafe {
+            tensor.with_graph(|cx| {
+                let shape = tensor
+                    .shape
+                    .shape()
+                    .iter()
+                    .map(|d| d.exec(&cx.dyn_map).expect("Unknown dynamic dimension"))
+                    .collect::<Vec<_>>();
+                let fans = cx
+                    .attribute::<Fans>(tensor.id)
+                    .copied()
+                    .unwrap_or_else(|| Fans::from_shape(&shape));
+                (shape, fans)
+            })
+        };
+        tensor.set_dyn(self.sample(shape.iter().product(), fans, rng), &shape)
+    }
+}
+
+fn sample_uniform(n: usize, low: f32, high: f32, rng: &mut impl Rng) -> Vec<f32> {
+    (0..n).map(|_| rng.gen_range(low..high)).collect()
+}
+
+/// A standard normal sample, using the Box-Muller transform
+fn normal(rng: &mut impl Rng) -> f32 {
+    let u: f32 = 1. - rng.gen::<f32>();
+    (-2. * u.ln()).sqrt() * (TAU * rng.gen::<f32>()).cos()
+}
+
+/// Initializers for every tensor of a model, picked by the end of each tensor's name (like `bias` or `norm/weight`).
+/// Rules are checked in the order they were added, and tensors matching none are left as they are.
+///
+/// Tensors are filled in order of their names from a single generator seeded with `seed`, so applying the same policy
+/// to the same model always gives the same values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitPolicy {
+    pub rules: Vec<(String, Init)>,
+    pub seed: u64,
+}
+
+impl InitPolicy {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rules: vec![],
+            seed,
+        }
+    }
+
+    /// Initialize tensors whose names end with `suffix`
+    pub fn rule(mut self, suffix: impl ToString, init: Init) -> Self {
+        self.rules.push((suffix.to_string(), init));
+        self
+    }
+
+    /// The initializer for a tensor name, if any rule matches it
+    pub fn init_for(&self, name: &str) -> Option<Init> {
+        self.rules
+            .iter()
+            .find(|(suffix, _)| name.ends_with(suffix.as_str()))
+            .map(|(_, init)| *init)
+    }
+
+    /// Set the values of every tensor in a model that a rule matches. Tensors whose dynamic dimensions aren't known
+    /// yet are skipped.
+    pub fn apply(&self, model: impl SerializeModule, cx: &mut Graph) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        for param in param_info(model, cx) {
+            let (Some(init), Some(n)) = (self.init_for(&param.name), param.n_elements) else {
+                continue;
+            };
+            let fans = cx.attribute::<Fans>(param.id).copied().unwrap_or_else(|| {
+                Fans::from_shape(
+                    &param
+                        .shape
+                        .iter()
+                        .map(|d| d.to_usize().unwrap())
+                        .collect::<Vec<_>>(),
+                )
+            });
+            let data = init.sample(n, fans, &mut rng);
+            cx.get_op_mut::<Function>(param.id).1 =
+                Box::new(move |_| vec![Tensor::new(data.clone())]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{FanMode, Fans, Init, InitPolicy};
+    use crate::{LayerNorm, Linear, PermutedLinear};
+    luminal::test_imports!();
+
+    fn mean_std(data: &[f32]) -> (f32, f32) {
+        let mean = data.iter().sum::<f32>() / data.len() as f32;
+        let var = data.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / data.len() as f32;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn test_initializers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let fans = Fans::from_shape(&[200, 50]);
+        assert_eq!(fans, Fans::from_shape(&[20, 5, 10]));
+
+        let bound = (6. / 250_f32).sqrt();
+        let xavier = Init::XavierUniform { gain: 1. }.sample(10000, fans, &mut rng);
+        assert!(xavier.iter().all(|x| x.abs() <= bound));
+        assert!((mean_std(&xavier).1 - bound / 3_f32.sqrt()).abs() < 0.01);
+
+        let kaiming = Init::KaimingNormal {
+            gain: 2_f32.sqrt(),
+            mode: FanMode::FanOut,
+        }
+        .sample(10000, fans, &mut rng);
+        let (mean, std) = mean_std(&kaiming);
+        assert!(mean.abs() < 0.01);
+        assert!((std - 0.2).abs() < 0.01);
+
+        let truncated = Init::TruncatedNormal {
+            mean: 1.,
+            std: 1.,
+            low: 0.,
+            high: 1.5,
+        }
+        .sample(1000, fans, &mut rng);
+        assert!(truncated.iter().all(|x| (0. ..=1.5).contains(x)));
+    }
+
+    #[test]
+    fn test_init_policy() {
+        let mut cx = Graph::new();
+        let model: (LayerNorm<4>, Linear<4, 8>, PermutedLinear<8, 4>) =
+            InitModule::initialize(&mut cx);
+        let norm = (model.0.weight.retrieve(), model.0.bias.retrieve());
+        let weights = (model.1.weight.retrieve(), model.2.weight.retrieve());
+        let policy = InitPolicy::new(3)
+            .rule("layer0/weight", Init::Constant(2.))
+            .rule(
+                "weight",
+                Init::KaimingUniform {
+                    gain: 1.,
+                    mode: FanMode::FanIn,
+                },
+            );
+        policy.apply(&model, &mut cx);
+        cx.execute();
+
+        assert_exact(&norm.0.data(), &[2.; 4]);
+        // The norm bias didn't match a rule
+        assert_exact(&norm.1.data(), &[0.; 4]);
+        // Both linear layers have a fan in of 4, even though the permuted one is laid out (out, in)
+        let bound = (3. / 4_f32).sqrt();
+        let (a, b) = (weights.0.data(), weights.1.data());
+        assert!(a.iter().chain(&b).all(|x| x.abs() <= bound));
+
+        // The same seed gives the same values
+        policy.apply(&model, &mut cx);
+        cx.execute();
+        assert_exact(&weights.0.data(), &a);
+        assert_exact(&weights.1.data(), &b);
+    }
+}
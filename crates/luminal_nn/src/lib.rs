@@ -2,14 +2,32 @@ use luminal::prelude::*;
 
 mod activation;
 pub use activation::*;
+mod container;
+pub use container::*;
 mod convolution;
 pub use convolution::*;
+mod dropout;
+pub use dropout::*;
 mod embedding;
 pub use embedding::*;
+mod init;
+pub use init::*;
 mod linear;
 pub use linear::*;
+mod lora;
+pub use lora::*;
+mod moe;
+pub use moe::*;
 mod norm;
 pub use norm::*;
+mod pooling;
+pub use pooling::*;
+mod position;
+pub use position::*;
+mod rotary;
+pub use rotary::*;
+mod ssm;
+pub use ssm::*;
 mod transformer;
 pub use transformer::*;
 
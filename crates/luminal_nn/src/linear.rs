@@ -1,7 +1,9 @@
-use rand::{thread_rng, Rng};
+use rand::thread_rng;
 
 use luminal::prelude::*;
 
+use crate::{Fans, Init};
+
 /// A simple unbiased linear layer
 pub struct Linear<const A: usize, const B: usize> {
     pub weight: GraphTensor<R2<A, B>>,
@@ -9,14 +11,8 @@ pub struct Linear<const A: usize, const B: usize> {
 
 impl<const A: usize, const B: usize> InitModule for Linear<A, B> {
     fn initialize(cx: &mut Graph) -> Self {
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
         Self {
-            weight: cx.named_tensor("Weight").set(
-                (0..(A * B))
-                    .map(|_| rng.gen_range(-1_f32..1_f32))
-                    .collect::<Vec<_>>(),
-            ),
+            weight: Init::default().init(cx.named_tensor("Weight"), &mut thread_rng()),
         }
     }
 }
@@ -45,14 +41,13 @@ pub struct PermutedLinear<const A: usize, const B: usize> {
 
 impl<const A: usize, const B: usize> InitModule for PermutedLinear<A, B> {
     fn initialize(cx: &mut Graph) -> Self {
-        // Init weight as uniform(-1, 1)
-        let mut rng = thread_rng();
+        // The weight is laid out (out, in)
+        let weight = cx.named_tensor("Weight").with_attribute(Fans {
+            fan_in: A,
+            fan_out: B,
+        });
         Self {
-            weight: cx.named_tensor("Weight").set(
-                (0..(A * B))
-                    .map(|_| rng.gen_range(-1_f32..1_f32))
-                    .collect::<Vec<_>>(),
-            ),
+            weight: Init::default().init(weight, &mut thread_rng()),
         }
     }
 }
@@ -74,9 +69,114 @@ where
     }
 }
 
+/// A linear layer whose sizes are dynamic dimensions, set on the graph at runtime, with an optional bias. Like
+/// `Linear`, the weight is laid out (in, out).
+pub struct DynLinear<In: Dimension, Out: Dimension> {
+    pub weight: GraphTensor<(In, Out)>,
+    pub bias: Option<GraphTensor<(Out,)>>,
+}
+
+impl<In: Dimension, Out: Dimension> DynLinear<In, Out> {
+    pub fn new(bias: bool, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("Weight"),
+            bias: bias.then(|| cx.named_tensor("Bias")),
+        }
+    }
+}
+
+impl<In: Dimension, Out: Dimension> SerializeModule for DynLinear<In, Out> {
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        if let Some(bias) = self.bias {
+            s.tensor("bias", bias);
+        }
+    }
+}
+
+impl<S: Dimension, In: Dimension, Out: Dimension> Module<GraphTensor<(S, In)>>
+    for DynLinear<In, Out>
+{
+    type Output = GraphTensor<(S, Out)>;
+
+    fn forward(&self, input: GraphTensor<(S, In)>) -> Self::Output {
+        let output = input.matmul(self.weight);
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
+impl<B: Dimension, S: Dimension, In: Dimension, Out: Dimension> Module<GraphTensor<(B, S, In)>>
+    for DynLinear<In, Out>
+{
+    type Output = GraphTensor<(B, S, Out)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, In)>) -> Self::Output {
+        let output = input.matmul(self.weight);
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
+/// A linear layer with dynamic sizes and an optional bias, with its weight laid out (out, in) like `PermutedLinear`
+pub struct DynPermutedLinear<In: Dimension, Out: Dimension> {
+    pub weight: GraphTensor<(Out, In)>,
+    pub bias: Option<GraphTensor<(Out,)>>,
+}
+
+impl<In: Dimension, Out: Dimension> DynPermutedLinear<In, Out> {
+    pub fn new(bias: bool, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("Weight"),
+            bias: bias.then(|| cx.named_tensor("Bias")),
+        }
+    }
+}
+
+impl<In: Dimension, Out: Dimension> SerializeModule for DynPermutedLinear<In, Out> {
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        if let Some(bias) = self.bias {
+            s.tensor("bias", bias);
+        }
+    }
+}
+
+impl<S: Dimension, In: Dimension, Out: Dimension> Module<GraphTensor<(S, In)>>
+    for DynPermutedLinear<In, Out>
+{
+    type Output = GraphTensor<(S, Out)>;
+
+    fn forward(&self, input: GraphTensor<(S, In)>) -> Self::Output {
+        let output = input.matmul(self.weight.permute());
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
+impl<B: Dimension, S: Dimension, In: Dimension, Out: Dimension> Module<GraphTensor<(B, S, In)>>
+    for DynPermutedLinear<In, Out>
+{
+    type Output = GraphTensor<(B, S, Out)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, In)>) -> Self::Output {
+        let output = input.matmul(self.weight.permute());
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Linear;
+    use super::{DynLinear, DynPermutedLinear, Linear};
     use luminal::{prelude::*, tests::assert_close};
     #[test]
     fn test_linear() {
@@ -101,4 +201,27 @@ mod tests {
         assert_close(&unoptimized_b, &b.data());
         assert_close(&unoptimized_batch_out, &batch_out.data());
     }
+
+    #[test]
+    fn test_dyn_linear() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(Const<2>, Dyn<'i'>)>()
+            .set_dyn(vec![1., 2., 3., -1., 0., 4.], &[2, 3]);
+        let linear = DynLinear::<Dyn<'i'>, Dyn<'o'>>::new(true, &mut cx);
+        linear
+            .weight
+            .set_dyn(vec![1., 0., 0., 1., 1., -1.], &[3, 2]);
+        linear.bias.unwrap().set_dyn(vec![0.5, -0.5], &[2]);
+        let permuted = DynPermutedLinear::<Dyn<'i'>, Dyn<'o'>>::new(false, &mut cx);
+        permuted
+            .weight
+            .set_dyn(vec![1., 0., 1., 0., 1., -1.], &[2, 3]);
+        let b = linear.forward(a).retrieve();
+        let c = permuted.forward(a).retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[4.5, -1.5, 3.5, -4.5]);
+        assert_close(&c.data(), &[4., -1., 3., -4.]);
+    }
 }
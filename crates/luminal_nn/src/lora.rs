@@ -0,0 +1,412 @@
+use std::{
+    io::{Error, ErrorKind},
+    ops::Add,
+    path::Path,
+};
+
+use itertools::Itertools;
+use luminal::{
+    op::Function,
+    prelude::*,
+    serialization::safetensors::{self, SafeTensorError, TensorMap},
+};
+use rand::thread_rng;
+
+use crate::{Fans, Init, PermutedLinear};
+
+/// A linear layer with a low rank adapter, mapping A to B through `x W^T + x A^T B^T`.
+///
+/// `W` is the frozen (out, in) weight of the wrapped layer, and the rank `R` matrices `A` (R, in) and `B` (out, R) hold
+/// the fine-tuned delta, laid out like PEFT's `lora_A` and `lora_B`. The adapter's scale is folded into `B` when it's
+/// loaded with `load_adapter`. `B` starts at zero, so a freshly initialized layer matches its base layer.
+pub struct LoraLinear<const A: usize, const B: usize, const R: usize> {
+    pub base: PermutedLinear<A, B>,
+    pub lora_a: GraphTensor<R2<R, A>>,
+    pub lora_b: GraphTensor<R2<B, R>>,
+}
+
+impl<const A: usize, const B: usize, const R: usize> InitModule for LoraLinear<A, B, R> {
+    fn initialize(cx: &mut Graph) -> Self {
+        let lora_a = cx.named_tensor("Lora A").with_attribute(Fans {
+            fan_in: A,
+            fan_out: R,
+        });
+        Self {
+            base: InitModule::initialize(cx),
+            lora_a: Init::default().init(lora_a, &mut thread_rng()),
+            lora_b: cx.named_tensor("Lora B").set(vec![0.; B * R]),
+        }
+    }
+}
+
+impl<const A: usize, const B: usize, const R: usize> SerializeModule for LoraLinear<A, B, R> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("", &self.base);
+        s.tensor("lora_A/weight", self.lora_a);
+        s.tensor("lora_B/weight", self.lora_b);
+    }
+}
+
+impl<const A: usize, const B: usize, const R: usize, S: Shape> Module<GraphTensor<S>>
+    for LoraLinear<A, B, R>
+where
+    GraphTensor<S>: Matmul<R2<A, B>> + Matmul<R2<A, R>>,
+    <GraphTensor<S> as Matmul<R2<A, R>>>::Output:
+        Matmul<R2<R, B>, Output = <GraphTensor<S> as Matmul<R2<A, B>>>::Output>,
+    <GraphTensor<S> as Matmul<R2<A, B>>>::Output:
+        Add<Output = <GraphTensor<S> as Matmul<R2<A, B>>>::Output>,
+{
+    type Output = <GraphTensor<S> as Matmul<R2<A, B>>>::Output;
+
+    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+        let delta = input
+            .matmul(self.lora_a.permute())
+            .matmul(self.lora_b.permute());
+        self.base.forward(input) + delta
+    }
+}
+
+/// The low rank adapter tensors of a model, which are the only ones trained when fine-tuning with LoRA. Mark these as
+/// trainable, so the optimizer only updates them.
+pub fn adapter_params(model: impl SerializeModule) -> Vec<NodeIndex> {
+    filter_params(model, true)
+}
+
+/// Every tensor of a model apart from its adapters. These stay frozen when fine-tuning with LoRA, so they can be
+/// quantized, like QLoRA's base weights.
+pub fn frozen_params(model: impl SerializeModule) -> Vec<NodeIndex> {
+    filter_params(model, false)
+}
+
+fn filter_params(model: impl SerializeModule, adapters: bool) -> Vec<NodeIndex> {
+    param_dict(model)
+        .into_iter()
+        .filter(|(path, _)| is_adapter_path(path) == adapters)
+        .sorted_by_key(|(path, _)| path.clone())
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// The settings of a PEFT LoRA adapter that affect how its weights are applied, read from `adapter_config.json`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoraConfig {
+    pub rank: usize,
+    pub alpha: f32,
+    /// Scale by `alpha / sqrt(rank)` instead of `alpha / rank`
+    pub use_rslora: bool,
+    /// Whether the adapted weights are laid out (in, out), like GPT-2's, rather than (out, in)
+    pub fan_in_fan_out: bool,
+}
+
+impl LoraConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, SafeTensorError> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let rank = json["r"]
+            .as_u64()
+            .filter(|r| *r > 0)
+            .ok_or_else(|| invalid_data("Adapter config needs a positive rank r".to_string()))?
+            as usize;
+        Ok(Self {
+            rank,
+            alpha: json["lora_alpha"].as_f64().map_or(8., |a| a as f32),
+            use_rslora: json["use_rslora"].as_bool().unwrap_or_default(),
+            fan_in_fan_out: json["fan_in_fan_out"].as_bool().unwrap_or_default(),
+        })
+    }
+
+    /// How much the adapter's delta is scaled by before being added to the weights
+    pub fn scale(&self) -> f32 {
+        if self.use_rslora {
+            self.alpha / (self.rank as f32).sqrt()
+        } else {
+            self.alpha / self.rank as f32
+        }
+    }
+}
+
+/// Load a base checkpoint into a model with `LoraLinear`s, skipping the adapter weights, which the checkpoint doesn't
+/// have. Names are mapped like `safetensors::load`.
+pub fn load_base<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<(), SafeTensorError> {
+    safetensors::load_matching(path, model, graph, |p| {
+        (!is_adapter_path(p)).then(|| p.replace('/', "."))
+    })
+}
+
+/// Load a PEFT adapter directory, holding `adapter_config.json` and `adapter_model.safetensors`, into the `LoraLinear`s
+/// of a model, keeping the low rank weights separate from the base weights at runtime.
+///
+/// A module path like `layers/0/q_proj/lora_A/weight` is read from `base_model.model.layers.0.q_proj.lora_A.weight`.
+/// Every adapted layer in the file must have a `LoraLinear` in the model, and vice versa.
+pub fn load_adapter<M: SerializeModule>(
+    dir: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<LoraConfig, SafeTensorError> {
+    let (config, mut tensors) = read_adapter(dir.as_ref())?;
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path, node) in s.state {
+        if !is_adapter_path(&path) {
+            continue;
+        }
+        let name = adapter_name(&path);
+        let (_, mut data) = tensors
+            .remove(&name)
+            .ok_or(SafeTensorError::TensorNotFound(name.clone()))?;
+        if s.shapes[&path].n_elements().to_usize() != Some(data.len()) {
+            return Err(invalid_data(format!(
+                "{name} doesn't fit {path}, so the adapter's rank may not match the model's"
+            )));
+        }
+        if path.ends_with("lora_B/weight") {
+            data.iter_mut().for_each(|v| *v *= config.scale());
+        }
+        loader(graph, node, &path)?.1 = Box::new(move |_| vec![Tensor::new(data.clone())]);
+    }
+    unused_adapter_weights(tensors)?;
+    Ok(config)
+}
+
+/// Merge a PEFT adapter directory, holding `adapter_config.json` and `adapter_model.safetensors`, into the weights of a
+/// model's linear layers, so an unchanged model runs the fine-tuned weights at no extra cost.
+///
+/// The delta `B A` of the adapted layer `layers.0.q_proj` is added to the tensor at `layers/0/q_proj/weight` as it
+/// loads, so the base weights must already have a loader, like one from `safetensors::load`, and merging must happen
+/// before compiling. Weights are expected to be laid out (out, in), unless the adapter sets `fan_in_fan_out`.
+pub fn merge_adapter<M: SerializeModule>(
+    dir: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<LoraConfig, SafeTensorError> {
+    let (config, mut tensors) = read_adapter(dir.as_ref())?;
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path, node) in s.state {
+        let Some(module) = path
+            .strip_suffix("weight")
+            .filter(|m| m.is_empty() || m.ends_with('/'))
+        else {
+            continue;
+        };
+        let [a, b] =
+            ["lora_A/weight", "lora_B/weight"].map(|w| adapter_name(&format!("{module}{w}")));
+        let (Some((a_shape, a)), Some((b_shape, b))) = (tensors.remove(&a), tensors.remove(&b))
+        else {
+            continue;
+        };
+        let (&[rank, fan_in], &[fan_out, b_rank]) = (&a_shape[..], &b_shape[..]) else {
+            return Err(invalid_data(format!("The adapter for {path} isn't 2D")));
+        };
+        let layout = if config.fan_in_fan_out {
+            [fan_in, fan_out]
+        } else {
+            [fan_out, fan_in]
+        };
+        let dims = s.shapes[&path]
+            .shape()
+            .into_iter()
+            .map(|e| e.exec(&graph.dyn_map))
+            .collect::<Option<Vec<_>>>();
+        if rank != b_rank || dims.as_deref() != Some(&layout[..]) {
+            return Err(invalid_data(format!(
+                "The adapter's {fan_out}x{fan_in} delta doesn't fit {path}"
+            )));
+        }
+        // delta = scale * B A, transposed for (in, out) weights
+        let mut delta = vec![0.; fan_out * fan_in];
+        for o in 0..fan_out {
+            for r in 0..rank {
+                let b = b[o * rank + r] * config.scale();
+                for i in 0..fan_in {
+                    let index = if config.fan_in_fan_out {
+                        i * fan_out + o
+                    } else {
+                        o * fan_in + i
+                    };
+                    delta[index] += b * a[r * fan_in + i];
+                }
+            }
+        }
+
+        let loader = loader(graph, node, &path)?;
+        let base = std::mem::replace(&mut loader.1, Box::new(|_| vec![]));
+        loader.1 = Box::new(move |inputs| {
+            let mut tensors = base(inputs);
+            let weight = tensors[0]
+                .downcast_mut::<Vec<f32>>()
+                .unwrap_or_else(|| panic!("{path} must load as f32s to merge an adapter into it"));
+            weight.iter_mut().zip(&delta).for_each(|(w, d)| *w += d);
+            tensors
+        });
+    }
+    unused_adapter_weights(tensors)?;
+    Ok(config)
+}
+
+fn is_adapter_path(path: &str) -> bool {
+    path.ends_with("lora_A/weight") || path.ends_with("lora_B/weight")
+}
+
+/// The name PEFT saves a module path's tensor under
+fn adapter_name(path: &str) -> String {
+    format!("base_model.model.{}", path.replace('/', "."))
+}
+
+fn read_adapter(dir: &Path) -> Result<(LoraConfig, TensorMap), SafeTensorError> {
+    let config = LoraConfig::from_file(dir.join("adapter_config.json"))?;
+    let mut tensors = safetensors::read_tensors(dir.join("adapter_model.safetensors"))?;
+    // Only the low rank matrices are applied, anything else the adapter trained isn't supported
+    tensors.retain(|name, _| name.contains("lora_"));
+    Ok((config, tensors))
+}
+
+fn unused_adapter_weights(tensors: TensorMap) -> Result<(), SafeTensorError> {
+    match tensors.keys().min() {
+        Some(name) => Err(invalid_data(format!(
+            "{name} doesn't match a layer of the model"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The op loading a tensor's data
+fn loader<'a>(
+    graph: &'a mut Graph,
+    node: NodeIndex,
+    path: &str,
+) -> Result<&'a mut Function, SafeTensorError> {
+    graph
+        .graph
+        .node_weight_mut(node)
+        .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        .ok_or_else(|| invalid_data(format!("{path} isn't loaded by the graph anymore")))
+}
+
+fn invalid_data(message: String) -> SafeTensorError {
+    SafeTensorError::IoError(Error::new(ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_adapter, load_base, merge_adapter, LoraLinear};
+    use crate::PermutedLinear;
+    use luminal::{
+        prelude::{InitModule, Module},
+        serialization::safetensors::{save, save_with_names},
+    };
+    luminal::test_imports!();
+
+    const W: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    const LORA_A: [f32; 6] = [0.5, -1., 0., 1., 2., -0.5];
+    const LORA_B: [f32; 4] = [1., 0., -1., 2.];
+
+    /// Save an adapter for a (2, 3) layer with a rank of 2 and a scale of 3
+    fn save_adapter(dir: &std::path::Path) {
+        let mut cx = Graph::new();
+        let model: LoraLinear<3, 2, 2> = InitModule::initialize(&mut cx);
+        model.lora_a.set(LORA_A.to_vec()).keep();
+        model.lora_b.set(LORA_B.to_vec()).keep();
+        model.base.weight.keep();
+        cx.execute();
+        save_with_names(dir.join("adapter_model.safetensors"), &model, &cx, |p| {
+            format!("base_model.model.{}", p.replace('/', "."))
+        })
+        .unwrap();
+        std::fs::write(
+            dir.join("adapter_config.json"),
+            r#"{"r": 2, "lora_alpha": 6, "target_modules": ["q_proj"]}"#,
+        )
+        .unwrap();
+    }
+
+    /// x W^T + 3 x A^T B^T
+    fn expected(x: &[f32]) -> Vec<f32> {
+        (0..2)
+            .map(|o| {
+                let base = (0..3).map(|i| x[i] * W[o * 3 + i]).sum::<f32>();
+                let delta = (0..2)
+                    .map(|r| {
+                        LORA_B[o * 2 + r] * (0..3).map(|i| x[i] * LORA_A[r * 3 + i]).sum::<f32>()
+                    })
+                    .sum::<f32>();
+                base + 3. * delta
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lora_linear() {
+        let mut cx = Graph::new();
+        let model: LoraLinear<3, 2, 2> = InitModule::initialize(&mut cx);
+        model.base.weight.set(W.to_vec());
+        let x = [1., -2., 0.5];
+        let mut out = model
+            .forward(cx.tensor::<R2<1, 3>>().set(x.to_vec()))
+            .retrieve();
+        // Zero initialized B leaves the base layer unchanged
+        cx.execute();
+        assert_close(&out.data(), &[-1.5, -3.]);
+        out.drop();
+
+        model.lora_a.set(LORA_A.to_vec());
+        model
+            .lora_b
+            .set(LORA_B.iter().map(|b| b * 3.).collect::<Vec<_>>());
+        cx.execute();
+        let unoptimized = out.data();
+        assert_close(&unoptimized, &expected(&x));
+
+        out.drop();
+        cx.compile(GenericCompiler::default(), &mut out);
+        cx.execute();
+        assert_close(&out.data(), &unoptimized);
+    }
+
+    #[test]
+    fn test_lora_adapter() {
+        let dir = temp_dir("lora_adapter");
+        save_adapter(&dir);
+        let checkpoint = dir.join("model.safetensors");
+        let mut cx = Graph::new();
+        let base: PermutedLinear<3, 2> = InitModule::initialize(&mut cx);
+        base.weight.set(W.to_vec()).keep();
+        cx.execute();
+        save(&checkpoint, &base, &cx).unwrap();
+        let x = [1., -2., 0.5];
+
+        // Kept separate at runtime
+        let mut cx = Graph::new();
+        let model: LoraLinear<3, 2, 2> = InitModule::initialize(&mut cx);
+        load_base(&checkpoint, &model, &mut cx).unwrap();
+        let config = load_adapter(&dir, &model, &mut cx).unwrap();
+        assert_eq!(config.scale(), 3.);
+        let out = model
+            .forward(cx.tensor::<R1<3>>().set(x.to_vec()))
+            .retrieve();
+        cx.execute();
+        assert_close(&out.data(), &expected(&x));
+
+        // Merged into the base weights
+        let mut cx = Graph::new();
+        let model: PermutedLinear<3, 2> = InitModule::initialize(&mut cx);
+        luminal::serialization::safetensors::load(&checkpoint, &model, &mut cx).unwrap();
+        merge_adapter(&dir, &model, &mut cx).unwrap();
+        let out = model
+            .forward(cx.tensor::<R1<3>>().set(x.to_vec()))
+            .retrieve();
+        cx.execute();
+        assert_close(&out.data(), &expected(&x));
+
+        // Adapters for layers the model doesn't have, or of the wrong rank, are errors
+        let mut cx = Graph::new();
+        let model: LoraLinear<3, 2, 1> = InitModule::initialize(&mut cx);
+        assert!(load_adapter(&dir, &model, &mut cx).is_err());
+        let model: PermutedLinear<2, 3> = InitModule::initialize(&mut cx);
+        assert!(merge_adapter(&dir, &model, &mut cx).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use luminal::prelude::*;
+
+/// A mixture of experts feed-forward layer with top-k routing, like Mixtral's top-2 routing.
+///
+/// A router scores every expert for each token, the `K` best scoring experts are picked, and their outputs are summed
+/// weighted by the softmax of their scores. The graph is static, so every expert runs on every token and the experts
+/// that weren't picked are weighted by 0.
+pub struct MixtureOfExperts<M, D: Dimension, E: Dimension, const K: usize> {
+    /// The router weight, laid out (experts, dim)
+    pub router: GraphTensor<(E, D)>,
+    pub experts: Vec<M>,
+}
+
+impl<M, D: Dimension, E: Dimension, const K: usize> MixtureOfExperts<M, D, E, K> {
+    pub fn new(router: GraphTensor<(E, D)>, experts: Vec<M>) -> Self {
+        assert!(
+            K > 0 && K <= experts.len(),
+            "Can't route to {K} of {} experts",
+            experts.len()
+        );
+        Self { router, experts }
+    }
+
+    /// The weight of each expert for each token, which is 0 for all but the top `K` experts
+    pub fn route<B: Dimension, S: Dimension>(
+        &self,
+        input: GraphTensor<(B, S, D)>,
+    ) -> GraphTensor<(B, S, E)> {
+        let scores = input.matmul(self.router.permute());
+        let (top, experts) = scores.topk::<(B, S, Const<K>), Axis<2>>(K);
+        unsafe { input.with_graph(|cx| cx.constant(0.)) }
+            .expand::<(B, S, E), _>()
+            .scatter_along::<Axis<2>, _>(experts, top.softmax::<Axis<2>>())
+    }
+}
+
+impl<M: InitModule, const D: usize, const E: usize, const K: usize> InitModule
+    for MixtureOfExperts<M, Const<D>, Const<E>, K>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self::new(
+            cx.named_tensor("Router"),
+            (0..E).map(|_| InitModule::initialize(cx)).collect(),
+        )
+    }
+}
+
+impl<M: SerializeModule, D: Dimension, E: Dimension, const K: usize> SerializeModule
+    for MixtureOfExperts<M, D, E, K>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("router", self.router);
+        for (i, expert) in self.experts.iter().enumerate() {
+            s.module(&format!("experts/{i}"), expert);
+        }
+    }
+}
+
+impl<
+        B: Dimension,
+        S: Dimension,
+        D: Dimension,
+        E: Dimension,
+        const K: usize,
+        M: Module<GraphTensor<(B, S, D)>, Output = GraphTensor<(B, S, D)>>,
+    > Module<GraphTensor<(B, S, D)>> for MixtureOfExperts<M, D, E, K>
+{
+    type Output = GraphTensor<(B, S, D)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, D)>) -> Self::Output {
+        let outputs = self
+            .experts
+            .iter()
+            .map(|e| e.forward(input))
+            .collect::<Vec<_>>();
+        let outputs = GraphTensor::stack::<(B, S, E, D), Axis<2>>(&outputs);
+        (outputs * self.route(input).expand::<_, Axis<3>>()).sum_reduce::<_, Axis<2>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MixtureOfExperts;
+    use crate::Linear;
+    use luminal::prelude::{InitModule, Module};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_mixture_of_experts() {
+        let mut cx = Graph::new();
+        let moe: MixtureOfExperts<Linear<2, 2>, LConst<2>, LConst<3>, 2> =
+            InitModule::initialize(&mut cx);
+        moe.router.set(vec![1., 0., 0., 1., -1., -3.]);
+        moe.experts[0].weight.set(vec![1., 0., 0., 1.]);
+        moe.experts[1].weight.set(vec![2., 0., 0., 2.]);
+        moe.experts[2].weight.set(vec![100., 0., 0., 100.]);
+        let input = cx.tensor::<R3<1, 2, 2>>().set(vec![1., 2., 3., -1.]);
+        let gates = moe.route(input).retrieve();
+        let out = moe.forward(input).retrieve();
+        cx.execute();
+
+        let softmax = |a: f32, b: f32| {
+            let (a, b) = (a.exp(), b.exp());
+            (a / (a + b), b / (a + b))
+        };
+        // The first token scores the experts 1, 2, -7 and the second 3, -1, 0
+        let (a0, a1) = softmax(1., 2.);
+        let (b0, b2) = softmax(3., 0.);
+        assert_close(&gates.data(), &[a0, a1, 0., b0, 0., b2]);
+        let (first, second) = (a0 + 2. * a1, b0 + 100. * b2);
+        assert_close(&out.data(), &[first, 2. * first, 3. * second, -second]);
+    }
+}
@@ -1,30 +1,56 @@
-use std::{marker::PhantomData, ops::Mul};
+use std::ops::Mul;
 
 use luminal::prelude::*;
 
-/// A simple layer norm layer. Calls `tensor.layer_norm::<DIM>()`.
-#[derive(Default)]
-pub struct LayerNorm<Ax: Axes>(PhantomData<Ax>);
+/// Layer norm over the last dimension with a learnable scale (`weight`, gamma) and shift (`bias`, beta). The
+/// normalization runs as a single `FusedLayerNorm` op.
+pub struct LayerNorm<const DIM: usize> {
+    pub weight: GraphTensor<R1<DIM>>,
+    pub bias: GraphTensor<R1<DIM>>,
+    pub epsilon: f32,
+}
 
-impl<Ax: Axes> InitModule for LayerNorm<Ax> {
-    fn initialize(_: &mut luminal::prelude::Graph) -> Self {
-        Self::default()
+impl<const DIM: usize> InitModule for LayerNorm<DIM> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("LayerNorm Weight").set(vec![1.0; DIM]),
+            bias: cx.named_tensor("LayerNorm Bias").set(vec![0.0; DIM]),
+            epsilon: 1e-5,
+        }
     }
 }
 
-impl<Ax: Axes, S: ConstShape> Module<GraphTensor<S>> for LayerNorm<Ax>
-where
-    S: ReduceShape<Ax>,
-    <S as ReduceShape<Ax>>::Reduced: ConstShape,
-{
-    type Output = GraphTensor<S>;
-    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
-        input.layer_norm::<Ax, _>(1e-5)
+impl<const DIM: usize> SerializeModule for LayerNorm<DIM> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+impl<const DIM: usize> Module<GraphTensor<R1<DIM>>> for LayerNorm<DIM> {
+    type Output = GraphTensor<R1<DIM>>;
+
+    fn forward(&self, input: GraphTensor<R1<DIM>>) -> Self::Output {
+        input.fused_layer_norm(self.epsilon) * self.weight + self.bias
+    }
+}
+
+impl<S: Dimension, const DIM: usize> Module<GraphTensor<(S, Const<DIM>)>> for LayerNorm<DIM> {
+    type Output = GraphTensor<(S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(S, Const<DIM>)>) -> Self::Output {
+        input.fused_layer_norm(self.epsilon) * self.weight.expand() + self.bias.expand()
     }
 }
 
-impl<Ax: Axes> SerializeModule for LayerNorm<Ax> {
-    fn serialize(&self, _: &mut Serializer) {}
+impl<B: Dimension, S: Dimension, const DIM: usize> Module<GraphTensor<(B, S, Const<DIM>)>>
+    for LayerNorm<DIM>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        input.fused_layer_norm(self.epsilon) * self.weight.expand() + self.bias.expand()
+    }
 }
 
 /// RMSNorm normalization
@@ -77,3 +103,232 @@ impl<B: Dimension, S: Dimension, const DIM: usize> Module<GraphTensor<(B, S, Con
             .mul(self.weight.expand())
     }
 }
+
+/// Layer norm over a last dimension whose size is set on the graph at runtime, with an optional shift
+pub struct DynLayerNorm<D: Dimension> {
+    pub weight: GraphTensor<(D,)>,
+    pub bias: Option<GraphTensor<(D,)>>,
+    pub epsilon: f32,
+}
+
+impl<D: Dimension> DynLayerNorm<D> {
+    pub fn new(bias: bool, epsilon: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("LayerNorm Weight"),
+            bias: bias.then(|| cx.named_tensor("LayerNorm Bias")),
+            epsilon,
+        }
+    }
+}
+
+impl<D: Dimension> SerializeModule for DynLayerNorm<D> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        if let Some(bias) = self.bias {
+            s.tensor("bias", bias);
+        }
+    }
+}
+
+impl<S: Dimension, D: Dimension> Module<GraphTensor<(S, D)>> for DynLayerNorm<D> {
+    type Output = GraphTensor<(S, D)>;
+
+    fn forward(&self, input: GraphTensor<(S, D)>) -> Self::Output {
+        let output = input.fused_layer_norm(self.epsilon) * self.weight.expand();
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
+impl<B: Dimension, S: Dimension, D: Dimension> Module<GraphTensor<(B, S, D)>> for DynLayerNorm<D> {
+    type Output = GraphTensor<(B, S, D)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, D)>) -> Self::Output {
+        let output = input.fused_layer_norm(self.epsilon) * self.weight.expand();
+        match self.bias {
+            Some(bias) => output + bias.expand(),
+            None => output,
+        }
+    }
+}
+
+/// RMSNorm over a last dimension whose size is set on the graph at runtime
+pub struct DynRMSNorm<D: Dimension> {
+    pub weight: GraphTensor<(D,)>,
+    pub epsilon: f32,
+    /// Added to the weight before scaling. Gemma stores its weights relative to 1, so it uses an offset of 1.
+    pub offset: f32,
+}
+
+impl<D: Dimension> DynRMSNorm<D> {
+    pub fn new(epsilon: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("RMSNorm Weight"),
+            epsilon,
+            offset: 0.,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: f32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    fn scale(&self) -> GraphTensor<(D,)> {
+        if self.offset == 0. {
+            self.weight
+        } else {
+            self.weight + self.offset
+        }
+    }
+}
+
+impl<D: Dimension> SerializeModule for DynRMSNorm<D> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+impl<S: Dimension, D: Dimension> Module<GraphTensor<(S, D)>> for DynRMSNorm<D> {
+    type Output = GraphTensor<(S, D)>;
+
+    fn forward(&self, input: GraphTensor<(S, D)>) -> Self::Output {
+        input
+            .std_norm::<Axis<1>, _>(self.epsilon)
+            .mul(self.scale().expand())
+    }
+}
+
+impl<B: Dimension, S: Dimension, D: Dimension> Module<GraphTensor<(B, S, D)>> for DynRMSNorm<D> {
+    type Output = GraphTensor<(B, S, D)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, D)>) -> Self::Output {
+        input
+            .std_norm::<Axis<2>, _>(self.epsilon)
+            .mul(self.scale().expand())
+    }
+}
+
+/// A layer norm or RMSNorm, for models that pick their norm in a config file
+#[allow(clippy::large_enum_variant)]
+pub enum DynNorm<D: Dimension> {
+    Layer(DynLayerNorm<D>),
+    RMS(DynRMSNorm<D>),
+}
+
+impl<D: Dimension> SerializeModule for DynNorm<D> {
+    fn serialize(&self, s: &mut Serializer) {
+        match self {
+            DynNorm::Layer(norm) => norm.serialize(s),
+            DynNorm::RMS(norm) => norm.serialize(s),
+        }
+    }
+}
+
+impl<S: Dimension, D: Dimension> Module<GraphTensor<(S, D)>> for DynNorm<D> {
+    type Output = GraphTensor<(S, D)>;
+
+    fn forward(&self, input: GraphTensor<(S, D)>) -> Self::Output {
+        match self {
+            DynNorm::Layer(norm) => norm.forward(input),
+            DynNorm::RMS(norm) => norm.forward(input),
+        }
+    }
+}
+
+impl<B: Dimension, S: Dimension, D: Dimension> Module<GraphTensor<(B, S, D)>> for DynNorm<D> {
+    type Output = GraphTensor<(B, S, D)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, D)>) -> Self::Output {
+        match self {
+            DynNorm::Layer(norm) => norm.forward(input),
+            DynNorm::RMS(norm) => norm.forward(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfdx::prelude::{Module as DfdxModule, *};
+    use luminal::{
+        prelude::{Const as LConst, Module, *},
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{DynLayerNorm, DynNorm, DynRMSNorm, LayerNorm, RMSNorm};
+
+    #[test]
+    fn test_layer_norm() {
+        let mut cx = Graph::new();
+        let (weight, bias, input) = (random_vec(4), random_vec(4), random_vec(2 * 3 * 4));
+        let model = LayerNorm::<4>::initialize(&mut cx);
+        model.weight.set(weight.clone());
+        model.bias.set(bias.clone());
+        let a = cx.tensor::<R3<2, 3, 4>>().set(input.clone());
+        let b = model.forward(a).retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let mut d_model = <dfdx::nn::modules::builders::LayerNorm1D<4>>::build_on_device(&d_dev);
+        d_model.gamma = d_dev.tensor_from_vec(weight, (dfdx::shapes::Const::<4>,));
+        d_model.beta = d_dev.tensor_from_vec(bias, (dfdx::shapes::Const::<4>,));
+        let d_a = d_dev.tensor_from_vec(
+            input,
+            (
+                dfdx::shapes::Const::<2>,
+                dfdx::shapes::Const::<3>,
+                dfdx::shapes::Const::<4>,
+            ),
+        );
+        let d_b = d_model.forward(d_a);
+
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+
+    #[test]
+    fn test_dyn_norms() {
+        let mut cx = Graph::new();
+        let (weight, bias, input) = (random_vec(4), random_vec(4), random_vec(2 * 3 * 4));
+        let (layer_norm, rms_norm) = (
+            LayerNorm::<4>::initialize(&mut cx),
+            RMSNorm::<4>::initialize(&mut cx),
+        );
+        let dyn_layer_norm = DynLayerNorm::<Dyn<'d'>>::new(true, 1e-5, &mut cx);
+        let dyn_rms_norm = DynRMSNorm::<Dyn<'d'>>::new(1e-6, &mut cx);
+        // An offset of 1 on weights stored relative to 1 is the same norm
+        let offset_rms_norm =
+            DynNorm::RMS(DynRMSNorm::<Dyn<'d'>>::new(1e-6, &mut cx).with_offset(1.));
+        for w in [layer_norm.weight, rms_norm.weight] {
+            w.set(weight.clone());
+        }
+        dyn_layer_norm.weight.set_dyn(weight.clone(), &[4]);
+        dyn_rms_norm.weight.set_dyn(weight.clone(), &[4]);
+        if let DynNorm::RMS(norm) = &offset_rms_norm {
+            norm.weight
+                .set_dyn(weight.iter().map(|w| w - 1.).collect::<Vec<_>>(), &[4]);
+        }
+        layer_norm.bias.set(bias.clone());
+        dyn_layer_norm.bias.unwrap().set_dyn(bias, &[4]);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(input.clone());
+        let dyn_a = cx
+            .tensor::<(LConst<2>, LConst<3>, Dyn<'d'>)>()
+            .set_dyn(input, &[2, 3, 4]);
+        let outputs = [
+            layer_norm.forward(a).retrieve(),
+            rms_norm.forward(a).retrieve(),
+        ];
+        let dyn_outputs = [
+            dyn_layer_norm.forward(dyn_a).retrieve(),
+            dyn_rms_norm.forward(dyn_a).retrieve(),
+        ];
+        let offset_output = offset_rms_norm.forward(dyn_a).retrieve();
+        cx.execute();
+
+        for (output, dyn_output) in outputs.iter().zip(&dyn_outputs) {
+            assert_close(&output.data(), &dyn_output.data());
+        }
+        assert_close(&outputs[1].data(), &offset_output.data());
+    }
+}
@@ -0,0 +1,159 @@
+use luminal::{
+    op::{AvgPooling2D, MaxPooling2D},
+    prelude::*,
+};
+
+/// 2D max pooling over the last two dimensions of a (batch, channels, dimx, dimy) input
+pub struct MaxPool2D<
+    const KERNELX: usize,
+    const KERNELY: usize,
+    const STRIDEX: usize,
+    const STRIDEY: usize,
+    const PADDINGX: usize = 0,
+    const PADDINGY: usize = 0,
+>;
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > InitModule for MaxPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    fn initialize(_: &mut Graph) -> Self {
+        Self
+    }
+}
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > SerializeModule for MaxPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > MaxPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    pub fn forward<
+        Batch: Dimension,
+        Channels: Dimension,
+        DimXIn: Dimension,
+        DimYIn: Dimension,
+        DimXOut: Dimension,
+        DimYOut: Dimension,
+    >(
+        &self,
+        input: GraphTensor<(Batch, Channels, DimXIn, DimYIn)>,
+    ) -> GraphTensor<(Batch, Channels, DimXOut, DimYOut)> {
+        input.max_pool2d(MaxPooling2D {
+            kernel: (KERNELX, KERNELY),
+            stride: (STRIDEX, STRIDEY),
+            padding: (PADDINGX, PADDINGY),
+        })
+    }
+}
+
+/// 2D average pooling over the last two dimensions of a (batch, channels, dimx, dimy) input. Padded elements count
+/// as zeros in the average.
+pub struct AvgPool2D<
+    const KERNELX: usize,
+    const KERNELY: usize,
+    const STRIDEX: usize,
+    const STRIDEY: usize,
+    const PADDINGX: usize = 0,
+    const PADDINGY: usize = 0,
+>;
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > InitModule for AvgPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    fn initialize(_: &mut Graph) -> Self {
+        Self
+    }
+}
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > SerializeModule for AvgPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl<
+        const KERNELX: usize,
+        const KERNELY: usize,
+        const STRIDEX: usize,
+        const STRIDEY: usize,
+        const PADDINGX: usize,
+        const PADDINGY: usize,
+    > AvgPool2D<KERNELX, KERNELY, STRIDEX, STRIDEY, PADDINGX, PADDINGY>
+{
+    pub fn forward<
+        Batch: Dimension,
+        Channels: Dimension,
+        DimXIn: Dimension,
+        DimYIn: Dimension,
+        DimXOut: Dimension,
+        DimYOut: Dimension,
+    >(
+        &self,
+        input: GraphTensor<(Batch, Channels, DimXIn, DimYIn)>,
+    ) -> GraphTensor<(Batch, Channels, DimXOut, DimYOut)> {
+        input.avg_pool2d(AvgPooling2D {
+            kernel: (KERNELX, KERNELY),
+            stride: (STRIDEX, STRIDEY),
+            padding: (PADDINGX, PADDINGY),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AvgPool2D, MaxPool2D};
+    use luminal::{prelude::*, tests::assert_close};
+
+    #[test]
+    fn test_pool2d() {
+        let mut cx = Graph::new();
+        let input = cx
+            .tensor::<R4<1, 2, 3, 4>>()
+            .set((0..24).map(|i| i as f32).collect::<Vec<_>>());
+
+        // A classic ResNet stem pool, and a global average pool
+        let max = MaxPool2D::<3, 3, 2, 2, 1, 1>::initialize(&mut cx)
+            .forward::<_, _, _, _, Const<2>, Const<2>>(input)
+            .retrieve();
+        let avg = AvgPool2D::<3, 4, 1, 1>::initialize(&mut cx)
+            .forward::<_, _, _, _, Const<1>, Const<1>>(input)
+            .retrieve();
+        cx.execute();
+
+        assert_close(&max.data(), &[5., 7., 9., 11., 17., 19., 21., 23.]);
+        assert_close(&avg.data(), &[5.5, 17.5]);
+    }
+}
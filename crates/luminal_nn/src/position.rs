@@ -0,0 +1,211 @@
+use std::f32::consts::FRAC_PI_2;
+
+use luminal::{prelude::*, tests::random_vec};
+
+/// Attention with linear biases (ALiBi), which replaces position embeddings with a per-head penalty on attention logits
+/// that grows linearly with the distance between a query and a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Alibi<const HEADS: usize>;
+
+impl<const HEADS: usize> Alibi<HEADS> {
+    /// The penalty per position of distance for each head, a geometric sequence starting at 2^(-8 / HEADS). When HEADS
+    /// isn't a power of two, the extra heads get every other slope of the sequence for twice as many heads.
+    pub fn slopes(&self) -> Vec<f32> {
+        let geometric = |n: usize| (1..=n).map(move |i| 2_f32.powf(-8. * i as f32 / n as f32));
+        let closest_power_of_two = if HEADS == 0 { 0 } else { 1 << HEADS.ilog2() };
+        geometric(closest_power_of_two)
+            .chain(
+                geometric(2 * closest_power_of_two)
+                    .step_by(2)
+                    .take(HEADS - closest_power_of_two),
+            )
+            .collect()
+    }
+
+    /// The bias added to each head's attention logits for `Sq` queries attending to `Sk` keys, where the queries are the
+    /// last `Sq` positions (the rest come from a KV cache)
+    pub fn bias<Sq: Dimension, Sk: Dimension>(
+        &self,
+        cx: &mut Graph,
+    ) -> GraphTensor<(Const<HEADS>, Sq, Sk)> {
+        let slopes = cx
+            .named_tensor::<(Const<HEADS>,)>("ALiBi Slopes")
+            .set(self.slopes());
+        let queries = cx.arange::<Sq>() + (Sk::const_size() - Sq::const_size());
+        let distance = (queries.expand::<(Sq, Sk), _>() - cx.arange::<Sk>().expand()).abs();
+        distance.expand::<(Const<HEADS>, Sq, Sk), _>() * slopes.expand() * -1.
+    }
+}
+
+// Applied to attention logits shaped (batch, heads, query seq, key seq)
+impl<B: Dimension, Sq: Dimension, Sk: Dimension, const HEADS: usize>
+    Module<GraphTensor<(B, Const<HEADS>, Sq, Sk)>> for Alibi<HEADS>
+{
+    type Output = GraphTensor<(B, Const<HEADS>, Sq, Sk)>;
+
+    fn forward(&self, weights: GraphTensor<(B, Const<HEADS>, Sq, Sk)>) -> Self::Output {
+        weights + unsafe { weights.with_graph(|cx| self.bias::<Sq, Sk>(cx)) }.expand()
+    }
+}
+
+/// Fixed sinusoidal position embeddings, added to (batch, seq, dim) inputs along with the number of positions that came
+/// before them. Even dimensions get `sin(pos / base^(i / DIM))` and odd ones the matching cosine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SinusoidalEmbedding<const DIM: usize> {
+    /// The base of the wavelengths
+    pub base: f32,
+}
+
+impl<const DIM: usize> Default for SinusoidalEmbedding<DIM> {
+    fn default() -> Self {
+        Self { base: 10000. }
+    }
+}
+
+impl<const DIM: usize> SinusoidalEmbedding<DIM> {
+    /// The frequency of each dimension
+    pub fn frequencies(&self) -> Vec<f32> {
+        (0..DIM)
+            .map(|i| self.base.powf(-((i - i % 2) as f32) / DIM as f32))
+            .collect()
+    }
+
+    /// The embeddings of `S` positions starting at `offset`
+    pub fn embeddings<S: Dimension>(
+        &self,
+        offset: BigExpression,
+        cx: &mut Graph,
+    ) -> GraphTensor<(S, Const<DIM>)> {
+        let freqs = cx
+            .named_tensor::<(Const<DIM>,)>("Sinusoidal Frequencies")
+            .set(self.frequencies());
+        // cos(x) = sin(x + pi / 2)
+        let phases = cx.named_tensor::<(Const<DIM>,)>("Sinusoidal Phases").set(
+            (0..DIM)
+                .map(|i| (i % 2) as f32 * FRAC_PI_2)
+                .collect::<Vec<_>>(),
+        );
+        let pos = cx.arange::<S>() + offset;
+        (pos.expand::<(S, Const<DIM>), _>() * freqs.expand() + phases.expand()).sin()
+    }
+}
+
+impl<B: Dimension, S: Dimension, const DIM: usize>
+    Module<(GraphTensor<(B, S, Const<DIM>)>, BigExpression)> for SinusoidalEmbedding<DIM>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (input, prev_seq): (GraphTensor<(B, S, Const<DIM>)>, BigExpression),
+    ) -> Self::Output {
+        input + unsafe { input.with_graph(|cx| self.embeddings::<S>(prev_seq, cx)) }.expand()
+    }
+}
+
+/// Learned absolute position embeddings for up to `MAX_POS` positions, added to (batch, seq, dim) inputs along with the
+/// number of positions that came before them
+pub struct LearnedPositionalEmbedding<const MAX_POS: usize, const DIM: usize> {
+    pub weight: GraphTensor<R2<MAX_POS, DIM>>,
+}
+
+impl<const MAX_POS: usize, const DIM: usize> InitModule
+    for LearnedPositionalEmbedding<MAX_POS, DIM>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            weight: cx
+                .named_tensor("Position Embedding Weight")
+                .set(random_vec(MAX_POS * DIM)),
+        }
+    }
+}
+
+impl<const MAX_POS: usize, const DIM: usize> SerializeModule
+    for LearnedPositionalEmbedding<MAX_POS, DIM>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+impl<B: Dimension, S: Dimension, const MAX_POS: usize, const DIM: usize>
+    Module<(GraphTensor<(B, S, Const<DIM>)>, BigExpression)>
+    for LearnedPositionalEmbedding<MAX_POS, DIM>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (input, prev_seq): (GraphTensor<(B, S, Const<DIM>)>, BigExpression),
+    ) -> Self::Output {
+        let positions = unsafe { input.with_graph(|cx| cx.arange::<S>()) } + prev_seq;
+        input + self.weight.gather(positions).expand()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::Module;
+
+    use super::{Alibi, LearnedPositionalEmbedding, SinusoidalEmbedding};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_alibi() {
+        assert_close(
+            &Alibi::<8>.slopes(),
+            &[1, 2, 3, 4, 5, 6, 7, 8].map(|i| 2_f32.powi(-i)),
+        );
+        // 4 heads' worth of slopes, then every other slope for 8 heads
+        assert_close(
+            &Alibi::<6>.slopes(),
+            &[2, 4, 6, 8, 1, 3].map(|i| 2_f32.powi(-i)),
+        );
+
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R4<1, 2, 2, 3>>().set(vec![0.; 12]);
+        let out = Alibi::<2>.forward(weights).retrieve();
+        cx.execute();
+
+        // The queries are at positions 1 and 2
+        let distances = [1., 0., 1., 2., 1., 0.];
+        let expected = [0.0625, 0.00390625]
+            .into_iter()
+            .flat_map(|slope| distances.map(|d| -d * slope))
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_position_embeddings() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 3 * 4);
+        let input = cx.tensor::<R3<2, 3, 4>>().set(input_data.clone());
+        let sinusoidal = SinusoidalEmbedding::<4>::default()
+            .forward((input, 2.into()))
+            .retrieve();
+        let learned: LearnedPositionalEmbedding<6, 4> = InitModule::initialize(&mut cx);
+        let weight = random_vec(24);
+        learned.weight.set(weight.clone());
+        let learned_out = learned.forward((input, 2.into())).retrieve();
+        cx.execute();
+
+        let mut expected_sinusoidal = vec![];
+        let mut expected_learned = vec![];
+        for (i, x) in input_data.iter().enumerate() {
+            let (pos, dim) = (2 + (i / 4) % 3, i % 4);
+            let angle = pos as f32 / 10000_f32.powf((dim - dim % 2) as f32 / 4.);
+            expected_sinusoidal.push(
+                x + if dim % 2 == 0 {
+                    angle.sin()
+                } else {
+                    angle.cos()
+                },
+            );
+            expected_learned.push(x + weight[pos * 4 + dim]);
+        }
+        assert_close(&sinusoidal.data(), &expected_sinusoidal);
+        assert_close(&learned_out.data(), &expected_learned);
+    }
+}
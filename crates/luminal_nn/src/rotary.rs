@@ -0,0 +1,347 @@
+use std::f32::consts::PI;
+
+use luminal::prelude::*;
+
+/// How rotary frequencies are scaled to stretch a model past the context length it was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RopeScaling {
+    #[default]
+    None,
+    /// Position interpolation: positions are divided by `factor`
+    Linear { factor: f32 },
+    /// NTK-aware scaling: the base is raised so low frequencies are interpolated and high frequencies are kept
+    Ntk { factor: f32 },
+    /// YaRN: frequencies that rotate fewer than `beta_slow` times over the original context are interpolated, ones that
+    /// rotate more than `beta_fast` times are kept, with a linear ramp in between. Attention is sharpened to match.
+    Yarn {
+        factor: f32,
+        original_max_position: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+    },
+}
+
+impl RopeScaling {
+    /// YaRN scaling with the usual ramp bounds
+    pub fn yarn(factor: f32, original_max_position: usize) -> Self {
+        Self::Yarn {
+            factor,
+            original_max_position,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        }
+    }
+
+    /// The rotation speed of each pair of dimensions of a head, for frequencies with base theta `base`
+    pub fn inverse_frequencies(&self, base: f32, head_dim: usize) -> Vec<f32> {
+        let base = match *self {
+            RopeScaling::Ntk { factor } => {
+                base * factor.powf(head_dim as f32 / (head_dim as f32 - 2.))
+            }
+            _ => base,
+        };
+        (0..head_dim / 2)
+            .map(|i| base.powf(-2. * i as f32 / head_dim as f32))
+            .map(|freq| match *self {
+                RopeScaling::None | RopeScaling::Ntk { .. } => freq,
+                RopeScaling::Linear { factor } => freq / factor,
+                RopeScaling::Yarn {
+                    factor,
+                    original_max_position,
+                    beta_fast,
+                    beta_slow,
+                } => {
+                    // How many full rotations this frequency makes over the original context
+                    let rotations = original_max_position as f32 * freq / (2. * PI);
+                    let keep = ((rotations - beta_slow) / (beta_fast - beta_slow)).clamp(0., 1.);
+                    freq / factor * (1. - keep) + freq * keep
+                }
+            })
+            .collect()
+    }
+
+    /// The factor the embeddings are scaled by, which YaRN uses to temper the attention softmax at long lengths
+    pub fn attention_scale(&self) -> f32 {
+        match *self {
+            RopeScaling::Yarn { factor, .. } if factor > 1. => 0.1 * factor.ln() + 1.,
+            _ => 1.,
+        }
+    }
+}
+
+/// Rotary position embeddings, applied to queries or keys shaped (batch, heads, seq, head dim) along with the number of
+/// positions that came before them. `HEAD_DIM_OVER_2` must be half of `HEAD_DIM`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotaryEmbedding<const HEAD_DIM: usize, const HEAD_DIM_OVER_2: usize> {
+    /// The base theta of the frequencies
+    pub base: f32,
+    pub scaling: RopeScaling,
+}
+
+impl<const HEAD_DIM: usize, const HEAD_DIM_OVER_2: usize> Default
+    for RotaryEmbedding<HEAD_DIM, HEAD_DIM_OVER_2>
+{
+    fn default() -> Self {
+        Self::new(10000.)
+    }
+}
+
+impl<const HEAD_DIM: usize, const HEAD_DIM_OVER_2: usize>
+    RotaryEmbedding<HEAD_DIM, HEAD_DIM_OVER_2>
+{
+    pub fn new(base: f32) -> Self {
+        assert_eq!(
+            HEAD_DIM,
+            HEAD_DIM_OVER_2 * 2,
+            "HEAD_DIM_OVER_2 must be half of HEAD_DIM"
+        );
+        Self {
+            base,
+            scaling: RopeScaling::None,
+        }
+    }
+
+    pub fn with_scaling(mut self, scaling: RopeScaling) -> Self {
+        self.scaling = scaling;
+        self
+    }
+
+    /// The rotation speed of each pair of dimensions, after scaling
+    pub fn inverse_frequencies(&self) -> Vec<f32> {
+        self.scaling.inverse_frequencies(self.base, HEAD_DIM)
+    }
+
+    /// The factor the embeddings are scaled by, which YaRN uses to temper the attention softmax at long lengths
+    pub fn attention_scale(&self) -> f32 {
+        self.scaling.attention_scale()
+    }
+}
+
+impl<
+        Batch: Dimension,
+        Heads: Dimension,
+        Seq: Dimension,
+        const HEAD_DIM: usize,
+        const HEAD_DIM_OVER_2: usize,
+    >
+    Module<(
+        GraphTensor<(Batch, Heads, Seq, Const<HEAD_DIM>)>,
+        BigExpression,
+    )> for RotaryEmbedding<HEAD_DIM, HEAD_DIM_OVER_2>
+{
+    type Output = GraphTensor<(Batch, Heads, Seq, Const<HEAD_DIM>)>;
+
+    fn forward(
+        &self,
+        (input, prev_seq): (
+            GraphTensor<(Batch, Heads, Seq, Const<HEAD_DIM>)>,
+            BigExpression,
+        ),
+    ) -> Self::Output {
+        let freqs = unsafe {
+            input.with_graph(|cx| cx.named_tensor::<(Const<HEAD_DIM_OVER_2>,)>("RoPE Frequencies"))
+        }
+        .set(self.inverse_frequencies());
+        input.rope_with_scale(freqs, prev_seq, self.attention_scale())
+    }
+}
+
+/// Rotary position embeddings for heads whose size is set at runtime, applied to queries or keys shaped (batch, heads,
+/// seq, head dim) along with the number of positions that came before them. `R` is half the number of rotated dims.
+pub struct DynRotaryEmbedding<R: Dimension> {
+    /// The rotation speed of each pair of rotated dims, after scaling
+    pub inv_freqs: GraphTensor<(R,)>,
+    /// The factor the embeddings are scaled by
+    pub scale: f32,
+    pub head_dim: usize,
+    /// How many dims of each head are rotated. The rest pass through unchanged.
+    pub rotary_dim: usize,
+    /// Rotate the first half of the rotated dims with the second half, the layout of HuggingFace checkpoints, rather
+    /// than adjacent pairs like GGUF and Meta checkpoints
+    pub split_halves: bool,
+}
+
+impl<R: Dimension> DynRotaryEmbedding<R> {
+    /// Rotate every dim of each head, with frequencies for base theta `base`
+    pub fn new(base: f32, scaling: RopeScaling, head_dim: usize, cx: &mut Graph) -> Self {
+        Self::partial(base, scaling, head_dim, head_dim, cx)
+    }
+
+    /// Rotate only the first `rotary_dim` dims of each head, as Phi-2 does
+    pub fn partial(
+        base: f32,
+        scaling: RopeScaling,
+        head_dim: usize,
+        rotary_dim: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            rotary_dim.is_multiple_of(2) && rotary_dim <= head_dim,
+            "The rotated dims must be even and fit in a head"
+        );
+        Self {
+            inv_freqs: cx.named_tensor("RoPE Frequencies").set_dyn(
+                scaling.inverse_frequencies(base, rotary_dim),
+                &[rotary_dim / 2],
+            ),
+            scale: scaling.attention_scale(),
+            head_dim,
+            rotary_dim,
+            split_halves: false,
+        }
+    }
+
+    pub fn with_split_halves(mut self) -> Self {
+        self.split_halves = true;
+        self
+    }
+}
+
+impl<R: Dimension, Batch: Dimension, Heads: Dimension, Seq: Dimension, HeadDim: Dimension>
+    Module<(GraphTensor<(Batch, Heads, Seq, HeadDim)>, BigExpression)> for DynRotaryEmbedding<R>
+{
+    type Output = GraphTensor<(Batch, Heads, Seq, HeadDim)>;
+
+    fn forward(
+        &self,
+        (input, prev_seq): (GraphTensor<(Batch, Heads, Seq, HeadDim)>, BigExpression),
+    ) -> Self::Output {
+        if self.rotary_dim == self.head_dim && !self.split_halves {
+            return input.rope_with_scale(self.inv_freqs, prev_seq, self.scale);
+        }
+        let dim = |axis: usize| input.shape.dims[input.shape.indexes[axis]];
+        let (batch, heads, seq) = (dim(0), dim(1), dim(2));
+        let (rotary_dim, half) = (
+            Expression::from(self.rotary_dim),
+            Expression::from(self.rotary_dim / 2),
+        );
+        let mut rotated = input
+            .slice((.., .., .., ..rotary_dim))
+            .contiguous()
+            .dyn_reshape::<(Batch, Heads, Seq, Dyn<'-'>)>(vec![batch, heads, seq, rotary_dim]);
+        // The rope op rotates adjacent pairs, so interleave the halves before rotating and split them back after
+        if self.split_halves {
+            rotated = rotated
+                .dyn_reshape::<(Batch, Heads, Seq, Const<2>, Dyn<'-'>)>(vec![
+                    batch,
+                    heads,
+                    seq,
+                    2.into(),
+                    half,
+                ])
+                .permute::<_, Axes5<0, 1, 2, 4, 3>>()
+                .dyn_reshape(vec![batch, heads, seq, rotary_dim]);
+        }
+        rotated = rotated.rope_with_scale(self.inv_freqs, prev_seq, self.scale);
+        if self.split_halves {
+            rotated = rotated
+                .dyn_reshape::<(Batch, Heads, Seq, Dyn<'-'>, Const<2>)>(vec![
+                    batch,
+                    heads,
+                    seq,
+                    half,
+                    2.into(),
+                ])
+                .permute::<_, Axes5<0, 1, 2, 4, 3>>()
+                .dyn_reshape(vec![batch, heads, seq, rotary_dim]);
+        }
+        if self.rotary_dim == self.head_dim {
+            return rotated.with_shape(rotated.shape);
+        }
+        rotated.concat_along::<_, Axis<3>, _>(input.slice((.., .., .., rotary_dim..)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::Module;
+
+    use super::{DynRotaryEmbedding, RopeScaling, RotaryEmbedding};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_rope_scaling_frequencies() {
+        let rope = RotaryEmbedding::<8, 4>::new(10000.);
+        assert_close(&rope.inverse_frequencies(), &[1., 0.1, 0.01, 0.001]);
+        let linear = rope.with_scaling(RopeScaling::Linear { factor: 4. });
+        assert_close(
+            &linear.inverse_frequencies(),
+            &[0.25, 0.025, 0.0025, 0.00025],
+        );
+        // The base is scaled by 4^(8/6)
+        let ntk = rope.with_scaling(RopeScaling::Ntk { factor: 4. });
+        let base = 10000. * 4_f32.powf(8. / 6.);
+        assert_close(
+            &ntk.inverse_frequencies(),
+            &[0, 1, 2, 3].map(|i| base.powf(-2. * i as f32 / 8.)),
+        );
+        // With an original context of 1000, the first frequency rotates ~159 times (kept), the last ~0.16 times (interpolated)
+        let yarn = rope.with_scaling(RopeScaling::yarn(4., 1000));
+        let freqs = yarn.inverse_frequencies();
+        assert_close(&[freqs[0], freqs[3]], &[1., 0.00025]);
+        assert!(freqs[2] > 0.0025 && freqs[2] < 0.01);
+        assert!((yarn.attention_scale() - (0.1 * 4_f32.ln() + 1.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rotary_embedding() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 3 * 4);
+        let input = cx.tensor::<R4<1, 2, 3, 4>>().set(input_data.clone());
+        let rope = RotaryEmbedding::<4, 2>::new(100.).with_scaling(RopeScaling::yarn(2., 8));
+        let out = rope.forward((input, 5.into())).retrieve();
+        cx.execute();
+
+        let freqs = rope.inverse_frequencies();
+        let scale = rope.attention_scale();
+        let mut expected = vec![];
+        for (i, pair) in input_data.chunks(2).enumerate() {
+            let pos = (5 + (i / 2) % 3) as f32;
+            let (sin, cos) = (pos * freqs[i % 2]).sin_cos();
+            expected.push((pair[0] * cos - pair[1] * sin) * scale);
+            expected.push((pair[0] * sin + pair[1] * cos) * scale);
+        }
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_dyn_rotary_embedding() {
+        let mut cx = Graph::new();
+        let input_data = random_vec(2 * 3 * 6);
+        let input = cx
+            .tensor::<(Dyn<'b'>, Dyn<'h'>, Dyn<'s'>, Dyn<'e'>)>()
+            .set_dyn(input_data.clone(), &[1, 2, 3, 6]);
+        let scaling = RopeScaling::Linear { factor: 2. };
+        let full = DynRotaryEmbedding::<Dyn<'r'>>::new(100., scaling, 6, &mut cx);
+        let partial = DynRotaryEmbedding::<Dyn<'o'>>::partial(100., scaling, 6, 4, &mut cx)
+            .with_split_halves();
+        let full_out = full.forward((input, 5.into())).retrieve();
+        let partial_out = partial.forward((input, 5.into())).retrieve();
+        cx.execute();
+
+        let (full_freqs, partial_freqs) = (
+            scaling.inverse_frequencies(100., 6),
+            scaling.inverse_frequencies(100., 4),
+        );
+        let (mut full_expected, mut partial_expected) = (vec![], vec![]);
+        for (i, head) in input_data.chunks(6).enumerate() {
+            let pos = (5 + i % 3) as f32;
+            // Adjacent pairs are rotated together
+            for (j, pair) in head.chunks(2).enumerate() {
+                let (sin, cos) = (pos * full_freqs[j]).sin_cos();
+                full_expected
+                    .extend([pair[0] * cos - pair[1] * sin, pair[0] * sin + pair[1] * cos]);
+            }
+            // Dims j and j + 2 are rotated together, and the last 2 dims pass through
+            let mut rotated = head.to_vec();
+            for j in 0..2 {
+                let (sin, cos) = (pos * partial_freqs[j]).sin_cos();
+                rotated[j] = head[j] * cos - head[j + 2] * sin;
+                rotated[j + 2] = head[j] * sin + head[j + 2] * cos;
+            }
+            partial_expected.extend(rotated);
+        }
+        assert_close(&full_out.data(), &full_expected);
+        assert_close(&partial_out.data(), &partial_expected);
+    }
+}
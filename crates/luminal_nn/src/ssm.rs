@@ -0,0 +1,252 @@
+use luminal::prelude::*;
+use rand::thread_rng;
+
+use crate::{Fans, Init};
+
+/// The selective state space model at the core of Mamba, run over a whole sequence.
+///
+/// Each channel of `x` (B, S, I) drives its own state of `N` values. Each step discretizes the continuous state matrix
+/// `a` (I, N) with that token's step size `dt` (B, S, I), then takes the input in through `b` (B, S, N) and reads it
+/// out through `c` (B, S, N):
+///
+/// `h_t = exp(dt_t * a) * h_(t-1) + dt_t * b_t * x_t`, `y_t = c_t . h_t + d * x_t`
+///
+/// The recurrence over the sequence is a single `SelectiveScan`, so the state is never materialized one step at a time.
+pub fn selective_state_space<B: Dimension, S: Dimension, I: Dimension, N: Dimension>(
+    x: GraphTensor<(B, S, I)>,
+    dt: GraphTensor<(B, S, I)>,
+    a: GraphTensor<(I, N)>,
+    b: GraphTensor<(B, S, N)>,
+    c: GraphTensor<(B, S, N)>,
+    d: GraphTensor<(I,)>,
+) -> GraphTensor<(B, S, I)> {
+    let decays = (dt.expand::<(B, S, I, N), Axis<3>>() * a.expand()).exp();
+    let inputs = (dt * x).expand::<(B, S, I, N), Axis<3>>() * b.expand::<_, Axis<2>>();
+    let states = decays.selective_scan::<Axis<1>>(inputs);
+    (states * c.expand::<_, Axis<2>>()).sum_reduce::<_, Axis<3>>() + x * d.expand()
+}
+
+/// A Mamba block, mapping (B, S, DIM) to (B, S, DIM) with a gated, selective state space model in place of attention.
+///
+/// The input is projected up to `I` channels twice: one branch goes through a short causal depthwise convolution of
+/// `K` steps and the state space model, and the other gates its output. The state space model's step size, input and
+/// output matrices are all projected from each token, which is what makes it selective. `X` is the size of that
+/// projection, `R + 2 * N`, for a step size of rank `R` and a state of `N` values per channel.
+///
+/// Weights follow HuggingFace's `MambaMixer` layout. The whole sequence is processed at once, with no recurrent state
+/// carried between calls.
+pub struct Mamba<
+    D: Dimension,
+    I: Dimension,
+    N: Dimension,
+    R: Dimension,
+    X: Dimension,
+    const K: usize,
+> {
+    /// The input projection, laid out (2, inner, dim). The first half feeds the state space model, the second gates it
+    pub in_proj: GraphTensor<(Const<2>, I, D)>,
+    /// The depthwise convolution weight, laid out (inner, kernel)
+    pub conv_weight: GraphTensor<(I, Const<K>)>,
+    pub conv_bias: GraphTensor<(I,)>,
+    /// Projects each token to its step size, input matrix and output matrix, laid out (R + 2 * N, inner)
+    pub x_proj: GraphTensor<(X, I)>,
+    /// Projects the low rank step size up to every channel, laid out (inner, R)
+    pub dt_proj: GraphTensor<(I, R)>,
+    pub dt_bias: GraphTensor<(I,)>,
+    /// The log of the negated continuous state matrix, so the state matrix `-exp(a_log)` always decays
+    pub a_log: GraphTensor<(I, N)>,
+    /// The skip connection of each channel
+    pub d: GraphTensor<(I,)>,
+    /// The output projection, laid out (dim, inner)
+    pub out_proj: GraphTensor<(D, I)>,
+}
+
+impl<D: Dimension, I: Dimension, N: Dimension, R: Dimension, X: Dimension, const K: usize>
+    Mamba<D, I, N, R, X, K>
+{
+    /// The causal depthwise convolution over the sequence, each step seeing itself and the `K - 1` steps before it
+    fn convolve<B: Dimension, S: Dimension>(
+        &self,
+        x: GraphTensor<(B, S, I)>,
+    ) -> GraphTensor<(B, S, I)> {
+        let windows = x
+            .permute::<(B, I, S), _>()
+            .pad::<(B, I, S), _, _>(&[(0, 0), (0, 0), (K - 1, 0)])
+            .contiguous()
+            .pool_last_dim::<(B, I, S, Const<K>)>(K.into(), 1.into(), 0);
+        (windows * self.conv_weight.expand::<_, Axes2<0, 2>>())
+            .sum_reduce::<_, Axis<3>>()
+            .permute::<(B, S, I), _>()
+            + self.conv_bias.expand()
+    }
+}
+
+impl<
+        const D: usize,
+        const I: usize,
+        const N: usize,
+        const R: usize,
+        const X: usize,
+        const K: usize,
+    > InitModule for Mamba<Const<D>, Const<I>, Const<N>, Const<R>, Const<X>, K>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        assert_eq!(X, R + 2 * N, "The x projection must hold R + 2 * N values");
+        fn weight<S: Shape>(
+            cx: &mut Graph,
+            name: &str,
+            fan_in: usize,
+            fan_out: usize,
+        ) -> GraphTensor<S> {
+            let weight = cx
+                .named_tensor(name)
+                .with_attribute(Fans { fan_in, fan_out });
+            Init::default().init(weight, &mut thread_rng())
+        }
+        Self {
+            in_proj: weight(cx, "In Proj", D, 2 * I),
+            conv_weight: weight(cx, "Conv Weight", K, K),
+            conv_bias: cx.named_tensor("Conv Bias").set(vec![0.; I]),
+            x_proj: weight(cx, "X Proj", I, X),
+            dt_proj: weight(cx, "Dt Proj", R, I),
+            // Step sizes start around 0.01, the inverse softplus of which is ln(exp(0.01) - 1)
+            dt_bias: cx
+                .named_tensor("Dt Bias")
+                .set(vec![0.01_f32.exp_m1().ln(); I]),
+            // Mamba's S4D-real initialization, where state n decays at a rate of n + 1
+            a_log: cx.named_tensor("A Log").set(
+                (0..I)
+                    .flat_map(|_| (1..=N).map(|n| (n as f32).ln()))
+                    .collect::<Vec<_>>(),
+            ),
+            d: cx.named_tensor("D").set(vec![1.; I]),
+            out_proj: weight(cx, "Out Proj", I, D),
+        }
+    }
+}
+
+impl<D: Dimension, I: Dimension, N: Dimension, R: Dimension, X: Dimension, const K: usize>
+    SerializeModule for Mamba<D, I, N, R, X, K>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("in_proj/weight", self.in_proj);
+        s.tensor("conv1d/weight", self.conv_weight);
+        s.tensor("conv1d/bias", self.conv_bias);
+        s.tensor("x_proj/weight", self.x_proj);
+        s.tensor("dt_proj/weight", self.dt_proj);
+        s.tensor("dt_proj/bias", self.dt_bias);
+        s.tensor("A_log", self.a_log);
+        s.tensor("D", self.d);
+        s.tensor("out_proj/weight", self.out_proj);
+    }
+}
+
+impl<
+        B: Dimension,
+        S: Dimension,
+        D: Dimension,
+        I: Dimension,
+        N: Dimension,
+        R: Dimension,
+        X: Dimension,
+        const K: usize,
+    > Module<GraphTensor<(B, S, D)>> for Mamba<D, I, N, R, X, K>
+{
+    type Output = GraphTensor<(B, S, D)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, D)>) -> Self::Output {
+        let [x_weight, gate_weight] = self.in_proj.unbind::<(I, D), Axis<0>>().try_into().unwrap();
+        let x = self.convolve(input.matmul(x_weight.permute())).swish();
+        let gate = input.matmul(gate_weight.permute()).swish();
+
+        // Split the projection into the step size, input matrix and output matrix of each token
+        let projected = x.matmul(self.x_proj.permute());
+        let dt = projected
+            .split::<(B, S, R), Axis<2>, _>(&[R::const_size()])
+            .remove(0);
+        let [_, b, c] = projected
+            .split::<(B, S, N), Axis<2>, _>(&[R::const_size(), N::const_size(), N::const_size()])
+            .try_into()
+            .unwrap();
+        // Softplus keeps the step sizes positive
+        let dt = ((dt.matmul(self.dt_proj.permute()) + self.dt_bias.expand()).exp() + 1.).ln();
+
+        let a = -self.a_log.exp();
+        let y = selective_state_space(x, dt, a, b, c, self.d);
+        (y * gate).matmul(self.out_proj.permute())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{selective_state_space, Mamba};
+    use luminal::prelude::{InitModule, Module};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_selective_state_space() {
+        let mut cx = Graph::new();
+        let data = [
+            random_vec(6),
+            random_vec(6),
+            random_vec(4),
+            random_vec(6),
+            random_vec(6),
+        ];
+        let [x, dt, a, b, c] = data.clone();
+        let dt = dt.iter().map(|d| d.abs()).collect::<Vec<_>>();
+        let out = selective_state_space(
+            cx.tensor::<R3<1, 3, 2>>().set(x.clone()),
+            cx.tensor::<R3<1, 3, 2>>().set(dt.clone()),
+            cx.tensor::<R2<2, 2>>().set(a.clone()),
+            cx.tensor::<R3<1, 3, 2>>().set(b.clone()),
+            cx.tensor::<R3<1, 3, 2>>().set(c.clone()),
+            cx.tensor::<R1<2>>().set(vec![0.5, -1.]),
+        )
+        .retrieve();
+        cx.execute();
+
+        // Step through the recurrence one token at a time
+        let mut h = [[0.; 2]; 2];
+        let mut expected = vec![];
+        for t in 0..3 {
+            for i in 0..2 {
+                let mut y = [0.5, -1.][i] * x[t * 2 + i];
+                for n in 0..2 {
+                    h[i][n] = (dt[t * 2 + i] * a[i * 2 + n]).exp() * h[i][n]
+                        + dt[t * 2 + i] * b[t * 2 + n] * x[t * 2 + i];
+                    y += c[t * 2 + n] * h[i][n];
+                }
+                expected.push(y);
+            }
+        }
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_mamba_causal() {
+        // Changing a token can only change the outputs at and after it
+        let mut cx = Graph::new();
+        let model: Mamba<LConst<4>, LConst<6>, LConst<3>, LConst<2>, LConst<8>, 3> =
+            InitModule::initialize(&mut cx);
+        let data = random_vec(20);
+        let mut changed = data.clone();
+        for v in &mut changed[12..16] {
+            *v += 1.;
+        }
+        let a = model
+            .forward(cx.tensor::<R3<1, 5, 4>>().set(data))
+            .retrieve();
+        let b = model
+            .forward(cx.tensor::<R3<1, 5, 4>>().set(changed))
+            .retrieve();
+        cx.execute();
+
+        let (a, b) = (a.data(), b.data());
+        assert_close(&a[..12], &b[..12]);
+        assert!(a[12..]
+            .iter()
+            .zip(&b[12..])
+            .any(|(a, b)| (a - b).abs() > 1e-4));
+    }
+}
@@ -1,6 +1,6 @@
-use std::ops::Mul;
+use std::{marker::PhantomData, ops::Mul};
 
-use crate::Linear;
+use crate::{AttentionMask, DynPermutedLinear, DynRotaryEmbedding, Linear};
 use luminal::prelude::*;
 
 /// Multi-head self attention as layed out in [*Attention Is All You Need*](https://arxiv.org/abs/1706.03762).
@@ -138,6 +138,38 @@ impl<
             GraphTensor<(B, S2, Const<DIM>)>,
             GraphTensor<(B, S1, Const<DIM>)>,
         ),
+    ) -> Self::Output {
+        self.forward((keys, queries, values, AttentionMask::none()))
+    }
+}
+
+// Batched different key-query-value, with a mask
+impl<
+        const DIM: usize,
+        const K_DIM: usize,
+        const V_DIM: usize,
+        const HEADS: usize,
+        S1: Dimension,
+        S2: Dimension,
+        B: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, S1, Const<DIM>)>,
+        GraphTensor<(B, S2, Const<DIM>)>,
+        GraphTensor<(B, S1, Const<DIM>)>,
+        AttentionMask<B, S2, S1>,
+    )> for MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    type Output = GraphTensor<(B, S2, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (keys, queries, values, mask): (
+            GraphTensor<(B, S1, Const<DIM>)>,
+            GraphTensor<(B, S2, Const<DIM>)>,
+            GraphTensor<(B, S1, Const<DIM>)>,
+            AttentionMask<B, S2, S1>,
+        ),
     ) -> Self::Output {
         let values = self
             .w_v
@@ -170,9 +202,12 @@ impl<
             ])
             .permute::<_, Axes4<0, 2, 1, 3>>();
 
-        let weights = queries
-            .matmul(keys)
-            .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32)
+        let weights = mask
+            .apply(
+                queries
+                    .matmul(keys)
+                    .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32),
+            )
             .softmax::<Axis<3>>();
 
         let tokens: GraphTensor<(B, S2, Const<V_DIM>)> = weights
@@ -183,15 +218,367 @@ impl<
     }
 }
 
+/// Scaled dot product attention where keys and values can have fewer heads than queries. Each key / value head is shared
+/// by a group of query heads, which covers grouped-query attention, multi-query attention (a single key / value head)
+/// and regular multi-head attention (as many key / value heads as query heads).
+///
+/// Queries are shaped (batch, heads, query seq, head dim) and keys and values (batch, kv heads, key seq, head dim). The
+/// number of query heads must be a multiple of the number of key / value heads.
+pub fn grouped_query_attention<
+    B: Dimension,
+    H: Dimension,
+    KVH: Dimension,
+    Sq: Dimension,
+    Sk: Dimension,
+    D: Dimension,
+>(
+    queries: GraphTensor<(B, H, Sq, D)>,
+    keys: GraphTensor<(B, KVH, Sk, D)>,
+    values: GraphTensor<(B, KVH, Sk, D)>,
+    mask: AttentionMask<B, Sq, Sk>,
+) -> GraphTensor<(B, H, Sq, D)> {
+    let dim = |shape: ShapeTracker, axis: usize| shape.dims[shape.indexes[axis]];
+    let (batch, heads, q_seq, head_dim) = (
+        dim(queries.shape, 0),
+        dim(queries.shape, 1),
+        dim(queries.shape, 2),
+        dim(queries.shape, 3),
+    );
+    let kv_heads = dim(keys.shape, 1);
+    let groups = heads / kv_heads;
+
+    // Split the query heads into the groups sharing each key / value head, and broadcast keys and values over the
+    // groups rather than copying them
+    let queries = queries
+        .dyn_reshape::<(B, KVH, Dyn<'-'>, Sq, D)>(vec![batch, kv_heads, groups, q_seq, head_dim]);
+    let repeat = |mut t: GraphTensor<(B, KVH, Sk, D)>| {
+        t.shape.expand(2, groups);
+        t.with_shape::<(B, KVH, Dyn<'-'>, Sk, D)>(t.shape)
+    };
+
+    let mut weights = queries.matmul(repeat(keys).permute::<_, Axes5<0, 1, 2, 4, 3>>());
+    weights = match head_dim.to_usize() {
+        Some(d) => weights * (1.0 / (d as f64).sqrt()) as f32,
+        None => {
+            let scale = unsafe { weights.with_graph(|cx| cx.constant_expr(head_dim)) }.sqrt();
+            weights / scale.expand_to(weights.shape)
+        }
+    };
+    if let Some(mask) = mask.additive() {
+        let mut mask = mask.reshape::<(B, Sq, Sk)>();
+        mask.shape.expand(1, kv_heads);
+        mask.shape.expand(2, groups);
+        weights += mask.with_shape(mask.shape);
+    }
+
+    weights
+        .softmax::<Axis<4>>()
+        .matmul(repeat(values))
+        // Merge the groups back into heads
+        .dyn_reshape(vec![batch, heads, q_seq, head_dim])
+}
+
+/// Multi-head attention where keys and values can have fewer heads than queries, as used by grouped-query attention
+/// (Llama 2 70B, Llama 3, Mistral) and multi-query attention. `Q_DIM` and `KV_DIM` are the query and key / value
+/// projection sizes, which are split into `HEADS` and `KV_HEADS` heads of the same size.
+pub struct MultiHeadAttention<
+    const DIM: usize,
+    const Q_DIM: usize,
+    const KV_DIM: usize,
+    const HEADS: usize,
+    const KV_HEADS: usize,
+> {
+    pub w_q: Linear<DIM, Q_DIM>,
+    pub w_k: Linear<DIM, KV_DIM>,
+    pub w_v: Linear<DIM, KV_DIM>,
+    pub w_o: Linear<Q_DIM, DIM>,
+}
+
+impl<
+        const DIM: usize,
+        const Q_DIM: usize,
+        const KV_DIM: usize,
+        const HEADS: usize,
+        const KV_HEADS: usize,
+    > InitModule for MultiHeadAttention<DIM, Q_DIM, KV_DIM, HEADS, KV_HEADS>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        assert_eq!(HEADS % KV_HEADS, 0, "HEADS must be a multiple of KV_HEADS");
+        assert_eq!(
+            Q_DIM / HEADS,
+            KV_DIM / KV_HEADS,
+            "Query and key / value heads must be the same size"
+        );
+        Self {
+            w_q: InitModule::initialize(cx),
+            w_k: InitModule::initialize(cx),
+            w_v: InitModule::initialize(cx),
+            w_o: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<
+        const DIM: usize,
+        const Q_DIM: usize,
+        const KV_DIM: usize,
+        const HEADS: usize,
+        const KV_HEADS: usize,
+    > SerializeModule for MultiHeadAttention<DIM, Q_DIM, KV_DIM, HEADS, KV_HEADS>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("w_q", &self.w_q);
+        s.module("w_k", &self.w_k);
+        s.module("w_v", &self.w_v);
+        s.module("w_o", &self.w_o);
+    }
+}
+
+// Batched self attention
+impl<
+        const DIM: usize,
+        const Q_DIM: usize,
+        const KV_DIM: usize,
+        const HEADS: usize,
+        const KV_HEADS: usize,
+        S: Dimension,
+        B: Dimension,
+    > Module<GraphTensor<(B, S, Const<DIM>)>>
+    for MultiHeadAttention<DIM, Q_DIM, KV_DIM, HEADS, KV_HEADS>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        self.forward((input, input, input, AttentionMask::none()))
+    }
+}
+
+// Batched different key-query-value, with a mask
+impl<
+        const DIM: usize,
+        const Q_DIM: usize,
+        const KV_DIM: usize,
+        const HEADS: usize,
+        const KV_HEADS: usize,
+        S1: Dimension,
+        S2: Dimension,
+        B: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, S1, Const<DIM>)>,
+        GraphTensor<(B, S2, Const<DIM>)>,
+        GraphTensor<(B, S1, Const<DIM>)>,
+        AttentionMask<B, S2, S1>,
+    )> for MultiHeadAttention<DIM, Q_DIM, KV_DIM, HEADS, KV_HEADS>
+{
+    type Output = GraphTensor<(B, S2, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (keys, queries, values, mask): (
+            GraphTensor<(B, S1, Const<DIM>)>,
+            GraphTensor<(B, S2, Const<DIM>)>,
+            GraphTensor<(B, S1, Const<DIM>)>,
+            AttentionMask<B, S2, S1>,
+        ),
+    ) -> Self::Output {
+        let head_dim = Expression::from(Q_DIM / HEADS);
+        let keys = self
+            .w_k
+            .forward(keys)
+            .dyn_reshape::<(B, S1, Const<KV_HEADS>, Dyn<'-'>)>(vec![
+                B::const_size(),
+                S1::const_size(),
+                KV_HEADS.into(),
+                head_dim,
+            ])
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let values = self
+            .w_v
+            .forward(values)
+            .dyn_reshape::<(B, S1, Const<KV_HEADS>, Dyn<'-'>)>(vec![
+                B::const_size(),
+                S1::const_size(),
+                KV_HEADS.into(),
+                head_dim,
+            ])
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let queries = self
+            .w_q
+            .forward(queries)
+            .dyn_reshape::<(B, S2, Const<HEADS>, Dyn<'-'>)>(vec![
+                B::const_size(),
+                S2::const_size(),
+                HEADS.into(),
+                head_dim,
+            ])
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        let tokens: GraphTensor<(B, S2, Const<Q_DIM>)> =
+            grouped_query_attention(queries, keys, values, mask)
+                .permute::<_, Axes4<0, 2, 1, 3>>()
+                .reshape();
+        self.w_o.forward(tokens)
+    }
+}
+
+/// `MultiHeadAttention` with sizes set on the graph at runtime, for models whose sizes come from a config file. Keys and
+/// values are appended to a cache of the positions before them, and queries and keys are rotated first if there's a
+/// `rotary` embedding. Projections are laid out (out, in), the way checkpoints store them.
+pub struct DynMultiHeadAttention<
+    Dim: Dimension,
+    QDim: Dimension,
+    KVDim: Dimension,
+    Heads: Dimension,
+    KVHeads: Dimension,
+    HeadDim: Dimension,
+    RotaryDimOver2: Dimension,
+> {
+    pub w_q: DynPermutedLinear<Dim, QDim>,
+    pub w_k: DynPermutedLinear<Dim, KVDim>,
+    pub w_v: DynPermutedLinear<Dim, KVDim>,
+    pub w_o: DynPermutedLinear<QDim, Dim>,
+    pub rotary: Option<DynRotaryEmbedding<RotaryDimOver2>>,
+    _phantom: PhantomData<(Heads, KVHeads, HeadDim)>,
+}
+
+impl<
+        Dim: Dimension,
+        QDim: Dimension,
+        KVDim: Dimension,
+        Heads: Dimension,
+        KVHeads: Dimension,
+        HeadDim: Dimension,
+        RotaryDimOver2: Dimension,
+    > DynMultiHeadAttention<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2>
+{
+    /// Attention with biases on the projections if `bias` is set, and `w_o`'s bias if `output_bias` is set
+    pub fn new(
+        bias: bool,
+        output_bias: bool,
+        rotary: Option<DynRotaryEmbedding<RotaryDimOver2>>,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            w_q: DynPermutedLinear::new(bias, cx),
+            w_k: DynPermutedLinear::new(bias, cx),
+            w_v: DynPermutedLinear::new(bias, cx),
+            w_o: DynPermutedLinear::new(output_bias, cx),
+            rotary,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        Dim: Dimension,
+        QDim: Dimension,
+        KVDim: Dimension,
+        Heads: Dimension,
+        KVHeads: Dimension,
+        HeadDim: Dimension,
+        RotaryDimOver2: Dimension,
+    > SerializeModule
+    for DynMultiHeadAttention<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("w_q", &self.w_q);
+        s.module("w_k", &self.w_k);
+        s.module("w_v", &self.w_v);
+        s.module("w_o", &self.w_o);
+    }
+}
+
+// Self attention over the current positions and the cached ones before them
+impl<
+        Dim: Dimension,
+        QDim: Dimension,
+        KVDim: Dimension,
+        Heads: Dimension,
+        KVHeads: Dimension,
+        HeadDim: Dimension,
+        RotaryDimOver2: Dimension,
+        B: Dimension,
+        CurSeq: Dimension,
+        PrevSeq: Dimension,
+        TotSeq: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, CurSeq, Dim)>,
+        (
+            GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+            GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+        ),
+        AttentionMask<B, CurSeq, TotSeq>,
+    )> for DynMultiHeadAttention<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2>
+{
+    /// The output, and the keys and values of every position for the cache
+    type Output = (
+        GraphTensor<(B, CurSeq, Dim)>,
+        (
+            GraphTensor<(B, KVHeads, TotSeq, HeadDim)>,
+            GraphTensor<(B, KVHeads, TotSeq, HeadDim)>,
+        ),
+    );
+
+    fn forward(
+        &self,
+        (x, (k_cache, v_cache), mask): (
+            GraphTensor<(B, CurSeq, Dim)>,
+            (
+                GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+                GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+            ),
+            AttentionMask<B, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        let queries = self
+            .w_q
+            .forward(x)
+            .reshape::<(B, CurSeq, Heads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let keys = self
+            .w_k
+            .forward(x)
+            .reshape::<(B, CurSeq, KVHeads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let values = self
+            .w_v
+            .forward(x)
+            .reshape::<(B, CurSeq, KVHeads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let (queries, keys) = match &self.rotary {
+            Some(rotary) => (
+                rotary.forward((queries, PrevSeq::const_size().into())),
+                rotary.forward((keys, PrevSeq::const_size().into())),
+            ),
+            None => (queries, keys),
+        };
+
+        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
+        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
+        let output = grouped_query_attention(queries, keys, values, mask)
+            // Merge heads
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(B, CurSeq, QDim)>();
+        // The cache needs to be contiguous for transferring to another graph
+        (
+            self.w_o.forward(output),
+            (keys.contiguous(), values.contiguous()),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dfdx::prelude::{Module as DfdxModule, *};
     use luminal::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec},
     };
 
-    use super::MultiHeadSelfAttention;
+    use super::{DynMultiHeadAttention, MultiHeadSelfAttention};
+    use crate::AttentionMask;
     #[test]
     fn test_self_attention() {
         let mut cx = Graph::new();
@@ -276,4 +663,112 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_grouped_query_attention() {
+        // 4 query heads sharing 2 key / value heads should match regular attention with each KV head repeated
+        let mut cx = Graph::new();
+        let gqa: super::MultiHeadAttention<4, 8, 4, 4, 2> = InitModule::initialize(&mut cx);
+        let mha: MultiHeadSelfAttention<4, 8, 8, 4> = InitModule::initialize(&mut cx);
+        let (q_data, k_data, v_data, o_data) = (
+            random_vec(4 * 8),
+            random_vec(4 * 4),
+            random_vec(4 * 4),
+            random_vec(8 * 4),
+        );
+        // Each query head h reads KV head h / 2, and heads are 2 wide
+        let repeat_heads = |w: &[f32]| {
+            (0..4)
+                .flat_map(|row| (0..8).map(move |col| w[row * 4 + (col / 4) * 2 + col % 2]))
+                .collect::<Vec<_>>()
+        };
+        gqa.w_q.weight.set(q_data.clone());
+        gqa.w_k.weight.set(k_data.clone());
+        gqa.w_v.weight.set(v_data.clone());
+        gqa.w_o.weight.set(o_data.clone());
+        mha.w_q.weight.set(q_data);
+        mha.w_k.weight.set(repeat_heads(&k_data));
+        mha.w_v.weight.set(repeat_heads(&v_data));
+        mha.w_o.weight.set(o_data);
+
+        let input = cx
+            .tensor::<(luminal::shape::Const<2>, Dyn<'s'>, luminal::shape::Const<4>)>()
+            .set_dyn(random_vec(2 * 3 * 4), &[2, 3, 4]);
+        let mask = AttentionMask::<luminal::shape::Const<2>, Dyn<'s'>, Dyn<'s'>>::causal(&mut cx);
+        let gqa_out = gqa.forward((input, input, input, mask)).retrieve();
+        let mha_out = mha.forward((input, input, input, mask)).retrieve();
+        cx.execute();
+
+        assert_close(&gqa_out.data(), &mha_out.data());
+    }
+
+    #[test]
+    fn test_dyn_attention_with_cache() {
+        // Attending over 1 position, then 2 more with the first in the cache, should match attending over all 3
+        let mut cx = Graph::new();
+        let gqa: super::MultiHeadAttention<4, 8, 4, 4, 2> = InitModule::initialize(&mut cx);
+        let dyn_gqa = DynMultiHeadAttention::<
+            Dyn<'d'>,
+            Dyn<'q'>,
+            Dyn<'a'>,
+            Dyn<'h'>,
+            Dyn<'k'>,
+            Dyn<'e'>,
+            Dyn<'r'>,
+        >::new(false, false, None, &mut cx);
+        // The dynamic projections are laid out (out, in)
+        let transpose = |w: &[f32], rows: usize, cols: usize| {
+            (0..cols)
+                .flat_map(|c| (0..rows).map(move |r| w[r * cols + c]))
+                .collect::<Vec<_>>()
+        };
+        let (q, k, v, o) = (
+            random_vec(4 * 8),
+            random_vec(4 * 4),
+            random_vec(4 * 4),
+            random_vec(8 * 4),
+        );
+        gqa.w_q.weight.set(q.clone());
+        gqa.w_k.weight.set(k.clone());
+        gqa.w_v.weight.set(v.clone());
+        gqa.w_o.weight.set(o.clone());
+        dyn_gqa.w_q.weight.set_dyn(transpose(&q, 4, 8), &[8, 4]);
+        dyn_gqa.w_k.weight.set_dyn(transpose(&k, 4, 4), &[4, 4]);
+        dyn_gqa.w_v.weight.set_dyn(transpose(&v, 4, 4), &[4, 4]);
+        dyn_gqa.w_o.weight.set_dyn(transpose(&o, 8, 4), &[4, 8]);
+
+        let input_data = random_vec(3 * 4);
+        let input = cx
+            .tensor::<(luminal::shape::Const<1>, Dyn<'t'>, luminal::shape::Const<4>)>()
+            .set_dyn(input_data.clone(), &[1, 3, 4]);
+        let mask = AttentionMask::causal(&mut cx);
+        let expected = gqa.forward((input, input, input, mask)).retrieve();
+
+        type Cache<S> = (
+            GraphTensor<(luminal::shape::Const<1>, Dyn<'k'>, S, Dyn<'e'>)>,
+            GraphTensor<(luminal::shape::Const<1>, Dyn<'k'>, S, Dyn<'e'>)>,
+        );
+        let empty: Cache<Dyn<'p'>> = (cx.tensor(), cx.tensor());
+        empty.0.set_dyn(vec![], &[1, 2, 0, 2]);
+        empty.1.set_dyn(vec![], &[1, 2, 0, 2]);
+        let first = cx
+            .tensor::<(luminal::shape::Const<1>, Dyn<'x'>, Dyn<'d'>)>()
+            .set_dyn(input_data[..4].to_vec(), &[1, 1, 4]);
+        let rest = cx
+            .tensor::<(luminal::shape::Const<1>, Dyn<'s'>, Dyn<'d'>)>()
+            .set_dyn(input_data[4..].to_vec(), &[1, 2, 4]);
+        let (first_out, cache): (_, Cache<Dyn<'x'>>) =
+            dyn_gqa.forward((first, empty, AttentionMask::causal(&mut cx)));
+        let (rest_out, cache): (_, Cache<Dyn<'t'>>) =
+            dyn_gqa.forward((rest, cache, AttentionMask::causal(&mut cx)));
+        let (first_out, rest_out) = (first_out.retrieve(), rest_out.retrieve());
+        cache.0.retrieve();
+        cx.set_dyn_dims([('d', 4), ('q', 8), ('a', 4), ('h', 4), ('k', 2), ('e', 2)]);
+        cx.execute();
+
+        let mut outputs = first_out.data();
+        outputs.extend(rest_out.data());
+        assert_close(&outputs, &expected.data());
+        assert_eq!(cache.0.data().len(), 2 * 3 * 2);
+    }
 }
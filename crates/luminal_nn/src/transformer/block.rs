@@ -0,0 +1,724 @@
+use crate::{DynPermutedLinear, DynRMSNorm, GeLU, LayerNorm, Linear};
+use luminal::prelude::*;
+
+use super::{
+    attention::{DynMultiHeadAttention, MultiHeadSelfAttention},
+    mask::AttentionMask,
+};
+
+/// Where a residual block applies its norm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormPosition {
+    /// Normalize the block's input and leave the residual stream untouched, like GPT-2 and llama
+    #[default]
+    Pre,
+    /// Normalize the sum of the block's input and output, like the original transformer and BERT
+    Post,
+}
+
+impl NormPosition {
+    /// Wrap `f` in a residual connection, normalized with `norm`
+    pub fn residual<B: Dimension, S: Dimension, const DIM: usize, N>(
+        self,
+        norm: &N,
+        x: GraphTensor<(B, S, Const<DIM>)>,
+        f: impl FnOnce(GraphTensor<(B, S, Const<DIM>)>) -> GraphTensor<(B, S, Const<DIM>)>,
+    ) -> GraphTensor<(B, S, Const<DIM>)>
+    where
+        N: Module<GraphTensor<(B, S, Const<DIM>)>, Output = GraphTensor<(B, S, Const<DIM>)>>,
+    {
+        match self {
+            NormPosition::Pre => x + f(norm.forward(x)),
+            NormPosition::Post => norm.forward(x + f(x)),
+        }
+    }
+}
+
+/// Multi-head attention with a norm and a residual connection. Used as self attention on a (batch, seq, dim) input with
+/// a mask, or as cross attention from an input to a memory (like encoder outputs) with a mask.
+pub struct AttentionBlock<const DIM: usize, const HEADS: usize, Norm = LayerNorm<DIM>> {
+    pub attention: MultiHeadSelfAttention<DIM, DIM, DIM, HEADS>,
+    pub norm: Norm,
+    pub norm_position: NormPosition,
+}
+
+impl<const DIM: usize, const HEADS: usize, Norm> AttentionBlock<DIM, HEADS, Norm> {
+    pub fn with_norm_position(mut self, norm_position: NormPosition) -> Self {
+        self.norm_position = norm_position;
+        self
+    }
+}
+
+impl<const DIM: usize, const HEADS: usize, Norm: InitModule> InitModule
+    for AttentionBlock<DIM, HEADS, Norm>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            attention: InitModule::initialize(cx),
+            norm: InitModule::initialize(cx),
+            norm_position: NormPosition::default(),
+        }
+    }
+}
+
+impl<const DIM: usize, const HEADS: usize, Norm: SerializeModule> SerializeModule
+    for AttentionBlock<DIM, HEADS, Norm>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attention", &self.attention);
+        s.module("norm", &self.norm);
+    }
+}
+
+// Self attention
+impl<B: Dimension, S: Dimension, const DIM: usize, const HEADS: usize, Norm>
+    Module<(GraphTensor<(B, S, Const<DIM>)>, AttentionMask<B, S, S>)>
+    for AttentionBlock<DIM, HEADS, Norm>
+where
+    Norm: Module<GraphTensor<(B, S, Const<DIM>)>, Output = GraphTensor<(B, S, Const<DIM>)>>,
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (x, mask): (GraphTensor<(B, S, Const<DIM>)>, AttentionMask<B, S, S>),
+    ) -> Self::Output {
+        self.norm_position
+            .residual(&self.norm, x, |x| self.attention.forward((x, x, x, mask)))
+    }
+}
+
+// Cross attention
+impl<B: Dimension, S1: Dimension, S2: Dimension, const DIM: usize, const HEADS: usize, Norm>
+    Module<(
+        GraphTensor<(B, S2, Const<DIM>)>,
+        GraphTensor<(B, S1, Const<DIM>)>,
+        AttentionMask<B, S2, S1>,
+    )> for AttentionBlock<DIM, HEADS, Norm>
+where
+    Norm: Module<GraphTensor<(B, S2, Const<DIM>)>, Output = GraphTensor<(B, S2, Const<DIM>)>>,
+{
+    type Output = GraphTensor<(B, S2, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (x, memory, mask): (
+            GraphTensor<(B, S2, Const<DIM>)>,
+            GraphTensor<(B, S1, Const<DIM>)>,
+            AttentionMask<B, S2, S1>,
+        ),
+    ) -> Self::Output {
+        self.norm_position.residual(&self.norm, x, |x| {
+            self.attention.forward((memory, x, memory, mask))
+        })
+    }
+}
+
+/// A two layer feed forward network with a norm and a residual connection
+pub struct MlpBlock<const DIM: usize, const FF: usize, Act = GeLU, Norm = LayerNorm<DIM>> {
+    pub ff: (Linear<DIM, FF>, Act, Linear<FF, DIM>),
+    pub norm: Norm,
+    pub norm_position: NormPosition,
+}
+
+impl<const DIM: usize, const FF: usize, Act, Norm> MlpBlock<DIM, FF, Act, Norm> {
+    pub fn with_norm_position(mut self, norm_position: NormPosition) -> Self {
+        self.norm_position = norm_position;
+        self
+    }
+}
+
+impl<const DIM: usize, const FF: usize, Act: InitModule, Norm: InitModule> InitModule
+    for MlpBlock<DIM, FF, Act, Norm>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            ff: InitModule::initialize(cx),
+            norm: InitModule::initialize(cx),
+            norm_position: NormPosition::default(),
+        }
+    }
+}
+
+impl<const DIM: usize, const FF: usize, Act: SerializeModule, Norm: SerializeModule> SerializeModule
+    for MlpBlock<DIM, FF, Act, Norm>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("ff", &self.ff);
+        s.module("norm", &self.norm);
+    }
+}
+
+impl<B: Dimension, S: Dimension, const DIM: usize, const FF: usize, Act, Norm>
+    Module<GraphTensor<(B, S, Const<DIM>)>> for MlpBlock<DIM, FF, Act, Norm>
+where
+    Act: Module<GraphTensor<(B, S, Const<FF>)>, Output = GraphTensor<(B, S, Const<FF>)>>,
+    Norm: Module<GraphTensor<(B, S, Const<DIM>)>, Output = GraphTensor<(B, S, Const<DIM>)>>,
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, x: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        self.norm_position
+            .residual(&self.norm, x, |x| self.ff.forward(x))
+    }
+}
+
+/// A transformer encoder layer: self attention followed by an MLP, taking a (batch, seq, dim) input and a mask
+pub struct TransformerEncoderLayer<
+    const DIM: usize,
+    const FF: usize,
+    const HEADS: usize,
+    Act = GeLU,
+    Norm = LayerNorm<DIM>,
+> {
+    pub attention: AttentionBlock<DIM, HEADS, Norm>,
+    pub mlp: MlpBlock<DIM, FF, Act, Norm>,
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize, Act, Norm>
+    TransformerEncoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    pub fn with_norm_position(self, norm_position: NormPosition) -> Self {
+        Self {
+            attention: self.attention.with_norm_position(norm_position),
+            mlp: self.mlp.with_norm_position(norm_position),
+        }
+    }
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize, Act: InitModule, Norm: InitModule>
+    InitModule for TransformerEncoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            attention: InitModule::initialize(cx),
+            mlp: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<
+        const DIM: usize,
+        const FF: usize,
+        const HEADS: usize,
+        Act: SerializeModule,
+        Norm: SerializeModule,
+    > SerializeModule for TransformerEncoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("mlp", &self.mlp);
+    }
+}
+
+impl<
+        B: Dimension,
+        S: Dimension,
+        const DIM: usize,
+        const FF: usize,
+        const HEADS: usize,
+        Act,
+        Norm,
+    > Module<(GraphTensor<(B, S, Const<DIM>)>, AttentionMask<B, S, S>)>
+    for TransformerEncoderLayer<DIM, FF, HEADS, Act, Norm>
+where
+    Act: Module<GraphTensor<(B, S, Const<FF>)>, Output = GraphTensor<(B, S, Const<FF>)>>,
+    Norm: Module<GraphTensor<(B, S, Const<DIM>)>, Output = GraphTensor<(B, S, Const<DIM>)>>,
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (x, mask): (GraphTensor<(B, S, Const<DIM>)>, AttentionMask<B, S, S>),
+    ) -> Self::Output {
+        self.mlp.forward(self.attention.forward((x, mask)))
+    }
+}
+
+/// A transformer decoder layer: self attention, cross attention to a memory (like encoder outputs), then an MLP. Takes
+/// a (batch, seq, dim) input, the memory, a self attention mask and a cross attention mask.
+pub struct TransformerDecoderLayer<
+    const DIM: usize,
+    const FF: usize,
+    const HEADS: usize,
+    Act = GeLU,
+    Norm = LayerNorm<DIM>,
+> {
+    pub self_attention: AttentionBlock<DIM, HEADS, Norm>,
+    pub cross_attention: AttentionBlock<DIM, HEADS, Norm>,
+    pub mlp: MlpBlock<DIM, FF, Act, Norm>,
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize, Act, Norm>
+    TransformerDecoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    pub fn with_norm_position(self, norm_position: NormPosition) -> Self {
+        Self {
+            self_attention: self.self_attention.with_norm_position(norm_position),
+            cross_attention: self.cross_attention.with_norm_position(norm_position),
+            mlp: self.mlp.with_norm_position(norm_position),
+        }
+    }
+}
+
+impl<const DIM: usize, const FF: usize, const HEADS: usize, Act: InitModule, Norm: InitModule>
+    InitModule for TransformerDecoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            self_attention: InitModule::initialize(cx),
+            cross_attention: InitModule::initialize(cx),
+            mlp: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<
+        const DIM: usize,
+        const FF: usize,
+        const HEADS: usize,
+        Act: SerializeModule,
+        Norm: SerializeModule,
+    > SerializeModule for TransformerDecoderLayer<DIM, FF, HEADS, Act, Norm>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.self_attention);
+        s.module("cross_attn", &self.cross_attention);
+        s.module("mlp", &self.mlp);
+    }
+}
+
+impl<
+        B: Dimension,
+        S1: Dimension,
+        S2: Dimension,
+        const DIM: usize,
+        const FF: usize,
+        const HEADS: usize,
+        Act,
+        Norm,
+    >
+    Module<(
+        GraphTensor<(B, S2, Const<DIM>)>,
+        GraphTensor<(B, S1, Const<DIM>)>,
+        AttentionMask<B, S2, S2>,
+        AttentionMask<B, S2, S1>,
+    )> for TransformerDecoderLayer<DIM, FF, HEADS, Act, Norm>
+where
+    Act: Module<GraphTensor<(B, S2, Const<FF>)>, Output = GraphTensor<(B, S2, Const<FF>)>>,
+    Norm: Module<GraphTensor<(B, S2, Const<DIM>)>, Output = GraphTensor<(B, S2, Const<DIM>)>>,
+{
+    type Output = GraphTensor<(B, S2, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (x, memory, self_mask, memory_mask): (
+            GraphTensor<(B, S2, Const<DIM>)>,
+            GraphTensor<(B, S1, Const<DIM>)>,
+            AttentionMask<B, S2, S2>,
+            AttentionMask<B, S2, S1>,
+        ),
+    ) -> Self::Output {
+        let x = self.self_attention.forward((x, self_mask));
+        let x = self.cross_attention.forward((x, memory, memory_mask));
+        self.mlp.forward(x)
+    }
+}
+
+/// A stack of `N` transformer layers, run in order. Masks (and the memory, for decoder layers) are passed to every layer.
+pub struct TransformerStack<T, const N: usize> {
+    pub layers: Vec<T>,
+}
+
+impl<T, const N: usize> TransformerStack<T, N> {
+    /// Apply a change to every layer, like `|l| l.with_norm_position(NormPosition::Post)`
+    pub fn map_layers(self, f: impl FnMut(T) -> T) -> Self {
+        Self {
+            layers: self.layers.into_iter().map(f).collect(),
+        }
+    }
+}
+
+impl<T: InitModule, const N: usize> InitModule for TransformerStack<T, N> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            layers: (0..N).map(|_| InitModule::initialize(cx)).collect(),
+        }
+    }
+}
+
+impl<T: SerializeModule, const N: usize> SerializeModule for TransformerStack<T, N> {
+    fn serialize(&self, s: &mut Serializer) {
+        for (i, l) in self.layers.iter().enumerate() {
+            s.module(&format!("layer{i}"), l);
+        }
+    }
+}
+
+// Encoder layers
+impl<X, M: Copy, T: Module<(X, M), Output = X>, const N: usize> Module<(X, M)>
+    for TransformerStack<T, N>
+{
+    type Output = X;
+
+    fn forward(&self, (mut x, mask): (X, M)) -> Self::Output {
+        for layer in &self.layers {
+            x = layer.forward((x, mask));
+        }
+        x
+    }
+}
+
+// Decoder layers
+impl<X, Mem: Copy, M1: Copy, M2: Copy, T: Module<(X, Mem, M1, M2), Output = X>, const N: usize>
+    Module<(X, Mem, M1, M2)> for TransformerStack<T, N>
+{
+    type Output = X;
+
+    fn forward(&self, (mut x, memory, self_mask, memory_mask): (X, Mem, M1, M2)) -> Self::Output {
+        for layer in &self.layers {
+            x = layer.forward((x, memory, self_mask, memory_mask));
+        }
+        x
+    }
+}
+
+/// The feed forward network of a decoder-only language model. GELUs use the tanh approximation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MlpKind {
+    /// Up projection, GELU, down projection, like Phi-2
+    Gelu,
+    /// A GELU gated linear unit, where a GELU of the gate projection scales the up projection, like Gemma
+    GeGlu,
+    /// The same with SiLU in place of GELU, like llama
+    SwiGlu,
+}
+
+/// A feed forward network with sizes set on the graph at runtime, and projections laid out (out, in)
+pub struct DynMlp<Dim: Dimension, Hidden: Dimension> {
+    pub up: DynPermutedLinear<Dim, Hidden>,
+    /// Gates the up projection in a gated MLP
+    pub gate: Option<DynPermutedLinear<Dim, Hidden>>,
+    pub down: DynPermutedLinear<Hidden, Dim>,
+    pub kind: MlpKind,
+}
+
+impl<Dim: Dimension, Hidden: Dimension> DynMlp<Dim, Hidden> {
+    pub fn new(kind: MlpKind, bias: bool, cx: &mut Graph) -> Self {
+        Self {
+            up: DynPermutedLinear::new(bias, cx),
+            gate: (kind != MlpKind::Gelu).then(|| DynPermutedLinear::new(bias, cx)),
+            down: DynPermutedLinear::new(bias, cx),
+            kind,
+        }
+    }
+}
+
+impl<Dim: Dimension, Hidden: Dimension> SerializeModule for DynMlp<Dim, Hidden> {
+    fn serialize(&self, s: &mut Serializer) {
+        if let Some(gate) = &self.gate {
+            s.module("gate", gate);
+        }
+        s.module("up", &self.up);
+        s.module("down", &self.down);
+    }
+}
+
+impl<B: Dimension, S: Dimension, Dim: Dimension, Hidden: Dimension> Module<GraphTensor<(B, S, Dim)>>
+    for DynMlp<Dim, Hidden>
+{
+    type Output = GraphTensor<(B, S, Dim)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Dim)>) -> Self::Output {
+        let up = self.up.forward(input);
+        let hidden = match &self.gate {
+            Some(gate) if self.kind == MlpKind::SwiGlu => gate.forward(input).swish() * up,
+            Some(gate) => gate.forward(input).gelu_tanh() * up,
+            None => up.gelu_tanh(),
+        };
+        self.down.forward(hidden)
+    }
+}
+
+/// A decoder-only language model layer with sizes set on the graph at runtime: pre-norm self attention over the new
+/// positions and a KV cache of the ones before them, then an MLP. Without an `mlp_norm`, attention and the MLP read
+/// the same normed input and are added to the residual stream together, like Phi-2's parallel residual.
+pub struct DynTransformerBlock<
+    Dim: Dimension,
+    QDim: Dimension,
+    KVDim: Dimension,
+    Heads: Dimension,
+    KVHeads: Dimension,
+    HeadDim: Dimension,
+    RotaryDimOver2: Dimension,
+    Hidden: Dimension,
+    Norm = DynRMSNorm<Dim>,
+> {
+    pub attention: DynMultiHeadAttention<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2>,
+    pub attention_norm: Norm,
+    pub mlp: DynMlp<Dim, Hidden>,
+    pub mlp_norm: Option<Norm>,
+}
+
+impl<
+        Dim: Dimension,
+        QDim: Dimension,
+        KVDim: Dimension,
+        Heads: Dimension,
+        KVHeads: Dimension,
+        HeadDim: Dimension,
+        RotaryDimOver2: Dimension,
+        Hidden: Dimension,
+        Norm: SerializeModule,
+    > SerializeModule
+    for DynTransformerBlock<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2, Hidden, Norm>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("attention_norm", &self.attention_norm);
+        s.module("mlp", &self.mlp);
+        if let Some(norm) = &self.mlp_norm {
+            s.module("mlp_norm", norm);
+        }
+    }
+}
+
+impl<
+        Dim: Dimension,
+        QDim: Dimension,
+        KVDim: Dimension,
+        Heads: Dimension,
+        KVHeads: Dimension,
+        HeadDim: Dimension,
+        RotaryDimOver2: Dimension,
+        Hidden: Dimension,
+        Norm,
+        B: Dimension,
+        CurSeq: Dimension,
+        PrevSeq: Dimension,
+        TotSeq: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, CurSeq, Dim)>,
+        (
+            GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+            GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+        ),
+        AttentionMask<B, CurSeq, TotSeq>,
+    )>
+    for DynTransformerBlock<Dim, QDim, KVDim, Heads, KVHeads, HeadDim, RotaryDimOver2, Hidden, Norm>
+where
+    Norm: Module<GraphTensor<(B, CurSeq, Dim)>, Output = GraphTensor<(B, CurSeq, Dim)>>,
+{
+    /// The output, and the keys and values of every position for the cache
+    type Output = (
+        GraphTensor<(B, CurSeq, Dim)>,
+        (
+            GraphTensor<(B, KVHeads, TotSeq, HeadDim)>,
+            GraphTensor<(B, KVHeads, TotSeq, HeadDim)>,
+        ),
+    );
+
+    fn forward(
+        &self,
+        (x, cache, mask): (
+            GraphTensor<(B, CurSeq, Dim)>,
+            (
+                GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+                GraphTensor<(B, KVHeads, PrevSeq, HeadDim)>,
+            ),
+            AttentionMask<B, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        let normed = self.attention_norm.forward(x);
+        let (y, cache) = self.attention.forward((normed, cache, mask));
+        match &self.mlp_norm {
+            Some(norm) => {
+                let x = x + y;
+                (x + self.mlp.forward(norm.forward(x)), cache)
+            }
+            None => (x + y + self.mlp.forward(normed), cache),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::Module;
+
+    use super::{
+        DynMlp, DynTransformerBlock, MlpKind, NormPosition, TransformerDecoderLayer,
+        TransformerEncoderLayer, TransformerStack,
+    };
+    use crate::{AttentionMask, DynMultiHeadAttention, DynRMSNorm, ReLU, TransformerEncoderBlock};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_post_norm_encoder_layer() {
+        // A post-norm layer with a ReLU MLP is the original encoder block
+        let mut cx = Graph::new();
+        let block: TransformerEncoderBlock<3, 4, 1> = InitModule::initialize(&mut cx);
+        let layer = <TransformerEncoderLayer<3, 4, 1, ReLU>>::initialize(&mut cx)
+            .with_norm_position(NormPosition::Post);
+        for (a, b) in [
+            (
+                block.attention.w_q.weight,
+                layer.attention.attention.w_q.weight,
+            ),
+            (
+                block.attention.w_k.weight,
+                layer.attention.attention.w_k.weight,
+            ),
+            (
+                block.attention.w_v.weight,
+                layer.attention.attention.w_v.weight,
+            ),
+            (
+                block.attention.w_o.weight,
+                layer.attention.attention.w_o.weight,
+            ),
+        ] {
+            let weight = random_vec(9);
+            a.set(weight.clone());
+            b.set(weight);
+        }
+        let ff = random_vec(12);
+        block.ff.0.weight.set(ff.clone());
+        layer.mlp.ff.0.weight.set(ff);
+        let ff = random_vec(12);
+        block.ff.2.weight.set(ff.clone());
+        layer.mlp.ff.2.weight.set(ff);
+
+        let input = cx.tensor::<R3<2, 3, 3>>().set(random_vec(18));
+        let expected = block.forward(input).retrieve();
+        let out = layer.forward((input, AttentionMask::none())).retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
+
+    #[test]
+    fn test_pre_norm_stack() {
+        let mut cx = Graph::new();
+        let encoder: TransformerStack<TransformerEncoderLayer<4, 8, 2>, 2> =
+            InitModule::initialize(&mut cx);
+        let decoder: TransformerStack<TransformerDecoderLayer<4, 8, 2>, 2> =
+            InitModule::initialize(&mut cx);
+        let input = cx.tensor::<R3<1, 3, 4>>().set(random_vec(12));
+        let target = cx.tensor::<R3<1, 2, 4>>().set(random_vec(8));
+        let memory = encoder.forward((input, AttentionMask::none()));
+        let out = decoder
+            .forward((
+                target,
+                memory,
+                AttentionMask::causal(&mut cx),
+                AttentionMask::none(),
+            ))
+            .retrieve();
+
+        // The same layers applied one at a time
+        let mut x = input;
+        for layer in &encoder.layers {
+            let h = x + layer
+                .attention
+                .attention
+                .forward(layer.attention.norm.forward(x));
+            x = h + layer.mlp.ff.forward(layer.mlp.norm.forward(h));
+        }
+        let memory = x;
+        let mut x = target;
+        for layer in &decoder.layers {
+            let normed = layer.self_attention.norm.forward(x);
+            let h = x + layer.self_attention.attention.forward((
+                normed,
+                normed,
+                normed,
+                AttentionMask::causal(&mut cx),
+            ));
+            let normed = layer.cross_attention.norm.forward(h);
+            let h = h + layer
+                .cross_attention
+                .attention
+                .forward((memory, normed, memory));
+            x = h + layer.mlp.ff.forward(layer.mlp.norm.forward(h));
+        }
+        let expected = x.retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
+
+    #[test]
+    fn test_dyn_transformer_block_residuals() {
+        // A block without an MLP norm adds attention and the MLP to the residual stream side by side, and one with
+        // an MLP norm runs them one after the other
+        let mut cx = Graph::new();
+        let block: DynTransformerBlock<
+            Dyn<'d'>,
+            Dyn<'d'>,
+            Dyn<'d'>,
+            Dyn<'h'>,
+            Dyn<'h'>,
+            Dyn<'e'>,
+            Dyn<'r'>,
+            Dyn<'f'>,
+        > = DynTransformerBlock {
+            attention: DynMultiHeadAttention::new(false, false, None, &mut cx),
+            attention_norm: DynRMSNorm::new(1e-6, &mut cx),
+            mlp: DynMlp::new(MlpKind::SwiGlu, false, &mut cx),
+            mlp_norm: None,
+        };
+        let mlp_norm = DynRMSNorm::new(1e-6, &mut cx);
+        let attention = &block.attention;
+        for w in [
+            &attention.w_q,
+            &attention.w_k,
+            &attention.w_v,
+            &attention.w_o,
+        ] {
+            w.weight.set_dyn(random_vec(16), &[4, 4]);
+        }
+        block.mlp.up.weight.set_dyn(random_vec(12), &[3, 4]);
+        block
+            .mlp
+            .gate
+            .as_ref()
+            .unwrap()
+            .weight
+            .set_dyn(random_vec(12), &[3, 4]);
+        block.mlp.down.weight.set_dyn(random_vec(12), &[4, 3]);
+        block.attention_norm.weight.set_dyn(random_vec(4), &[4]);
+        mlp_norm.weight.set_dyn(random_vec(4), &[4]);
+
+        type Cache<S> = (
+            GraphTensor<(luminal::shape::Const<1>, Dyn<'h'>, S, Dyn<'e'>)>,
+            GraphTensor<(luminal::shape::Const<1>, Dyn<'h'>, S, Dyn<'e'>)>,
+        );
+        let empty: Cache<Dyn<'p'>> = (cx.tensor(), cx.tensor());
+        empty.0.set_dyn(vec![], &[1, 2, 0, 2]);
+        empty.1.set_dyn(vec![], &[1, 2, 0, 2]);
+        let x = cx
+            .tensor::<(luminal::shape::Const<1>, Dyn<'s'>, Dyn<'d'>)>()
+            .set_dyn(random_vec(8), &[1, 2, 4]);
+        let mask = AttentionMask::<_, _, Dyn<'s'>>::causal(&mut cx);
+
+        let normed = block.attention_norm.forward(x);
+        let (attended, _): (_, Cache<Dyn<'s'>>) = block.attention.forward((normed, empty, mask));
+        let parallel_expected = (x + attended + block.mlp.forward(normed)).retrieve();
+        let residual = x + attended;
+        let sequential_expected =
+            (residual + block.mlp.forward(mlp_norm.forward(residual))).retrieve();
+        let (parallel, _): (_, Cache<Dyn<'s'>>) = block.forward((x, empty, mask));
+        let block = DynTransformerBlock {
+            mlp_norm: Some(mlp_norm),
+            ..block
+        };
+        let (sequential, _): (_, Cache<Dyn<'s'>>) = block.forward((x, empty, mask));
+        let (parallel, sequential) = (parallel.retrieve(), sequential.retrieve());
+        cx.set_dyn_dims([('d', 4), ('h', 2), ('e', 2), ('f', 3)]);
+        cx.execute();
+
+        assert_close(&parallel.data(), &parallel_expected.data());
+        assert_close(&sequential.data(), &sequential_expected.data());
+    }
+}
@@ -0,0 +1,167 @@
+use luminal::prelude::*;
+
+/// Masking for attention weights, supplied by the caller. Both parts are shaped (batch, 1, query seq, key seq) and are
+/// broadcast over heads.
+pub struct AttentionMask<B: Dimension, Sq: Dimension, Sk: Dimension> {
+    /// Added to the attention logits, for causal or prefix-LM masks and relative position biases
+    pub bias: Option<GraphTensor<(B, Const<1>, Sq, Sk)>>,
+    /// 1 where a query may attend to a key and 0 where it may not, like for padding tokens
+    pub padding: Option<GraphTensor<(B, Const<1>, Sq, Sk)>>,
+}
+
+impl<B: Dimension, Sq: Dimension, Sk: Dimension> Clone for AttentionMask<B, Sq, Sk> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B: Dimension, Sq: Dimension, Sk: Dimension> Copy for AttentionMask<B, Sq, Sk> {}
+
+impl<B: Dimension, Sq: Dimension, Sk: Dimension> Default for AttentionMask<B, Sq, Sk> {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl<B: Dimension, Sq: Dimension, Sk: Dimension> AttentionMask<B, Sq, Sk> {
+    /// Every query attends to every key
+    pub fn none() -> Self {
+        Self {
+            bias: None,
+            padding: None,
+        }
+    }
+
+    /// A causal mask for `Sq` new queries attending to `Sk` keys, where the first `Sk - Sq` keys come from earlier
+    /// positions (like a KV cache) and are always visible
+    pub fn causal(cx: &mut Graph) -> Self {
+        let mask = (cx.triu::<Sq>(1) * f16::MIN.to_f32()).pad::<(Sq, Sk), _, _>(&[
+            (0.into(), Expression::from(0)),
+            (Sk::const_size() - Sq::const_size(), 0.into()),
+        ]);
+        Self::none().with_bias(mask.expand::<(B, Const<1>, Sq, Sk), _>())
+    }
+
+    /// A causal mask where each query only sees the last `window` keys up to and including its own position, like
+    /// Mistral's sliding window attention. As with `causal`, the first `Sk - Sq` keys come from earlier positions.
+    pub fn sliding_window(cx: &mut Graph, window: usize) -> Self {
+        let queries = (cx.arange::<Sq>()
+            + cx.constant_expr(Sk::const_size() - Sq::const_size())
+                .expand())
+        .expand::<(Sq, Sk), Axis<1>>();
+        let keys = cx.arange::<Sk>().expand::<(Sq, Sk), Axis<0>>();
+        // How many positions back each key is from each query
+        let distance = queries - keys;
+        let visible = distance.ge_mask(cx.constant(0.).expand())
+            * distance.lt_mask(cx.constant(window as f32).expand());
+        let mask = (1. - visible) * f16::MIN.to_f32();
+        Self::none().with_bias(mask.expand::<(B, Const<1>, Sq, Sk), _>())
+    }
+
+    pub fn with_bias(mut self, bias: GraphTensor<(B, Const<1>, Sq, Sk)>) -> Self {
+        self.bias = Some(match self.bias {
+            Some(b) => b + bias,
+            None => bias,
+        });
+        self
+    }
+
+    pub fn with_padding(mut self, padding: GraphTensor<(B, Const<1>, Sq, Sk)>) -> Self {
+        self.padding = Some(match self.padding {
+            Some(p) => p * padding,
+            None => padding,
+        });
+        self
+    }
+
+    /// Mask out padding keys, given a mask shaped (batch, key seq) that's 1 for real tokens and 0 for padding, like
+    /// `PaddedBatch::key_mask`
+    pub fn with_key_padding(self, key_mask: GraphTensor<(B, Sk)>) -> Self {
+        self.with_padding(key_mask.expand::<(B, Const<1>, Sq, Sk), _>())
+    }
+
+    /// The whole mask as a single bias to add to the attention logits, if there is any masking
+    pub fn additive(&self) -> Option<GraphTensor<(B, Const<1>, Sq, Sk)>> {
+        // Large negatives that stay finite in half precision
+        let padding = self.padding.map(|p| (1. - p) * f16::MIN.to_f32());
+        match (self.bias, padding) {
+            (Some(bias), Some(padding)) => Some(bias + padding),
+            (bias, padding) => bias.or(padding),
+        }
+    }
+
+    /// Apply the mask to attention logits shaped (batch, heads, query seq, key seq)
+    pub fn apply<H: Dimension>(
+        &self,
+        weights: GraphTensor<(B, H, Sq, Sk)>,
+    ) -> GraphTensor<(B, H, Sq, Sk)> {
+        match self.additive() {
+            Some(mask) => weights + mask.reshape::<(B, Sq, Sk)>().expand(),
+            None => weights,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AttentionMask;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_attention_mask() {
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R4<2, 1, 2, 3>>().set(vec![0.; 12]);
+        // The second sequence has a padding token at the start
+        let padding = cx
+            .tensor::<R4<2, 1, 2, 3>>()
+            .set(vec![1., 1., 1., 1., 1., 1., 0., 1., 1., 0., 1., 1.]);
+        let bias = cx.tensor::<R4<2, 1, 2, 3>>().set(vec![0.5; 12]);
+        let mask = AttentionMask::causal(&mut cx)
+            .with_padding(padding)
+            .with_bias(bias);
+        let out = mask
+            .apply(weights)
+            .softmax::<luminal::prelude::Axis<3>>()
+            .retrieve();
+        cx.execute();
+
+        let third = 1. / 3.;
+        let expected = [
+            [0.5, 0.5, 0., third, third, third],
+            [0., 1., 0., 0., 0.5, 0.5],
+        ];
+        assert_close(&out.data(), &expected.concat());
+    }
+
+    #[test]
+    fn test_key_padding() {
+        let mut cx = Graph::new();
+        let batch = PaddedBatch::left_padded(&[vec![1, 2, 3], vec![4, 5]], 0);
+        let weights = cx.tensor::<R4<2, 1, 2, 3>>().set(vec![0.; 12]);
+        let key_mask = cx.tensor::<R2<2, 3>>().set(batch.key_mask());
+        let out = AttentionMask::none()
+            .with_key_padding(key_mask)
+            .apply(weights)
+            .softmax::<luminal::prelude::Axis<3>>()
+            .retrieve();
+        cx.execute();
+
+        let third = 1. / 3.;
+        let expected = [[third; 6], [0., 0.5, 0.5, 0., 0.5, 0.5]];
+        assert_close(&out.data(), &expected.concat());
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut cx = Graph::new();
+        // Two new queries after two cached keys, with a window of 2
+        let weights = cx.tensor::<R4<1, 1, 2, 4>>().set(vec![0.; 8]);
+        let out = AttentionMask::sliding_window(&mut cx, 2)
+            .apply(weights)
+            .softmax::<luminal::prelude::Axis<3>>()
+            .retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &[0., 0.5, 0.5, 0., 0., 0., 0.5, 0.5]);
+    }
+}
@@ -2,10 +2,16 @@ use luminal::prelude::*;
 
 mod attention;
 pub use attention::*;
+mod block;
+pub use block::*;
 mod decoder;
 pub use decoder::*;
 mod encoder;
 pub use encoder::*;
+mod mask;
+pub use mask::*;
+mod paged;
+pub use paged::*;
 
 pub struct Transformer<
     const DIM: usize,
@@ -0,0 +1,310 @@
+use luminal::prelude::*;
+
+use super::{grouped_query_attention, AttentionMask};
+
+/// A KV cache split into fixed-size blocks of token rows, shared between sequences.
+///
+/// Each sequence has a block table listing the blocks holding its tokens in order, and takes a new block only when its
+/// last one fills up, so memory isn't reserved for the longest possible context up front. Every layer has its own pool
+/// of key and value rows, shaped (pool rows, kv dim), which grows a block at a time as blocks are first used. The same
+/// block tables are used for every layer.
+///
+/// Attention reads a sequence's keys and values out of the pools through `slots`, see `paged_attention`.
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    pub block_size: usize,
+    pub kv_dim: usize,
+    blocks: CacheSlots,
+    /// The block table and length of each sequence, or None for freed sequence ids
+    tables: Vec<Option<(Vec<usize>, usize)>>,
+    /// The key and value pools of each layer
+    pools: Vec<(Vec<f32>, Vec<f32>)>,
+}
+
+impl PagedKvCache {
+    pub fn new(layers: usize, kv_dim: usize, block_size: usize, max_blocks: usize) -> Self {
+        assert!(block_size > 0, "Blocks must hold at least one token");
+        Self {
+            block_size,
+            kv_dim,
+            blocks: CacheSlots::new(max_blocks),
+            tables: vec![],
+            pools: vec![(vec![], vec![]); layers],
+        }
+    }
+
+    /// Start a new, empty sequence, returning its id. Ids of freed sequences are reused.
+    pub fn add_sequence(&mut self) -> usize {
+        let id = self
+            .tables
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or_else(|| {
+                self.tables.push(None);
+                self.tables.len() - 1
+            });
+        self.tables[id] = Some((vec![], 0));
+        id
+    }
+
+    /// Free a sequence's blocks for other sequences to use
+    pub fn free_sequence(&mut self, sequence: usize) {
+        let (blocks, _) = self.tables[sequence]
+            .take()
+            .expect("Sequence was already freed");
+        for block in blocks {
+            self.blocks.release(block);
+        }
+    }
+
+    fn table(&self, sequence: usize) -> &(Vec<usize>, usize) {
+        self.tables[sequence]
+            .as_ref()
+            .expect("Sequence has been freed")
+    }
+
+    /// The number of tokens cached for a sequence
+    pub fn len(&self, sequence: usize) -> usize {
+        self.table(sequence).1
+    }
+
+    /// The blocks holding a sequence's tokens, in order
+    pub fn block_table(&self, sequence: usize) -> &[usize] {
+        &self.table(sequence).0
+    }
+
+    /// The number of blocks not used by any sequence
+    pub fn free_blocks(&self) -> usize {
+        self.blocks.available()
+    }
+
+    /// Whether `tokens` more tokens fit in a sequence, taking new blocks if needed
+    pub fn can_append(&self, sequence: usize, tokens: usize) -> bool {
+        let (blocks, len) = self.table(sequence);
+        (len + tokens).div_ceil(self.block_size) - blocks.len() <= self.blocks.available()
+    }
+
+    /// Cache the keys and values of new tokens for a sequence, given as (keys, values) for each layer, each shaped
+    /// (tokens, kv dim). Returns false without changing anything if there aren't enough free blocks.
+    pub fn append(&mut self, sequence: usize, layers: &[(Vec<f32>, Vec<f32>)]) -> bool {
+        assert_eq!(
+            layers.len(),
+            self.pools.len(),
+            "Need keys and values for every layer"
+        );
+        let tokens = layers[0].0.len() / self.kv_dim;
+        assert!(
+            layers
+                .iter()
+                .all(|(k, v)| k.len() == tokens * self.kv_dim && v.len() == tokens * self.kv_dim),
+            "Every layer needs keys and values for the same number of tokens"
+        );
+        if !self.can_append(sequence, tokens) {
+            return false;
+        }
+        let (block_size, kv_dim) = (self.block_size, self.kv_dim);
+        let (blocks, len) = self.tables[sequence].as_mut().unwrap();
+        while blocks.len() * block_size < *len + tokens {
+            let block = self.blocks.acquire().unwrap();
+            blocks.push(block);
+            // Grow the pools to hold the new block
+            let rows = (block + 1) * block_size * kv_dim;
+            for (k, v) in &mut self.pools {
+                if k.len() < rows {
+                    k.resize(rows, 0.);
+                    v.resize(rows, 0.);
+                }
+            }
+        }
+        for t in 0..tokens {
+            let pos = *len + t;
+            let row = blocks[pos / block_size] * block_size + pos % block_size;
+            let (dst, src) = (
+                row * kv_dim..(row + 1) * kv_dim,
+                t * kv_dim..(t + 1) * kv_dim,
+            );
+            for ((k, v), (new_k, new_v)) in self.pools.iter_mut().zip(layers) {
+                k[dst.clone()].copy_from_slice(&new_k[src.clone()]);
+                v[dst.clone()].copy_from_slice(&new_v[src.clone()]);
+            }
+        }
+        *len += tokens;
+        true
+    }
+
+    /// The pool row of each of a sequence's tokens, in order, as data for the index tensor of `paged_attention`
+    pub fn slots(&self, sequence: usize) -> Vec<f32> {
+        let (blocks, len) = self.table(sequence);
+        (0..*len)
+            .map(|p| (blocks[p / self.block_size] * self.block_size + p % self.block_size) as f32)
+            .collect()
+    }
+
+    /// A layer's key pool, shaped (pool rows, kv dim)
+    pub fn keys(&self, layer: usize) -> &[f32] {
+        &self.pools[layer].0
+    }
+
+    /// A layer's value pool, shaped (pool rows, kv dim)
+    pub fn values(&self, layer: usize) -> &[f32] {
+        &self.pools[layer].1
+    }
+
+    /// The number of rows in each pool
+    pub fn pool_rows(&self) -> usize {
+        self.pools
+            .first()
+            .map(|(k, _)| k.len() / self.kv_dim)
+            .unwrap_or_default()
+    }
+
+    /// A sequence's keys and values for a layer, copied out in order as (tokens, kv dim)
+    pub fn gather(&self, sequence: usize, layer: usize) -> (Vec<f32>, Vec<f32>) {
+        let (k, v) = &self.pools[layer];
+        let rows = self.slots(sequence);
+        let copy = |pool: &[f32]| {
+            rows.iter()
+                .flat_map(|r| &pool[*r as usize * self.kv_dim..(*r as usize + 1) * self.kv_dim])
+                .copied()
+                .collect()
+        };
+        (copy(k), copy(v))
+    }
+}
+
+/// Attention for one sequence over keys and values read from a `PagedKvCache` through its block table.
+///
+/// Queries are shaped (heads, query seq, head dim), the pools are a layer's key and value pools shaped (pool rows,
+/// kv dim), and `slots` holds the pool row of each of the sequence's tokens (`PagedKvCache::slots`). The kv dim is
+/// split into `KV_HEADS` heads shared by groups of query heads like `grouped_query_attention`.
+pub fn paged_attention<
+    const KV_HEADS: usize,
+    const KV_DIM: usize,
+    H: Dimension,
+    Sq: Dimension,
+    Sk: Dimension,
+    D: Dimension,
+    P: Dimension,
+>(
+    queries: GraphTensor<(H, Sq, D)>,
+    key_pool: GraphTensor<(P, Const<KV_DIM>)>,
+    value_pool: GraphTensor<(P, Const<KV_DIM>)>,
+    slots: GraphTensor<(Sk,)>,
+    mask: AttentionMask<Const<1>, Sq, Sk>,
+) -> GraphTensor<(H, Sq, D)> {
+    let head_dim = KV_DIM / KV_HEADS;
+    let seq = slots.shape.dims[slots.shape.indexes[0]];
+    let read = |pool: GraphTensor<(P, Const<KV_DIM>)>| {
+        pool.gather(slots)
+            .dyn_reshape::<(Const<1>, Sk, Const<KV_HEADS>, D)>(vec![
+                1.into(),
+                seq,
+                KV_HEADS.into(),
+                head_dim.into(),
+            ])
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+    };
+    let shape = (0..3)
+        .map(|i| queries.shape.dims[queries.shape.indexes[i]])
+        .collect();
+    let queries = queries.expand::<(Const<1>, H, Sq, D), _>();
+    grouped_query_attention(queries, read(key_pool), read(value_pool), mask).dyn_reshape(shape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{paged_attention, PagedKvCache};
+    use crate::{grouped_query_attention, AttentionMask};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_paged_kv_cache() {
+        let mut cache = PagedKvCache::new(1, 4, 2, 4);
+        let (a, b) = (cache.add_sequence(), cache.add_sequence());
+        let rows = |start: usize, n: usize| {
+            let k = (start..start + n * 4).map(|i| i as f32).collect::<Vec<_>>();
+            let v = k.iter().map(|x| -x).collect::<Vec<_>>();
+            vec![(k, v)]
+        };
+        // Interleave appends so the sequences' blocks aren't contiguous
+        assert!(cache.append(a, &rows(0, 3)));
+        assert!(cache.append(b, &rows(100, 2)));
+        assert!(cache.append(a, &rows(12, 1)));
+        assert_eq!(cache.block_table(a), [0, 1]);
+        assert_eq!(cache.block_table(b), [2]);
+        assert_eq!(cache.slots(a), [0., 1., 2., 3.]);
+        assert_eq!(
+            cache.gather(a, 0),
+            (rows(0, 4)[0].0.clone(), rows(0, 4)[0].1.clone())
+        );
+        // Only one block is left, so 3 more tokens don't fit in b
+        assert!(!cache.can_append(b, 3));
+        assert!(!cache.append(b, &rows(0, 3)));
+        assert_eq!(cache.len(b), 2);
+        assert!(cache.append(b, &rows(108, 1)));
+        assert_eq!(cache.slots(b), [4., 5., 6.]);
+        assert_eq!(cache.free_blocks(), 0);
+
+        cache.free_sequence(a);
+        assert_eq!(cache.free_blocks(), 2);
+        let c = cache.add_sequence();
+        assert_eq!(c, a);
+        assert!(cache.append(c, &rows(200, 3)));
+        assert_eq!(cache.block_table(c), [0, 1]);
+    }
+
+    #[test]
+    fn test_paged_attention() {
+        let mut cache = PagedKvCache::new(1, 4, 2, 8);
+        let other = cache.add_sequence();
+        let seq = cache.add_sequence();
+        let data = random_vec(5 * 4 * 2);
+        // Split the sequence's tokens between blocks interleaved with another sequence's
+        for t in 0..5 {
+            let row = |d: &[f32]| d[t * 4..(t + 1) * 4].to_vec();
+            assert!(cache.append(seq, &[(row(&data[..20]), row(&data[20..]))]));
+            assert!(cache.append(other, &[(random_vec(4), random_vec(4))]));
+        }
+        let (keys, values) = cache.gather(seq, 0);
+        assert_eq!(keys, data[..20]);
+
+        let mut cx = Graph::new();
+        let queries = cx
+            .tensor::<(LConst<4>, Dyn<'q'>, LConst<2>)>()
+            .set_dyn(random_vec(4 * 2 * 2), &[4, 2, 2]);
+        let key_pool = cx
+            .tensor::<(Dyn<'r'>, LConst<4>)>()
+            .set_dyn(cache.keys(0).to_vec(), &[cache.pool_rows(), 4]);
+        let value_pool = cx
+            .tensor::<(Dyn<'r'>, LConst<4>)>()
+            .set_dyn(cache.values(0).to_vec(), &[cache.pool_rows(), 4]);
+        let slots = cx.tensor::<(Dyn<'k'>,)>().set_dyn(cache.slots(seq), &[5]);
+        let out = paged_attention::<2, 4, _, _, _, _, _>(
+            queries,
+            key_pool,
+            value_pool,
+            slots,
+            AttentionMask::none(),
+        )
+        .retrieve();
+
+        // The same attention over contiguous keys and values
+        let mut contiguous = |data: Vec<f32>| {
+            cx.tensor::<(Dyn<'k'>, LConst<2>, LConst<2>)>()
+                .set_dyn(data, &[5, 2, 2])
+                .permute::<_, LAxes3<1, 0, 2>>()
+                .expand::<(LConst<1>, LConst<2>, Dyn<'k'>, LConst<2>), _>()
+        };
+        let (keys, values) = (contiguous(keys), contiguous(values));
+        let expected = grouped_query_attention(
+            queries.expand::<(LConst<1>, LConst<4>, Dyn<'q'>, LConst<2>), _>(),
+            keys,
+            values,
+            AttentionMask::none(),
+        )
+        .retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
+}
@@ -6,8 +6,10 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
     op::{
-        Add, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, Recip, Sin, Sqrt,
-        SumReduce,
+        Add, ArgTopK, Cast, Concat, Contiguous, CrossEntropy, CumProd, CumSum, DropoutMask, Erf,
+        Exp2, Function, FusedLayerNorm, Gather, LessThan, Log2, LogSoftmax, MaxReduce, Mod, Mul,
+        Operator, Pad, ProdReduce, Recip, Rope, Sample, ScaledDotProductAttention, ScatterAdd,
+        SelectiveScan, Sin, Softmax, Sqrt, StochasticRound, SumReduce, TopK, Where,
     },
     prelude::{tinyvec::ArrayVec, *},
 };
@@ -49,138 +51,878 @@ impl Compiler for Autograd {
     type Output = Vec<(NodeIndex, ShapeTracker)>;
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) -> Vec<(NodeIndex, ShapeTracker)> {
         let Autograd(params, loss) = self;
-        // Build up valid set for nodes we want to pay attention to (everything outside of this set doesn't matter)
-        let forward_set = build_dfs_set(&mut params.clone(), graph, Direction::Outgoing);
-        let backward_set = build_dfs_set(&mut vec![*loss], graph, Direction::Incoming);
-        let valid_set: FxHashSet<_> = forward_set.intersection(&backward_set).copied().collect();
-
-        // We have the last loss node, now let's backprop through everything to get the gradient graph
-        let mut grads = FxHashMap::default();
-        // Add loss gradient
-        grads.insert(
-            *loss,
-            (
-                graph.constant(1.0).id,
-                ShapeTracker::new(&[]), // Assume scalar loss for now
-            ),
-        );
-        let weight_set = params.iter().copied().collect::<FxHashSet<_>>();
-        for fwd_node in toposort(&graph.graph, None).unwrap().into_iter().rev() {
-            if !valid_set.contains(&fwd_node) {
-                continue;
+        let forward = graph.graph.node_indices().collect::<FxHashSet<_>>();
+        let grads = differentiate(params, *loss, graph);
+        recompute_checkpoints(graph, &forward, &grads);
+
+        // Create a gradient array to match 1-1 with the weight array passed in
+        params.iter().map(|weight| grads[weight]).collect()
+    }
+}
+
+/// Backpropagate from the loss to the params, returning the gradient of every node in between. Each op's rule lives
+/// in its own function where it's more than a few lines, which keeps this loop's stack frame small in debug builds.
+#[inline(never)]
+fn differentiate(
+    params: &[NodeIndex],
+    loss: NodeIndex,
+    graph: &mut Graph,
+) -> FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)> {
+    // Build up valid set for nodes we want to pay attention to (everything outside of this set doesn't matter)
+    let forward_set = build_dfs_set(&mut params.to_vec(), graph, Direction::Outgoing);
+    let backward_set = build_dfs_set(&mut vec![loss], graph, Direction::Incoming);
+    let valid_set: FxHashSet<_> = forward_set.intersection(&backward_set).copied().collect();
+
+    // We have the last loss node, now let's backprop through everything to get the gradient graph
+    let mut grads = FxHashMap::default();
+    // Add loss gradient
+    grads.insert(
+        loss,
+        (
+            graph.constant(1.0).id,
+            ShapeTracker::new(&[]), // Assume scalar loss for now
+        ),
+    );
+    let weight_set = params.iter().copied().collect::<FxHashSet<_>>();
+    for fwd_node in toposort(&graph.graph, None).unwrap().into_iter().rev() {
+        if !valid_set.contains(&fwd_node) {
+            continue;
+        }
+        // Check if the node is undifferentiable
+        let op = graph.node_weight(fwd_node).unwrap().as_any().type_id();
+        if op == TypeId::of::<Function>() {
+            continue;
+        }
+        if op == TypeId::of::<Mod>()
+            || op == TypeId::of::<LessThan>()
+            || op == TypeId::of::<Sample>()
+            || op == TypeId::of::<ArgTopK>()
+            || op == TypeId::of::<DropoutMask>()
+        {
+            assert!(
+                !weight_set.contains(&fwd_node),
+                "{fwd_node:?} is marked as a weight but is undifferentiable: {:?}",
+                graph.node_weight(fwd_node).unwrap()
+            );
+            continue;
+        }
+
+        // Differentiate through fwd_node to get gradients for it's sources
+        // Get input tensors
+        let inps = graph
+            .edges_directed(fwd_node, Direction::Incoming)
+            .filter_map(|e| e.weight().as_data().map(|i| (e.source(), i)))
+            .sorted_by_key(|(_, (a, _, _))| *a)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|(node, (_, _, sh))| GraphTensor::<()>::from_id(node, sh, graph))
+            .collect::<Vec<_>>();
+        let mut prev_grad = {
+            let (id, sh) = grads[&fwd_node];
+            GraphTensor::from_id(id, sh, graph)
+        };
+        if op == TypeId::of::<Add>() {
+            // f(a, b) = a + b
+            // df/da = 1
+            if valid_set.contains(&inps[0].id) {
+                add_grad(prev_grad, inps[0], graph, &mut grads);
             }
-            // Check if the node is undifferentiable
-            let graph_ref: *mut Graph = graph;
-            let op = graph.node_weight(fwd_node).unwrap().as_any().type_id();
-            if op == TypeId::of::<Function>() {
-                continue;
+            // df/db = 1
+            if valid_set.contains(&inps[1].id) {
+                add_grad(prev_grad, inps[1], graph, &mut grads);
             }
-            if op == TypeId::of::<Mod>() || op == TypeId::of::<LessThan>() {
-                assert!(
-                    !weight_set.contains(&fwd_node),
-                    "{fwd_node:?} is marked as a weight but is undifferentiable: {:?}",
-                    graph.node_weight(fwd_node).unwrap()
+        } else if op == TypeId::of::<Mul>() {
+            // f(a, b) = a * b
+            // df/da = b
+            if valid_set.contains(&inps[0].id) {
+                add_grad(inps[1] * prev_grad, inps[0], graph, &mut grads);
+            }
+            // df/db = a
+            if valid_set.contains(&inps[1].id) {
+                add_grad(inps[0] * prev_grad, inps[1], graph, &mut grads);
+            }
+        } else if op == TypeId::of::<Where>() {
+            // f(c, a, b) = a where c, otherwise b
+            // df/da = c, df/db = 1 - c
+            // The mask isn't differentiable
+            where_grads(&inps, prev_grad, &valid_set, graph, &mut grads);
+        } else if let Some(op) = graph.try_get_op::<SumReduce>(fwd_node).cloned() {
+            // f(x) = sum_reduce(x)
+            // f'(x) = 1
+            if valid_set.contains(&inps[0].id) {
+                prev_grad
+                    .shape
+                    .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
+                add_grad(prev_grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(op) = graph.try_get_op::<MaxReduce>(fwd_node).cloned() {
+            // f(x) = max_reduce(x)
+            // f'(x) = x == max_reduce(x)
+            if valid_set.contains(&inps[0].id) {
+                let grad = max_reduce_grad(fwd_node, op.0, inps[0], prev_grad, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(op) = graph.try_get_op::<ProdReduce>(fwd_node).cloned() {
+            // f(x) = prod_reduce(x)
+            // f'(x) = prod_reduce(x) / x
+            if valid_set.contains(&inps[0].id) {
+                let grad = prod_reduce_grad(fwd_node, op.0, inps[0], prev_grad, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(FusedLayerNorm(epsilon)) = graph.try_get_op::<FusedLayerNorm>(fwd_node) {
+            // f(x) = (x - mean(x)) / sqrt(var(x) + e) = y
+            // df/dx = (g - mean(g) - y * mean(g * y)) / sqrt(var(x) + e)
+            if valid_set.contains(&inps[0].id) {
+                let grad = layer_norm_grad(fwd_node, inps[0], prev_grad, *epsilon, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(&Softmax(dim)) = graph.try_get_op::<Softmax>(fwd_node) {
+            // f(x) = softmax(x) = y
+            // df/dx = y * (g - sum(g * y))
+            if valid_set.contains(&inps[0].id) {
+                let y = GraphTensor::<()>::from_id(fwd_node, inps[0].shape.contiguous(), graph);
+                let grad = softmax_grad(y, prev_grad, dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(&LogSoftmax(dim)) = graph.try_get_op::<LogSoftmax>(fwd_node) {
+            // f(x) = log_softmax(x) = y
+            // df/dx = g - exp(y) * sum(g)
+            if valid_set.contains(&inps[0].id) {
+                let y = GraphTensor::<()>::from_id(fwd_node, inps[0].shape.contiguous(), graph);
+                let grad = log_softmax_grad(y, prev_grad, dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(CrossEntropy {
+            label_smoothing,
+            ignore_index,
+        }) = graph.try_get_op::<CrossEntropy>(fwd_node).copied()
+        {
+            // f(x, t) = mean(-sum(q * log_softmax(x))), where q is the smoothed one-hot of t
+            // df/dx = (softmax(x) - q) / n for each counted row
+            // The targets aren't differentiable
+            if valid_set.contains(&inps[0].id) {
+                let grad = cross_entropy_grad(
+                    inps[0],
+                    inps[1],
+                    prev_grad,
+                    label_smoothing,
+                    ignore_index,
+                    graph,
+                );
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(ScaledDotProductAttention(scale)) = graph
+            .try_get_op::<ScaledDotProductAttention>(fwd_node)
+            .copied()
+        {
+            attention_grads(scale, &inps, prev_grad, &valid_set, graph, &mut grads);
+        } else if let Some(Rope(offset, scale, dyn_map)) = graph.try_get_op::<Rope>(fwd_node) {
+            // f(x) = R(p * freq) x * s, where R is a rotation of each pair
+            // df/dx = R(p * freq)^T g * s = R(-p * freq) g * s
+            if valid_set.contains(&inps[0].id) {
+                let grad = rotate_back(
+                    prev_grad,
+                    inps[1],
+                    Rope(offset.clone(), *scale, *dyn_map),
+                    graph,
                 );
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(CumSum(dim)) = graph.try_get_op::<CumSum>(fwd_node) {
+            // f(x)_i = sum_{j <= i} x_j
+            // df/dx_i = sum_{j >= i} g_j
+            if valid_set.contains(&inps[0].id) {
+                let grad = reverse_cumsum(prev_grad, *dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(CumProd(dim)) = graph.try_get_op::<CumProd>(fwd_node) {
+            // f(x)_i = prod_{j <= i} x_j = y_i
+            // df/dx_i = sum_{j >= i} g_j * y_j / x_i
+            // This is undefined where x is zero
+            if valid_set.contains(&inps[0].id) {
+                let grad = cumprod_grad(fwd_node, inps[0], prev_grad, *dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(SelectiveScan { dim, reverse }) =
+            graph.try_get_op::<SelectiveScan>(fwd_node).copied()
+        {
+            // f(a, b)_i = h_i = a_i * h_(i-1) + b_i
+            // df/db_i = l_i, where l_i = g_i + a_(i+1) * l_(i+1) is the same scan run backwards
+            // df/da_i = l_i * h_(i-1)
+            selective_scan_grads(
+                fwd_node,
+                SelectiveScan { dim, reverse },
+                &inps,
+                prev_grad,
+                &valid_set,
+                graph,
+                &mut grads,
+            );
+        } else if let Some(Concat(dim)) = graph.try_get_op::<Concat>(fwd_node) {
+            // f(x_1, .., x_n) = x_1 .. x_n joined along dim
+            // df/dx_i = the slice of the gradient x_i was copied to
+            concat_grads(*dim, &inps, prev_grad, &valid_set, graph, &mut grads);
+        } else if let Some(Gather(dim)) = graph.try_get_op::<Gather>(fwd_node) {
+            // f(x) = x[indexes] along dim
+            // df/dx = each gradient slice added to the slice of x its index picked
+            // The indexes aren't differentiable
+            if valid_set.contains(&inps[0].id) {
+                let grad = gather_grad(prev_grad, inps[1], inps[0].shape, *dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if let Some(TopK { k, dim }) = graph.try_get_op::<TopK>(fwd_node) {
+            // f(x) = the k largest elements of x along dim
+            // df/dx = the gradient of each element written back to where it was taken from
+            if valid_set.contains(&inps[0].id) {
+                let grad = topk_grad(inps[0], prev_grad, *k, *dim, graph);
+                add_grad(grad, inps[0], graph, &mut grads);
+            }
+        } else if op == TypeId::of::<Cast>() {
+            // Gradients flow back through casts as f32, like from half precision compute to f32 master weights
+            if valid_set.contains(&inps[0].id) {
+                add_grad(prev_grad.cast(DType::F32), inps[0], graph, &mut grads);
+            }
+        } else if op == TypeId::of::<Contiguous>() || op == TypeId::of::<StochasticRound>() {
+            // Rounding is treated as the identity (straight-through estimator)
+            if valid_set.contains(&inps[0].id) {
+                add_grad(prev_grad, inps[0], graph, &mut grads);
+            }
+        } else {
+            if !valid_set.contains(&inps[0].id) {
                 continue;
             }
-
-            // Differentiate through fwd_node to get gradients for it's sources
-            // Get input tensors
-            let inps = graph
-                .edges_directed(fwd_node, Direction::Incoming)
-                .filter_map(|e| e.weight().as_data().map(|i| (e.source(), i)))
-                .sorted_by_key(|(_, (a, _, _))| *a)
-                .map(|(node, (_, _, sh))| GraphTensor::<()>::from_id(node, sh, graph_ref))
-                .collect::<Vec<_>>();
-            let mut prev_grad = {
-                let (id, sh) = grads[&fwd_node];
-                GraphTensor::from_id(id, sh, graph_ref)
+            let Some(local_grad) = local_grad(op, inps[0]) else {
+                panic!(
+                    "Can't differentiate through {:?}",
+                    graph.node_weight(fwd_node).unwrap()
+                )
             };
-            if op == TypeId::of::<Add>() {
-                // f(a, b) = a + b
-                // df/da = 1
-                if valid_set.contains(&inps[0].id) {
-                    add_grad(prev_grad, inps[0], graph, &mut grads);
-                }
-                // df/db = 1
-                if valid_set.contains(&inps[1].id) {
-                    add_grad(prev_grad, inps[1], graph, &mut grads);
-                }
-            } else if op == TypeId::of::<Mul>() {
-                // f(a, b) = a * b
-                // df/da = b
-                if valid_set.contains(&inps[0].id) {
-                    add_grad(inps[1] * prev_grad, inps[0], graph, &mut grads);
-                }
-                // df/db = a
-                if valid_set.contains(&inps[1].id) {
-                    add_grad(inps[0] * prev_grad, inps[1], graph, &mut grads);
-                }
-            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
-                .try_get_op::<SumReduce>(fwd_node)
-                .cloned()
-            {
-                // f(x) = sum_reduce(x)
-                // f'(x) = 1
-                if valid_set.contains(&inps[0].id) {
-                    prev_grad
-                        .shape
-                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
-                    add_grad(prev_grad, inps[0], graph, &mut grads);
-                }
-            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
-                .try_get_op::<MaxReduce>(fwd_node)
-                .cloned()
-            {
-                // f(x) = max_reduce(x)
-                // f'(x) = x == max_reduce(x)
-                if valid_set.contains(&inps[0].id) {
-                    // fwd_nod is already max_reduce(x)
-                    prev_grad
-                        .shape
-                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
-                    let reduced = GraphTensor::<()>::from_id(fwd_node, prev_grad.shape, graph_ref);
-                    let grad = inps[0].equals(reduced) * prev_grad;
-                    add_grad(grad, inps[0], graph, &mut grads);
-                }
-            } else if op == TypeId::of::<Contiguous>() {
-                if valid_set.contains(&inps[0].id) {
-                    add_grad(prev_grad, inps[0], graph, &mut grads);
-                }
-            } else {
-                if !valid_set.contains(&inps[0].id) {
-                    continue;
+            add_grad(local_grad * prev_grad, inps[0], graph, &mut grads);
+        }
+    }
+    grads
+}
+
+/// Point the backward ops reading activations inside checkpointed regions at copies of the ops producing them. The
+/// copies are scheduled after the gradient reaches the region, so the original activations are freed once the forward
+/// pass is done with them.
+fn recompute_checkpoints(
+    graph: &mut Graph,
+    forward: &FxHashSet<NodeIndex>,
+    grads: &FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)>,
+) {
+    let mut regions: FxHashMap<usize, FxHashSet<NodeIndex>> = FxHashMap::default();
+    for node in forward {
+        if let Some(Checkpointed(region)) = graph.attribute::<Checkpointed>(*node) {
+            regions.entry(*region).or_default().insert(*node);
+        }
+    }
+    for (_, region) in regions.into_iter().sorted_by_key(|(r, _)| *r) {
+        // Activations the rest of the forward pass reads are the region's outputs. They stay alive anyway, so they
+        // aren't recomputed, and the gradients flowing into their consumers trigger the recomputation.
+        let mut outputs = FxHashSet::default();
+        let mut triggers = vec![];
+        for &node in &region {
+            for edge in graph.graph.edges_directed(node, Direction::Outgoing) {
+                if !edge.weight().is_schedule()
+                    && forward.contains(&edge.target())
+                    && !region.contains(&edge.target())
+                {
+                    outputs.insert(node);
+                    if let Some((grad, _)) = grads.get(&edge.target()) {
+                        triggers.push(*grad);
+                    }
                 }
-                let local_grad = if op == TypeId::of::<Log2>() {
-                    // f(x) = log2(x)
-                    // f'(x) = 1 / (x * ln(2))
-                    1.0 / (inps[0] * 2_f32.ln())
-                } else if op == TypeId::of::<Exp2>() {
-                    // f(x) = exp2(x)
-                    // f'(x) = exp2(x) * ln(2)
-                    inps[0].exp2() * 2_f32.ln()
-                } else if op == TypeId::of::<Sin>() {
-                    // f(x) = sin(x)
-                    // f'(x) = cos(x)
-                    inps[0].cos()
-                } else if op == TypeId::of::<Sqrt>() {
-                    // f(x) = sqrt(x)
-                    // f'(x) = 1 / (2 * sqrt(x))
-                    1.0 / (2.0 * inps[0].sqrt())
-                } else if op == TypeId::of::<Recip>() {
-                    // f(x) = 1 / x
-                    // f'(x) = -1 / x**2
-                    -1.0 / (inps[0] * inps[0])
-                } else {
-                    unreachable!()
-                };
-                add_grad(local_grad * prev_grad, inps[0], graph, &mut grads);
             }
         }
+        triggers.sort();
+        triggers.dedup();
+        if triggers.is_empty() {
+            continue;
+        }
+        let reads = region
+            .iter()
+            .filter(|n| !outputs.contains(n))
+            .flat_map(|n| graph.graph.edges_directed(*n, Direction::Outgoing))
+            .filter(|e| !forward.contains(&e.target()))
+            .filter_map(|e| Some((e.id(), e.source(), e.target(), e.weight().as_data()?)))
+            .sorted_by_key(|(e, ..)| *e)
+            .collect::<Vec<_>>();
+        let mut copies = FxHashMap::default();
+        for (edge, source, target, (input_order, output_order, shape)) in reads {
+            let copy = recompute(source, &region, &outputs, &triggers, &mut copies, graph);
+            graph.graph.remove_edge(edge);
+            graph.graph.add_edge(
+                copy,
+                target,
+                Dependency::Data {
+                    input_order,
+                    output_order,
+                    shape,
+                },
+            );
+        }
+    }
+}
+
+/// Copy a region's op and its sources inside the region, stopping at the region's inputs and outputs
+fn recompute(
+    node: NodeIndex,
+    region: &FxHashSet<NodeIndex>,
+    outputs: &FxHashSet<NodeIndex>,
+    triggers: &[NodeIndex],
+    copies: &mut FxHashMap<NodeIndex, NodeIndex>,
+    graph: &mut Graph,
+) -> NodeIndex {
+    if let Some(copy) = copies.get(&node) {
+        return *copy;
+    }
+    if !region.contains(&node) || outputs.contains(&node) {
+        return node;
+    }
+    // Loaders, constants and random ops are left as they are
+    let Some(op) = clone_op(graph.node_weight(node).unwrap().as_ref()) else {
+        return node;
+    };
+    let sources = graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .filter_map(|e| Some((e.source(), e.weight().as_data()?)))
+        .sorted_by_key(|(_, (i, _, _))| *i)
+        .collect::<Vec<_>>();
+    let copy = graph.graph.add_node(op);
+    // Node indexes get reused, so don't inherit metadata from a removed node
+    graph.metadata.remove(&copy);
+    if let Some(metadata) = graph.metadata.get(&node).cloned() {
+        graph.metadata.insert(copy, metadata);
+        graph.remove_attribute::<Checkpointed>(copy);
+    }
+    let mut reads_region = false;
+    for (source, (input_order, output_order, shape)) in sources {
+        let source_copy = recompute(source, region, outputs, triggers, copies, graph);
+        reads_region |= source_copy != source;
+        graph.graph.add_edge(
+            source_copy,
+            copy,
+            Dependency::Data {
+                input_order,
+                output_order,
+                shape,
+            },
+        );
+    }
+    if !reads_region {
+        for trigger in triggers {
+            graph.add_schedule_dependency(*trigger, copy);
+        }
+    }
+    copies.insert(node, copy);
+    copy
+}
 
-        // Create a gradient array to match 1-1 with the weight array passed in
-        self.0.iter().map(|weight| grads[weight]).collect()
+/// Clone a primitive op, if it's deterministic
+fn clone_op(op: &dyn Operator) -> Option<Box<dyn Operator>> {
+    macro_rules! clone_op {
+        ($($t:ty),*) => {
+            $(if let Some(op) = op.as_any().downcast_ref::<$t>() {
+                return Some(Box::new(op.clone()));
+            })*
+        };
     }
+    clone_op!(
+        Add,
+        Mul,
+        Mod,
+        LessThan,
+        Where,
+        Contiguous,
+        Log2,
+        Exp2,
+        Sin,
+        Recip,
+        Sqrt,
+        Erf,
+        Cast,
+        SumReduce,
+        MaxReduce,
+        ProdReduce,
+        CumSum,
+        CumProd,
+        SelectiveScan,
+        FusedLayerNorm,
+        Softmax,
+        LogSoftmax,
+        CrossEntropy,
+        Rope,
+        ScaledDotProductAttention,
+        Gather,
+        ScatterAdd,
+        Concat,
+        Pad,
+        TopK,
+        ArgTopK
+    );
+    None
+}
+
+/// Backpropagate through a where, whose mask isn't differentiable
+fn where_grads(
+    inps: &[GraphTensor<()>],
+    prev_grad: GraphTensor<()>,
+    valid_set: &FxHashSet<NodeIndex>,
+    graph: &mut Graph,
+    grads: &mut FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)>,
+) {
+    let zeros = graph.constant(0.).expand_to(prev_grad.shape);
+    if valid_set.contains(&inps[1].id) {
+        add_grad(inps[0].where_(prev_grad, zeros), inps[1], graph, grads);
+    }
+    if valid_set.contains(&inps[2].id) {
+        add_grad(inps[0].where_(zeros, prev_grad), inps[2], graph, grads);
+    }
+}
+
+/// Gradient of a max reduce's input, given its output `fwd_node`
+fn max_reduce_grad(
+    fwd_node: NodeIndex,
+    dim: usize,
+    x: GraphTensor<()>,
+    mut prev_grad: GraphTensor<()>,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    prev_grad
+        .shape
+        .expand(dim, x.shape.dims[x.shape.indexes[dim]]);
+    let reduced = GraphTensor::<()>::from_id(fwd_node, prev_grad.shape, graph);
+    x.equals(reduced) * prev_grad
+}
+
+/// Gradient of a product reduce's input, given its output `fwd_node`
+fn prod_reduce_grad(
+    fwd_node: NodeIndex,
+    dim: usize,
+    x: GraphTensor<()>,
+    mut prev_grad: GraphTensor<()>,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    prev_grad
+        .shape
+        .expand(dim, x.shape.dims[x.shape.indexes[dim]]);
+    // The product is read through its own shape, since the incoming grad's may be broadcasted
+    let mut shape = x.shape.contiguous();
+    let size = shape.remove_dim(dim);
+    shape.expand(dim, size);
+    let reduced = GraphTensor::<()>::from_id(fwd_node, shape, graph);
+    prev_grad * reduced / x
+}
+
+/// Gradient of a cumulative product's input, given its output `fwd_node`
+fn cumprod_grad(
+    fwd_node: NodeIndex,
+    x: GraphTensor<()>,
+    prev_grad: GraphTensor<()>,
+    dim: usize,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let y = GraphTensor::<()>::from_id(fwd_node, x.shape.contiguous(), graph);
+    reverse_cumsum(prev_grad * y, dim, graph) / x
+}
+
+/// Gradient of a fused layer norm's input, given its output `fwd_node`
+fn layer_norm_grad(
+    fwd_node: NodeIndex,
+    x: GraphTensor<()>,
+    prev_grad: GraphTensor<()>,
+    epsilon: f32,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let y = GraphTensor::<()>::from_id(fwd_node, x.shape.contiguous(), graph);
+    let centered = x - mean_last_dim(x, graph);
+    let rstd = (mean_last_dim(centered * centered, graph) + epsilon)
+        .sqrt()
+        .recip();
+    (prev_grad - mean_last_dim(prev_grad, graph) - y * mean_last_dim(prev_grad * y, graph)) * rstd
+}
+
+/// Gradient of the logits of a fused cross entropy
+fn cross_entropy_grad(
+    logits: GraphTensor<()>,
+    targets: GraphTensor<()>,
+    prev_grad: GraphTensor<()>,
+    label_smoothing: f32,
+    ignore_index: Option<usize>,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let dim = logits.shape.len() - 1;
+    let classes = logits.shape.dims[logits.shape.indexes[dim]];
+    let probs = graph
+        .add_op(Softmax(dim))
+        .input(logits.id, 0, logits.shape)
+        .finish();
+    let probs = GraphTensor::<()>::from_id(probs, logits.shape.contiguous(), graph);
+    // Each row's target and the class index of each logit
+    let mut row_targets = targets;
+    row_targets.shape.expand(dim, classes);
+    let class_ones: GraphTensor<()> = graph.constant(1.).expand_to(probs.shape);
+    let class_indexes = graph
+        .add_op(CumSum(dim))
+        .input(class_ones.id, 0, class_ones.shape)
+        .finish();
+    let class_indexes = GraphTensor::<()>::from_id(class_indexes, probs.shape, graph) - 1.;
+    let smoothing = graph.constant_expr(classes).recip() * label_smoothing;
+    let target_probs = row_targets.eq_mask(class_indexes) * (1. - label_smoothing)
+        + smoothing.expand_to(probs.shape);
+    let grad = (probs - target_probs) * prev_grad.expand_to(probs.shape);
+    if let Some(ignore_index) = ignore_index {
+        let counted = targets.ne_mask(graph.constant(ignore_index as f32).expand_to(targets.shape));
+        let mut row_counted = counted;
+        row_counted.shape.expand(dim, classes);
+        let mut n_counted = counted;
+        for d in (0..targets.shape.len()).rev() {
+            let id = graph
+                .add_op(SumReduce(d))
+                .input(n_counted.id, 0, n_counted.shape)
+                .finish();
+            let mut shape = n_counted.shape.contiguous();
+            shape.remove_dim(d);
+            n_counted = GraphTensor::from_id(id, shape, graph);
+        }
+        grad * row_counted / n_counted.expand_to(probs.shape)
+    } else {
+        let rows = graph.constant_expr(targets.shape.n_elements());
+        grad / rows.expand_to(probs.shape)
+    }
+}
+
+/// Backpropagate through a selective scan, given its output `fwd_node`
+fn selective_scan_grads(
+    fwd_node: NodeIndex,
+    SelectiveScan { dim, reverse }: SelectiveScan,
+    inps: &[GraphTensor<()>],
+    prev_grad: GraphTensor<()>,
+    valid_set: &FxHashSet<NodeIndex>,
+    graph: &mut Graph,
+    grads: &mut FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)>,
+) {
+    let (a, b) = (inps[0], inps[1]);
+    let h = GraphTensor::<()>::from_id(fwd_node, a.shape.contiguous(), graph);
+    let next_a = shift(a, dim, reverse);
+    let id = graph
+        .add_op(SelectiveScan {
+            dim,
+            reverse: !reverse,
+        })
+        .input(next_a.id, 0, next_a.shape)
+        .input(prev_grad.id, 0, prev_grad.shape)
+        .finish();
+    let l = GraphTensor::<()>::from_id(id, prev_grad.shape.contiguous(), graph);
+    if valid_set.contains(&a.id) {
+        add_grad(l * shift(h, dim, !reverse), a, graph, grads);
+    }
+    if valid_set.contains(&b.id) {
+        add_grad(l, b, graph, grads);
+    }
+}
+
+/// Backpropagate through a concat by handing each input the slice of the gradient it was copied to
+fn concat_grads(
+    dim: usize,
+    inps: &[GraphTensor<()>],
+    mut prev_grad: GraphTensor<()>,
+    valid_set: &FxHashSet<NodeIndex>,
+    graph: &mut Graph,
+    grads: &mut FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)>,
+) {
+    if prev_grad.shape.is_reshaped() {
+        prev_grad = prev_grad.contiguous();
+    }
+    let mut offset = Expression::from(0);
+    for inp in inps {
+        let size = inp.shape.shape()[dim].small();
+        if valid_set.contains(&inp.id) {
+            let mut ranges =
+                vec![(Expression::from(0), Expression::from(i32::MAX)); inp.shape.len()];
+            ranges[dim] = (offset, offset + size);
+            let mut grad = prev_grad;
+            grad.shape.slice(&ranges);
+            add_grad(grad, *inp, graph, grads);
+        }
+        offset += size;
+    }
+}
+
+/// Write the gradient of each of the top `k` elements back to where it was taken from along `dim`
+fn topk_grad(
+    x: GraphTensor<()>,
+    prev_grad: GraphTensor<()>,
+    k: usize,
+    dim: usize,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let indexes = graph
+        .add_op(ArgTopK { k, dim })
+        .input(x.id, 0, x.shape)
+        .finish();
+    let dims = x.shape.shape().into_iter().map(|d| d.small()).collect_vec();
+    let mut index_dims = dims.clone();
+    index_dims[dim] = k.into();
+    let zeros = graph.constant(0.).id;
+    let id = graph
+        .add_op(ScatterAdd(dim))
+        .input(zeros, 0, ShapeTracker::fake(&dims))
+        .input(indexes, 0, ShapeTracker::new(&index_dims))
+        .input(prev_grad.id, 0, prev_grad.shape)
+        .finish();
+    GraphTensor::from_id(id, ShapeTracker::new(&dims), graph)
+}
+
+/// Derivative of a unary op at `x`, if it's differentiable
+fn local_grad(op: TypeId, x: GraphTensor<()>) -> Option<GraphTensor<()>> {
+    Some(if op == TypeId::of::<Log2>() {
+        // f(x) = log2(x)
+        // f'(x) = 1 / (x * ln(2))
+        1.0 / (x * 2_f32.ln())
+    } else if op == TypeId::of::<Exp2>() {
+        // f(x) = exp2(x)
+        // f'(x) = exp2(x) * ln(2)
+        x.exp2() * 2_f32.ln()
+    } else if op == TypeId::of::<Sin>() {
+        // f(x) = sin(x)
+        // f'(x) = cos(x)
+        x.cos()
+    } else if op == TypeId::of::<Sqrt>() {
+        // f(x) = sqrt(x)
+        // f'(x) = 1 / (2 * sqrt(x))
+        1.0 / (2.0 * x.sqrt())
+    } else if op == TypeId::of::<Erf>() {
+        // f(x) = erf(x)
+        // f'(x) = 2 / sqrt(pi) * exp(-x**2)
+        (-(x * x)).exp() * std::f32::consts::FRAC_2_SQRT_PI
+    } else if op == TypeId::of::<Recip>() {
+        // f(x) = 1 / x
+        // f'(x) = -1 / x**2
+        -1.0 / (x * x)
+    } else {
+        return None;
+    })
+}
+
+/// Sum along `dim`, expanded back to the input's shape
+fn sum_dim(tensor: GraphTensor<()>, dim: usize, graph: &mut Graph) -> GraphTensor<()> {
+    let size = tensor.shape.dims[tensor.shape.indexes[dim]];
+    let id = graph
+        .add_op(SumReduce(dim))
+        .input(tensor.id, 0, tensor.shape)
+        .finish();
+    let mut shape = tensor.shape.contiguous();
+    shape.remove_dim(dim);
+    shape.expand(dim, size);
+    GraphTensor::<()>::from_id(id, shape, graph)
+}
+
+/// Backpropagate through a fused attention node, recomputing the attention weights it never stores
+fn attention_grads(
+    scale: Option<f32>,
+    inps: &[GraphTensor<()>],
+    prev_grad: GraphTensor<()>,
+    valid_set: &FxHashSet<NodeIndex>,
+    graph: &mut Graph,
+    grads: &mut FxHashMap<NodeIndex, (NodeIndex, ShapeTracker)>,
+) {
+    // f(q, k, v, m) = softmax(q k^T * s + m) v = p v
+    // df/dv = p^T g, df/dp = g v^T, df/dm = p * (df/dp - sum(df/dp * p))
+    // df/dq = df/dm k * s, df/dk = df/dm^T q * s
+    let (q, k, v, mask) = (inps[0], inps[1], inps[2], inps.get(3).copied());
+    let scale = match scale {
+        Some(scale) => graph.constant(scale),
+        None => {
+            let head_dim = q.shape.dims[q.shape.indexes[q.shape.len() - 1]];
+            graph.constant_expr(head_dim).sqrt().recip()
+        }
+    };
+    let probs = attention_probs(q, k, mask, scale, graph);
+    if valid_set.contains(&v.id) {
+        let grad = matmul_transposed(transpose(probs), transpose(prev_grad), graph);
+        add_grad(grad, v, graph, grads);
+    }
+    let dim = probs.shape.len() - 1;
+    let grad_scores = softmax_grad(probs, matmul_transposed(prev_grad, v, graph), dim, graph);
+    if valid_set.contains(&q.id) {
+        let grad = matmul_transposed(grad_scores, transpose(k), graph);
+        add_grad(grad * scale.expand_to(grad.shape), q, graph, grads);
+    }
+    if valid_set.contains(&k.id) {
+        let grad = matmul_transposed(transpose(grad_scores), transpose(q), graph);
+        add_grad(grad * scale.expand_to(grad.shape), k, graph, grads);
+    }
+    if let Some(mask) = mask.filter(|m| valid_set.contains(&m.id)) {
+        add_grad(grad_scores, mask, graph, grads);
+    }
+}
+
+/// Softmax over the last dimension of `q k^T * scale (+ mask)`
+fn attention_probs(
+    q: GraphTensor<()>,
+    k: GraphTensor<()>,
+    mask: Option<GraphTensor<()>>,
+    scale: GraphTensor<()>,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let mut scores = matmul_transposed(q, k, graph);
+    scores = scores * scale.expand_to(scores.shape);
+    if let Some(mask) = mask {
+        scores += mask;
+    }
+    let dim = scores.shape.len() - 1;
+    let probs = graph
+        .add_op(Softmax(dim))
+        .input(scores.id, 0, scores.shape)
+        .finish();
+    GraphTensor::<()>::from_id(probs, scores.shape.contiguous(), graph)
+}
+
+/// Gradient of the softmax inputs along `dim` given its outputs `probs` and their gradient
+fn softmax_grad(
+    probs: GraphTensor<()>,
+    grad: GraphTensor<()>,
+    dim: usize,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    probs * (grad - sum_dim(grad * probs, dim, graph))
+}
+
+/// Gradient of the log softmax inputs given its outputs `y` and their gradient
+fn log_softmax_grad(
+    y: GraphTensor<()>,
+    grad: GraphTensor<()>,
+    dim: usize,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    grad - y.exp() * sum_dim(grad, dim, graph)
+}
+
+/// Apply a `Rope` op with the rotation reversed
+fn rotate_back(
+    tensor: GraphTensor<()>,
+    freqs: GraphTensor<()>,
+    rope: Rope,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let freqs = freqs * -1.;
+    let id = graph
+        .add_op(rope)
+        .input(tensor.id, 0, tensor.shape)
+        .input(freqs.id, 0, freqs.shape)
+        .finish();
+    GraphTensor::<()>::from_id(id, tensor.shape.contiguous(), graph)
+}
+
+/// Batched `a b^T` over the last two dimensions, for `a` shaped (..., M, K) and `b` shaped (..., N, K)
+fn matmul_transposed(
+    mut a: GraphTensor<()>,
+    mut b: GraphTensor<()>,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let dim = a.shape.len() - 1;
+    let (m, n) = (
+        a.shape.dims[a.shape.indexes[dim - 1]],
+        b.shape.dims[b.shape.indexes[dim - 1]],
+    );
+    // Broadcast both to (..., M, N, K) and sum over K
+    a.shape.expand(dim, n);
+    b.shape.expand(dim - 1, m);
+    let product = a * b;
+    let id = graph
+        .add_op(SumReduce(dim + 1))
+        .input(product.id, 0, product.shape)
+        .finish();
+    let mut shape = product.shape.contiguous();
+    shape.remove_dim(dim + 1);
+    GraphTensor::<()>::from_id(id, shape, graph)
+}
+
+/// Swap the last two dimensions
+fn transpose(mut tensor: GraphTensor<()>) -> GraphTensor<()> {
+    let dim = tensor.shape.len() - 1;
+    let mut axes = (0..=dim).collect::<Vec<_>>();
+    axes.swap(dim - 1, dim);
+    tensor.shape.permute(&axes);
+    tensor
+}
+
+/// Mean along the last dimension, expanded back to the input's shape
+fn mean_last_dim(tensor: GraphTensor<()>, graph: &mut Graph) -> GraphTensor<()> {
+    let dim = tensor.shape.len() - 1;
+    let size = tensor.shape.dims[tensor.shape.indexes[dim]];
+    let sum = sum_dim(tensor, dim, graph);
+    sum * graph.constant_expr(size).expand_to(sum.shape).recip()
+}
+
+/// Sum of each element and everything after it along `dim`, as `sum - cumsum + x`
+fn reverse_cumsum(tensor: GraphTensor<()>, dim: usize, graph: &mut Graph) -> GraphTensor<()> {
+    let cumsum = graph
+        .add_op(CumSum(dim))
+        .input(tensor.id, 0, tensor.shape)
+        .finish();
+    sum_dim(tensor, dim, graph)
+        - GraphTensor::<()>::from_id(cumsum, tensor.shape.contiguous(), graph)
+        + tensor
+}
+
+/// Move each element one step along `dim`, towards the end or towards the start, filling the vacated slot with zero
+fn shift(tensor: GraphTensor<()>, dim: usize, towards_end: bool) -> GraphTensor<()> {
+    let size = tensor.shape.shape()[dim].small();
+    let mut ranges = vec![(Expression::from(0), Expression::from(i32::MAX)); tensor.shape.len()];
+    let mut padding = vec![(Expression::from(0), Expression::from(0)); tensor.shape.len()];
+    if towards_end {
+        ranges[dim].1 = size - 1;
+        padding[dim].0 = 1.into();
+    } else {
+        ranges[dim].0 = 1.into();
+        padding[dim].1 = 1.into();
+    }
+    let mut shifted = tensor;
+    shifted.shape.slice(&ranges);
+    shifted.pad(&padding)
+}
+
+/// Scatter-add the gradient of a `Gather` along `dim` back into the shape of its input
+fn gather_grad(
+    grad: GraphTensor<()>,
+    indexes: GraphTensor<()>,
+    input: ShapeTracker,
+    dim: usize,
+    graph: &mut Graph,
+) -> GraphTensor<()> {
+    let shape = input.shape().into_iter().map(|d| d.small()).collect_vec();
+    let product = |dims: &[Expression]| dims.iter().fold(Expression::from(1), |acc, d| acc * *d);
+    let (front, rows, back) = (
+        product(&shape[..dim]),
+        shape[dim],
+        product(&shape[dim + 1..]),
+    );
+    let n_indexes = indexes.shape.n_elements().small();
+    // Flatten the gradient to (F, T, B), and give each of its elements the index of its slice
+    let grad = GraphTensor::<()>::from_id(
+        grad.contiguous().id,
+        ShapeTracker::new(&[front, n_indexes, back]),
+        graph,
+    );
+    let mut indexes = GraphTensor::<()>::from_id(
+        indexes.contiguous().id,
+        ShapeTracker::new(&[n_indexes]),
+        graph,
+    );
+    indexes.shape.expand(0, front);
+    indexes.shape.expand(2, back);
+    let zeros = graph.constant(0.).id;
+    let id = graph
+        .add_op(ScatterAdd(1))
+        .input(zeros, 0, ShapeTracker::fake(&[front, rows, back]))
+        .input(indexes.id, 0, indexes.shape)
+        .input(grad.id, 0, grad.shape)
+        .finish();
+    GraphTensor::from_id(id, ShapeTracker::new(&shape), graph)
 }
 
 fn add_grad(
@@ -211,11 +953,21 @@ fn add_grad(
     }
 
     // Check to see if a reshape was done here. If so, we may need to assert grad shape is contiguous or insert a contiguous call
-    if let Some((_, _, mut pre_fwd_shape)) = graph.get_sources(fwd.id).first() {
+    // Gathers, top-ks, concats and attention have their own output shapes, so their input shape says nothing about reshapes
+    let own_shape = graph.try_get_op::<Gather>(fwd.id).is_some()
+        || graph.try_get_op::<TopK>(fwd.id).is_some()
+        || graph.try_get_op::<Concat>(fwd.id).is_some()
+        || graph
+            .try_get_op::<ScaledDotProductAttention>(fwd.id)
+            .is_some();
+    if let (false, Some((_, _, mut pre_fwd_shape))) = (own_shape, graph.get_sources(fwd.id).first())
+    {
         if let Some(SumReduce(dim)) = graph.try_get_op(fwd.id) {
             pre_fwd_shape.remove_dim(*dim);
         } else if let Some(MaxReduce(dim)) = graph.try_get_op(fwd.id) {
             pre_fwd_shape.remove_dim(*dim);
+        } else if let Some(ProdReduce(dim)) = graph.try_get_op(fwd.id) {
+            pre_fwd_shape.remove_dim(*dim);
         }
         if grad.shape.shape() != pre_fwd_shape.shape() {
             if !grad.shape.is_contiguous() {
@@ -236,10 +988,190 @@ fn add_grad(
     }
 }
 
+/// Marks a tensor as trainable, so `backward` computes its gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trainable;
+
+/// Marks a node as part of a checkpointed region, whose intermediate activations are recomputed during the backward pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpointed(pub usize);
+
+pub trait TrainableTensor {
+    /// Mark this tensor as trainable
+    fn trainable(self) -> Self;
+}
+
+impl<S: Shape> TrainableTensor for GraphTensor<S> {
+    fn trainable(self) -> Self {
+        self.with_attribute(Trainable)
+    }
+}
+
+/// The gradients of trainable tensors with respect to a loss
+#[derive(Debug, Clone, Default)]
+pub struct Gradients {
+    params: Vec<NodeIndex>,
+    grads: Vec<(NodeIndex, ShapeTracker)>,
+}
+
+impl Gradients {
+    /// Get the gradient of a trainable tensor, if the loss depends on it
+    pub fn get<S: Shape>(&self, tensor: GraphTensor<S>) -> Option<GraphTensor<S>> {
+        let i = self.params.iter().position(|p| *p == tensor.id)?;
+        let (id, shape) = self.grads[i];
+        Some(unsafe { tensor.with_graph(|cx| GraphTensor::from_id(id, shape, cx)) })
+    }
+
+    /// The trainable tensors, matching 1-1 with the gradients
+    pub fn params(&self) -> &[NodeIndex] {
+        &self.params
+    }
+
+    /// The gradient nodes and their shapes, matching 1-1 with the trainable tensors
+    pub fn grads(&self) -> &[(NodeIndex, ShapeTracker)] {
+        &self.grads
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    /// The L2 norm of all the gradients together, as if they were one vector. Retrieve it to log the norm.
+    pub fn global_norm(&self, graph: &mut Graph) -> GraphTensor<()> {
+        let mut total = graph.constant(0.);
+        for (id, shape) in &self.grads {
+            let grad = GraphTensor::<()>::from_id(*id, *shape, graph);
+            total += sum_all(grad * grad, graph);
+        }
+        total.sqrt()
+    }
+
+    /// Scale the gradients down so their global norm is at most `max_norm`, leaving them as they are if it already is.
+    /// Returns the clipped gradients, along with the global norm from before clipping for logging.
+    pub fn clip_norm(&self, max_norm: f32, graph: &mut Graph) -> (Gradients, GraphTensor<()>) {
+        let norm = self.global_norm(graph);
+        // The epsilon keeps all-zero gradients from dividing by zero
+        let scale = (max_norm / (norm + 1e-6)).min_f32(1.);
+        let clipped = self.map(graph, |grad| grad * scale.expand_to(grad.shape));
+        (clipped, norm)
+    }
+
+    /// Clamp each gradient value to be between `-max_value` and `max_value`
+    pub fn clip_value(&self, max_value: f32, graph: &mut Graph) -> Gradients {
+        self.map(graph, |grad| grad.max_f32(-max_value).min_f32(max_value))
+    }
+
+    pub(crate) fn map(
+        &self,
+        graph: &mut Graph,
+        mut f: impl FnMut(GraphTensor<()>) -> GraphTensor<()>,
+    ) -> Gradients {
+        let grads = self
+            .grads
+            .iter()
+            .map(|(id, shape)| {
+                let grad = f(GraphTensor::from_id(*id, *shape, graph));
+                (grad.id, grad.shape)
+            })
+            .collect();
+        Gradients {
+            params: self.params.clone(),
+            grads,
+        }
+    }
+}
+
+/// Sum a tensor over all of its dimensions
+pub(crate) fn sum_all(mut tensor: GraphTensor<()>, graph: &mut Graph) -> GraphTensor<()> {
+    for d in (0..tensor.shape.len()).rev() {
+        let id = graph
+            .add_op(SumReduce(d))
+            .input(tensor.id, 0, tensor.shape)
+            .finish();
+        let mut shape = tensor.shape.contiguous();
+        shape.remove_dim(d);
+        tensor = GraphTensor::from_id(id, shape, graph);
+    }
+    tensor
+}
+
+impl ToIds for Gradients {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        self.grads.iter().map(|(id, _)| *id).collect()
+    }
+}
+
+impl ToIdsMut for Gradients {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        self.grads.iter_mut().map(|(id, _)| id).collect()
+    }
+}
+
+/// Reverse-mode differentiation on a graph
+pub trait Backward {
+    /// Mark tensors, like a model's `params`, as trainable
+    fn mark_trainable(&mut self, tensors: impl ToIds);
+    /// Add ops computing the gradient of a scalar loss with respect to every trainable tensor it depends on
+    fn backward(&mut self, loss: GraphTensor<()>) -> Gradients;
+    /// Run `f`, marking the ops it adds as a checkpointed region. Rather than keeping the region's intermediate
+    /// activations alive until `backward` needs them, they're recomputed from its inputs, trading compute for memory.
+    fn checkpoint<T>(&mut self, f: impl FnOnce() -> T) -> T;
+}
+
+impl Backward for Graph {
+    fn mark_trainable(&mut self, tensors: impl ToIds) {
+        for id in tensors.to_ids() {
+            self.set_attribute(id, Trainable);
+        }
+    }
+
+    fn backward(&mut self, loss: GraphTensor<()>) -> Gradients {
+        let used = build_dfs_set(&mut vec![loss.id], &self.graph, Direction::Incoming);
+        let params = self
+            .metadata
+            .iter()
+            .filter(|(id, m)| m.get::<Trainable>().is_some() && used.contains(id))
+            .map(|(id, _)| *id)
+            .sorted()
+            .collect::<Vec<_>>();
+        let grads = self.compile(Autograd(params.clone(), loss.id), ());
+        Gradients { params, grads }
+    }
+
+    fn checkpoint<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        // Regions are numbered after the graph's existing ones
+        let region = Checkpointed(
+            self.metadata
+                .values()
+                .filter_map(|m| m.get::<Checkpointed>())
+                .map(|Checkpointed(r)| r + 1)
+                .max()
+                .unwrap_or_default(),
+        );
+        let existing = self.graph.node_indices().collect::<FxHashSet<_>>();
+        let output = f();
+        let added = self
+            .graph
+            .node_indices()
+            .filter(|n| !existing.contains(n))
+            .collect::<Vec<_>>();
+        for node in added {
+            self.set_attribute(node, region);
+        }
+        output
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::Backward;
     use super::*;
-    use dfdx::nn::Module as DModule;
+    use crate::cross_entropy_loss;
+    use dfdx::{nn::Module as DModule, prelude::Backward as _};
     use luminal::prelude::Module as LModule;
     luminal::test_imports!();
 
@@ -247,6 +1179,40 @@ mod tests {
         GraphTensor::<()>::from_id(grad.0, grad.1, cx).data()
     }
 
+    #[test]
+    fn test_backward() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R2<1, 2>>().set([[2., -1.]]);
+        let w = cx
+            .tensor::<R2<2, 3>>()
+            .set([[0.5, 1., -2.], [3., 0.1, 1.]])
+            .trainable();
+        let b = cx.tensor::<R2<3, 1>>().set([[1.], [0.], [-1.]]);
+        let unused = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        cx.mark_trainable((b, unused));
+        let loss = (x.matmul(w) + b.permute())
+            .sin()
+            .reshape::<R1<3>>()
+            .sum_reduce();
+
+        let grads = cx.backward(loss);
+        assert_eq!(grads.len(), 2);
+        assert!(grads.get(unused).is_none());
+        assert!(grads.get(x).is_none());
+        let (d_w, d_b) = (grads.get(w).unwrap(), grads.get(b).unwrap());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // z = xw + b, dL/dw_ij = x_i cos(z_j), dL/db_j = cos(z_j)
+        let z = [2. * 0.5 - 3. + 1., 2. * 1. - 0.1, 2. * -2. - 1. - 1.];
+        let cos = z.map(|z: f32| z.cos());
+        assert_close(&d_b.data(), &cos);
+        assert_close(
+            &d_w.data(),
+            &[cos.map(|c| 2. * c), cos.map(|c| -c)].concat(),
+        );
+    }
+
     #[test]
     fn test_autograd_max_reduce() {
         let mut cx = Graph::new();
@@ -259,12 +1225,26 @@ mod tests {
 
         let dev = dfdx::prelude::Cpu::default();
         let d_a = dev.tensor([10., 5.]);
-        let d_b = d_a.trace(Gradients::leaky()).max();
+        let d_b = d_a.trace(dfdx::prelude::Gradients::leaky()).max();
         let d_grads = d_b.backward();
 
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_prod_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., -5., 6.]);
+        let b = a.prod_reduce::<R1<2>, LAxis<1>>().sum_reduce();
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // The product of the other elements in each row
+        assert_close(&get_vec(grads[0], &mut cx), &[6., 3., 2., -30., 24., -20.]);
+    }
+
     #[test]
     fn test_autograd_matmul() {
         let mut cx = Graph::new();
@@ -279,7 +1259,10 @@ mod tests {
         let dev = dfdx::prelude::Cpu::default();
         let w1 = dev.tensor([[2., 4.], [3., 1.]]);
         let inp = dev.tensor([10., 5.]);
-        let out = inp.trace(Gradients::leaky()).matmul(w1.clone()).sum();
+        let out = inp
+            .trace(dfdx::prelude::Gradients::leaky())
+            .matmul(w1.clone())
+            .sum();
         let d_grads = out.backward();
 
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&w1).as_vec());
@@ -312,7 +1295,9 @@ mod tests {
         d_model.0.weight = dev.tensor([[2., 4.], [3., 1.]]).permute();
         d_model.2.weight = dev.tensor([[6.], [5.]]).permute();
         let inp = dev.tensor([10., 5.]);
-        let out = d_model.forward(inp.trace(Gradients::leaky())).sum();
+        let out = d_model
+            .forward(inp.trace(dfdx::prelude::Gradients::leaky()))
+            .sum();
         let d_grads = out.backward();
 
         assert_exact(
@@ -338,12 +1323,177 @@ mod tests {
 
         let d_dev = Cpu::default();
         let d_a = d_dev.tensor([-1., 2., 3.]);
-        let d_b = d_a.trace(Gradients::leaky()).normalize(1e-5).max();
+        let d_b = d_a
+            .trace(dfdx::prelude::Gradients::leaky())
+            .normalize(1e-5)
+            .max();
         assert_close(&b.data(), &d_b.as_vec());
         let d_grads = d_b.backward();
         assert_close(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_fused_layer_norm() {
+        let mut cx = Graph::new();
+        let a_data = vec![-1., 2., 3., 0.5, 4., -2.];
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let mut b = (a.fused_layer_norm(1e-5) * a).sum_reduce().retrieve();
+
+        let grads = cx.compile(Autograd::new(a, b), &mut b);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut b);
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = (d_a
+            .trace(dfdx::prelude::Gradients::leaky())
+            .normalize::<DAxis<1>>(1e-5)
+            * d_a.clone())
+        .sum();
+        assert_close(&b.data(), &d_b.as_vec());
+        let d_grads = d_b.backward();
+        assert_close(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
+    }
+
+    #[test]
+    fn test_autograd_gather() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 2>>().set(vec![0.; 6]);
+        let rows = a.gather(cx.tensor::<R1<3>>().set(vec![2_u32, 0, 2]));
+        let b = cx.tensor::<R2<2, 3>>().set(vec![0.; 6]);
+        let cols =
+            b.index_select::<R2<2, 2>, LAxis<1>, _>(cx.tensor::<R1<2>>().set(vec![1_u32, 1]));
+        let mut loss = ((rows * cx.tensor::<R2<3, 2>>().set(vec![1., 2., 3., 4., 5., 6.]))
+            .sum_reduce()
+            + (cols * cx.tensor::<R2<2, 2>>().set(vec![1., 2., 3., 4.])).sum_reduce())
+        .retrieve();
+
+        let grads = cx.compile(Autograd::new((a, b), loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut loss);
+        cx.execute();
+
+        // Rows picked more than once get the sum of their gradients
+        assert_exact(&get_vec(grads[0], &mut cx), &[3., 4., 0., 0., 6., 8.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[0., 3., 0., 0., 7., 0.]);
+    }
+
+    #[test]
+    fn test_autograd_topk() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<5>>().set(vec![3., 1., 4., 1., 5.]);
+        let (values, _) = a.topk::<R1<2>, LAxis<0>>(2);
+        let mut loss = (values * cx.tensor::<R1<2>>().set(vec![1., 2.]))
+            .sum_reduce()
+            .retrieve();
+
+        let grads = cx.compile(Autograd::new(a, loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut loss);
+        cx.execute();
+
+        assert_exact(&loss.data(), &[13.]);
+        assert_exact(&get_vec(grads[0], &mut cx), &[0., 0., 2., 0., 1.]);
+    }
+
+    #[test]
+    fn test_autograd_cumsum_cumprod() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let w = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let mut loss = ((a.cumsum::<LAxis<1>>() * w).sum_reduce()
+            + a.cumprod::<LAxis<0>>().sum_reduce())
+        .retrieve();
+
+        let grads = cx.compile(Autograd::new(a, loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut loss);
+        cx.execute();
+
+        assert_exact(&loss.data(), &[214.]);
+        // Reverse cumsum of w: [[6, 5, 3], [15, 11, 6]]
+        // Cumprod along the rows: [[1 + a_1j], [a_0j]] = [[5, 6, 7], [1, 2, 3]]
+        assert_close(&get_vec(grads[0], &mut cx), &[11., 11., 10., 16., 13., 9.]);
+    }
+
+    #[test]
+    fn test_autograd_selective_scan() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![0.5, 2., 1., 1., 1., 1.]);
+        let b = cx.tensor::<R2<2, 3>>().set(vec![1., 1., 1., 1., 2., 3.]);
+        let w = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 1., 1., 1.]);
+        let mut loss = (a.selective_scan::<LAxis<1>>(b) * w)
+            .sum_reduce()
+            .retrieve();
+
+        let grads = cx.compile(Autograd::new((a, b), loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut loss);
+        cx.execute();
+
+        // Scanned: [[1, 3, 4], [1, 3, 6]]
+        assert_exact(&loss.data(), &[29.]);
+        // Backwards scan of w: [[11, 5, 3], [3, 2, 1]], times the previous state for the decays
+        assert_close(&get_vec(grads[0], &mut cx), &[0., 5., 9., 0., 2., 3.]);
+        assert_close(&get_vec(grads[1], &mut cx), &[11., 5., 3., 3., 2., 1.]);
+    }
+
+    #[test]
+    fn test_autograd_where() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 5., -2., 3.]);
+        let b = cx.tensor::<R1<4>>().set(vec![2., 5., -3., 0.]);
+        let mut loss = (a.gt_mask(b).where_(a, b * 2.) * a).sum_reduce().retrieve();
+
+        let grads = cx.compile(Autograd::new((a, b), loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut loss);
+        cx.execute();
+
+        assert_exact(&loss.data(), &[67.]);
+        assert_exact(&get_vec(grads[0], &mut cx), &[4., 10., -4., 6.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[2., 10., 0., 0.]);
+    }
+
+    #[test]
+    fn test_autograd_concat() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 1>>().set(vec![1., 2.]);
+        let b = cx.tensor::<R2<2, 2>>().set(vec![3., 4., 5., 6.]);
+        let w = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let joined = GraphTensor::concat::<R2<2, 3>, LAxis<1>>(&[a.no_shape(), b.no_shape()]);
+        let mut loss = (joined * w).sum_reduce().retrieve();
+
+        let mut grads = cx.compile(Autograd::new((a, b), loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), (&mut grads, &mut loss));
+        cx.execute();
+
+        assert_exact(&loss.data(), &[88.]);
+        assert_exact(&get_vec(grads[0], &mut cx), &[1., 4.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[2., 3., 5., 6.]);
+    }
+
+    #[test]
+    fn test_autograd_gelu() {
+        let mut cx = Graph::new();
+        let a = cx.tensor().set([-1.5, 0.2, 2.]);
+        let mut b = a.gelu().sum_reduce().retrieve();
+
+        let mut grads = cx.compile(Autograd::new(a, b), &mut b);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), (&mut grads, &mut b));
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor([-1.5, 0.2, 2.]);
+        let d_b = d_a.accurate_gelu().sum();
+        assert_close(&b.data(), &d_b.as_vec());
+        // gelu'(x) = cdf(x) + x * pdf(x). dfdx's accurate_gelu gradient is off, so these are computed directly
+        assert_close(&get_vec(grads[0], &mut cx), &[-0.12747, 0.65747, 1.08523]);
+    }
+
     #[test]
     fn test_autograd_softmax() {
         let mut cx = Graph::new();
@@ -357,12 +1507,206 @@ mod tests {
 
         let d_dev = Cpu::default();
         let d_a = d_dev.tensor([-1., 2., 3.]);
-        let d_b = d_a.trace(Gradients::leaky()).softmax().max();
+        let d_b = d_a.trace(dfdx::prelude::Gradients::leaky()).softmax().max();
         assert_close(&b.data(), &d_b.as_vec());
         let d_grads = d_b.backward();
         assert_close(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_softmax_axis() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![-1., 2., 3., 0.5, -2., 1.]);
+        let w = cx.tensor::<R2<2, 3>>().set(vec![1., -2., 3., 4., 5., -6.]);
+        let mut loss = (a.softmax_axis(0) * w).sum_reduce().retrieve();
+
+        let mut grads = cx.compile(Autograd::new(a, loss), &mut loss);
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), (&mut grads, &mut loss));
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor([[-1., 2., 3.], [0.5, -2., 1.]]);
+        let d_w = d_dev.tensor([[1., -2., 3.], [4., 5., -6.]]);
+        let d_loss = (d_a
+            .trace(dfdx::prelude::Gradients::leaky())
+            .softmax::<DAxis<0>>()
+            * d_w)
+            .sum();
+        assert_close(&loss.data(), &d_loss.as_vec());
+        let d_grads = d_loss.backward();
+        assert_close(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
+    }
+
+    #[test]
+    fn test_autograd_rope() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R3<2, 3, 4>>().set(random_vec(24));
+        let freqs = cx.tensor::<R1<2>>().set(vec![1., 0.1]);
+        let w_data = random_vec(24);
+        let w = cx.tensor::<R3<2, 3, 4>>().set(w_data.clone());
+        let loss = (x.rope_with_scale(freqs, 2, 0.5) * w)
+            .sum_reduce()
+            .retrieve();
+        let mut grads = cx.compile(Autograd::new(x, loss), ());
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut grads);
+        cx.execute();
+
+        // Each pair of w is rotated back
+        let mut expected = vec![];
+        for (i, pair) in w_data.chunks(2).enumerate() {
+            let pos = (2 + (i / 2) % 3) as f32;
+            let (sin, cos) = (pos * [1., 0.1][i % 2]).sin_cos();
+            expected.push((pair[0] * cos + pair[1] * sin) * 0.5);
+            expected.push((pair[1] * cos - pair[0] * sin) * 0.5);
+        }
+        assert_close(&get_vec(grads[0], &mut cx), &expected);
+    }
+
+    #[test]
+    fn test_autograd_sdpa() {
+        let mut cx = Graph::new();
+        let q = cx.tensor::<R4<1, 2, 3, 4>>().set(random_vec(24));
+        let k = cx.tensor::<R4<1, 2, 5, 4>>().set(random_vec(40));
+        let v = cx.tensor::<R4<1, 2, 5, 3>>().set(random_vec(30));
+        let mask = cx.tensor::<R2<3, 5>>().set(random_vec(15));
+        let w = cx.tensor::<R4<1, 2, 3, 3>>().set(random_vec(18));
+        let full_mask = mask.expand::<R4<1, 2, 3, 5>, _>();
+        let fused = (q.sdpa(k, v, Some(full_mask)) * w).sum_reduce().retrieve();
+        let scaled = (q.sdpa_with_scale(k, v, None, 0.3) * w)
+            .sum_reduce()
+            .retrieve();
+        let unfused = ((q.matmul(k.permute()) * 0.5 + full_mask)
+            .softmax::<LAxis<3>>()
+            .matmul(v)
+            * w)
+            .sum_reduce()
+            .retrieve();
+        let unfused_scaled = ((q.matmul(k.permute()) * 0.3)
+            .softmax::<LAxis<3>>()
+            .matmul(v)
+            * w)
+            .sum_reduce()
+            .retrieve();
+        let mut grads = [fused, unfused]
+            .map(|loss| cx.compile(Autograd::new((q, k, v, mask), loss), ()))
+            .concat();
+        grads.extend(
+            [scaled, unfused_scaled]
+                .map(|loss| cx.compile(Autograd::new((q, k, v), loss), ()))
+                .concat(),
+        );
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut grads);
+        cx.execute();
+
+        assert_close(&fused.data(), &unfused.data());
+        assert_close(&scaled.data(), &unfused_scaled.data());
+        for i in 0..4 {
+            assert_close(&get_vec(grads[i], &mut cx), &get_vec(grads[i + 4], &mut cx));
+        }
+        for i in 8..11 {
+            assert_close(&get_vec(grads[i], &mut cx), &get_vec(grads[i + 3], &mut cx));
+        }
+    }
+
+    #[test]
+    fn test_autograd_log_softmax_cross_entropy() {
+        let mut cx = Graph::new();
+        let logits_data = [1., -2., 0.5, 3., 0., 2., -1., 1., 0.2, 0.3, 0.1, 4.];
+        let a = cx.tensor::<R2<3, 4>>().set(logits_data.to_vec());
+        let w_data = random_vec(12);
+        let w = cx.tensor::<R2<3, 4>>().set(w_data.clone());
+        let targets = cx.tensor::<R1<3>>().set(vec![3., 0., 2.]);
+        // The last row is padding
+        let padded_targets = cx.tensor::<R1<3>>().set(vec![3., 0., 100.]);
+        let mut losses = (
+            (a.log_softmax::<LAxis<1>>() * w).sum_reduce().retrieve(),
+            cross_entropy_loss(a, targets, 0., None).retrieve(),
+            cross_entropy_loss(a, targets, 0.1, None).retrieve(),
+            cross_entropy_loss(a, padded_targets, 0., Some(100)).retrieve(),
+        );
+        let mut grads = [losses.0, losses.1, losses.2, losses.3]
+            .map(|loss| cx.compile(Autograd::new(a, loss), ())[0])
+            .to_vec();
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), (&mut grads, &mut losses));
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(logits_data.to_vec(), (DConst::<3>, DConst::<4>));
+        let d_w = d_dev.tensor_from_vec(w_data, (DConst::<3>, DConst::<4>));
+        let one_hot = |rows: &[usize], smoothing: f32| {
+            let mut probs = vec![smoothing / 4.; rows.len() * 4];
+            for (r, t) in rows.iter().enumerate() {
+                probs[r * 4 + t] += 1. - smoothing;
+            }
+            probs
+        };
+        let check =
+            |loss: GraphTensor<()>, grad: Vec<f32>, logits: &[f32], target_probs: Vec<f32>| {
+                let rows = logits.len() / 4;
+                let d_a = d_dev.tensor_from_vec(logits.to_vec(), (rows, DConst::<4>));
+                let target_probs = d_dev.tensor_from_vec(target_probs, (rows, DConst::<4>));
+                let d_loss = dfdx::losses::cross_entropy_with_logits_loss(
+                    d_a.trace(dfdx::prelude::Gradients::leaky()),
+                    target_probs,
+                );
+                assert_close(&loss.data(), &d_loss.as_vec());
+                let mut d_grad = d_loss.backward().get(&d_a).as_vec();
+                d_grad.resize(12, 0.);
+                assert_close(&grad, &d_grad);
+            };
+
+        let d_loss = (d_a
+            .clone()
+            .trace(dfdx::prelude::Gradients::leaky())
+            .log_softmax::<DAxis<1>>()
+            * d_w)
+            .sum();
+        assert_close(&losses.0.data(), &d_loss.as_vec());
+        assert_close(
+            &get_vec(grads[0], &mut cx),
+            &d_loss.backward().get(&d_a).as_vec(),
+        );
+        check(
+            losses.1,
+            get_vec(grads[1], &mut cx),
+            &logits_data,
+            one_hot(&[3, 0, 2], 0.),
+        );
+        check(
+            losses.2,
+            get_vec(grads[2], &mut cx),
+            &logits_data,
+            one_hot(&[3, 0, 2], 0.1),
+        );
+        // Ignored rows have no gradient and don't count towards the mean
+        check(
+            losses.3,
+            get_vec(grads[3], &mut cx),
+            &logits_data[..8],
+            one_hot(&[3, 0], 0.),
+        );
+    }
+
+    #[test]
+    fn test_autograd_dropout() {
+        let mut cx = Graph::new();
+        cx.set_training(true);
+        let a = cx.tensor::<R1<64>>().set(vec![1.; 64]);
+        let out = a.dropout(0.5).retrieve();
+        let loss = out.sum_reduce();
+
+        let grads = cx.compile(Autograd::new(a, loss), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // Gradients flow through the kept elements, scaled like the forward pass
+        assert_exact(&get_vec(grads[0], &mut cx), &out.data());
+    }
+
     #[test]
     fn test_autograd_transformer() {
         let mut cx = Graph::new();
@@ -401,7 +1745,7 @@ mod tests {
         let a = cx.tensor().set([[-1., 2., 3.], [3., 3., -1.]]);
         let target = cx.tensor().set([[0., 1., 0.], [0., 0., 1.]]);
         let out = model.forward(a);
-        let mut loss = crate::cross_entropy_with_logits_loss(out, target).retrieve();
+        let mut loss = crate::cross_entropy_with_logits_loss(out, target, crate::Mean).retrieve();
 
         let mut model_params = params(&model);
         let mut grads = cx.compile(
@@ -469,7 +1813,7 @@ mod tests {
         let d_a = d_dev.tensor_from_vec(vec![-1., 2., 3., 3., 3., -1.], (DConst::<2>, DConst::<3>));
         let d_target =
             d_dev.tensor_from_vec(vec![0., 1., 0., 0., 0., 1.], (DConst::<2>, DConst::<3>));
-        let d_b = d_model.forward(d_a.trace(Gradients::leaky()));
+        let d_b = d_model.forward(d_a.trace(dfdx::prelude::Gradients::leaky()));
         let d_loss = dfdx::prelude::cross_entropy_with_logits_loss(d_b, d_target);
 
         assert_close(&loss.data(), &d_loss.as_vec());
@@ -552,4 +1896,101 @@ mod tests {
                 .as_vec(),
         );
     }
+
+    #[test]
+    fn test_checkpoint() {
+        // A stack of blocks whose hidden activations are much bigger than their outputs
+        let run = |checkpoint: bool| {
+            let mut cx = Graph::new();
+            let weight = |i: usize| ((i * 7 % 13) as f32 - 6.) / 20.;
+            let blocks = (0..4)
+                .map(|_| {
+                    (
+                        cx.tensor::<R2<4, 64>>()
+                            .set((0..256).map(weight).collect::<Vec<_>>()),
+                        cx.tensor::<R2<64, 4>>()
+                            .set((0..256).map(|i| weight(i + 3)).collect::<Vec<_>>()),
+                    )
+                })
+                .collect::<Vec<_>>();
+            for (a, b) in &blocks {
+                cx.mark_trainable((*a, *b));
+                cx.keep_tensors((*a, *b));
+            }
+            let mut x = cx
+                .tensor::<R2<32, 4>>()
+                .set((0..128).map(|i| weight(i + 5)).collect::<Vec<_>>());
+            for (a, b) in &blocks {
+                let block = || x.matmul(*a).sin().matmul(*b) + x;
+                x = if checkpoint {
+                    cx.checkpoint(block)
+                } else {
+                    block()
+                };
+            }
+            let loss = (x * x).sum_reduce();
+            let mut grads = cx.backward(loss);
+            cx.keep_tensors(&grads);
+            cx.compile(GenericCompiler::default(), &mut grads);
+            cx.execute();
+            let grads = grads
+                .grads()
+                .iter()
+                .map(|g| get_vec(*g, &mut cx))
+                .collect::<Vec<_>>();
+            (grads, cx.memory_report().peak_intermediate)
+        };
+        let (grads, peak) = run(false);
+        let (checkpointed_grads, checkpointed_peak) = run(true);
+        for (a, b) in grads.iter().zip(&checkpointed_grads) {
+            assert_close(a, b);
+        }
+        assert!(checkpointed_peak < peak, "{checkpointed_peak} >= {peak}");
+    }
+
+    #[test]
+    fn test_checkpoint_regions() {
+        // Regions are numbered per graph, so every graph's first region is 0
+        for _ in 0..2 {
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R1<3>>();
+            let b = cx.checkpoint(|| a.exp());
+            let c = cx.checkpoint(|| b.sin());
+            assert_eq!(cx.attribute::<Checkpointed>(b.id), Some(&Checkpointed(0)));
+            assert_eq!(cx.attribute::<Checkpointed>(c.id), Some(&Checkpointed(1)));
+        }
+    }
+
+    #[test]
+    fn test_gradient_clipping() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set([1., 2.]);
+        let b = cx.tensor::<R2<2, 2>>().set([[0., 3.], [1., 1.]]);
+        cx.mark_trainable((a, b));
+        // The gradients are [3, -4] and [[0, 2], [-2, 4]], for a global norm of 7
+        let loss = (a * cx.tensor::<R1<2>>().set([3., -4.])).sum_reduce()
+            + (b * cx.tensor::<R2<2, 2>>().set([[0., 2.], [-2., 4.]])).sum_reduce();
+        let grads = cx.backward(loss);
+        let (clipped, norm) = grads.clip_norm(3.5, &mut cx);
+        let (unclipped, _) = grads.clip_norm(10., &mut cx);
+        let clamped = grads.clip_value(1.5, &mut cx);
+        norm.retrieve();
+        cx.keep_tensors((&clipped, &unclipped, &clamped));
+        cx.execute();
+
+        assert_close(&norm.data(), &[7.]);
+        let data = |grads: &super::Gradients, cx: &mut Graph| {
+            grads
+                .grads()
+                .iter()
+                .map(|g| get_vec(*g, cx))
+                .collect::<Vec<_>>()
+        };
+        assert_close(&data(&clipped, &mut cx)[0], &[1.5, -2.]);
+        assert_close(&data(&clipped, &mut cx)[1], &[0., 1., -1., 2.]);
+        assert_close(&data(&unclipped, &mut cx)[0], &[3., -4.]);
+        assert_close(&data(&unclipped, &mut cx)[1], &[0., 2., -2., 4.]);
+        assert_close(&data(&clamped, &mut cx)[0], &[1.5, -1.5]);
+        assert_close(&data(&clamped, &mut cx)[1], &[0., 1.5, -1.5, 1.5]);
+    }
 }
@@ -0,0 +1,245 @@
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use itertools::Itertools;
+use luminal::{
+    prelude::*,
+    serialization::safetensors::{read_tensors, write_tensors, SafeTensorError, TensorMap},
+};
+
+use crate::OptimizerUpdate;
+
+const TENSORS_FILE: &str = "checkpoint.safetensors";
+const STATE_FILE: &str = "training_state.json";
+
+/// Where a training run was when it was checkpointed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrainingState {
+    /// The number of optimizer steps taken
+    pub step: u64,
+    /// The graph's random seed
+    pub seed: u64,
+}
+
+/// Save a training run to a directory so it can be resumed with `load_checkpoint`. The model's weights, the optimizer
+/// state (like Adam's moments and step count) and the learning rate are saved to `checkpoint.safetensors`, and the
+/// step and the graph's random seed to `training_state.json`.
+///
+/// Call this after `OptimizerUpdate::step`, while the graph holds the updated weights and state as f32s on the CPU.
+///
+/// Random ops continue their streams across executions, and the positions in those streams aren't saved. For a resumed
+/// run to draw the same random numbers as an uninterrupted one, like for dropout, derive the seed from the step with
+/// `set_seed` each iteration.
+pub fn save_checkpoint<M: SerializeModule>(
+    dir: impl AsRef<Path>,
+    model: &M,
+    optimizer: &OptimizerUpdate,
+    step: u64,
+    graph: &Graph,
+) -> Result<(), SafeTensorError> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut tensors = TensorMap::default();
+    for (name, node, shape) in checkpoint_tensors(model, optimizer) {
+        let data = graph
+            .get_tensor_ref(node, 0)
+            .and_then(|t| t.f32_data())
+            .ok_or_else(|| invalid_data(format!("{name} isn't held by the graph as f32s")))?;
+        let shape = shape
+            .and_then(|s| {
+                s.shape()
+                    .into_iter()
+                    .map(|e| e.exec(&graph.dyn_map))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .unwrap_or(vec![data.len()]);
+        tensors.insert(name, (shape, data.into_owned()));
+    }
+    write_tensors(dir.join(TENSORS_FILE), &tensors)?;
+    let state = serde_json::json!({ "step": step, "seed": graph.seed });
+    fs::write(dir.join(STATE_FILE), serde_json::to_string_pretty(&state)?)?;
+    Ok(())
+}
+
+/// Resume a training run saved by `save_checkpoint`, replacing the model's weights, the optimizer state and the
+/// learning rate, and restoring the graph's random seed. The model and optimizer must be built the same way as in the
+/// saved run.
+///
+/// The tensors are set on the graph directly, so the ids passed in need to be current if this is called after
+/// compiling, and the weights need to be kept so the next execution doesn't consume them.
+pub fn load_checkpoint<M: SerializeModule>(
+    dir: impl AsRef<Path>,
+    model: &M,
+    optimizer: &OptimizerUpdate,
+    graph: &mut Graph,
+) -> Result<TrainingState, SafeTensorError> {
+    let dir = dir.as_ref();
+    let state: serde_json::Value = serde_json::from_reader(File::open(dir.join(STATE_FILE))?)?;
+    let field = |key: &str| {
+        state[key]
+            .as_u64()
+            .ok_or_else(|| invalid_data(format!("{STATE_FILE} has no {key}")))
+    };
+    let state = TrainingState {
+        step: field("step")?,
+        seed: field("seed")?,
+    };
+
+    // Match up every tensor before touching the graph, so a mismatched checkpoint doesn't leave it half loaded
+    let mut tensors = read_tensors(dir.join(TENSORS_FILE))?;
+    let mut loaded = vec![];
+    for (name, node, shape) in checkpoint_tensors(model, optimizer) {
+        let (_, data) = tensors
+            .remove(&name)
+            .ok_or(SafeTensorError::TensorNotFound(name.clone()))?;
+        if let Some(n) = shape.and_then(|s| s.n_elements().exec(&graph.dyn_map)) {
+            if n != data.len() {
+                return Err(invalid_data(format!(
+                    "{name} has {} elements in the checkpoint, but {n} in the model",
+                    data.len()
+                )));
+            }
+        }
+        loaded.push((node, data));
+    }
+    if !tensors.is_empty() {
+        return Err(invalid_data(format!(
+            "The checkpoint has tensors the model and optimizer don't: {}",
+            tensors.keys().sorted().join(", ")
+        )));
+    }
+
+    for (node, data) in loaded {
+        graph.set_tensor(node, 0, Tensor::new(data));
+    }
+    graph.set_seed(state.seed);
+    Ok(state)
+}
+
+/// The tensors in a checkpoint by name, with their shapes if they're known
+fn checkpoint_tensors<M: SerializeModule>(
+    model: &M,
+    optimizer: &OptimizerUpdate,
+) -> Vec<(String, NodeIndex, Option<ShapeTracker>)> {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    let model = s.state.into_iter().sorted().map(|(path, node)| {
+        let shape = s.shapes.get(&path).copied();
+        (format!("model.{}", path.replace('/', ".")), node, shape)
+    });
+    let state = optimizer
+        .state
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (format!("optimizer.state.{i}"), *node, None));
+    model
+        .chain(state)
+        .chain([("optimizer.lr".to_string(), optimizer.lr.id, None)])
+        .collect()
+}
+
+fn invalid_data(message: String) -> SafeTensorError {
+    SafeTensorError::IoError(Error::new(ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mse_loss, Adam, Backward, Mean, Optimizer};
+    use luminal::prelude::{InitModule, Module};
+    use luminal_nn::{Dropout, Linear, Swish};
+    luminal::test_imports!();
+
+    type Model = (Linear<4, 8>, Swish, Dropout, Linear<8, 2>);
+
+    struct Run {
+        // Boxed so the graph doesn't move after its tensors point to it
+        cx: Box<Graph>,
+        model: Model,
+        weights: Vec<NodeIndex>,
+        update: OptimizerUpdate,
+    }
+
+    impl Run {
+        fn new() -> Self {
+            let mut cx = Box::new(Graph::new());
+            cx.set_training(true);
+            let model = Model::initialize(&mut cx);
+            let input = cx
+                .tensor::<R2<3, 4>>()
+                .set(vec![0.5, -1., 2., 0., 1., 1., -0.5, 3., -2., 0.25, 1., -1.]);
+            let target = cx.tensor::<R2<3, 2>>().set(vec![1., 0., -1., 2., 0.5, 0.5]);
+            let loss = mse_loss(model.forward(input), target, Mean);
+            let weights = params(&model);
+            cx.keep_tensors(&weights);
+            cx.mark_trainable(&weights);
+            let grads = cx.backward(loss);
+            let update = Adam::new(1e-2).update(&mut cx, &weights, grads.grads());
+            Self {
+                cx,
+                model,
+                weights,
+                update,
+            }
+        }
+
+        fn train(&mut self, steps: std::ops::Range<u64>) {
+            for step in steps {
+                // Seeding from the step keeps dropout the same when resuming
+                self.cx.set_seed(step);
+                self.cx.execute();
+                self.update.step(&self.weights, &mut self.cx);
+            }
+        }
+
+        fn weights(&self) -> Vec<Vec<f32>> {
+            self.weights
+                .iter()
+                .map(|w| {
+                    self.cx
+                        .get_tensor_ref(*w, 0)
+                        .unwrap()
+                        .downcast_ref::<Vec<f32>>()
+                        .unwrap()
+                        .clone()
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_resume_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("luminal_checkpoint_{}", std::process::id()));
+        let mut run = Run::new();
+        run.train(0..3);
+        save_checkpoint(&dir, &run.model, &run.update, 3, &run.cx).unwrap();
+        run.train(3..6);
+
+        let mut resumed = Run::new();
+        let state =
+            load_checkpoint(&dir, &resumed.model, &resumed.update, &mut resumed.cx).unwrap();
+        assert_eq!(state, TrainingState { step: 3, seed: 2 });
+        resumed.train(state.step..6);
+        for (a, b) in run.weights().iter().zip(resumed.weights()) {
+            assert_exact(a, &b);
+        }
+
+        // A different optimizer's state doesn't fit
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        let weights = params(&model);
+        let update = crate::Sgd::new(1e-2).update(
+            &mut cx,
+            &weights,
+            &weights
+                .iter()
+                .map(|w| (*w, ShapeTracker::new(&[])))
+                .collect::<Vec<_>>(),
+        );
+        assert!(load_checkpoint(&dir, &model, &update, &mut cx).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
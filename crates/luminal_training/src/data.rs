@@ -0,0 +1,278 @@
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use luminal::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+/// A collection of training examples that can be read by index
+pub trait Dataset {
+    type Item;
+    /// The number of examples
+    fn len(&self) -> usize;
+    /// Read the example at an index, which is less than `len`
+    fn get(&self, index: usize) -> Self::Item;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> Dataset for Vec<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn get(&self, index: usize) -> T {
+        self[index].clone()
+    }
+}
+
+/// Splits a dataset into batches each epoch, optionally shuffling the examples and reading batches ahead on a
+/// background thread while the current one trains.
+pub struct DataLoader<D> {
+    dataset: Arc<D>,
+    batch_size: usize,
+    shuffle_seed: Option<u64>,
+    drop_last: bool,
+    prefetch: usize,
+}
+
+impl<D: Dataset> DataLoader<D> {
+    pub fn new(dataset: D, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batch size must be at least 1");
+        Self {
+            dataset: Arc::new(dataset),
+            batch_size,
+            shuffle_seed: None,
+            drop_last: false,
+            prefetch: 0,
+        }
+    }
+
+    /// Shuffle the examples each epoch. The order only depends on the seed and the epoch, so a resumed run sees the
+    /// same batches as an uninterrupted one.
+    pub fn with_shuffle(mut self, seed: u64) -> Self {
+        self.shuffle_seed = Some(seed);
+        self
+    }
+
+    /// Leave out the last batch of an epoch if it's smaller than the batch size
+    pub fn with_drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    /// Read up to this many batches ahead on a background thread. 0 reads each batch when it's asked for.
+    pub fn with_prefetch(mut self, batches: usize) -> Self {
+        self.prefetch = batches;
+        self
+    }
+
+    pub fn dataset(&self) -> &D {
+        &self.dataset
+    }
+
+    /// The number of batches in each epoch
+    pub fn len(&self) -> usize {
+        if self.drop_last {
+            self.dataset.len() / self.batch_size
+        } else {
+            self.dataset.len().div_ceil(self.batch_size)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The indexes of the examples in each batch of an epoch
+    fn batch_indexes(&self, epoch: u64) -> Vec<Vec<usize>> {
+        let mut order = (0..self.dataset.len()).collect::<Vec<_>>();
+        if let Some(seed) = self.shuffle_seed {
+            let stream = seed ^ epoch.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            order.shuffle(&mut StdRng::seed_from_u64(stream));
+        }
+        order
+            .chunks(self.batch_size)
+            .take(self.len())
+            .map(|c| c.to_vec())
+            .collect()
+    }
+}
+
+impl<D: Dataset + Send + Sync + 'static> DataLoader<D>
+where
+    D::Item: Send + 'static,
+{
+    /// Iterate over the batches of an epoch
+    pub fn epoch(&self, epoch: u64) -> Batches<D::Item> {
+        let indexes = self.batch_indexes(epoch);
+        let remaining = indexes.len();
+        let dataset = self.dataset.clone();
+        let batches = indexes
+            .into_iter()
+            .map(move |batch| batch.into_iter().map(|i| dataset.get(i)).collect());
+        let source = if self.prefetch == 0 {
+            BatchSource::Inline(Box::new(batches))
+        } else {
+            // The thread stops once the receiver is dropped, or it runs out of batches
+            let (sender, receiver) = mpsc::sync_channel(self.prefetch);
+            thread::spawn(move || {
+                for batch in batches {
+                    if sender.send(batch).is_err() {
+                        break;
+                    }
+                }
+            });
+            BatchSource::Prefetched(receiver)
+        };
+        Batches { source, remaining }
+    }
+}
+
+/// The batches of an epoch, from `DataLoader::epoch`
+pub struct Batches<T> {
+    source: BatchSource<T>,
+    remaining: usize,
+}
+
+enum BatchSource<T> {
+    Inline(Box<dyn Iterator<Item = Vec<T>>>),
+    Prefetched(mpsc::Receiver<Vec<T>>),
+}
+
+impl<T> Iterator for Batches<T> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Vec<T>> {
+        let batch = match &mut self.source {
+            BatchSource::Inline(batches) => batches.next(),
+            BatchSource::Prefetched(receiver) => receiver.recv().ok(),
+        }?;
+        self.remaining -= 1;
+        Some(batch)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> ExactSizeIterator for Batches<T> {}
+
+/// Token sequences batched for next-token prediction. Each input row is a sequence without its last token, and each
+/// target row is the sequence without its first token, so position `i` of the targets is the token following
+/// position `i` of the inputs. Rows are padded to the longest sequence in the batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CausalLmBatch {
+    /// Input token ids, padded with the pad token
+    pub inputs: Vec<f32>,
+    /// Target token ids, padded with the ignore index so the padding is left out of `cross_entropy_loss`
+    pub targets: Vec<f32>,
+    pub batch_size: usize,
+    pub seq_len: usize,
+}
+
+impl CausalLmBatch {
+    pub fn new<T: AsRef<[u32]>>(sequences: &[T], pad_token: u32, ignore_index: usize) -> Self {
+        let seq_len = sequences
+            .iter()
+            .map(|s| s.as_ref().len().saturating_sub(1))
+            .max()
+            .unwrap_or_default();
+        assert!(
+            seq_len > 0,
+            "At least one sequence needs two tokens to predict from"
+        );
+        let (mut inputs, mut targets) = (vec![], vec![]);
+        for sequence in sequences {
+            let sequence = sequence.as_ref();
+            let n = sequence.len().saturating_sub(1);
+            inputs.extend(sequence[..n].iter().map(|t| *t as f32));
+            inputs.extend(std::iter::repeat_n(pad_token as f32, seq_len - n));
+            targets.extend(sequence.iter().skip(1).map(|t| *t as f32));
+            targets.extend(std::iter::repeat_n(ignore_index as f32, seq_len - n));
+        }
+        Self {
+            inputs,
+            targets,
+            batch_size: sequences.len(),
+            seq_len,
+        }
+    }
+
+    /// Set input and target tensors to this batch, along with their dynamic dimensions
+    pub fn set<B: Dimension, L: Dimension>(
+        &self,
+        inputs: GraphTensor<(B, L)>,
+        targets: GraphTensor<(B, L)>,
+    ) {
+        let shape = [self.batch_size, self.seq_len];
+        inputs.set_dyn(self.inputs.clone(), &shape);
+        targets.set_dyn(self.targets.clone(), &shape);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cross_entropy_loss;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_batches() {
+        let loader = DataLoader::new((0..10).collect::<Vec<_>>(), 4);
+        assert_eq!(loader.len(), 3);
+        let batches = loader.epoch(0);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(
+            batches.collect::<Vec<_>>(),
+            [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+
+        let loader = loader.with_drop_last(true);
+        assert_eq!(loader.len(), 2);
+        assert_eq!(loader.epoch(0).flatten().count(), 8);
+    }
+
+    #[test]
+    fn test_shuffle_and_prefetch() {
+        let loader = DataLoader::new((0..50).collect::<Vec<_>>(), 8).with_shuffle(7);
+        let epoch = |loader: &DataLoader<Vec<i32>>, epoch| loader.epoch(epoch).collect::<Vec<_>>();
+        let first = epoch(&loader, 0);
+        // Every example is seen once, in an order that changes each epoch but is the same for the same epoch
+        let mut seen = first.iter().flatten().copied().collect::<Vec<_>>();
+        assert_ne!(seen, (0..50).collect::<Vec<_>>());
+        seen.sort();
+        assert_eq!(seen, (0..50).collect::<Vec<_>>());
+        assert_ne!(first, epoch(&loader, 1));
+        assert_eq!(first, epoch(&loader, 0));
+
+        let loader = loader.with_prefetch(2);
+        assert_eq!(first, epoch(&loader, 0));
+        // Stopping early doesn't block the prefetching thread
+        assert_eq!(loader.epoch(0).next(), Some(first[0].clone()));
+    }
+
+    #[test]
+    fn test_causal_lm_batch() {
+        let batch = CausalLmBatch::new(&[vec![5, 6, 7, 8], vec![9, 10]], 0, 100);
+        assert_eq!((batch.batch_size, batch.seq_len), (2, 3));
+        assert_eq!(batch.inputs, [5., 6., 7., 9., 0., 0.]);
+        assert_eq!(batch.targets, [6., 7., 8., 10., 100., 100.]);
+
+        // The padded targets are left out of the loss
+        let mut cx = Graph::new();
+        let inputs = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Inputs");
+        let targets = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Targets");
+        let logits = inputs.expand::<(Dyn<'b'>, Dyn<'s'>, LConst<12>), _>() * 0.;
+        let loss = cross_entropy_loss(logits, targets, 0., Some(100)).retrieve();
+        batch.set(inputs, targets);
+        cx.execute();
+        assert_close(&loss.data(), &[12f32.ln()]);
+    }
+}
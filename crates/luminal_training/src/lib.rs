@@ -1,6 +1,12 @@
 mod autograd;
 pub use autograd::*;
+mod checkpoint;
+pub use checkpoint::*;
+mod data;
+pub use data::*;
 mod loss;
 pub use loss::*;
+mod mixed_precision;
+pub use mixed_precision::*;
 mod optimizer;
 pub use optimizer::*;
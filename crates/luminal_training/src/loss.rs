@@ -1,24 +1,71 @@
 use luminal::prelude::*;
 
+/// How a loss combines its per-element (or per-row) values
+pub trait Reduction {
+    type Output<S: Shape>;
+    fn reduce<S: Shape>(&self, loss: GraphTensor<S>) -> Self::Output<S>;
+}
+
+/// Average the losses into a scalar
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Mean;
+
+impl Reduction for Mean {
+    type Output<S: Shape> = GraphTensor<()>;
+    fn reduce<S: Shape>(&self, loss: GraphTensor<S>) -> GraphTensor<()> {
+        loss.mean_reduce()
+    }
+}
+
+/// Add up the losses into a scalar
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sum;
+
+impl Reduction for Sum {
+    type Output<S: Shape> = GraphTensor<()>;
+    fn reduce<S: Shape>(&self, loss: GraphTensor<S>) -> GraphTensor<()> {
+        loss.sum_reduce()
+    }
+}
+
+/// Keep each loss separate, like to weight them before reducing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoReduction;
+
+impl Reduction for NoReduction {
+    type Output<S: Shape> = GraphTensor<S>;
+    fn reduce<S: Shape>(&self, loss: GraphTensor<S>) -> GraphTensor<S> {
+        loss
+    }
+}
+
 /// [Mean Squared Error](https://en.wikipedia.org/wiki/Mean_squared_error).
 ///
-/// This computes `(prediction - target).square().mean()`.
-pub fn mse_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) -> GraphTensor<()> {
-    (prediction - target).square().mean_reduce()
+/// This computes `(prediction - target).square()`, reduced by `reduction`.
+pub fn mse_loss<S: Shape, R: Reduction>(
+    prediction: GraphTensor<S>,
+    target: GraphTensor<S>,
+    reduction: R,
+) -> R::Output<S> {
+    reduction.reduce((prediction - target).square())
 }
 
 /// [Root Mean square error](https://en.wikipedia.org/wiki/Root-mean-square_deviation).
 ///
 /// This computes `(prediction - target).square().mean().sqrt()`
 pub fn rmse_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) -> GraphTensor<()> {
-    mse_loss(prediction, target).sqrt()
+    mse_loss(prediction, target, Mean).sqrt()
 }
 
 /// [Mean absolute error](https://en.wikipedia.org/wiki/Mean_absolute_error).
 ///
-/// This computes `(prediction - target).abs().mean()`
-pub fn mae_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) -> GraphTensor<()> {
-    (prediction - target).abs().mean_reduce()
+/// This computes `(prediction - target).abs()`, reduced by `reduction`.
+pub fn mae_loss<S: Shape, R: Reduction>(
+    prediction: GraphTensor<S>,
+    target: GraphTensor<S>,
+    reduction: R,
+) -> R::Output<S> {
+    reduction.reduce((prediction - target).abs())
 }
 
 /// [Huber Loss](https://en.wikipedia.org/wiki/Huber_loss)
@@ -28,18 +75,19 @@ pub fn mae_loss<S: Shape>(prediction: GraphTensor<S>, target: GraphTensor<S>) ->
 /// It computes:
 /// 1. if `|x - y| < delta`: `0.5 * (x - y)^2`
 /// 2. otherwise: `delta * (|x - y| - 0.5 * delta)`
-pub fn huber_loss<S: Shape>(
+pub fn huber_loss<S: Shape, R: Reduction>(
     prediction: GraphTensor<S>,
     target: GraphTensor<S>,
     delta: impl Into<f32>,
-) -> GraphTensor<()> {
+    reduction: R,
+) -> R::Output<S> {
     let delta: f32 = delta.into();
     let abs_error = (prediction - target).abs();
-    let delta_tensor = prediction.graph().constant(delta);
+    let delta_tensor = unsafe { prediction.with_graph(|cx| cx.constant(delta)) };
     let huber_error = (0.5 * (prediction - target).square())
         * abs_error.less_than(delta_tensor.expand())
         + (delta * (abs_error - 0.5 * delta)) * abs_error.greater_than_equal(delta_tensor.expand());
-    huber_error.mean_reduce()
+    reduction.reduce(huber_error)
 }
 
 /// Smooth l1 loss (closely related to [Huber Loss](https://en.wikipedia.org/wiki/Huber_loss))
@@ -49,16 +97,17 @@ pub fn huber_loss<S: Shape>(
 /// It computes:
 /// 1. if `|x - y| < beta`: `0.5 * (x - y)^2 / beta`
 /// 2. otherwise: `|x - y| - 0.5 * beta`
-pub fn smooth_l1_loss<S: Shape>(
+pub fn smooth_l1_loss<S: Shape, R: Reduction>(
     prediction: GraphTensor<S>,
     target: GraphTensor<S>,
     delta: impl Copy + Into<f32>,
-) -> GraphTensor<()> {
-    huber_loss(prediction, target, delta) / delta.into()
+    reduction: R,
+) -> R::Output<S> {
+    reduction.reduce(huber_loss(prediction, target, delta, NoReduction) / delta.into())
 }
 
 /// [Cross entropy loss](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression).
-/// This computes: `-(logits.log_softmax() * target_probs).sum(-1).mean()`
+/// This computes: `-(logits.log_softmax() * target_probs).sum(-1)`, with each row's loss reduced by `reduction`.
 ///
 /// This will call `log_softmax(logits)`, so make sure logits is **not the
 /// output from** [softmax()] or [log_softmax()] already.
@@ -67,20 +116,49 @@ pub fn smooth_l1_loss<S: Shape>(
 ///
 /// - `logits`: The un-normalized output from a model. [log_softmax()] is called **in** this function
 /// - `target_probabilities`: Target containing probability vectors **NOT** class indices.
-pub fn cross_entropy_with_logits_loss<S: Shape>(
+pub fn cross_entropy_with_logits_loss<S: Shape, R: Reduction>(
     logits: GraphTensor<S>,
     target_probabilities: GraphTensor<S>,
-) -> GraphTensor<()> {
-    let inv_last_axis_numel = 1.0
-        / logits
-            .graph()
-            .constant(logits.shape.shape().last().unwrap());
+    reduction: R,
+) -> R::Output<<S as ReduceShape<S::LastAxis>>::Reduced> {
     let probs = logits.log_softmax::<S::LastAxis>();
-    (-(probs * target_probabilities).mean_reduce()) / inv_last_axis_numel
+    reduction.reduce(-(probs * target_probabilities).sum_reduce::<_, S::LastAxis>())
+}
+
+/// [Cross entropy loss](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression)
+/// against class indexes, as a single fused `CrossEntropy` op.
+/// This computes `-logits.log_softmax()[targets].mean()`, with classes along the last axis of `logits`.
+///
+/// ### Inputs
+///
+/// - `logits`: The un-normalized output from a model. **NOT** the output of [softmax()] or [log_softmax()]
+/// - `targets`: The class index of each row of `logits`, as `IndexVec` data or whole number floats
+/// - `label_smoothing`: How much of each target's probability is spread evenly over all classes, from 0 to 1
+/// - `ignore_index`: Rows with this target (like padding) are left out of the loss and the mean
+pub fn cross_entropy_loss<S: Shape>(
+    logits: GraphTensor<S>,
+    targets: GraphTensor<<S as ReduceShape<S::LastAxis>>::Reduced>,
+    label_smoothing: f32,
+    ignore_index: Option<usize>,
+) -> GraphTensor<()> {
+    unsafe {
+        logits.with_graph(|cx| {
+            let new_id = cx
+                .add_op(luminal::op::CrossEntropy {
+                    label_smoothing,
+                    ignore_index,
+                })
+                .input(logits.id, 0, logits.shape)
+                .input(targets.id, 0, targets.shape)
+                .finish();
+            GraphTensor::from_id(new_id, ShapeTracker::new(&[]), cx)
+        })
+    }
 }
 
 /// [KL Divergence loss](https://en.wikipedia.org/wiki/Kullback%E2%80%93Leibler_divergence).
-/// This computes `(target_probs * (target_probs.log() - logits.log_softmax())).sum(-1).mean()`
+/// This computes `(target_probs * (target_probs.log() - logits.log_softmax())).sum(-1)`, with each row's loss reduced
+/// by `reduction`.
 ///
 /// This will call `log_softmax(logits)`, so make sure logits is **not the
 /// output from** [softmax()] or [log_softmax()] already.
@@ -89,32 +167,175 @@ pub fn cross_entropy_with_logits_loss<S: Shape>(
 ///
 /// - `logits`: The un-normalized output from a model. [log_softmax()] is called **in** this function
 /// - `target_probs`: Target containing probability vectors **NOT** class indices.
-pub fn kl_div_with_logits_loss<S: Shape>(
+pub fn kl_div_with_logits_loss<S: Shape, R: Reduction>(
     logits: GraphTensor<S>,
     target_probabilities: GraphTensor<S>,
-) -> GraphTensor<()> {
-    let inv_last_axis_numel = 1.0
-        / logits
-            .graph()
-            .constant(logits.shape.shape().last().unwrap());
+    reduction: R,
+) -> R::Output<<S as ReduceShape<S::LastAxis>>::Reduced> {
     let probs = logits.log_softmax::<S::LastAxis>();
-    (-((probs - target_probabilities.ln()) * target_probabilities).mean_reduce())
-        / inv_last_axis_numel
+    reduction.reduce(
+        -((probs - target_probabilities.ln()) * target_probabilities)
+            .sum_reduce::<_, S::LastAxis>(),
+    )
 }
 
 /// [Binary Cross Entropy](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression)
 /// With Logits in numerically stable way.
 ///
 /// Computes `target_probs * log(sigmoid(logits)) + (1 - target_probs) * log(1 - sigmoid(logits))`
-/// as `(1 - target_probs) * logits + log(1 + exp(-logits))`.
+/// as `(1 - target_probs) * logits + log(1 + exp(-logits))`, reduced by `reduction`.
 ///
 /// ### Inputs
 /// - `logits` - unnormalized inputs. **NOT** output of sigmoid
 /// - `target_probs` - target values between 0 and 1.
-pub fn binary_cross_entropy_with_logits_loss<S: Shape>(
+pub fn binary_cross_entropy_with_logits_loss<S: Shape, R: Reduction>(
     logits: GraphTensor<S>,
     target_probabilities: GraphTensor<S>,
-) -> GraphTensor<()> {
+    reduction: R,
+) -> R::Output<S> {
     let bce = (1.0 - target_probabilities) * logits + (1.0 + (-logits).exp()).ln();
-    bce.mean_reduce()
+    reduction.reduce(bce)
+}
+
+/// [Binary Cross Entropy](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression)
+/// on probabilities, like the output of a sigmoid.
+///
+/// Computes `-(target_probs * log(probs) + (1 - target_probs) * log(1 - probs))`, reduced by `reduction`. The logs are
+/// clamped to be at least -100, so probabilities of exactly 0 or 1 don't give infinite losses.
+/// [binary_cross_entropy_with_logits_loss] is more numerically stable when the logits are available.
+pub fn binary_cross_entropy_loss<S: Shape, R: Reduction>(
+    probabilities: GraphTensor<S>,
+    target_probabilities: GraphTensor<S>,
+    reduction: R,
+) -> R::Output<S> {
+    let bce = -(target_probabilities * probabilities.ln().max_f32(-100.)
+        + (1.0 - target_probabilities) * (1.0 - probabilities).ln().max_f32(-100.));
+    reduction.reduce(bce)
+}
+
+/// Cosine similarity loss, which only cares about the direction of each row and not its magnitude.
+///
+/// Computes `1 - cos(prediction, target)` along the last axis, with each row's loss reduced by `reduction`. The norms
+/// are clamped to be at least `1e-8`, so a row of zeros gives a loss of 1.
+pub fn cosine_similarity_loss<S: Shape, R: Reduction>(
+    prediction: GraphTensor<S>,
+    target: GraphTensor<S>,
+    reduction: R,
+) -> R::Output<<S as ReduceShape<S::LastAxis>>::Reduced> {
+    let dot = (prediction * target).sum_reduce::<_, S::LastAxis>();
+    let norm = |t: GraphTensor<S>| {
+        t.square()
+            .sum_reduce::<_, S::LastAxis>()
+            .sqrt()
+            .max_f32(1e-8)
+    };
+    reduction.reduce(1.0 - dot / (norm(prediction) * norm(target)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        binary_cross_entropy_loss, binary_cross_entropy_with_logits_loss, cosine_similarity_loss,
+        cross_entropy_with_logits_loss, huber_loss, kl_div_with_logits_loss, mae_loss, mse_loss,
+        smooth_l1_loss, Mean, NoReduction, Sum,
+    };
+    use dfdx::losses as d_losses;
+    luminal::test_imports!();
+
+    const PREDICTION: [f32; 6] = [-1.5, 0.25, 3., 0.5, -0.75, 2.];
+    const TARGET: [f32; 6] = [0.5, 0.25, 1., 0.75, -2., 0.];
+    const PROBS: [f32; 6] = [0.1, 0.6, 0.3, 0.0, 0.25, 0.75];
+
+    #[test]
+    fn test_losses() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(PREDICTION.to_vec());
+        let b = cx.tensor::<R2<2, 3>>().set(TARGET.to_vec());
+        let p = cx.tensor::<R2<2, 3>>().set(PROBS.to_vec());
+        let losses = [
+            mse_loss(a, b, Mean),
+            mae_loss(a, b, Mean),
+            huber_loss(a, b, 1.5, Mean),
+            smooth_l1_loss(a, b, 1.5, Mean),
+            cross_entropy_with_logits_loss(a, p, Mean),
+            kl_div_with_logits_loss(a, p, Mean),
+            binary_cross_entropy_with_logits_loss(a, p, Mean),
+        ]
+        .map(|loss| loss.retrieve());
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(PREDICTION.to_vec(), (DConst::<2>, DConst::<3>));
+        let d_b = d_dev.tensor_from_vec(TARGET.to_vec(), (DConst::<2>, DConst::<3>));
+        let d_p = d_dev.tensor_from_vec(PROBS.to_vec(), (DConst::<2>, DConst::<3>));
+        let d_losses = [
+            d_losses::mse_loss(d_a.clone(), d_b.clone()),
+            d_losses::mae_loss(d_a.clone(), d_b.clone()),
+            d_losses::huber_loss(d_a.clone(), d_b.clone(), 1.5),
+            d_losses::smooth_l1_loss(d_a.clone(), d_b.clone(), 1.5),
+            d_losses::cross_entropy_with_logits_loss(d_a.clone(), d_p.clone()),
+            d_losses::kl_div_with_logits_loss(d_a.clone(), d_p.clone()),
+            d_losses::binary_cross_entropy_with_logits_loss(d_a.clone(), d_p.clone()),
+        ];
+        for (loss, d_loss) in losses.iter().zip(d_losses) {
+            assert_close(&loss.data(), &d_loss.as_vec());
+        }
+    }
+
+    #[test]
+    fn test_loss_reductions() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(PREDICTION.to_vec());
+        let b = cx.tensor::<R2<2, 3>>().set(TARGET.to_vec());
+        let p = cx.tensor::<R2<2, 3>>().set(PROBS.to_vec());
+        let mse = mse_loss(a, b, NoReduction).retrieve();
+        let mse_sum = mse_loss(a, b, Sum).retrieve();
+        let ce = cross_entropy_with_logits_loss(a, p, NoReduction).retrieve();
+        let ce_sum = cross_entropy_with_logits_loss(a, p, Sum).retrieve();
+        let ce_mean = cross_entropy_with_logits_loss(a, p, Mean).retrieve();
+        cx.execute();
+
+        let squared_errors = PREDICTION
+            .iter()
+            .zip(TARGET)
+            .map(|(a, b)| (a - b).powi(2))
+            .collect::<Vec<_>>();
+        assert_close(&mse.data(), &squared_errors);
+        assert_close(&mse_sum.data(), &[squared_errors.iter().sum()]);
+        let rows = ce.data();
+        assert_eq!(rows.len(), 2);
+        assert_close(&ce_sum.data(), &[rows[0] + rows[1]]);
+        assert_close(&ce_mean.data(), &[(rows[0] + rows[1]) / 2.]);
+    }
+
+    #[test]
+    fn test_binary_cross_entropy() {
+        let mut cx = Graph::new();
+        let logits = cx.tensor::<R2<2, 3>>().set(PREDICTION.to_vec());
+        let p = cx.tensor::<R2<2, 3>>().set(PROBS.to_vec());
+        let from_probs = binary_cross_entropy_loss(logits.sigmoid(), p, NoReduction).retrieve();
+        let from_logits = binary_cross_entropy_with_logits_loss(logits, p, NoReduction).retrieve();
+        // Probabilities of exactly 0 or 1 are clamped rather than giving infinite losses
+        let saturated = cx.tensor::<R1<2>>().set([0., 1.]);
+        let flipped = cx.tensor::<R1<2>>().set([1., 0.]);
+        let clamped = binary_cross_entropy_loss(saturated, flipped, NoReduction).retrieve();
+        cx.execute();
+
+        assert_close(&from_probs.data(), &from_logits.data());
+        assert_close(&clamped.data(), &[100., 100.]);
+    }
+
+    #[test]
+    fn test_cosine_similarity_loss() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 2>>().set([[1., 0.], [2., 2.], [0., 0.]]);
+        let b = cx.tensor::<R2<3, 2>>().set([[0., 3.], [1., 1.], [1., 2.]]);
+        let loss = cosine_similarity_loss(a, b, NoReduction).retrieve();
+        let mean = cosine_similarity_loss(a, b, Mean).retrieve();
+        cx.execute();
+
+        // Orthogonal, parallel, and zero rows
+        assert_close(&loss.data(), &[1., 0., 1.]);
+        assert_close(&mean.data(), &[2. / 3.]);
+    }
 }
@@ -0,0 +1,295 @@
+use luminal::{
+    op::{Cast, DType},
+    prelude::{
+        petgraph::{visit::EdgeRef, Direction},
+        *,
+    },
+};
+
+use crate::{autograd::sum_all, Gradients, OptimizerUpdate};
+
+/// Runs the forward pass in half precision while the weights stay f32, as master copies for the optimizer to update.
+///
+/// Ops reading the weights read half precision casts of them instead, and since half precision wins when combining
+/// dtypes, the compute downstream of the weights runs in half precision too. Cast the output back to f32 before the
+/// loss to keep the loss itself in full precision.
+///
+/// Run this before `backward`, so the gradients flow back through the casts to the f32 weights. For f16, which has a
+/// much smaller range than f32, scale the loss with a `LossScaler` so small gradients don't underflow.
+#[derive(Debug)]
+pub struct MixedPrecision {
+    weights: Vec<NodeIndex>,
+    dtype: DType,
+}
+
+impl MixedPrecision {
+    pub fn new(weights: impl ToIds, dtype: DType) -> Self {
+        assert!(
+            matches!(dtype, DType::F16 | DType::Bf16),
+            "Mixed precision computes in f16 or bf16, not {dtype:?}"
+        );
+        Self {
+            weights: weights.to_ids(),
+            dtype,
+        }
+    }
+}
+
+impl Compiler for MixedPrecision {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for &weight in &self.weights {
+            if !graph.contains_node(weight) {
+                continue;
+            }
+            let consumers = graph
+                .edges_directed(weight, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.target(), d, e.id())))
+                .collect::<Vec<_>>();
+            let Some((_, (_, _, shape), _)) = consumers.first() else {
+                continue;
+            };
+            // Consumers may read the weight through different views, so cast its whole underlying buffer and let each
+            // one keep its view of it
+            let stored = shape
+                .dims
+                .iter()
+                .zip(&shape.fake)
+                .filter(|(_, fake)| !**fake)
+                .map(|(d, _)| *d)
+                .collect::<Vec<_>>();
+            let cast = graph
+                .add_op(Cast(self.dtype))
+                .input(weight, 0, ShapeTracker::new(&stored))
+                .finish();
+            for (target, (input_order, output_order, shape), edge) in consumers {
+                graph.graph.remove_edge(edge);
+                graph.add_edge(
+                    cast,
+                    target,
+                    Dependency::Data {
+                        input_order,
+                        output_order,
+                        shape,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Dynamic loss scaling, which keeps small gradients from underflowing when computing in f16.
+///
+/// The loss is multiplied by a large scale before the backward pass, and the gradients are divided by it again in f32
+/// before the optimizer uses them. If the scale is so large that the gradients overflow, the step is skipped and the
+/// scale is backed off. After enough steps in a row without overflowing, the scale is grown again.
+///
+/// Pass this in with the other ids when compiling, and call `step` after each execution in place of
+/// `OptimizerUpdate::step`.
+#[derive(Debug, Clone)]
+pub struct LossScaler {
+    scale: GraphTensor<()>,
+    /// 0 if the gradients are all finite, otherwise NaN
+    overflow: Option<GraphTensor<()>>,
+    current: f32,
+    good_steps: usize,
+    /// How much to grow the scale by after `growth_interval` steps without overflowing
+    pub growth_factor: f32,
+    /// How much to shrink the scale by when the gradients overflow
+    pub backoff_factor: f32,
+    pub growth_interval: usize,
+}
+
+impl LossScaler {
+    /// A loss scaler starting at `initial_scale`, usually 2^16
+    pub fn new(initial_scale: f32, graph: &mut Graph) -> Self {
+        Self {
+            scale: graph.named_tensor("Loss Scale").set(initial_scale).keep(),
+            overflow: None,
+            current: initial_scale,
+            good_steps: 0,
+            growth_factor: 2.,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+        }
+    }
+
+    /// The current scale
+    pub fn scale(&self) -> f32 {
+        self.current
+    }
+
+    /// Multiply the loss by the scale, to be used for the backward pass
+    pub fn scale_loss(&self, loss: GraphTensor<()>) -> GraphTensor<()> {
+        loss * self.scale
+    }
+
+    /// Divide the gradients of the scaled loss by the scale in f32, giving the gradients of the original loss. These
+    /// are the gradients to clip and pass to the optimizer.
+    pub fn unscale(&mut self, grads: &Gradients, graph: &mut Graph) -> Gradients {
+        let inv_scale = 1. / self.scale;
+        let mut overflow = graph.constant(0.);
+        let unscaled = grads.map(graph, |grad| {
+            let grad = grad.cast(DType::F32) * inv_scale.expand_to(grad.shape);
+            // Infinities and NaNs turn into NaN when multiplied by 0, and the NaN carries through the sum
+            let zeroed = grad * 0.;
+            overflow += unsafe { zeroed.with_graph(|cx| sum_all(zeroed, cx)) };
+            grad
+        });
+        self.overflow = Some(overflow.retrieve());
+        unscaled
+    }
+
+    /// Once the graph has executed, move the new weights and optimizer state into the old ones if the gradients were
+    /// finite, otherwise throw the update away. The scale is adjusted either way. Returns if the step was taken.
+    pub fn step(
+        &mut self,
+        update: &OptimizerUpdate,
+        weights: impl ToIds,
+        graph: &mut Graph,
+    ) -> bool {
+        let overflow = self
+            .overflow
+            .expect("Unscale the gradients before stepping the loss scaler");
+        let finite = overflow.data()[0].is_finite();
+        overflow.drop();
+        if finite {
+            update.step(weights, graph);
+            self.good_steps += 1;
+            if self.good_steps == self.growth_interval {
+                self.current *= self.growth_factor;
+                self.good_steps = 0;
+            }
+        } else {
+            graph.drop_tensors(&update.new_weights);
+            graph.drop_tensors(&update.new_state);
+            self.current *= self.backoff_factor;
+            self.good_steps = 0;
+        }
+        graph.set_tensor(self.scale.id, 0, Tensor::new(vec![self.current]));
+        finite
+    }
+}
+
+impl ToIds for LossScaler {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        [self.scale.id]
+            .into_iter()
+            .chain(self.overflow.map(|o| o.id))
+            .collect()
+    }
+}
+
+impl ToIdsMut for LossScaler {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        let mut ids = vec![&mut self.scale.id];
+        if let Some(overflow) = &mut self.overflow {
+            ids.push(&mut overflow.id);
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mse_loss, Adam, Backward, Mean, Optimizer};
+    use luminal::op::HalfVec;
+    luminal::test_imports!();
+
+    const W1: [f32; 32] = [
+        0.3, -0.2, 0.5, 0.1, -0.4, 0.2, 0.6, -0.1, 0.2, 0.4, -0.3, 0.5, 0.1, -0.6, 0.3, 0.2, -0.5,
+        0.1, 0.2, -0.3, 0.4, 0.3, -0.2, 0.6, 0.1, 0.5, -0.1, 0.2, -0.3, 0.4, 0.1, -0.2,
+    ];
+    const W2: [f32; 16] = [
+        0.5, -0.3, 0.2, 0.4, -0.1, 0.6, 0.3, -0.2, 0.4, 0.1, -0.5, 0.3, 0.2, -0.4, 0.1, 0.5,
+    ];
+    const X: [f32; 12] = [1., -0.5, 0.25, 2., 0.5, 1.5, -1., 0.75, -2., 0.5, 1., -0.25];
+    const Y: [f32; 6] = [0.5, -1., 1., 0.25, -0.5, 2.];
+
+    type Weights = (GraphTensor<R2<4, 8>>, GraphTensor<R2<8, 2>>);
+
+    /// A two layer MLP, returning the weights, the hidden activations and the loss
+    fn mlp(cx: &mut Graph) -> (Weights, GraphTensor<R2<3, 8>>, GraphTensor<()>) {
+        let w1 = cx.tensor::<R2<4, 8>>().set(W1.to_vec()).keep();
+        let w2 = cx.tensor::<R2<8, 2>>().set(W2.to_vec()).keep();
+        let x = cx.tensor::<R2<3, 4>>().set(X.to_vec());
+        let y = cx.tensor::<R2<3, 2>>().set(Y.to_vec());
+        let hidden = x.matmul(w1).swish();
+        let out = hidden.matmul(w2).cast(DType::F32);
+        cx.mark_trainable((w1, w2));
+        ((w1, w2), hidden, mse_loss(out, y, Mean))
+    }
+
+    #[test]
+    fn test_mixed_precision() {
+        let mut cx = Graph::new();
+        let (weights, _, loss) = mlp(&mut cx);
+        let grads = cx.backward(loss);
+        cx.keep_tensors(&grads);
+        cx.execute();
+        let f32_grads = [
+            grads.get(weights.0).unwrap().data(),
+            grads.get(weights.1).unwrap().data(),
+        ];
+
+        let mut cx = Graph::new();
+        let (weights, hidden, loss) = mlp(&mut cx);
+        cx.compile(MixedPrecision::new(weights, DType::Bf16), ());
+        let grads = cx.backward(loss);
+        let update = Adam::new(1e-2).update(&mut cx, weights, grads.grads());
+        hidden.retrieve();
+        cx.keep_tensors(&grads);
+        cx.execute();
+        update.step(weights, &mut cx);
+
+        // The compute runs in bf16, while the gradients and weights are f32
+        let hidden = cx.get_tensor_ref(hidden.id, 0).unwrap();
+        assert_eq!(
+            hidden.downcast_ref::<HalfVec>().unwrap().dtype(),
+            DType::Bf16
+        );
+        let (g1, g2) = (grads.get(weights.0).unwrap(), grads.get(weights.1).unwrap());
+        let grads = [(g1.id, g1.data()), (g2.id, g2.data())];
+        for ((id, grad), f32_grad) in grads.iter().zip(&f32_grads) {
+            assert!(cx.get_tensor_ref(*id, 0).unwrap().is::<Vec<f32>>());
+            assert_close_precision(grad, f32_grad, 2e-2);
+        }
+        assert!(cx.get_tensor_ref(weights.0.id, 0).unwrap().is::<Vec<f32>>());
+        assert!(cx.get_tensor_ref(weights.1.id, 0).unwrap().is::<Vec<f32>>());
+    }
+
+    #[test]
+    fn test_loss_scaling() {
+        let mut cx = Graph::new();
+        let (weights, _, loss) = mlp(&mut cx);
+        loss.retrieve();
+        cx.compile(MixedPrecision::new(weights, DType::F16), ());
+        // Large enough for the f16 gradients to overflow
+        let mut scaler = LossScaler::new(2f32.powi(24), &mut cx);
+        scaler.growth_interval = 4;
+        let grads = cx.backward(scaler.scale_loss(loss));
+        let grads = scaler.unscale(&grads, &mut cx);
+        let update = Adam::new(1e-2).update(&mut cx, weights, grads.grads());
+
+        cx.execute();
+        assert!(!scaler.step(&update, weights, &mut cx));
+        assert_eq!(scaler.scale(), 2f32.powi(23));
+        assert_exact(&weights.0.data(), &W1);
+        loss.drop();
+
+        let (mut steps, mut losses, mut scales) = (0, vec![], vec![]);
+        for _ in 0..40 {
+            cx.execute();
+            steps += scaler.step(&update, weights, &mut cx) as usize;
+            scales.push(scaler.scale());
+            losses.push(loss.data()[0]);
+            loss.drop();
+        }
+        assert!(steps > 20, "{steps}");
+        assert!(losses[39] < losses[0] * 0.8, "{losses:?}");
+        // The scale grows after enough steps without overflowing, and backs off again when it overflows
+        assert!(scales.windows(2).any(|s| s[1] > s[0]));
+        assert!(scales.windows(3).any(|s| s[1] > s[0] && s[2] < s[1]));
+    }
+}
@@ -58,5 +58,394 @@ pub fn sgd_on_graph(
     (new_weights, lr)
 }
 
-// /// Implements the [Adam](https://arxiv.org/abs/1412.6980) algorithm.
-// pub fn adam(grads: &[(NodeIndex, ShapeTracker)]) {}
+/// Builds weight update ops into a graph
+pub trait Optimizer {
+    /// Add ops that compute the updated weights from their gradients, which match 1-1 with the weights
+    fn update(
+        &self,
+        graph: &mut Graph,
+        weights: impl ToIds,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> OptimizerUpdate;
+}
+
+/// The update ops built by an optimizer.
+///
+/// After each execution, call `step` to move the new weights and optimizer state into the old ones for the next
+/// iteration. The ids need to be remapped when compiling, so pass this in with the other ids.
+#[derive(Debug, Clone)]
+pub struct OptimizerUpdate {
+    /// The updated weights, matching 1-1 with the weights passed to the optimizer
+    pub new_weights: Vec<NodeIndex>,
+    /// Optimizer state read by the update, like moment estimates or the step count
+    pub state: Vec<NodeIndex>,
+    /// The updated optimizer state, matching 1-1 with `state`
+    pub new_state: Vec<NodeIndex>,
+    /// The learning rate, which can be set between steps for scheduling
+    pub lr: GraphTensor<()>,
+}
+
+impl OptimizerUpdate {
+    /// Move the new weights and optimizer state into the old ones
+    pub fn step(&self, weights: impl ToIds, graph: &mut Graph) {
+        transfer_data_same_graph(&self.new_weights, weights, graph);
+        transfer_data_same_graph(&self.new_state, &self.state, graph);
+    }
+}
+
+impl ToIds for OptimizerUpdate {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        [&self.new_weights, &self.state, &self.new_state]
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain([self.lr.id])
+            .collect()
+    }
+}
+
+impl ToIdsMut for OptimizerUpdate {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        self.new_weights
+            .iter_mut()
+            .chain(&mut self.state)
+            .chain(&mut self.new_state)
+            .chain([&mut self.lr.id])
+            .collect()
+    }
+}
+
+/// A tensor of optimizer state, starting at zero
+fn zeros(graph: &mut Graph, name: &str, shape: ShapeTracker) -> GraphTensor<()> {
+    let n = shape.n_elements().to_usize().unwrap();
+    let mut t = graph.named_tensor::<()>(name);
+    t.shape = shape;
+    t.set(vec![0.; n]).keep()
+}
+
+/// [Stochastic Gradient Descent](https://en.wikipedia.org/wiki/Stochastic_gradient_descent) with momentum and L2
+/// weight decay
+///
+/// `velocity = momentum * velocity + gradient + weight_decay * weight`
+///
+/// `new_weight = weight - learning_rate * velocity`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sgd {
+    pub lr: f32,
+    pub momentum: f32,
+    pub weight_decay: f32,
+}
+
+impl Sgd {
+    pub fn new(lr: f32) -> Self {
+        Self {
+            lr,
+            momentum: 0.,
+            weight_decay: 0.,
+        }
+    }
+
+    pub fn with_momentum(mut self, momentum: f32) -> Self {
+        self.momentum = momentum;
+        self
+    }
+
+    pub fn with_weight_decay(mut self, weight_decay: f32) -> Self {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for Sgd {
+    fn update(
+        &self,
+        graph: &mut Graph,
+        weights: impl ToIds,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> OptimizerUpdate {
+        let lr = graph.named_tensor("Learning Rate").set(self.lr).keep();
+        let (mut new_weights, mut state, mut new_state) = (vec![], vec![], vec![]);
+        for ((grad_id, grad_shape), weight_id) in grads.iter().copied().zip(weights.to_ids()) {
+            let weight = GraphTensor::<()>::from_id(weight_id, grad_shape, graph);
+            let mut update = GraphTensor::<()>::from_id(grad_id, grad_shape, graph);
+            if self.weight_decay != 0. {
+                update += weight * self.weight_decay;
+            }
+            if self.momentum != 0. {
+                let velocity = zeros(graph, "SGD Velocity", grad_shape);
+                update = (velocity * self.momentum + update).keep();
+                state.push(velocity.id);
+                new_state.push(update.id);
+            }
+            new_weights.push((weight - update * lr.expand_to(grad_shape)).keep().id);
+        }
+        OptimizerUpdate {
+            new_weights,
+            state,
+            new_state,
+            lr,
+        }
+    }
+}
+
+/// [Adam](https://arxiv.org/abs/1412.6980), with L2 weight decay added to the gradients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adam {
+    pub lr: f32,
+    pub betas: (f32, f32),
+    pub eps: f32,
+    pub weight_decay: f32,
+}
+
+impl Adam {
+    pub fn new(lr: f32) -> Self {
+        Self {
+            lr,
+            betas: (0.9, 0.999),
+            eps: 1e-8,
+            weight_decay: 0.,
+        }
+    }
+
+    pub fn with_betas(mut self, beta1: f32, beta2: f32) -> Self {
+        self.betas = (beta1, beta2);
+        self
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+
+    pub fn with_weight_decay(mut self, weight_decay: f32) -> Self {
+        self.weight_decay = weight_decay;
+        self
+    }
+}
+
+impl Optimizer for Adam {
+    fn update(
+        &self,
+        graph: &mut Graph,
+        weights: impl ToIds,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> OptimizerUpdate {
+        adam_update(graph, weights, grads, *self, false)
+    }
+}
+
+/// [AdamW](https://arxiv.org/abs/1711.05101), Adam with weight decay applied directly to the weights instead of
+/// through the gradients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdamW(pub Adam);
+
+impl AdamW {
+    /// AdamW with the usual weight decay of 0.01
+    pub fn new(lr: f32) -> Self {
+        Self(Adam::new(lr).with_weight_decay(0.01))
+    }
+
+    pub fn with_betas(self, beta1: f32, beta2: f32) -> Self {
+        Self(self.0.with_betas(beta1, beta2))
+    }
+
+    pub fn with_eps(self, eps: f32) -> Self {
+        Self(self.0.with_eps(eps))
+    }
+
+    pub fn with_weight_decay(self, weight_decay: f32) -> Self {
+        Self(self.0.with_weight_decay(weight_decay))
+    }
+}
+
+impl Optimizer for AdamW {
+    fn update(
+        &self,
+        graph: &mut Graph,
+        weights: impl ToIds,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> OptimizerUpdate {
+        adam_update(graph, weights, grads, self.0, true)
+    }
+}
+
+fn adam_update(
+    graph: &mut Graph,
+    weights: impl ToIds,
+    grads: &[(NodeIndex, ShapeTracker)],
+    Adam {
+        lr: initial_lr,
+        betas: (beta1, beta2),
+        eps,
+        weight_decay,
+    }: Adam,
+    decoupled: bool,
+) -> OptimizerUpdate {
+    let lr = graph.named_tensor("Learning Rate").set(initial_lr).keep();
+    // The step count is shared by all weights and is used to correct the bias of the zero-initialized moments
+    let step = graph.named_tensor::<()>("Adam Step").set(0.).keep();
+    let new_step = (step + 1.).keep();
+    let correction1 = 1. - (new_step * beta1.log2()).exp2();
+    let correction2 = 1. - (new_step * beta2.log2()).exp2();
+    let (mut state, mut new_state) = (vec![step.id], vec![new_step.id]);
+    let mut new_weights = vec![];
+    for ((grad_id, grad_shape), weight_id) in grads.iter().copied().zip(weights.to_ids()) {
+        let weight = GraphTensor::<()>::from_id(weight_id, grad_shape, graph);
+        let mut grad = GraphTensor::<()>::from_id(grad_id, grad_shape, graph);
+        if weight_decay != 0. && !decoupled {
+            grad += weight * weight_decay;
+        }
+        let (m, v) = (
+            zeros(graph, "Adam First Moment", grad_shape),
+            zeros(graph, "Adam Second Moment", grad_shape),
+        );
+        let new_m = (m * beta1 + grad * (1. - beta1)).keep();
+        let new_v = (v * beta2 + grad * grad * (1. - beta2)).keep();
+        let m_hat = new_m / correction1.expand_to(grad_shape);
+        let v_hat = new_v / correction2.expand_to(grad_shape);
+        let mut update = m_hat / (v_hat.sqrt() + eps);
+        if weight_decay != 0. && decoupled {
+            update += weight * weight_decay;
+        }
+        new_weights.push((weight - update * lr.expand_to(grad_shape)).keep().id);
+        state.extend([m.id, v.id]);
+        new_state.extend([new_m.id, new_v.id]);
+    }
+    OptimizerUpdate {
+        new_weights,
+        state,
+        new_state,
+        lr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backward, Optimizer};
+    luminal::test_imports!();
+
+    /// Run a few steps on `sum(w * x)`, where the gradient of `w` is always `x`
+    fn run(optimizer: impl Optimizer, steps: usize) -> Vec<f32> {
+        let mut cx = Graph::new();
+        let w = cx.tensor::<R1<3>>().set([1., -2., 0.5]).keep();
+        let x = cx.tensor::<R1<3>>().set([0.5, 1., -3.]);
+        cx.mark_trainable(w);
+        let loss = (w * x).sum_reduce();
+        let grads = cx.backward(loss);
+        let update = optimizer.update(&mut cx, w, grads.grads());
+        for _ in 0..steps {
+            cx.execute();
+            update.step(w, &mut cx);
+        }
+        w.data()
+    }
+
+    #[test]
+    fn test_sgd() {
+        let (w, x) = ([1., -2., 0.5], [0.5_f32, 1., -3.]);
+        let mut expected = w;
+        let mut velocity = [0.; 3];
+        for _ in 0..3 {
+            for i in 0..3 {
+                velocity[i] = 0.9 * velocity[i] + x[i] + 0.1 * expected[i];
+                expected[i] -= 0.01 * velocity[i];
+            }
+        }
+        let sgd = Sgd::new(0.01).with_momentum(0.9).with_weight_decay(0.1);
+        assert_close(&run(sgd, 3), &expected);
+    }
+
+    #[test]
+    fn test_adam() {
+        let (w, x) = ([1., -2., 0.5], [0.5_f32, 1., -3.]);
+        for decoupled in [false, true] {
+            let mut expected = w;
+            let (mut m, mut v) = ([0.; 3], [0.; 3]);
+            for t in 1..=3 {
+                for i in 0..3 {
+                    let g = if decoupled {
+                        x[i]
+                    } else {
+                        x[i] + 0.1 * expected[i]
+                    };
+                    m[i] = 0.9 * m[i] + 0.1 * g;
+                    v[i] = 0.999 * v[i] + 0.001 * g * g;
+                    let m_hat = m[i] / (1. - 0.9_f32.powi(t));
+                    let v_hat = v[i] / (1. - 0.999_f32.powi(t));
+                    let decay = if decoupled { 0.1 * expected[i] } else { 0. };
+                    expected[i] -= 0.01 * (m_hat / (v_hat.sqrt() + 1e-8) + decay);
+                }
+            }
+            let out = if decoupled {
+                run(AdamW::new(0.01).with_weight_decay(0.1), 3)
+            } else {
+                run(Adam::new(0.01).with_weight_decay(0.1), 3)
+            };
+            assert_close(&out, &expected);
+        }
+    }
+
+    #[test]
+    fn test_qlora() {
+        // Train the adapters of a two layer model with int4 base weights. The first layer's adapters get their
+        // gradients through the second layer's frozen weights, which the backward pass reads dequantized.
+        use luminal::prelude::{InitModule, Module};
+        use luminal_cpu::{
+            CPUCompiler, DequantMatMul, Dequantize, Int4Matrix, WeightQuantCompiler,
+        };
+        use luminal_nn::{adapter_params, frozen_params, LoraLinear};
+        let mut cx = Graph::new();
+        let model: (LoraLinear<8, 16, 2>, LoraLinear<16, 4, 2>) = InitModule::initialize(&mut cx);
+        let mut input = cx.tensor::<R2<6, 8>>().set(random_vec(48));
+        let mut target = cx.tensor::<R2<6, 4>>().set(random_vec(24));
+        let mut loss =
+            crate::mse_loss(model.forward(input).swish(), target, crate::Mean).retrieve();
+
+        let mut adapters = adapter_params(&model);
+        let mut frozen = frozen_params(&model);
+        cx.keep_tensors(&adapters);
+        cx.keep_tensors(&frozen);
+        cx.mark_trainable(&adapters);
+        let grads = cx.backward(loss);
+        assert_eq!(grads.params(), adapters);
+        let mut update = Adam::new(1e-2).update(&mut cx, &adapters, grads.grads());
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+                WeightQuantCompiler::new(&frozen, 4),
+            ),
+            (
+                &mut input,
+                &mut target,
+                &mut loss,
+                &mut adapters,
+                &mut frozen,
+                &mut update,
+            ),
+        );
+        let count = |f: fn(&dyn std::any::Any) -> bool| {
+            cx.graph.node_weights().filter(|op| f(op.as_any())).count()
+        };
+        assert_eq!(count(|op| op.is::<DequantMatMul>()), 2);
+        assert_eq!(count(|op| op.is::<Dequantize>()), 1);
+
+        let mut losses = vec![];
+        for _ in 0..50 {
+            cx.execute();
+            update.step(&adapters, &mut cx);
+            losses.push(loss.data()[0]);
+            loss.drop();
+        }
+        assert!(losses[49] < losses[0] * 0.8, "{losses:?}");
+        // The base weights stay quantized
+        for weight in frozen {
+            assert!(cx
+                .get_tensor_ref(weight, 0)
+                .unwrap()
+                .downcast_ref::<Int4Matrix>()
+                .is_some());
+        }
+    }
+}
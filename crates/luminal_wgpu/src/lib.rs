@@ -0,0 +1,407 @@
+use std::{
+    any::{Any, TypeId},
+    fmt::Debug,
+    ops::Deref,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+#[cfg(test)]
+mod tests;
+
+pub mod prim;
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, Buffer, BufferDescriptor, BufferUsages,
+    CommandEncoderDescriptor, ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor,
+    Device, Queue, ShaderModuleDescriptor, ShaderSource,
+};
+
+use luminal::{op::InputTensor, prelude::*};
+
+/// Compile graphs to run on any GPU supported by wgpu (Vulkan, DX12, Metal, OpenGL or WebGPU) in f32
+pub type WgpuCompiler = prim::PrimitiveCompiler;
+
+/// Threads in each workgroup of the generated compute shaders
+const WORKGROUP_SIZE: usize = 256;
+
+/// The wgpu device every kernel and buffer is created on, along with its buffer pool and shader cache
+pub struct WgpuDevice {
+    pub device: Device,
+    pub queue: Queue,
+    /// Released buffers, keyed by their size in bytes
+    pool: Mutex<FxHashMap<u64, Vec<Buffer>>>,
+    /// Compiled pipelines, keyed by their WGSL source
+    pipelines: Mutex<FxHashMap<String, Arc<ComputePipeline>>>,
+}
+
+impl Debug for WgpuDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WgpuDevice")
+    }
+}
+
+/// Get the shared wgpu device, creating it on the highest performance adapter the first time
+pub fn device() -> &'static WgpuDevice {
+    static DEVICE: OnceLock<WgpuDevice> = OnceLock::new();
+    DEVICE.get_or_init(|| {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))
+        .expect("No wgpu adapter found");
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("luminal"),
+                required_limits: adapter.limits(),
+                ..Default::default()
+            },
+            None,
+        ))
+        .unwrap();
+        WgpuDevice {
+            device,
+            queue,
+            pool: Default::default(),
+            pipelines: Default::default(),
+        }
+    })
+}
+
+impl WgpuDevice {
+    /// Get a storage buffer that can hold at least `size` bytes, reusing a released buffer if there is one.
+    ///
+    /// Sizes are rounded up to a power of two so buffers can be reused as dynamic dimensions grow.
+    pub fn buffer(&'static self, size: usize) -> WgpuBuffer {
+        let size = (size.max(4) as u64).next_power_of_two();
+        let buffer = self
+            .pool
+            .lock()
+            .unwrap()
+            .get_mut(&size)
+            .and_then(|b| b.pop())
+            .unwrap_or_else(|| {
+                self.device.create_buffer(&BufferDescriptor {
+                    label: None,
+                    size,
+                    usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            });
+        WgpuBuffer(Arc::new(PooledBuffer {
+            buffer: Some(buffer),
+            device: self,
+        }))
+    }
+
+    /// Get a buffer holding these values
+    pub fn buffer_with_data(&'static self, data: &[f32]) -> WgpuBuffer {
+        let buffer = self.buffer(data.len() * 4);
+        let bytes = data
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        if !bytes.is_empty() {
+            self.queue.write_buffer(&buffer, 0, &bytes);
+        }
+        buffer
+    }
+
+    /// Get a buffer holding these integer parameters
+    fn params_buffer(&'static self, params: &[i32]) -> WgpuBuffer {
+        let buffer = self.buffer(params.len() * 4);
+        let bytes = params
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.queue.write_buffer(&buffer, 0, &bytes);
+        buffer
+    }
+
+    /// Read the first `n_elements` values of a buffer back to the host, waiting for all submitted work to finish
+    pub fn read(&self, buffer: &Buffer, n_elements: usize) -> Vec<f32> {
+        let size = (n_elements * 4) as u64;
+        if size == 0 {
+            return vec![];
+        }
+        let staging = self.device.create_buffer(&BufferDescriptor {
+            label: None,
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit([encoder.finish()]);
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |r| r.unwrap());
+        self.device.poll(wgpu::Maintain::Wait);
+        let data = slice
+            .get_mapped_range()
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        staging.unmap();
+        data
+    }
+
+    /// Compile a compute shader with a `main` entry point, or get it from the cache
+    fn pipeline(&self, source: &str) -> Arc<ComputePipeline> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        record_kernel_cache_lookup(pipelines.contains_key(source));
+        pipelines
+            .entry(source.to_string())
+            .or_insert_with(|| {
+                let module = self.device.create_shader_module(ShaderModuleDescriptor {
+                    label: None,
+                    source: ShaderSource::Wgsl(source.into()),
+                });
+                Arc::new(
+                    self.device
+                        .create_compute_pipeline(&ComputePipelineDescriptor {
+                            label: None,
+                            layout: None,
+                            module: &module,
+                            entry_point: "main",
+                            compilation_options: Default::default(),
+                            cache: None,
+                        }),
+                )
+            })
+            .clone()
+    }
+}
+
+/// A buffer that goes back to the device's pool when it's dropped
+pub struct PooledBuffer {
+    buffer: Option<Buffer>,
+    device: &'static WgpuDevice,
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buffer = self.buffer.take().unwrap();
+        // Work already submitted that uses this buffer runs before anything that reuses it, since the queue is ordered
+        self.device
+            .pool
+            .lock()
+            .unwrap()
+            .entry(buffer.size())
+            .or_default()
+            .push(buffer);
+    }
+}
+
+/// A tensor of f32s on the device
+#[derive(Clone)]
+pub struct WgpuBuffer(pub Arc<PooledBuffer>);
+
+impl Debug for WgpuBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WgpuBuffer({} bytes)", self.size())
+    }
+}
+
+impl Deref for WgpuBuffer {
+    type Target = Buffer;
+    fn deref(&self) -> &Self::Target {
+        self.0.buffer.as_ref().unwrap()
+    }
+}
+
+impl Data for WgpuBuffer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.size() as usize
+    }
+
+    fn device(&self) -> &'static str {
+        "wgpu"
+    }
+}
+
+/// A compiled compute shader, which reads some input buffers and writes one output value per thread.
+///
+/// Shaders are rendered by `render_kernel`, so bindings are the inputs, then the output, then an array of integer
+/// parameters that starts with the number of output elements.
+#[derive(Clone)]
+pub struct WgpuKernel {
+    pipeline: Arc<ComputePipeline>,
+    n_inputs: usize,
+}
+
+impl Debug for WgpuKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WgpuKernel")
+    }
+}
+
+impl WgpuKernel {
+    pub fn new(n_inputs: usize, source: &str) -> Self {
+        Self {
+            pipeline: device().pipeline(source),
+            n_inputs,
+        }
+    }
+
+    /// Submit the kernel, producing `n_elements` outputs. The work is queued and not waited on.
+    pub fn dispatch(
+        &self,
+        inputs: &[&WgpuBuffer],
+        n_elements: usize,
+        params: &[i32],
+    ) -> WgpuBuffer {
+        assert_eq!(inputs.len(), self.n_inputs, "Wrong number of kernel inputs");
+        let dev = device();
+        let out = dev.buffer(n_elements * 4);
+        if n_elements == 0 {
+            return out;
+        }
+        let params = dev.params_buffer(&[&[n_elements as i32], params].concat());
+        let entries = inputs
+            .iter()
+            .copied()
+            .chain([&out, &params])
+            .enumerate()
+            .map(|(i, b)| BindGroupEntry {
+                binding: i as u32,
+                resource: b.as_entire_binding(),
+            })
+            .collect::<Vec<_>>();
+        let bind_group = dev.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        let mut encoder = dev
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            // Workgroup counts are limited in each dimension, so large dispatches spill into y
+            let groups = n_elements.div_ceil(WORKGROUP_SIZE);
+            let max = dev.device.limits().max_compute_workgroups_per_dimension as usize;
+            pass.dispatch_workgroups(groups.min(max) as u32, groups.div_ceil(max) as u32, 1);
+        }
+        dev.queue.submit([encoder.finish()]);
+        out
+    }
+}
+
+/// Render a compute shader that runs `body` once for each output element `i_`, with inputs `inp0`, `inp1`, ... and
+/// integer parameters `params`
+fn render_kernel(n_inputs: usize, body: &str) -> String {
+    let inputs = (0..n_inputs)
+        .map(|i| format!("@group(0) @binding({i}) var<storage, read> inp{i}: array<f32>;"))
+        .join("\n");
+    format!(
+        "{inputs}
+@group(0) @binding({n_inputs}) var<storage, read_write> out: array<f32>;
+@group(0) @binding({}) var<storage, read> params: array<i32>;
+
+@compute @workgroup_size({WORKGROUP_SIZE})
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {{
+    let i_ = i32(gid.x + gid.y * nwg.x * {WORKGROUP_SIZE}u);
+    if (i_ >= params[0]) {{
+        return;
+    }}
+{body}
+}}",
+        n_inputs + 1
+    )
+}
+
+/// The dynamic dimensions used by these shapes, which are passed to kernels as parameters
+fn dyn_symbols(shapes: &[ShapeTracker]) -> Vec<char> {
+    shapes
+        .iter()
+        .flat_map(|st| {
+            st.shape()
+                .into_iter()
+                .chain(
+                    st.padding
+                        .into_iter()
+                        .flat_map(|i| [i.0.into(), i.1.into()]),
+                )
+                .chain(st.mask.into_iter().flat_map(|i| [i.0.into(), i.1.into()]))
+        })
+        .flat_map(|d| d.to_symbols())
+        .unique()
+        .collect()
+}
+
+/// The current values of dynamic dimensions
+fn dyn_values(symbols: &[char], dyn_map: *const FxHashMap<char, usize>) -> Vec<i32> {
+    let dyn_map = unsafe { dyn_map.as_ref().unwrap() };
+    symbols.iter().map(|s| dyn_map[s] as i32).collect()
+}
+
+/// Render an expression as an i32 in WGSL, where `idx` is the index variable and dynamic dimensions are read from
+/// `params`, starting at `offset`
+fn expr_to_wgsl(expr: &BigExpression, symbols: &[char], offset: usize) -> String {
+    let mut stack = vec![];
+    for term in expr.terms.clone() {
+        let new_symbol = match term {
+            Term::Num(n) => n.to_string(),
+            Term::Var('z') => "idx".to_string(),
+            Term::Var(c) => format!(
+                "params[{}]",
+                offset + symbols.iter().position(|s| *s == c).unwrap()
+            ),
+            Term::Max | Term::Min => format!(
+                "{term:?}({}, {})",
+                stack.pop().unwrap(),
+                stack.pop().unwrap()
+            ),
+            Term::Lt | Term::Gte => format!(
+                "select(0, 1, {} {term:?} {})",
+                stack.pop().unwrap(),
+                stack.pop().unwrap()
+            ),
+            Term::And | Term::Or => format!(
+                "select(0, 1, ({} != 0) {term:?} ({} != 0))",
+                stack.pop().unwrap(),
+                stack.pop().unwrap()
+            ),
+            _ => format!(
+                "({} {term:?} {})",
+                stack.pop().unwrap(),
+                stack.pop().unwrap()
+            ),
+        };
+        stack.push(new_symbol);
+    }
+    stack.pop().unwrap()
+}
+
+/// Render the index and validity expressions of a shape
+fn get_idx_valid_exps(shape: ShapeTracker, symbols: &[char], offset: usize) -> (String, String) {
+    (
+        expr_to_wgsl(&shape.index_expression(), symbols, offset),
+        expr_to_wgsl(&shape.valid_expression(), symbols, offset),
+    )
+}
+
+fn get_buffer_from_tensor<'a>(tensor: &'a InputTensor) -> &'a WgpuBuffer {
+    tensor
+        .borrowed()
+        .downcast_ref::<WgpuBuffer>()
+        .expect("Tensor does not contain a wgpu buffer")
+}
+
+fn is<T: Any>(type_id: TypeId) -> bool {
+    type_id == TypeId::of::<T>()
+}
@@ -0,0 +1,422 @@
+use std::fmt::Debug;
+
+use super::*;
+use petgraph::visit::EdgeRef;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use luminal::{
+    op::{InputTensor, Operator},
+    prelude::*,
+};
+
+/// Copy a tensor to the GPU
+#[derive(Debug, Clone, Default)]
+pub struct WgpuCopyToDevice;
+
+impl Operator for WgpuCopyToDevice {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        if inp[0].0.borrowed().is::<WgpuBuffer>() {
+            // Already on device
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        let data = inp[0]
+            .0
+            .borrowed()
+            .f32_data()
+            .expect("Only f32 tensors can be copied to a wgpu device");
+        vec![Tensor::new(device().buffer_with_data(&data))]
+    }
+}
+
+/// Copy a tensor from the GPU
+#[derive(Debug, Clone, Default)]
+pub struct WgpuCopyFromDevice;
+
+impl Operator for WgpuCopyFromDevice {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        if inp[0].0.borrowed().is::<Vec<f32>>() {
+            // Already off device
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        let buffer = get_buffer_from_tensor(&inp[0].0);
+        // Buffers are rounded up in size, so they can hold more than the tensor
+        let n_elements = inp[0]
+            .1
+            .n_physical_elements()
+            .to_usize()
+            .unwrap()
+            .min(buffer.size() as usize / 4);
+        vec![Tensor::new(device().read(buffer, n_elements))]
+    }
+}
+
+#[derive(Clone)]
+pub struct WgpuConstant(pub ConstantValue, pub *const FxHashMap<char, usize>);
+
+impl PartialEq for WgpuConstant {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+impl Debug for WgpuConstant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WgpuConstant({:?})", self.0)
+    }
+}
+
+impl Operator for WgpuConstant {
+    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let val = match &self.0 {
+            ConstantValue::Expression(e) => {
+                e.exec(unsafe { self.1.as_ref().unwrap() }).unwrap() as f32
+            }
+            ConstantValue::Float(f) => *f,
+        };
+        vec![Tensor::new(device().buffer_with_data(&[val]))]
+    }
+}
+
+/// Render the WGSL that loads input `i` at the current `idx` into `name`, reading 0 where the shape isn't valid
+fn render_load(
+    name: &str,
+    i: usize,
+    shape: ShapeTracker,
+    symbols: &[char],
+    offset: usize,
+) -> String {
+    let (idx_exp, valid_exp) = get_idx_valid_exps(shape, symbols, offset);
+    format!(
+        "    var {name} = 0.0;
+    if ({valid_exp} != 0) {{
+        {name} = inp{i}[{idx_exp}];
+    }}"
+    )
+}
+
+macro_rules! wgpu_unary_op {
+    ($(#[$meta:meta])* $name:ident, $op:literal) => {
+        $(#[$meta])*
+        #[derive(Clone)]
+        pub struct $name {
+            kernel: WgpuKernel,
+            dyn_symbols: Vec<char>,
+            dyn_map: *const FxHashMap<char, usize>,
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, stringify!($name))
+            }
+        }
+
+        impl $name {
+            pub fn new(shape: ShapeTracker, dyn_map: *const FxHashMap<char, usize>) -> Self {
+                let dyn_symbols = dyn_symbols(&[shape]);
+                let body = format!(
+                    "    let idx = i_;\n{}\n    out[i_] = {};",
+                    render_load("a", 0, shape, &dyn_symbols, 1),
+                    $op
+                );
+                Self {
+                    kernel: WgpuKernel::new(1, &render_kernel(1, &body)),
+                    dyn_symbols,
+                    dyn_map,
+                }
+            }
+        }
+
+        impl Operator for $name {
+            fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+                let n_elements = tensors[0].1.n_elements().to_usize().unwrap();
+                vec![Tensor::new(self.kernel.dispatch(
+                    &[get_buffer_from_tensor(&tensors[0].0)],
+                    n_elements,
+                    &dyn_values(&self.dyn_symbols, self.dyn_map),
+                ))]
+            }
+        }
+    };
+}
+
+wgpu_unary_op!(WgpuContiguous, "a");
+wgpu_unary_op!(WgpuLog2, "log2(a)");
+wgpu_unary_op!(WgpuExp2, "exp2(a)");
+wgpu_unary_op!(WgpuSin, "sin(a)");
+wgpu_unary_op!(WgpuSqrt, "sqrt(a)");
+wgpu_unary_op!(WgpuRecip, "1.0 / a");
+
+macro_rules! wgpu_binary_op {
+    ($(#[$meta:meta])* $name:ident, $op:literal) => {
+        $(#[$meta])*
+        #[derive(Clone)]
+        pub struct $name {
+            kernel: WgpuKernel,
+            dyn_symbols: Vec<char>,
+            dyn_map: *const FxHashMap<char, usize>,
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, stringify!($name))
+            }
+        }
+
+        impl $name {
+            pub fn new(
+                a_shape: ShapeTracker,
+                b_shape: ShapeTracker,
+                dyn_map: *const FxHashMap<char, usize>,
+            ) -> Self {
+                let dyn_symbols = dyn_symbols(&[a_shape, b_shape]);
+                let body = format!(
+                    "    let idx = i_;\n{}\n{}\n    out[i_] = {};",
+                    render_load("a", 0, a_shape, &dyn_symbols, 1),
+                    render_load("b", 1, b_shape, &dyn_symbols, 1),
+                    $op
+                );
+                Self {
+                    kernel: WgpuKernel::new(2, &render_kernel(2, &body)),
+                    dyn_symbols,
+                    dyn_map,
+                }
+            }
+        }
+
+        impl Operator for $name {
+            fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+                let n_elements = tensors[0].1.n_elements().to_usize().unwrap();
+                vec![Tensor::new(self.kernel.dispatch(
+                    &[
+                        get_buffer_from_tensor(&tensors[0].0),
+                        get_buffer_from_tensor(&tensors[1].0),
+                    ],
+                    n_elements,
+                    &dyn_values(&self.dyn_symbols, self.dyn_map),
+                ))]
+            }
+        }
+    };
+}
+
+wgpu_binary_op!(WgpuAdd, "a + b");
+wgpu_binary_op!(WgpuMul, "a * b");
+// WGSL's % truncates like fmod
+wgpu_binary_op!(WgpuMod, "a % b");
+wgpu_binary_op!(WgpuLessThan, "select(0.0, 1.0, a < b)");
+
+macro_rules! wgpu_reduce_op {
+    ($(#[$meta:meta])* $name:ident, $init:literal, $op:literal) => {
+        $(#[$meta])*
+        #[derive(Clone)]
+        pub struct $name {
+            kernel: WgpuKernel,
+            pub dim: usize,
+            dyn_symbols: Vec<char>,
+            dyn_map: *const FxHashMap<char, usize>,
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}({})", stringify!($name), self.dim)
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.dim == other.dim
+            }
+        }
+
+        impl $name {
+            pub fn new(
+                shape: ShapeTracker,
+                dim: usize,
+                dyn_map: *const FxHashMap<char, usize>,
+            ) -> Self {
+                let dyn_symbols = dyn_symbols(&[shape]);
+                // params[1] is the size of the dimensions after the reduced one, params[2] is the reduced dimension
+                let body = format!(
+                    "    let a_ = i_ / params[1];
+    let b_ = i_ % params[1];
+    var acc = {};
+    for (var c_ = 0; c_ < params[2]; c_++) {{
+        let idx = a_ * params[2] * params[1] + c_ * params[1] + b_;
+    {}
+        acc = {};
+    }}
+    out[i_] = acc;",
+                    $init,
+                    render_load("a", 0, shape, &dyn_symbols, 3),
+                    $op
+                );
+                Self {
+                    kernel: WgpuKernel::new(1, &render_kernel(1, &body)),
+                    dim,
+                    dyn_symbols,
+                    dyn_map,
+                }
+            }
+        }
+
+        impl Operator for $name {
+            fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+                let shape = tensors[0].1.shape_usize();
+                let back_size = shape.iter().skip(self.dim + 1).product::<usize>();
+                let mut out_shape = tensors[0].1;
+                out_shape.remove_dim(self.dim);
+                let params = [back_size as i32, shape[self.dim] as i32]
+                    .into_iter()
+                    .chain(dyn_values(&self.dyn_symbols, self.dyn_map))
+                    .collect::<Vec<_>>();
+                vec![Tensor::new(self.kernel.dispatch(
+                    &[get_buffer_from_tensor(&tensors[0].0)],
+                    out_shape.n_elements().to_usize().unwrap(),
+                    &params,
+                ))]
+            }
+        }
+    };
+}
+
+wgpu_reduce_op!(WgpuSumReduce, "0.0", "acc + a");
+wgpu_reduce_op!(WgpuMaxReduce, "-3.40282347e+38", "max(acc, a)");
+
+/// Convert all primitive ops to wgpu kernels.
+///
+/// Any other op (like functions loading data) runs on the host, with copies inserted wherever data moves between the
+/// host and the device.
+#[derive(Debug, Default)]
+pub struct PrimitiveCompiler;
+
+impl Compiler for PrimitiveCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let on_device = |op: &dyn Operator| {
+            let t = op.as_any().type_id();
+            is::<Constant>(t)
+                || is::<Contiguous>(t)
+                || is::<Log2>(t)
+                || is::<Exp2>(t)
+                || is::<Sin>(t)
+                || is::<Sqrt>(t)
+                || is::<Recip>(t)
+                || is::<Add>(t)
+                || is::<Mul>(t)
+                || is::<Mod>(t)
+                || is::<LessThan>(t)
+                || is::<SumReduce>(t)
+                || is::<MaxReduce>(t)
+        };
+        let host_nodes = graph
+            .node_indices()
+            .filter(|n| !on_device(graph.node_weight(*n).unwrap().as_ref()))
+            .collect::<FxHashSet<_>>();
+
+        for &host_node in &host_nodes {
+            // Copy outputs going to device ops onto the device, once for each output
+            let outgoing = graph
+                .edges_directed(host_node, petgraph::Direction::Outgoing)
+                .filter(|e| !host_nodes.contains(&e.target()))
+                .filter_map(|e| e.weight().as_data().map(|d| (e.id(), e.target(), d)))
+                .collect::<Vec<_>>();
+            let mut copies = FxHashMap::default();
+            for (edge, dest, (input_order, output_order, shape)) in outgoing {
+                let copy_node = *copies.entry(output_order).or_insert_with(|| {
+                    graph
+                        .add_op(WgpuCopyToDevice)
+                        .input(host_node, output_order, ShapeTracker::new(&[]))
+                        .finish()
+                });
+                graph.add_edge(
+                    copy_node,
+                    dest,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+                graph.remove_edge(edge);
+            }
+
+            // Copy inputs coming from device ops off the device
+            for (source, edge, (input_order, output_order, shape)) in graph
+                .edges_directed(host_node, petgraph::Direction::Incoming)
+                .filter(|e| !host_nodes.contains(&e.source()))
+                .filter_map(|e| e.weight().as_data().map(|d| (e.source(), e.id(), d)))
+                .collect::<Vec<_>>()
+            {
+                let copy_from_node = graph
+                    .add_op(WgpuCopyFromDevice)
+                    .input(source, output_order, shape)
+                    .finish();
+                graph.add_edge(
+                    copy_from_node,
+                    host_node,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+                graph.remove_edge(edge);
+            }
+        }
+
+        // Copy retrieved device tensors back to the host
+        for (output_node, (_, output_shape)) in graph
+            .to_retrieve
+            .iter()
+            .map(|(a, b)| (*a, *b))
+            .filter(|(n, _)| !host_nodes.contains(n))
+            .collect::<Vec<_>>()
+        {
+            let copy_node = graph
+                .add_op(WgpuCopyFromDevice)
+                .input(output_node, 0, output_shape)
+                .finish();
+            remap(output_node, copy_node, &mut ids, graph);
+        }
+
+        // Swap primitive ops
+        for id in graph.node_indices().collect::<Vec<_>>() {
+            let src_shapes = graph
+                .edges_directed(id, petgraph::Direction::Incoming)
+                .filter_map(|e| e.weight().as_data())
+                .sorted_by_key(|e| e.0)
+                .map(|e| e.2)
+                .collect::<Vec<_>>();
+            let dyn_map = &graph.dyn_map as *const _;
+            let op = graph.graph.node_weight(id).unwrap().as_any().type_id();
+            let op_ref = graph.graph.node_weight_mut(id).unwrap();
+            if let Some(c) = op_ref.as_any().downcast_ref::<Constant>() {
+                *op_ref = Box::new(WgpuConstant(c.0.clone(), c.1));
+            } else if is::<Contiguous>(op) {
+                *op_ref = Box::new(WgpuContiguous::new(src_shapes[0], dyn_map));
+            } else if is::<Log2>(op) {
+                *op_ref = Box::new(WgpuLog2::new(src_shapes[0], dyn_map));
+            } else if is::<Exp2>(op) {
+                *op_ref = Box::new(WgpuExp2::new(src_shapes[0], dyn_map));
+            } else if is::<Sin>(op) {
+                *op_ref = Box::new(WgpuSin::new(src_shapes[0], dyn_map));
+            } else if is::<Sqrt>(op) {
+                *op_ref = Box::new(WgpuSqrt::new(src_shapes[0], dyn_map));
+            } else if is::<Recip>(op) {
+                *op_ref = Box::new(WgpuRecip::new(src_shapes[0], dyn_map));
+            } else if is::<Add>(op) {
+                *op_ref = Box::new(WgpuAdd::new(src_shapes[0], src_shapes[1], dyn_map));
+            } else if is::<Mul>(op) {
+                *op_ref = Box::new(WgpuMul::new(src_shapes[0], src_shapes[1], dyn_map));
+            } else if is::<Mod>(op) {
+                *op_ref = Box::new(WgpuMod::new(src_shapes[0], src_shapes[1], dyn_map));
+            } else if is::<LessThan>(op) {
+                *op_ref = Box::new(WgpuLessThan::new(src_shapes[0], src_shapes[1], dyn_map));
+            } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(WgpuSumReduce::new(src_shapes[0], *dim, dyn_map));
+            } else if let Some(MaxReduce(dim)) = op_ref.as_any().downcast_ref() {
+                *op_ref = Box::new(WgpuMaxReduce::new(src_shapes[0], *dim, dyn_map));
+            }
+        }
+    }
+}
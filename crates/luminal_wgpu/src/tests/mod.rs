@@ -0,0 +1,156 @@
+use dfdx::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+
+use luminal::{module::Module, prelude::*};
+use luminal_nn::Linear;
+
+use crate::{device, WgpuCompiler};
+luminal::test_imports!();
+
+type DTensor = dfdx::prelude::Tensor<Rank1<783>, f32, Cpu>;
+
+/// Run a unary function on the wgpu backend and on dfdx, and check they match
+fn check_unary(f: fn(GraphTensor<R1<783>>) -> GraphTensor<R1<783>>, d_f: fn(DTensor) -> DTensor) {
+    let mut rng = StdRng::seed_from_u64(1);
+    let data = random_vec_rng(783, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<783>>().set(data.clone());
+    let mut b = f(a).retrieve();
+    cx.compile(WgpuCompiler::default(), &mut b);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_b = d_f(d_dev.tensor_from_vec(data, (DConst::<783>,)));
+    assert_close(&b.data(), &d_b.as_vec());
+}
+
+/// Run a binary function on the wgpu backend and on dfdx, and check they match
+fn check_binary(
+    f: fn(GraphTensor<R1<783>>, GraphTensor<R1<783>>) -> GraphTensor<R1<783>>,
+    d_f: fn(DTensor, DTensor) -> DTensor,
+) {
+    let mut rng = StdRng::seed_from_u64(2);
+    let a_data = random_vec_rng(783, &mut rng);
+    let b_data = random_vec_rng(783, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<783>>().set(a_data.clone());
+    let b = cx.tensor::<R1<783>>().set(b_data.clone());
+    let mut c = f(a, b).retrieve();
+    cx.compile(WgpuCompiler::default(), &mut c);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_c = d_f(
+        d_dev.tensor_from_vec(a_data, (DConst::<783>,)),
+        d_dev.tensor_from_vec(b_data, (DConst::<783>,)),
+    );
+    assert_close(&c.data(), &d_c.as_vec());
+}
+
+#[test]
+fn test_unary() {
+    check_unary(|a| a.sin(), |a| a.sin());
+    check_unary(|a| a.abs().sqrt(), |a| a.abs().sqrt());
+    check_unary(|a| a.recip(), |a| a.recip());
+    check_unary(|a| a.abs().log2(), |a| a.abs().ln() / 2_f32.ln());
+    check_unary(|a| a.exp2(), |a| (a * 2_f32.ln()).exp());
+    check_unary(|a| a.softmax::<LAxis<0>>(), |a| a.softmax::<DAxis<0>>());
+    check_unary(
+        |a| a.mean_norm::<LAxis<0>>().std_norm::<LAxis<0>, _>(1e-5),
+        |a| a.normalize::<DAxis<0>>(1e-5),
+    );
+}
+
+#[test]
+fn test_binary() {
+    check_binary(|a, b| a + b, |a, b| a + b);
+    check_binary(|a, b| a - b, |a, b| a - b);
+    check_binary(|a, b| a * b, |a, b| a * b);
+    check_binary(|a, b| a / b, |a, b| a / b);
+    check_binary(
+        |a, b| a % b,
+        |a, b| a.clone() - ((a / b.clone()).to_dtype::<i32>().to_dtype::<f32>() * b),
+    );
+    check_binary(|a, b| a.min(b), |a, b| a.minimum(b));
+    check_binary(|a, b| a.max(b), |a, b| a.maximum(b));
+}
+
+#[test]
+fn test_reduce() {
+    let data = random_vec(2 * 3 * 4);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+    let mut sum = a.sum_reduce::<_, LAxis<1>>().retrieve();
+    let mut max = a.max_reduce::<_, LAxis<2>>().retrieve();
+    cx.compile(WgpuCompiler::default(), (&mut sum, &mut max));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<2>, DConst::<3>, DConst::<4>));
+    assert_close(&sum.data(), &d_a.clone().sum::<_, DAxis<1>>().as_vec());
+    assert_close(&max.data(), &d_a.max::<_, DAxis<2>>().as_vec());
+}
+
+#[test]
+fn test_matmul() {
+    let a_data = random_vec(130 * 70);
+    let b_data = random_vec(70 * 90);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<130, 70>>().set(a_data.clone());
+    let b = cx.tensor::<R2<70, 90>>().set(b_data.clone());
+    let mut c = a.matmul(b).retrieve();
+    cx.compile(WgpuCompiler::default(), &mut c);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(a_data, (DConst::<130>, DConst::<70>));
+    let d_b = d_dev.tensor_from_vec(b_data, (DConst::<70>, DConst::<90>));
+    assert_close(&c.data(), &d_a.matmul(d_b).as_vec());
+}
+
+#[test]
+fn test_padding_and_dyn_dims() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<(Dyn<'a'>, LConst<2>)>()
+        .set_dyn(vec![1., 2., 3., 4., 5., 6.], &[3, 2]);
+    let mut c = (a
+        .pad::<(Dyn<'a'>, LConst<3>), _, _>(&[(0, 0), (0, 1)])
+        .slice((.., Expression::from(1)..))
+        .realize::<(Dyn<'a'>, LConst<2>)>()
+        * 2.)
+        .retrieve();
+    cx.compile(WgpuCompiler::default(), &mut c);
+    cx.execute();
+    assert_exact(&c.data(), &[4., 0., 8., 0., 12., 0.]);
+
+    // Dynamic dimensions are read when kernels run, so the same graph works for other sizes
+    c.drop();
+    a.set_dyn(vec![1., 2.], &[1, 2]);
+    cx.execute();
+    assert_exact(&c.data(), &[4., 0.]);
+}
+
+#[test]
+fn test_linear_repeated() {
+    let mut cx = Graph::new();
+    let model = Linear::<32, 16>::initialize(&mut cx);
+    let weight = random_vec(32 * 16);
+    model.weight.set(weight.clone()).keep();
+    let input = cx.tensor::<R2<4, 32>>();
+    let mut out = model.forward(input).retrieve();
+    cx.compile(WgpuCompiler::default(), &mut out);
+
+    let d_dev = Cpu::default();
+    let d_weight = d_dev.tensor_from_vec(weight, (DConst::<32>, DConst::<16>));
+    for _ in 0..3 {
+        // Buffers released by one execution are reused by the next
+        let data = random_vec(4 * 32);
+        input.set(data.clone());
+        cx.execute();
+        let d_input = d_dev.tensor_from_vec(data, (DConst::<4>, DConst::<32>));
+        assert_close(&out.data(), &d_input.matmul(d_weight.clone()).as_vec());
+        out.drop();
+    }
+    assert!(!device().pool.lock().unwrap().is_empty());
+}
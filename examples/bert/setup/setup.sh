@@ -0,0 +1,8 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+echo "Downloading Model and Tokenizer..."
+curl --location https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/resolve/main/tokenizer.json?download=true --output $SCRIPT_DIR/tokenizer.json
+curl --location https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/resolve/main/config.json?download=true --output $SCRIPT_DIR/config.json
+curl --location https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/resolve/main/model.safetensors?download=true --output $SCRIPT_DIR/model.safetensors
+echo "Done!"
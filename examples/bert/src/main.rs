@@ -0,0 +1,169 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use clap::{Parser, ValueEnum};
+
+mod model;
+
+use luminal::{prelude::*, serialization::safetensors, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
+
+/// How token states are pooled into a sentence embedding
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Pooling {
+    /// Average the states of every token, like sentence-transformers models
+    Mean,
+    /// Take the state of the CLS token at the start of the sentence
+    Cls,
+}
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Sentences to embed, which can be given multiple times
+    #[clap(short = 's', long = "sentence", default_values = [
+        "The cat sits on the mat.",
+        "A cat is resting on a rug.",
+        "Stock markets fell sharply today.",
+    ])]
+    sentences: Vec<String>,
+
+    /// Directory holding the HuggingFace config.json, model.safetensors and tokenizer.json
+    #[clap(short = 'm', long = "model", default_value = "setup")]
+    model: String,
+
+    /// How token states are pooled into a sentence embedding
+    #[clap(long = "pooling", value_enum, default_value = "mean")]
+    pooling: Pooling,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let dir = Path::new(&cli_args.model);
+    let config = model::BertConfig::from_hf_config(dir.join("config.json")).unwrap();
+    let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json")).unwrap();
+
+    print!("Defining graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+
+    // Set up graph
+    let mut cx = Graph::new();
+    config.set_dims(&mut cx);
+    let mut tokens = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Tokens");
+    let mut token_types = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Token Types");
+    let mut positions = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Positions");
+    let mut padding = cx.named_tensor::<(Dyn<'b'>, Const<1>, Dyn<'s'>, Dyn<'s'>)>("Padding");
+    let mut pool_weights = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Pooling Weights");
+    let model = model::Bert::new(&config, &mut cx);
+    let mut model_weights = params(&model);
+    cx.keep_tensors(&model_weights);
+    let states = model.forward((
+        tokens,
+        token_types,
+        positions,
+        AttentionMask::none().with_padding(padding),
+    ));
+    let mut embeddings = model::pool(states, pool_weights).retrieve();
+
+    // Set up model loading. Some checkpoints nest the weights under `bert.`
+    let weights = dir.join("model.safetensors");
+    if safetensors::load(&weights, &model, &mut cx).is_err() {
+        safetensors::load_with_names(&weights, &model, &mut cx, |p| {
+            format!("bert.{}", p.replace('/', "."))
+        })
+        .unwrap();
+    }
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (
+            &mut tokens,
+            &mut token_types,
+            &mut positions,
+            &mut padding,
+            &mut pool_weights,
+            &mut embeddings,
+            &mut model_weights,
+        ),
+    );
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Tokenize with the tokenizer's own special tokens ([CLS] ... [SEP]), and batch the sentences together
+    let encoded = cli_args
+        .sentences
+        .iter()
+        .map(|s| {
+            let mut ids = tokenizer
+                .inner()
+                .encode(s.as_str(), true)
+                .unwrap()
+                .get_ids()
+                .to_vec();
+            ids.truncate(config.n_positions);
+            ids
+        })
+        .collect::<Vec<_>>();
+    let pad = tokenizer.inner().token_to_id("[PAD]").unwrap_or(0);
+    let batch = PaddedBatch::left_padded(&encoded, pad);
+    let shape = [batch.batch_size(), batch.seq_len];
+    let weights: Vec<f32> = match cli_args.pooling {
+        Pooling::Mean => batch
+            .key_mask()
+            .chunks(batch.seq_len)
+            .zip(&batch.lengths)
+            .flat_map(|(mask, len)| mask.iter().map(move |m| m / *len as f32))
+            .collect(),
+        Pooling::Cls => (0..batch.batch_size())
+            .flat_map(|i| {
+                let cls = batch.padding(i);
+                (0..batch.seq_len).map(move |p| (p == cls) as u8 as f32)
+            })
+            .collect(),
+    };
+    tokens.set_dyn(batch.token_data(), &shape);
+    token_types.set_dyn(vec![0.; shape[0] * shape[1]], &shape);
+    positions.set_dyn(batch.positions(), &shape);
+    padding.set_dyn(
+        batch.attention_mask(batch.seq_len),
+        &[shape[0], 1, shape[1], shape[1]],
+    );
+    pool_weights.set_dyn(weights, &shape);
+
+    let now = Instant::now();
+    cx.execute();
+    println!(
+        "Embedded {} sentences in {}ms",
+        batch.batch_size(),
+        now.elapsed().as_millis()
+    );
+
+    // Embeddings are normalized, so their dot products are cosine similarities
+    let embeddings = embeddings.data();
+    let embeddings = embeddings.chunks(config.hidden_dim).collect::<Vec<_>>();
+    for (sentence, embedding) in cli_args.sentences.iter().zip(&embeddings) {
+        println!(
+            "{sentence:?}: {:.4?} ...",
+            &embedding[..4.min(embedding.len())]
+        );
+    }
+    println!("\nCosine similarities:");
+    for (i, a) in embeddings.iter().enumerate() {
+        let row = embeddings
+            .iter()
+            .map(|b| format!("{:.3}", a.iter().zip(*b).map(|(x, y)| x * y).sum::<f32>()))
+            .collect::<Vec<_>>();
+        println!("  [{i}] {}", row.join("  "));
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynLayerNorm, DynPermutedLinear};
+
+// Model dimensions are dynamic so the same graph code works for every BERT size. Their sizes come from a
+// `BertConfig`, and are set on the graph with `BertConfig::set_dims` before it's compiled.
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type HeadDim = Dyn<'e'>;
+pub type Positions = Dyn<'n'>;
+pub type TokenTypes = Dyn<'y'>;
+
+/// The hyperparameters of a BERT model
+#[derive(Debug, Clone, PartialEq)]
+pub struct BertConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub mlp_dim: usize,
+    /// The number of learned positions, which is the longest input the model supports
+    pub n_positions: usize,
+    /// The number of token types (segments), usually 2 for sentence pairs
+    pub n_token_types: usize,
+    pub layer_norm_epsilon: f32,
+}
+
+impl BertConfig {
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing {key}")))
+        };
+        let config = Self {
+            vocab_size: get_usize("vocab_size")?,
+            hidden_dim: get_usize("hidden_size")?,
+            n_layers: get_usize("num_hidden_layers")?,
+            n_heads: get_usize("num_attention_heads")?,
+            mlp_dim: get_usize("intermediate_size")?,
+            n_positions: get_usize("max_position_embeddings")?,
+            n_token_types: get_usize("type_vocab_size").unwrap_or(2),
+            layer_norm_epsilon: json
+                .get("layer_norm_eps")
+                .and_then(serde_json::Value::as_f64)
+                .unwrap_or(1e-12) as f32,
+        };
+        if config.n_heads == 0 || !config.hidden_dim.is_multiple_of(config.n_heads) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Hidden dim must be divisible by the number of heads",
+            ));
+        }
+        match json.get("hidden_act").and_then(serde_json::Value::as_str) {
+            None | Some("gelu") => Ok(config),
+            Some(act) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported activation: {act}"),
+            )),
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 7] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('e', self.head_dim()),
+            ('n', self.n_positions),
+            ('y', self.n_token_types),
+        ]
+    }
+
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
+
+pub struct SelfAttention {
+    pub query: DynPermutedLinear<Hidden, Hidden>,
+    pub key: DynPermutedLinear<Hidden, Hidden>,
+    pub value: DynPermutedLinear<Hidden, Hidden>,
+    pub output: DynPermutedLinear<Hidden, Hidden>,
+}
+
+impl<Batch: Dimension, Seq: Dimension>
+    Module<(
+        GraphTensor<(Batch, Seq, Hidden)>,
+        AttentionMask<Batch, Seq, Seq>,
+    )> for SelfAttention
+{
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(
+        &self,
+        (x, mask): (
+            GraphTensor<(Batch, Seq, Hidden)>,
+            AttentionMask<Batch, Seq, Seq>,
+        ),
+    ) -> Self::Output {
+        let heads = |proj: &DynPermutedLinear<Hidden, Hidden>| {
+            proj.forward(x)
+                .reshape::<(Batch, Seq, Heads, HeadDim)>()
+                .permute::<_, Axes4<0, 2, 1, 3>>()
+        };
+        // Every token attends to every other token in its sequence, so the mask only hides padding
+        let output = grouped_query_attention(
+            heads(&self.query),
+            heads(&self.key),
+            heads(&self.value),
+            mask,
+        )
+        // Merge heads
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        .reshape::<(Batch, Seq, Hidden)>();
+        self.output.forward(output)
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self/query", &self.query);
+        s.module("self/key", &self.key);
+        s.module("self/value", &self.value);
+        s.module("output/dense", &self.output);
+    }
+}
+
+pub struct EncoderLayer {
+    pub attention: SelfAttention,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub intermediate: DynPermutedLinear<Hidden, MlpDim>,
+    pub output: DynPermutedLinear<MlpDim, Hidden>,
+    pub output_norm: DynLayerNorm<Hidden>,
+}
+
+impl EncoderLayer {
+    pub fn new(config: &BertConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention {
+                query: DynPermutedLinear::new(true, cx),
+                key: DynPermutedLinear::new(true, cx),
+                value: DynPermutedLinear::new(true, cx),
+                output: DynPermutedLinear::new(true, cx),
+            },
+            attention_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            intermediate: DynPermutedLinear::new(true, cx),
+            output: DynPermutedLinear::new(true, cx),
+            output_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, Seq: Dimension>
+    Module<(
+        GraphTensor<(Batch, Seq, Hidden)>,
+        AttentionMask<Batch, Seq, Seq>,
+    )> for EncoderLayer
+{
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(
+        &self,
+        (x, mask): (
+            GraphTensor<(Batch, Seq, Hidden)>,
+            AttentionMask<Batch, Seq, Seq>,
+        ),
+    ) -> Self::Output {
+        // Post-norm attention and MLP, each with a residual connection
+        let x = self
+            .attention_norm
+            .forward(x + self.attention.forward((x, mask)));
+        let y = self.output.forward(self.intermediate.forward(x).gelu());
+        self.output_norm.forward(x + y)
+    }
+}
+
+impl SerializeModule for EncoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attention", &self.attention);
+        s.module("attention/output/LayerNorm", &self.attention_norm);
+        s.module("intermediate/dense", &self.intermediate);
+        s.module("output/dense", &self.output);
+        s.module("output/LayerNorm", &self.output_norm);
+    }
+}
+
+pub struct Bert {
+    pub word_embeddings: GraphTensor<(Vocab, Hidden)>,
+    pub position_embeddings: GraphTensor<(Positions, Hidden)>,
+    pub token_type_embeddings: GraphTensor<(TokenTypes, Hidden)>,
+    pub embedding_norm: DynLayerNorm<Hidden>,
+    pub layers: Vec<EncoderLayer>,
+}
+
+impl Bert {
+    /// Build the model for a config. The config's dims must also be set on the graph with `BertConfig::set_dims`.
+    pub fn new(config: &BertConfig, cx: &mut Graph) -> Self {
+        Self {
+            word_embeddings: cx.named_tensor("Word Embeddings"),
+            position_embeddings: cx.named_tensor("Position Embeddings"),
+            token_type_embeddings: cx.named_tensor("Token Type Embeddings"),
+            embedding_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            layers: (0..config.n_layers)
+                .map(|_| EncoderLayer::new(config, cx))
+                .collect(),
+        }
+    }
+}
+
+/// Token ids, token type ids, and the position of each token, all shaped (batch, seq), and the attention mask
+pub type BertInput<Batch, Seq> = (
+    GraphTensor<(Batch, Seq)>,
+    GraphTensor<(Batch, Seq)>,
+    GraphTensor<(Batch, Seq)>,
+    AttentionMask<Batch, Seq, Seq>,
+);
+
+impl<Batch: Dimension, Seq: Dimension> Module<BertInput<Batch, Seq>> for Bert {
+    /// The final hidden state of every token
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(
+        &self,
+        (tokens, token_types, positions, mask): BertInput<Batch, Seq>,
+    ) -> Self::Output {
+        let embeddings: GraphTensor<(Batch, Seq, Hidden)> =
+            self.word_embeddings.index_select::<_, Axis<0>, _>(tokens)
+                + self
+                    .position_embeddings
+                    .index_select::<_, Axis<0>, _>(positions)
+                + self
+                    .token_type_embeddings
+                    .index_select::<_, Axis<0>, _>(token_types);
+        let mut x = self.embedding_norm.forward(embeddings);
+        for layer in &self.layers {
+            x = layer.forward((x, mask));
+        }
+        x
+    }
+}
+
+impl SerializeModule for Bert {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("embeddings/word_embeddings/weight", self.word_embeddings);
+        s.tensor(
+            "embeddings/position_embeddings/weight",
+            self.position_embeddings,
+        );
+        s.tensor(
+            "embeddings/token_type_embeddings/weight",
+            self.token_type_embeddings,
+        );
+        s.module("embeddings/LayerNorm", &self.embedding_norm);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("encoder/layer/{i}"), layer);
+        }
+    }
+}
+
+/// Pool token states shaped (batch, seq, hidden) into one L2 normalized embedding per sequence, as a weighted sum
+/// over the sequence with `weights` shaped (batch, seq). Mean pooling weights each real token by 1 / length, and CLS
+/// pooling puts all the weight on the CLS token.
+pub fn pool<Batch: Dimension, Seq: Dimension>(
+    states: GraphTensor<(Batch, Seq, Hidden)>,
+    weights: GraphTensor<(Batch, Seq)>,
+) -> GraphTensor<(Batch, Hidden)> {
+    let pooled = (states * weights.expand::<_, Axis<2>>()).sum_reduce::<_, Axis<1>>();
+    let norm = pooled
+        .square()
+        .sum_reduce::<(Batch,), Axis<1>>()
+        .sqrt()
+        .max_f32(1e-12);
+    pooled / norm.expand::<_, Axis<1>>()
+}
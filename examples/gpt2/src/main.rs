@@ -0,0 +1,147 @@
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+    time::Instant,
+};
+
+use clap::Parser;
+use colored::Colorize;
+
+mod model;
+
+use crate::model::KVCache;
+use luminal::{prelude::*, serialization::safetensors, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Number of tokens to generate
+    #[clap(short = 't', long = "gen_tokens", default_value = "64")]
+    gen_tokens: i32,
+
+    /// Prompt for the model
+    #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/history.txt"))]
+    prompt: String,
+
+    /// Directory holding the HuggingFace config.json, model.safetensors and tokenizer.json
+    #[clap(short = 'm', long = "model", default_value = "setup")]
+    model: String,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let dir = std::path::Path::new(&cli_args.model);
+
+    let config = model::Gpt2Config::from_hf_config(dir.join("config.json")).unwrap();
+    let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+        .unwrap()
+        .with_eos_tokens([config.eos_token]);
+
+    print!("Defining graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+
+    // Set up graph
+    let mut cx = Graph::new();
+    config.set_dims(&mut cx);
+    let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.n_layers)
+        .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
+        .collect();
+    cache_src.set_dyn(vec![], &[1, config.n_heads, 0, config.head_dim()]);
+    let model = model::Gpt2::new(&config, &mut cx);
+    let mut model_weights = params(&model);
+    cx.keep_tensors(&model_weights);
+    let mask = AttentionMask::<_, _, Dyn<'t'>>::causal(&mut cx);
+    let (logits, mut cache_dest) = model.forward((input, &cache_src, mask));
+    let mut logits = logits
+        .slice((.., (Expression::from('s') - 1).., ..))
+        .retrieve();
+    cache_dest.keep();
+
+    // Set up model loading. Some checkpoints nest the weights under `transformer.`
+    let weights = dir.join("model.safetensors");
+    if safetensors::load(&weights, &model, &mut cx).is_err() {
+        safetensors::load_with_names(&weights, &model, &mut cx, |p| {
+            format!("transformer.{}", p.replace('/', "."))
+        })
+        .unwrap();
+    }
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (
+            &mut input,
+            &mut logits,
+            &mut cache_src,
+            &mut cache_dest,
+            &mut model_weights,
+        ),
+    );
+    cx.compile(MemoryPlanner, ());
+    let cache_src = downstream(&cache_src, &cx);
+    let cache_dest = cache_dest.to_ids();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Initial forward pass to load weights
+    print!("Loading model");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    input.set_dyn(vec![1.], &[1, 1]);
+    cx.set_dyn_dim('t', 1);
+    cx.execute();
+    logits.drop();
+    cx.drop_tensors(&cache_dest);
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Now that weights are loaded, delete the loading nodes so they don't run again
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
+
+    // Generate, streaming the decoded text as tokens come in
+    let input_ids = tokenizer.encode(&cli_args.prompt).unwrap();
+    // Positions past the learned ones have no embedding
+    let max_tokens =
+        (cli_args.gen_tokens as usize + 1).min(config.n_positions.saturating_sub(input_ids.len()));
+    let mut output_stream = tokenizer.decode_stream(true);
+    print!("{}", cli_args.prompt.white().bold());
+    io::stdout().flush().unwrap();
+    let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+    let mut greedy = Sample::new(0., 0, 1., 0);
+    let start = Instant::now();
+    let mut start_decode = None;
+    let output_ids = Generator::new(max_tokens)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied())
+        .generate(&mut model, &mut greedy, &input_ids, |token| {
+            start_decode.get_or_insert_with(Instant::now);
+            if let Some(text) = output_stream.push(token).unwrap() {
+                print!("{}", text.bright_green());
+                io::stdout().flush().unwrap();
+            }
+            ControlFlow::Continue(())
+        });
+    println!();
+
+    let start_decode = start_decode.unwrap();
+    let prompt_ms = (start_decode - start).as_millis();
+    println!(
+        "\nProcessed prompt in {prompt_ms}ms ({:.2} tok/s, {} prompt tokens)",
+        1000.0 * (input_ids.len() as f64) / (prompt_ms as f64),
+        input_ids.len()
+    );
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "Average token generated in {:.2}ms\t - ({:.2} tok/s)",
+        avg_token_time,
+        1000.0 / avg_token_time
+    );
+}
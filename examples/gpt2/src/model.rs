@@ -0,0 +1,310 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynLayerNorm, DynLinear};
+
+// Model dimensions are dynamic so the same graph code works for every GPT-2 size. Their sizes come from a
+// `Gpt2Config`, and are set on the graph with `Gpt2Config::set_dims` before it's compiled.
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type HeadDim = Dyn<'e'>;
+pub type Positions = Dyn<'n'>;
+pub type QkvDim = Dyn<'q'>;
+
+pub type KVCache<Batch, Seq> = (
+    GraphTensor<(Batch, Heads, Seq, HeadDim)>,
+    GraphTensor<(Batch, Heads, Seq, HeadDim)>,
+);
+
+/// The hyperparameters of a GPT-2 model
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gpt2Config {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub mlp_dim: usize,
+    /// The number of learned positions, which is the longest context the model supports
+    pub n_positions: usize,
+    pub layer_norm_epsilon: f32,
+    pub eos_token: u32,
+}
+
+impl Gpt2Config {
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing {key}")))
+        };
+        let hidden_dim = get_usize("n_embd")?;
+        let config = Self {
+            vocab_size: get_usize("vocab_size")?,
+            hidden_dim,
+            n_layers: get_usize("n_layer")?,
+            n_heads: get_usize("n_head")?,
+            // n_inner is null for the released checkpoints, meaning 4x the hidden dim
+            mlp_dim: get_usize("n_inner").unwrap_or(4 * hidden_dim),
+            n_positions: get_usize("n_positions")?,
+            layer_norm_epsilon: json
+                .get("layer_norm_epsilon")
+                .and_then(serde_json::Value::as_f64)
+                .unwrap_or(1e-5) as f32,
+            eos_token: get_usize("eos_token_id").unwrap_or(50256) as u32,
+        };
+        if config.n_heads == 0 || !config.hidden_dim.is_multiple_of(config.n_heads) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Hidden dim must be divisible by the number of heads",
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 7] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('e', self.head_dim()),
+            ('n', self.n_positions),
+            ('q', 3 * self.hidden_dim),
+        ]
+    }
+
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
+
+// GPT-2's linear weights are stored (in, out), so they're `DynLinear`s used without permuting
+pub struct Mlp {
+    pub fc: DynLinear<Hidden, MlpDim>,
+    pub proj: DynLinear<MlpDim, Hidden>,
+}
+
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Mlp {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        // GPT-2 uses the tanh approximation of GELU
+        self.proj.forward(self.fc.forward(input).gelu_tanh())
+    }
+}
+
+impl SerializeModule for Mlp {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("c_fc", &self.fc);
+        s.module("c_proj", &self.proj);
+    }
+}
+
+pub struct SelfAttention {
+    /// The query, key and value projections fused into one
+    pub qkv: DynLinear<Hidden, QkvDim>,
+    pub proj: DynLinear<Hidden, Hidden>,
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for SelfAttention
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (x, (k_cache, v_cache), mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Project to queries, keys and values at once, then split them into heads
+        let heads = self
+            .qkv
+            .forward(x)
+            .split::<(Batch, CurSeq, Hidden), Axis<2>, _>(&[Hidden::const_size(); 3])
+            .into_iter()
+            .map(|t| {
+                t.contiguous()
+                    .reshape::<(Batch, CurSeq, Heads, HeadDim)>()
+                    .permute::<_, Axes4<0, 2, 1, 3>>()
+            })
+            .collect::<Vec<_>>();
+        let (queries, keys, values) = (heads[0], heads[1], heads[2]);
+
+        // Add KV cache
+        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
+        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
+
+        let output = grouped_query_attention(queries, keys, values, mask)
+            // Merge heads
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(Batch, CurSeq, Hidden)>();
+        (
+            self.proj.forward(output),
+            (keys.contiguous(), values.contiguous()), // Cache needs to be contiguous for transferring to another graph
+        )
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("c_attn", &self.qkv);
+        s.module("c_proj", &self.proj);
+    }
+}
+
+pub struct TransformerBlock {
+    pub attention: SelfAttention,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub mlp: Mlp,
+    pub mlp_norm: DynLayerNorm<Hidden>,
+}
+
+impl TransformerBlock {
+    pub fn new(config: &Gpt2Config, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention {
+                qkv: DynLinear::new(true, cx),
+                proj: DynLinear::new(true, cx),
+            },
+            attention_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            mlp: Mlp {
+                fc: DynLinear::new(true, cx),
+                proj: DynLinear::new(true, cx),
+            },
+            mlp_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for TransformerBlock
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (mut x, cache, mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Pre-norm attention and MLP, each with a residual connection
+        let (y, cache) = self
+            .attention
+            .forward((self.attention_norm.forward(x), cache, mask));
+        x += y;
+        (x + self.mlp.forward(self.mlp_norm.forward(x)), cache)
+    }
+}
+
+impl SerializeModule for TransformerBlock {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attn", &self.attention);
+        s.module("ln_1", &self.attention_norm);
+        s.module("mlp", &self.mlp);
+        s.module("ln_2", &self.mlp_norm);
+    }
+}
+
+pub struct Gpt2 {
+    /// Token embeddings, which are also used as the LM head
+    pub token_embedding: GraphTensor<(Vocab, Hidden)>,
+    /// Learned position embeddings
+    pub position_embedding: GraphTensor<(Positions, Hidden)>,
+    pub layers: Vec<TransformerBlock>,
+    pub norm: DynLayerNorm<Hidden>,
+}
+
+impl Gpt2 {
+    /// Build the model for a config. The config's dims must also be set on the graph with `Gpt2Config::set_dims`.
+    pub fn new(config: &Gpt2Config, cx: &mut Graph) -> Self {
+        Self {
+            token_embedding: cx.named_tensor("Token Embedding"),
+            position_embedding: cx.named_tensor("Position Embedding"),
+            layers: (0..config.n_layers)
+                .map(|_| TransformerBlock::new(config, cx))
+                .collect(),
+            norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq)>,
+        &[KVCache<Batch, PrevSeq>],
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for Gpt2
+{
+    type Output = (
+        GraphTensor<(Batch, CurSeq, Vocab)>,
+        Vec<KVCache<Batch, TotSeq>>,
+    );
+    fn forward(
+        &self,
+        (input, cache, mask): (
+            GraphTensor<(Batch, CurSeq)>,
+            &[KVCache<Batch, PrevSeq>],
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Embed tokens and their positions, which continue on from the cached tokens
+        let positions = unsafe { input.with_graph(|cx| cx.arange::<CurSeq>()) }
+            + unsafe { input.with_graph(|cx| cx.constant_expr(PrevSeq::const_size())) }
+                .expand::<(CurSeq,), _>();
+        let mut x = self.token_embedding.index_select::<_, Axis<0>, _>(input)
+            + self
+                .position_embedding
+                .index_select::<(CurSeq, Hidden), Axis<0>, _>(positions)
+                .expand::<(Batch, CurSeq, Hidden), Axis<0>>();
+
+        // Run through layers and collect new caches
+        let mut new_caches = vec![];
+        let mut new_cache;
+        for (i, layer) in self.layers.iter().enumerate() {
+            (x, new_cache) = layer.forward((x, cache[i], mask));
+            new_caches.push(new_cache);
+        }
+        // Run through the last norm, and project back to the vocab with the tied embeddings
+        let output = self.norm.forward(x).matmul(self.token_embedding.permute());
+
+        (output, new_caches)
+    }
+}
+
+impl SerializeModule for Gpt2 {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("wte/weight", self.token_embedding);
+        s.tensor("wpe/weight", self.position_embedding);
+        s.module("ln_f", &self.norm);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("h/{i}"), layer);
+        }
+    }
+}
@@ -1,20 +1,20 @@
 use std::{
     io::{self, Write},
-    marker::PhantomData,
+    ops::ControlFlow,
     time::Instant,
 };
 
 use clap::Parser;
 use colored::Colorize;
-use itertools::Itertools;
-use tokenizers::Tokenizer;
 
-mod gguf;
+#[cfg(any(feature = "metal", feature = "cuda"))]
 mod loader;
 mod model;
+mod server;
 
 use crate::model::KVCache;
-use luminal::prelude::*;
+use luminal::{prelude::*, serialization::gguf, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
 
 // Command args parser
 #[derive(Debug, Parser)]
@@ -27,11 +27,45 @@ pub struct CLIArgs {
     /// Prompt for the model
     #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/merge_sort.txt"))]
     prompt: String,
+
+    /// Path to the GGUF weights
+    #[clap(short = 'm', long = "model", default_value = "setup/llama3-8b.gguf")]
+    model: String,
+
+    /// Path to a HuggingFace config.json to read the model's hyperparameters from, instead of the GGUF metadata
+    #[clap(short = 'c', long = "config")]
+    config: Option<String>,
+
+    /// Quantize the linear weights to this many bits (8 or 4), which lets 7B models fit in under 8GB of RAM
+    #[clap(long = "weight_bits")]
+    weight_bits: Option<u8>,
+
+    /// Store the KV cache quantized to this many bits (8 or 4). Only supported on the CPU.
+    #[clap(long = "kv_cache_bits")]
+    kv_cache_bits: Option<u8>,
+
+    /// Serve an OpenAI-compatible API on this address (like 127.0.0.1:8080) instead of running the prompt
+    #[clap(long = "serve")]
+    serve: Option<String>,
+
+    /// The most requests the server runs at once, each with its own KV cache. Others wait for a free slot.
+    #[clap(long = "slots", default_value = "4")]
+    slots: usize,
 }
 
 fn main() {
     let cli_args = CLIArgs::parse();
-    let tokenizer = Tokenizer::from_file("setup/tokenizer.json").unwrap();
+
+    // Read the model's hyperparameters
+    let gguf_content = gguf::Content::from_file(&cli_args.model).unwrap();
+    let config = match &cli_args.config {
+        Some(path) => model::LlamaConfig::from_hf_config(path),
+        None => model::LlamaConfig::from_gguf(&gguf_content.hyperparameters().unwrap()),
+    }
+    .unwrap();
+    let tokenizer = Tokenizer::from_file("setup/tokenizer.json")
+        .unwrap()
+        .with_gguf_config(&gguf_content);
 
     print!("Defining graph");
     io::stdout().flush().unwrap();
@@ -39,15 +73,17 @@ fn main() {
 
     // Set up graph
     let mut cx = Graph::new();
+    config.set_dims(&mut cx);
     let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
-    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::NUM_LAYERS)
+    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.n_layers)
         .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, model::N_KV_HEADS, 0, model::HEAD_DIM]);
-    let model = model::MistralLM::initialize(&mut cx);
+    cache_src.set_dyn(vec![], &[1, config.n_kv_heads, 0, config.head_dim()]);
+    let model = model::MistralLM::new(&config, &mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
-    let (logits, mut cache_dest) = model.forward((input, &cache_src, PhantomData::<Dyn<'t'>>));
+    let mask = AttentionMask::<_, _, Dyn<'t'>>::causal(&mut cx);
+    let (logits, mut cache_dest) = model.forward((input, &cache_src, mask));
     let mut logits = logits
         .slice((.., (Expression::from('s') - 1).., ..))
         .retrieve();
@@ -55,14 +91,27 @@ fn main() {
 
     // Set up model loading
     #[cfg(any(feature = "metal", feature = "cuda"))]
-    let q_weights = loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
+    let q_weights = loader::q8_load(&cli_args.model, &model, &mut cx);
     #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-    loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
+    gguf::load(&cli_args.model, &model, &mut cx).unwrap();
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     print!("Compiling graph");
     io::stdout().flush().unwrap();
     let now = Instant::now();
+    // The cache concats are replaced before the other compilers can fuse them away
+    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+    if let Some(bits) = cli_args.kv_cache_bits {
+        cx.compile(
+            luminal_cpu::QuantizedKVCacheCompiler::new(cache_dest.to_ids(), bits),
+            &mut cache_dest,
+        );
+    }
+    #[cfg(any(feature = "metal", feature = "cuda"))]
+    assert!(
+        cli_args.kv_cache_bits.is_none(),
+        "Quantized KV caches are only supported on the CPU"
+    );
     cx.compile(
         (
             GenericCompiler::default(),
@@ -81,6 +130,17 @@ fn main() {
             &mut model_weights,
         ),
     );
+    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+    match cli_args.weight_bits {
+        // Quantized weights are read in their stored order, so they don't need prepacking
+        Some(bits) => cx.compile(
+            luminal_cpu::WeightQuantCompiler::new(&model_weights, bits),
+            (),
+        ),
+        None => cx.compile(PrepackWeights::new(&model_weights), ()),
+    }
+    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+    cx.compile(MemoryPlanner, ());
     let cache_src = downstream(&cache_src, &cx);
     let cache_dest = cache_dest.to_ids();
     println!("\t\t - {}ms", now.elapsed().as_millis());
@@ -99,86 +159,56 @@ fn main() {
     // Now that weights are loaded, delete the loading nodes so they don't run again
     delete_inputs(&downstream(model_weights, &cx), &mut cx);
 
-    // Run prompt processing pass
-    let mut input_ids = tokenizer
-        .encode(&cli_args.prompt as &str, false)
-        .unwrap()
-        .get_ids()
-        .to_vec();
-    input_ids.insert(0, 1);
-    input.set_dyn(
-        input_ids.iter().map(|i| *i as f32).collect::<Vec<_>>(),
-        &[1, input_ids.len()],
-    );
-    cx.set_dyn_dim('t', input_ids.len());
-    print!("Processing Prompt");
-    io::stdout().flush().unwrap();
-    let now = Instant::now();
-    cx.execute();
-    let elapsed_ms = now.elapsed().as_millis();
-    println!(
-        "\t - {elapsed_ms}ms ({:.2} tok/s, {} prompt tokens)",
-        1000.0 * (input_ids.len() as f64) / (elapsed_ms as f64),
-        input_ids.len()
-    );
-    delete_inputs(&cache_src, &mut cx);
-    let mut output_ids = vec![sample_index(&logits.data())];
-    logits.drop();
+    if let Some(addr) = cli_args.serve.clone() {
+        let template = ChatTemplate::from_gguf(&gguf_content).unwrap_or(ChatTemplate::Llama3);
+        // Requests set their own sampling, so the default sampler is never used
+        let model = SlottedLM::new(
+            GraphLM::new(&mut cx, input, logits, cache_src, cache_dest),
+            Sample::new(0., 0, 1., 0),
+            cli_args.slots,
+        );
+        let session = InferenceSession::new(model);
+        let handle = session.handle();
+        let model_name = cli_args.model.clone();
+        // The session owns the compiled graph, so it runs here while requests come in on other threads
+        std::thread::spawn(move || server::serve(&addr, handle, tokenizer, &model_name, template));
+        session.run();
+        return;
+    }
 
-    // Decode token
+    // Generate, streaming the decoded text as tokens come in
+    let input_ids = tokenizer.encode(&cli_args.prompt).unwrap();
+    let mut output_stream = tokenizer.decode_stream(true);
     print!("{}", cli_args.prompt.white().bold());
-    print!(
-        "{}",
-        tokenizer.decode(&output_ids, false).unwrap().bright_green()
-    );
     io::stdout().flush().unwrap();
-
-    // Swap caches
-    transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
-
-    // Decode loop
-    let start_decode = std::time::Instant::now();
-    let mut prev_output_len = 0;
-    for _ in 0..cli_args.gen_tokens {
-        input.set_dyn(vec![*output_ids.last().unwrap() as f32], &[1, 1]);
-        cx.set_dyn_dim('p', input_ids.len() + output_ids.len() - 1);
-        cx.set_dyn_dim('t', input_ids.len() + output_ids.len());
-        cx.execute();
-
-        // Sample tokens
-        let output_id = sample_index(&logits.data());
-        logits.drop();
-        output_ids.push(output_id);
-
-        // Get the current decoded output
-        let current_output = tokenizer.decode(&output_ids, false).unwrap();
-
-        // Print the new substring added to the decoded output
-        let new_substring = &current_output[prev_output_len..];
-        print!("{}", new_substring.bright_green());
-        io::stdout().flush().unwrap();
-
-        // Update the previous output
-        prev_output_len = current_output.len();
-
-        // Swap caches
-        transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
-    }
-
+    let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+    let mut greedy = Sample::new(0., 0, 1., 0);
+    let start = Instant::now();
+    let mut start_decode = None;
+    let output_ids = Generator::new(cli_args.gen_tokens as usize + 1)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied())
+        .generate(&mut model, &mut greedy, &input_ids, |token| {
+            start_decode.get_or_insert_with(Instant::now);
+            if let Some(text) = output_stream.push(token).unwrap() {
+                print!("{}", text.bright_green());
+                io::stdout().flush().unwrap();
+            }
+            ControlFlow::Continue(())
+        });
     println!();
-    let avg_token_time = (std::time::Instant::now() - start_decode).as_micros() as f32
-        / (output_ids.len() - 1) as f32
-        / 1000.0;
+
+    let start_decode = start_decode.unwrap();
+    let prompt_ms = (start_decode - start).as_millis();
     println!(
-        "\nAverage token generated in {:.2}ms\t - ({:.2} tok/s)",
+        "\nProcessed prompt in {prompt_ms}ms ({:.2} tok/s, {} prompt tokens)",
+        1000.0 * (input_ids.len() as f64) / (prompt_ms as f64),
+        input_ids.len()
+    );
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "Average token generated in {:.2}ms\t - ({:.2} tok/s)",
         avg_token_time,
         1000.0 / avg_token_time
     );
 }
-
-// Currently just an argmax, do actual sampling here
-fn sample_index(dist: &[f32]) -> u32 {
-    dist.iter()
-        .position_max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-        .unwrap() as u32
-}
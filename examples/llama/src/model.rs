@@ -1,235 +1,258 @@
-use std::{marker::PhantomData, ops::Div};
-
-use luminal::prelude::{binary::F32Pow, *};
-use luminal_nn::{Embedding, PermutedLinear, RMSNorm};
-
-// Llama3 8B Config
-pub const VOCAB_SIZE: usize = 128256;
-pub const HIDDEN_DIM: usize = 4096;
-pub const NUM_LAYERS: usize = 32;
-pub const N_HEADS: usize = 32;
-pub const N_KV_HEADS: usize = 8;
-pub const MLP_DIM: usize = 14336;
-
-pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
-pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
-pub const HEAD_DIM_OVER_2: usize = HEAD_DIM / 2;
-pub const ATTN_PROJ_DIM: usize = HEAD_DIM * N_KV_HEADS;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::{prelude::*, serialization::gguf::Hyperparameters};
+use luminal_nn::{
+    AttentionMask, DynMultiHeadAttention, DynRMSNorm, DynRotaryEmbedding, RopeScaling,
+};
+
+// Model dimensions are dynamic so the same graph code works for every model size. Their sizes come from a
+// `LlamaConfig`, and are set on the graph with `LlamaConfig::set_dims` before it's compiled.
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type KVHeads = Dyn<'k'>;
+pub type HeadDim = Dyn<'e'>;
+pub type HeadDimOver2 = Dyn<'r'>;
+pub type AttnProjDim = Dyn<'a'>;
 
 pub type KVCache<Batch, Seq> = (
-    GraphTensor<(Batch, Const<N_KV_HEADS>, Seq, Const<HEAD_DIM>)>,
-    GraphTensor<(Batch, Const<N_KV_HEADS>, Seq, Const<HEAD_DIM>)>,
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
 );
 
-pub struct Mlp<const I: usize, const H: usize> {
-    pub gate_proj: PermutedLinear<H, I>,
-    pub down_proj: PermutedLinear<I, H>,
-    pub up_proj: PermutedLinear<H, I>,
+/// The hyperparameters of a llama model
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub mlp_dim: usize,
+    pub rope_theta: f32,
+    /// How rotary frequencies are stretched for contexts longer than the model was trained on
+    pub rope_scaling: RopeScaling,
+    pub rms_norm_epsilon: f32,
 }
 
-impl<Sh: Shape, Im: Shape, const I: usize, const H: usize> Module<GraphTensor<Sh>> for Mlp<I, H>
-where
-    GraphTensor<Sh>: Matmul<R2<H, I>, Output = GraphTensor<Im>>,
-    GraphTensor<Im>: Matmul<R2<I, H>, Output = GraphTensor<Sh>>,
-{
-    type Output = GraphTensor<Sh>;
-
-    fn forward(&self, input: GraphTensor<Sh>) -> Self::Output {
-        let gate = self.gate_proj.forward(input).swish();
-        let up = self.up_proj.forward(input) * gate;
-        self.down_proj.forward(up)
+impl LlamaConfig {
+    /// Read the config from a GGUF file's metadata
+    pub fn from_gguf(hparams: &Hyperparameters) -> Result<Self> {
+        Self {
+            vocab_size: hparams.vocab_size,
+            hidden_dim: hparams.hidden_dim,
+            n_layers: hparams.n_layers,
+            n_heads: hparams.n_heads,
+            n_kv_heads: hparams.n_kv_heads,
+            mlp_dim: hparams.mlp_dim,
+            rope_theta: hparams.rope_theta,
+            rope_scaling: RopeScaling::None,
+            rms_norm_epsilon: hparams.rms_norm_epsilon.unwrap_or(1e-5),
+        }
+        .validated()
     }
-}
 
-impl<const I: usize, const H: usize> InitModule for Mlp<I, H> {
-    fn initialize(cx: &mut Graph) -> Self {
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing {key}")))
+        };
+        let get_f32 = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_f64)
+                .map(|v| v as f32)
+        };
+        let n_heads = get_usize("num_attention_heads")?;
         Self {
-            gate_proj: PermutedLinear {
-                weight: cx.named_tensor("Gate"),
-            },
-            up_proj: PermutedLinear {
-                weight: cx.named_tensor("Up"),
-            },
-            down_proj: PermutedLinear {
-                weight: cx.named_tensor("Down"),
+            vocab_size: get_usize("vocab_size")?,
+            hidden_dim: get_usize("hidden_size")?,
+            n_layers: get_usize("num_hidden_layers")?,
+            n_heads,
+            n_kv_heads: get_usize("num_key_value_heads").unwrap_or(n_heads),
+            mlp_dim: get_usize("intermediate_size")?,
+            rope_theta: get_f32("rope_theta").unwrap_or(10000.),
+            rope_scaling: match json.get("rope_scaling") {
+                Some(scaling) if !scaling.is_null() => rope_scaling_from_json(scaling)?,
+                _ => RopeScaling::None,
             },
+            rms_norm_epsilon: get_f32("rms_norm_eps").unwrap_or(1e-5),
         }
+        .validated()
     }
-}
 
-impl<const I: usize, const H: usize> SerializeModule for Mlp<I, H> {
-    fn serialize(&self, s: &mut Serializer) {
-        s.module("ffn_gate", &self.gate_proj);
-        s.module("ffn_up", &self.up_proj);
-        s.module("ffn_down", &self.down_proj);
+    fn validated(self) -> Result<Self> {
+        let invalid = |message: &str| Err(Error::new(ErrorKind::InvalidData, message.to_string()));
+        if self.n_heads == 0 || !self.hidden_dim.is_multiple_of(self.n_heads) {
+            return invalid("Hidden dim must be divisible by the number of heads");
+        }
+        if self.n_kv_heads == 0 || !self.n_heads.is_multiple_of(self.n_kv_heads) {
+            return invalid("Number of heads must be divisible by the number of KV heads");
+        }
+        if !self.head_dim().is_multiple_of(2) {
+            return invalid("Head dim must be even for rotary embeddings");
+        }
+        Ok(self)
     }
-}
 
-fn apply_rotary_embeddings_ggml<const N_HEADS: usize, Batch: Dimension, Seq: Dimension>(
-    input: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)>,
-    prev_seq: BigExpression,
-) -> GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)> {
-    // Get freqs
-    let freqs = (input.graph().arange::<Const<HEAD_DIM_OVER_2>>() * 2.0) / (HEAD_DIM as f32);
-    let freqs = 500000_f32.pow(freqs);
-    let pos = input.graph().arange::<Seq>() + prev_seq;
-    let emb = pos.expand::<(_, Const<1>), _>().matmul(freqs.expand());
-
-    // Split input into evens and odds
-    let split = input.reshape::<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<2>)>();
-    let x0: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<1>)> = split
-        .slice((.., .., .., .., ..Expression::from(1)))
-        .realize();
-    let x1: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<1>)> = split
-        .slice((.., .., .., .., Expression::from(1)..))
-        .realize();
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
 
-    // Apply sin and cos embeddings
-    let x0_out = x0 * emb.cos().expand() - x1 * emb.sin().expand();
-    let x1_out = x0 * emb.sin().expand() + x1 * emb.cos().expand();
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 8] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('k', self.n_kv_heads),
+            ('e', self.head_dim()),
+            ('r', self.head_dim() / 2),
+            ('a', self.head_dim() * self.n_kv_heads),
+        ]
+    }
 
-    // Combine back into output
-    x0_out
-        .concat_along::<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<2>), Axis<4>, _>(
-            x1_out,
-        )
-        .reshape()
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
 }
 
-pub struct SelfAttention {
-    pub q_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
-    pub k_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub v_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub o_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
+/// Read a HuggingFace `rope_scaling` entry, like `{"rope_type": "yarn", "factor": 4.0, ...}`
+fn rope_scaling_from_json(json: &serde_json::Value) -> Result<RopeScaling> {
+    let kind = json
+        .get("rope_type")
+        .or_else(|| json.get("type"))
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or_default();
+    let get_f32 = |key: &str| {
+        json.get(key)
+            .and_then(serde_json::Value::as_f64)
+            .map(|v| v as f32)
+    };
+    let factor = get_f32("factor").unwrap_or(1.);
+    Ok(match kind {
+        "linear" => RopeScaling::Linear { factor },
+        "dynamic" => RopeScaling::Ntk { factor },
+        "yarn" => RopeScaling::Yarn {
+            factor,
+            original_max_position: json
+                .get("original_max_position_embeddings")
+                .and_then(serde_json::Value::as_u64)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "Missing original_max_position_embeddings for YaRN",
+                    )
+                })? as usize,
+            beta_fast: get_f32("beta_fast").unwrap_or(32.),
+            beta_slow: get_f32("beta_slow").unwrap_or(1.),
+        },
+        "default" => RopeScaling::None,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported rope scaling: {kind:?}"),
+            ))
+        }
+    })
 }
 
-impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
-    Module<(
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        KVCache<Batch, PrevSeq>,
-        PhantomData<TotSeq>,
-    )> for SelfAttention
-{
-    type Output = (
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        KVCache<Batch, TotSeq>,
-    );
-    fn forward(
-        &self,
-        (x, (k_cache, v_cache), _): (
-            GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-            KVCache<Batch, PrevSeq>,
-            PhantomData<TotSeq>,
-        ),
-    ) -> Self::Output {
-        // Apply the Projections
-        let queries = x
-            .matmul(self.q_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-
-        let keys = x
-            .matmul(self.k_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-
-        let values = x
-            .matmul(self.v_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-
-        // Rotary embed queries and keys
-        let queries = apply_rotary_embeddings_ggml(queries, PrevSeq::const_size().into());
-        let keys = apply_rotary_embeddings_ggml(keys, PrevSeq::const_size().into());
-
-        // Add KV cache
-        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
-        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
-
-        // Repeat the KV States for Grouped-Query Attention
-        let repeated_keys = keys.expand::<(_, _, Const<N_ATTENTION_GROUPS>, _, _), _>();
-        let repeated_values = values.expand::<(_, _, Const<N_ATTENTION_GROUPS>, _, _), _>();
-
-        // Calculate attention weights
-        let mut attention_weights = queries
-            .reshape::<(_, Const<N_KV_HEADS>, Const<N_ATTENTION_GROUPS>, _, _)>() // Split query heads into groups
-            .matmul(repeated_keys.permute())
-            .div((HEAD_DIM as f32).sqrt());
+pub struct Mlp {
+    pub gate_proj: GraphTensor<(MlpDim, Hidden)>,
+    pub down_proj: GraphTensor<(Hidden, MlpDim)>,
+    pub up_proj: GraphTensor<(MlpDim, Hidden)>,
+}
 
-        let attention_mask = self.k_proj.graph().triu::<CurSeq>(1) * f16::MIN.to_f32();
-        attention_weights += attention_mask
-            .pad::<(CurSeq, TotSeq), _, _>(&[
-                (0.into(), Expression::from(0)),
-                (TotSeq::const_size() - CurSeq::const_size(), 0.into()),
-            ])
-            .expand();
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Mlp {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
 
-        // Calculate final outputs
-        let output = attention_weights
-            .softmax::<Axis<4>>()
-            // Apply distribution to values
-            .matmul(repeated_values)
-            // Merge heads
-            .permute::<_, Axes5<0, 3, 1, 2, 4>>()
-            .reshape::<(Batch, CurSeq, Const<HIDDEN_DIM>)>();
-        let output = output
-            // Apply output projection
-            .matmul(self.o_proj.permute());
-        (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        let gate = input.matmul(self.gate_proj.permute()).swish();
+        let up = input.matmul(self.up_proj.permute()) * gate;
+        up.matmul(self.down_proj.permute())
     }
 }
 
-impl InitModule for SelfAttention {
+impl InitModule for Mlp {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            q_proj: cx.named_tensor("Q Proj"),
-            k_proj: cx.named_tensor("K Proj"),
-            v_proj: cx.named_tensor("V Proj"),
-            o_proj: cx.named_tensor("O Proj"),
+            gate_proj: cx.named_tensor("Gate"),
+            up_proj: cx.named_tensor("Up"),
+            down_proj: cx.named_tensor("Down"),
         }
     }
 }
 
-impl SerializeModule for SelfAttention {
+impl SerializeModule for Mlp {
     fn serialize(&self, s: &mut Serializer) {
-        s.tensor("attn_q/weight", self.q_proj);
-        s.tensor("attn_v/weight", self.v_proj);
-        s.tensor("attn_k/weight", self.k_proj);
-        s.tensor("attn_output/weight", self.o_proj);
+        s.tensor("ffn_gate/weight", self.gate_proj);
+        s.tensor("ffn_up/weight", self.up_proj);
+        s.tensor("ffn_down/weight", self.down_proj);
     }
 }
 
+/// Llama's attention. GGUF checkpoints lay out each head so its rotary embeddings rotate adjacent pairs of dims.
+pub type SelfAttention =
+    DynMultiHeadAttention<Hidden, Hidden, AttnProjDim, Heads, KVHeads, HeadDim, HeadDimOver2>;
+
 pub struct TransformerBlock {
     pub attention: SelfAttention,
-    pub attention_norm: RMSNorm<HIDDEN_DIM>,
-    pub feed_forward: Mlp<MLP_DIM, HIDDEN_DIM>,
-    pub feed_forward_norm: RMSNorm<HIDDEN_DIM>,
+    pub attention_norm: DynRMSNorm<Hidden>,
+    pub feed_forward: Mlp,
+    pub feed_forward_norm: DynRMSNorm<Hidden>,
+}
+
+impl TransformerBlock {
+    pub fn new(config: &LlamaConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention::new(
+                false,
+                false,
+                Some(DynRotaryEmbedding::new(
+                    config.rope_theta,
+                    config.rope_scaling,
+                    config.head_dim(),
+                    cx,
+                )),
+                cx,
+            ),
+            attention_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            feed_forward: InitModule::initialize(cx),
+            feed_forward_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+        }
+    }
 }
 
 impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
     Module<(
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
+        GraphTensor<(Batch, CurSeq, Hidden)>,
         KVCache<Batch, PrevSeq>,
-        PhantomData<TotSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
     )> for TransformerBlock
 {
-    type Output = (
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        KVCache<Batch, TotSeq>,
-    );
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
     fn forward(
         &self,
-        (mut x, cache, _): (
-            GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
+        (mut x, cache, mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
             KVCache<Batch, PrevSeq>,
-            PhantomData<TotSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
         ),
     ) -> Self::Output {
         // Attention
         let normed = self.attention_norm.forward(x);
-        let (y, cache) = self
-            .attention
-            .forward((normed, cache, PhantomData::<TotSeq>));
+        let (y, cache) = self.attention.forward((normed, cache, mask));
 
         // Residual Addition
         x += y;
@@ -242,26 +265,12 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
     }
 }
 
-impl InitModule for TransformerBlock {
-    fn initialize(cx: &mut Graph) -> Self {
-        Self {
-            attention: InitModule::initialize(cx),
-            attention_norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
-                epsilon: 1e-5,
-            },
-            feed_forward: InitModule::initialize(cx),
-            feed_forward_norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
-                epsilon: 1e-5,
-            },
-        }
-    }
-}
-
 impl SerializeModule for TransformerBlock {
     fn serialize(&self, s: &mut Serializer) {
-        s.module("", &self.attention);
+        s.module("attn_q", &self.attention.w_q);
+        s.module("attn_k", &self.attention.w_k);
+        s.module("attn_v", &self.attention.w_v);
+        s.module("attn_output", &self.attention.w_o);
         s.module("attn_norm", &self.attention_norm);
         s.module("ffn_norm", &self.feed_forward_norm);
         s.module("", &self.feed_forward);
@@ -270,42 +279,56 @@ impl SerializeModule for TransformerBlock {
 
 pub struct MistralLM {
     // Token embeddings
-    pub embedding: Embedding<VOCAB_SIZE, HIDDEN_DIM>,
+    pub embedding: GraphTensor<(Vocab, Hidden)>,
     // Transformer layers
     pub layers: Vec<TransformerBlock>,
     // Final Norm layer
-    pub norm: RMSNorm<HIDDEN_DIM>,
+    pub norm: DynRMSNorm<Hidden>,
     // LM Head Layer
-    pub lm_head: GraphTensor<R2<VOCAB_SIZE, HIDDEN_DIM>>,
+    pub lm_head: GraphTensor<(Vocab, Hidden)>,
+}
+
+impl MistralLM {
+    /// Build the model for a config. The config's dims must also be set on the graph with `LlamaConfig::set_dims`.
+    pub fn new(config: &LlamaConfig, cx: &mut Graph) -> Self {
+        Self {
+            embedding: cx.named_tensor("Embedding Weight"),
+            norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            lm_head: cx.named_tensor("LM Head"),
+            layers: (0..config.n_layers)
+                .map(|_| TransformerBlock::new(config, cx))
+                .collect(),
+        }
+    }
 }
 
 impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
     Module<(
         GraphTensor<(Batch, CurSeq)>,
         &[KVCache<Batch, PrevSeq>],
-        PhantomData<TotSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
     )> for MistralLM
 {
     type Output = (
-        GraphTensor<(Batch, CurSeq, Const<VOCAB_SIZE>)>,
+        GraphTensor<(Batch, CurSeq, Vocab)>,
         Vec<KVCache<Batch, TotSeq>>,
     );
     fn forward(
         &self,
-        (input, cache, _): (
+        (input, cache, mask): (
             GraphTensor<(Batch, CurSeq)>,
             &[KVCache<Batch, PrevSeq>],
-            PhantomData<TotSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
         ),
     ) -> Self::Output {
         // Embed tokens
-        let mut x = self.embedding.forward(input);
+        let mut x = self.embedding.index_select::<_, Axis<0>, _>(input);
 
         // Run through layers and collect new caches
         let mut new_caches = vec![];
         let mut new_cache;
         for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) = layer.forward((x, cache[i], PhantomData::<TotSeq>));
+            (x, new_cache) = layer.forward((x, cache[i], mask));
             new_caches.push(new_cache);
         }
         // Run through last norm and output projection
@@ -315,27 +338,9 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
     }
 }
 
-impl InitModule for MistralLM {
-    fn initialize(cx: &mut Graph) -> Self {
-        Self {
-            embedding: Embedding {
-                weight: cx.named_tensor("Embedding Weight"),
-            },
-            norm: RMSNorm {
-                weight: cx.named_tensor("RMS Norm Weight"),
-                epsilon: 1e-5,
-            },
-            lm_head: cx.named_tensor("LM Head"),
-            layers: (0..NUM_LAYERS)
-                .map(|_| InitModule::initialize(cx))
-                .collect(),
-        }
-    }
-}
-
 impl SerializeModule for MistralLM {
     fn serialize(&self, s: &mut Serializer) {
-        s.module("token_embd", &self.embedding);
+        s.tensor("token_embd/weight", self.embedding);
         s.module("output_norm", &self.norm);
         s.tensor("output/weight", self.lm_head);
         for (i, layer) in self.layers.iter().enumerate() {
@@ -0,0 +1,257 @@
+//! A minimal OpenAI-compatible HTTP server over the compiled model, serving `/v1/completions`,
+//! `/v1/chat/completions` and `/v1/models`. Each request is handled on its own thread and submitted to an
+//! `InferenceSession`, which batches the requests running at the same time onto the model. `"stream": true` responses
+//! are sent as server-sent events while tokens are generated.
+
+use std::{
+    io::Write,
+    ops::ControlFlow,
+    sync::Arc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use luminal::{prelude::*, tokenizer::Tokenizer};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// The sampling settings and prompt of a completion request
+struct Completion {
+    chat: bool,
+    /// The template chat prompts were rendered with
+    template: Option<ChatTemplate>,
+    prompt: String,
+    max_tokens: usize,
+    temperature: f32,
+    top_p: f32,
+    seed: u64,
+    stop: Vec<String>,
+    stream: bool,
+}
+
+impl Completion {
+    fn parse(body: &str, chat: bool, template: ChatTemplate) -> Result<Self, String> {
+        let body: Value = serde_json::from_str(body).map_err(|e| format!("Invalid JSON: {e}"))?;
+        let prompt = if chat {
+            let messages = body["messages"]
+                .as_array()
+                .ok_or("`messages` must be a list")?
+                .iter()
+                .map(|m| match (m["role"].as_str(), m["content"].as_str()) {
+                    (Some(role), Some(content)) => Some(ChatMessage::new(role, content)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or("Each message needs a `role` and text `content`")?;
+            template.render(&messages, true)
+        } else {
+            body["prompt"]
+                .as_str()
+                .ok_or("`prompt` must be a string")?
+                .to_string()
+        };
+        let stop = match &body["stop"] {
+            Value::Null => vec![],
+            Value::String(s) => vec![s.clone()],
+            Value::Array(a) => a
+                .iter()
+                .map(|s| s.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or("`stop` must be a string or a list of strings")?,
+            _ => return Err("`stop` must be a string or a list of strings".to_string()),
+        };
+        Ok(Self {
+            chat,
+            template: chat.then_some(template),
+            prompt,
+            max_tokens: body["max_tokens"].as_u64().unwrap_or(128) as usize,
+            temperature: body["temperature"].as_f64().unwrap_or(1.) as f32,
+            top_p: body["top_p"].as_f64().unwrap_or(1.) as f32,
+            seed: body["seed"].as_u64().unwrap_or_else(|| now() as u64),
+            stop,
+            stream: body["stream"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// The JSON of a response, or a streamed chunk of one, holding some text
+    fn response(
+        &self,
+        id: &str,
+        model: &str,
+        text: &str,
+        finish: Option<&str>,
+        chunk: bool,
+    ) -> Value {
+        let choice = match (self.chat, chunk) {
+            (true, true) => {
+                json!({"index": 0, "delta": {"content": text}, "finish_reason": finish})
+            }
+            (true, false) => json!({
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": finish,
+            }),
+            (false, _) => {
+                json!({"index": 0, "text": text, "logprobs": null, "finish_reason": finish})
+            }
+        };
+        let object = match (self.chat, chunk) {
+            (true, true) => "chat.completion.chunk",
+            (true, false) => "chat.completion",
+            (false, _) => "text_completion",
+        };
+        json!({"id": id, "object": object, "created": now(), "model": model, "choices": [choice]})
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u128
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(
+        status,
+        &json!({"error": {"message": message, "type": "invalid_request_error"}}),
+    )
+}
+
+/// Serve requests on `addr` until the process is stopped, submitting them to the session behind `session` and
+/// formatting chat requests with `template`
+pub fn serve(
+    addr: &str,
+    session: SessionHandle,
+    tokenizer: Tokenizer,
+    model_name: &str,
+    template: ChatTemplate,
+) {
+    let server = Server::http(addr).unwrap();
+    println!("Serving on http://{addr}");
+    let tokenizer = Arc::new(tokenizer);
+    for (n, mut request) in server.incoming_requests().enumerate() {
+        let chat = match (request.method(), request.url()) {
+            (Method::Post, "/v1/completions") => false,
+            (Method::Post, "/v1/chat/completions") => true,
+            (Method::Get, "/v1/models") => {
+                let models = json!({
+                    "object": "list",
+                    "data": [{"id": model_name, "object": "model", "owned_by": "luminal"}],
+                });
+                let _ = request.respond(json_response(200, &models));
+                continue;
+            }
+            _ => {
+                let _ = request.respond(error_response(404, "Not found"));
+                continue;
+            }
+        };
+        let (session, tokenizer, model_name) =
+            (session.clone(), tokenizer.clone(), model_name.to_string());
+        thread::spawn(move || {
+            let mut body = String::new();
+            let completion = request
+                .as_reader()
+                .read_to_string(&mut body)
+                .map_err(|e| e.to_string())
+                .and_then(|_| Completion::parse(&body, chat, template));
+            match completion {
+                Ok(completion) => complete(
+                    request,
+                    &completion,
+                    &format!("cmpl-{n}"),
+                    &session,
+                    &tokenizer,
+                    &model_name,
+                ),
+                Err(e) => {
+                    let _ = request.respond(error_response(400, &e));
+                }
+            }
+        });
+    }
+}
+
+fn complete(
+    request: Request,
+    completion: &Completion,
+    id: &str,
+    session: &SessionHandle,
+    tokenizer: &Tokenizer,
+    model_name: &str,
+) {
+    let prompt = match tokenizer.encode(&completion.prompt) {
+        Ok(p) if !p.is_empty() => p,
+        _ => {
+            let _ = request.respond(error_response(400, "The prompt couldn't be tokenized"));
+            return;
+        }
+    };
+    // Chat turns end with an end of turn token rather than the end of text one
+    let end_of_turn = completion
+        .template
+        .and_then(|t| tokenizer.end_of_turn_token(t));
+    let generator = Generator::new(completion.max_tokens)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied().chain(end_of_turn))
+        .with_stop_sequences(completion.stop.iter().cloned());
+    let sampler = Sample::new(completion.temperature, 0, completion.top_p, completion.seed);
+    let mut decoder = tokenizer.decode_stream(true);
+    // Stops are checked here, and the request is cancelled when `tokens` is dropped
+    let tokens = session.generate_stream_with(prompt.clone(), completion.max_tokens, sampler);
+
+    if !completion.stream {
+        let out = generator.decode_text(&tokens, &mut decoder, |_| ControlFlow::Continue(()));
+        let mut body = completion.response(
+            id,
+            model_name,
+            &out.text,
+            Some(finish_reason(out.finish)),
+            false,
+        );
+        body["usage"] = json!({
+            "prompt_tokens": prompt.len(),
+            "completion_tokens": out.tokens.len(),
+            "total_tokens": prompt.len() + out.tokens.len(),
+        });
+        let _ = request.respond(json_response(200, &body));
+        return;
+    }
+
+    // Streamed responses write the HTTP response themselves so events go out as they're generated
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                Connection: close\r\n\r\n";
+    if writer.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    let mut send = |event: &str| -> std::io::Result<()> {
+        writer.write_all(format!("data: {event}\n\n").as_bytes())?;
+        writer.flush()
+    };
+    let out = generator.decode_text(&tokens, &mut decoder, |text| {
+        let chunk = completion.response(id, model_name, text, None, true);
+        // Stop generating once the client disconnects
+        match send(&chunk.to_string()) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    });
+    if out.finish != FinishReason::Callback {
+        let last = completion.response(id, model_name, "", Some(finish_reason(out.finish)), true);
+        let _ = send(&last.to_string()).and_then(|_| send("[DONE]"));
+    }
+}
+
+fn finish_reason(finish: FinishReason) -> &'static str {
+    match finish {
+        FinishReason::Length => "length",
+        _ => "stop",
+    }
+}
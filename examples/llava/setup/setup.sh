@@ -0,0 +1,20 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+REPO=llava-hf/llava-1.5-7b-hf
+download() {
+    curl --location https://huggingface.co/$REPO/resolve/main/$1?download=true --output $SCRIPT_DIR/$1
+}
+
+echo "Downloading Model and Tokenizer..."
+download config.json
+download preprocessor_config.json
+download tokenizer.json
+download model.safetensors.index.json
+for shard in $(grep -o 'model-[0-9]*-of-[0-9]*\.safetensors' $SCRIPT_DIR/model.safetensors.index.json | sort -u); do
+    download $shard
+done
+echo "Downloading Sample Image..."
+curl --location http://images.cocodataset.org/val2017/000000039769.jpg --output $SCRIPT_DIR/cats.jpg
+# The example reads binary PPM images
+convert $SCRIPT_DIR/cats.jpg $SCRIPT_DIR/cats.ppm
+echo "Done!"
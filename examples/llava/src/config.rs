@@ -0,0 +1,240 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+
+/// The hyperparameters of a LLaVA model: a CLIP vision tower, the projector from its features to token embeddings,
+/// and the llama decoder those embeddings are spliced into
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlavaConfig {
+    pub vision: VisionConfig,
+    pub text: TextConfig,
+    /// The placeholder token marking where an image's features go in the prompt
+    pub image_token: u32,
+}
+
+/// The hyperparameters of the CLIP vision tower, and how its features are picked for the projector
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisionConfig {
+    pub hidden_dim: usize,
+    pub n_heads: usize,
+    pub mlp_dim: usize,
+    pub n_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    pub layer_norm_epsilon: f32,
+    /// How many encoder layers run. LLaVA reads the features of an inner layer, so the ones after it are skipped.
+    pub n_layers: usize,
+    /// Whether the class token's features are projected along with the patches'
+    pub keep_class_token: bool,
+    /// The hidden dim of the language model, which the projector maps features to
+    pub text_hidden_dim: usize,
+}
+
+/// The hyperparameters of the llama decoder
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub mlp_dim: usize,
+    pub rope_theta: f32,
+    pub rms_norm_epsilon: f32,
+    pub bos_token: u32,
+    pub eos_token: u32,
+}
+
+impl LlavaConfig {
+    /// Read the config from a HuggingFace `config.json`. LLaVA configs only list the fields of their vision and text
+    /// configs that differ from CLIP's and llama's defaults, so missing fields fall back to those.
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        let section = |key: &str| {
+            json.get(key)
+                .filter(|v| v.is_object())
+                .ok_or_else(|| invalid(format!("Missing {key}")))
+        };
+        let (vision, text) = (section("vision_config")?, section("text_config")?);
+        let get_usize = |json: &serde_json::Value, key: &str, default: usize| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map_or(default, |v| v as usize)
+        };
+        let get_f32 = |json: &serde_json::Value, key: &str, default: f32| {
+            json.get(key)
+                .and_then(serde_json::Value::as_f64)
+                .map_or(default, |v| v as f32)
+        };
+
+        let text_heads = get_usize(text, "num_attention_heads", 32);
+        let text = TextConfig {
+            vocab_size: get_usize(text, "vocab_size", 32000),
+            hidden_dim: get_usize(text, "hidden_size", 4096),
+            n_layers: get_usize(text, "num_hidden_layers", 32),
+            n_heads: text_heads,
+            n_kv_heads: get_usize(text, "num_key_value_heads", text_heads),
+            mlp_dim: get_usize(text, "intermediate_size", 11008),
+            rope_theta: get_f32(text, "rope_theta", 10000.),
+            rms_norm_epsilon: get_f32(text, "rms_norm_eps", 1e-6),
+            bos_token: get_usize(text, "bos_token_id", 1) as u32,
+            eos_token: get_usize(text, "eos_token_id", 2) as u32,
+        };
+
+        // The layer features are read from counts the embeddings as layer 0, and negative layers count from the end
+        let vision_layers = get_usize(vision, "num_hidden_layers", 12);
+        let feature_layer = json
+            .get("vision_feature_layer")
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or(-2);
+        let n_layers = if feature_layer < 0 {
+            (vision_layers + 1).checked_sub(feature_layer.unsigned_abs() as usize)
+        } else {
+            Some(feature_layer as usize).filter(|l| *l <= vision_layers)
+        }
+        .ok_or_else(|| invalid(format!("Invalid vision feature layer {feature_layer}")))?;
+        let keep_class_token = match json
+            .get("vision_feature_select_strategy")
+            .and_then(serde_json::Value::as_str)
+        {
+            None | Some("default") => false,
+            Some("full") => true,
+            Some(strategy) => {
+                return Err(invalid(format!(
+                    "Unsupported feature select strategy: {strategy}"
+                )))
+            }
+        };
+        let vision = VisionConfig {
+            hidden_dim: get_usize(vision, "hidden_size", 768),
+            n_heads: get_usize(vision, "num_attention_heads", 12),
+            mlp_dim: get_usize(vision, "intermediate_size", 3072),
+            n_channels: get_usize(vision, "num_channels", 3),
+            image_size: get_usize(vision, "image_size", 224),
+            patch_size: get_usize(vision, "patch_size", 32),
+            layer_norm_epsilon: get_f32(vision, "layer_norm_eps", 1e-5),
+            n_layers,
+            keep_class_token,
+            text_hidden_dim: text.hidden_dim,
+        };
+
+        match json
+            .get("projector_hidden_act")
+            .and_then(serde_json::Value::as_str)
+        {
+            None | Some("gelu") => {}
+            Some(act) => return Err(invalid(format!("Unsupported projector activation: {act}"))),
+        }
+        let config = Self {
+            vision,
+            text,
+            image_token: get_usize(&json, "image_token_index", 32000) as u32,
+        };
+        config.validated()
+    }
+
+    fn validated(self) -> Result<Self> {
+        let invalid = |message: &str| Err(Error::new(ErrorKind::InvalidData, message.to_string()));
+        let (vision, text) = (&self.vision, &self.text);
+        if vision.n_heads == 0 || !vision.hidden_dim.is_multiple_of(vision.n_heads) {
+            return invalid("Vision hidden dim must be divisible by the number of heads");
+        }
+        if vision.patch_size == 0 || !vision.image_size.is_multiple_of(vision.patch_size) {
+            return invalid("Image size must be divisible by the patch size");
+        }
+        if text.n_heads == 0 || !text.hidden_dim.is_multiple_of(text.n_heads) {
+            return invalid("Text hidden dim must be divisible by the number of heads");
+        }
+        if text.n_kv_heads == 0 || !text.n_heads.is_multiple_of(text.n_kv_heads) {
+            return invalid("Number of heads must be divisible by the number of KV heads");
+        }
+        if !text.head_dim().is_multiple_of(2) {
+            return invalid("Head dim must be even for rotary embeddings");
+        }
+        if self.image_token as usize >= text.vocab_size {
+            return invalid("The image token must be in the vocabulary");
+        }
+        Ok(self)
+    }
+}
+
+impl VisionConfig {
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    pub fn grid_size(&self) -> usize {
+        self.image_size / self.patch_size
+    }
+
+    /// The number of features each image is projected to, which is how many tokens it takes up in the prompt
+    pub fn tokens_per_image(&self) -> usize {
+        self.grid_size() * self.grid_size() + self.keep_class_token as usize
+    }
+
+    /// The sizes of the vision tower's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 13] {
+        let patches = self.grid_size() * self.grid_size();
+        [
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('e', self.head_dim()),
+            ('c', self.n_channels),
+            ('i', self.image_size),
+            ('q', self.patch_size),
+            ('g', self.grid_size()),
+            ('p', patches),
+            ('k', self.n_channels * self.patch_size * self.patch_size),
+            ('n', patches + 1),
+            ('o', self.text_hidden_dim),
+            ('m', self.tokens_per_image()),
+        ]
+    }
+
+    /// Set the vision tower's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
+
+impl TextConfig {
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    /// The rotary frequency of each pair of dimensions in a head
+    pub fn rope_frequencies(&self) -> Vec<f32> {
+        let head_dim = self.head_dim();
+        (0..head_dim / 2)
+            .map(|i| self.rope_theta.powf(-2. * i as f32 / head_dim as f32))
+            .collect()
+    }
+
+    /// The sizes of the decoder's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 8] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('k', self.n_kv_heads),
+            ('e', self.head_dim()),
+            ('r', self.head_dim() / 2),
+            ('a', self.head_dim() * self.n_kv_heads),
+        ]
+    }
+
+    /// Set the decoder's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynRMSNorm};
+
+use crate::config::TextConfig;
+
+// The decoder's dimensions are dynamic, with sizes from a `TextConfig` set on its graph with `TextConfig::set_dims`
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type KVHeads = Dyn<'k'>;
+pub type HeadDim = Dyn<'e'>;
+pub type HeadDimOver2 = Dyn<'r'>;
+pub type AttnProjDim = Dyn<'a'>;
+/// The image features of every image in the prompt, one row per image token
+pub type ImageRows = Dyn<'i'>;
+
+pub type KVCache<Batch, Seq> = (
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+);
+
+pub struct Mlp {
+    pub gate_proj: GraphTensor<(MlpDim, Hidden)>,
+    pub down_proj: GraphTensor<(Hidden, MlpDim)>,
+    pub up_proj: GraphTensor<(MlpDim, Hidden)>,
+}
+
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Mlp {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        let gate = input.matmul(self.gate_proj.permute()).swish();
+        let up = input.matmul(self.up_proj.permute()) * gate;
+        up.matmul(self.down_proj.permute())
+    }
+}
+
+impl InitModule for Mlp {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            gate_proj: cx.named_tensor("Gate"),
+            up_proj: cx.named_tensor("Up"),
+            down_proj: cx.named_tensor("Down"),
+        }
+    }
+}
+
+impl SerializeModule for Mlp {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("gate_proj/weight", self.gate_proj);
+        s.tensor("up_proj/weight", self.up_proj);
+        s.tensor("down_proj/weight", self.down_proj);
+    }
+}
+
+pub struct SelfAttention {
+    pub q_proj: GraphTensor<(Hidden, Hidden)>,
+    pub k_proj: GraphTensor<(AttnProjDim, Hidden)>,
+    pub v_proj: GraphTensor<(AttnProjDim, Hidden)>,
+    pub o_proj: GraphTensor<(Hidden, Hidden)>,
+    /// The rotary frequency of each pair of dimensions in a head
+    pub rope_freqs: GraphTensor<(HeadDimOver2,)>,
+}
+
+impl SelfAttention {
+    pub fn new(config: &TextConfig, cx: &mut Graph) -> Self {
+        Self {
+            q_proj: cx.named_tensor("Q Proj"),
+            k_proj: cx.named_tensor("K Proj"),
+            v_proj: cx.named_tensor("V Proj"),
+            o_proj: cx.named_tensor("O Proj"),
+            rope_freqs: cx
+                .named_tensor("RoPE Frequencies")
+                .set_dyn(config.rope_frequencies(), &[config.head_dim() / 2]),
+        }
+    }
+
+    /// Split projected queries or keys into heads, and apply rotary embeddings
+    fn rotate<Batch: Dimension, Seq: Dimension, N: Dimension>(
+        &self,
+        x: GraphTensor<(Batch, Seq, N, HeadDim)>,
+        prev_seq: BigExpression,
+    ) -> GraphTensor<(Batch, N, Seq, HeadDim)> {
+        // HF checkpoints rotate the first half of each head with the second half, but the rope op rotates adjacent
+        // pairs, so interleave the halves first. Queries and keys are reordered the same way, so their dot products
+        // don't change.
+        x.reshape::<(Batch, Seq, N, Const<2>, HeadDimOver2)>()
+            .permute::<_, Axes5<0, 2, 1, 4, 3>>()
+            .reshape::<(Batch, N, Seq, HeadDim)>()
+            .rope(self.rope_freqs, prev_seq)
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for SelfAttention
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (x, (k_cache, v_cache), mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        let queries = self.rotate(
+            x.matmul(self.q_proj.permute())
+                .reshape::<(Batch, CurSeq, Heads, HeadDim)>(),
+            PrevSeq::const_size().into(),
+        );
+        let keys = self.rotate(
+            x.matmul(self.k_proj.permute())
+                .reshape::<(Batch, CurSeq, KVHeads, HeadDim)>(),
+            PrevSeq::const_size().into(),
+        );
+        let values = x
+            .matmul(self.v_proj.permute())
+            .reshape::<(Batch, CurSeq, KVHeads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        // Add KV cache
+        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
+        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
+
+        // Attend with each KV head shared by a group of query heads
+        let output = grouped_query_attention(queries, keys, values, mask)
+            // Merge heads
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(Batch, CurSeq, Hidden)>()
+            .matmul(self.o_proj.permute());
+        // The cache needs to be contiguous to be transferred back to the cache inputs
+        (output, (keys.contiguous(), values.contiguous()))
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("q_proj/weight", self.q_proj);
+        s.tensor("k_proj/weight", self.k_proj);
+        s.tensor("v_proj/weight", self.v_proj);
+        s.tensor("o_proj/weight", self.o_proj);
+    }
+}
+
+pub struct TransformerBlock {
+    pub attention: SelfAttention,
+    pub attention_norm: DynRMSNorm<Hidden>,
+    pub feed_forward: Mlp,
+    pub feed_forward_norm: DynRMSNorm<Hidden>,
+}
+
+impl TransformerBlock {
+    pub fn new(config: &TextConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention::new(config, cx),
+            attention_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            feed_forward: InitModule::initialize(cx),
+            feed_forward_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for TransformerBlock
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (x, cache, mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        let (y, cache) = self
+            .attention
+            .forward((self.attention_norm.forward(x), cache, mask));
+        let x = x + y;
+        let y = self.feed_forward.forward(self.feed_forward_norm.forward(x));
+        (x + y, cache)
+    }
+}
+
+impl SerializeModule for TransformerBlock {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("input_layernorm", &self.attention_norm);
+        s.module("mlp", &self.feed_forward);
+        s.module("post_attention_layernorm", &self.feed_forward_norm);
+    }
+}
+
+/// The llama decoder, reading image features wherever the prompt holds image tokens
+pub struct LanguageModel {
+    pub embedding: GraphTensor<(Vocab, Hidden)>,
+    pub layers: Vec<TransformerBlock>,
+    pub norm: DynRMSNorm<Hidden>,
+    pub lm_head: GraphTensor<(Vocab, Hidden)>,
+    pub image_token: u32,
+}
+
+impl LanguageModel {
+    /// Build the model for a config. The config's dims must also be set on the graph with `TextConfig::set_dims`.
+    pub fn new(config: &TextConfig, image_token: u32, cx: &mut Graph) -> Self {
+        Self {
+            embedding: cx.named_tensor("Embedding Weight"),
+            layers: (0..config.n_layers)
+                .map(|_| TransformerBlock::new(config, cx))
+                .collect(),
+            norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            lm_head: cx.named_tensor("LM Head"),
+            image_token,
+        }
+    }
+
+    /// Embed the tokens, splicing in a row of the image features at each image token. The nth image token of each
+    /// sequence takes the nth row, so the prompt needs one image token per feature row.
+    pub fn embed<Batch: Dimension, Seq: Dimension>(
+        &self,
+        tokens: GraphTensor<(Batch, Seq)>,
+        image_features: GraphTensor<(ImageRows, Hidden)>,
+    ) -> GraphTensor<(Batch, Seq, Hidden)> {
+        let text = self
+            .embedding
+            .index_select::<(Batch, Seq, Hidden), Axis<0>, _>(tokens);
+        let is_image = tokens
+            .eq_mask(
+                unsafe { tokens.with_graph(|cx| cx.constant(self.image_token as f32)) }
+                    .expand_to(tokens.shape),
+            )
+            .cast(DType::F32);
+        // Count the image tokens so far to find each one's row, which is 0 at text tokens
+        let rows = (is_image.cumsum::<Axis<1>>() - 1.) * is_image;
+        let image = image_features.index_select::<(Batch, Seq, Hidden), Axis<0>, _>(rows);
+        is_image
+            .expand::<(Batch, Seq, Hidden), Axis<2>>()
+            .where_(image, text)
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq)>,
+        GraphTensor<(ImageRows, Hidden)>,
+        &[KVCache<Batch, PrevSeq>],
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for LanguageModel
+{
+    type Output = (
+        GraphTensor<(Batch, CurSeq, Vocab)>,
+        Vec<KVCache<Batch, TotSeq>>,
+    );
+    fn forward(
+        &self,
+        (tokens, image_features, cache, mask): (
+            GraphTensor<(Batch, CurSeq)>,
+            GraphTensor<(ImageRows, Hidden)>,
+            &[KVCache<Batch, PrevSeq>],
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        let mut x = self.embed(tokens, image_features);
+        let mut new_caches = vec![];
+        let mut new_cache;
+        for (i, layer) in self.layers.iter().enumerate() {
+            (x, new_cache) = layer.forward((x, cache[i], mask));
+            new_caches.push(new_cache);
+        }
+        let output = self.norm.forward(x).matmul(self.lm_head.permute());
+        (output, new_caches)
+    }
+}
+
+impl SerializeModule for LanguageModel {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("language_model/model/embed_tokens/weight", self.embedding);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("language_model/model/layers/{i}"), layer);
+        }
+        s.module("language_model/model/norm", &self.norm);
+        s.tensor("language_model/lm_head/weight", self.lm_head);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+    path::Path,
+    time::Instant,
+};
+
+use clap::Parser;
+use colored::Colorize;
+
+mod config;
+mod language;
+mod vision;
+
+use crate::{config::LlavaConfig, language::KVCache};
+use luminal::{image, prelude::*, serialization::safetensors, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Binary PPM images to ask about, which can be given multiple times
+    #[clap(short = 'i', long = "image", default_values = ["setup/cats.ppm"])]
+    images: Vec<String>,
+
+    /// Question about the images
+    #[clap(
+        short = 'p',
+        long = "prompt",
+        default_value = "What is shown in this image?"
+    )]
+    prompt: String,
+
+    /// Number of tokens to generate
+    #[clap(short = 't', long = "gen_tokens", default_value = "128")]
+    gen_tokens: i32,
+
+    /// Directory holding the HuggingFace config.json, preprocessor_config.json, tokenizer.json and sharded weights
+    #[clap(short = 'm', long = "model", default_value = "setup")]
+    model: String,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let dir = Path::new(&cli_args.model);
+    let config = LlavaConfig::from_hf_config(dir.join("config.json")).unwrap();
+    let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+        .unwrap()
+        .with_bos_token(config.text.bos_token)
+        .with_eos_tokens([config.text.eos_token]);
+    let defaults = image::Preprocessor::clip(config.vision.image_size);
+    let preprocessor =
+        image::Preprocessor::from_hf_config(dir.join("preprocessor_config.json"), defaults.clone())
+            .unwrap_or(defaults);
+    let weights = dir.join("model.safetensors.index.json");
+
+    // The vision tower and the decoder are separate graphs. The decoder runs once per token, while the vision tower
+    // only runs once per prompt, and its output is moved into the decoder's image features input.
+    print!("Defining graphs");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+
+    let mut vision_cx = Graph::new();
+    config.vision.set_dims(&mut vision_cx);
+    let mut images = vision_cx.named_tensor::<(
+        Dyn<'b'>,
+        vision::Channels,
+        vision::ImageSize,
+        vision::ImageSize,
+    )>("Images");
+    let vision_tower = vision::VisionTower::new(&config.vision, &mut vision_cx);
+    let mut vision_weights = params(&vision_tower);
+    vision_cx.keep_tensors(&vision_weights);
+    let mut features = vision_tower.forward(images).retrieve();
+    safetensors::load(&weights, &vision_tower, &mut vision_cx).unwrap();
+
+    let mut cx = Graph::new();
+    config.text.set_dims(&mut cx);
+    let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+    let mut image_features =
+        cx.named_tensor::<(language::ImageRows, language::Hidden)>("Image Features");
+    cx.keep_tensors(image_features);
+    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.text.n_layers)
+        .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
+        .collect();
+    cache_src.set_dyn(
+        vec![],
+        &[1, config.text.n_kv_heads, 0, config.text.head_dim()],
+    );
+    let model = language::LanguageModel::new(&config.text, config.image_token, &mut cx);
+    let mut model_weights = params(&model);
+    cx.keep_tensors(&model_weights);
+    let mask = AttentionMask::<_, _, Dyn<'t'>>::causal(&mut cx);
+    let (logits, mut cache_dest) = model.forward((input, image_features, &cache_src, mask));
+    let mut logits = logits
+        .slice((.., (Expression::from('s') - 1).., ..))
+        .retrieve();
+    cache_dest.keep();
+    safetensors::load(&weights, &model, &mut cx).unwrap();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graphs");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    vision_cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (&mut images, &mut features, &mut vision_weights),
+    );
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (
+            &mut input,
+            &mut image_features,
+            &mut logits,
+            &mut cache_src,
+            &mut cache_dest,
+            &mut model_weights,
+        ),
+    );
+    cx.compile(MemoryPlanner, ());
+    let cache_src = downstream(&cache_src, &cx);
+    let cache_dest = cache_dest.to_ids();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Encode the images, and hand their features to the decoder
+    print!("Encoding images");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    let data = cli_args
+        .images
+        .iter()
+        .flat_map(|path| {
+            let image =
+                image::read_pnm(path).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+            preprocessor.process(&image)
+        })
+        .collect::<Vec<_>>();
+    let size = config.vision.image_size;
+    images.set_dyn(
+        data,
+        &[cli_args.images.len(), config.vision.n_channels, size, size],
+    );
+    vision_cx.execute();
+    transfer_data(features, &mut vision_cx, image_features, &mut cx);
+    cx.set_dyn_dim(
+        'i',
+        cli_args.images.len() * config.vision.tokens_per_image(),
+    );
+    // The vision tower isn't needed anymore, so free its weights
+    drop(vision_cx);
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Initial forward pass to load weights
+    print!("Loading model");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    input.set_dyn(vec![config.text.bos_token as f32], &[1, 1]);
+    cx.set_dyn_dim('t', 1);
+    cx.execute();
+    logits.drop();
+    cx.drop_tensors(&cache_dest);
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Now that weights are loaded, delete the loading nodes so they don't run again
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
+
+    // LLaVA 1.5's prompt format, with an image token for each image. Each is expanded to one token per feature row.
+    let prompt = format!(
+        "USER: {}\n{} ASSISTANT:",
+        "<image>".repeat(cli_args.images.len()),
+        cli_args.prompt
+    );
+    let input_ids = tokenizer
+        .encode(&prompt)
+        .unwrap()
+        .into_iter()
+        .flat_map(|id| {
+            let copies = if id == config.image_token {
+                config.vision.tokens_per_image()
+            } else {
+                1
+            };
+            std::iter::repeat_n(id, copies)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        input_ids
+            .iter()
+            .filter(|id| **id == config.image_token)
+            .count(),
+        cli_args.images.len() * config.vision.tokens_per_image(),
+        "The tokenizer should encode <image> as the image token"
+    );
+
+    // Generate, streaming the decoded text as tokens come in
+    let mut output_stream = tokenizer.decode_stream(true);
+    print!("{}", prompt.white().bold());
+    io::stdout().flush().unwrap();
+    let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+    let mut greedy = Sample::new(0., 0, 1., 0);
+    let start = Instant::now();
+    let mut start_decode = None;
+    let output_ids = Generator::new(cli_args.gen_tokens as usize + 1)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied())
+        .generate(&mut model, &mut greedy, &input_ids, |token| {
+            start_decode.get_or_insert_with(Instant::now);
+            if let Some(text) = output_stream.push(token).unwrap() {
+                print!("{}", text.bright_green());
+                io::stdout().flush().unwrap();
+            }
+            ControlFlow::Continue(())
+        });
+    println!();
+
+    let start_decode = start_decode.unwrap();
+    let prompt_ms = (start_decode - start).as_millis();
+    println!(
+        "\nProcessed prompt in {prompt_ms}ms ({:.2} tok/s, {} prompt tokens)",
+        1000.0 * (input_ids.len() as f64) / (prompt_ms as f64),
+        input_ids.len()
+    );
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "Average token generated in {:.2}ms\t - ({:.2} tok/s)",
+        avg_token_time,
+        1000.0 / avg_token_time
+    );
+}
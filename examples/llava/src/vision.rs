@@ -0,0 +1,258 @@
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynLayerNorm, DynPermutedLinear};
+
+use crate::config::VisionConfig;
+
+// The vision tower's dimensions are dynamic, with sizes from a `VisionConfig` set on its graph with
+// `VisionConfig::set_dims`. It has its own graph, so these don't clash with the decoder's dimensions.
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type HeadDim = Dyn<'e'>;
+pub type Channels = Dyn<'c'>;
+/// The width and height of input images
+pub type ImageSize = Dyn<'i'>;
+/// The width and height of each patch
+pub type PatchSize = Dyn<'q'>;
+/// The number of patches along each side of the image
+pub type Grid = Dyn<'g'>;
+pub type Patches = Dyn<'p'>;
+/// The number of values in a patch, which is channels * patch size * patch size
+pub type PatchDim = Dyn<'k'>;
+/// The patches and the class token
+pub type Positions = Dyn<'n'>;
+/// The hidden dim of the language model
+pub type TextHidden = Dyn<'o'>;
+/// The number of features each image is projected to
+pub type ImageTokens = Dyn<'m'>;
+
+/// Split images into a row-major sequence of flattened patches, each laid out (channel, y, x) like the patch
+/// embedding's weights
+pub fn patchify<Batch: Dimension>(
+    images: GraphTensor<(Batch, Channels, ImageSize, ImageSize)>,
+) -> GraphTensor<(Batch, Patches, PatchDim)> {
+    let dim = |axis: usize| images.shape.dims[images.shape.indexes[axis]];
+    let (batch, channels) = (dim(0), dim(1));
+    let (grid, patch) = (Grid::const_size(), PatchSize::const_size());
+    // Images are (batch, channel, grid y, patch y, grid x, patch x). Shapes have at most 5 permutable dims, so first
+    // gather the patches of each channel...
+    images
+        .dyn_reshape::<(Dyn<'-'>, Grid, PatchSize, Grid, PatchSize)>(vec![
+            batch * channels,
+            grid,
+            patch,
+            grid,
+            patch,
+        ])
+        .permute::<_, Axes5<0, 1, 3, 2, 4>>()
+        // ...then move the channels inside each patch
+        .dyn_reshape::<(Batch, Channels, Patches, Dyn<'-'>)>(vec![
+            batch,
+            channels,
+            grid * grid,
+            patch * patch,
+        ])
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        .reshape::<(Batch, Patches, PatchDim)>()
+}
+
+pub struct SelfAttention {
+    pub q_proj: DynPermutedLinear<Hidden, Hidden>,
+    pub k_proj: DynPermutedLinear<Hidden, Hidden>,
+    pub v_proj: DynPermutedLinear<Hidden, Hidden>,
+    pub out_proj: DynPermutedLinear<Hidden, Hidden>,
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Positions, Hidden)>> for SelfAttention {
+    type Output = GraphTensor<(Batch, Positions, Hidden)>;
+
+    fn forward(&self, x: GraphTensor<(Batch, Positions, Hidden)>) -> Self::Output {
+        let heads = |proj: &DynPermutedLinear<Hidden, Hidden>| {
+            proj.forward(x)
+                .reshape::<(Batch, Positions, Heads, HeadDim)>()
+                .permute::<_, Axes4<0, 2, 1, 3>>()
+        };
+        let output = grouped_query_attention(
+            heads(&self.q_proj),
+            heads(&self.k_proj),
+            heads(&self.v_proj),
+            AttentionMask::none(),
+        )
+        // Merge heads
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        .reshape::<(Batch, Positions, Hidden)>();
+        self.out_proj.forward(output)
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("q_proj", &self.q_proj);
+        s.module("k_proj", &self.k_proj);
+        s.module("v_proj", &self.v_proj);
+        s.module("out_proj", &self.out_proj);
+    }
+}
+
+pub struct EncoderLayer {
+    pub attention: SelfAttention,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub fc1: DynPermutedLinear<Hidden, MlpDim>,
+    pub fc2: DynPermutedLinear<MlpDim, Hidden>,
+    pub mlp_norm: DynLayerNorm<Hidden>,
+}
+
+impl EncoderLayer {
+    pub fn new(config: &VisionConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention {
+                q_proj: DynPermutedLinear::new(true, cx),
+                k_proj: DynPermutedLinear::new(true, cx),
+                v_proj: DynPermutedLinear::new(true, cx),
+                out_proj: DynPermutedLinear::new(true, cx),
+            },
+            attention_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            fc1: DynPermutedLinear::new(true, cx),
+            fc2: DynPermutedLinear::new(true, cx),
+            mlp_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Positions, Hidden)>> for EncoderLayer {
+    type Output = GraphTensor<(Batch, Positions, Hidden)>;
+
+    fn forward(&self, x: GraphTensor<(Batch, Positions, Hidden)>) -> Self::Output {
+        let x = x + self.attention.forward(self.attention_norm.forward(x));
+        // CLIP's MLP uses the quick GELU approximation, x * sigmoid(1.702 * x)
+        let hidden = self.fc1.forward(self.mlp_norm.forward(x));
+        x + self.fc2.forward(hidden * (hidden * 1.702).sigmoid())
+    }
+}
+
+impl SerializeModule for EncoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("layer_norm1", &self.attention_norm);
+        s.module("mlp/fc1", &self.fc1);
+        s.module("mlp/fc2", &self.fc2);
+        s.module("layer_norm2", &self.mlp_norm);
+    }
+}
+
+/// Maps vision features to token embeddings with a two layer MLP
+pub struct Projector {
+    pub linear_1: DynPermutedLinear<Hidden, TextHidden>,
+    pub linear_2: DynPermutedLinear<TextHidden, TextHidden>,
+}
+
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Projector {
+    type Output = GraphTensor<(Batch, Seq, TextHidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        self.linear_2.forward(self.linear_1.forward(input).gelu())
+    }
+}
+
+impl SerializeModule for Projector {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("linear_1", &self.linear_1);
+        s.module("linear_2", &self.linear_2);
+    }
+}
+
+/// The CLIP vision tower and the projector, turning images into the features spliced into the prompt in place of
+/// image tokens
+pub struct VisionTower {
+    pub patch_embedding: GraphTensor<(Hidden, Channels, PatchSize, PatchSize)>,
+    /// Prepended to the patches
+    pub class_embedding: GraphTensor<(Hidden,)>,
+    /// Learned position embeddings for the class token and each patch
+    pub position_embedding: GraphTensor<(Positions, Hidden)>,
+    pub pre_norm: DynLayerNorm<Hidden>,
+    /// The layers up to the one features are read from
+    pub layers: Vec<EncoderLayer>,
+    pub projector: Projector,
+    pub keep_class_token: bool,
+}
+
+impl VisionTower {
+    /// Build the tower for a config. The config's dims must also be set on the graph with `VisionConfig::set_dims`.
+    pub fn new(config: &VisionConfig, cx: &mut Graph) -> Self {
+        Self {
+            patch_embedding: cx.named_tensor("Patch Embedding"),
+            class_embedding: cx.named_tensor("Class Embedding"),
+            position_embedding: cx.named_tensor("Position Embedding"),
+            pre_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            layers: (0..config.n_layers)
+                .map(|_| EncoderLayer::new(config, cx))
+                .collect(),
+            projector: Projector {
+                linear_1: DynPermutedLinear::new(true, cx),
+                linear_2: DynPermutedLinear::new(true, cx),
+            },
+            keep_class_token: config.keep_class_token,
+        }
+    }
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Channels, ImageSize, ImageSize)>>
+    for VisionTower
+{
+    /// The token embeddings of each image
+    type Output = GraphTensor<(Batch, ImageTokens, TextHidden)>;
+
+    fn forward(
+        &self,
+        images: GraphTensor<(Batch, Channels, ImageSize, ImageSize)>,
+    ) -> Self::Output {
+        // Embed the patches, put the class token in front of them, and add positions. The patch embedding is a
+        // convolution with a stride of its kernel size, and no bias.
+        let patches = patchify(images).matmul(
+            self.patch_embedding
+                .reshape::<(Hidden, PatchDim)>()
+                .permute(),
+        );
+        let mut x = self.pre_norm.forward(
+            self.class_embedding
+                .expand::<(Batch, Const<1>, Hidden), _>()
+                .concat_along::<(Batch, Positions, Hidden), Axis<1>, _>(patches)
+                + self.position_embedding.expand(),
+        );
+        for layer in &self.layers {
+            x = layer.forward(x);
+        }
+        let features = if self.keep_class_token {
+            x.realize::<(Batch, ImageTokens, Hidden)>()
+        } else {
+            x.slice((.., Expression::from(1).., ..))
+                .realize::<(Batch, ImageTokens, Hidden)>()
+        };
+        self.projector.forward(features)
+    }
+}
+
+impl SerializeModule for VisionTower {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor(
+            "vision_tower/vision_model/embeddings/patch_embedding/weight",
+            self.patch_embedding,
+        );
+        s.tensor(
+            "vision_tower/vision_model/embeddings/class_embedding",
+            self.class_embedding,
+        );
+        s.tensor(
+            "vision_tower/vision_model/embeddings/position_embedding/weight",
+            self.position_embedding,
+        );
+        s.module("vision_tower/vision_model/pre_layrnorm", &self.pre_norm);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(
+                &format!("vision_tower/vision_model/encoder/layers/{i}"),
+                layer,
+            );
+        }
+        s.module("multi_modal_projector", &self.projector);
+    }
+}
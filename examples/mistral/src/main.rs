@@ -0,0 +1,161 @@
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+    time::Instant,
+};
+
+use clap::Parser;
+use colored::Colorize;
+
+mod model;
+
+use crate::model::KVCache;
+use luminal::{prelude::*, serialization::gguf, tokenizer::Tokenizer};
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Number of tokens to generate
+    #[clap(short = 't', long = "gen_tokens", default_value = "128")]
+    gen_tokens: i32,
+
+    /// Prompt for the model
+    #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/merge_sort.txt"))]
+    prompt: String,
+
+    /// Path to the GGUF weights of a Mistral or Mixtral model
+    #[clap(short = 'm', long = "model", default_value = "setup/mistral-7b.gguf")]
+    model: String,
+
+    /// Path to a HuggingFace config.json to read the model's hyperparameters from, instead of the GGUF metadata
+    #[clap(short = 'c', long = "config")]
+    config: Option<String>,
+
+    /// Limit attention to this many tokens back, overriding the config's sliding window
+    #[clap(long = "sliding_window")]
+    sliding_window: Option<usize>,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+
+    // Read the model's hyperparameters
+    let gguf_content = gguf::Content::from_file(&cli_args.model).unwrap();
+    let mut config = match &cli_args.config {
+        Some(path) => model::MistralConfig::from_hf_config(path),
+        None => model::MistralConfig::from_gguf(&gguf_content.hyperparameters().unwrap()),
+    }
+    .unwrap();
+    if cli_args.sliding_window.is_some() {
+        config.sliding_window = cli_args.sliding_window;
+    }
+    // Newer GGUF conversions store each layer's experts stacked in a single tensor
+    if gguf_content
+        .tensor_infos
+        .contains_key("blk.0.ffn_gate_exps.weight")
+    {
+        panic!("Stacked expert weights aren't supported, use a GGUF with per-expert tensors (blk.N.ffn_gate.E.weight)");
+    }
+    let tokenizer = Tokenizer::from_file("setup/tokenizer.json")
+        .unwrap()
+        .with_gguf_config(&gguf_content);
+
+    print!("Defining graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+
+    // Set up graph
+    let mut cx = Graph::new();
+    config.set_dims(&mut cx);
+    let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.n_layers)
+        .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
+        .collect();
+    cache_src.set_dyn(vec![], &[1, config.n_kv_heads, 0, config.head_dim()]);
+    let model = model::MistralLM::new(&config, &mut cx);
+    let mut model_weights = params(&model);
+    cx.keep_tensors(&model_weights);
+    let mask = config.attention_mask::<_, _, Dyn<'t'>>(&mut cx);
+    let (logits, mut cache_dest) = model.forward((input, &cache_src, mask));
+    let mut logits = logits
+        .slice((.., (Expression::from('s') - 1).., ..))
+        .retrieve();
+    cache_dest.keep();
+
+    // Set up model loading
+    gguf::load(&cli_args.model, &model, &mut cx).unwrap();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (
+            &mut input,
+            &mut logits,
+            &mut cache_src,
+            &mut cache_dest,
+            &mut model_weights,
+        ),
+    );
+    cx.compile(PrepackWeights::new(&model_weights), ());
+    cx.compile(MemoryPlanner, ());
+    let cache_src = downstream(&cache_src, &cx);
+    let cache_dest = cache_dest.to_ids();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Initial forward pass to load weights
+    print!("Loading model");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    input.set_dyn(vec![1.], &[1, 1]);
+    cx.set_dyn_dim('t', 1);
+    cx.execute();
+    logits.drop();
+    cx.drop_tensors(&cache_dest);
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Now that weights are loaded, delete the loading nodes so they don't run again
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
+
+    // Generate, streaming the decoded text as tokens come in
+    let input_ids = tokenizer.encode(&cli_args.prompt).unwrap();
+    let mut output_stream = tokenizer.decode_stream(true);
+    print!("{}", cli_args.prompt.white().bold());
+    io::stdout().flush().unwrap();
+    let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+    let mut greedy = Sample::new(0., 0, 1., 0);
+    let start = Instant::now();
+    let mut start_decode = None;
+    let output_ids = Generator::new(cli_args.gen_tokens as usize + 1)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied())
+        .generate(&mut model, &mut greedy, &input_ids, |token| {
+            start_decode.get_or_insert_with(Instant::now);
+            if let Some(text) = output_stream.push(token).unwrap() {
+                print!("{}", text.bright_green());
+                io::stdout().flush().unwrap();
+            }
+            ControlFlow::Continue(())
+        });
+    println!();
+
+    let start_decode = start_decode.unwrap();
+    let prompt_ms = (start_decode - start).as_millis();
+    println!(
+        "\nProcessed prompt in {prompt_ms}ms ({:.2} tok/s, {} prompt tokens)",
+        1000.0 * (input_ids.len() as f64) / (prompt_ms as f64),
+        input_ids.len()
+    );
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "Average token generated in {:.2}ms\t - ({:.2} tok/s)",
+        avg_token_time,
+        1000.0 / avg_token_time
+    );
+}
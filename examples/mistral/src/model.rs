@@ -0,0 +1,449 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::{prelude::*, serialization::gguf::Hyperparameters};
+use luminal_nn::{
+    grouped_query_attention, AttentionMask, DynRMSNorm, MixtureOfExperts, RopeScaling,
+};
+
+// Model dimensions are dynamic so the same graph code works for Mistral and Mixtral. Their sizes come from a
+// `MistralConfig`, and are set on the graph with `MistralConfig::set_dims` before it's compiled.
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type KVHeads = Dyn<'k'>;
+pub type HeadDim = Dyn<'e'>;
+pub type HeadDimOver2 = Dyn<'r'>;
+pub type AttnProjDim = Dyn<'a'>;
+pub type Experts = Dyn<'x'>;
+
+/// The number of experts each token is routed to in Mixtral
+pub const TOP_K: usize = 2;
+
+pub type KVCache<Batch, Seq> = (
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+);
+
+/// The hyperparameters of a Mistral or Mixtral model
+#[derive(Debug, Clone, PartialEq)]
+pub struct MistralConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub mlp_dim: usize,
+    pub rope_theta: f32,
+    pub rms_norm_epsilon: f32,
+    /// How many tokens back each token can attend to, or None to attend to the whole context
+    pub sliding_window: Option<usize>,
+    /// The number of experts in each feed-forward layer for Mixtral, or None for Mistral's dense feed-forwards
+    pub n_experts: Option<usize>,
+}
+
+impl MistralConfig {
+    /// Read the config from a GGUF file's metadata. GGUF files don't record the sliding window.
+    pub fn from_gguf(hparams: &Hyperparameters) -> Result<Self> {
+        Self {
+            vocab_size: hparams.vocab_size,
+            hidden_dim: hparams.hidden_dim,
+            n_layers: hparams.n_layers,
+            n_heads: hparams.n_heads,
+            n_kv_heads: hparams.n_kv_heads,
+            mlp_dim: hparams.mlp_dim,
+            rope_theta: hparams.rope_theta,
+            rms_norm_epsilon: hparams.rms_norm_epsilon.unwrap_or(1e-5),
+            sliding_window: None,
+            n_experts: hparams.expert_count.filter(|n| *n > 0),
+        }
+        .validated(hparams.expert_used_count)
+    }
+
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing {key}")))
+        };
+        let get_f32 = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_f64)
+                .map(|v| v as f32)
+        };
+        let n_heads = get_usize("num_attention_heads")?;
+        Self {
+            vocab_size: get_usize("vocab_size")?,
+            hidden_dim: get_usize("hidden_size")?,
+            n_layers: get_usize("num_hidden_layers")?,
+            n_heads,
+            n_kv_heads: get_usize("num_key_value_heads").unwrap_or(n_heads),
+            mlp_dim: get_usize("intermediate_size")?,
+            rope_theta: get_f32("rope_theta").unwrap_or(10000.),
+            rms_norm_epsilon: get_f32("rms_norm_eps").unwrap_or(1e-5),
+            sliding_window: get_usize("sliding_window").ok(),
+            n_experts: get_usize("num_local_experts").ok(),
+        }
+        .validated(get_usize("num_experts_per_tok").ok())
+    }
+
+    fn validated(self, experts_per_token: Option<usize>) -> Result<Self> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+        if self.n_heads == 0 || !self.hidden_dim.is_multiple_of(self.n_heads) {
+            return invalid("Hidden dim must be divisible by the number of heads".to_string());
+        }
+        if self.n_kv_heads == 0 || !self.n_heads.is_multiple_of(self.n_kv_heads) {
+            return invalid(
+                "Number of heads must be divisible by the number of KV heads".to_string(),
+            );
+        }
+        if let Some(n_experts) = self.n_experts {
+            if n_experts < TOP_K || experts_per_token.is_some_and(|k| k != TOP_K) {
+                return invalid(format!(
+                    "Only routing to {TOP_K} experts is supported, but the model routes to {} of {n_experts}",
+                    experts_per_token.unwrap_or(TOP_K)
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 9] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('k', self.n_kv_heads),
+            ('e', self.head_dim()),
+            ('r', self.head_dim() / 2),
+            ('a', self.head_dim() * self.n_kv_heads),
+            ('x', self.n_experts.unwrap_or_default()),
+        ]
+    }
+
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+
+    /// The attention mask for new tokens attending to themselves and the cached tokens before them
+    pub fn attention_mask<Batch: Dimension, CurSeq: Dimension, TotSeq: Dimension>(
+        &self,
+        cx: &mut Graph,
+    ) -> AttentionMask<Batch, CurSeq, TotSeq> {
+        match self.sliding_window {
+            Some(window) => AttentionMask::sliding_window(cx, window),
+            None => AttentionMask::causal(cx),
+        }
+    }
+}
+
+pub struct Mlp {
+    pub gate_proj: GraphTensor<(MlpDim, Hidden)>,
+    pub down_proj: GraphTensor<(Hidden, MlpDim)>,
+    pub up_proj: GraphTensor<(MlpDim, Hidden)>,
+}
+
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Mlp {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        let gate = input.matmul(self.gate_proj.permute()).swish();
+        let up = input.matmul(self.up_proj.permute()) * gate;
+        up.matmul(self.down_proj.permute())
+    }
+}
+
+impl InitModule for Mlp {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            gate_proj: cx.named_tensor("Gate"),
+            up_proj: cx.named_tensor("Up"),
+            down_proj: cx.named_tensor("Down"),
+        }
+    }
+}
+
+impl SerializeModule for Mlp {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("ffn_gate/weight", self.gate_proj);
+        s.tensor("ffn_up/weight", self.up_proj);
+        s.tensor("ffn_down/weight", self.down_proj);
+    }
+}
+
+/// Mistral's dense feed-forward, or Mixtral's mixture of experts
+pub enum FeedForward {
+    Dense(Box<Mlp>),
+    Sparse(Box<MixtureOfExperts<Mlp, Hidden, Experts, TOP_K>>),
+}
+
+impl FeedForward {
+    pub fn new(config: &MistralConfig, cx: &mut Graph) -> Self {
+        match config.n_experts {
+            Some(n) => Self::Sparse(Box::new(MixtureOfExperts::new(
+                cx.named_tensor("Router"),
+                (0..n).map(|_| InitModule::initialize(cx)).collect(),
+            ))),
+            None => Self::Dense(Box::new(InitModule::initialize(cx))),
+        }
+    }
+}
+
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for FeedForward {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        match self {
+            Self::Dense(mlp) => mlp.forward(input),
+            Self::Sparse(moe) => moe.forward(input),
+        }
+    }
+}
+
+impl SerializeModule for FeedForward {
+    fn serialize(&self, s: &mut Serializer) {
+        match self {
+            Self::Dense(mlp) => s.module("", mlp.as_ref()),
+            // Experts are stored per expert, like blk.0.ffn_gate.3.weight
+            Self::Sparse(moe) => {
+                s.tensor("ffn_gate_inp/weight", moe.router);
+                for (i, expert) in moe.experts.iter().enumerate() {
+                    s.tensor(&format!("ffn_gate/{i}/weight"), expert.gate_proj);
+                    s.tensor(&format!("ffn_up/{i}/weight"), expert.up_proj);
+                    s.tensor(&format!("ffn_down/{i}/weight"), expert.down_proj);
+                }
+            }
+        }
+    }
+}
+
+pub struct SelfAttention {
+    pub q_proj: GraphTensor<(Hidden, Hidden)>,
+    pub k_proj: GraphTensor<(AttnProjDim, Hidden)>,
+    pub v_proj: GraphTensor<(AttnProjDim, Hidden)>,
+    pub o_proj: GraphTensor<(Hidden, Hidden)>,
+    /// The rotary frequency of each pair of dimensions in a head
+    pub rope_freqs: GraphTensor<(HeadDimOver2,)>,
+}
+
+impl SelfAttention {
+    pub fn new(config: &MistralConfig, cx: &mut Graph) -> Self {
+        let head_dim = config.head_dim();
+        Self {
+            q_proj: cx.named_tensor("Q Proj"),
+            k_proj: cx.named_tensor("K Proj"),
+            v_proj: cx.named_tensor("V Proj"),
+            o_proj: cx.named_tensor("O Proj"),
+            rope_freqs: cx.named_tensor("RoPE Frequencies").set_dyn(
+                RopeScaling::None.inverse_frequencies(config.rope_theta, head_dim),
+                &[head_dim / 2],
+            ),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for SelfAttention
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (x, (k_cache, v_cache), mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Apply the Projections
+        let queries = x
+            .matmul(self.q_proj.permute())
+            .reshape::<(Batch, CurSeq, Heads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        let keys = x
+            .matmul(self.k_proj.permute())
+            .reshape::<(Batch, CurSeq, KVHeads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        let values = x
+            .matmul(self.v_proj.permute())
+            .reshape::<(Batch, CurSeq, KVHeads, HeadDim)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        // Rotary embed queries and keys
+        let queries = queries.rope(self.rope_freqs, PrevSeq::const_size());
+        let keys = keys.rope(self.rope_freqs, PrevSeq::const_size());
+
+        // Add KV cache
+        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
+        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
+
+        // Attend with each KV head shared by a group of query heads. The sliding window is part of the mask.
+        let output = grouped_query_attention(queries, keys, values, mask)
+            // Merge heads
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(Batch, CurSeq, Hidden)>();
+        let output = output
+            // Apply output projection
+            .matmul(self.o_proj.permute());
+        (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("attn_q/weight", self.q_proj);
+        s.tensor("attn_v/weight", self.v_proj);
+        s.tensor("attn_k/weight", self.k_proj);
+        s.tensor("attn_output/weight", self.o_proj);
+    }
+}
+
+pub struct TransformerBlock {
+    pub attention: SelfAttention,
+    pub attention_norm: DynRMSNorm<Hidden>,
+    pub feed_forward: FeedForward,
+    pub feed_forward_norm: DynRMSNorm<Hidden>,
+}
+
+impl TransformerBlock {
+    pub fn new(config: &MistralConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention::new(config, cx),
+            attention_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            feed_forward: FeedForward::new(config, cx),
+            feed_forward_norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for TransformerBlock
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+    fn forward(
+        &self,
+        (mut x, cache, mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Attention
+        let normed = self.attention_norm.forward(x);
+        let (y, cache) = self.attention.forward((normed, cache, mask));
+
+        // Residual Addition
+        x += y;
+
+        // Feed Forward
+        let y = self.feed_forward.forward(self.feed_forward_norm.forward(x));
+
+        // Residual Addition
+        (x + y, cache)
+    }
+}
+
+impl SerializeModule for TransformerBlock {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("", &self.attention);
+        s.module("attn_norm", &self.attention_norm);
+        s.module("ffn_norm", &self.feed_forward_norm);
+        s.module("", &self.feed_forward);
+    }
+}
+
+pub struct MistralLM {
+    // Token embeddings
+    pub embedding: GraphTensor<(Vocab, Hidden)>,
+    // Transformer layers
+    pub layers: Vec<TransformerBlock>,
+    // Final Norm layer
+    pub norm: DynRMSNorm<Hidden>,
+    // LM Head Layer
+    pub lm_head: GraphTensor<(Vocab, Hidden)>,
+}
+
+impl MistralLM {
+    /// Build the model for a config. The config's dims must also be set on the graph with `MistralConfig::set_dims`.
+    pub fn new(config: &MistralConfig, cx: &mut Graph) -> Self {
+        Self {
+            embedding: cx.named_tensor("Embedding Weight"),
+            norm: DynRMSNorm::new(config.rms_norm_epsilon, cx),
+            lm_head: cx.named_tensor("LM Head"),
+            layers: (0..config.n_layers)
+                .map(|_| TransformerBlock::new(config, cx))
+                .collect(),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq)>,
+        &[KVCache<Batch, PrevSeq>],
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for MistralLM
+{
+    type Output = (
+        GraphTensor<(Batch, CurSeq, Vocab)>,
+        Vec<KVCache<Batch, TotSeq>>,
+    );
+    fn forward(
+        &self,
+        (input, cache, mask): (
+            GraphTensor<(Batch, CurSeq)>,
+            &[KVCache<Batch, PrevSeq>],
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Embed tokens
+        let mut x = self.embedding.index_select::<_, Axis<0>, _>(input);
+
+        // Run through layers and collect new caches
+        let mut new_caches = vec![];
+        let mut new_cache;
+        for (i, layer) in self.layers.iter().enumerate() {
+            (x, new_cache) = layer.forward((x, cache[i], mask));
+            new_caches.push(new_cache);
+        }
+        // Run through last norm and output projection
+        let output = self.norm.forward(x).matmul(self.lm_head.permute());
+
+        (output, new_caches)
+    }
+}
+
+impl SerializeModule for MistralLM {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("token_embd/weight", self.embedding);
+        s.module("output_norm", &self.norm);
+        s.tensor("output/weight", self.lm_head);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("blk/{i}"), layer);
+        }
+    }
+}
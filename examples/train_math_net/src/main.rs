@@ -1,6 +1,6 @@
 use luminal::prelude::*;
 use luminal_nn::{Linear, Swish};
-use luminal_training::{mse_loss, sgd_on_graph, Autograd};
+use luminal_training::{mse_loss, Backward, Mean, Optimizer, Sgd};
 use rand::{rngs::ThreadRng, thread_rng, Rng};
 
 // This is a simple example of using luminal to train.
@@ -14,12 +14,12 @@ fn main() {
     let mut input = cx.tensor::<R1<8>>();
     let mut target = cx.tensor::<R1<5>>();
     let mut output = model.forward(input).retrieve();
-    let mut loss = mse_loss(output, target).retrieve();
+    let mut loss = mse_loss(output, target, Mean).retrieve();
 
     let mut weights = params(&model);
-    let grads = cx.compile(Autograd::new(&weights, loss), ());
-    let (mut new_weights, lr) = sgd_on_graph(&mut cx, &weights, &grads);
-    lr.set(1e-1);
+    cx.mark_trainable(&weights);
+    let grads = cx.backward(loss);
+    let mut update = Sgd::new(1e-1).update(&mut cx, &weights, grads.grads());
 
     cx.compile(
         GenericCompiler::default(),
@@ -29,7 +29,7 @@ fn main() {
             &mut loss,
             &mut output,
             &mut weights,
-            &mut new_weights,
+            &mut update,
         ),
     );
 
@@ -42,7 +42,7 @@ fn main() {
             &mut loss,
             &mut output,
             &mut weights,
-            &mut new_weights,
+            &mut update,
         ),
     );
 
@@ -58,7 +58,7 @@ fn main() {
 
         // Execute graph and update weights
         cx.execute();
-        transfer_data_same_graph(&new_weights, &weights, &mut cx);
+        update.step(&weights, &mut cx);
 
         // Report progress
         loss_avg.update(loss.data()[0]);
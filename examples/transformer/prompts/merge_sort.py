@@ -0,0 +1,2 @@
+def merge_sort(items):
+    """Sort a list of items with merge sort."""
@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+# Usage: setup.sh phi-2 | gemma-2b | llama-2-7b
+# Gemma and llama's weights are gated, so accept their licenses on HuggingFace and export HF_TOKEN first
+case "$1" in
+    phi-2) REPO=microsoft/phi-2 ;;
+    gemma-2b) REPO=google/gemma-2b ;;
+    llama-2-7b) REPO=meta-llama/Llama-2-7b-hf ;;
+    *) echo "Usage: $0 phi-2 | gemma-2b | llama-2-7b"; exit 1 ;;
+esac
+DIR=$SCRIPT_DIR/$1
+mkdir -p $DIR
+download() {
+    curl --location --header "Authorization: Bearer $HF_TOKEN" https://huggingface.co/$REPO/resolve/main/$1?download=true --output $DIR/$1
+}
+
+echo "Downloading Model and Tokenizer..."
+download config.json
+download tokenizer.json
+download model.safetensors.index.json
+for shard in $(grep -o 'model-[0-9]*-of-[0-9]*\.safetensors' $DIR/model.safetensors.index.json | sort -u); do
+    download $shard
+done
+echo "Done!"
@@ -0,0 +1,5 @@
+use transformer::config::Architecture;
+
+fn main() {
+    transformer::run(Architecture::Gemma, "setup/gemma-2b");
+}
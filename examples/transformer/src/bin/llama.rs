@@ -0,0 +1,5 @@
+use transformer::config::Architecture;
+
+fn main() {
+    transformer::run(Architecture::Llama, "setup/llama-2-7b");
+}
@@ -0,0 +1,5 @@
+use transformer::config::Architecture;
+
+fn main() {
+    transformer::run(Architecture::Phi2, "setup/phi-2");
+}
@@ -0,0 +1,225 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+pub use luminal_nn::MlpKind;
+
+/// The model families this core can be configured as. Beyond choosing the options in `TransformerConfig`, it only
+/// decides how weights are named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Phi2,
+    Gemma,
+    Llama,
+}
+
+impl Architecture {
+    /// The attention output projection
+    pub fn output_proj_name(self) -> &'static str {
+        match self {
+            Architecture::Phi2 => "dense",
+            Architecture::Gemma | Architecture::Llama => "o_proj",
+        }
+    }
+
+    /// The norm after the last layer
+    pub fn final_norm_name(self) -> &'static str {
+        match self {
+            Architecture::Phi2 => "final_layernorm",
+            Architecture::Gemma | Architecture::Llama => "norm",
+        }
+    }
+}
+
+/// How the residual stream is normalized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormKind {
+    /// Layer norm with a scale and shift, like Phi-2
+    LayerNorm,
+    /// RMS norm, scaled by the weight plus `offset`. Gemma stores its weights relative to 1, so it uses an offset of 1.
+    RmsNorm { offset: f32 },
+}
+
+/// The hyperparameters of a decoder-only transformer, read at runtime so one set of modules covers every model size
+/// and family. Sizes are set on the graph as dynamic dimensions with `TransformerConfig::set_dims`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformerConfig {
+    pub architecture: Architecture,
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    /// The size of each head, which doesn't have to be the hidden dim over the number of heads
+    pub head_dim: usize,
+    pub mlp_dim: usize,
+    /// How many dims of each query and key head get rotary embeddings. The rest pass through unrotated.
+    pub rotary_dim: usize,
+    pub rope_theta: f32,
+    pub norm: NormKind,
+    pub norm_epsilon: f32,
+    pub mlp: MlpKind,
+    /// Attention and the MLP read the same normed input and are added to the residual stream together, rather than
+    /// one after the other
+    pub parallel_residual: bool,
+    /// Whether the attention, MLP and LM head projections have biases
+    pub bias: bool,
+    /// Whether token embeddings are scaled by the square root of the hidden dim
+    pub scale_embeddings: bool,
+    /// Whether the LM head reuses the token embeddings
+    pub tie_embeddings: bool,
+    /// The token prompts should start with, if the model expects one
+    pub bos_token: Option<u32>,
+    pub eos_token: u32,
+}
+
+impl TransformerConfig {
+    /// Read the config from a HuggingFace `config.json`, picking the architecture from its `model_type`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| invalid(format!("Missing {key}")))
+        };
+        let get_f32 = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_f64)
+                .map(|v| v as f32)
+        };
+        let get_bool = |key: &str| json.get(key).and_then(serde_json::Value::as_bool);
+
+        let hidden_dim = get_usize("hidden_size")?;
+        let n_heads = get_usize("num_attention_heads")?;
+        if n_heads == 0 {
+            return Err(invalid("Models need at least one head".to_string()));
+        }
+        let head_dim = get_usize("head_dim").unwrap_or(hidden_dim / n_heads);
+        let model_type = json.get("model_type").and_then(serde_json::Value::as_str);
+        let config = match model_type {
+            Some("phi") => {
+                if get_bool("qk_layernorm") == Some(true) {
+                    return Err(invalid("Query and key norms aren't supported".to_string()));
+                }
+                Self {
+                    architecture: Architecture::Phi2,
+                    vocab_size: get_usize("vocab_size")?,
+                    hidden_dim,
+                    n_layers: get_usize("num_hidden_layers")?,
+                    n_heads,
+                    n_kv_heads: get_usize("num_key_value_heads").unwrap_or(n_heads),
+                    head_dim,
+                    mlp_dim: get_usize("intermediate_size")?,
+                    rotary_dim: (get_f32("partial_rotary_factor").unwrap_or(0.5) * head_dim as f32)
+                        as usize,
+                    rope_theta: get_f32("rope_theta").unwrap_or(10000.),
+                    norm: NormKind::LayerNorm,
+                    norm_epsilon: get_f32("layer_norm_eps").unwrap_or(1e-5),
+                    mlp: MlpKind::Gelu,
+                    parallel_residual: true,
+                    bias: true,
+                    scale_embeddings: false,
+                    tie_embeddings: get_bool("tie_word_embeddings").unwrap_or(false),
+                    // Phi-2's prompts don't start with a BOS token
+                    bos_token: None,
+                    eos_token: get_usize("eos_token_id").unwrap_or(50256) as u32,
+                }
+            }
+            Some("gemma") => Self {
+                architecture: Architecture::Gemma,
+                vocab_size: get_usize("vocab_size")?,
+                hidden_dim,
+                n_layers: get_usize("num_hidden_layers")?,
+                n_heads,
+                n_kv_heads: get_usize("num_key_value_heads").unwrap_or(n_heads),
+                head_dim,
+                mlp_dim: get_usize("intermediate_size")?,
+                rotary_dim: head_dim,
+                rope_theta: get_f32("rope_theta").unwrap_or(10000.),
+                norm: NormKind::RmsNorm { offset: 1. },
+                norm_epsilon: get_f32("rms_norm_eps").unwrap_or(1e-6),
+                mlp: MlpKind::GeGlu,
+                parallel_residual: false,
+                bias: false,
+                scale_embeddings: true,
+                tie_embeddings: get_bool("tie_word_embeddings").unwrap_or(true),
+                bos_token: Some(get_usize("bos_token_id").unwrap_or(2) as u32),
+                eos_token: get_usize("eos_token_id").unwrap_or(1) as u32,
+            },
+            Some("llama") => {
+                if get_bool("attention_bias") == Some(true) || get_bool("mlp_bias") == Some(true) {
+                    return Err(invalid(
+                        "Llama models with biases aren't supported".to_string(),
+                    ));
+                }
+                Self {
+                    architecture: Architecture::Llama,
+                    vocab_size: get_usize("vocab_size")?,
+                    hidden_dim,
+                    n_layers: get_usize("num_hidden_layers")?,
+                    n_heads,
+                    n_kv_heads: get_usize("num_key_value_heads").unwrap_or(n_heads),
+                    head_dim,
+                    mlp_dim: get_usize("intermediate_size")?,
+                    rotary_dim: head_dim,
+                    rope_theta: get_f32("rope_theta").unwrap_or(10000.),
+                    norm: NormKind::RmsNorm { offset: 0. },
+                    norm_epsilon: get_f32("rms_norm_eps").unwrap_or(1e-6),
+                    mlp: MlpKind::SwiGlu,
+                    parallel_residual: false,
+                    bias: false,
+                    scale_embeddings: false,
+                    tie_embeddings: get_bool("tie_word_embeddings").unwrap_or(false),
+                    bos_token: Some(get_usize("bos_token_id").unwrap_or(1) as u32),
+                    eos_token: get_usize("eos_token_id").unwrap_or(2) as u32,
+                }
+            }
+            _ => {
+                return Err(invalid(format!(
+                "Unsupported model type {model_type:?}, expected \"phi\", \"gemma\" or \"llama\""
+            )))
+            }
+        };
+        config.validated()
+    }
+
+    fn validated(self) -> Result<Self> {
+        let invalid = |message: &str| Err(Error::new(ErrorKind::InvalidData, message.to_string()));
+        if self.n_kv_heads == 0 || !self.n_heads.is_multiple_of(self.n_kv_heads) {
+            return invalid("Number of heads must be divisible by the number of KV heads");
+        }
+        if self.rotary_dim == 0
+            || self.rotary_dim > self.head_dim
+            || !self.rotary_dim.is_multiple_of(2)
+        {
+            return invalid("Rotary dims must be even, and at most the head dim");
+        }
+        Ok(self)
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 9] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('k', self.n_kv_heads),
+            ('e', self.head_dim),
+            ('q', self.n_heads * self.head_dim),
+            ('a', self.n_kv_heads * self.head_dim),
+            ('o', self.rotary_dim / 2),
+        ]
+    }
+
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Phi-2, Gemma and llama built from one set of transformer modules. The differences between them (partial rotary
+//! dims, the gated MLPs, RMS norm with a +1 offset, parallel residuals, biases and tied embeddings) are options on a
+//! `TransformerConfig` read at runtime, rather than separate model code or const generic sizes.
+//!
+//! With the `hf-hub` feature, `LoadedModel::from_pretrained` downloads a model from the HuggingFace Hub and builds it.
+
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+    path::Path,
+    time::Instant,
+};
+
+use clap::Parser;
+use colored::Colorize;
+
+pub mod config;
+pub mod model;
+
+use crate::{
+    config::{Architecture, TransformerConfig},
+    model::{KVCache, Vocab},
+};
+use luminal::{prelude::*, serialization::safetensors, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Number of tokens to generate
+    #[clap(short = 't', long = "gen_tokens", default_value = "128")]
+    gen_tokens: i32,
+
+    /// Prompt for the model
+    #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/merge_sort.py"))]
+    prompt: String,
+
+    /// Directory holding the HuggingFace config.json, tokenizer.json and weights, either model.safetensors or a
+    /// sharded model.safetensors.index.json. Defaults to the directory the setup script downloads the model to.
+    #[clap(short = 'm', long = "model")]
+    model: Option<String>,
+
+    /// Download the model from the HuggingFace Hub instead, like google/gemma-2b. Files are cached, so they're only
+    /// downloaded once.
+    #[cfg(feature = "hf-hub")]
+    #[clap(long = "hub", conflicts_with = "model")]
+    hub: Option<String>,
+}
+
+/// The logits of the last position in the sequence
+pub type Logits = (Const<1>, Dyn<'-'>, Vocab);
+
+/// A model with its weights loaded, in a graph compiled for the CPU
+pub struct LoadedModel {
+    pub config: TransformerConfig,
+    pub tokenizer: Tokenizer,
+    // Boxed so the tensors' graph pointers stay valid when the model moves
+    pub cx: Box<Graph>,
+    pub input: GraphTensor<(Const<1>, Dyn<'s'>)>,
+    pub logits: GraphTensor<Logits>,
+    pub cache_src: Vec<NodeIndex>,
+    pub cache_dest: Vec<NodeIndex>,
+}
+
+impl LoadedModel {
+    /// Build the model in a directory holding a HuggingFace config.json, tokenizer.json and weights. Larger
+    /// checkpoints are split into shards listed in a model.safetensors.index.json.
+    pub fn from_dir(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let index = dir.join("model.safetensors.index.json");
+        let weights = if index.exists() {
+            index
+        } else {
+            dir.join("model.safetensors")
+        };
+        Self::from_files(
+            &dir.join("config.json"),
+            &dir.join("tokenizer.json"),
+            &weights,
+        )
+    }
+
+    /// Download a model from the HuggingFace Hub, like `google/gemma-2b` or `meta-llama/Llama-2-7b-hf`, and build it.
+    /// The architecture is picked from the model's config.
+    #[cfg(feature = "hf-hub")]
+    pub fn from_pretrained(model_id: &str) -> io::Result<Self> {
+        let files = luminal::hub::download(model_id)?;
+        Self::from_files(&files.config, &files.tokenizer, &files.weights)
+    }
+
+    fn from_files(config: &Path, tokenizer: &Path, weights: &Path) -> io::Result<Self> {
+        let config = TransformerConfig::from_hf_config(config)?;
+        let mut tokenizer = Tokenizer::from_file(tokenizer)
+            .map_err(io::Error::other)?
+            .with_eos_tokens([config.eos_token]);
+        if let Some(bos) = config.bos_token {
+            tokenizer = tokenizer.with_bos_token(bos);
+        }
+
+        print!("Defining graph");
+        io::stdout().flush().unwrap();
+        let now = Instant::now();
+
+        // Set up graph
+        let mut cx = Box::new(Graph::new());
+        config.set_dims(&mut cx);
+        let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+        let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.n_layers)
+            .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
+            .collect();
+        cache_src.set_dyn(vec![], &[1, config.n_kv_heads, 0, config.head_dim]);
+        let model = model::TransformerLM::new(&config, &mut cx);
+        let mut model_weights = params(&model);
+        cx.keep_tensors(&model_weights);
+        let mask = AttentionMask::<_, _, Dyn<'t'>>::causal(&mut cx);
+        let (logits, mut cache_dest) = model.forward((input, &cache_src, mask));
+        let mut logits = logits
+            .slice((.., (Expression::from('s') - 1).., ..))
+            .retrieve();
+        cache_dest.keep();
+
+        // Set up model loading
+        safetensors::load(weights, &model, &mut cx).map_err(io::Error::other)?;
+        println!("\t\t - {}ms", now.elapsed().as_millis());
+
+        print!("Compiling graph");
+        io::stdout().flush().unwrap();
+        let now = Instant::now();
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                luminal_cpu::CPUCompiler::default(),
+            ),
+            (
+                &mut input,
+                &mut logits,
+                &mut cache_src,
+                &mut cache_dest,
+                &mut model_weights,
+            ),
+        );
+        cx.compile(MemoryPlanner, ());
+        let cache_src = downstream(&cache_src, &cx);
+        let cache_dest = cache_dest.to_ids();
+        println!("\t\t - {}ms", now.elapsed().as_millis());
+
+        // Initial forward pass to load weights
+        print!("Loading model");
+        io::stdout().flush().unwrap();
+        let now = Instant::now();
+        input.set_dyn(vec![1.], &[1, 1]);
+        cx.set_dyn_dim('t', 1);
+        cx.execute();
+        logits.drop();
+        cx.drop_tensors(&cache_dest);
+        println!("\t\t - {}ms", now.elapsed().as_millis());
+
+        // Now that weights are loaded, delete the loading nodes so they don't run again
+        delete_inputs(downstream(model_weights, &cx), &mut cx);
+
+        Ok(Self {
+            config,
+            tokenizer,
+            cx,
+            input,
+            logits,
+            cache_src,
+            cache_dest,
+        })
+    }
+
+    /// Generate with the model, starting from an empty cache
+    pub fn lm(&mut self) -> GraphLM<'_, Logits> {
+        GraphLM::new(
+            &mut self.cx,
+            self.input,
+            self.logits,
+            self.cache_src.clone(),
+            self.cache_dest.clone(),
+        )
+    }
+}
+
+/// Load a model of the given architecture, and stream a completion of the prompt
+pub fn run(architecture: Architecture, default_dir: &str) {
+    let cli_args = CLIArgs::parse();
+    #[cfg(feature = "hf-hub")]
+    let mut loaded = match &cli_args.hub {
+        Some(model_id) => LoadedModel::from_pretrained(model_id),
+        None => LoadedModel::from_dir(cli_args.model.as_deref().unwrap_or(default_dir)),
+    }
+    .unwrap();
+    #[cfg(not(feature = "hf-hub"))]
+    let mut loaded =
+        LoadedModel::from_dir(cli_args.model.as_deref().unwrap_or(default_dir)).unwrap();
+    assert_eq!(
+        loaded.config.architecture, architecture,
+        "The model isn't a {architecture:?} model"
+    );
+
+    // Generate, streaming the decoded text as tokens come in
+    let tokenizer = loaded.tokenizer.clone();
+    let input_ids = tokenizer.encode(&cli_args.prompt).unwrap();
+    let mut output_stream = tokenizer.decode_stream(true);
+    print!("{}", cli_args.prompt.white().bold());
+    io::stdout().flush().unwrap();
+    let mut model = loaded.lm();
+    let mut greedy = Sample::new(0., 0, 1., 0);
+    let start = Instant::now();
+    let mut start_decode = None;
+    let output_ids = Generator::new(cli_args.gen_tokens as usize + 1)
+        .with_stop_tokens(tokenizer.eos_tokens().iter().copied())
+        .generate(&mut model, &mut greedy, &input_ids, |token| {
+            start_decode.get_or_insert_with(Instant::now);
+            if let Some(text) = output_stream.push(token).unwrap() {
+                print!("{}", text.bright_green());
+                io::stdout().flush().unwrap();
+            }
+            ControlFlow::Continue(())
+        });
+    println!();
+
+    let start_decode = start_decode.unwrap();
+    let prompt_ms = (start_decode - start).as_millis();
+    println!(
+        "\nProcessed prompt in {prompt_ms}ms ({:.2} tok/s, {} prompt tokens)",
+        1000.0 * (input_ids.len() as f64) / (prompt_ms as f64),
+        input_ids.len()
+    );
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "Average token generated in {:.2}ms\t - ({:.2} tok/s)",
+        avg_token_time,
+        1000.0 / avg_token_time
+    );
+}
@@ -0,0 +1,181 @@
+use luminal::prelude::*;
+use luminal_nn::{
+    AttentionMask, DynLayerNorm, DynMlp, DynMultiHeadAttention, DynNorm, DynPermutedLinear,
+    DynRMSNorm, DynRotaryEmbedding, DynTransformerBlock, RopeScaling,
+};
+
+use crate::config::{Architecture, NormKind, TransformerConfig};
+
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type KVHeads = Dyn<'k'>;
+pub type HeadDim = Dyn<'e'>;
+/// All the query heads together
+pub type QueryDim = Dyn<'q'>;
+/// All the key / value heads together
+pub type KVDim = Dyn<'a'>;
+/// Half the dims of each head that get rotary embeddings
+pub type RotaryDimOver2 = Dyn<'o'>;
+
+pub type KVCache<Batch, Seq> = (
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+    GraphTensor<(Batch, KVHeads, Seq, HeadDim)>,
+);
+
+pub type TransformerBlock = DynTransformerBlock<
+    Hidden,
+    QueryDim,
+    KVDim,
+    Heads,
+    KVHeads,
+    HeadDim,
+    RotaryDimOver2,
+    MlpDim,
+    DynNorm<Hidden>,
+>;
+
+fn norm(config: &TransformerConfig, cx: &mut Graph) -> DynNorm<Hidden> {
+    match config.norm {
+        NormKind::LayerNorm => DynNorm::Layer(DynLayerNorm::new(true, config.norm_epsilon, cx)),
+        NormKind::RmsNorm { offset } => {
+            DynNorm::RMS(DynRMSNorm::new(config.norm_epsilon, cx).with_offset(offset))
+        }
+    }
+}
+
+fn transformer_block(config: &TransformerConfig, cx: &mut Graph) -> TransformerBlock {
+    // HF checkpoints rotate the first half of the rotary dims with the second half
+    let rotary = DynRotaryEmbedding::partial(
+        config.rope_theta,
+        RopeScaling::None,
+        config.head_dim,
+        config.rotary_dim,
+        cx,
+    )
+    .with_split_halves();
+    DynTransformerBlock {
+        attention: DynMultiHeadAttention::new(config.bias, config.bias, Some(rotary), cx),
+        attention_norm: norm(config, cx),
+        mlp: DynMlp::new(config.mlp, config.bias, cx),
+        // Layers with a parallel residual feed the MLP the attention's normed input
+        mlp_norm: (!config.parallel_residual).then(|| norm(config, cx)),
+    }
+}
+
+pub struct TransformerLM {
+    pub embedding: GraphTensor<(Vocab, Hidden)>,
+    /// Token embeddings are multiplied by this, if set
+    pub embedding_scale: Option<f32>,
+    pub layers: Vec<TransformerBlock>,
+    pub norm: DynNorm<Hidden>,
+    /// Decides how weights are named
+    pub architecture: Architecture,
+    /// Projects to the vocab. Tied models use the token embeddings instead.
+    pub lm_head: Option<DynPermutedLinear<Hidden, Vocab>>,
+}
+
+impl TransformerLM {
+    /// Build the model for a config. The config's dims must also be set on the graph with
+    /// `TransformerConfig::set_dims`.
+    pub fn new(config: &TransformerConfig, cx: &mut Graph) -> Self {
+        Self {
+            embedding: cx.named_tensor("Embedding Weight"),
+            embedding_scale: config
+                .scale_embeddings
+                .then(|| (config.hidden_dim as f32).sqrt()),
+            layers: (0..config.n_layers)
+                .map(|_| transformer_block(config, cx))
+                .collect(),
+            norm: norm(config, cx),
+            architecture: config.architecture,
+            lm_head: (!config.tie_embeddings).then(|| DynPermutedLinear::new(config.bias, cx)),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<(
+        GraphTensor<(Batch, CurSeq)>,
+        &[KVCache<Batch, PrevSeq>],
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for TransformerLM
+{
+    type Output = (
+        GraphTensor<(Batch, CurSeq, Vocab)>,
+        Vec<KVCache<Batch, TotSeq>>,
+    );
+    fn forward(
+        &self,
+        (input, cache, mask): (
+            GraphTensor<(Batch, CurSeq)>,
+            &[KVCache<Batch, PrevSeq>],
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Embed tokens
+        let mut x = self.embedding.index_select::<_, Axis<0>, _>(input);
+        if let Some(scale) = self.embedding_scale {
+            x = x * scale;
+        }
+
+        // Run through layers and collect new caches
+        let mut new_caches = vec![];
+        let mut new_cache;
+        for (i, layer) in self.layers.iter().enumerate() {
+            (x, new_cache) = layer.forward((x, cache[i], mask));
+            new_caches.push(new_cache);
+        }
+        // Run through the last norm and project back to the vocab
+        let x = self.norm.forward(x);
+        let output = match &self.lm_head {
+            Some(lm_head) => lm_head.forward(x),
+            None => x.matmul(self.embedding.permute()),
+        };
+
+        (output, new_caches)
+    }
+}
+
+impl SerializeModule for TransformerLM {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("model/embed_tokens/weight", self.embedding);
+        // Layers are serialized under their HuggingFace names rather than the nn modules' own
+        for (i, layer) in self.layers.iter().enumerate() {
+            let name = |module: &str| format!("model/layers/{i}/{module}");
+            s.module(&name("self_attn/q_proj"), &layer.attention.w_q);
+            s.module(&name("self_attn/k_proj"), &layer.attention.w_k);
+            s.module(&name("self_attn/v_proj"), &layer.attention.w_v);
+            s.module(
+                &name(&format!(
+                    "self_attn/{}",
+                    self.architecture.output_proj_name()
+                )),
+                &layer.attention.w_o,
+            );
+            s.module(&name("input_layernorm"), &layer.attention_norm);
+            if let Some(norm) = &layer.mlp_norm {
+                s.module(&name("post_attention_layernorm"), norm);
+            }
+            match &layer.mlp.gate {
+                Some(gate) => {
+                    s.module(&name("mlp/gate_proj"), gate);
+                    s.module(&name("mlp/up_proj"), &layer.mlp.up);
+                    s.module(&name("mlp/down_proj"), &layer.mlp.down);
+                }
+                None => {
+                    s.module(&name("mlp/fc1"), &layer.mlp.up);
+                    s.module(&name("mlp/fc2"), &layer.mlp.down);
+                }
+            }
+        }
+        s.module(
+            &format!("model/{}", self.architecture.final_norm_name()),
+            &self.norm,
+        );
+        if let Some(lm_head) = &self.lm_head {
+            s.module("lm_head", lm_head);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+echo "Downloading Model..."
+curl --location https://huggingface.co/google/vit-base-patch16-224/resolve/main/config.json?download=true --output $SCRIPT_DIR/config.json
+curl --location https://huggingface.co/google/vit-base-patch16-224/resolve/main/preprocessor_config.json?download=true --output $SCRIPT_DIR/preprocessor_config.json
+curl --location https://huggingface.co/google/vit-base-patch16-224/resolve/main/model.safetensors?download=true --output $SCRIPT_DIR/model.safetensors
+echo "Downloading Sample Image..."
+curl --location http://images.cocodataset.org/val2017/000000039769.jpg --output $SCRIPT_DIR/cats.jpg
+# The example reads binary PPM images
+convert $SCRIPT_DIR/cats.jpg $SCRIPT_DIR/cats.ppm
+echo "Done!"
@@ -0,0 +1,115 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use clap::Parser;
+
+mod model;
+
+use luminal::{image, prelude::*, serialization::safetensors};
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Binary PPM images to classify, which can be given multiple times
+    #[clap(short = 'i', long = "image", default_values = ["setup/cats.ppm"])]
+    images: Vec<String>,
+
+    /// Directory holding the HuggingFace config.json, preprocessor_config.json and model.safetensors
+    #[clap(short = 'm', long = "model", default_value = "setup")]
+    model: String,
+
+    /// Number of top labels to show for each image
+    #[clap(short = 'k', long = "top_k", default_value = "5")]
+    top_k: usize,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let dir = Path::new(&cli_args.model);
+    let config = model::VitConfig::from_hf_config(dir.join("config.json")).unwrap();
+    let defaults = image::Preprocessor::new(config.image_size);
+    let preprocessor =
+        image::Preprocessor::from_hf_config(dir.join("preprocessor_config.json"), defaults.clone())
+            .unwrap_or(defaults);
+
+    print!("Defining graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+
+    // Set up graph
+    let mut cx = Graph::new();
+    config.set_dims(&mut cx);
+    let mut images = cx.named_tensor::<(
+        Dyn<'b'>,
+        model::Channels,
+        model::ImageSize,
+        model::ImageSize,
+    )>("Images");
+    let model = model::Vit::new(&config, &mut cx);
+    let mut model_weights = params(&model);
+    cx.keep_tensors(&model_weights);
+    let mut logits = model.forward(images).retrieve();
+    safetensors::load(dir.join("model.safetensors"), &model, &mut cx).unwrap();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graph");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (&mut images, &mut logits, &mut model_weights),
+    );
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Preprocess the images and batch them together
+    let data = cli_args
+        .images
+        .iter()
+        .flat_map(|path| {
+            let image =
+                image::read_pnm(path).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+            preprocessor.process(&image)
+        })
+        .collect::<Vec<_>>();
+    images.set_dyn(
+        data,
+        &[
+            cli_args.images.len(),
+            config.n_channels,
+            config.image_size,
+            config.image_size,
+        ],
+    );
+
+    let now = Instant::now();
+    cx.execute();
+    println!(
+        "Classified {} images in {}ms",
+        cli_args.images.len(),
+        now.elapsed().as_millis()
+    );
+
+    for (path, logits) in cli_args
+        .images
+        .iter()
+        .zip(logits.data().chunks(config.labels.len()))
+    {
+        // Softmax the logits into probabilities
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+        let sum = exp.iter().sum::<f32>();
+        let mut ranked = exp.iter().map(|e| e / sum).enumerate().collect::<Vec<_>>();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        println!("\n{path}:");
+        for (label, prob) in ranked.into_iter().take(cli_args.top_k) {
+            println!("  {:>6.2}%  {}", prob * 100., config.labels[label]);
+        }
+    }
+}
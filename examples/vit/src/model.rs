@@ -0,0 +1,356 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynLayerNorm, DynPermutedLinear};
+
+// Model dimensions are dynamic so the same graph code works for every ViT size. Their sizes come from a `VitConfig`,
+// and are set on the graph with `VitConfig::set_dims` before it's compiled.
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type HeadDim = Dyn<'e'>;
+pub type Channels = Dyn<'c'>;
+/// The width and height of input images
+pub type ImageSize = Dyn<'i'>;
+/// The width and height of each patch
+pub type PatchSize = Dyn<'q'>;
+/// The number of patches along each side of the image
+pub type Grid = Dyn<'g'>;
+pub type Patches = Dyn<'p'>;
+/// The number of values in a patch, which is channels * patch size * patch size
+pub type PatchDim = Dyn<'k'>;
+/// The patches and the class token
+pub type Positions = Dyn<'n'>;
+pub type Labels = Dyn<'l'>;
+
+/// The hyperparameters of a ViT classifier
+#[derive(Debug, Clone, PartialEq)]
+pub struct VitConfig {
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub mlp_dim: usize,
+    pub n_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    pub layer_norm_epsilon: f32,
+    /// The name of each class, indexed by the classifier's outputs
+    pub labels: Vec<String>,
+}
+
+impl VitConfig {
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| invalid(format!("Missing {key}")))
+        };
+        // Labels are stored as a map from stringified ids to names
+        let mut labels = json
+            .get("id2label")
+            .and_then(serde_json::Value::as_object)
+            .ok_or_else(|| invalid("Missing id2label".to_string()))?
+            .iter()
+            .map(|(id, label)| {
+                Ok((
+                    id.parse::<usize>()
+                        .map_err(|_| invalid(format!("Invalid label id {id}")))?,
+                    label.as_str().unwrap_or_default().to_string(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        labels.sort();
+        let config = Self {
+            hidden_dim: get_usize("hidden_size")?,
+            n_layers: get_usize("num_hidden_layers")?,
+            n_heads: get_usize("num_attention_heads")?,
+            mlp_dim: get_usize("intermediate_size")?,
+            n_channels: get_usize("num_channels").unwrap_or(3),
+            image_size: get_usize("image_size")?,
+            patch_size: get_usize("patch_size")?,
+            layer_norm_epsilon: json
+                .get("layer_norm_eps")
+                .and_then(serde_json::Value::as_f64)
+                .unwrap_or(1e-12) as f32,
+            labels: labels.into_iter().map(|(_, label)| label).collect(),
+        };
+        if config.n_heads == 0 || !config.hidden_dim.is_multiple_of(config.n_heads) {
+            return Err(invalid(
+                "Hidden dim must be divisible by the number of heads".to_string(),
+            ));
+        }
+        if config.patch_size == 0 || !config.image_size.is_multiple_of(config.patch_size) {
+            return Err(invalid(
+                "Image size must be divisible by the patch size".to_string(),
+            ));
+        }
+        match json.get("hidden_act").and_then(serde_json::Value::as_str) {
+            None | Some("gelu") => Ok(config),
+            Some(act) => Err(invalid(format!("Unsupported activation: {act}"))),
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    pub fn grid_size(&self) -> usize {
+        self.image_size / self.patch_size
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 12] {
+        let patches = self.grid_size() * self.grid_size();
+        [
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('e', self.head_dim()),
+            ('c', self.n_channels),
+            ('i', self.image_size),
+            ('q', self.patch_size),
+            ('g', self.grid_size()),
+            ('p', patches),
+            ('k', self.n_channels * self.patch_size * self.patch_size),
+            ('n', patches + 1),
+            ('l', self.labels.len()),
+        ]
+    }
+
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
+    }
+}
+
+/// Splits images into patches and projects each one to the hidden dim, which is the same as a convolution with a
+/// stride of its kernel size
+pub struct PatchEmbedding {
+    pub weight: GraphTensor<(Hidden, Channels, PatchSize, PatchSize)>,
+    pub bias: GraphTensor<(Hidden,)>,
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Channels, ImageSize, ImageSize)>>
+    for PatchEmbedding
+{
+    type Output = GraphTensor<(Batch, Patches, Hidden)>;
+
+    fn forward(
+        &self,
+        images: GraphTensor<(Batch, Channels, ImageSize, ImageSize)>,
+    ) -> Self::Output {
+        patchify(images).matmul(self.weight.reshape::<(Hidden, PatchDim)>().permute())
+            + self.bias.expand()
+    }
+}
+
+impl SerializeModule for PatchEmbedding {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+/// Split images into a row-major sequence of flattened patches, each laid out (channel, y, x) like the patch
+/// embedding's weights
+pub fn patchify<Batch: Dimension>(
+    images: GraphTensor<(Batch, Channels, ImageSize, ImageSize)>,
+) -> GraphTensor<(Batch, Patches, PatchDim)> {
+    let dim = |axis: usize| images.shape.dims[images.shape.indexes[axis]];
+    let (batch, channels) = (dim(0), dim(1));
+    let (grid, patch) = (Grid::const_size(), PatchSize::const_size());
+    // Images are (batch, channel, grid y, patch y, grid x, patch x). Shapes have at most 5 permutable dims, so first
+    // gather the patches of each channel...
+    images
+        .dyn_reshape::<(Dyn<'-'>, Grid, PatchSize, Grid, PatchSize)>(vec![
+            batch * channels,
+            grid,
+            patch,
+            grid,
+            patch,
+        ])
+        .permute::<_, Axes5<0, 1, 3, 2, 4>>()
+        // ...then move the channels inside each patch
+        .dyn_reshape::<(Batch, Channels, Patches, Dyn<'-'>)>(vec![
+            batch,
+            channels,
+            grid * grid,
+            patch * patch,
+        ])
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        .reshape::<(Batch, Patches, PatchDim)>()
+}
+
+pub struct SelfAttention {
+    pub query: DynPermutedLinear<Hidden, Hidden>,
+    pub key: DynPermutedLinear<Hidden, Hidden>,
+    pub value: DynPermutedLinear<Hidden, Hidden>,
+    pub output: DynPermutedLinear<Hidden, Hidden>,
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Positions, Hidden)>> for SelfAttention {
+    type Output = GraphTensor<(Batch, Positions, Hidden)>;
+
+    fn forward(&self, x: GraphTensor<(Batch, Positions, Hidden)>) -> Self::Output {
+        let heads = |proj: &DynPermutedLinear<Hidden, Hidden>| {
+            proj.forward(x)
+                .reshape::<(Batch, Positions, Heads, HeadDim)>()
+                .permute::<_, Axes4<0, 2, 1, 3>>()
+        };
+        // Every patch attends to every other patch
+        let output = grouped_query_attention(
+            heads(&self.query),
+            heads(&self.key),
+            heads(&self.value),
+            AttentionMask::none(),
+        )
+        // Merge heads
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        .reshape::<(Batch, Positions, Hidden)>();
+        self.output.forward(output)
+    }
+}
+
+impl SerializeModule for SelfAttention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attention/query", &self.query);
+        s.module("attention/key", &self.key);
+        s.module("attention/value", &self.value);
+        s.module("output/dense", &self.output);
+    }
+}
+
+pub struct EncoderLayer {
+    pub attention: SelfAttention,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub intermediate: DynPermutedLinear<Hidden, MlpDim>,
+    pub output: DynPermutedLinear<MlpDim, Hidden>,
+    pub mlp_norm: DynLayerNorm<Hidden>,
+}
+
+impl EncoderLayer {
+    pub fn new(config: &VitConfig, cx: &mut Graph) -> Self {
+        Self {
+            attention: SelfAttention {
+                query: DynPermutedLinear::new(true, cx),
+                key: DynPermutedLinear::new(true, cx),
+                value: DynPermutedLinear::new(true, cx),
+                output: DynPermutedLinear::new(true, cx),
+            },
+            attention_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            intermediate: DynPermutedLinear::new(true, cx),
+            output: DynPermutedLinear::new(true, cx),
+            mlp_norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Positions, Hidden)>> for EncoderLayer {
+    type Output = GraphTensor<(Batch, Positions, Hidden)>;
+
+    fn forward(&self, x: GraphTensor<(Batch, Positions, Hidden)>) -> Self::Output {
+        // Pre-norm attention and MLP, each with a residual connection
+        let x = x + self.attention.forward(self.attention_norm.forward(x));
+        x + self
+            .output
+            .forward(self.intermediate.forward(self.mlp_norm.forward(x)).gelu())
+    }
+}
+
+impl SerializeModule for EncoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attention", &self.attention);
+        s.module("layernorm_before", &self.attention_norm);
+        s.module("intermediate/dense", &self.intermediate);
+        s.module("output/dense", &self.output);
+        s.module("layernorm_after", &self.mlp_norm);
+    }
+}
+
+pub struct Vit {
+    pub patch_embedding: PatchEmbedding,
+    /// Prepended to the patches, and classified once it's attended to them
+    pub class_token: GraphTensor<(Hidden,)>,
+    /// Learned position embeddings for the class token and each patch
+    pub position_embedding: GraphTensor<(Positions, Hidden)>,
+    pub layers: Vec<EncoderLayer>,
+    pub norm: DynLayerNorm<Hidden>,
+    pub classifier: DynPermutedLinear<Hidden, Labels>,
+}
+
+impl Vit {
+    /// Build the model for a config. The config's dims must also be set on the graph with `VitConfig::set_dims`.
+    pub fn new(config: &VitConfig, cx: &mut Graph) -> Self {
+        Self {
+            patch_embedding: PatchEmbedding {
+                weight: cx.named_tensor("Patch Embedding Weight"),
+                bias: cx.named_tensor("Patch Embedding Bias"),
+            },
+            class_token: cx.named_tensor("Class Token"),
+            position_embedding: cx.named_tensor("Position Embedding"),
+            layers: (0..config.n_layers)
+                .map(|_| EncoderLayer::new(config, cx))
+                .collect(),
+            norm: DynLayerNorm::new(true, config.layer_norm_epsilon, cx),
+            classifier: DynPermutedLinear::new(true, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension> Module<GraphTensor<(Batch, Channels, ImageSize, ImageSize)>> for Vit {
+    /// The logits of each label
+    type Output = GraphTensor<(Batch, Labels)>;
+
+    fn forward(
+        &self,
+        images: GraphTensor<(Batch, Channels, ImageSize, ImageSize)>,
+    ) -> Self::Output {
+        // Embed the patches, put the class token in front of them, and add positions
+        let patches = self.patch_embedding.forward(images);
+        let mut x = self
+            .class_token
+            .expand::<(Batch, Const<1>, Hidden), _>()
+            .concat_along::<(Batch, Positions, Hidden), Axis<1>, _>(patches)
+            + self.position_embedding.expand();
+        for layer in &self.layers {
+            x = layer.forward(x);
+        }
+        // Classify the final class token
+        let class_token = self
+            .norm
+            .forward(x)
+            .slice((.., ..Expression::from(1), ..))
+            .realize::<(Batch, Const<1>, Hidden)>();
+        self.classifier
+            .forward(class_token)
+            .reshape::<(Batch, Labels)>()
+    }
+}
+
+impl SerializeModule for Vit {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module(
+            "vit/embeddings/patch_embeddings/projection",
+            &self.patch_embedding,
+        );
+        s.tensor("vit/embeddings/cls_token", self.class_token);
+        s.tensor(
+            "vit/embeddings/position_embeddings",
+            self.position_embedding,
+        );
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("vit/encoder/layer/{i}"), layer);
+        }
+        s.module("vit/layernorm", &self.norm);
+        s.module("classifier", &self.classifier);
+    }
+}
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+echo "Downloading Model and Tokenizer..."
+curl --location https://huggingface.co/openai/whisper-tiny/resolve/main/tokenizer.json?download=true --output $SCRIPT_DIR/tokenizer.json
+curl --location https://huggingface.co/openai/whisper-tiny/resolve/main/config.json?download=true --output $SCRIPT_DIR/config.json
+curl --location https://huggingface.co/openai/whisper-tiny/resolve/main/model.safetensors?download=true --output $SCRIPT_DIR/model.safetensors
+echo "Downloading Sample Audio..."
+curl --location https://github.com/ggerganov/whisper.cpp/raw/master/samples/jfk.wav --output $SCRIPT_DIR/jfk.wav
+echo "Done!"
@@ -1,3 +1,5 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -222,8 +224,8 @@ pub fn log_mel_spectrogram_<T: Float>(
         samples_padded
     };
 
-    // ensure that the number of threads is even and less than 12
-    let n_threads = std::cmp::min(get_num_threads() - get_num_threads() % 2, 12);
+    // ensure that the number of threads is even and less than 12, while still using one thread on a single core
+    let n_threads = std::cmp::min(get_num_threads() - get_num_threads() % 2, 12).max(1);
 
     let hann = Arc::new(hann);
     let samples = Arc::new(samples);
@@ -287,3 +289,124 @@ pub fn log_mel_spectrogram_<T: Float>(
 pub fn pcm_to_mel<T: Float>(n_mel: usize, samples: &[T], filters: &[T]) -> Vec<T> {
     log_mel_spectrogram_(samples, filters, N_FFT, HOP_LENGTH, n_mel, false)
 }
+
+/// Read a WAV file as mono samples at `SAMPLE_RATE`. 16 bit PCM and 32 bit float files are supported, multiple channels
+/// are averaged, and other sample rates are linearly resampled.
+pub fn read_wav(path: impl AsRef<Path>) -> Result<Vec<f32>> {
+    let bytes = std::fs::read(path)?;
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("Not a WAV file"));
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+
+    // Walk the chunks to find the format and the samples
+    let (mut format, mut data) = (None, None);
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32_at(offset + 4) as usize;
+        let body = offset + 8;
+        if body + size > bytes.len() {
+            return Err(invalid("Truncated WAV chunk"));
+        }
+        match &bytes[offset..offset + 4] {
+            b"fmt " if size >= 16 => {
+                // (format tag, channels, sample rate, bits per sample)
+                format = Some((
+                    u16_at(body),
+                    u16_at(body + 2) as usize,
+                    u32_at(body + 4) as usize,
+                    u16_at(body + 14),
+                ))
+            }
+            b"data" => data = Some(&bytes[body..body + size]),
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        offset = body + size + size % 2;
+    }
+    let (Some((tag, channels, sample_rate, bits)), Some(data)) = (format, data) else {
+        return Err(invalid("WAV file is missing its format or data"));
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(invalid("WAV file has no channels"));
+    }
+    // 0xFFFE is the extensible format, which stores its real format in the extension
+    let interleaved = match (tag, bits) {
+        (1 | 0xFFFE, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.)
+            .collect::<Vec<_>>(),
+        (3 | 0xFFFE, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+        _ => {
+            return Err(invalid(&format!(
+                "Unsupported WAV format {tag} with {bits} bits per sample"
+            )))
+        }
+    };
+    let mono = interleaved
+        .chunks_exact(channels)
+        .map(|c| c.iter().sum::<f32>() / channels as f32)
+        .collect::<Vec<_>>();
+    Ok(resample(&mono, sample_rate, SAMPLE_RATE))
+}
+
+/// Linearly resample audio between sample rates
+pub fn resample(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let len = samples.len() * to / from;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * from as f64 / to as f64;
+            let (index, frac) = (pos as usize, (pos - pos.floor()) as f32);
+            let next = samples[(index + 1).min(samples.len() - 1)];
+            samples[index] * (1. - frac) + next * frac
+        })
+        .collect()
+}
+
+/// A mel filterbank for `pcm_to_mel`, laid out (mel, frequency bin) and matching librosa's defaults with Slaney's mel scale and area normalization
+pub fn mel_filters(n_mels: usize) -> Vec<f32> {
+    let n_bins = N_FFT / 2 + 1;
+    // Slaney's mel scale is linear below 1kHz and logarithmic above
+    let (f_sp, min_log_hz, min_log_mel) = (200. / 3., 1000., 15.);
+    let log_step = 6.4f64.ln() / 27.;
+    let hz_to_mel = |hz: f64| {
+        if hz < min_log_hz {
+            hz / f_sp
+        } else {
+            min_log_mel + (hz / min_log_hz).ln() / log_step
+        }
+    };
+    let mel_to_hz = |mel: f64| {
+        if mel < min_log_mel {
+            mel * f_sp
+        } else {
+            min_log_hz * (log_step * (mel - min_log_mel)).exp()
+        }
+    };
+    let nyquist = SAMPLE_RATE as f64 / 2.;
+    let max_mel = hz_to_mel(nyquist);
+    // The edges of each triangular filter, evenly spaced on the mel scale
+    let edges = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (n_mels + 1) as f64))
+        .collect::<Vec<_>>();
+    let mut filters = vec![0.; n_mels * n_bins];
+    for m in 0..n_mels {
+        let (low, center, high) = (edges[m], edges[m + 1], edges[m + 2]);
+        let norm = 2. / (high - low);
+        for k in 0..n_bins {
+            let freq = nyquist * k as f64 / (n_bins - 1) as f64;
+            let rising = (freq - low) / (center - low);
+            let falling = (high - freq) / (high - center);
+            filters[m * n_bins + k] = (rising.min(falling).max(0.) * norm) as f32;
+        }
+    }
+    filters
+}
@@ -1,8 +1,223 @@
-use luminal::prelude::*;
+use std::{
+    io::{self, Write},
+    ops::ControlFlow,
+    path::Path,
+    time::Instant,
+};
+
+use clap::Parser;
 
 mod audio;
 mod model;
+mod timestamps;
+
+use crate::{
+    model::{AudioSeq, KVCache},
+    timestamps::TimestampRules,
+};
+use luminal::{prelude::*, serialization::safetensors, tokenizer::Tokenizer};
+use luminal_nn::AttentionMask;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// WAV file to transcribe
+    #[clap(short = 'a', long = "audio", default_value = "setup/jfk.wav")]
+    audio: String,
+
+    /// Directory holding the HuggingFace config.json, model.safetensors and tokenizer.json
+    #[clap(short = 'm', long = "model", default_value = "setup")]
+    model: String,
+
+    /// Language spoken in the audio, for multilingual models
+    #[clap(short = 'l', long = "language", default_value = "en")]
+    language: String,
+
+    /// Transcribe the text only, without segment timestamps
+    #[clap(long = "no_timestamps")]
+    no_timestamps: bool,
+}
 
 fn main() {
-    let mut graph = Graph::new();
+    let cli_args = CLIArgs::parse();
+    let dir = Path::new(&cli_args.model);
+    let config = model::WhisperConfig::from_hf_config(dir.join("config.json")).unwrap();
+    let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json")).unwrap();
+    let special = |token: &str| tokenizer.inner().token_to_id(token);
+    let end_of_text = special("<|endoftext|>").unwrap();
+    let no_timestamps = special("<|notimestamps|>").unwrap();
+    // Timestamp tokens start right after <|notimestamps|>, even in tokenizers that don't list them
+    let timestamp_begin = special("<|0.00|>").unwrap_or(no_timestamps + 1);
+
+    // English-only models (with a vocab of 51864) only take the start of transcript token, and multilingual models
+    // also take the language and task
+    let mut prompt = vec![special("<|startoftranscript|>").unwrap()];
+    if config.vocab_size > 51864 {
+        let language = format!("<|{}|>", cli_args.language);
+        prompt.push(special(&language).unwrap_or_else(|| panic!("Unknown language {language}")));
+        prompt.push(special("<|transcribe|>").unwrap());
+    }
+    if cli_args.no_timestamps {
+        prompt.push(no_timestamps);
+    }
+
+    print!("Defining graphs");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    let weights = dir.join("model.safetensors");
+
+    // The encoder graph runs once per 30 second chunk, computing each decoder layer's cross attention keys and values
+    let mut enc_cx = Graph::new();
+    config.set_dims(&mut enc_cx);
+    let mut mel = enc_cx.named_tensor::<(model::MelBins, model::MelFrames)>("Mel");
+    let encoder = model::Encoder::new(&config, &mut enc_cx);
+    let mut encoder_weights = params(&encoder);
+    enc_cx.keep_tensors(&encoder_weights);
+    let mut cross_dest = encoder.forward(mel);
+    for (keys, values) in &mut cross_dest {
+        *keys = keys.retrieve();
+        *values = values.retrieve();
+    }
+    safetensors::load(&weights, &encoder, &mut enc_cx).unwrap();
+
+    // The decoder graph runs once per token, reading the cross attention caches the encoder computed
+    let mut cx = Graph::new();
+    config.set_dims(&mut cx);
+    let mut input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+    let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..config.decoder_layers)
+        .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
+        .collect();
+    cache_src.set_dyn(vec![], &[1, config.n_heads, 0, config.head_dim()]);
+    let mut cross_src: Vec<KVCache<Const<1>, AudioSeq>> = (0..config.decoder_layers)
+        .map(|_| {
+            (
+                cx.named_tensor("Cross Key Cache"),
+                cx.named_tensor("Cross Value Cache"),
+            )
+        })
+        .collect();
+    let decoder = model::Decoder::new(&config, &mut cx);
+    let mut decoder_weights = params(&decoder);
+    cx.keep_tensors(&decoder_weights);
+    let mask = AttentionMask::<_, _, Dyn<'t'>>::causal(&mut cx);
+    let (logits, mut cache_dest) = decoder.forward((input, &cache_src, &cross_src, mask));
+    let mut logits = logits
+        .slice((.., (Expression::from('s') - 1).., ..))
+        .retrieve();
+    cache_dest.keep();
+    safetensors::load(&weights, &decoder, &mut cx).unwrap();
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    print!("Compiling graphs");
+    io::stdout().flush().unwrap();
+    let now = Instant::now();
+    enc_cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (&mut mel, &mut cross_dest, &mut encoder_weights),
+    );
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            luminal_cpu::CPUCompiler::default(),
+        ),
+        (
+            &mut input,
+            &mut logits,
+            &mut cache_src,
+            &mut cache_dest,
+            &mut cross_src,
+            &mut decoder_weights,
+        ),
+    );
+    cx.compile(MemoryPlanner, ());
+    let cache_src = downstream(&cache_src, &cx);
+    let cache_dest = cache_dest.to_ids();
+    // The cross attention caches are set once per chunk, so they're kept through every decoding step
+    let cross_src = downstream(&cross_src, &cx);
+    cx.keep_tensors(&cross_src);
+    println!("\t\t - {}ms", now.elapsed().as_millis());
+
+    // Compute the mel spectrogram of the whole file, padded to a whole number of chunks
+    let mut samples = audio::read_wav(&cli_args.audio).unwrap();
+    let duration = samples.len() as f32 / audio::SAMPLE_RATE as f32;
+    let n_chunks = samples.len().div_ceil(audio::N_SAMPLES).max(1);
+    samples.resize(n_chunks * audio::N_SAMPLES, 0.);
+    let filters = audio::mel_filters(config.n_mels);
+    let spectrogram = audio::pcm_to_mel(config.n_mels, &samples, &filters);
+    let total_frames = spectrogram.len() / config.n_mels;
+
+    let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+    let generator = Generator::new(config.n_text_positions / 2)
+        .with_max_length(config.n_text_positions)
+        .with_stop_tokens([end_of_text]);
+    for chunk in 0..n_chunks {
+        let offset = (chunk * audio::CHUNK_LENGTH) as f32;
+        let start = chunk * audio::N_FRAMES;
+
+        // Encode the chunk, and hand its cross attention caches to the decoder graph
+        let now = Instant::now();
+        mel.set_dyn(
+            (0..config.n_mels)
+                .flat_map(|m| {
+                    let row = m * total_frames + start;
+                    spectrogram[row..row + audio::N_FRAMES].iter().copied()
+                })
+                .collect::<Vec<_>>(),
+            &[config.n_mels, audio::N_FRAMES],
+        );
+        enc_cx.execute();
+        for ((keys, values), ids) in cross_dest.iter().zip(cross_src.chunks(2)) {
+            model.graph.set_tensor(ids[0], 0, Tensor::new(keys.data()));
+            model
+                .graph
+                .set_tensor(ids[1], 0, Tensor::new(values.data()));
+            keys.drop();
+            values.drop();
+        }
+        let encode_ms = now.elapsed().as_millis();
+
+        // Decode the transcript of the chunk, following the timestamp rules
+        let now = Instant::now();
+        model.reset();
+        let mut rules = TimestampRules::new(end_of_text, timestamp_begin, !cli_args.no_timestamps);
+        let mut tokens = generator.generate(&mut model, &mut rules, &prompt, |_| {
+            ControlFlow::Continue(())
+        });
+        tokens.retain(|t| *t != end_of_text);
+        let decode_ms = now.elapsed().as_millis();
+
+        if cli_args.no_timestamps {
+            println!("{}", tokenizer.decode(&tokens, true).unwrap().trim());
+        } else {
+            let chunk_end = (offset + audio::CHUNK_LENGTH as f32).min(duration);
+            for segment in timestamps::segments(&tokens, timestamp_begin, offset, chunk_end) {
+                println!(
+                    "[{} --> {}] {}",
+                    format_time(segment.start),
+                    format_time(segment.end),
+                    tokenizer.decode(&segment.tokens, true).unwrap().trim()
+                );
+            }
+        }
+        println!(
+            "  (chunk {}/{n_chunks}: encoded in {encode_ms}ms, decoded {} tokens in {decode_ms}ms)",
+            chunk + 1,
+            tokens.len()
+        );
+    }
+}
+
+/// Format seconds as mm:ss.mmm
+fn format_time(seconds: f32) -> String {
+    let millis = (seconds * 1000.).round() as usize;
+    format!(
+        "{:02}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
 }
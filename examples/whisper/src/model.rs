@@ -1,347 +1,552 @@
-use std::{marker::PhantomData, ops::Div};
-
-use luminal::prelude::{binary::F32Pow, *};
-use luminal_nn::{Embedding, RMSNorm};
-
-// Mistral 7B Config
-pub const VOCAB_SIZE: usize = 32000;
-pub const HIDDEN_DIM: usize = 4096;
-pub const NUM_LAYERS: usize = 32;
-pub const N_HEADS: usize = 32;
-pub const N_KV_HEADS: usize = 8;
-pub const MLP_DIM: usize = 14336;
-
-pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
-pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
-pub const HEAD_DIM_OVER_2: usize = HEAD_DIM / 2;
-pub const ATTN_PROJ_DIM: usize = HEAD_DIM * N_KV_HEADS;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use luminal::prelude::*;
+use luminal_nn::{grouped_query_attention, AttentionMask, DynLayerNorm, DynPermutedLinear};
+
+// Model dimensions are dynamic so the same graph code works for every Whisper size. Their sizes come from a
+// `WhisperConfig`, and are set on the graph with `WhisperConfig::set_dims` before it's compiled.
+pub type Vocab = Dyn<'v'>;
+pub type Hidden = Dyn<'d'>;
+pub type MlpDim = Dyn<'f'>;
+pub type Heads = Dyn<'h'>;
+pub type HeadDim = Dyn<'e'>;
+pub type MelBins = Dyn<'c'>;
+/// Mel frames in a 30 second chunk
+pub type MelFrames = Dyn<'m'>;
+/// Encoder positions, which are half the mel frames after the strided convolution
+pub type AudioSeq = Dyn<'a'>;
+/// Learned decoder positions, which is the longest transcript the decoder supports for a chunk
+pub type TextPositions = Dyn<'n'>;
 
 pub type KVCache<Batch, Seq> = (
-    GraphTensor<(Batch, Const<N_KV_HEADS>, Seq, Const<HEAD_DIM>)>,
-    GraphTensor<(Batch, Const<N_KV_HEADS>, Seq, Const<HEAD_DIM>)>,
+    GraphTensor<(Batch, Heads, Seq, HeadDim)>,
+    GraphTensor<(Batch, Heads, Seq, HeadDim)>,
 );
 
-pub struct Mlp<const I: usize, const H: usize> {
-    pub gate_proj: GraphTensor<(Const<I>, Const<H>)>,
-    pub down_proj: GraphTensor<(Const<H>, Const<I>)>,
-    pub up_proj: GraphTensor<(Const<I>, Const<H>)>,
+/// The hyperparameters of a Whisper model
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhisperConfig {
+    pub vocab_size: usize,
+    pub hidden_dim: usize,
+    pub encoder_layers: usize,
+    pub decoder_layers: usize,
+    pub n_heads: usize,
+    pub mlp_dim: usize,
+    pub n_mels: usize,
+    /// The number of encoder positions, 1500 for 30 seconds of audio
+    pub n_audio_positions: usize,
+    /// The number of learned decoder positions
+    pub n_text_positions: usize,
 }
 
-impl<Sh: Shape, Im: Shape, const I: usize, const H: usize> Module<GraphTensor<Sh>> for Mlp<I, H>
-where
-    GraphTensor<Sh>: Matmul<R2<H, I>, Output = GraphTensor<Im>>,
-    GraphTensor<Im>: Matmul<R2<I, H>, Output = GraphTensor<Sh>>,
-{
-    type Output = GraphTensor<Sh>;
+impl WhisperConfig {
+    /// Read the config from a HuggingFace `config.json`
+    pub fn from_hf_config(path: impl AsRef<Path>) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let get_usize = |key: &str| {
+            json.get(key)
+                .and_then(serde_json::Value::as_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing {key}")))
+        };
+        let invalid = |msg: &str| Err(Error::new(ErrorKind::InvalidData, msg.to_string()));
+        let config = Self {
+            vocab_size: get_usize("vocab_size")?,
+            hidden_dim: get_usize("d_model")?,
+            encoder_layers: get_usize("encoder_layers")?,
+            decoder_layers: get_usize("decoder_layers")?,
+            n_heads: get_usize("encoder_attention_heads")?,
+            mlp_dim: get_usize("encoder_ffn_dim")?,
+            n_mels: get_usize("num_mel_bins")?,
+            n_audio_positions: get_usize("max_source_positions")?,
+            n_text_positions: get_usize("max_target_positions")?,
+        };
+        // The released checkpoints use the same shape of layer in the encoder and decoder
+        if get_usize("decoder_attention_heads")? != config.n_heads
+            || get_usize("decoder_ffn_dim")? != config.mlp_dim
+        {
+            return invalid("The encoder and decoder layers must have the same shape");
+        }
+        if config.n_heads == 0 || !config.hidden_dim.is_multiple_of(config.n_heads) {
+            return invalid("Hidden dim must be divisible by the number of heads");
+        }
+        Ok(config)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_dim / self.n_heads
+    }
+
+    /// The sizes of the model's dynamic dimensions
+    pub fn dims(&self) -> [(char, usize); 9] {
+        [
+            ('v', self.vocab_size),
+            ('d', self.hidden_dim),
+            ('f', self.mlp_dim),
+            ('h', self.n_heads),
+            ('e', self.head_dim()),
+            ('c', self.n_mels),
+            ('m', 2 * self.n_audio_positions),
+            ('a', self.n_audio_positions),
+            ('n', self.n_text_positions),
+        ]
+    }
 
-    fn forward(&self, input: GraphTensor<Sh>) -> Self::Output {
-        let gate = input.matmul(self.gate_proj.permute()).swish();
-        let up = input.matmul(self.up_proj.permute()) * gate;
-        up.matmul(self.down_proj.permute())
+    /// Set the model's dynamic dimensions on a graph
+    pub fn set_dims(&self, cx: &mut Graph) {
+        for (dim, size) in self.dims() {
+            cx.set_dyn_dim(dim, size);
+        }
     }
 }
 
-impl<const I: usize, const H: usize> InitModule for Mlp<I, H> {
-    fn initialize(cx: &mut Graph) -> Self {
+/// A 1D convolution with a kernel of 3, a stride of 1 and padding of 1, over inputs laid out (channels, frames)
+pub struct Conv1D<In: Dimension, Out: Dimension> {
+    pub weight: GraphTensor<(Out, In, Const<3>)>,
+    pub bias: GraphTensor<(Out,)>,
+}
+
+impl<In: Dimension, Out: Dimension> Conv1D<In, Out> {
+    pub fn new(cx: &mut Graph) -> Self {
         Self {
-            gate_proj: cx.named_tensor("Gate Weight"),
-            up_proj: cx.named_tensor("Up Weight"),
-            down_proj: cx.named_tensor("Down Weight"),
+            weight: cx.named_tensor("Conv Weight"),
+            bias: cx.named_tensor("Conv Bias"),
         }
     }
+
+    pub fn forward<Frames: Dimension>(
+        &self,
+        input: GraphTensor<(In, Frames)>,
+    ) -> GraphTensor<(Out, Frames)> {
+        // Each kernel position sees the input shifted by a frame, with zeros past the edges, so the convolution is the
+        // sum of one matmul per kernel position
+        let shifted: [GraphTensor<(In, Frames)>; 3] = [
+            input
+                .pad::<(In, Frames), _, _>(&[(0, 0), (1, 0)])
+                .slice((.., ..Frames::const_size()))
+                .realize(),
+            input,
+            input
+                .pad::<(In, Frames), _, _>(&[(0, 0), (0, 1)])
+                .slice((.., Expression::from(1)..))
+                .realize(),
+        ];
+        shifted
+            .into_iter()
+            .enumerate()
+            .map(|(k, x)| {
+                self.weight
+                    .slice((.., .., Expression::from(k)..Expression::from(k + 1)))
+                    .contiguous()
+                    .reshape::<(Out, In)>()
+                    .matmul(x)
+            })
+            .reduce(|a, b| a + b)
+            .unwrap()
+            + self.bias.expand()
+    }
 }
 
-impl<const I: usize, const H: usize> SerializeModule for Mlp<I, H> {
+impl<In: Dimension, Out: Dimension> SerializeModule for Conv1D<In, Out> {
     fn serialize(&self, s: &mut Serializer) {
-        s.tensor("ffn_gate/weight", self.gate_proj);
-        s.tensor("ffn_up/weight", self.up_proj);
-        s.tensor("ffn_down/weight", self.down_proj);
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
     }
 }
 
-fn apply_rotary_embeddings_ggml<const N_HEADS: usize, Batch: Dimension, Seq: Dimension>(
-    input: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)>,
-    prev_seq: BigExpression,
-) -> GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)> {
-    // Get freqs
-    let freqs = (input.graph().arange::<Const<HEAD_DIM_OVER_2>>() * 2.0) / (HEAD_DIM as f32);
-    let freqs = 1000000_f32.pow(freqs);
-    let pos = input.graph().arange::<Seq>() + prev_seq;
-    let emb = pos.expand::<(_, Const<1>), _>().matmul(freqs.expand());
-
-    // Split input into evens and odds
-    let split = input.reshape::<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<2>)>();
-    let x0: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<1>)> = split
-        .slice((.., .., .., .., ..Expression::from(1)))
-        .contiguous()
-        .realize();
-    let x1: GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<1>)> = split
-        .slice((.., .., .., .., Expression::from(1)..))
-        .contiguous()
-        .realize();
+/// Key and value projections, split into heads
+pub struct KeyValue {
+    pub key: DynPermutedLinear<Hidden, Hidden>,
+    pub value: DynPermutedLinear<Hidden, Hidden>,
+}
 
-    // Apply sin and cos embeddings
-    let x0_out = x0 * emb.cos().expand() - x1 * emb.sin().expand();
-    let x1_out = x0 * emb.sin().expand() + x1 * emb.cos().expand();
+impl KeyValue {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            key: DynPermutedLinear::new(false, cx),
+            value: DynPermutedLinear::new(true, cx),
+        }
+    }
+}
 
-    // Combine back into output
-    x0_out
-        .concat_along::<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM_OVER_2>, Const<2>), Axis<4>, _>(
-            x1_out,
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for KeyValue {
+    type Output = KVCache<Batch, Seq>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        (
+            split_heads(self.key.forward(input)),
+            split_heads(self.value.forward(input)),
         )
-        .reshape()
+    }
 }
 
-pub struct SelfAttention {
-    pub q_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
-    pub k_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub v_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub o_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
+impl SerializeModule for KeyValue {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("k_proj", &self.key);
+        s.module("v_proj", &self.value);
+    }
 }
 
-impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+fn split_heads<Batch: Dimension, Seq: Dimension>(
+    x: GraphTensor<(Batch, Seq, Hidden)>,
+) -> GraphTensor<(Batch, Heads, Seq, HeadDim)> {
+    x.reshape::<(Batch, Seq, Heads, HeadDim)>()
+        .permute::<_, Axes4<0, 2, 1, 3>>()
+        // Keys and values need to be contiguous for transferring to another graph
+        .contiguous()
+}
+
+/// The query and output projections of an attention layer. The keys and values come from a `KeyValue`, which for
+/// cross attention runs in the encoder graph.
+pub struct Attention {
+    pub query: DynPermutedLinear<Hidden, Hidden>,
+    pub output: DynPermutedLinear<Hidden, Hidden>,
+}
+
+impl Attention {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            query: DynPermutedLinear::new(true, cx),
+            output: DynPermutedLinear::new(true, cx),
+        }
+    }
+}
+
+impl<Batch: Dimension, CurSeq: Dimension, KeySeq: Dimension>
     Module<(
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        Option<KVCache<Batch, PrevSeq>>,
-        PhantomData<TotSeq>,
-    )> for SelfAttention
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, KeySeq>,
+        AttentionMask<Batch, CurSeq, KeySeq>,
+    )> for Attention
 {
-    type Output = (
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        KVCache<Batch, TotSeq>,
-    );
+    type Output = GraphTensor<(Batch, CurSeq, Hidden)>;
+
     fn forward(
         &self,
-        (x, cache, _): (
-            GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-            Option<KVCache<Batch, PrevSeq>>,
-            PhantomData<TotSeq>,
+        (x, (keys, values), mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, KeySeq>,
+            AttentionMask<Batch, CurSeq, KeySeq>,
         ),
     ) -> Self::Output {
-        // Apply the Projections
-        let queries = x
-            .matmul(self.q_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-        let keys = x
-            .matmul(self.k_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-        let values = x
-            .matmul(self.v_proj.permute())
-            .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
-            .permute::<_, Axes4<0, 2, 1, 3>>();
-
-        // Rotary embed queries and keys
-        let queries = apply_rotary_embeddings_ggml(queries, PrevSeq::const_size().into());
-        let keys = apply_rotary_embeddings_ggml(keys, PrevSeq::const_size().into());
-
-        // Add KV cache
-        let (keys, values) = if let Some((k_cache, v_cache)) = cache {
-            (
-                k_cache.concat_along::<_, Axis<2>, _>(keys),
-                v_cache.concat_along::<_, Axis<2>, _>(values),
-            )
-        } else {
-            (keys.realize(), values.contiguous().realize())
-        };
-
-        // Repeat the KV States for Grouped-Query Attention
-        let repeated_keys = keys.expand::<(_, _, Const<N_ATTENTION_GROUPS>, _, _), _>();
-        let repeated_values = values.expand::<(_, _, Const<N_ATTENTION_GROUPS>, _, _), _>();
-
-        // Calculate attention weights
-        let mut attention_weights = queries
-            .reshape::<(_, Const<N_KV_HEADS>, Const<N_ATTENTION_GROUPS>, _, _)>() // Split query heads into groups
-            .matmul(repeated_keys.permute())
-            .div((HEAD_DIM as f32).sqrt());
-
-        let attention_mask = self.k_proj.graph().triu::<CurSeq>(1) * f16::MIN.to_f32();
-        attention_weights += attention_mask
-            .pad::<(CurSeq, TotSeq), _, _>(&[
-                (0.into(), Expression::from(0)),
-                (TotSeq::const_size() - CurSeq::const_size(), 0.into()),
-            ])
-            .expand();
-
-        // Calculate final outputs
-        let output = attention_weights
-            .softmax::<Axis<4>>()
-            // Apply distribution to values
-            .matmul(repeated_values)
+        let queries = split_heads(self.query.forward(x));
+        let output = grouped_query_attention(queries, keys, values, mask)
             // Merge heads
-            .permute::<_, Axes5<0, 3, 1, 2, 4>>()
-            .reshape::<(Batch, CurSeq, Const<HIDDEN_DIM>)>();
-        let output = output
-            // Apply output projection
-            .matmul(self.o_proj.permute());
-        (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(Batch, CurSeq, Hidden)>();
+        self.output.forward(output)
     }
 }
 
-impl InitModule for SelfAttention {
-    fn initialize(cx: &mut Graph) -> Self {
+impl SerializeModule for Attention {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("q_proj", &self.query);
+        s.module("out_proj", &self.output);
+    }
+}
+
+pub struct Mlp {
+    pub fc1: DynPermutedLinear<Hidden, MlpDim>,
+    pub fc2: DynPermutedLinear<MlpDim, Hidden>,
+    pub norm: DynLayerNorm<Hidden>,
+}
+
+impl Mlp {
+    pub fn new(cx: &mut Graph) -> Self {
         Self {
-            q_proj: cx.named_tensor("Q Proj"),
-            k_proj: cx.named_tensor("K Proj"),
-            v_proj: cx.named_tensor("V Proj"),
-            o_proj: cx.named_tensor("O Proj"),
+            fc1: DynPermutedLinear::new(true, cx),
+            fc2: DynPermutedLinear::new(true, cx),
+            norm: DynLayerNorm::new(true, 1e-5, cx),
         }
     }
 }
 
-impl SerializeModule for SelfAttention {
-    fn serialize(&self, s: &mut Serializer) {
-        s.tensor("attn_q/weight", self.q_proj);
-        s.tensor("attn_v/weight", self.v_proj);
-        s.tensor("attn_k/weight", self.k_proj);
-        s.tensor("attn_output/weight", self.o_proj);
+impl<Batch: Dimension, Seq: Dimension> Module<GraphTensor<(Batch, Seq, Hidden)>> for Mlp {
+    type Output = GraphTensor<(Batch, Seq, Hidden)>;
+
+    fn forward(&self, input: GraphTensor<(Batch, Seq, Hidden)>) -> Self::Output {
+        self.fc2
+            .forward(self.fc1.forward(self.norm.forward(input)).gelu())
     }
 }
 
-pub struct TransformerBlock {
-    pub attention: SelfAttention,
-    pub attention_norm: RMSNorm<HIDDEN_DIM>,
-    pub feed_forward: Mlp<MLP_DIM, HIDDEN_DIM>,
-    pub feed_forward_norm: RMSNorm<HIDDEN_DIM>,
+impl SerializeModule for Mlp {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("fc1", &self.fc1);
+        s.module("fc2", &self.fc2);
+        s.module("final_layer_norm", &self.norm);
+    }
 }
 
-impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
-    Module<(
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        Option<KVCache<Batch, PrevSeq>>,
-        PhantomData<TotSeq>,
-    )> for TransformerBlock
-{
-    type Output = (
-        GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-        KVCache<Batch, TotSeq>,
-    );
-    fn forward(
-        &self,
-        (mut x, cache, _): (
-            GraphTensor<(Batch, CurSeq, Const<HIDDEN_DIM>)>,
-            Option<KVCache<Batch, PrevSeq>>,
-            PhantomData<TotSeq>,
-        ),
-    ) -> Self::Output {
-        // Attention
-        let normed = self.attention_norm.forward(x);
-        let (y, cache) = self
-            .attention
-            .forward((normed, cache, PhantomData::<TotSeq>));
+pub struct EncoderLayer {
+    pub attention: Attention,
+    pub attention_kv: KeyValue,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub mlp: Mlp,
+}
 
-        // Residual Addition
-        x += y;
+impl EncoderLayer {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            attention: Attention::new(cx),
+            attention_kv: KeyValue::new(cx),
+            attention_norm: DynLayerNorm::new(true, 1e-5, cx),
+            mlp: Mlp::new(cx),
+        }
+    }
+}
 
-        // Feed Forward
-        let y = self.feed_forward.forward(self.feed_forward_norm.forward(x));
+impl<Batch: Dimension> Module<GraphTensor<(Batch, AudioSeq, Hidden)>> for EncoderLayer {
+    type Output = GraphTensor<(Batch, AudioSeq, Hidden)>;
+
+    fn forward(&self, x: GraphTensor<(Batch, AudioSeq, Hidden)>) -> Self::Output {
+        // Pre-norm attention and MLP, each with a residual connection. Every frame sees every other frame.
+        let h = self.attention_norm.forward(x);
+        let x =
+            x + self
+                .attention
+                .forward((h, self.attention_kv.forward(h), AttentionMask::none()));
+        x + self.mlp.forward(x)
+    }
+}
 
-        // Residual Addition
-        (x + y, cache)
+impl SerializeModule for EncoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("self_attn", &self.attention_kv);
+        s.module("self_attn_layer_norm", &self.attention_norm);
+        s.module("", &self.mlp);
     }
 }
 
-impl InitModule for TransformerBlock {
-    fn initialize(cx: &mut Graph) -> Self {
+/// The audio encoder, along with the decoder's cross attention key and value projections so each chunk's cross
+/// attention caches are computed once here, rather than on every decoding step
+pub struct Encoder {
+    pub conv1: Conv1D<MelBins, Hidden>,
+    pub conv2: Conv1D<Hidden, Hidden>,
+    /// Sinusoidal position embeddings, stored with the weights
+    pub position_embedding: GraphTensor<(AudioSeq, Hidden)>,
+    pub layers: Vec<EncoderLayer>,
+    pub norm: DynLayerNorm<Hidden>,
+    pub cross_attention_kv: Vec<KeyValue>,
+}
+
+impl Encoder {
+    /// Build the encoder for a config. The config's dims must also be set on the graph with `WhisperConfig::set_dims`.
+    pub fn new(config: &WhisperConfig, cx: &mut Graph) -> Self {
         Self {
-            attention: InitModule::initialize(cx),
-            attention_norm: {
-                let mut norm = RMSNorm::initialize(cx);
-                norm.epsilon = 1e-5;
-                norm
-            },
-            feed_forward: InitModule::initialize(cx),
-            feed_forward_norm: {
-                let mut norm = RMSNorm::initialize(cx);
-                norm.epsilon = 1e-5;
-                norm
-            },
+            conv1: Conv1D::new(cx),
+            conv2: Conv1D::new(cx),
+            position_embedding: cx.named_tensor("Position Embedding"),
+            layers: (0..config.encoder_layers)
+                .map(|_| EncoderLayer::new(cx))
+                .collect(),
+            norm: DynLayerNorm::new(true, 1e-5, cx),
+            cross_attention_kv: (0..config.decoder_layers)
+                .map(|_| KeyValue::new(cx))
+                .collect(),
         }
     }
 }
 
-impl SerializeModule for TransformerBlock {
+impl Module<GraphTensor<(MelBins, MelFrames)>> for Encoder {
+    /// The cross attention keys and values for each decoder layer
+    type Output = Vec<KVCache<Const<1>, AudioSeq>>;
+
+    fn forward(&self, mel: GraphTensor<(MelBins, MelFrames)>) -> Self::Output {
+        // Two convolutions, the second with a stride of 2 to halve the frame rate. A stride of 2 is the same as a
+        // stride of 1 keeping every other frame.
+        let x = self
+            .conv2
+            .forward(self.conv1.forward(mel).gelu())
+            .reshape::<(Hidden, AudioSeq, Const<2>)>()
+            .slice((.., .., ..Expression::from(1)))
+            .contiguous()
+            .reshape::<(Hidden, AudioSeq)>()
+            .gelu();
+        // Add positions
+        let x = x.permute::<_, Axes2<1, 0>>() + self.position_embedding;
+        let mut x = x.expand::<(Const<1>, AudioSeq, Hidden), _>();
+        for layer in &self.layers {
+            x = layer.forward(x);
+        }
+        let x = self.norm.forward(x);
+        self.cross_attention_kv
+            .iter()
+            .map(|kv| kv.forward(x))
+            .collect()
+    }
+}
+
+impl SerializeModule for Encoder {
     fn serialize(&self, s: &mut Serializer) {
-        s.module("", &self.attention);
-        s.module("attn_norm", &self.attention_norm);
-        s.module("ffn_norm", &self.feed_forward_norm);
-        s.module("", &self.feed_forward);
+        s.module("model/encoder/conv1", &self.conv1);
+        s.module("model/encoder/conv2", &self.conv2);
+        s.tensor(
+            "model/encoder/embed_positions/weight",
+            self.position_embedding,
+        );
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("model/encoder/layers/{i}"), layer);
+        }
+        s.module("model/encoder/layer_norm", &self.norm);
+        for (i, kv) in self.cross_attention_kv.iter().enumerate() {
+            s.module(&format!("model/decoder/layers/{i}/encoder_attn"), kv);
+        }
     }
 }
 
-pub struct MistralLM {
-    // Token embeddings
-    pub embedding: Embedding<VOCAB_SIZE, HIDDEN_DIM>,
-    // Transformer layers
-    pub layers: Vec<TransformerBlock>,
-    // Final Norm layer
-    pub norm: RMSNorm<HIDDEN_DIM>,
-    // LM Head Layer
-    pub lm_head: GraphTensor<R2<VOCAB_SIZE, HIDDEN_DIM>>,
+pub struct DecoderLayer {
+    pub attention: Attention,
+    pub attention_kv: KeyValue,
+    pub attention_norm: DynLayerNorm<Hidden>,
+    pub cross_attention: Attention,
+    pub cross_attention_norm: DynLayerNorm<Hidden>,
+    pub mlp: Mlp,
+}
+
+impl DecoderLayer {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            attention: Attention::new(cx),
+            attention_kv: KeyValue::new(cx),
+            attention_norm: DynLayerNorm::new(true, 1e-5, cx),
+            cross_attention: Attention::new(cx),
+            cross_attention_norm: DynLayerNorm::new(true, 1e-5, cx),
+            mlp: Mlp::new(cx),
+        }
+    }
 }
 
 impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
     Module<(
-        GraphTensor<(Batch, CurSeq)>,
-        Option<Vec<KVCache<Batch, PrevSeq>>>,
-        PhantomData<TotSeq>,
-    )> for MistralLM
+        GraphTensor<(Batch, CurSeq, Hidden)>,
+        KVCache<Batch, PrevSeq>,
+        KVCache<Batch, AudioSeq>,
+        AttentionMask<Batch, CurSeq, TotSeq>,
+    )> for DecoderLayer
+{
+    type Output = (GraphTensor<(Batch, CurSeq, Hidden)>, KVCache<Batch, TotSeq>);
+
+    fn forward(
+        &self,
+        (mut x, (k_cache, v_cache), cross, mask): (
+            GraphTensor<(Batch, CurSeq, Hidden)>,
+            KVCache<Batch, PrevSeq>,
+            KVCache<Batch, AudioSeq>,
+            AttentionMask<Batch, CurSeq, TotSeq>,
+        ),
+    ) -> Self::Output {
+        // Causal self attention over the transcript so far
+        let h = self.attention_norm.forward(x);
+        let (keys, values) = self.attention_kv.forward(h);
+        let keys = k_cache.concat_along::<_, Axis<2>, _>(keys);
+        let values = v_cache.concat_along::<_, Axis<2>, _>(values);
+        x += self.attention.forward((h, (keys, values), mask));
+        // Cross attention over the whole chunk of audio
+        x += self.cross_attention.forward((
+            self.cross_attention_norm.forward(x),
+            cross,
+            AttentionMask::none(),
+        ));
+        (
+            x + self.mlp.forward(x),
+            (keys.contiguous(), values.contiguous()),
+        )
+    }
+}
+
+impl SerializeModule for DecoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("self_attn", &self.attention);
+        s.module("self_attn", &self.attention_kv);
+        s.module("self_attn_layer_norm", &self.attention_norm);
+        s.module("encoder_attn", &self.cross_attention);
+        s.module("encoder_attn_layer_norm", &self.cross_attention_norm);
+        s.module("", &self.mlp);
+    }
+}
+
+pub struct Decoder {
+    /// Token embeddings, which are also used as the LM head
+    pub token_embedding: GraphTensor<(Vocab, Hidden)>,
+    /// Learned position embeddings
+    pub position_embedding: GraphTensor<(TextPositions, Hidden)>,
+    pub layers: Vec<DecoderLayer>,
+    pub norm: DynLayerNorm<Hidden>,
+}
+
+impl Decoder {
+    /// Build the decoder for a config. The config's dims must also be set on the graph with `WhisperConfig::set_dims`.
+    pub fn new(config: &WhisperConfig, cx: &mut Graph) -> Self {
+        Self {
+            token_embedding: cx.named_tensor("Token Embedding"),
+            position_embedding: cx.named_tensor("Position Embedding"),
+            layers: (0..config.decoder_layers)
+                .map(|_| DecoderLayer::new(cx))
+                .collect(),
+            norm: DynLayerNorm::new(true, 1e-5, cx),
+        }
+    }
+}
+
+/// The new tokens, the self attention caches, the cross attention caches from the `Encoder`, and the causal mask
+pub type DecoderInput<'a, Batch, CurSeq, PrevSeq, TotSeq> = (
+    GraphTensor<(Batch, CurSeq)>,
+    &'a [KVCache<Batch, PrevSeq>],
+    &'a [KVCache<Batch, AudioSeq>],
+    AttentionMask<Batch, CurSeq, TotSeq>,
+);
+
+impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
+    Module<DecoderInput<'_, Batch, CurSeq, PrevSeq, TotSeq>> for Decoder
 {
     type Output = (
-        GraphTensor<(Batch, CurSeq, Const<VOCAB_SIZE>)>,
+        GraphTensor<(Batch, CurSeq, Vocab)>,
         Vec<KVCache<Batch, TotSeq>>,
     );
+
     fn forward(
         &self,
-        (input, cache, _): (
-            GraphTensor<(Batch, CurSeq)>,
-            Option<Vec<KVCache<Batch, PrevSeq>>>,
-            PhantomData<TotSeq>,
-        ),
+        (input, cache, cross, mask): DecoderInput<Batch, CurSeq, PrevSeq, TotSeq>,
     ) -> Self::Output {
-        // Embed tokens
-        let mut x = self.embedding.forward(input);
+        // Embed tokens and their positions, which continue on from the cached tokens
+        let positions = unsafe { input.with_graph(|cx| cx.arange::<CurSeq>()) }
+            + unsafe { input.with_graph(|cx| cx.constant_expr(PrevSeq::const_size())) }
+                .expand::<(CurSeq,), _>();
+        let mut x = self.token_embedding.index_select::<_, Axis<0>, _>(input)
+            + self
+                .position_embedding
+                .index_select::<(CurSeq, Hidden), Axis<0>, _>(positions)
+                .expand::<(Batch, CurSeq, Hidden), Axis<0>>();
 
         // Run through layers and collect new caches
         let mut new_caches = vec![];
         let mut new_cache;
         for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) =
-                layer.forward((x, cache.as_ref().map(|c| c[i]), PhantomData::<TotSeq>));
+            (x, new_cache) = layer.forward((x, cache[i], cross[i], mask));
             new_caches.push(new_cache);
         }
-        // Run through last norm and output projection
-        let output = self.norm.forward(x).matmul(self.lm_head.permute());
+        // Run through the last norm, and project back to the vocab with the tied embeddings
+        let output = self.norm.forward(x).matmul(self.token_embedding.permute());
 
         (output, new_caches)
     }
 }
 
-impl InitModule for MistralLM {
-    fn initialize(cx: &mut Graph) -> Self {
-        Self {
-            embedding: InitModule::initialize(cx),
-            norm: {
-                let mut norm = RMSNorm::initialize(cx);
-                norm.epsilon = 1e-5;
-                norm
-            },
-            lm_head: cx.named_tensor("LM Head"),
-            layers: (0..NUM_LAYERS)
-                .map(|_| InitModule::initialize(cx))
-                .collect(),
-        }
-    }
-}
-
-impl SerializeModule for MistralLM {
+impl SerializeModule for Decoder {
     fn serialize(&self, s: &mut Serializer) {
-        s.module("token_embd", &self.embedding);
-        s.module("output_norm", &self.norm);
-        s.tensor("output/weight", self.lm_head);
+        s.tensor("model/decoder/embed_tokens/weight", self.token_embedding);
+        s.tensor(
+            "model/decoder/embed_positions/weight",
+            self.position_embedding,
+        );
         for (i, layer) in self.layers.iter().enumerate() {
-            s.module(&format!("blk/{i}"), layer);
+            s.module(&format!("model/decoder/layers/{i}"), layer);
         }
+        s.module("model/decoder/layer_norm", &self.norm);
     }
 }
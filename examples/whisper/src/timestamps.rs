@@ -0,0 +1,145 @@
+use luminal::generate::Sampler;
+
+/// Each timestamp token is 20ms after the previous one
+pub const SECONDS_PER_TIMESTAMP: f32 = 0.02;
+/// The first timestamp can be at most 1 second into the chunk
+const MAX_INITIAL_TIMESTAMP: usize = 50;
+
+/// Greedy sampling with Whisper's timestamp rules, which keep the sampled timestamps well formed:
+/// - Timestamps come in pairs around each segment of text, except the first and last ones
+/// - Timestamps never go backwards, and the first one is near the start of the chunk
+/// - A timestamp is sampled whenever all the timestamps together are more likely than any text token
+///
+/// Without timestamps, only text tokens and the end of text token are sampled.
+pub struct TimestampRules {
+    pub end_of_text: u32,
+    /// The id of `<|0.00|>`. Every token from here on is a timestamp.
+    pub timestamp_begin: u32,
+    pub timestamps: bool,
+    /// The tokens sampled so far, not including the prompt
+    sampled: Vec<u32>,
+}
+
+impl TimestampRules {
+    pub fn new(end_of_text: u32, timestamp_begin: u32, timestamps: bool) -> Self {
+        Self {
+            end_of_text,
+            timestamp_begin,
+            timestamps,
+            sampled: vec![],
+        }
+    }
+
+    /// Mask out the tokens the rules don't allow next
+    pub fn apply(&self, logits: &mut [f32]) {
+        let (eot, begin) = (self.end_of_text as usize, self.timestamp_begin as usize);
+        let len = logits.len();
+        let begin = begin.min(len);
+        let mut suppress = |range: std::ops::Range<usize>| {
+            logits[range.start.min(len)..range.end.min(len)].fill(f32::NEG_INFINITY)
+        };
+        // Never sample the special tokens between the end of text and the timestamps, like the language and task
+        suppress(eot + 1..begin);
+        if !self.timestamps {
+            suppress(begin..usize::MAX);
+            return;
+        }
+
+        let is_timestamp = |t: &u32| *t as usize >= begin;
+        let last_was_timestamp = self.sampled.last().is_some_and(is_timestamp);
+        let penultimate_was_timestamp =
+            self.sampled.len() < 2 || is_timestamp(&self.sampled[self.sampled.len() - 2]);
+        if last_was_timestamp {
+            if penultimate_was_timestamp {
+                // A pair of timestamps was just closed, so text comes next
+                suppress(begin..usize::MAX);
+            } else {
+                // A segment of text just ended, so it needs a closing timestamp
+                suppress(0..eot);
+            }
+        }
+        if let Some(last) = self.sampled.iter().rev().find(|t| is_timestamp(t)) {
+            // A segment's end can share its start's timestamp, otherwise timestamps must increase
+            let min = if last_was_timestamp && !penultimate_was_timestamp {
+                *last as usize
+            } else {
+                *last as usize + 1
+            };
+            suppress(begin..min);
+        }
+        if self.sampled.is_empty() {
+            suppress(0..begin);
+            suppress(begin + MAX_INITIAL_TIMESTAMP + 1..usize::MAX);
+        }
+
+        // If all the timestamps together are more likely than any single text token, sample a timestamp
+        let log_sum_exp = |l: &[f32]| {
+            let max = l.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            if max == f32::NEG_INFINITY {
+                return max;
+            }
+            max + l.iter().map(|x| (x - max).exp()).sum::<f32>().ln()
+        };
+        let timestamp_logprob = log_sum_exp(&logits[begin..]);
+        let max_text_logprob = logits[..begin]
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        if timestamp_logprob > max_text_logprob {
+            logits[..begin].fill(f32::NEG_INFINITY);
+        }
+    }
+}
+
+impl Sampler for TimestampRules {
+    fn sample(&mut self, logits: &[f32]) -> u32 {
+        let mut logits = logits.to_vec();
+        self.apply(&mut logits);
+        let token = logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i as u32)
+            .unwrap_or(self.end_of_text);
+        self.sampled.push(token);
+        token
+    }
+}
+
+/// A span of transcribed text, with its start and end in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub start: f32,
+    pub end: f32,
+    pub tokens: Vec<u32>,
+}
+
+/// Split the tokens of a chunk into the segments between its timestamps, offsetting the times by the start of the
+/// chunk. Text after the last timestamp runs to `chunk_end`.
+pub fn segments(tokens: &[u32], timestamp_begin: u32, offset: f32, chunk_end: f32) -> Vec<Segment> {
+    let time = |t: u32| offset + (t - timestamp_begin) as f32 * SECONDS_PER_TIMESTAMP;
+    let mut segments = vec![];
+    let (mut start, mut text) = (None, vec![]);
+    for &token in tokens {
+        if token < timestamp_begin {
+            text.push(token);
+        } else if let (Some(s), false) = (start, text.is_empty()) {
+            segments.push(Segment {
+                start: s,
+                end: time(token),
+                tokens: std::mem::take(&mut text),
+            });
+            start = None;
+        } else {
+            start = Some(time(token));
+        }
+    }
+    if !text.is_empty() {
+        segments.push(Segment {
+            start: start.unwrap_or(offset),
+            end: chunk_end,
+            tokens: text,
+        });
+    }
+    segments
+}
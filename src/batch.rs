@@ -0,0 +1,170 @@
+/// A batch of token sequences of different lengths, left padded to the same length so they can run through one graph
+/// with a `Batch` dimension.
+///
+/// Padding goes on the left so every sequence's newest token is in the last position, which keeps generated tokens
+/// aligned across the batch as they're appended. Padding positions should be masked out of attention with
+/// `attention_mask` or `key_mask`, and models with position embeddings should use `positions` so each sequence's
+/// first real token is position 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaddedBatch {
+    /// The tokens of each sequence, padded, laid out (batch, seq)
+    pub tokens: Vec<u32>,
+    /// The number of real (non-padding) tokens in each sequence
+    pub lengths: Vec<usize>,
+    pub seq_len: usize,
+    pub pad_token: u32,
+}
+
+impl PaddedBatch {
+    /// Left pad sequences to the length of the longest one
+    pub fn left_padded<T: AsRef<[u32]>>(sequences: &[T], pad_token: u32) -> Self {
+        let lengths = sequences
+            .iter()
+            .map(|s| s.as_ref().len())
+            .collect::<Vec<_>>();
+        let seq_len = lengths.iter().copied().max().unwrap_or_default();
+        let mut tokens = Vec::with_capacity(seq_len * sequences.len());
+        for seq in sequences {
+            let seq = seq.as_ref();
+            tokens.extend(std::iter::repeat_n(pad_token, seq_len - seq.len()));
+            tokens.extend_from_slice(seq);
+        }
+        Self {
+            tokens,
+            lengths,
+            seq_len,
+            pad_token,
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// How many padding tokens are in front of a sequence
+    pub fn padding(&self, sequence: usize) -> usize {
+        self.seq_len - self.lengths[sequence]
+    }
+
+    /// A sequence's tokens without padding
+    pub fn sequence(&self, sequence: usize) -> &[u32] {
+        let start = sequence * self.seq_len;
+        &self.tokens[start + self.padding(sequence)..start + self.seq_len]
+    }
+
+    /// The tokens as data for an input tensor shaped (batch, seq)
+    pub fn token_data(&self) -> Vec<f32> {
+        self.tokens.iter().map(|t| *t as f32).collect()
+    }
+
+    /// The position of each token counting from its sequence's first real token, shaped (batch, seq). Padding is
+    /// given position 0.
+    pub fn positions(&self) -> Vec<f32> {
+        (0..self.batch_size())
+            .flat_map(|b| {
+                let padding = self.padding(b);
+                (0..self.seq_len).map(move |i| i.saturating_sub(padding) as f32)
+            })
+            .collect()
+    }
+
+    /// 1 for real tokens and 0 for padding, shaped (batch, seq), to mask padded keys out of attention
+    pub fn key_mask(&self) -> Vec<f32> {
+        (0..self.batch_size())
+            .flat_map(|b| {
+                let padding = self.padding(b);
+                (0..self.seq_len).map(move |i| if i < padding { 0. } else { 1. })
+            })
+            .collect()
+    }
+
+    /// A padding mask for the last `queries` tokens attending to all tokens, shaped (batch, 1, queries, seq). It's 1
+    /// where a query may attend to a key, which is for every real key, and for padding queries also themselves so
+    /// their attention rows aren't empty. Causality is left to a separate mask.
+    pub fn attention_mask(&self, queries: usize) -> Vec<f32> {
+        assert!(
+            queries <= self.seq_len,
+            "Can't have more queries than tokens"
+        );
+        let mut mask = Vec::with_capacity(self.batch_size() * queries * self.seq_len);
+        for b in 0..self.batch_size() {
+            let padding = self.padding(b);
+            for q in self.seq_len - queries..self.seq_len {
+                mask.extend((0..self.seq_len).map(|k| (k >= padding || k == q) as u8 as f32));
+            }
+        }
+        mask
+    }
+
+    /// Add a token to the end of every sequence, like the ones just generated for each
+    pub fn push(&mut self, tokens: &[u32]) {
+        assert_eq!(
+            tokens.len(),
+            self.batch_size(),
+            "Need one token for each sequence"
+        );
+        let seq_len = self.seq_len;
+        self.tokens = self
+            .tokens
+            .chunks(seq_len.max(1))
+            .take(self.batch_size())
+            .zip(tokens)
+            .flat_map(|(seq, t)| seq[..seq_len].iter().chain(std::iter::once(t)).copied())
+            .collect();
+        self.seq_len += 1;
+        for l in &mut self.lengths {
+            *l += 1;
+        }
+    }
+
+    /// Split logits shaped (batch, seq, vocab) into the next-token logits of each sequence, which with left padding
+    /// are always at the last position
+    pub fn last_logits(&self, logits: &[f32]) -> Vec<Vec<f32>> {
+        self.gather_logits(logits, &vec![self.seq_len - 1; self.batch_size()])
+    }
+
+    /// Pick the logits at one position of each sequence out of logits shaped (batch, seq, vocab)
+    pub fn gather_logits(&self, logits: &[f32], positions: &[usize]) -> Vec<Vec<f32>> {
+        let rows = self.batch_size() * self.seq_len;
+        assert!(
+            rows > 0 && logits.len().is_multiple_of(rows),
+            "Logits don't have a row for every token"
+        );
+        let vocab = logits.len() / rows;
+        positions
+            .iter()
+            .enumerate()
+            .map(|(b, p)| {
+                let start = (b * self.seq_len + p) * vocab;
+                logits[start..start + vocab].to_vec()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PaddedBatch;
+
+    #[test]
+    fn test_padded_batch() {
+        let mut batch = PaddedBatch::left_padded(&[vec![5, 6, 7], vec![8]], 0);
+        assert_eq!(batch.tokens, [5, 6, 7, 0, 0, 8]);
+        assert_eq!(batch.sequence(1), [8]);
+        assert_eq!(batch.positions(), [0., 1., 2., 0., 0., 0.]);
+        assert_eq!(batch.key_mask(), [1., 1., 1., 0., 0., 1.]);
+        // Padding queries attend to themselves, real ones to every real key
+        assert_eq!(
+            batch.attention_mask(3),
+            [[1.; 9].as_slice(), &[1., 0., 1., 0., 1., 1., 0., 0., 1.]].concat()
+        );
+
+        let logits = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+        assert_eq!(batch.last_logits(&logits), [vec![4., 5.], vec![10., 11.]]);
+
+        batch.push(&[9, 10]);
+        assert_eq!(batch.tokens, [5, 6, 7, 9, 0, 0, 8, 10]);
+        assert_eq!(batch.sequence(1), [8, 10]);
+        assert_eq!(batch.attention_mask(1), [1., 1., 1., 1., 0., 0., 1., 1.]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::cmp::Ordering;
+
+/// A model that can score the next token for a batch of beams
+pub trait BeamModel {
+    /// The state kept for each beam between steps, like its KV cache. A beam's state is cloned when it's extended
+    /// with more than one token.
+    type State: Clone;
+
+    /// Create the state for a new sequence
+    fn new_state(&mut self) -> Self::State;
+
+    /// Run one step over a batch of beams. Each beam is given the tokens it hasn't seen yet (the whole prompt on the
+    /// first step, then the last chosen token), and the next token logits are returned for each beam in batch order.
+    fn step(&mut self, beams: &mut [(&mut Self::State, &[u32])]) -> Vec<Vec<f32>>;
+}
+
+/// A finished sequence from a beam search
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, not including the prompt
+    pub tokens: Vec<u32>,
+    /// The total log-probability of the generated tokens
+    pub log_prob: f32,
+    /// The log-probability with the length penalty applied, which hypotheses are ranked by
+    pub score: f32,
+}
+
+struct Beam<S> {
+    state: S,
+    pending: Vec<u32>,
+    tokens: Vec<u32>,
+    log_prob: f32,
+}
+
+/// Beam search decoding over a `BeamModel`.
+///
+/// Every step, each running beam is extended with its most likely next tokens, and the best `beam_width` extensions
+/// across all beams are kept. Beams that produce a stop token become finished hypotheses.
+#[derive(Debug, Clone)]
+pub struct BeamSearch {
+    beam_width: usize,
+    length_penalty: f32,
+    stop_tokens: Vec<u32>,
+    early_stopping: bool,
+}
+
+impl BeamSearch {
+    pub fn new(beam_width: usize) -> Self {
+        assert!(beam_width > 0, "Beam width must be at least 1");
+        Self {
+            beam_width,
+            length_penalty: 1.0,
+            stop_tokens: vec![],
+            early_stopping: false,
+        }
+    }
+
+    /// Hypotheses are ranked by `log_prob / length^length_penalty`. Values above 0 favour longer sequences, and 0
+    /// ranks by raw log-probability. Defaults to 1.
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    /// Finish a beam once it generates one of these tokens. The stop token is kept in the hypothesis.
+    pub fn with_stop_tokens(mut self, stop_tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_tokens = stop_tokens.into_iter().collect();
+        self
+    }
+
+    /// Stop as soon as `beam_width` hypotheses are finished, rather than once no running beam can beat them
+    pub fn with_early_stopping(mut self, early_stopping: bool) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    fn score(&self, log_prob: f32, length: usize) -> f32 {
+        log_prob / (length.max(1) as f32).powf(self.length_penalty)
+    }
+
+    /// Generate up to `max_new_tokens` tokens after the prompt, returning the best hypotheses, best first
+    pub fn generate<M: BeamModel>(
+        &self,
+        model: &mut M,
+        prompt: Vec<u32>,
+        max_new_tokens: usize,
+    ) -> Vec<Hypothesis> {
+        let mut beams = vec![Beam {
+            state: model.new_state(),
+            pending: prompt,
+            tokens: vec![],
+            log_prob: 0.,
+        }];
+        let mut finished: Vec<Hypothesis> = vec![];
+        for _ in 0..max_new_tokens {
+            let mut batch = beams
+                .iter_mut()
+                .map(|b| (&mut b.state, b.pending.as_slice()))
+                .collect::<Vec<_>>();
+            let logits = model.step(&mut batch);
+            assert_eq!(
+                logits.len(),
+                beams.len(),
+                "Model should return logits for each beam"
+            );
+
+            // Only the top 2 * beam_width extensions can matter, since at most beam_width of them stop
+            let mut candidates = beams
+                .iter()
+                .zip(&logits)
+                .enumerate()
+                .flat_map(|(i, (beam, logits))| {
+                    log_softmax(logits)
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(token, lp)| (i, token as u32, beam.log_prob + lp))
+                })
+                .filter(|(_, _, lp)| !lp.is_nan())
+                .collect::<Vec<_>>();
+            candidates.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+            candidates.truncate(2 * self.beam_width);
+
+            let mut next = vec![];
+            for (rank, (i, token, log_prob)) in candidates.into_iter().enumerate() {
+                let mut tokens = beams[i].tokens.clone();
+                tokens.push(token);
+                if self.stop_tokens.contains(&token) {
+                    // Stops ranked below the beam width wouldn't have been kept as beams either
+                    if rank < self.beam_width {
+                        finished.push(Hypothesis {
+                            score: self.score(log_prob, tokens.len()),
+                            tokens,
+                            log_prob,
+                        });
+                    }
+                } else if next.len() < self.beam_width {
+                    next.push(Beam {
+                        state: beams[i].state.clone(),
+                        pending: vec![token],
+                        tokens,
+                        log_prob,
+                    });
+                }
+            }
+            beams = next;
+            if beams.is_empty() || self.is_done(&finished, &beams) {
+                break;
+            }
+        }
+
+        // Beams still running at the end count as hypotheses too
+        finished.extend(beams.into_iter().map(|b| Hypothesis {
+            score: self.score(b.log_prob, b.tokens.len()),
+            tokens: b.tokens,
+            log_prob: b.log_prob,
+        }));
+        finished.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        finished.truncate(self.beam_width);
+        finished
+    }
+
+    fn is_done<S>(&self, finished: &[Hypothesis], beams: &[Beam<S>]) -> bool {
+        if finished.len() < self.beam_width {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        // Done once the best running beam, scored at its current length, can't beat the worst kept hypothesis
+        let mut scores = finished.iter().map(|h| h.score).collect::<Vec<_>>();
+        scores.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        let worst_kept = scores[self.beam_width - 1];
+        beams
+            .iter()
+            .map(|b| self.score(b.log_prob, b.tokens.len()))
+            .all(|s| s <= worst_kept)
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    /// A fixed next token distribution over 3 tokens (2 is the stop token) given the tokens generated so far. The
+    /// likeliest first token leads nowhere, so greedy decoding misses the best sequence.
+    struct TableModel {
+        cx: Box<Graph>,
+        probs: GraphTensor<(Dyn<'b'>, Const<3>)>,
+        logits: GraphTensor<(Dyn<'b'>, Const<3>)>,
+        batch_sizes: Vec<usize>,
+    }
+
+    impl TableModel {
+        fn new() -> Self {
+            let mut cx = Box::new(Graph::new());
+            let probs = cx.named_tensor("Probs");
+            let logits = probs.ln().retrieve();
+            Self {
+                cx,
+                probs,
+                logits,
+                batch_sizes: vec![],
+            }
+        }
+    }
+
+    impl BeamModel for TableModel {
+        // The whole sequence seen so far
+        type State = Vec<u32>;
+
+        fn new_state(&mut self) -> Vec<u32> {
+            vec![]
+        }
+
+        fn step(&mut self, beams: &mut [(&mut Vec<u32>, &[u32])]) -> Vec<Vec<f32>> {
+            self.batch_sizes.push(beams.len());
+            let probs = beams
+                .iter_mut()
+                .flat_map(|(seen, tokens)| {
+                    seen.extend_from_slice(tokens);
+                    // Skip the one token prompt
+                    match &seen[1..] {
+                        [] => [0.6, 0.4, 1e-6],
+                        [0] => [0.34, 0.33, 0.33],
+                        [1] => [0.05, 0.05, 0.9],
+                        [0, 0] => [0.5, 0.2, 0.3],
+                        _ => [0.1, 0.1, 0.8],
+                    }
+                })
+                .collect::<Vec<_>>();
+            self.probs.set_dyn(probs, &[beams.len(), 3]);
+            self.cx.execute();
+            let logits = self.logits.data();
+            self.logits.drop();
+            logits.chunks(3).map(|c| c.to_vec()).collect()
+        }
+    }
+
+    #[test]
+    fn test_beam_search() {
+        let search = |width, length_penalty, early_stopping| {
+            let mut model = TableModel::new();
+            let hypotheses = BeamSearch::new(width)
+                .with_stop_tokens([2])
+                .with_length_penalty(length_penalty)
+                .with_early_stopping(early_stopping)
+                .generate(&mut model, vec![7], 10);
+            assert_eq!(hypotheses.len(), width);
+            assert!(hypotheses.windows(2).all(|h| h[0].score >= h[1].score));
+            (hypotheses, model.batch_sizes)
+        };
+
+        let (greedy, _) = search(1, 1.0, false);
+        assert_eq!(greedy[0].tokens, vec![0, 0, 0, 2]);
+
+        // Beams are run together, and the search stops once no running beam can beat the finished hypotheses
+        let (beams, batch_sizes) = search(2, 1.0, false);
+        assert_eq!(beams[0].tokens, vec![1, 2]);
+        assert!((beams[0].log_prob - (0.4_f32 * 0.9).ln()).abs() < 1e-4);
+        assert_eq!(batch_sizes, vec![1, 2, 2]);
+
+        // A large length penalty favours longer hypotheses, so the search runs on
+        let (beams, batch_sizes) = search(2, 3.0, false);
+        assert_eq!(beams[0].tokens, vec![0, 0, 0, 2]);
+        assert_eq!(batch_sizes, vec![1, 2, 2, 2]);
+
+        // Early stopping returns as soon as enough hypotheses are finished
+        let (beams, batch_sizes) = search(2, 3.0, true);
+        assert_eq!(beams[0].tokens, vec![0, 1, 2]);
+        assert_eq!(batch_sizes, vec![1, 2, 2]);
+    }
+}
@@ -0,0 +1,196 @@
+//! Turning chat messages into prompts with the special tokens each model family was trained on.
+//!
+//! The templates shipped with HF models are Jinja, which isn't supported here. Instead the common formats are built
+//! in, and `ChatTemplate::detect` picks one by looking for its markers in a Jinja template, like the `chat_template` of
+//! a `tokenizer_config.json` or a GGUF file's `tokenizer.chat_template`.
+
+use crate::serialization::gguf;
+
+/// A single message of a conversation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Who wrote the message, usually `system`, `user` or `assistant`
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl ToString, content: impl ToString) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn system(content: impl ToString) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: impl ToString) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: impl ToString) -> Self {
+        Self::new("assistant", content)
+    }
+}
+
+/// A built-in chat format. Rendered prompts leave out the BOS token, which the tokenizer adds when encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatTemplate {
+    /// `<|start_header_id|>role<|end_header_id|>\n\ncontent<|eot_id|>`
+    Llama3,
+    /// `[INST] <<SYS>>\nsystem\n<</SYS>>\n\nuser [/INST] assistant </s>`
+    Llama2,
+    /// `[INST] user [/INST]assistant</s>`, with any system prompt put in front of the first user message
+    Mistral,
+    /// `<|im_start|>role\ncontent<|im_end|>\n`, used by Qwen, Phi 3 style fine-tunes and many others
+    ChatML,
+}
+
+impl ChatTemplate {
+    /// Guess the format of a Jinja chat template from the special tokens it uses
+    pub fn detect(template: &str) -> Option<Self> {
+        if template.contains("<|start_header_id|>") {
+            Some(Self::Llama3)
+        } else if template.contains("<|im_start|>") {
+            Some(Self::ChatML)
+        } else if template.contains("<<SYS>>") {
+            Some(Self::Llama2)
+        } else if template.contains("[INST]") {
+            Some(Self::Mistral)
+        } else {
+            None
+        }
+    }
+
+    /// Detect the format from a GGUF file's `tokenizer.chat_template`
+    pub fn from_gguf(content: &gguf::Content) -> Option<Self> {
+        content
+            .metadata
+            .get("tokenizer.chat_template")
+            .and_then(gguf::Value::as_str)
+            .and_then(Self::detect)
+    }
+
+    /// Detect the format from the `chat_template` of a `tokenizer_config.json`
+    pub fn from_tokenizer_config(config: &serde_json::Value) -> Option<Self> {
+        config
+            .get("chat_template")
+            .and_then(|t| t.as_str())
+            .and_then(Self::detect)
+    }
+
+    /// The text that ends each message, which should stop generation of an assistant reply
+    pub fn end_of_turn(&self) -> &'static str {
+        match self {
+            Self::Llama3 => "<|eot_id|>",
+            Self::Llama2 | Self::Mistral => "</s>",
+            Self::ChatML => "<|im_end|>",
+        }
+    }
+
+    /// Render a conversation into a prompt. With `add_generation_prompt` the prompt ends by opening an assistant
+    /// message for the model to write.
+    ///
+    /// The Llama 2 and Mistral formats expect user and assistant messages to alternate, and always leave the prompt
+    /// ready for an assistant reply after a user message.
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+        let mut prompt = String::new();
+        match self {
+            Self::Llama3 => {
+                for m in messages {
+                    prompt.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        m.role, m.content
+                    ));
+                }
+                if add_generation_prompt {
+                    prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+                }
+            }
+            Self::ChatML => {
+                for m in messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        m.role, m.content
+                    ));
+                }
+                if add_generation_prompt {
+                    prompt.push_str("<|im_start|>assistant\n");
+                }
+            }
+            Self::Llama2 | Self::Mistral => {
+                let (system, messages) = match messages.first() {
+                    Some(m) if m.role == "system" => (Some(m.content.as_str()), &messages[1..]),
+                    _ => (None, messages),
+                };
+                let mut first_user = true;
+                for m in messages {
+                    if m.role == "assistant" {
+                        match self {
+                            Self::Llama2 => prompt.push_str(&format!(" {} </s>", m.content)),
+                            _ => prompt.push_str(&format!("{}</s>", m.content)),
+                        }
+                        continue;
+                    }
+                    // Every turn after the first starts with its own BOS
+                    if *self == Self::Llama2 && !prompt.is_empty() {
+                        prompt.push_str("<s>");
+                    }
+                    let content = match (system.filter(|_| first_user), self) {
+                        (Some(s), Self::Llama2) => {
+                            format!("<<SYS>>\n{s}\n<</SYS>>\n\n{}", m.content)
+                        }
+                        (Some(s), _) => format!("{s}\n\n{}", m.content),
+                        (None, _) => m.content.clone(),
+                    };
+                    first_user = false;
+                    prompt.push_str(&format!("[INST] {content} [/INST]"));
+                }
+            }
+        }
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatMessage, ChatTemplate};
+
+    #[test]
+    fn test_chat_templates() {
+        let messages = [
+            ChatMessage::system("Be brief."),
+            ChatMessage::user("Hi"),
+            ChatMessage::assistant("Hello!"),
+            ChatMessage::user("Bye"),
+        ];
+        assert_eq!(
+            ChatTemplate::Llama3.render(&messages[1..2], true),
+            "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            ChatTemplate::ChatML.render(&messages[..2], false),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n"
+        );
+        assert_eq!(
+            ChatTemplate::Llama2.render(&messages, true),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Bye [/INST]"
+        );
+        assert_eq!(
+            ChatTemplate::Mistral.render(&messages, true),
+            "[INST] Be brief.\n\nHi [/INST]Hello!</s>[INST] Bye [/INST]"
+        );
+
+        let config = serde_json::json!({
+            "chat_template": "{% for message in messages %}{{'<|im_start|>' + message['role'] }}{% endfor %}"
+        });
+        assert_eq!(
+            ChatTemplate::from_tokenizer_config(&config),
+            Some(ChatTemplate::ChatML)
+        );
+        assert_eq!(ChatTemplate::detect("{{ message }}"), None);
+    }
+}
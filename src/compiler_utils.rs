@@ -1,6 +1,6 @@
 // Lots of utilities used by compilers
 
-use std::{any::TypeId, borrow::Borrow, collections::HashSet, fmt::Debug, sync::Arc};
+use std::{any::TypeId, borrow::Borrow, collections::HashSet, fmt::Debug, io::Write, sync::Arc};
 
 use colored::Colorize;
 use itertools::Itertools;
@@ -328,12 +328,15 @@ impl Graph {
     ///     .add_op(luminal::op::Mul)
     ///     .input(a.id, 0, a.shape)
     ///     .finish();
-    /// let b = GraphTensor::<R1<3>>::from_id(b_id, a.shape, a.graph());
+    /// let b = GraphTensor::<R1<3>>::from_id(b_id, a.shape, &mut cx);
     /// ```
     pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
         self.linearized_graph = None;
+        let new_op_id = self.graph.add_node(Box::new(op));
+        // Node indexes get reused, so don't inherit metadata from a removed node
+        self.metadata.remove(&new_op_id);
         NewOp {
-            new_op_id: self.graph.add_node(Box::new(op)),
+            new_op_id,
             graph_ref: self,
             num_srcs: 0,
         }
@@ -447,6 +450,93 @@ impl Graph {
         );
     }
 
+    /// Render the graph in Graphviz DOT format. Nodes are labelled with their op, id and output shapes, where
+    /// dynamic dimensions also show their current value if it's known. Kept tensors are grey, retrieved tensors
+    /// are yellow, and schedule edges are dashed green.
+    pub fn to_dot(&self) -> String {
+        let dim = |d: &BigExpression| {
+            let d_str = d.to_string();
+            match (d.to_usize(), d.exec(&self.dyn_map)) {
+                (None, Some(val)) => format!("{d_str} (={val})"),
+                _ => d_str,
+            }
+        };
+        let mut dot = "digraph {\n    node [shape=box fontname=monospace]\n".to_string();
+        for node in self.graph.node_indices() {
+            let mut lines = vec![format!("{:?} | {}", self.graph[node], node.index())];
+            // The shape of each output, as read by its first consumer
+            lines.extend(
+                self.graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .filter_map(|e| e.weight().as_data())
+                    .map(|(_, output, shape)| (output, shape))
+                    .unique_by(|(output, _)| *output)
+                    .sorted_by_key(|(output, _)| *output)
+                    .map(|(output, shape)| {
+                        format!("{output}: [{}]", shape.shape().iter().map(dim).join(", "))
+                    }),
+            );
+            let label = lines
+                .iter()
+                .map(|l| l.replace('\\', "\\\\").replace('"', "\\\""))
+                .join("\\n");
+            let style = if self.to_retrieve.contains_key(&node) {
+                " style=filled fillcolor=yellow"
+            } else if self.no_delete.contains(&node) {
+                " style=filled fillcolor=lightgrey"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "    {} [label=\"{label}\"{style}]\n",
+                node.index(),
+            ));
+        }
+        for edge in self
+            .graph
+            .node_indices()
+            .flat_map(|n| self.graph.edges_directed(n, Direction::Outgoing))
+        {
+            let attrs = match edge.weight() {
+                Dependency::Data { input_order, .. } => format!("label=\"{input_order}\""),
+                Dependency::Schedule => "style=dashed color=green".to_string(),
+            };
+            dot.push_str(&format!(
+                "    {} -> {} [{attrs}]\n",
+                edge.source().index(),
+                edge.target().index()
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the graph to SVG with the Graphviz `dot` binary, which must be installed
+    pub fn to_svg(&self) -> std::io::Result<String> {
+        let mut child = std::process::Command::new("dot")
+            .arg("-Tsvg")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(self.to_dot().as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// View the graph from `to_dot` in the browser
+    pub fn display_dot(&self) {
+        open_dot(&self.to_dot());
+    }
+
     /// Remove node if it only has n dests
     pub fn safe_remove_node(&mut self, node: NodeIndex, dests: usize) {
         if self
@@ -457,6 +547,7 @@ impl Graph {
             <= dests
         {
             self.graph.remove_node(node);
+            self.metadata.remove(&node);
         }
     }
 
@@ -523,9 +614,14 @@ pub fn display_graph(
         );
     }
 
+    open_dot(&graph_string);
+}
+
+/// Open a DOT graph in an online Graphviz viewer
+fn open_dot(dot: &str) {
     let url = format!(
         "https://dreampuf.github.io/GraphvizOnline/#{}",
-        urlencoding::encode(&graph_string)
+        urlencoding::encode(dot)
     );
     if let Err(e) = webbrowser::open(&url) {
         panic!("Error displaying graph: {:?}", e);
@@ -573,6 +669,10 @@ pub fn remap<T: ToIdsMut>(from: NodeIndex, to: NodeIndex, mut ids: T, graph: &mu
     if let Some(w) = graph.to_retrieve.remove(&from) {
         graph.to_retrieve.insert(to, w);
     }
+    // Transfer metadata
+    if let Some(m) = graph.metadata.remove(&from) {
+        graph.metadata.entry(to).or_default().merge(m);
+    }
 }
 
 pub fn move_outgoing_edge<N, E: Clone>(
@@ -636,8 +736,32 @@ impl GraphSearch {
                 }
             }
         }
-        if let Some(mapping) = self.to_return.pop() {
-            self.returned_anchors.insert(mapping[&self.anchor]);
+        while let Some(mapping) = self.to_return.pop() {
+            let anchor = mapping[&self.anchor];
+            self.returned_anchors.insert(anchor);
+            // Rewrites since this match was cached may have removed some of its nodes, so match the anchor again
+            if mapping.values().any(|n| !graph.graph.contains_node(*n)) {
+                let (_, select_op) = self.selector.node_weight(self.anchor).unwrap();
+                if graph.graph.contains_node(anchor)
+                    && test_node(select_op, &mut graph.graph, anchor)
+                {
+                    if let Some(mapping) =
+                        backtrack_match(self.anchor, &self.selector, anchor, &mut graph.graph)
+                    {
+                        self.to_return.push(mapping);
+                    }
+                }
+                continue;
+            }
+            // Nodes marked as not fusable can't be matched by typed ops of multi-node patterns (they can still be wildcard inputs)
+            if mapping.len() > 1
+                && mapping.iter().any(|(k, n)| {
+                    self.selector.node_weight(*k).unwrap().1.type_id.is_some()
+                        && graph.has_attribute::<DoNotFuse>(*n)
+                })
+            {
+                continue;
+            }
             self.current = mapping
                 .into_iter()
                 .map(|(k, v)| (self.selector.node_weight(k).unwrap().0, v))
@@ -0,0 +1,78 @@
+use std::{any::Any, fmt::Display};
+
+use petgraph::stable_graph::NodeIndex;
+
+/// The message unset input tensors panic with when they're loaded
+pub(crate) const UNSET_TENSOR: &str = "You must set a value for this tensor!";
+
+/// Why building, compiling or running a graph failed, returned by the fallible `try_*` methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LuminalError {
+    /// An input tensor was loaded without ever being given data
+    MissingData { node: NodeIndex, name: String },
+    /// Data given to a tensor doesn't fill its shape
+    ShapeMismatch {
+        node: NodeIndex,
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The graph's shapes use dynamic dimensions that have no size
+    MissingDimensions(Vec<char>),
+    /// Sizes were given for dynamic dimensions none of the graph's shapes use
+    UnknownDimensions(Vec<char>),
+    /// The graph has a cycle through this node, so it can't be ordered for execution
+    Cycle(NodeIndex),
+    /// An op panicked while running
+    OpFailed {
+        node: NodeIndex,
+        op: String,
+        message: String,
+    },
+    /// A compiler panicked
+    CompileFailed(String),
+}
+
+impl Display for LuminalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuminalError::MissingData { node, name } => {
+                write!(f, "No data was set for {name} (node {})", node.index())
+            }
+            LuminalError::ShapeMismatch {
+                node,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{name} (node {}) holds {expected} elements but was given {found}",
+                node.index()
+            ),
+            LuminalError::MissingDimensions(dims) => {
+                write!(f, "No size given for dynamic dimensions {dims:?}")
+            }
+            LuminalError::UnknownDimensions(dims) => {
+                write!(f, "The graph doesn't use dynamic dimensions {dims:?}")
+            }
+            LuminalError::Cycle(node) => {
+                write!(f, "The graph has a cycle through node {}", node.index())
+            }
+            LuminalError::OpFailed { node, op, message } => {
+                write!(f, "{op} (node {}) failed: {message}", node.index())
+            }
+            LuminalError::CompileFailed(message) => write!(f, "Compiling failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for LuminalError {}
+
+/// The message a panic was raised with
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
@@ -0,0 +1,756 @@
+use std::{
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+    io::{Error, ErrorKind, Read, Result, Write},
+    ops::ControlFlow,
+};
+
+use rand::Rng;
+use rustc_hash::{FxHashMap, FxHasher};
+
+use crate::{op::sample_row, prelude::*};
+
+/// A causal language model that runs one step at a time, keeping its own KV cache between steps
+pub trait CausalLM {
+    /// Run the model over the tokens it hasn't seen yet (the whole prompt on the first step, then the last generated
+    /// token), returning the logits for the next token
+    fn step(&mut self, tokens: &[u32]) -> Vec<f32>;
+
+    /// Clear the KV cache so the next step starts a new sequence
+    fn reset(&mut self);
+}
+
+/// Picks the next token from a row of logits
+pub trait Sampler {
+    fn sample(&mut self, logits: &[f32]) -> u32;
+}
+
+impl<F: FnMut(&[f32]) -> u32> Sampler for F {
+    fn sample(&mut self, logits: &[f32]) -> u32 {
+        self(logits)
+    }
+}
+
+// Samples on the CPU with the same rules as `GraphTensor::sample`
+impl Sampler for Sample {
+    fn sample(&mut self, logits: &[f32]) -> u32 {
+        let uniform = self.rng.gen();
+        sample_row(logits, self.temperature, self.top_k, self.top_p, uniform) as u32
+    }
+}
+
+/// A compiled causal LM graph laid out like the examples' models:
+/// - `input` takes (1, 's') token ids
+/// - 'p' is the number of cached positions and 't' is 'p' + 's'
+/// - `logits` holds the logits of the last position only
+/// - `cache_dest` holds each step's updated caches, which are moved onto `cache_src` for the next step
+///
+/// The cache inputs should be set to empty caches, which are loaded on the first step and after a `reset`.
+pub struct GraphLM<'a, S: Shape> {
+    pub graph: &'a mut Graph,
+    pub input: GraphTensor<(Const<1>, Dyn<'s'>)>,
+    pub logits: GraphTensor<S>,
+    pub cache_src: Vec<NodeIndex>,
+    pub cache_dest: Vec<NodeIndex>,
+    /// The number of positions in the cache
+    pub cached: usize,
+}
+
+impl<'a, S: Shape> GraphLM<'a, S> {
+    pub fn new(
+        graph: &'a mut Graph,
+        input: GraphTensor<(Const<1>, Dyn<'s'>)>,
+        logits: GraphTensor<S>,
+        cache_src: impl ToIds,
+        cache_dest: impl ToIds,
+    ) -> Self {
+        Self {
+            graph,
+            input,
+            logits,
+            cache_src: cache_src.to_ids(),
+            cache_dest: cache_dest.to_ids(),
+            cached: 0,
+        }
+    }
+}
+
+impl<S: Shape> CausalLM for GraphLM<'_, S> {
+    fn step(&mut self, tokens: &[u32]) -> Vec<f32> {
+        self.input.set_dyn(
+            tokens.iter().map(|t| *t as f32).collect::<Vec<_>>(),
+            &[1, tokens.len()],
+        );
+        self.graph.set_dyn_dim('p', self.cached);
+        self.graph.set_dyn_dim('t', self.cached + tokens.len());
+        self.graph.execute();
+        let logits = self.logits.data();
+        self.logits.drop();
+        // Cache nodes holding data skip their loaders on the next execution
+        transfer_data_same_graph(&self.cache_dest, &self.cache_src, self.graph);
+        self.cached += tokens.len();
+        logits
+    }
+
+    fn reset(&mut self) {
+        self.graph.drop_tensors(&self.cache_src);
+        self.cached = 0;
+    }
+}
+
+impl<S: Shape> SnapshotLM for GraphLM<'_, S> {
+    fn snapshot(&self, tokens: &[u32]) -> KvSnapshot {
+        assert_eq!(
+            tokens.len(),
+            self.cached,
+            "The snapshot tokens should be the ones in the cache"
+        );
+        KvSnapshot {
+            tokens: tokens.to_vec(),
+            tensors: self
+                .cache_src
+                .iter()
+                .map(|id| {
+                    self.graph
+                        .get_tensor_ref(*id, 0)
+                        .and_then(|t| t.f32_data())
+                        .map(|d| d.into_owned())
+                        .unwrap_or_default()
+                })
+                .collect(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: &KvSnapshot) {
+        assert_eq!(
+            snapshot.tensors.len(),
+            self.cache_src.len(),
+            "The snapshot is from a model with a different number of caches"
+        );
+        for (id, data) in self.cache_src.iter().zip(&snapshot.tensors) {
+            self.graph.set_tensor(*id, 0, Tensor::new(data.clone()));
+        }
+        self.cached = snapshot.tokens.len();
+    }
+}
+
+/// A `CausalLM` whose KV cache can be saved and loaded
+pub trait SnapshotLM: CausalLM {
+    /// Copy out the KV cache, which holds these tokens
+    fn snapshot(&self, tokens: &[u32]) -> KvSnapshot;
+
+    /// Replace the KV cache with a snapshot, so the next step continues after the snapshot's tokens
+    fn restore(&mut self, snapshot: &KvSnapshot);
+}
+
+/// A copy of a model's KV cache tensors and the tokens they hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvSnapshot {
+    pub tokens: Vec<u32>,
+    pub tensors: Vec<Vec<f32>>,
+}
+
+impl KvSnapshot {
+    const MAGIC: &'static [u8; 4] = b"LKVS";
+
+    /// Bytes of tensor data held
+    pub fn size_bytes(&self) -> usize {
+        self.tensors.iter().map(|t| t.len() * 4).sum()
+    }
+
+    /// Write the snapshot as little endian token ids and tensor data, each list prefixed by its length
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(Self::MAGIC)?;
+        writer.write_all(&(self.tokens.len() as u64).to_le_bytes())?;
+        for t in &self.tokens {
+            writer.write_all(&t.to_le_bytes())?;
+        }
+        writer.write_all(&(self.tensors.len() as u64).to_le_bytes())?;
+        for tensor in &self.tensors {
+            writer.write_all(&(tensor.len() as u64).to_le_bytes())?;
+            for x in tensor {
+                writer.write_all(&x.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not a KV cache snapshot",
+            ));
+        }
+        let read_u64 = |reader: &mut R| -> Result<u64> {
+            let mut bytes = [0; 8];
+            reader.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let read_4 = |reader: &mut R| -> Result<[u8; 4]> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(bytes)
+        };
+        let n_tokens = read_u64(reader)?;
+        let tokens = (0..n_tokens)
+            .map(|_| read_4(reader).map(u32::from_le_bytes))
+            .collect::<Result<_>>()?;
+        let n_tensors = read_u64(reader)?;
+        let tensors = (0..n_tensors)
+            .map(|_| {
+                let len = read_u64(reader)?;
+                (0..len)
+                    .map(|_| read_4(reader).map(f32::from_le_bytes))
+                    .collect::<Result<_>>()
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { tokens, tensors })
+    }
+}
+
+fn hash_tokens(tokens: &[u32]) -> u64 {
+    let mut hasher = FxHasher::default();
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keeps KV cache snapshots of prompt prefixes, like a shared system prompt, so later prompts starting with them don't
+/// need to run them through the model again. Snapshots are keyed by a hash of their tokens, and once there are more
+/// than `capacity` the least recently used one is dropped.
+#[derive(Debug, Clone)]
+pub struct PrefixCache {
+    capacity: usize,
+    entries: FxHashMap<u64, KvSnapshot>,
+    /// Hashes from least to most recently used
+    recency: VecDeque<u64>,
+}
+
+impl PrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: FxHashMap::default(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn touch(&mut self, hash: u64) {
+        self.recency.retain(|h| *h != hash);
+        self.recency.push_back(hash);
+    }
+
+    /// Add a snapshot, replacing any with the same tokens
+    pub fn insert(&mut self, snapshot: KvSnapshot) {
+        let hash = hash_tokens(&snapshot.tokens);
+        self.entries.insert(hash, snapshot);
+        self.touch(hash);
+        while self.entries.len() > self.capacity {
+            let oldest = self.recency.pop_front().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// The snapshot of the longest cached prefix of `tokens`, marking it as recently used
+    pub fn longest_prefix(&mut self, tokens: &[u32]) -> Option<&KvSnapshot> {
+        let mut lengths = self
+            .entries
+            .values()
+            .map(|s| s.tokens.len())
+            .filter(|l| *l <= tokens.len())
+            .collect::<Vec<_>>();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+        let hash = lengths
+            .into_iter()
+            .map(|l| hash_tokens(&tokens[..l]))
+            .find(|h| {
+                self.entries
+                    .get(h)
+                    .is_some_and(|s| tokens.starts_with(&s.tokens))
+            })?;
+        self.touch(hash);
+        self.entries.get(&hash)
+    }
+
+    /// Start a new sequence on the model from the longest cached prefix of the prompt, run the rest of the prompt
+    /// except its last token, and cache a snapshot of everything before the last token. Returns the tokens still to be
+    /// given to the model, which are passed to the `Generator` as the prompt.
+    pub fn prefill<'p>(&mut self, model: &mut impl SnapshotLM, prompt: &'p [u32]) -> &'p [u32] {
+        assert!(!prompt.is_empty(), "The prompt needs at least one token");
+        let prefix = &prompt[..prompt.len() - 1];
+        model.reset();
+        let restored = match self.longest_prefix(prefix) {
+            Some(snapshot) => {
+                model.restore(snapshot);
+                snapshot.tokens.len()
+            }
+            None => 0,
+        };
+        if restored < prefix.len() {
+            model.step(&prefix[restored..]);
+            self.insert(model.snapshot(prefix));
+        }
+        &prompt[prefix.len()..]
+    }
+}
+
+/// Turns generated tokens into text as they come in
+pub trait TokenDecoder {
+    /// Add a token, getting back the new text it completes, if any
+    fn push(&mut self, token: u32) -> Option<String>;
+}
+
+impl<F: FnMut(u32) -> Option<String>> TokenDecoder for F {
+    fn push(&mut self, token: u32) -> Option<String> {
+        self(token)
+    }
+}
+
+/// Why generation ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    StopToken,
+    StopSequence,
+    /// The new token or total length limit was reached
+    Length,
+    /// The callback broke out of the loop
+    Callback,
+}
+
+/// The result of `Generator::generate_text`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextGeneration {
+    /// The generated tokens, not including the prompt
+    pub tokens: Vec<u32>,
+    /// The decoded text, cut off before any stop sequence
+    pub text: String,
+    pub finish: FinishReason,
+}
+
+/// Drives the prefill and decode loop of a `CausalLM`, streaming each sampled token to a callback
+#[derive(Debug, Clone)]
+pub struct Generator {
+    max_new_tokens: usize,
+    max_length: usize,
+    stop_tokens: Vec<u32>,
+    stop_sequences: Vec<String>,
+    logit_bias: FxHashMap<u32, f32>,
+    banned_tokens: Vec<u32>,
+}
+
+impl Generator {
+    pub fn new(max_new_tokens: usize) -> Self {
+        Self {
+            max_new_tokens,
+            max_length: usize::MAX,
+            stop_tokens: vec![],
+            stop_sequences: vec![],
+            logit_bias: FxHashMap::default(),
+            banned_tokens: vec![],
+        }
+    }
+
+    /// Stop once the prompt and generated tokens reach this many tokens, like the model's context length
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Stop once one of these tokens is generated. The stop token is still passed to the callback and returned.
+    pub fn with_stop_tokens(mut self, stop_tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_tokens = stop_tokens.into_iter().collect();
+        self
+    }
+
+    /// Stop once the decoded text contains one of these strings. Only `generate_text` decodes text, and it leaves the
+    /// stop sequence out of the text it streams and returns.
+    pub fn with_stop_sequences(
+        mut self,
+        stop_sequences: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.stop_sequences = stop_sequences.into_iter().map(|s| s.to_string()).collect();
+        self.stop_sequences.retain(|s| !s.is_empty());
+        self
+    }
+
+    /// Add these amounts to the logits of tokens before sampling
+    pub fn with_logit_bias(mut self, logit_bias: impl IntoIterator<Item = (u32, f32)>) -> Self {
+        self.logit_bias = logit_bias.into_iter().collect();
+        self
+    }
+
+    /// Never sample these tokens
+    pub fn with_banned_tokens(mut self, banned_tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.banned_tokens = banned_tokens.into_iter().collect();
+        self
+    }
+
+    /// Apply the logit bias and banned tokens to a row of logits
+    pub fn adjust_logits(&self, logits: &mut [f32]) {
+        for (token, bias) in &self.logit_bias {
+            if let Some(l) = logits.get_mut(*token as usize) {
+                *l += bias;
+            }
+        }
+        for token in &self.banned_tokens {
+            if let Some(l) = logits.get_mut(*token as usize) {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Run the prompt through the model, then generate tokens one at a time, passing each to `callback` as soon as
+    /// it's sampled. Generation ends on a stop token, at the token limits, or when the callback breaks. Returns the
+    /// generated tokens, not including the prompt. The model's cache is left as is, so call `reset` before reusing it
+    /// for an unrelated prompt.
+    pub fn generate(
+        &self,
+        model: &mut impl CausalLM,
+        sampler: &mut impl Sampler,
+        prompt: &[u32],
+        callback: impl FnMut(u32) -> ControlFlow<()>,
+    ) -> Vec<u32> {
+        self.run(model, sampler, prompt, callback).0
+    }
+
+    /// Like `generate`, but decodes the tokens and streams text to `callback`, also stopping on stop sequences.
+    ///
+    /// Text that could be the start of a stop sequence is held back until it's clear it isn't, so a stop sequence is
+    /// never streamed.
+    pub fn generate_text(
+        &self,
+        model: &mut impl CausalLM,
+        sampler: &mut impl Sampler,
+        prompt: &[u32],
+        decoder: &mut impl TokenDecoder,
+        callback: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> TextGeneration {
+        self.stream_text(decoder, callback, |on_token| {
+            self.run(model, sampler, prompt, on_token)
+        })
+    }
+
+    /// Like `generate_text`, but for tokens generated elsewhere, like a stream from an `InferenceSession`. Stop tokens,
+    /// stop sequences and the new token limit apply the same way, and the stream ending counts as reaching the limit.
+    /// Once this returns the rest of the stream isn't needed, so a session stream can be dropped to cancel it.
+    pub fn decode_text(
+        &self,
+        tokens: impl IntoIterator<Item = u32>,
+        decoder: &mut impl TokenDecoder,
+        callback: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> TextGeneration {
+        self.stream_text(decoder, callback, |on_token| {
+            let mut generated = vec![];
+            for token in tokens.into_iter().take(self.max_new_tokens) {
+                generated.push(token);
+                if on_token(token).is_break() {
+                    return (generated, FinishReason::Callback);
+                }
+                if self.stop_tokens.contains(&token) {
+                    return (generated, FinishReason::StopToken);
+                }
+            }
+            (generated, FinishReason::Length)
+        })
+    }
+
+    /// Decode the tokens `generate` passes to its callback, streaming text and stopping on stop sequences
+    fn stream_text(
+        &self,
+        decoder: &mut impl TokenDecoder,
+        mut callback: impl FnMut(&str) -> ControlFlow<()>,
+        generate: impl FnOnce(&mut dyn FnMut(u32) -> ControlFlow<()>) -> (Vec<u32>, FinishReason),
+    ) -> TextGeneration {
+        let mut text = String::new();
+        // How much of the text has been streamed
+        let mut streamed = 0;
+        let mut stopped = false;
+        let (tokens, finish) = generate(&mut |token| {
+            let Some(new_text) = decoder.push(token) else {
+                return ControlFlow::Continue(());
+            };
+            text.push_str(&new_text);
+            if let Some(stop) = self
+                .stop_sequences
+                .iter()
+                .filter_map(|s| text.find(s.as_str()))
+                .min()
+            {
+                text.truncate(stop);
+                if stop > streamed {
+                    let _ = callback(&text[streamed..]);
+                }
+                streamed = text.len();
+                stopped = true;
+                return ControlFlow::Break(());
+            }
+            let ready = text.len() - self.held_back(&text);
+            if ready > streamed {
+                let flow = callback(&text[streamed..ready]);
+                streamed = ready;
+                return flow;
+            }
+            ControlFlow::Continue(())
+        });
+        if streamed < text.len() {
+            let _ = callback(&text[streamed..]);
+        }
+        TextGeneration {
+            tokens,
+            text,
+            finish: if stopped {
+                FinishReason::StopSequence
+            } else {
+                finish
+            },
+        }
+    }
+
+    /// The length of the longest end of the text that a stop sequence starts with
+    fn held_back(&self, text: &str) -> usize {
+        self.stop_sequences
+            .iter()
+            .flat_map(|s| {
+                (1..s.len()).filter(|i| s.is_char_boundary(*i) && text.ends_with(&s[..*i]))
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn run(
+        &self,
+        model: &mut impl CausalLM,
+        sampler: &mut impl Sampler,
+        prompt: &[u32],
+        mut callback: impl FnMut(u32) -> ControlFlow<()>,
+    ) -> (Vec<u32>, FinishReason) {
+        assert!(!prompt.is_empty(), "The prompt needs at least one token");
+        let limit = self
+            .max_new_tokens
+            .min(self.max_length.saturating_sub(prompt.len()));
+        let mut generated = vec![];
+        let mut pending = prompt;
+        while generated.len() < limit {
+            let mut logits = model.step(pending);
+            self.adjust_logits(&mut logits);
+            let token = sampler.sample(&logits);
+            generated.push(token);
+            if callback(token).is_break() {
+                return (generated, FinishReason::Callback);
+            }
+            if self.stop_tokens.contains(&token) {
+                return (generated, FinishReason::StopToken);
+            }
+            pending = &generated[generated.len() - 1..];
+        }
+        (generated, FinishReason::Length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::{CausalLM, FinishReason, Generator, GraphLM, KvSnapshot, PrefixCache};
+    crate::test_imports!();
+
+    #[test]
+    fn test_generate() {
+        // The next token is the one closest to the sum of every token so far
+        let mut cx = Graph::new();
+        let input = cx.named_tensor::<(LConst<1>, Dyn<'s'>)>("Input");
+        let cache_src = cx
+            .named_tensor::<(LConst<1>, Dyn<'p'>)>("Cache")
+            .set_dyn(vec![], &[1, 0]);
+        let cache_dest = cache_src
+            .concat_along::<(LConst<1>, Dyn<'t'>), LAxis<1>, _>(input)
+            .keep();
+        let total = cache_dest.sum_reduce::<R0, _>();
+        let distance = cx.arange::<LConst<8>>() - total.expand();
+        let logits = (distance * distance * -1.).retrieve();
+        let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+        let mut greedy = Sample::new(0., 0, 1., 0);
+
+        let mut streamed = vec![];
+        let tokens =
+            Generator::new(10)
+                .with_stop_tokens([7])
+                .generate(&mut model, &mut greedy, &[1], |t| {
+                    streamed.push(t);
+                    ControlFlow::Continue(())
+                });
+        assert_eq!(tokens, [1, 2, 4, 7]);
+        assert_eq!(streamed, tokens);
+        assert_eq!(model.cached, 4);
+
+        // The callback can stop generation early, and the limits cap it
+        model.reset();
+        let tokens = Generator::new(10).generate(&mut model, &mut greedy, &[0, 1], |t| {
+            if t == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(tokens, [1, 2]);
+        model.reset();
+        let tokens = Generator::new(10).with_max_length(3).generate(
+            &mut model,
+            &mut |logits: &[f32]| logits.len() as u32 - 8,
+            &[3],
+            |_| ControlFlow::Continue(()),
+        );
+        assert_eq!(tokens, [0, 0]);
+    }
+
+    #[test]
+    fn test_prefix_cache() {
+        let mut cx = Graph::new();
+        let input = cx.named_tensor::<(LConst<1>, Dyn<'s'>)>("Input");
+        let cache_src = cx
+            .named_tensor::<(LConst<1>, Dyn<'p'>)>("Cache")
+            .set_dyn(vec![], &[1, 0]);
+        let cache_dest = cache_src
+            .concat_along::<(LConst<1>, Dyn<'t'>), LAxis<1>, _>(input)
+            .keep();
+        let total = cache_dest.sum_reduce::<R0, _>();
+        let distance = cx.arange::<LConst<8>>() - total.expand();
+        let logits = (distance * distance * -1.).retrieve();
+        let mut model = GraphLM::new(&mut cx, input, logits, cache_src, cache_dest);
+        let mut greedy = Sample::new(0., 0, 1., 0);
+        let generator = Generator::new(3);
+
+        let mut cache = PrefixCache::new(2);
+        let rest = cache.prefill(&mut model, &[1, 0, 1]);
+        assert_eq!(rest, [1]);
+        let tokens =
+            generator.generate(&mut model, &mut greedy, rest, |_| ControlFlow::Continue(()));
+        assert_eq!(tokens, [2, 4, 7]);
+
+        // A longer prompt restores the cached [1, 0] and only runs [2] through the model
+        let rest = cache.prefill(&mut model, &[1, 0, 2, 1]);
+        assert_eq!(model.cached, 3);
+        assert_eq!(cache.len(), 2);
+        let tokens =
+            generator.generate(&mut model, &mut greedy, rest, |_| ControlFlow::Continue(()));
+        model.reset();
+        let uncached = generator.generate(&mut model, &mut greedy, &[1, 0, 2, 1], |_| {
+            ControlFlow::Continue(())
+        });
+        assert_eq!(tokens, uncached);
+
+        // [1, 0] was used more recently than [1, 0, 2], so it survives a new entry
+        cache.prefill(&mut model, &[1, 0, 0]);
+        cache.prefill(&mut model, &[5, 5]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.longest_prefix(&[1, 0, 2]).unwrap().tokens == [1, 0]);
+
+        let snapshot = cache.longest_prefix(&[5]).unwrap().clone();
+        let mut bytes = vec![];
+        snapshot.write(&mut bytes).unwrap();
+        assert_eq!(KvSnapshot::read(&mut bytes.as_slice()).unwrap(), snapshot);
+        assert!(KvSnapshot::read(&mut &bytes[1..]).is_err());
+    }
+
+    /// Counts up from 0 one token per step, out of 10 tokens
+    struct Counter(usize);
+
+    impl CausalLM for Counter {
+        fn step(&mut self, _: &[u32]) -> Vec<f32> {
+            self.0 += 1;
+            (0..10)
+                .map(|i| -((i - self.0 as i32 + 1) as f32).powi(2))
+                .collect()
+        }
+
+        fn reset(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    #[test]
+    fn test_generate_text() {
+        let mut greedy = Sample::new(0., 0, 1., 0);
+        let mut letters = |t: u32| Some(char::from(b'a' + t as u8).to_string());
+        let mut streamed = vec![];
+        let out = Generator::new(10)
+            .with_stop_sequences(["cd"])
+            .generate_text(&mut Counter(0), &mut greedy, &[0], &mut letters, |text| {
+                streamed.push(text.to_string());
+                ControlFlow::Continue(())
+            });
+        assert_eq!(out.tokens, [0, 1, 2, 3]);
+        assert_eq!(out.text, "ab");
+        assert_eq!(out.finish, FinishReason::StopSequence);
+        // "c" was held back in case it started the stop sequence
+        assert_eq!(streamed, ["a", "b"]);
+
+        let out = Generator::new(3).with_stop_sequences(["cx"]).generate_text(
+            &mut Counter(0),
+            &mut greedy,
+            &[0],
+            &mut letters,
+            |_| ControlFlow::Continue(()),
+        );
+        assert_eq!(out.text, "abc");
+        assert_eq!(out.finish, FinishReason::Length);
+
+        let tokens = Generator::new(4)
+            .with_banned_tokens([0, 2])
+            .with_logit_bias([(9, 100.)])
+            .generate(&mut Counter(0), &mut greedy, &[0], |_| {
+                ControlFlow::Continue(())
+            });
+        assert_eq!(tokens, [9, 9, 9, 9]);
+        let tokens = Generator::new(4).with_banned_tokens([0, 2]).generate(
+            &mut Counter(0),
+            &mut greedy,
+            &[0],
+            |_| ControlFlow::Continue(()),
+        );
+        // Ties between the neighbours of a banned token go to the lower one
+        assert_eq!(tokens, [1, 1, 1, 3]);
+    }
+
+    #[test]
+    fn test_decode_text() {
+        // Tokens generated elsewhere stop the same way
+        let mut letters = |t: u32| Some(char::from(b'a' + t as u8).to_string());
+        let mut streamed = vec![];
+        let out =
+            Generator::new(10)
+                .with_stop_sequences(["cd"])
+                .decode_text(0.., &mut letters, |text| {
+                    streamed.push(text.to_string());
+                    ControlFlow::Continue(())
+                });
+        assert_eq!(out.tokens, [0, 1, 2, 3]);
+        assert_eq!(out.text, "ab");
+        assert_eq!(out.finish, FinishReason::StopSequence);
+        assert_eq!(streamed, ["a", "b"]);
+
+        let out = Generator::new(10)
+            .with_stop_tokens([2])
+            .decode_text(0.., &mut letters, |_| ControlFlow::Continue(()));
+        assert_eq!(
+            (out.text.as_str(), out.finish),
+            ("abc", FinishReason::StopToken)
+        );
+        let out = Generator::new(10).decode_text(0..2, &mut letters, |_| ControlFlow::Continue(()));
+        assert_eq!(
+            (out.text.as_str(), out.finish),
+            ("ab", FinishReason::Length)
+        );
+    }
+}
@@ -9,7 +9,11 @@ use petgraph::{
 };
 
 use crate::{
-    op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
+    op::{
+        Add, Constant, ConstantValue, Contiguous, DropoutMask, ElementwiseInstruction, Exp2,
+        Function, FusedElementwise, InputTensor, LessThan, Log2, MaxReduce, Mod, Mul, Operator,
+        Random, Recip, Sample, Sin, Sqrt, StochasticRound, SumReduce, Tensor,
+    },
     prelude::*,
 };
 
@@ -28,74 +32,40 @@ pub struct CSE;
 impl Compiler for CSE {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        // Look for nodes that have the exact same srcs
-        // Loop cause I'm lazy
-        let mut eliminated = true;
-        while eliminated {
-            eliminated = false;
-            let mut srcs_set: HashMap<Vec<NodeIndex>, Vec<NodeIndex>> = HashMap::new();
-            for node in graph.graph.node_indices().collect_vec() {
-                if graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .is::<Function>()
-                {
-                    continue;
-                }
-                let srcs = graph
-                    .graph
-                    .edges_directed(node, petgraph::Direction::Incoming)
-                    .filter(|e| !e.weight().is_schedule())
-                    .sorted_by_key(|e| e.weight().as_data().unwrap().0)
-                    .map(|e| e.source())
-                    .collect_vec();
-
-                if let Some(other_nodes) = srcs_set.get(&srcs) {
-                    for other_node in other_nodes {
-                        let a = graph.graph.node_weight(node).unwrap();
-                        let Some(b) = graph.graph.node_weight(*other_node) else {
-                            continue;
-                        };
-                        if format!("{a:?}") != format!("{b:?}") {
-                            // Sloppy way to check if ops are equal, but we only expect primops here so it's ok
-                            continue;
-                        }
-                        let a_src_shapes = graph
-                            .get_sources(node)
-                            .into_iter()
-                            .map(|(_, _, a)| a)
-                            .collect_vec();
-                        let b_src_shapes = graph
-                            .get_sources(*other_node)
-                            .into_iter()
-                            .map(|(_, _, a)| a)
-                            .collect_vec();
-                        if a_src_shapes != b_src_shapes {
-                            continue;
-                        }
-                        // If the op, input shapes, and output shape is the same, we can combine them (UNCLEAR IF THIS IS TRUE, NEED PROPER PartialEq)
-                        // Carry over outgoing edges from node to other_node
-                        move_outgoing_edge(node, *other_node, &mut graph.graph);
-                        // Transfer all references to node over to other node
-                        remap(node, *other_node, &mut ids, graph);
-                        // Remove node
-                        graph.graph.remove_node(node);
-                        eliminated = true;
-                        break;
-                    }
-                    if eliminated {
-                        break;
-                    }
-                }
-                if let Some(nodes) = srcs_set.get_mut(&srcs) {
-                    nodes.push(node);
-                } else {
-                    srcs_set.insert(srcs, vec![node]);
-                }
+        // Nodes are visited after their sources, so by the time a node is checked its sources have already been merged,
+        // and whole duplicated subgraphs collapse in one pass
+        let mut seen = HashMap::new();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let op = graph.graph.node_weight(node).unwrap();
+            // Loaders and ops drawing random numbers give different results each time
+            if op.as_any().is::<Function>()
+                || op.as_any().is::<StochasticRound>()
+                || op.as_any().is::<DropoutMask>()
+                || op.as_any().is::<Random>()
+                || op.as_any().is::<Sample>()
+            {
+                continue;
+            }
+            // Scheduled ops, like activations recomputed during the backward pass, are meant to run later than an
+            // identical op that may already exist
+            if graph
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .any(|e| e.weight().is_schedule())
+            {
+                continue;
+            }
+            // Sloppy way to check if ops are equal, but we only expect primops here so it's ok
+            let key = (format!("{op:?}"), graph.get_sources(node));
+            if let Some(&other_node) = seen.get(&key) {
+                // Carry over outgoing edges from node to other_node
+                move_outgoing_edge(node, other_node, &mut graph.graph);
+                // Transfer all references to node over to other node
+                remap(node, other_node, &mut ids, graph);
+                graph.graph.remove_node(node);
+            } else {
+                seen.insert(key, node);
             }
-            srcs_set.clear();
         }
     }
 }
@@ -157,6 +127,334 @@ impl Compiler for RemoveSingleReductions {
     }
 }
 
+/// Store weights in the layout their consumers read them in, transposing them once when they are loaded.
+///
+/// Weights that are always used with the same permutation (like `weight.permute()` in a linear layer) get their
+/// loading op wrapped so the loaded data is reordered, and consumers then read the weight contiguously instead of
+/// through a permuted view every pass. Weights need f32 data and shapes that are static or only use dynamic dimensions
+/// already set on the graph, and are skipped otherwise.
+///
+/// Run this after the backend's compilers, so the layout matches what its matmul ops actually read.
+#[derive(Debug, Default)]
+pub struct PrepackWeights(Vec<NodeIndex>);
+
+impl PrepackWeights {
+    pub fn new<T: ToIds>(weights: T) -> Self {
+        Self(weights.to_ids())
+    }
+}
+
+impl Compiler for PrepackWeights {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        // Physical dimensions in the order the consumer reads them, ignoring broadcasted dims
+        fn read_order(shape: &ShapeTracker) -> Vec<usize> {
+            shape
+                .indexes
+                .iter()
+                .copied()
+                .filter(|i| !shape.fake[*i])
+                .collect()
+        }
+
+        for &weight in &self.0 {
+            if !graph.contains_node(weight) {
+                continue;
+            }
+            let edges = graph
+                .edges_directed(weight, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|(_, _, sh)| (e.id(), sh)))
+                .collect::<Vec<_>>();
+            let Some((_, first)) = edges.first() else {
+                continue;
+            };
+            let is_f32 = match graph.tensors.get(&(weight, 0)) {
+                Some(tensor) => tensor.is::<Vec<f32>>(),
+                None => graph.dtype(weight) == DType::F32,
+            };
+            if !is_f32 {
+                continue;
+            }
+            let order = read_order(first);
+            if order.windows(2).all(|w| w[0] < w[1])
+                || edges.iter().any(|(_, sh)| read_order(sh) != order)
+            {
+                // Already contiguous, or consumers disagree on the layout
+                continue;
+            }
+            let Some(stored_shape) = (0..first.len())
+                .filter(|i| !first.fake[*i])
+                .map(|i| first.dims[i].exec(&graph.dyn_map).map(Expression::from))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            // Reads the stored data in the consumer order
+            let mut transpose = ShapeTracker::new(&stored_shape);
+            let sorted = order.iter().copied().sorted().collect::<Vec<_>>();
+            transpose.permute(
+                &order
+                    .iter()
+                    .map(|i| sorted.iter().position(|j| j == i).unwrap())
+                    .collect::<Vec<_>>(),
+            );
+            let prepack = move |tensor: Tensor| {
+                assert!(
+                    tensor.is::<Vec<f32>>(),
+                    "Prepacked weight loaded non-f32 data without recording its dtype"
+                );
+                Contiguous
+                    .process(vec![(InputTensor::Owned(tensor), transpose)])
+                    .pop()
+                    .unwrap()
+            };
+
+            if graph.node_weight(weight).unwrap().as_any().is::<Function>() {
+                // Wrap the loader so data gets reordered as it's loaded
+                let name = format!("{:?} (Prepacked)", graph.node_weight(weight).unwrap());
+                let loader = std::cell::RefCell::new(std::mem::replace(
+                    graph.graph.node_weight_mut(weight).unwrap(),
+                    Box::new(Function(String::new(), Box::new(|_| vec![]))),
+                ));
+                *graph.graph.node_weight_mut(weight).unwrap() = Box::new(Function(
+                    name,
+                    Box::new(move |inp| {
+                        let mut tensors = loader.borrow_mut().process(inp);
+                        let loaded = tensors.remove(0);
+                        tensors.insert(0, prepack(loaded));
+                        tensors
+                    }),
+                ));
+            } else if !graph.tensors.contains_key(&(weight, 0)) {
+                continue;
+            }
+            // Reorder data that's already been loaded
+            if let Some(tensor) = graph.tensors.remove(&(weight, 0)) {
+                graph.tensors.insert((weight, 0), prepack(tensor));
+            }
+
+            // Consumers now read the weight in storage order
+            for (edge, mut shape) in edges {
+                let orig = shape;
+                for (i, p) in orig.indexes.iter().enumerate() {
+                    shape.dims[i] = orig.dims[*p];
+                    shape.fake[i] = orig.fake[*p];
+                    shape.mask[i] = orig.mask[*p];
+                    shape.padding[i] = orig.padding[*p];
+                    shape.indexes[i] = i;
+                }
+                if let Some(Dependency::Data { shape: s, .. }) = graph.graph.edge_weight_mut(edge) {
+                    *s = shape;
+                }
+            }
+        }
+    }
+}
+
+/// Collapse chains of elementwise ops into `FusedElementwise` ops, so intermediate results are never written to memory.
+///
+/// An op is fused into its consumer when that consumer is its only user and reads it element for element. Backends
+/// that generate kernels can build them from the fused op's instructions (see `FusedElementwise::expression`).
+#[derive(Default, Debug)]
+pub struct ElementwiseFusion;
+
+impl Compiler for ElementwiseFusion {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        fn is_elementwise(graph: &Graph, node: NodeIndex) -> bool {
+            let op = graph.node_weight(node).unwrap().as_any();
+            op.is::<Contiguous>()
+                || op.is::<Log2>()
+                || op.is::<Exp2>()
+                || op.is::<Sin>()
+                || op.is::<Recip>()
+                || op.is::<Sqrt>()
+                || op.is::<Add>()
+                || op.is::<Mul>()
+                || op.is::<Mod>()
+                || op.is::<LessThan>()
+        }
+        fn fuses_into_consumer(graph: &Graph, node: NodeIndex) -> bool {
+            if !is_elementwise(graph, node)
+                || graph.no_delete.contains(&node)
+                || graph.to_retrieve.contains_key(&node)
+            {
+                return false;
+            }
+            let mut edges = graph.edges_directed(node, Direction::Outgoing);
+            match (edges.next(), edges.next()) {
+                (Some(e), None) => {
+                    matches!(e.weight(), Dependency::Data { shape, .. } if !shape.is_reshaped())
+                        && is_elementwise(graph, e.target())
+                }
+                _ => false,
+            }
+        }
+        /// Add the instructions computing a node, returning the index of its result
+        fn build(
+            graph: &Graph,
+            node: NodeIndex,
+            instructions: &mut Vec<ElementwiseInstruction>,
+            inputs: &mut Vec<(NodeIndex, u8, ShapeTracker)>,
+            group: &mut Vec<NodeIndex>,
+        ) -> usize {
+            group.push(node);
+            let args = graph
+                .get_sources(node)
+                .into_iter()
+                .map(|(src, out, shape)| {
+                    if fuses_into_consumer(graph, src) {
+                        return build(graph, src, instructions, inputs, group);
+                    }
+                    let instruction = match graph.try_get_op::<Constant>(src) {
+                        Some(Constant(ConstantValue::Float(f), _))
+                            if !shape.is_sliced() && !shape.is_padded() =>
+                        {
+                            ElementwiseInstruction::Constant(*f)
+                        }
+                        _ => {
+                            let input = (src, out, shape);
+                            ElementwiseInstruction::Input(
+                                inputs.iter().position(|i| *i == input).unwrap_or_else(|| {
+                                    inputs.push(input);
+                                    inputs.len() - 1
+                                }),
+                            )
+                        }
+                    };
+                    instructions.push(instruction);
+                    instructions.len() - 1
+                })
+                .collect::<Vec<_>>();
+            let op = graph.node_weight(node).unwrap().as_any();
+            let instruction = if op.is::<Contiguous>() {
+                // Reading the input in order is all a contiguous op does
+                return args[0];
+            } else if op.is::<Log2>() {
+                ElementwiseInstruction::Log2(args[0])
+            } else if op.is::<Exp2>() {
+                ElementwiseInstruction::Exp2(args[0])
+            } else if op.is::<Sin>() {
+                ElementwiseInstruction::Sin(args[0])
+            } else if op.is::<Recip>() {
+                ElementwiseInstruction::Recip(args[0])
+            } else if op.is::<Sqrt>() {
+                ElementwiseInstruction::Sqrt(args[0])
+            } else if op.is::<Add>() {
+                ElementwiseInstruction::Add(args[0], args[1])
+            } else if op.is::<Mul>() {
+                ElementwiseInstruction::Mul(args[0], args[1])
+            } else if op.is::<Mod>() {
+                ElementwiseInstruction::Mod(args[0], args[1])
+            } else {
+                ElementwiseInstruction::LessThan(args[0], args[1])
+            };
+            instructions.push(instruction);
+            instructions.len() - 1
+        }
+
+        let roots = graph
+            .node_indices()
+            .filter(|n| is_elementwise(graph, *n) && !fuses_into_consumer(graph, *n))
+            .collect::<Vec<_>>();
+        for root in roots {
+            let (mut instructions, mut inputs, mut group) = (vec![], vec![], vec![]);
+            build(graph, root, &mut instructions, &mut inputs, &mut group);
+            if group.len() < 2 || inputs.is_empty() {
+                continue;
+            }
+            let mut fused = graph.add_op(FusedElementwise(instructions));
+            for (src, out, shape) in &inputs {
+                fused = fused.input(*src, *out, *shape);
+            }
+            let fused = fused.finish();
+            move_outgoing_edge(root, fused, graph);
+            // Constants that were inlined are removed if nothing else uses them
+            let constants = group
+                .iter()
+                .flat_map(|n| graph.get_sources(*n))
+                .map(|(src, _, _)| src)
+                .filter(|src| graph.check_node_type::<Constant>(*src))
+                .collect::<Vec<_>>();
+            for node in group {
+                remap(node, fused, &mut ids, graph);
+                graph.remove_node(node);
+            }
+            for constant in constants {
+                if graph.contains_node(constant)
+                    && !graph.no_delete.contains(&constant)
+                    && graph
+                        .edges_directed(constant, Direction::Outgoing)
+                        .next()
+                        .is_none()
+                {
+                    graph.remove_node(constant);
+                }
+            }
+        }
+    }
+}
+
+/// Plan memory for intermediate tensors, so ops write their outputs into a small set of reused buffers instead of
+/// allocating fresh ones every execution. A liveness analysis over the execution order lets intermediates that are
+/// never alive at the same time share a buffer.
+///
+/// Run this after all other compilers, since it plans for the graph as it is. Ops opt in by allocating their outputs
+/// with `op::output_buffer`, and the plan is used by `Graph::execute`.
+#[derive(Debug, Default)]
+pub struct MemoryPlanner;
+
+impl Compiler for MemoryPlanner {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        graph.toposort();
+        let order = graph.linearized_graph.clone().unwrap();
+        // The step each tensor is read for the last time
+        let mut last_use = HashMap::new();
+        for (step, (_, srcs)) in order.iter().enumerate() {
+            for (src, _, _) in srcs {
+                last_use.insert(*src, step);
+            }
+        }
+        let plannable = order
+            .iter()
+            .map(|(node, _)| *node)
+            .filter(|node| {
+                !graph.no_delete.contains(node)
+                    && !graph.to_retrieve.contains_key(node)
+                    && !graph.check_node_type::<Function>(*node)
+                    && last_use.contains_key(node)
+                    && graph
+                        .edges_directed(*node, Direction::Outgoing)
+                        .filter_map(|e| e.weight().as_data())
+                        .all(|(_, output, _)| output == 0)
+            })
+            .collect::<HashSet<_>>();
+
+        let (mut free, mut n_slots, mut slots) = (vec![], 0, HashMap::new());
+        for (step, (node, srcs)) in order.iter().enumerate() {
+            graph.remove_attribute::<BufferSlot>(*node);
+            if plannable.contains(node) {
+                let slot = free.pop().unwrap_or_else(|| {
+                    n_slots += 1;
+                    n_slots - 1
+                });
+                slots.insert(*node, slot);
+            }
+            // Buffers of inputs read for the last time are free once this op is done
+            for (src, _, _) in srcs.iter().unique_by(|(src, _, _)| *src) {
+                if last_use[src] == step {
+                    free.extend(slots.get(src));
+                }
+            }
+        }
+        for (node, slot) in slots {
+            graph.set_attribute(node, BufferSlot(slot));
+        }
+        graph.buffer_arena = vec![vec![]; n_slots];
+    }
+}
+
 /// Remove unused nodes
 #[derive(Default, Debug)]
 pub struct RemoveUnusedNodes;
@@ -439,3 +737,158 @@ fn constant(num: f32) -> SelectGraph {
     });
     n
 }
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_cse() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        // Two structurally identical ladders
+        let x = (a.exp2().sin() + 1.).permute::<_, LAxes2<1, 0>>();
+        let y = (a.exp2().sin() + 1.).permute::<_, LAxes2<1, 0>>();
+        let mut out = (x * y).retrieve();
+        // Random ops aren't merged
+        let mut rounded = (a.stochastic_round(RoundingFormat::BF16)
+            - a.stochastic_round(RoundingFormat::BF16))
+        .retrieve();
+        cx.execute();
+        let expected = out.data();
+        cx.drop_tensors((out, rounded));
+
+        let count = |cx: &Graph, f: fn(&dyn Operator) -> bool| {
+            cx.graph.node_weights().filter(|op| f(op.as_ref())).count()
+        };
+        cx.compile(CSE, (&mut out, &mut rounded));
+        assert_eq!(count(&cx, |op| op.as_any().is::<crate::op::Exp2>()), 1);
+        assert_eq!(count(&cx, |op| op.as_any().is::<crate::op::Sin>()), 1);
+        // One add left from the ladders, and one from the subtraction
+        assert_eq!(count(&cx, |op| op.as_any().is::<crate::op::Add>()), 2);
+        assert_eq!(
+            count(&cx, |op| op.as_any().is::<crate::op::StochasticRound>()),
+            2
+        );
+        cx.execute();
+        assert_exact(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_memory_planner() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<64>>().set(random_vec(64));
+        let mut x = a;
+        for _ in 0..8 {
+            x = (x.sin() + a).exp2().sqrt();
+        }
+        let mut out = x.retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        cx.compile(MemoryPlanner, &mut out);
+        let slots = cx
+            .graph
+            .node_indices()
+            .filter_map(|n| cx.attribute::<BufferSlot>(n).map(|s| s.0))
+            .collect::<Vec<_>>();
+        // A chain only ever has a couple of intermediates alive at once
+        assert!(slots.len() > 16);
+        assert!(cx.buffer_arena.len() <= 3);
+        assert!(slots.iter().all(|s| *s < cx.buffer_arena.len()));
+        for _ in 0..3 {
+            cx.execute();
+            assert_exact(&out.data(), &expected);
+            out.drop();
+        }
+    }
+
+    #[test]
+    fn test_prepack_weights() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(12);
+        let w_data = random_vec(12);
+        let a = cx.tensor::<R2<3, 4>>().set(a_data.clone());
+        let w = cx.tensor::<R2<4, 3>>().set(w_data.clone()).keep();
+        let mut b = (a + w.permute()).retrieve();
+        cx.execute();
+        let unpacked = b.data();
+        b.drop();
+
+        cx.compile(PrepackWeights::new(w), &mut b);
+        assert!(cx
+            .graph
+            .edges_directed(w.id, petgraph::Direction::Outgoing)
+            .all(|e| e.weight().as_data().unwrap().2.is_contiguous()));
+        // Run twice to make sure weights are only transposed once
+        cx.execute();
+        assert_close(&b.data(), &unpacked);
+        b.drop();
+        cx.execute();
+        assert_close(&b.data(), &unpacked);
+    }
+
+    #[test]
+    fn test_prepack_skips_non_f32_weights() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(12);
+        let w_data = random_vec(12)
+            .into_iter()
+            .map(f16::from_f32)
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R2<3, 4>>().set(a_data.clone());
+        let w = cx.tensor::<R2<4, 3>>().set(w_data.clone()).keep();
+        let mut b = (a + w.permute().cast(DType::F32)).retrieve();
+        cx.execute();
+        let unpacked = b.data();
+        b.drop();
+
+        cx.compile(PrepackWeights::new(w), &mut b);
+        // The half precision weight is left as it was
+        assert!(cx
+            .graph
+            .edges_directed(w.id, petgraph::Direction::Outgoing)
+            .all(|e| !e.weight().as_data().unwrap().2.is_contiguous()));
+        assert!(cx.get_tensor_ref(w.id, 0).unwrap().is::<HalfVec>());
+        cx.execute();
+        assert_close(&b.data(), &unpacked);
+    }
+
+    #[test]
+    fn test_elementwise_fusion() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let b = cx.tensor::<R2<4, 3>>().set(random_vec(12));
+        let c = ((a.sigmoid() * a + b.permute()).exp() * 2.).retrieve();
+        // Retrieved intermediates aren't fused away
+        let d = a.exp2().retrieve();
+        let e = (d + 1.).sqrt().retrieve();
+        let mut outputs = (c, d, e);
+        cx.execute();
+        let expected = (c.data(), d.data(), e.data());
+        cx.drop_tensors(outputs);
+
+        cx.compile(ElementwiseFusion, &mut outputs);
+        let fused = cx
+            .graph
+            .node_weights()
+            .filter_map(|op| op.as_any().downcast_ref::<FusedElementwise>())
+            .collect::<Vec<_>>();
+        assert_eq!(fused.len(), 2);
+        assert!(cx.check_node_type::<Exp2>(outputs.1.id));
+        assert_eq!(
+            cx.node_weight(outputs.2.id)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<FusedElementwise>()
+                .unwrap()
+                .expression(|i| format!("in{i}")),
+            "sqrt((in0 + 1.0))"
+        );
+        cx.execute();
+        assert_close(&outputs.0.data(), &expected.0);
+        assert_close(&outputs.1.data(), &expected.1);
+        assert_close(&outputs.2.data(), &expected.2);
+    }
+}
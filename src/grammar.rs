@@ -0,0 +1,943 @@
+//! Constrained decoding with GBNF-style grammars and JSON schemas.
+//!
+//! A `Grammar` is parsed from rules like `root ::= "yes" | "no" | [0-9]+`, and a `GrammarMatcher` tracks how far some
+//! text has gotten through it one character at a time. `ConstrainedSampler` wraps any `Sampler` to mask out tokens the
+//! grammar doesn't allow next, so generation can only produce text the grammar matches.
+//!
+//! The grammar syntax follows llama.cpp's GBNF: literals in double quotes, character classes like `[a-z]` or `[^"]`,
+//! `.` for any character, rule names, parenthesized groups, alternatives with `|`, the `*`, `+` and `?` repetitions,
+//! and `#` comments. Generation starts from the `root` rule. Rules can't be left recursive.
+
+use std::fmt::Display;
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_json::Value;
+
+use crate::generate::Sampler;
+
+/// Why a grammar or JSON schema couldn't be turned into a `Grammar`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarError {
+    pub message: String,
+    /// The character offset into the grammar where parsing failed, if it failed while parsing
+    pub position: Option<usize>,
+}
+
+impl GrammarError {
+    fn new(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            position: None,
+        }
+    }
+}
+
+impl Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some(p) => write!(f, "{} at character {p}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    /// A single character in one of the inclusive ranges, or in none of them if negated
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Element::Chars {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// A parsed grammar. Each rule is a list of alternatives, each a sequence of elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+impl Grammar {
+    /// Parse a GBNF-style grammar starting from its `root` rule
+    pub fn parse(source: &str) -> Result<Self, GrammarError> {
+        Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            names: vec![],
+            rules: vec![],
+        }
+        .parse()
+    }
+
+    /// A grammar matching the JSON values a JSON schema describes, see `json_schema_to_gbnf`
+    pub fn from_json_schema(schema: &Value) -> Result<Self, GrammarError> {
+        Self::parse(&json_schema_to_gbnf(schema)?)
+    }
+
+    /// The names of the rules, including the ones made for groups and repetitions
+    pub fn rule_names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Check that no rule can reach itself without matching a character first, which would expand forever
+    fn check_left_recursion(&self) -> Result<(), GrammarError> {
+        let mut nullable = vec![false; self.rules.len()];
+        let is_nullable = |e: &Element, nullable: &[bool]| match e {
+            Element::Chars { .. } => false,
+            Element::Rule(r) => nullable[*r],
+        };
+        loop {
+            let mut changed = false;
+            for (r, alts) in self.rules.iter().enumerate() {
+                if !nullable[r]
+                    && alts
+                        .iter()
+                        .any(|a| a.iter().all(|e| is_nullable(e, &nullable)))
+                {
+                    nullable[r] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // Rules each rule can start with
+        let starts = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut starts = vec![];
+                for alt in alts {
+                    for e in alt {
+                        if let Element::Rule(r) = e {
+                            starts.push(*r);
+                        }
+                        if !is_nullable(e, &nullable) {
+                            break;
+                        }
+                    }
+                }
+                starts
+            })
+            .collect::<Vec<_>>();
+        // Depth first search for a cycle, with 1 marking rules on the current path and 2 finished ones
+        let mut state = vec![0u8; self.rules.len()];
+        for start in 0..self.rules.len() {
+            let mut stack = vec![(start, 0)];
+            while let Some((r, i)) = stack.last_mut() {
+                let r = *r;
+                state[r] = 1;
+                if let Some(&next) = starts[r].get(*i) {
+                    *i += 1;
+                    match state[next] {
+                        0 => stack.push((next, 0)),
+                        1 => {
+                            return Err(GrammarError::new(format!(
+                                "Rule `{}` is left recursive",
+                                self.names[next]
+                            )))
+                        }
+                        _ => {}
+                    }
+                } else {
+                    state[r] = 2;
+                    stack.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: Vec<String>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+impl Parser {
+    fn error(&self, message: impl ToString) -> GrammarError {
+        GrammarError {
+            message: message.to_string(),
+            position: Some(self.pos),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char, GrammarError> {
+        let c = self.peek().ok_or_else(|| self.error("Unexpected end"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), GrammarError> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.error(format!("Expected `{s}`")));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().is_some_and(Self::is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("Expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Whether a new rule definition starts here
+    fn at_definition(&self) -> bool {
+        let mut i = self.pos;
+        if !self.chars.get(i).is_some_and(|c| Self::is_name_char(*c)) {
+            return false;
+        }
+        while self.chars.get(i).is_some_and(|c| Self::is_name_char(*c)) {
+            i += 1;
+        }
+        while self.chars.get(i).is_some_and(|c| c.is_whitespace()) {
+            i += 1;
+        }
+        self.chars[i.min(self.chars.len())..].starts_with(&[':', ':', '='])
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .unwrap_or_else(|| {
+                self.names.push(name.to_string());
+                self.rules.push(None);
+                self.names.len() - 1
+            })
+    }
+
+    /// Add an unnamed rule for a group or repetition inside another rule
+    fn add_rule(&mut self, parent: usize, alts: Vec<Vec<Element>>) -> usize {
+        let name = format!("{}-{}", self.names[parent], self.names.len());
+        let id = self.rule_id(&name);
+        self.rules[id] = Some(alts);
+        id
+    }
+
+    fn parse(mut self) -> Result<Grammar, GrammarError> {
+        self.skip_space();
+        while self.peek().is_some() {
+            let name = self.name()?;
+            self.skip_space();
+            self.expect("::=")?;
+            let id = self.rule_id(&name);
+            let alts = self.alternatives(id)?;
+            if self.peek() == Some(')') {
+                return Err(self.error("Unmatched `)`"));
+            }
+            if self.rules[id].is_some() {
+                return Err(self.error(format!("Rule `{name}` is defined twice")));
+            }
+            self.rules[id] = Some(alts);
+        }
+        let rules = self
+            .rules
+            .into_iter()
+            .zip(&self.names)
+            .map(|(r, n)| r.ok_or_else(|| GrammarError::new(format!("Rule `{n}` is not defined"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let grammar = Grammar {
+            root: self
+                .names
+                .iter()
+                .position(|n| n == "root")
+                .ok_or_else(|| GrammarError::new("No `root` rule"))?,
+            rules,
+            names: self.names,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    fn alternatives(&mut self, rule: usize) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alts = vec![self.sequence(rule)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.sequence(rule)?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, rule: usize) -> Result<Vec<Element>, GrammarError> {
+        let mut seq = vec![];
+        loop {
+            self.skip_space();
+            let item = match self.peek() {
+                None | Some('|') | Some(')') => return Ok(seq),
+                Some(_) if self.at_definition() => return Ok(seq),
+                Some('"') => {
+                    self.pos += 1;
+                    let mut item = vec![];
+                    while self.peek() != Some('"') {
+                        item.push(Element::char(self.char()?));
+                    }
+                    self.pos += 1;
+                    item
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.char()?;
+                        let hi = if self.peek() == Some('-')
+                            && self.chars.get(self.pos + 1) != Some(&']')
+                        {
+                            self.pos += 1;
+                            self.char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    vec![Element::Chars { ranges, negated }]
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alts = self.alternatives(rule)?;
+                    self.expect(")")?;
+                    vec![Element::Rule(self.add_rule(rule, alts))]
+                }
+                Some(c) if Self::is_name_char(c) => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return Err(self.error(format!("Unexpected `{c}`"))),
+            };
+            let repeat = self.peek().filter(|c| matches!(c, '*' | '+' | '?'));
+            let Some(op) = repeat else {
+                seq.extend(item);
+                continue;
+            };
+            self.pos += 1;
+            let element = if item.len() == 1 {
+                item[0].clone()
+            } else {
+                Element::Rule(self.add_rule(rule, vec![item]))
+            };
+            match op {
+                '?' => seq.push(Element::Rule(
+                    self.add_rule(rule, vec![vec![element], vec![]]),
+                )),
+                _ => {
+                    // x* becomes a new rule r ::= x r | (nothing), and x+ becomes x r
+                    let id = self.add_rule(rule, vec![]);
+                    self.rules[id] = Some(vec![vec![element.clone(), Element::Rule(id)], vec![]]);
+                    if op == '+' {
+                        seq.push(element);
+                    }
+                    seq.push(Element::Rule(id));
+                }
+            }
+        }
+    }
+
+    /// A single character in a literal or class, with escapes
+    fn char(&mut self) -> Result<char, GrammarError> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |p: &mut Self, n: usize| {
+            let digits = (0..n).map(|_| p.next()).collect::<Result<String, _>>()?;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| p.error(format!("Invalid escape `{digits}`")))
+        };
+        Ok(match self.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'x' => hex(self, 2)?,
+            'u' => hex(self, 4)?,
+            'U' => hex(self, 8)?,
+            c => c,
+        })
+    }
+}
+
+/// A position in the grammar: a rule, one of its alternatives, and the index of the next element to match
+type Position = (usize, usize, usize);
+
+/// Tracks which text a `Grammar` still allows after the text matched so far.
+///
+/// Every way the text could have been matched is kept as a stack of rule positions, like llama.cpp's grammar sampler.
+/// Stacks always point at a character to match next, or are empty once the root rule is finished.
+#[derive(Debug, Clone)]
+pub struct GrammarMatcher {
+    grammar: Grammar,
+    stacks: Vec<Vec<Position>>,
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Grammar) -> Self {
+        let mut stacks = vec![];
+        for alt in 0..grammar.rules[grammar.root].len() {
+            expand(&grammar, vec![(grammar.root, alt, 0)], &mut stacks);
+        }
+        stacks.sort();
+        stacks.dedup();
+        Self { grammar, stacks }
+    }
+
+    /// Whether the grammar allows this text next
+    pub fn accepts(&self, text: &str) -> bool {
+        !self.advance_stacks(text).is_empty()
+    }
+
+    /// Match this text, returning false and staying put if the grammar doesn't allow it
+    pub fn advance(&mut self, text: &str) -> bool {
+        let stacks = self.advance_stacks(text);
+        if stacks.is_empty() {
+            return false;
+        }
+        self.stacks = stacks;
+        true
+    }
+
+    /// Whether the text so far is a full match of the grammar
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// Whether the text so far is a full match that can't be extended any further
+    pub fn is_finished(&self) -> bool {
+        self.stacks.iter().all(|s| s.is_empty())
+    }
+
+    fn advance_stacks(&self, text: &str) -> Vec<Vec<Position>> {
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            let mut next = vec![];
+            for mut stack in stacks {
+                let Some((r, a, i)) = stack.last_mut() else {
+                    continue;
+                };
+                if self.grammar.rules[*r][*a][*i].matches(c) {
+                    *i += 1;
+                    expand(&self.grammar, stack, &mut next);
+                }
+            }
+            next.sort();
+            next.dedup();
+            if next.is_empty() {
+                return next;
+            }
+            stacks = next;
+        }
+        stacks
+    }
+}
+
+/// Follow a stack through rule references and finished sequences until it points at a character to match
+fn expand(grammar: &Grammar, mut stack: Vec<Position>, out: &mut Vec<Vec<Position>>) {
+    while let Some(&(r, a, i)) = stack.last() {
+        match grammar.rules[r][a].get(i) {
+            None => {
+                stack.pop();
+            }
+            Some(Element::Chars { .. }) => break,
+            Some(Element::Rule(sub)) => {
+                stack.last_mut().unwrap().2 += 1;
+                for alt in 0..grammar.rules[*sub].len() {
+                    let mut s = stack.clone();
+                    s.push((*sub, alt, 0));
+                    expand(grammar, s, out);
+                }
+                return;
+            }
+        }
+    }
+    out.push(stack);
+}
+
+/// Wraps a sampler to only pick tokens whose text the grammar allows next. End of sequence tokens are only allowed
+/// once the text is a full match, so they should be set as stop tokens on the `Generator`.
+///
+/// Tokens are only checked against the grammar, not against what the vocab can go on to spell, so a vocab that can't
+/// finish some text the grammar allows can get stuck. Once nothing is allowed the inner sampler picks from all `-inf`.
+pub struct ConstrainedSampler<S: Sampler> {
+    pub sampler: S,
+    pub matcher: GrammarMatcher,
+    /// The text of each token as it appears in the middle of generated text. Tokens with no text, like partial UTF-8
+    /// characters, are never picked.
+    pub vocab: Vec<String>,
+    pub eos_tokens: Vec<u32>,
+}
+
+impl<S: Sampler> ConstrainedSampler<S> {
+    pub fn new(
+        sampler: S,
+        grammar: Grammar,
+        vocab: Vec<String>,
+        eos_tokens: impl IntoIterator<Item = u32>,
+    ) -> Self {
+        Self {
+            sampler,
+            matcher: GrammarMatcher::new(grammar),
+            vocab,
+            eos_tokens: eos_tokens.into_iter().collect(),
+        }
+    }
+
+    /// Set the logits of tokens the grammar doesn't allow next to negative infinity
+    pub fn mask(&self, logits: &mut [f32]) {
+        let complete = self.matcher.is_complete();
+        let mut allowed = FxHashMap::default();
+        for (token, logit) in logits.iter_mut().enumerate() {
+            let ok = if self.eos_tokens.contains(&(token as u32)) {
+                complete
+            } else {
+                match self.vocab.get(token) {
+                    Some(text) if !text.is_empty() => *allowed
+                        .entry(text.as_str())
+                        .or_insert_with(|| self.matcher.accepts(text)),
+                    _ => false,
+                }
+            };
+            if !ok {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+impl<S: Sampler> Sampler for ConstrainedSampler<S> {
+    fn sample(&mut self, logits: &[f32]) -> u32 {
+        let mut logits = logits.to_vec();
+        self.mask(&mut logits);
+        let token = self.sampler.sample(&logits);
+        if let Some(text) = self.vocab.get(token as usize) {
+            self.matcher.advance(text);
+        }
+        token
+    }
+}
+
+/// Convert a JSON schema into a GBNF grammar for the JSON values it describes, written compactly with at most a
+/// single space between tokens.
+///
+/// Supports `type` (including lists of types), `properties` with `required`, `items`, `enum`, `const`, `anyOf` and
+/// `oneOf`. Object properties are generated in the order the schema lists them, and no extra properties are allowed.
+/// Schemas without constraints, like `{}` or `true`, allow any JSON value. Other keywords like string formats and
+/// lengths are ignored, and `$ref` isn't supported.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, GrammarError> {
+    let mut converter = SchemaConverter::default();
+    let root = converter.visit(schema, "root")?;
+    if root != "root" {
+        converter.add("root", root);
+    }
+    Ok(converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{name} ::= {body}"))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+const JSON_PRIMITIVES: [(&str, &str); 9] = [
+    ("ws", r#"" "?"#),
+    (
+        "json-string",
+        r#""\"" ([^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]))* "\"""#,
+    ),
+    (
+        "json-number",
+        r#""-"? ([0-9] | [1-9] [0-9]+) ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#,
+    ),
+    ("json-integer", r#""-"? ([0-9] | [1-9] [0-9]+)"#),
+    ("json-boolean", r#""true" | "false""#),
+    ("json-null", r#""null""#),
+    (
+        "json-value",
+        "json-object | json-array | json-string | json-number | json-boolean | json-null",
+    ),
+    (
+        "json-object",
+        r#""{" ws (json-string ws ":" ws json-value ws ("," ws json-string ws ":" ws json-value ws)*)? "}""#,
+    ),
+    (
+        "json-array",
+        r#""[" ws (json-value ws ("," ws json-value ws)*)? "]""#,
+    ),
+];
+
+#[derive(Default)]
+struct SchemaConverter {
+    rules: Vec<(String, String)>,
+    names: FxHashSet<String>,
+}
+
+impl SchemaConverter {
+    /// Add a rule, returning its name, which gets a number added if it's taken
+    fn add(&mut self, name: &str, body: String) -> String {
+        let mut name = name.to_string();
+        let mut i = 1;
+        while self.names.contains(&name) {
+            name = format!(
+                "{}-{i}",
+                name.trim_end_matches(char::is_numeric)
+                    .trim_end_matches('-')
+            );
+            i += 1;
+        }
+        self.names.insert(name.clone());
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    /// The name of a primitive JSON rule, adding it and the rules it uses if they aren't already there
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.names.contains(name) {
+            let body = JSON_PRIMITIVES.iter().find(|(n, _)| *n == name).unwrap().1;
+            self.names.insert(name.to_string());
+            self.rules.push((name.to_string(), body.to_string()));
+            for (dep, _) in JSON_PRIMITIVES {
+                if body.split([' ', '(', ')', '|', '*', '?']).any(|w| w == dep) {
+                    self.primitive(dep);
+                }
+            }
+        }
+        name.to_string()
+    }
+
+    /// A grammar expression matching the schema, adding rules named after `name` as needed
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, GrammarError> {
+        let Value::Object(obj) = schema else {
+            return match schema {
+                Value::Bool(true) => Ok(self.primitive("json-value")),
+                _ => Err(GrammarError::new(format!(
+                    "Unsupported schema at `{name}`: {schema}"
+                ))),
+            };
+        };
+        if obj.contains_key("$ref") {
+            return Err(GrammarError::new(format!(
+                "`$ref` isn't supported (at `{name}`)"
+            )));
+        }
+        if let Some(value) = obj.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = obj.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| GrammarError::new(format!("`enum` of `{name}` isn't a list")))?;
+            return Ok(format!(
+                "({})",
+                values
+                    .iter()
+                    .map(json_literal)
+                    .collect::<Vec<_>>()
+                    .join(" | ")
+            ));
+        }
+        if let Some(options) = obj.get("anyOf").or_else(|| obj.get("oneOf")) {
+            let options = options
+                .as_array()
+                .ok_or_else(|| GrammarError::new(format!("`anyOf` of `{name}` isn't a list")))?;
+            let alts = options
+                .iter()
+                .enumerate()
+                .map(|(i, s)| self.visit(s, &format!("{name}-{i}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(format!("({})", alts.join(" | ")));
+        }
+        let ty = match obj.get("type") {
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|t| {
+                        let mut single = obj.clone();
+                        single.insert("type".to_string(), t.clone());
+                        self.visit(&Value::Object(single), name)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(format!("({})", alts.join(" | ")));
+            }
+            Some(Value::String(t)) => t.as_str(),
+            None if obj.contains_key("properties") => "object",
+            None if obj.contains_key("items") => "array",
+            None => return Ok(self.primitive("json-value")),
+            Some(t) => return Err(GrammarError::new(format!("Invalid type {t} at `{name}`"))),
+        };
+        match ty {
+            "string" | "number" | "integer" | "boolean" | "null" => {
+                Ok(self.primitive(&format!("json-{ty}")))
+            }
+            "array" => {
+                let item = match obj.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => self.primitive("json-value"),
+                };
+                self.primitive("ws");
+                Ok(self.add(
+                    name,
+                    format!(r#""[" ws ({item} ws ("," ws {item} ws)*)? "]""#),
+                ))
+            }
+            "object" => {
+                let Some(properties) = obj.get("properties").and_then(|p| p.as_object()) else {
+                    return Ok(self.primitive("json-object"));
+                };
+                let required = obj
+                    .get("required")
+                    .and_then(|r| r.as_array())
+                    .map(|r| r.iter().filter_map(|k| k.as_str()).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                self.primitive("ws");
+                let mut req = vec![];
+                let mut opt = vec![];
+                for (key, prop) in properties {
+                    let value = self.visit(prop, &format!("{name}-{}", rule_name(key)))?;
+                    let kv = format!(
+                        r#"{} ws ":" ws {value} ws"#,
+                        json_literal(&Value::String(key.clone()))
+                    );
+                    if required.contains(&key.as_str()) {
+                        req.push(kv);
+                    } else {
+                        opt.push(kv);
+                    }
+                }
+                let mut body = r#""{" ws "#.to_string();
+                if req.is_empty() {
+                    // Each optional property can be followed by any of the ones after it
+                    let mut rest: Option<String> = None;
+                    for (i, kv) in opt.iter().enumerate().rev() {
+                        let rule = match &rest {
+                            Some(rest) => format!(r#"{kv} ("," ws {rest})? | {rest}"#),
+                            None => kv.clone(),
+                        };
+                        rest = Some(self.add(&format!("{name}-opt{i}"), rule));
+                    }
+                    if let Some(rest) = rest {
+                        body.push_str(&format!("({rest})? "));
+                    }
+                } else {
+                    body.push_str(&req.join(r#" "," ws "#));
+                    for kv in opt {
+                        body.push_str(&format!(r#" ("," ws {kv})?"#));
+                    }
+                    body.push(' ');
+                }
+                body.push_str(r#""}""#);
+                Ok(self.add(name, body))
+            }
+            t => Err(GrammarError::new(format!(
+                "Unsupported type `{t}` at `{name}`"
+            ))),
+        }
+    }
+}
+
+/// A grammar literal matching a JSON value's text exactly
+fn json_literal(value: &Value) -> String {
+    let text = value.to_string();
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Make a property name usable in a rule name
+fn rule_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use serde_json::{json, Value};
+
+    use super::{ConstrainedSampler, Grammar, GrammarMatcher};
+    use crate::generate::{CausalLM, Generator};
+    use crate::op::Sample;
+
+    #[test]
+    fn test_grammar() {
+        let grammar = Grammar::parse(
+            r#"
+            # A comma separated list of numbers, or a greeting
+            root ::= list | "hi" [!?]?
+            list ::= "[" (num ("," num)*)? "]"
+            num ::= "-"? [0-9]+
+            "#,
+        )
+        .unwrap();
+        let matches = |text: &str| {
+            let mut m = GrammarMatcher::new(grammar.clone());
+            m.advance(text) && m.is_complete()
+        };
+        for text in ["[]", "[1,-20,3]", "hi", "hi!"] {
+            assert!(matches(text), "{text}");
+        }
+        for text in ["[", "[1,]", "[-]", "hi!!", "h"] {
+            assert!(!matches(text), "{text}");
+        }
+
+        let mut m = GrammarMatcher::new(grammar);
+        assert!(m.advance("[1"));
+        assert!(!m.advance("a"));
+        assert!(m.accepts("2,3]") && !m.accepts("]]"));
+        assert!(m.advance("]"));
+        assert!(m.is_finished());
+
+        assert!(Grammar::parse("root ::= a\na ::= a \"x\" | \"y\"")
+            .unwrap_err()
+            .message
+            .contains("left recursive"));
+        assert!(Grammar::parse("root ::= b").is_err());
+        assert!(Grammar::parse("root ::= (\"a\"").is_err());
+    }
+
+    /// Random logits over a small vocabulary
+    struct RandomLM(StdRng, usize);
+
+    impl CausalLM for RandomLM {
+        fn step(&mut self, _: &[u32]) -> Vec<f32> {
+            (0..self.1).map(|_| self.0.gen_range(-2_f32..2.)).collect()
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"},
+                "pets": {"type": "array", "items": {"enum": ["cat", "dog"]}},
+                "vip": {"type": ["boolean", "null"]}
+            },
+            "required": ["age", "name"]
+        });
+        let grammar = Grammar::from_json_schema(&schema).unwrap();
+        let matches = |text: &str| {
+            let mut m = GrammarMatcher::new(grammar.clone());
+            m.advance(text) && m.is_complete()
+        };
+        assert!(matches(r#"{"age": 3, "name": "a\"b"}"#));
+        assert!(matches(
+            r#"{"age":-1,"name":"","pets":["dog", "cat"],"vip":null}"#
+        ));
+        assert!(!matches(r#"{"name": "a", "age": 3}"#));
+        assert!(!matches(r#"{"age": 3.5, "name": "a"}"#));
+        assert!(!matches(r#"{"age": 3, "name": "a", "pets": ["fish"]}"#));
+
+        // Sampling from random logits always produces a matching value, as long as single characters can finish any
+        // text the longer tokens start
+        let vocab = [
+            "{", "}", "[", "]", ",", ":", " ", "\"", "0", "1", "-", ".", "true", "null", "\"age\"",
+            "\"name\"", "\"pets\"", "\"vip\"", "cat", "\"cat\"",
+        ]
+        .map(String::from)
+        .into_iter()
+        .chain(('a'..='z').map(String::from))
+        .collect::<Vec<_>>();
+        let eos = vocab.len() as u32;
+        for seed in 0..5 {
+            let mut sampler = ConstrainedSampler::new(
+                Sample::new(1., 0, 1., seed),
+                grammar.clone(),
+                vocab.clone(),
+                [eos],
+            );
+            let tokens = Generator::new(1000).with_stop_tokens([eos]).generate(
+                &mut RandomLM(StdRng::seed_from_u64(seed), vocab.len() + 1),
+                &mut sampler,
+                &[0],
+                |_| ControlFlow::Continue(()),
+            );
+            assert_eq!(tokens.last(), Some(&eos));
+            let text = tokens[..tokens.len() - 1]
+                .iter()
+                .map(|t| vocab[*t as usize].as_str())
+                .collect::<String>();
+            let value: Value = serde_json::from_str(&text).unwrap();
+            assert!(value["age"].is_i64(), "{text}");
+            assert!(value["name"].is_string(), "{text}");
+        }
+    }
+}
@@ -2,15 +2,20 @@
 
 use crate::prelude::*;
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
     io::Write,
     ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
     time::Duration,
 };
 
 use super::compiler_utils::{ToIds, ToIdsMut};
+use crate::error::{panic_message, UNSET_TENSOR};
 use colored::Colorize;
 use itertools::Itertools;
 use petgraph::{stable_graph::StableGraph, visit::EdgeRef, Direction};
+use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 pub type MainGraph = StableGraph<Box<dyn Operator>, Dependency>;
@@ -25,6 +30,12 @@ pub struct Graph {
     pub tensors: FxHashMap<(NodeIndex, u8), Tensor>,
     /// A map of dynamic dimensions to concrete dimension sizes
     pub dyn_map: FxHashMap<char, usize>,
+    /// Seed for ops that draw random numbers, so runs are reproducible
+    pub seed: u64,
+    /// Whether the graph is being trained, which turns on ops like dropout that only apply during training
+    pub training: bool,
+    /// Whether executions count launches and bytes moved for each op (see `telemetry`)
+    pub collect_telemetry: bool,
     /// Edge weights: (Input index, Output index, Input shape)
     pub graph: MainGraph,
     /// Tensors marked in this set will not get deleted when the graph is ran
@@ -34,8 +45,22 @@ pub struct Graph {
     /// A list of current node to run, source nodes, and view nodes to delete after execution.
     #[allow(clippy::type_complexity)]
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
+    /// Typed attributes attached to nodes, which compilers read and carry over when replacing nodes
+    pub metadata: FxHashMap<NodeIndex, NodeMetadata>,
     /// Cached consumers (for execution only)
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// Peak bytes held by intermediate tensors during the last execution
+    peak_intermediate_memory: usize,
+    /// Execution counters for each node since the last compile or reset
+    node_counters: FxHashMap<NodeIndex, OpCounters>,
+    /// Execution counters of nodes that have since been compiled away, by op class
+    class_counters: BTreeMap<String, OpCounters>,
+    /// Hits and misses of kernel cache lookups made while compiling or executing this graph since the last reset
+    kernel_cache_lookups: (u64, u64),
+    /// Reusable buffers for each slot of the memory plan, if there is one (see `MemoryPlanner`)
+    pub(crate) buffer_arena: Vec<Vec<f32>>,
+    /// Passes run after the compiler on every compile, alongside the globally registered ones (see `register_pass`)
+    pub passes: PassRegistry,
 }
 
 /// A dependency between two nodes
@@ -108,11 +133,164 @@ impl Graph {
         self.tensors.insert((id, ind), tensor);
     }
 
+    /// The dtype of a node's output. Inputs are f32 unless set with half precision data, `Cast` ops output their
+    /// target dtype, and other ops keep the half precision dtype of their inputs if they have one.
+    pub fn dtype(&self, node: NodeIndex) -> DType {
+        let mut dtypes = FxHashMap::default();
+        let mut stack = vec![node];
+        while let Some(&id) = stack.last() {
+            if dtypes.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            let op = self.graph.node_weight(id).unwrap().as_any();
+            if let Some(dtype) = self
+                .attribute::<DType>(id)
+                .copied()
+                .or_else(|| op.downcast_ref::<Cast>().map(|c| c.0))
+            {
+                dtypes.insert(id, dtype);
+                stack.pop();
+                continue;
+            }
+            let srcs = self.get_sources(id);
+            let pending = srcs
+                .iter()
+                .map(|(src, _, _)| *src)
+                .filter(|src| !dtypes.contains_key(src))
+                .collect_vec();
+            if pending.is_empty() {
+                let dtype = srcs
+                    .iter()
+                    .fold(DType::F32, |acc, (src, _, _)| acc.combine(dtypes[src]));
+                dtypes.insert(id, dtype);
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+        dtypes[&node]
+    }
+
     /// Set a dynamic dimension
     pub fn set_dyn_dim(&mut self, dimension: char, val: usize) {
         self.dyn_map.insert(dimension, val);
     }
 
+    /// Set the sizes of several dynamic dimensions at once, like `[('b', 4), ('s', 97), ('c', 1024)]`.
+    ///
+    /// Panics if a dimension isn't used by the graph's shapes, a size doesn't match the data set on an input using it,
+    /// or any dynamic dimension used in the graph's shapes is still left without a size.
+    pub fn set_dyn_dims(&mut self, dims: impl IntoIterator<Item = (char, usize)>) {
+        if let Err(e) = self.try_set_dyn_dims(dims) {
+            panic!("{e}");
+        }
+    }
+
+    /// Set the sizes of several dynamic dimensions at once, returning an error instead of panicking if they don't fit
+    /// the graph. The sizes are only set if they all fit.
+    pub fn try_set_dyn_dims(
+        &mut self,
+        dims: impl IntoIterator<Item = (char, usize)>,
+    ) -> Result<(), LuminalError> {
+        let dims = dims.into_iter().collect::<FxHashMap<_, _>>();
+        let symbols = self.dyn_symbols();
+        let mut unknown = dims
+            .keys()
+            .filter(|d| !symbols.contains(d))
+            .copied()
+            .collect_vec();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(LuminalError::UnknownDimensions(unknown));
+        }
+        let mut dyn_map = self.dyn_map.clone();
+        dyn_map.extend(dims.iter().map(|(d, s)| (*d, *s)));
+        let missing = symbols
+            .into_iter()
+            .filter(|s| !dyn_map.contains_key(s))
+            .collect_vec();
+        if !missing.is_empty() {
+            return Err(LuminalError::MissingDimensions(missing));
+        }
+        // Inputs that will be loaded this execution must hold as many elements as their shapes now call for
+        for (node, DataLength(found)) in self
+            .metadata
+            .iter()
+            .filter_map(|(n, m)| Some((*n, *m.get::<DataLength>()?)))
+            .filter(|(n, _)| !self.tensors.contains_key(&(*n, 0)))
+            .sorted_by_key(|(n, _)| *n)
+        {
+            let shapes = self
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data())
+                // Unlike `n_physical_elements`, empty inputs (like an empty KV cache) hold 0 elements
+                .map(|(_, _, st)| {
+                    st.indexes
+                        .into_iter()
+                        .filter(|i| !st.fake[*i])
+                        .map(|i| st.dims[i].big())
+                        .product::<BigExpression>()
+                })
+                .filter(|n| n.to_symbols().iter().any(|s| dims.contains_key(s)));
+            for n_elements in shapes {
+                let expected = n_elements.exec(&dyn_map).unwrap();
+                if expected != found {
+                    return Err(LuminalError::ShapeMismatch {
+                        node,
+                        name: format!("{:?}", self.graph.node_weight(node).unwrap()),
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        self.dyn_map = dyn_map;
+        Ok(())
+    }
+
+    /// Dynamic dimensions the graph's shapes use that have no size yet
+    fn missing_dyn_dims(&self) -> Vec<char> {
+        self.dyn_symbols()
+            .into_iter()
+            .filter(|s| !self.dyn_map.contains_key(s))
+            .collect()
+    }
+
+    /// All dynamic dimension symbols the graph's shapes depend on
+    pub fn dyn_symbols(&self) -> BTreeSet<char> {
+        self.graph
+            .edge_weights()
+            .filter_map(|e| e.as_data())
+            .flat_map(|(_, _, st)| {
+                st.dims
+                    .into_iter()
+                    .chain(st.mask.into_iter().flat_map(|(a, b)| [a, b]))
+                    .chain(st.padding.into_iter().flat_map(|(a, b)| [a, b]))
+                    .flat_map(|e| e.to_symbols())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Set the seed for ops that draw random numbers. Their random streams restart from this seed on the next execution
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Switch between training and inference. Ops like dropout read this on each execution, so the same compiled graph
+    /// can be trained and then evaluated. Graphs start out in inference mode.
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    /// Turn counting launches and bytes moved for each op on or off. It's checked once per execution, and graphs start
+    /// out with it off so executions don't pay for the bookkeeping.
+    pub fn set_telemetry(&mut self, enabled: bool) {
+        self.collect_telemetry = enabled;
+    }
+
     /// Create a new tensor with shape S
     pub fn tensor<S: Shape>(&mut self) -> GraphTensor<S> {
         self.named_tensor("Tensor")
@@ -123,7 +301,7 @@ impl Graph {
         GraphTensor {
             id: self.graph.add_node(Box::new(Function(
                 format!("{name} Load"),
-                Box::new(|_| panic!("You must set a value for this tensor!")),
+                Box::new(|_| panic!("{UNSET_TENSOR}")),
             ))),
             graph_ref: self,
             shape: S::to_tracker(),
@@ -131,19 +309,56 @@ impl Graph {
         }
     }
 
-    /// Compile the graph using the given compiler
-    pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) -> C::Output {
-        let output = compiler.compile(self, remap);
+    /// Compile the graph using the given compiler, followed by the registered passes (see `register_pass`)
+    pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, mut remap: T) -> C::Output {
+        self.archive_node_counters();
+        let kernel_cache = KernelCacheScope::enter();
+        let output = compiler.compile(self, &mut remap);
+        self.run_registered_passes(&mut remap);
+        kernel_cache.finish(self);
         self.toposort();
         self.reset();
         output
     }
 
+    /// Compile the graph using the given compiler, returning an error instead of panicking if the compiler fails or
+    /// leaves a cycle in the graph
+    pub fn try_compile<T: ToIdsMut, C: Compiler>(
+        &mut self,
+        compiler: C,
+        mut remap: T,
+    ) -> Result<C::Output, LuminalError> {
+        self.archive_node_counters();
+        let output = catch_unwind(AssertUnwindSafe(|| {
+            let kernel_cache = KernelCacheScope::enter();
+            let output = compiler.compile(self, &mut remap);
+            self.run_registered_passes(&mut remap);
+            kernel_cache.finish(self);
+            output
+        }))
+        .map_err(|p| LuminalError::CompileFailed(panic_message(&*p)))?;
+        self.try_toposort()?;
+        self.reset();
+        Ok(output)
+    }
+
+    /// Nodes are about to be replaced, so attribute their counters to their op class now
+    fn archive_node_counters(&mut self) {
+        for (node, counters) in std::mem::take(&mut self.node_counters) {
+            *self.class_counters.entry(self.op_class(node)).or_default() += counters;
+        }
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
+        self.try_toposort().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Refresh the internally sorted graph, failing if it has a cycle
+    pub(crate) fn try_toposort(&mut self) -> Result<(), LuminalError> {
         self.linearized_graph = Some(
             petgraph::algo::toposort(&self.graph, None)
-                .unwrap()
+                .map_err(|c| LuminalError::Cycle(c.node_id()))?
                 .into_iter()
                 .map(|node| (node, self.get_sources(node)))
                 .collect(),
@@ -164,6 +379,7 @@ impl Graph {
                 })
                 .collect(),
         );
+        Ok(())
     }
 
     /// Swap the tensors with these ids
@@ -195,24 +411,119 @@ impl Graph {
         if self.linearized_graph.is_none() {
             self.toposort();
         }
+        // Op panics aren't caught here, so nothing is returned
+        let kernel_cache = KernelCacheScope::enter();
+        let _ = self.run(false);
+        kernel_cache.finish(self);
+    }
+
+    /// Execute the graph, returning an error instead of panicking if an input was never set, a dynamic dimension has
+    /// no size or an op fails. Tensors from a failed execution are cleared, apart from kept ones.
+    pub fn try_execute(&mut self) -> Result<(), LuminalError> {
+        if self.linearized_graph.is_none() {
+            self.try_toposort()?;
+        }
+        let missing = self.missing_dyn_dims();
+        if !missing.is_empty() {
+            return Err(LuminalError::MissingDimensions(missing));
+        }
+        let kernel_cache = KernelCacheScope::enter();
+        let result = self.run(true);
+        kernel_cache.finish(self);
+        result
+    }
+
+    /// Run the linearized graph, turning op panics into errors if `catch_panics` is set
+    fn run(&mut self, catch_panics: bool) -> Result<(), LuminalError> {
         let mut consumers = self.consumers_map.as_ref().unwrap().clone();
         let mut dim_stack = Vec::new();
+        let mut memory = MemoryTracker::new(self);
+        let telemetry = self.collect_telemetry;
 
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
             if self.tensors.contains_key(&(*node, 0)) {
                 continue;
             }
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+            let in_place = self.has_attribute::<InPlace>(*node);
+            let mut srcs = get_source_tensors(
+                &self.no_delete,
+                &mut self.tensors,
+                src_ids,
+                &consumers,
+                in_place,
+            );
 
             // Substitute in the dyn dims
             for (_, st) in srcs.iter_mut() {
                 st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
             }
 
+            // Offer the op its planned buffer, and note where buffers of inputs dying here go back to
+            let planned = !self.buffer_arena.is_empty();
+            let slot = self
+                .metadata
+                .get(node)
+                .and_then(|m| m.get::<BufferSlot>())
+                .map(|s| s.0);
+            let mut returning = vec![];
+            if planned {
+                for (src, input) in src_ids.iter().zip(&srcs) {
+                    if let (InputTensor::Owned(t), Some(BufferSlot(slot))) =
+                        (&input.0, self.metadata.get(&src.0).and_then(|m| m.get()))
+                    {
+                        returning.extend(t.f32_ptr().map(|p| (p, *slot)));
+                    }
+                }
+                begin_planned_op(slot.map(|s| std::mem::take(&mut self.buffer_arena[s])));
+            }
+
             // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            let freed = owned_bytes(&srcs);
+            let read = if telemetry { read_bytes(&srcs) } else { 0 };
+            let op = self.graph.node_weight_mut(*node).unwrap();
+            let tensors = if catch_panics {
+                match catch_unwind(AssertUnwindSafe(|| op.process(srcs))) {
+                    Ok(tensors) => tensors,
+                    Err(payload) => {
+                        if planned {
+                            end_planned_op();
+                        }
+                        self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
+                        let name = format!("{:?}", self.graph.node_weight(*node).unwrap());
+                        let message = panic_message(&*payload);
+                        return Err(if message == UNSET_TENSOR {
+                            LuminalError::MissingData { node: *node, name }
+                        } else {
+                            LuminalError::OpFailed {
+                                node: *node,
+                                op: name,
+                                message,
+                            }
+                        });
+                    }
+                }
+            } else {
+                op.process(srcs)
+            };
+            if planned {
+                let (unused, recycled) = end_planned_op();
+                if let (Some(buffer), Some(slot)) = (unused, slot) {
+                    self.buffer_arena[slot] = buffer;
+                }
+                for buffer in recycled {
+                    if let Some((_, slot)) = returning.iter().find(|(p, _)| *p == buffer.as_ptr()) {
+                        self.buffer_arena[*slot] = buffer;
+                    }
+                }
+            }
+            memory.step(*node, &tensors, freed, &self.no_delete);
+            if telemetry {
+                self.node_counters
+                    .entry(*node)
+                    .or_default()
+                    .record(read, &tensors);
+            }
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
@@ -222,9 +533,138 @@ impl Graph {
                 *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
             }
         }
+        self.peak_intermediate_memory = memory.peak;
+        self.reset();
+        Ok(())
+    }
+
+    /// Execute the graph with sizes for its dynamic dimensions, checking every dimension the graph's shapes use is bound
+    /// and the sizes match the data set on the inputs (see `set_dyn_dims`)
+    pub fn execute_with_dims(&mut self, dims: impl IntoIterator<Item = (char, usize)>) {
+        self.set_dyn_dims(dims);
+        self.execute();
+    }
+
+    /// Execute the graph with sizes for its dynamic dimensions, returning an error instead of panicking if the sizes
+    /// don't fit the graph or its inputs, or the execution fails (see `try_execute`)
+    pub fn try_execute_with_dims(
+        &mut self,
+        dims: impl IntoIterator<Item = (char, usize)>,
+    ) -> Result<(), LuminalError> {
+        self.try_set_dyn_dims(dims)?;
+        self.try_execute()
+    }
+
+    /// Execute the graph, running ops that don't depend on each other at the same time on the rayon thread pool.
+    ///
+    /// The graph is split into waves, where each node only depends on nodes in earlier waves. Every op in the graph must
+    /// be safe to run on another thread alongside the rest of its wave, which holds for the CPU ops.
+    pub fn execute_parallel(&mut self) {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
+        let mut dim_stack = Vec::new();
+        let mut memory = MemoryTracker::new(self);
+        let telemetry = self.collect_telemetry;
+        let kernel_cache = KernelCacheScope::enter();
+
+        for wave in self.waves() {
+            let linearized = self.linearized_graph.as_ref().unwrap();
+            // Gather inputs for the whole wave before anything runs. Tensors shared by several nodes in the wave have
+            // multiple consumers left, so they're only ever borrowed here.
+            let mut jobs = vec![];
+            for i in wave {
+                let (node, src_ids) = &linearized[i];
+                if self.tensors.contains_key(&(*node, 0)) {
+                    continue;
+                }
+                let in_place = self.has_attribute::<InPlace>(*node);
+                let mut srcs = get_source_tensors(
+                    &self.no_delete,
+                    &mut self.tensors,
+                    src_ids,
+                    &consumers,
+                    in_place,
+                );
+                for (_, st) in srcs.iter_mut() {
+                    st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+                }
+                let op = self.graph.node_weight_mut(*node).unwrap() as *mut Box<dyn Operator>;
+                jobs.push(WaveJob {
+                    node: *node,
+                    index: i,
+                    op,
+                    freed: owned_bytes(&srcs),
+                    read: if telemetry { read_bytes(&srcs) } else { 0 },
+                    srcs,
+                });
+            }
+
+            // Execute
+            let outputs = if jobs.len() == 1 {
+                jobs.into_iter().map(WaveJob::run).collect::<Vec<_>>()
+            } else {
+                jobs.into_par_iter().map(WaveJob::run).collect::<Vec<_>>()
+            };
+
+            for WaveOutput {
+                node,
+                index,
+                freed,
+                read,
+                tensors,
+            } in outputs
+            {
+                memory.step(node, &tensors, freed, &self.no_delete);
+                if telemetry {
+                    self.node_counters
+                        .entry(node)
+                        .or_default()
+                        .record(read, &tensors);
+                }
+                for (i, tensor) in tensors.into_iter().enumerate() {
+                    self.tensors.insert((node, i as u8), tensor);
+                }
+
+                // Bookkeep remaining consumers, dropping borrowed tensors nothing else needs
+                for (id, ind, _) in &linearized[index].1 {
+                    let remaining = consumers.get_mut(&(*id, *ind)).unwrap();
+                    *remaining -= 1;
+                    if *remaining == 0 && !self.no_delete.contains(id) {
+                        if let Some(t) = self.tensors.remove(&(*id, *ind)) {
+                            memory.free(t.size_bytes());
+                        }
+                    }
+                }
+            }
+        }
+        kernel_cache.finish(self);
+        self.peak_intermediate_memory = memory.peak;
         self.reset();
     }
 
+    /// Group the linearized graph into waves of nodes that only depend on nodes in earlier waves, as indexes into the
+    /// linearized graph
+    fn waves(&self) -> Vec<Vec<usize>> {
+        let mut levels = FxHashMap::default();
+        let mut waves: Vec<Vec<usize>> = vec![];
+        for (i, (node, _)) in self.linearized_graph.as_ref().unwrap().iter().enumerate() {
+            let level = self
+                .graph
+                .neighbors_directed(*node, Direction::Incoming)
+                .map(|n| levels[&n] + 1)
+                .max()
+                .unwrap_or_default();
+            levels.insert(*node, level);
+            if waves.len() <= level {
+                waves.push(vec![]);
+            }
+            waves[level].push(i);
+        }
+        waves
+    }
+
     /// Execute the graph without deleting intermediate tensors
     pub fn execute_no_delete(&mut self) {
         // Track the number of views pointing to each tensor so we know when to clear;
@@ -232,6 +672,9 @@ impl Graph {
             self.toposort();
         }
         let mut dim_stack = Vec::new();
+        let mut memory = MemoryTracker::new(self);
+        let telemetry = self.collect_telemetry;
+        let kernel_cache = KernelCacheScope::enter();
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
             if self.tensors.contains_key(&(*node, 0)) {
                 continue;
@@ -252,11 +695,21 @@ impl Graph {
             }
 
             // All sources are ready, execute
+            let read = if telemetry { read_bytes(&srcs) } else { 0 };
             let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            memory.step(*node, &tensors, 0, &self.no_delete);
+            if telemetry {
+                self.node_counters
+                    .entry(*node)
+                    .or_default()
+                    .record(read, &tensors);
+            }
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
         }
+        kernel_cache.finish(self);
+        self.peak_intermediate_memory = memory.peak;
     }
 
     /// Execute the graph with debug prints
@@ -277,6 +730,9 @@ impl Graph {
         let mut dim_stack = Vec::new();
         let mut consumers = self.consumers_map.as_ref().unwrap().clone();
         let mut op_times = FxHashMap::default();
+        let mut memory = MemoryTracker::new(self);
+        let telemetry = self.collect_telemetry;
+        let kernel_cache = KernelCacheScope::enter();
         let width = term_size::dimensions().unwrap().0;
 
         println!(
@@ -293,8 +749,14 @@ impl Graph {
             let op_name = format!("{:?} | {}", self.node_weight(*node).unwrap(), node.index());
             print!("{}", op_name.bold().bright_green());
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+            let in_place = self.has_attribute::<InPlace>(*node);
+            let mut srcs = get_source_tensors(
+                &self.no_delete,
+                &mut self.tensors,
+                src_ids,
+                &consumers,
+                in_place,
+            );
 
             // Substitute in the dyn dims
             for (_, st) in srcs.iter_mut() {
@@ -320,9 +782,18 @@ impl Graph {
             print!("{shapes_string}");
             std::io::stdout().flush().unwrap();
             // Execute
+            let freed = owned_bytes(&srcs);
+            let read = if telemetry { read_bytes(&srcs) } else { 0 };
             let now = std::time::Instant::now();
             let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
             let elapsed = now.elapsed();
+            memory.step(*node, &tensors, freed, &self.no_delete);
+            if telemetry {
+                self.node_counters
+                    .entry(*node)
+                    .or_default()
+                    .record(read, &tensors);
+            }
             println!(
                 "{:.>1$}",
                 format_duration(&elapsed).bold(),
@@ -362,10 +833,359 @@ impl Graph {
             );
         }
         println!("Total: {}", format_duration(&start.elapsed()).bold());
+        kernel_cache.finish(self);
+        self.peak_intermediate_memory = memory.peak;
         self.reset();
     }
 }
 
+impl Graph {
+    /// Report how much memory the graph's tensors currently hold, broken down by tensor and device,
+    /// along with the peak memory used by intermediate tensors during the last execution.
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            peak_intermediate: self.peak_intermediate_memory,
+            ..Default::default()
+        };
+        for (id, tensor) in &self.tensors {
+            let bytes = tensor.size_bytes();
+            report.tensors.insert(*id, bytes);
+            *report.devices.entry(tensor.device()).or_default() += bytes;
+        }
+        report
+    }
+
+    /// Get the runtime counters accumulated since the last reset: launches and bytes moved for each op class over
+    /// executions with `collect_telemetry` on, along with hits and misses of the backends' compiled kernel caches while
+    /// compiling or executing this graph.
+    pub fn telemetry(&self) -> Telemetry {
+        let mut ops = self.class_counters.clone();
+        for (node, counters) in &self.node_counters {
+            *ops.entry(self.op_class(*node)).or_default() += *counters;
+        }
+        Telemetry {
+            ops,
+            kernel_cache_hits: self.kernel_cache_lookups.0,
+            kernel_cache_misses: self.kernel_cache_lookups.1,
+        }
+    }
+
+    /// Reset the runtime counters reported by `telemetry`
+    pub fn reset_telemetry(&mut self) {
+        self.node_counters.clear();
+        self.class_counters.clear();
+        self.kernel_cache_lookups = (0, 0);
+    }
+
+    /// The name counters for a node are grouped under, like `Add` or `MetalMatmul2D`
+    fn op_class(&self, node: NodeIndex) -> String {
+        let Some(op) = self.node_weight(node) else {
+            return "Removed".to_string();
+        };
+        if op.as_any().is::<Function>() {
+            return "Function".to_string();
+        }
+        format!("{op:?}")
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect()
+    }
+
+    /// Same as `memory_report`, but also sums up the bytes held by the weights under each module path of `model`.
+    pub fn memory_report_with_modules(&self, model: impl SerializeModule) -> MemoryReport {
+        let mut report = self.memory_report();
+        for (path, id) in param_dict(model) {
+            // Weights may have been moved to a device by a copy op downstream of the loader
+            let bytes = [id, downstream(vec![id], self)[0]]
+                .into_iter()
+                .unique()
+                .map(|n| {
+                    report
+                        .tensors
+                        .iter()
+                        .filter(|((node, _), _)| *node == n)
+                        .map(|(_, b)| *b)
+                        .sum::<usize>()
+                })
+                .max()
+                .unwrap_or_default();
+            let parts = path.split('/').collect::<Vec<_>>();
+            for i in 1..=parts.len() {
+                *report.modules.entry(parts[..i].join("/")).or_default() += bytes;
+            }
+        }
+        report
+    }
+}
+
+/// A breakdown of the memory held by a graph
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    /// Bytes held by each stored tensor
+    pub tensors: FxHashMap<(NodeIndex, u8), usize>,
+    /// Bytes held by each module path (empty unless a model was passed in)
+    pub modules: BTreeMap<String, usize>,
+    /// Total bytes held on each device
+    pub devices: FxHashMap<&'static str, usize>,
+    /// Peak bytes held by intermediate tensors during the last execution
+    pub peak_intermediate: usize,
+}
+
+impl MemoryReport {
+    /// Total bytes held by all tensors
+    pub fn total(&self) -> usize {
+        self.devices.values().sum()
+    }
+}
+
+pub(crate) fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.2} GB", b as f64 / (1 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.2} MB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.2} KB", b as f64 / (1 << 10) as f64),
+        b => format!("{b} B"),
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Total: {}", format_bytes(self.total()))?;
+        for (device, bytes) in self.devices.iter().sorted() {
+            writeln!(f, "  {device}: {}", format_bytes(*bytes))?;
+        }
+        writeln!(
+            f,
+            "Peak intermediates: {}",
+            format_bytes(self.peak_intermediate)
+        )?;
+        for (path, bytes) in &self.modules {
+            let depth = path.matches('/').count();
+            let name = path.rsplit('/').next().unwrap();
+            writeln!(
+                f,
+                "{:2$}{name}: {}",
+                "",
+                format_bytes(*bytes),
+                2 * depth + 2
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Execution counters for a node or class of ops
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpCounters {
+    /// Number of times the op was launched
+    pub launches: u64,
+    /// Bytes of input tensors read
+    pub bytes_read: u64,
+    /// Bytes of output tensors written
+    pub bytes_written: u64,
+}
+
+impl OpCounters {
+    fn record(&mut self, read: usize, outputs: &[Tensor]) {
+        self.launches += 1;
+        self.bytes_read += read as u64;
+        self.bytes_written += outputs.iter().map(|t| t.size_bytes() as u64).sum::<u64>();
+    }
+}
+
+impl std::ops::AddAssign for OpCounters {
+    fn add_assign(&mut self, rhs: Self) {
+        self.launches += rhs.launches;
+        self.bytes_read += rhs.bytes_read;
+        self.bytes_written += rhs.bytes_written;
+    }
+}
+
+/// Runtime counters accumulated over a graph's executions
+#[derive(Debug, Clone, Default)]
+pub struct Telemetry {
+    /// Counters for each class of op
+    pub ops: BTreeMap<String, OpCounters>,
+    /// Compiled kernels that were found in a backend's cache
+    pub kernel_cache_hits: u64,
+    /// Compiled kernels that had to be compiled from scratch
+    pub kernel_cache_misses: u64,
+}
+
+impl Telemetry {
+    /// Counters summed over all op classes
+    pub fn total(&self) -> OpCounters {
+        let mut total = OpCounters::default();
+        for counters in self.ops.values() {
+            total += *counters;
+        }
+        total
+    }
+
+    /// The fraction of kernel lookups served from cache, if there were any
+    pub fn kernel_cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.kernel_cache_hits + self.kernel_cache_misses;
+        (lookups > 0).then(|| self.kernel_cache_hits as f64 / lookups as f64)
+    }
+}
+
+impl std::fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (class, counters) in self
+            .ops
+            .iter()
+            .chain([(&"Total".to_string(), &self.total())])
+        {
+            writeln!(
+                f,
+                "{class}: {} launches, {} read, {} written",
+                counters.launches,
+                format_bytes(counters.bytes_read as usize),
+                format_bytes(counters.bytes_written as usize)
+            )?;
+        }
+        if let Some(rate) = self.kernel_cache_hit_rate() {
+            writeln!(
+                f,
+                "Kernel cache: {} hits, {} misses ({:.1}% hit rate)",
+                self.kernel_cache_hits,
+                self.kernel_cache_misses,
+                rate * 100.
+            )?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// Hits and misses of kernel cache lookups on this thread, while a graph is compiling or executing
+    static KERNEL_CACHE_LOOKUPS: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+/// Record a lookup of a compiled kernel. Backends call this whenever they need a kernel, with whether it could be
+/// reused from a cache instead of being compiled. The lookup counts towards the graph compiling or executing on this
+/// thread, and is ignored outside of one.
+pub fn record_kernel_cache_lookup(hit: bool) {
+    KERNEL_CACHE_LOOKUPS.with(|lookups| {
+        if let Some((hits, misses)) = lookups.get() {
+            lookups.set(Some((hits + hit as u64, misses + !hit as u64)));
+        }
+    });
+}
+
+/// Counts kernel cache lookups on this thread for one graph, putting back the counts of any graph compiling or
+/// executing around it when done
+struct KernelCacheScope(Option<(u64, u64)>);
+
+impl KernelCacheScope {
+    fn enter() -> Self {
+        Self(KERNEL_CACHE_LOOKUPS.replace(Some((0, 0))))
+    }
+
+    /// Add the lookups made since entering to the graph's counters
+    fn finish(self, graph: &mut Graph) {
+        let (hits, misses) = KERNEL_CACHE_LOOKUPS.get().unwrap_or_default();
+        graph.kernel_cache_lookups.0 += hits;
+        graph.kernel_cache_lookups.1 += misses;
+    }
+}
+
+impl Drop for KernelCacheScope {
+    fn drop(&mut self) {
+        KERNEL_CACHE_LOOKUPS.set(self.0);
+    }
+}
+
+/// Keeps track of the memory held by intermediate tensors during execution
+struct MemoryTracker {
+    live: usize,
+    peak: usize,
+}
+
+impl MemoryTracker {
+    fn new(graph: &Graph) -> Self {
+        let live = graph
+            .tensors
+            .iter()
+            .filter(|((n, _), _)| !graph.no_delete.contains(n))
+            .map(|(_, t)| t.size_bytes())
+            .sum();
+        Self { live, peak: live }
+    }
+
+    /// Record a node producing `outputs` and freeing `freed` bytes of consumed inputs
+    fn step(
+        &mut self,
+        node: NodeIndex,
+        outputs: &[Tensor],
+        freed: usize,
+        no_delete: &FxHashSet<NodeIndex>,
+    ) {
+        if !no_delete.contains(&node) {
+            self.live += outputs.iter().map(|t| t.size_bytes()).sum::<usize>();
+        }
+        self.peak = self.peak.max(self.live);
+        self.live = self.live.saturating_sub(freed);
+    }
+
+    /// Record an intermediate tensor being dropped
+    fn free(&mut self, bytes: usize) {
+        self.live = self.live.saturating_sub(bytes);
+    }
+}
+
+/// An op in a wave of parallel execution, along with its inputs
+struct WaveJob<'a> {
+    node: NodeIndex,
+    op: *mut Box<dyn Operator>,
+    /// Position in the linearized graph
+    index: usize,
+    srcs: Vec<(InputTensor<'a>, ShapeTracker)>,
+    freed: usize,
+    read: usize,
+}
+
+impl WaveJob<'_> {
+    fn run(self) -> WaveOutput {
+        let op = unsafe { self.op.as_mut().unwrap() };
+        WaveOutput {
+            node: self.node,
+            index: self.index,
+            tensors: op.process(self.srcs),
+            freed: self.freed,
+            read: self.read,
+        }
+    }
+}
+
+/// The outputs of an op run in a wave of parallel execution
+struct WaveOutput {
+    node: NodeIndex,
+    index: usize,
+    tensors: Vec<Tensor>,
+    freed: usize,
+    read: usize,
+}
+
+// Ops and tensors aren't generally thread safe, but each op in a wave is only touched by one thread, and the tensors it
+// borrows aren't modified until the wave is done
+unsafe impl Send for WaveJob<'_> {}
+unsafe impl Send for WaveOutput {}
+
+/// Bytes of all inputs an op reads
+fn read_bytes(srcs: &[(InputTensor, ShapeTracker)]) -> usize {
+    srcs.iter().map(|(t, _)| t.borrowed().size_bytes()).sum()
+}
+
+/// Bytes held by the inputs an op is consuming, which get freed once it runs
+fn owned_bytes(srcs: &[(InputTensor, ShapeTracker)]) -> usize {
+    srcs.iter()
+        .filter_map(|(t, _)| match t {
+            InputTensor::Owned(t) => Some(t.size_bytes()),
+            InputTensor::Borrowed(_) => None,
+        })
+        .sum()
+}
+
 impl Deref for Graph {
     type Target = MainGraph;
     fn deref(&self) -> &Self::Target {
@@ -385,11 +1205,13 @@ fn get_source_tensors<'a>(
     tensors: *mut FxHashMap<(NodeIndex, u8), Tensor>,
     src_ids: &'a [(NodeIndex, u8, ShapeTracker)],
     consumers: &'a FxHashMap<(NodeIndex, u8), usize>,
+    in_place: bool,
 ) -> Vec<(InputTensor<'a>, ShapeTracker)> {
     let mut srcs = vec![];
-    for (id, ind, sh) in src_ids {
+    for (i, (id, ind, sh)) in src_ids.iter().enumerate() {
         let id = &(*id, *ind);
-        if consumers[id] == 1 && !no_delete.contains(&id.0) {
+        // Kept inputs are handed to ops updating them in place, which move them into their output
+        if consumers[id] == 1 && (!no_delete.contains(&id.0) || (in_place && i == 0)) {
             srcs.push((
                 InputTensor::Owned(unsafe { tensors.as_mut().unwrap() }.remove(id).unwrap()),
                 *sh,
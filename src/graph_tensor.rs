@@ -1,4 +1,5 @@
 use crate::prelude::*;
+use std::borrow::Cow;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
@@ -18,22 +19,27 @@ use petgraph::graph::NodeIndex;
 #[derive(Clone, Copy)]
 pub struct GraphTensor<S: Shape> {
     pub id: NodeIndex,
-    pub graph_ref: *mut Graph,
+    pub(crate) graph_ref: *mut Graph,
     pub(crate) _phantom: PhantomData<S>,
     pub shape: ShapeTracker,
 }
 
 impl<S: Shape> GraphTensor<S> {
     /// Create a GraphTensor from a NodeIndex
-    pub fn from_id(id: NodeIndex, shape: ShapeTracker, graph_ref: *mut Graph) -> Self {
+    pub fn from_id(id: NodeIndex, shape: ShapeTracker, graph: &mut Graph) -> Self {
         Self {
             id,
-            graph_ref,
+            graph_ref: graph,
             shape,
             _phantom: Default::default(),
         }
     }
 
+    /// View the same node with another shape type and shape tracker, for views the typed movement ops can't express
+    pub fn with_shape<N: Shape>(self, shape: ShapeTracker) -> GraphTensor<N> {
+        GraphTensor::from_id(self.id, shape, self.graph())
+    }
+
     /// Mark this tensor to not be deleted
     pub fn keep(self) -> Self {
         self.graph().keep_tensors(self.id);
@@ -52,12 +58,31 @@ impl<S: Shape> GraphTensor<S> {
         self.graph().drop_tensors(self.id);
     }
 
-    /// Get a mutable reference to the graph this tensor belongs to
+    /// Get a mutable reference to the graph this tensor belongs to. Only held for the length of one op, so no two
+    /// of these are ever alive at once.
     #[allow(clippy::mut_from_ref)]
-    pub fn graph(&self) -> &mut Graph {
+    pub(crate) fn graph(&self) -> &mut Graph {
         unsafe { self.graph_ref.as_mut().unwrap() }
     }
 
+    /// Run a function with the graph this tensor belongs to, for building new tensors (constants, masks, custom ops)
+    /// in model code that only has its inputs.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<3>>();
+    /// let b = unsafe { a.with_graph(|cx| cx.arange::<Const<3>>()) } + a;
+    /// ```
+    ///
+    /// # Safety
+    /// Tensors only hold a pointer to their graph, so the graph this tensor was made on must still be alive and must not
+    /// have moved. The `&mut Graph` given to `f` aliases the one every tensor op on the same graph reaches through that
+    /// pointer, so `f` must not call `with_graph` again or hold a reference into the graph (like from `get_op` or
+    /// `attribute`) across a tensor op.
+    pub unsafe fn with_graph<R>(&self, f: impl FnOnce(&mut Graph) -> R) -> R {
+        f(self.graph())
+    }
+
     /// Set the value of the tensor, with dynamic dimensions.
     /// ```rust
     /// use luminal::prelude::*;
@@ -78,11 +103,89 @@ impl<S: Shape> GraphTensor<S> {
                 self.graph().dyn_map.insert(c, *s);
             }
         }
+        self.set_data(data)
+    }
+
+    /// Set the value of the tensor, checking the data fills its shape. Dynamic dimensions must already have sizes.
+    pub fn try_set<T: Data + Clone, D: ToData<S, T>>(self, data: D) -> Result<Self, LuminalError> {
+        let data = data.to_data_vec();
+        let n_elements = self.shape.n_elements();
+        let Some(expected) = n_elements.exec(&self.graph().dyn_map) else {
+            let mut dims = n_elements.to_symbols();
+            dims.retain(|d| !self.graph().dyn_map.contains_key(d));
+            dims.sort();
+            dims.dedup();
+            return Err(LuminalError::MissingDimensions(dims));
+        };
+        let data_any = &data as &dyn std::any::Any;
+        let found = data_any
+            .downcast_ref::<Vec<f32>>()
+            .map(|d| d.len())
+            .or_else(|| data_any.downcast_ref::<HalfVec>().map(|d| d.len()))
+            .or_else(|| data_any.downcast_ref::<IndexVec>().map(|d| d.len()));
+        if let Some(found) = found.filter(|f| *f != expected) {
+            return Err(LuminalError::ShapeMismatch {
+                node: self.id,
+                name: format!("{:?}", self.graph().node_weight(self.id).unwrap()),
+                expected,
+                found,
+            });
+        }
+        Ok(self.set_data(data))
+    }
+
+    /// Make the loading function output this data, recording its dtype so ops downstream know it
+    fn set_data<T: Data + Clone>(self, data: T) -> Self {
+        let data_any = &data as &dyn std::any::Any;
+        if let Some(h) = data_any.downcast_ref::<HalfVec>() {
+            self.graph().set_attribute(self.id, h.dtype());
+        } else if data_any.is::<IndexVec>() {
+            self.graph().set_attribute(self.id, DType::U32);
+        } else {
+            self.graph().remove_attribute::<DType>(self.id);
+        }
+        let len = data_any
+            .downcast_ref::<Vec<f32>>()
+            .map(|d| d.len())
+            .or_else(|| data_any.downcast_ref::<HalfVec>().map(|d| d.len()))
+            .or_else(|| data_any.downcast_ref::<IndexVec>().map(|d| d.len()));
+        self.set_data_length(len);
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(data.to_owned())]);
         self
     }
 
+    /// Record how many elements the loader outputs, if known, so bound dynamic dimensions can be checked against it
+    fn set_data_length(&self, len: Option<usize>) {
+        if let Some(len) = len {
+            self.graph().set_attribute(self.id, DataLength(len));
+        } else {
+            self.graph().remove_attribute::<DataLength>(self.id);
+        }
+    }
+
+    /// Set the value of the tensor to data that lives for the whole program (like a leaked or mmap'd buffer),
+    /// which is read in place on every execution rather than copied.
+    pub fn set_borrowed(self, data: &'static [f32]) -> Self {
+        unsafe { self.set_external(data.as_ptr(), data.len()) }
+    }
+
+    /// Set the value of the tensor to externally owned memory, which is read in place on every execution rather than copied.
+    ///
+    /// # Safety
+    /// `ptr` must point to `len` valid f32s whenever the graph is executed, and must not be written to during execution.
+    pub unsafe fn set_external(self, ptr: *const f32, len: usize) -> Self {
+        self.set_data_length(Some(len));
+        self.graph().get_op_mut::<Function>(self.id).1 =
+            Box::new(move |_| vec![Tensor::new(unsafe { BorrowedSlice::new(ptr, len) })]);
+        self
+    }
+
+    /// The dtype this tensor's data will be in
+    pub fn dtype(&self) -> DType {
+        self.graph().dtype(self.id)
+    }
+
     /// Set the name of a tensor
     pub fn set_name(&self, name: &str) {
         self.graph().get_op_mut::<Function>(self.id).0 = name.to_string();
@@ -90,41 +193,126 @@ impl<S: Shape> GraphTensor<S> {
 
     /// Convert tensor to a shapeless tensor
     pub fn no_shape(self) -> GraphTensor<()> {
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     /// Get the contiguous data of the tensor
     pub fn data(&self) -> Vec<f32> {
+        if let Some(data) = self.data_ref() {
+            return data.to_vec();
+        }
+        let mut data = vec![0.; self.n_elements()];
+        self.data_into(&mut data);
+        data
+    }
+
+    /// Get the contiguous data of the tensor in double precision, for graphs ran in f64
+    pub fn data_f64(&self) -> Vec<f64> {
         let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
-        let orig_data = tensor.downcast_ref::<Vec<f32>>().unwrap();
-        let mut st = self.shape;
-        if !st.is_reshaped() {
-            return orig_data.clone();
+        let mut data = vec![0.; self.n_elements()];
+        if let Some(F64Data(d)) = tensor.downcast_ref::<F64Data>() {
+            self.read_into(&mut data, |i| d[i]);
+        } else {
+            let d = tensor.f32_data().unwrap();
+            self.read_into(&mut data, |i| d[i] as f64);
+        }
+        data
+    }
+
+    /// Borrow the tensor's data without copying it, if it's already stored contiguously as f32
+    pub fn data_ref(&self) -> Option<&[f32]> {
+        if self.shape.is_reshaped() {
+            return None;
         }
+        match self
+            .graph()
+            .get_tensor_ref(self.id, 0)
+            .unwrap()
+            .f32_data()?
+        {
+            Cow::Borrowed(d) => Some(d),
+            Cow::Owned(_) => None,
+        }
+    }
+
+    /// Move the tensor's data out of the graph, avoiding a copy if it's already stored contiguously
+    pub fn take_data(&self) -> Vec<f32> {
+        if self.data_ref().is_none() {
+            let data = self.data();
+            self.drop();
+            return data;
+        }
+        let mut tensor = self.graph().get_tensor(self.id, 0).unwrap();
+        std::mem::take(tensor.downcast_mut::<Vec<f32>>().unwrap())
+    }
+
+    /// Write the contiguous data of the tensor into a caller-provided slice
+    pub fn data_into(&self, out: &mut [f32]) {
+        assert_eq!(
+            out.len(),
+            self.n_elements(),
+            "Output slice has the wrong length"
+        );
+        if let Some(data) = self.data_ref() {
+            out.copy_from_slice(data);
+            return;
+        }
+        let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
+        if let Some(F64Data(d)) = tensor.downcast_ref::<F64Data>() {
+            self.read_into(out, |i| d[i] as f32);
+        } else {
+            let d = tensor.f32_data().unwrap();
+            self.read_into(out, |i| d[i]);
+        }
+    }
+
+    /// Read the elements of this tensor's view in order
+    fn read_into<T: Default>(&self, out: &mut [T], get: impl Fn(usize) -> T) {
+        if !self.shape.is_reshaped() {
+            for (i, r) in out.iter_mut().enumerate() {
+                *r = get(i);
+            }
+            return;
+        }
+        let mut st = self.shape;
         st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
         let (ind, val) = (st.index_expression(), st.valid_expression());
-        #[allow(unused_mut)]
-        for (i, mut r) in data.iter_mut().enumerate() {
-            if val.exec_single_var(i) != 0 {
-                *r = orig_data[ind.exec_single_var(i)];
+        for (i, r) in out.iter_mut().enumerate() {
+            *r = if val.exec_single_var(i) != 0 {
+                get(ind.exec_single_var(i))
+            } else {
+                T::default()
+            };
+        }
+    }
+
+    /// Number of elements in the tensor, with dynamic dimensions resolved
+    fn n_elements(&self) -> usize {
+        if !self.shape.is_reshaped() {
+            if let Some(tensor) = self.graph().get_tensor_ref(self.id, 0) {
+                if let Some(d) = tensor.f32_data() {
+                    return d.len();
+                }
+                if let Some(F64Data(d)) = tensor.downcast_ref::<F64Data>() {
+                    return d.len();
+                }
             }
         }
-        data
+        let mut st = self.shape;
+        st.resolve_global_dyn_dims(&self.graph().dyn_map);
+        st.n_elements().to_usize().unwrap()
     }
 }
 
 impl<S: ConstShape> GraphTensor<S> {
     /// Set the value of the tensor matching the constant shape
     pub fn set<T: Data + Clone, D: ToData<S, T>>(self, data: D) -> Self {
-        let data = data.to_data_vec();
-        self.graph().get_op_mut::<Function>(self.id).1 =
-            Box::new(move |_| vec![Tensor::new(data.to_owned())]);
-        self
+        self.set_data(data.to_data_vec())
     }
 
     /// Set the tensor with a generating closure to be ran at runtime
     pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + 'static) -> Self {
+        self.set_data_length(None);
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(loader())]);
         self
@@ -349,6 +537,31 @@ impl<S: Shape> ToData<S, Vec<f32>> for Vec<f32> {
         self
     }
 }
+impl<S: Shape> ToData<S, HalfVec> for HalfVec {
+    fn to_data_vec(self) -> HalfVec {
+        self
+    }
+}
+impl<S: Shape> ToData<S, HalfVec> for Vec<f16> {
+    fn to_data_vec(self) -> HalfVec {
+        self.into()
+    }
+}
+impl<S: Shape> ToData<S, HalfVec> for Vec<bf16> {
+    fn to_data_vec(self) -> HalfVec {
+        self.into()
+    }
+}
+impl<S: Shape> ToData<S, IndexVec> for IndexVec {
+    fn to_data_vec(self) -> IndexVec {
+        self
+    }
+}
+impl<S: Shape> ToData<S, IndexVec> for Vec<u32> {
+    fn to_data_vec(self) -> IndexVec {
+        self.into()
+    }
+}
 impl ToData<R0, Vec<f32>> for f32 {
     fn to_data_vec(self) -> Vec<f32> {
         vec![self]
@@ -18,7 +18,7 @@ impl<S: Shape> Add for GraphTensor<S> {
             .input(self.id, 0, self.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 }
 
@@ -69,7 +69,7 @@ impl<S: Shape> Mul for GraphTensor<S> {
             .input(self.id, 0, self.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 }
 
@@ -122,7 +122,7 @@ impl<S: Shape> Rem<GraphTensor<S>> for GraphTensor<S> {
             .input(self.id, 0, self.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 }
 
@@ -238,7 +238,7 @@ impl<S: Shape> GraphTensor<S> {
             .input(self.id, 0, self.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     pub fn greater_than(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
@@ -261,6 +261,56 @@ impl<S: Shape> GraphTensor<S> {
         -self.not_equals(rhs) + 1.0
     }
 
+    /// Mask (`DType::Bool`) of where this tensor is less than `rhs`
+    pub fn lt_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.less_than(rhs).as_mask()
+    }
+
+    /// Mask (`DType::Bool`) of where this tensor is greater than `rhs`
+    pub fn gt_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.greater_than(rhs).as_mask()
+    }
+
+    /// Mask (`DType::Bool`) of where this tensor is less than or equal to `rhs`
+    pub fn le_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.less_than_equal(rhs).as_mask()
+    }
+
+    /// Mask (`DType::Bool`) of where this tensor is greater than or equal to `rhs`
+    pub fn ge_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.greater_than_equal(rhs).as_mask()
+    }
+
+    /// Mask (`DType::Bool`) of where this tensor is equal to `rhs`
+    pub fn eq_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.equals(rhs).as_mask()
+    }
+
+    /// Mask (`DType::Bool`) of where this tensor isn't equal to `rhs`
+    pub fn ne_mask(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
+        self.not_equals(rhs).as_mask()
+    }
+
+    /// Comparisons already produce 0s and 1s, so this only records that they're a mask
+    fn as_mask(self) -> GraphTensor<S> {
+        self.graph().set_attribute(self.id, DType::Bool);
+        self
+    }
+
+    /// Treating this tensor as a mask, pick elements from `a` where it's nonzero and from `b` everywhere else
+    pub fn where_(mut self, mut a: GraphTensor<S>, mut b: GraphTensor<S>) -> GraphTensor<S> {
+        resolve_local_dyn_dims(&mut a.shape, &mut b.shape, false);
+        resolve_local_dyn_dims(&mut self.shape, &mut a.shape, false);
+        let new_id = self
+            .graph()
+            .add_op(op::Where)
+            .input(self.id, 0, self.shape)
+            .input(a.id, 0, a.shape)
+            .input(b.id, 0, b.shape)
+            .finish();
+        GraphTensor::from_id(new_id, a.shape.contiguous(), self.graph())
+    }
+
     /// Raise the tensor to a power
     pub fn pow<T>(self, e: T) -> GraphTensor<S>
     where
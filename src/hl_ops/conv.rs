@@ -0,0 +1,208 @@
+use crate::{
+    op::{AvgPooling2D, Convolution2D, MaxPooling2D, Operator},
+    prelude::*,
+};
+
+impl<B: Dimension, C: Dimension, H: Dimension, W: Dimension> GraphTensor<(B, C, H, W)> {
+    /// 2D convolution with a (channels_out, channels_in / groups, kernel_height, kernel_width) weight, see
+    /// `Convolution2D`. The output height and width come from `Convolution2D::output_size`.
+    pub fn conv2d<
+        CO: Dimension,
+        CG: Dimension,
+        KH: Dimension,
+        KW: Dimension,
+        OH: Dimension,
+        OW: Dimension,
+    >(
+        self,
+        weight: GraphTensor<(CO, CG, KH, KW)>,
+        conv: Convolution2D,
+    ) -> GraphTensor<(B, CO, OH, OW)> {
+        let (in_shape, w_shape) = (self.shape.shape(), weight.shape.shape());
+        let kernel = |i: usize| {
+            w_shape[i]
+                .to_usize()
+                .expect("Convolution kernel sizes must be known")
+        };
+        let (out_height, out_width) = conv.output_size(
+            (in_shape[2].small(), in_shape[3].small()),
+            (kernel(2), kernel(3)),
+        );
+        let new_id = self
+            .graph()
+            .add_op(conv)
+            .input(self.id, 0, self.shape)
+            .input(weight.id, 0, weight.shape)
+            .finish();
+        GraphTensor::from_id(
+            new_id,
+            ShapeTracker::new(&[
+                in_shape[0].small(),
+                w_shape[0].small(),
+                out_height,
+                out_width,
+            ]),
+            self.graph(),
+        )
+    }
+}
+
+impl<C: Dimension, H: Dimension, W: Dimension> GraphTensor<(C, H, W)> {
+    /// 2D convolution of a single (channels, height, width) input, see the batched `conv2d`
+    pub fn conv2d<
+        CO: Dimension,
+        CG: Dimension,
+        KH: Dimension,
+        KW: Dimension,
+        OH: Dimension,
+        OW: Dimension,
+    >(
+        self,
+        weight: GraphTensor<(CO, CG, KH, KW)>,
+        conv: Convolution2D,
+    ) -> GraphTensor<(CO, OH, OW)> {
+        let mut out: GraphTensor<(Const<1>, CO, OH, OW)> =
+            self.expand::<(Const<1>, C, H, W), _>().conv2d(weight, conv);
+        // The batch dimension is 1, so removing it leaves the data in place
+        out.shape.remove_dim(0);
+        GraphTensor::from_id(out.id, out.shape, out.graph())
+    }
+}
+
+impl<B: Dimension, C: Dimension, H: Dimension, W: Dimension> GraphTensor<(B, C, H, W)> {
+    /// Max pooling over the last two dimensions, see `MaxPooling2D`
+    pub fn max_pool2d<OH: Dimension, OW: Dimension>(
+        self,
+        pool: MaxPooling2D,
+    ) -> GraphTensor<(B, C, OH, OW)> {
+        let shape = self.shape.shape();
+        self.pool2d(pool, pool.output_size((shape[2].small(), shape[3].small())))
+    }
+
+    /// Average pooling over the last two dimensions, see `AvgPooling2D`
+    pub fn avg_pool2d<OH: Dimension, OW: Dimension>(
+        self,
+        pool: AvgPooling2D,
+    ) -> GraphTensor<(B, C, OH, OW)> {
+        let shape = self.shape.shape();
+        self.pool2d(pool, pool.output_size((shape[2].small(), shape[3].small())))
+    }
+
+    fn pool2d<Dst: Shape, O: Operator + 'static>(
+        self,
+        op: O,
+        (out_height, out_width): (Expression, Expression),
+    ) -> GraphTensor<Dst> {
+        let shape = self.shape.shape();
+        let new_id = self
+            .graph()
+            .add_op(op)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(
+            new_id,
+            ShapeTracker::new(&[shape[0].small(), shape[1].small(), out_height, out_width]),
+            self.graph(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::op::{AvgPooling2D, Convolution2D, MaxPooling2D};
+    crate::test_imports!();
+
+    #[test]
+    fn test_conv2d() {
+        let mut cx = Graph::new();
+        let image = cx
+            .tensor::<R3<1, 3, 3>>()
+            .set(vec![1., 2., 3., 4., 5., 6., 7., 8., 9.]);
+        let ones = cx.tensor::<R4<1, 1, 2, 2>>().set(vec![1.; 4]);
+        // Zero padding with a stride
+        let padded = image
+            .conv2d::<_, _, _, _, LConst<2>, LConst<2>>(
+                ones,
+                Convolution2D {
+                    stride: (2, 2),
+                    padding: (1, 1),
+                    ..Default::default()
+                },
+            )
+            .retrieve();
+        // A dilated kernel only sees the corners
+        let dilated = image
+            .conv2d::<_, _, _, _, LConst<1>, LConst<1>>(
+                ones,
+                Convolution2D {
+                    dilation: (1, 1),
+                    ..Default::default()
+                },
+            )
+            .retrieve();
+        // Each group scales its own channel
+        let channels = cx.tensor::<R4<1, 2, 1, 2>>().set(vec![1., 2., 3., 4.]);
+        let scales = cx.tensor::<R4<2, 1, 1, 1>>().set(vec![2., 3.]);
+        let grouped = channels
+            .conv2d::<_, _, _, _, LConst<1>, LConst<2>>(
+                scales,
+                Convolution2D {
+                    groups: 2,
+                    ..Default::default()
+                },
+            )
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&padded.data(), &[1., 5., 11., 28.]);
+        assert_exact(&dilated.data(), &[20.]);
+        assert_exact(&grouped.data(), &[2., 4., 9., 12.]);
+    }
+
+    #[test]
+    fn test_pool2d() {
+        let mut cx = Graph::new();
+        let image = cx
+            .tensor::<R4<1, 1, 4, 4>>()
+            .set((1..=16).map(|i| i as f32).collect::<Vec<_>>());
+        let max = image
+            .max_pool2d::<LConst<2>, LConst<2>>(MaxPooling2D {
+                kernel: (2, 2),
+                stride: (2, 2),
+                padding: (0, 0),
+            })
+            .retrieve();
+        let avg = image
+            .avg_pool2d::<LConst<2>, LConst<2>>(AvgPooling2D {
+                kernel: (2, 2),
+                stride: (2, 2),
+                padding: (0, 0),
+            })
+            .retrieve();
+        // Overlapping windows over a padded input
+        let padded_max = image
+            .max_pool2d::<LConst<2>, LConst<2>>(MaxPooling2D {
+                kernel: (3, 3),
+                stride: (2, 2),
+                padding: (1, 1),
+            })
+            .retrieve();
+        let padded_avg = image
+            .avg_pool2d::<LConst<2>, LConst<2>>(AvgPooling2D {
+                kernel: (3, 3),
+                stride: (2, 2),
+                padding: (1, 1),
+            })
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&max.data(), &[6., 8., 14., 16.]);
+        assert_exact(&avg.data(), &[3.5, 5.5, 11.5, 13.5]);
+        assert_exact(&padded_max.data(), &[6., 8., 14., 16.]);
+        // Padding counts towards the average
+        assert_close(
+            &padded_avg.data(),
+            &[14. / 9., 30. / 9., 57. / 9., 99. / 9.],
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::{op, prelude::*};
+
+impl Graph {
+    /// Contract tensors according to an Einstein summation spec, like `"bij,bjk->bik"` for a batched matmul.
+    ///
+    /// Each operand gets one letter per dimension. Letters missing from the output are summed over, and shared letters
+    /// must have the same size. Without `->`, the output is every letter used exactly once, in alphabetical order.
+    ///
+    /// This lowers to permutes, expands, a broadcasted multiply and sum reduces, so two operand contractions end up
+    /// in the same form as `matmul`. Letters only one operand uses are summed out before the multiply.
+    pub fn einsum<Dst: Shape>(
+        &mut self,
+        spec: &str,
+        operands: &[GraphTensor<()>],
+    ) -> GraphTensor<Dst> {
+        let (inputs, output) = parse_einsum(spec);
+        assert_eq!(
+            inputs.len(),
+            operands.len(),
+            "Einsum spec {spec} has {} operands, but {} tensors were given",
+            inputs.len(),
+            operands.len()
+        );
+        // Find the size of each letter
+        let mut sizes = FxHashMap::<char, Expression>::default();
+        for (letters, operand) in inputs.iter().zip(operands) {
+            assert_eq!(
+                letters.len(),
+                operand.shape.len(),
+                "Einsum operand {} doesn't match a tensor with {} dimensions",
+                letters.iter().collect::<String>(),
+                operand.shape.len()
+            );
+            for (l, size) in letters.iter().zip(operand.shape.shape()) {
+                let size = size.small();
+                let known = *sizes.entry(*l).or_insert(size);
+                if let (Some(a), Some(b)) = (known.to_usize(), size.to_usize()) {
+                    assert_eq!(a, b, "Einsum letter {l} has sizes {a} and {b}");
+                }
+            }
+        }
+        for l in &output {
+            assert!(
+                sizes.contains_key(l),
+                "Einsum output letter {l} isn't in any operand"
+            );
+        }
+        // Output letters come first, so contracted letters are at the back to be reduced. Letters only one operand
+        // uses are summed out of it before the multiply, so they aren't here.
+        let counts = inputs.iter().flatten().counts();
+        let all = output
+            .iter()
+            .chain(
+                inputs
+                    .iter()
+                    .flatten()
+                    .filter(|l| !output.contains(l) && counts[l] > 1)
+                    .unique(),
+            )
+            .copied()
+            .collect_vec();
+
+        let product = inputs
+            .iter()
+            .zip(operands)
+            .map(|(letters, operand)| {
+                // Sum out letters no other operand or the output uses
+                let mut letters = letters.clone();
+                let mut tensor = *operand;
+                for dim in (0..letters.len()).rev() {
+                    if !all.contains(&letters[dim]) {
+                        tensor = sum_reduce_dim(tensor, dim);
+                        letters.remove(dim);
+                    }
+                }
+                // Line the rest up with all the letters, expanding the ones this operand doesn't have
+                let order = all
+                    .iter()
+                    .filter_map(|l| letters.iter().position(|m| m == l))
+                    .collect_vec();
+                tensor.shape.permute(&order);
+                for (dim, l) in all.iter().enumerate() {
+                    if !letters.contains(l) {
+                        tensor.shape.expand(dim, sizes[l]);
+                    }
+                }
+                tensor
+            })
+            .reduce(|a, b| a * b)
+            .expect("Einsum needs at least one operand");
+
+        let mut result = product;
+        for dim in (output.len()..all.len()).rev() {
+            result = sum_reduce_dim(result, dim);
+        }
+        GraphTensor::from_id(result.id, result.shape, self)
+    }
+}
+
+/// Split an einsum spec into the letters of each operand and of the output
+fn parse_einsum(spec: &str) -> (Vec<Vec<char>>, Vec<char>) {
+    let spec = spec
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    let (inputs, output) = match spec.split_once("->") {
+        Some((inputs, output)) => (inputs, Some(output)),
+        None => (spec.as_str(), None),
+    };
+    let inputs = inputs
+        .split(',')
+        .map(|operand| operand.chars().collect_vec())
+        .collect_vec();
+    for letters in &inputs {
+        for (i, l) in letters.iter().enumerate() {
+            assert!(
+                l.is_ascii_alphabetic(),
+                "Einsum specs can only use letters, found {l} in {spec}"
+            );
+            assert!(
+                !letters[..i].contains(l),
+                "Einsum letter {l} is repeated within an operand in {spec}, which isn't supported"
+            );
+        }
+    }
+    let output = match output {
+        Some(output) => output.chars().collect_vec(),
+        // Implicit output is every letter used once, in alphabetical order
+        None => inputs
+            .iter()
+            .flatten()
+            .counts()
+            .into_iter()
+            .filter(|(_, n)| *n == 1)
+            .map(|(l, _)| *l)
+            .sorted()
+            .collect(),
+    };
+    assert!(
+        output.iter().all_unique(),
+        "Einsum output letters must be unique in {spec}"
+    );
+    (inputs, output)
+}
+
+fn sum_reduce_dim(tensor: GraphTensor<()>, dim: usize) -> GraphTensor<()> {
+    let id = tensor
+        .graph()
+        .add_op(op::SumReduce(dim))
+        .input(tensor.id, 0, tensor.shape)
+        .finish();
+    let mut shape = tensor.shape;
+    shape.remove_dim(dim);
+    GraphTensor::from_id(id, shape, tensor.graph())
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_einsum() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let c = cx.tensor::<R2<4, 2>>().set(random_vec(8));
+        let x = cx.tensor::<R3<2, 3, 4>>().set(random_vec(24));
+        let y = cx.tensor::<R3<2, 4, 5>>().set(random_vec(40));
+
+        let matmul = cx
+            .einsum::<R2<2, 4>>("ij,jk->ik", &[a.no_shape(), b.no_shape()])
+            .retrieve();
+        let implicit = cx
+            .einsum::<R2<2, 4>>("ij, jk", &[a.no_shape(), b.no_shape()])
+            .retrieve();
+        let batched = cx
+            .einsum::<R3<2, 3, 5>>("bij,bjk->bik", &[x.no_shape(), y.no_shape()])
+            .retrieve();
+        let chain = cx
+            .einsum::<R2<2, 2>>("ij,jk,kl->il", &[a.no_shape(), b.no_shape(), c.no_shape()])
+            .retrieve();
+        let transpose = cx.einsum::<R2<3, 2>>("ij->ji", &[a.no_shape()]).retrieve();
+        let total = cx.einsum::<R0>("ij->", &[a.no_shape()]).retrieve();
+        // Letters only one operand has are summed out before the outer product
+        let outer = cx
+            .einsum::<R2<2, 4>>("ij,kl->ik", &[a.no_shape(), c.no_shape()])
+            .retrieve();
+        let expected = (
+            a.matmul(b).retrieve(),
+            x.matmul(y).retrieve(),
+            a.matmul(b).matmul(c).retrieve(),
+            a.permute::<R2<3, 2>, _>().retrieve(),
+            a.sum_reduce::<R0, _>().retrieve(),
+            (a.sum_reduce::<R1<2>, LAxis<1>>()
+                .expand::<R2<2, 4>, LAxis<1>>()
+                * c.sum_reduce::<R1<4>, LAxis<1>>()
+                    .expand::<R2<2, 4>, LAxis<0>>())
+            .retrieve(),
+        );
+        cx.execute();
+
+        assert_close(&matmul.data(), &expected.0.data());
+        assert_close(&implicit.data(), &expected.0.data());
+        assert_close(&batched.data(), &expected.1.data());
+        assert_close(&chain.data(), &expected.2.data());
+        assert_close(&transpose.data(), &expected.3.data());
+        assert_close(&total.data(), &expected.4.data());
+
+        assert_close(&outer.data(), &expected.5.data());
+    }
+}
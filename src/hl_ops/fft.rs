@@ -0,0 +1,484 @@
+use std::f32::consts::PI;
+
+use crate::{op, prelude::*};
+
+// Fourier transforms are built from primitive ops, so every backend gets them for free. Lengths that are powers of two
+// known when the graph is built use a radix-2 FFT, taking O(n log n) work. Other lengths fall back to dense DFT matmuls,
+// taking O(n^2). All transforms operate along the last dimension. Complex tensors are represented as (real, imaginary)
+// pairs.
+
+impl Graph {
+    /// The first `len` elements of an ARange from 0 to `n`, where both may be runtime expressions.
+    ///
+    /// Slicing is used because pooling over a compound expression (like `n / 2 + 1`) overflows the expression storage
+    fn arange_slice(&mut self, n: Expression, len: Expression) -> GraphTensor<()> {
+        let mut arange = if n.to_usize().map(|i| i == 1).unwrap_or_default() {
+            self.constant(0.).expand_to(ShapeTracker::new(&[n]))
+        } else {
+            self.constant(1.)
+                .expand_to(ShapeTracker::new(&[n]))
+                .cumsum_last_dim()
+                - 1.
+        };
+        arange.shape.slice(&[(0.into(), len)]);
+        arange
+    }
+
+    /// A 1D tensor of values known when the graph is built, loaded when it runs
+    fn load_vec(&mut self, name: &str, values: Vec<f32>) -> GraphTensor<()> {
+        let len = values.len();
+        let tensor = self
+            .named_tensor::<()>(name)
+            .set_deferred(move || values.clone());
+        GraphTensor::from_id(tensor.id, ShapeTracker::new(&[len.into()]), self)
+    }
+
+    /// The (real, imaginary) parts of the twiddle factors `e^(-2pi * i * j / n)` for `j` in `0..n / 2`
+    fn twiddles(&mut self, n: usize) -> (GraphTensor<()>, GraphTensor<()>) {
+        let angles = (0..n / 2)
+            .map(|j| -2. * std::f64::consts::PI * j as f64 / n as f64)
+            .collect::<Vec<_>>();
+        (
+            self.load_vec(
+                "Twiddles Real",
+                angles.iter().map(|a| a.cos() as f32).collect(),
+            ),
+            self.load_vec(
+                "Twiddles Imag",
+                angles.iter().map(|a| a.sin() as f32).collect(),
+            ),
+        )
+    }
+
+    /// Twiddle factor angles `2pi * (o * i mod period) / period` for an (n_out, n_in) transform matrix
+    fn twiddle_angles(
+        &mut self,
+        n_out: Expression,
+        n_in: Expression,
+        period: Expression,
+    ) -> GraphTensor<()> {
+        let mut out_ind = self.arange_slice(period, n_out);
+        out_ind.shape.expand(1, n_in);
+        let mut in_ind = self.arange_slice(period, n_in);
+        in_ind.shape.expand(0, n_out);
+        let period_t = self.constant_expr(period).expand_to(out_ind.shape);
+        // Reducing mod the period keeps the angles small, which matters for f32 precision on long signals
+        (out_ind * in_ind) % period_t * (2. * PI) / period_t
+    }
+}
+
+/// Apply an (n_out, n_in) transform matrix along the last dimension of `x`
+fn apply_last_dim(x: GraphTensor<()>, matrix: GraphTensor<()>) -> GraphTensor<()> {
+    let n_dims = x.shape.len();
+    let mut x_shape = x.shape;
+    x_shape.expand(n_dims - 1, matrix.shape.shape()[0].small());
+    let mut m_shape = matrix.shape;
+    for i in 0..n_dims - 1 {
+        m_shape.expand(i, x.shape.shape()[i].small());
+    }
+    let mul = GraphTensor::<()>::from_id(x.id, x_shape, x.graph())
+        * GraphTensor::<()>::from_id(matrix.id, m_shape, matrix.graph());
+    let mut shape = mul.shape;
+    let new_id = x
+        .graph()
+        .add_op(op::SumReduce(n_dims))
+        .input(mul.id, 0, shape)
+        .finish();
+    shape.remove_dim(n_dims);
+    GraphTensor::from_id(new_id, shape, x.graph())
+}
+
+/// The length of a transform if the radix-2 FFT can compute it, which needs a power of two known when the graph is built
+fn radix2_len(n: Expression) -> Option<usize> {
+    n.to_usize().filter(|n| n.is_power_of_two())
+}
+
+/// Radix-2 FFT of a complex signal along the last dimension, which has length `n`.
+///
+/// Each level of the decimation in frequency splits every signal into halves, which combine into two signals of half
+/// the length whose transforms are the even and odd frequencies. A level is a handful of elementwise ops over the
+/// whole signal, batched over every sub-transform, and there are log2(n) levels.
+fn radix2_fft(
+    re: GraphTensor<()>,
+    im: GraphTensor<()>,
+    n: usize,
+) -> (GraphTensor<()>, GraphTensor<()>) {
+    // Flatten the leading dimensions into a batch, with a dimension for the sub-transforms of each level
+    let mut dims = re
+        .shape
+        .shape()
+        .into_iter()
+        .map(|d| d.small())
+        .collect::<Vec<_>>();
+    let batch = dims[..dims.len() - 1]
+        .iter()
+        .fold(Expression::from(1), |acc, d| acc * *d);
+    let flat = |x: GraphTensor<()>| {
+        x.contiguous()
+            .dyn_reshape::<()>(vec![batch, 1.into(), n.into()])
+    };
+    let (mut re, mut im) = (flat(re), flat(im));
+    let zero = Expression::from(0);
+
+    // Split down to transforms of length 1, which are their own transforms
+    let (mut len, mut m) = (n, 1);
+    while len > 1 {
+        let h = len / 2;
+        let half = |mut x: GraphTensor<()>, start: usize| {
+            x.shape.slice(&[
+                (0.into(), i32::MAX.into()),
+                (0.into(), i32::MAX.into()),
+                (start.into(), (start + h).into()),
+            ]);
+            x
+        };
+        let (a_re, a_im, b_re, b_im) = (half(re, 0), half(im, 0), half(re, h), half(im, h));
+
+        let (mut w_re, mut w_im) = re.graph().twiddles(len);
+        for w in [&mut w_re, &mut w_im] {
+            w.shape.expand(0, batch);
+            w.shape.expand(1, m);
+        }
+        let (d_re, d_im) = (a_re - b_re, a_im - b_im);
+
+        // The even and odd signals of each transform become consecutive transforms of the next level
+        let join = |even: GraphTensor<()>, odd: GraphTensor<()>| {
+            let even = even.pad::<(), _, _>(&[(zero, zero), (zero, zero), (zero, h.into())]);
+            let odd = odd.pad::<(), _, _>(&[(zero, zero), (zero, zero), (h.into(), zero)]);
+            (even + odd).dyn_reshape::<()>(vec![batch, (m * 2).into(), h.into()])
+        };
+        (re, im) = (
+            join(a_re + b_re, d_re * w_re - d_im * w_im),
+            join(a_im + b_im, d_re * w_im + d_im * w_re),
+        );
+        (len, m) = (h, m * 2);
+    }
+
+    // Interleave the even and odd frequencies back up the levels
+    while len < n {
+        (len, m) = (len * 2, m / 2);
+        let interleave = |x: GraphTensor<()>| {
+            let mut x = x.dyn_reshape::<()>(vec![batch, m.into(), 2.into(), (len / 2).into()]);
+            x.shape.permute(&[0, 1, 3, 2]);
+            x.contiguous()
+                .dyn_reshape::<()>(vec![batch, m.into(), len.into()])
+        };
+        (re, im) = (interleave(re), interleave(im));
+    }
+    *dims.last_mut().unwrap() = n.into();
+    (re.dyn_reshape(dims.clone()), im.dyn_reshape(dims))
+}
+
+impl<S: Shape> GraphTensor<S> {
+    fn last_dim(&self) -> Expression {
+        self.shape.shape().last().unwrap().small()
+    }
+
+    /// Discrete fourier transform of a real signal along the last dimension.
+    ///
+    /// Returns the (real, imaginary) parts of the full spectrum.
+    pub fn fft(self) -> (GraphTensor<S>, GraphTensor<S>) {
+        let n = self.last_dim();
+        let x = self.no_shape();
+        let (re, im) = if let Some(n) = radix2_len(n) {
+            let zeros = self.graph().constant(0.).expand_to(self.shape.contiguous());
+            radix2_fft(x, zeros, n)
+        } else {
+            let angles = self.graph().twiddle_angles(n, n, n);
+            (
+                apply_last_dim(x, angles.cos()),
+                -apply_last_dim(x, angles.sin()),
+            )
+        };
+        (
+            GraphTensor::from_id(re.id, re.shape, self.graph()),
+            GraphTensor::from_id(im.id, im.shape, self.graph()),
+        )
+    }
+
+    /// Discrete fourier transform of a complex signal along the last dimension. `self` is the real part.
+    ///
+    /// Returns the (real, imaginary) parts of the spectrum.
+    pub fn fft_complex(self, imag: GraphTensor<S>) -> (GraphTensor<S>, GraphTensor<S>) {
+        complex_dft(self, imag, false)
+    }
+
+    /// Inverse discrete fourier transform along the last dimension. `self` is the real part.
+    ///
+    /// Returns the (real, imaginary) parts of the signal.
+    pub fn ifft(self, imag: GraphTensor<S>) -> (GraphTensor<S>, GraphTensor<S>) {
+        complex_dft(self, imag, true)
+    }
+
+    /// Fourier transform of a real signal along the last dimension, only keeping the `n / 2 + 1` non-redundant frequencies.
+    ///
+    /// Returns the (real, imaginary) parts of the half spectrum.
+    pub fn rfft<Dst: Shape>(self) -> (GraphTensor<Dst>, GraphTensor<Dst>) {
+        let n = self.last_dim();
+        let x = self.no_shape();
+        let (re, im) = if radix2_len(n).is_some() {
+            // Cut the redundant half off the full spectrum
+            let (mut re, mut im) = x.fft();
+            let mut slice = vec![(Expression::from(0), Expression::from(i32::MAX)); re.shape.len()];
+            slice.last_mut().unwrap().1 = n / 2 + 1;
+            re.shape.slice(&slice);
+            im.shape.slice(&slice);
+            (re.contiguous(), im.contiguous())
+        } else {
+            let angles = self.graph().twiddle_angles(n / 2 + 1, n, n);
+            (
+                apply_last_dim(x, angles.cos()),
+                -apply_last_dim(x, angles.sin()),
+            )
+        };
+        (
+            GraphTensor::from_id(re.id, re.shape, self.graph()),
+            GraphTensor::from_id(im.id, im.shape, self.graph()),
+        )
+    }
+
+    /// Inverse of [`GraphTensor::rfft`]. `self` is the real part of the half spectrum, and `n` is the length of the output signal.
+    pub fn irfft<Dst: Shape>(
+        self,
+        imag: GraphTensor<S>,
+        n: impl Into<Expression>,
+    ) -> GraphTensor<Dst> {
+        let n = n.into();
+        let m = self.last_dim();
+        if let (Some(len), Some(freqs)) = (radix2_len(n), m.to_usize()) {
+            // The signal is the real part of the inverse transform of the half spectrum zero padded to n, with each
+            // non-DC, non-Nyquist frequency counted twice to stand in for its conjugate pair
+            let counts = (0..freqs)
+                .map(|k| if k == 0 || 2 * k == len { 1. } else { 2. })
+                .collect();
+            let mut counts = self.graph().load_vec("Frequency Counts", counts);
+            for (i, d) in self.shape.shape().iter().rev().skip(1).rev().enumerate() {
+                counts.shape.expand(i, d.small());
+            }
+            let mut padding = vec![(Expression::from(0), Expression::from(0)); self.shape.len()];
+            padding.last_mut().unwrap().1 = n - m;
+            let re = (self.no_shape() * counts).pad::<(), _, _>(&padding);
+            let im = (imag.no_shape() * counts).pad::<(), _, _>(&padding);
+            let (out, _) = re.ifft(im);
+            return GraphTensor::from_id(out.id, out.shape, self.graph());
+        }
+        // Each non-DC, non-Nyquist frequency stands in for its conjugate pair, so gets counted twice
+        let freqs = self.graph().arange_slice(n, m);
+        let zero = self.graph().constant(0.).expand_to(freqs.shape);
+        let nyquist = self.graph().constant_expr(n).expand_to(freqs.shape) * 0.5;
+        let mut weights = (2. - freqs.equals(zero) - freqs.equals(nyquist))
+            / self.graph().constant_expr(n).expand_to(freqs.shape);
+        weights.shape.expand(0, n);
+        let angles = self.graph().twiddle_angles(n, m, n);
+        let out = apply_last_dim(self.no_shape(), angles.cos() * weights)
+            - apply_last_dim(imag.no_shape(), angles.sin() * weights);
+        GraphTensor::from_id(out.id, out.shape, self.graph())
+    }
+
+    /// Causal (linear, non-circular) convolution of a signal with a long kernel along the last dimension, computed in the frequency domain.
+    ///
+    /// The kernel must have the same leading dimensions as the signal. Output `i` is `sum_j signal[i - j] * kernel[j]`.
+    /// This is the long convolution used by architectures like [Hyena](https://arxiv.org/abs/2302.10866).
+    ///
+    /// When both lengths are known when the graph is built, this takes O(n log n) work. Otherwise the transforms are
+    /// dense DFTs, taking O(n^2).
+    pub fn fft_conv<K: Shape>(self, kernel: GraphTensor<K>) -> GraphTensor<S> {
+        let (n, k) = (self.last_dim(), kernel.last_dim());
+        let n_dims = self.shape.len();
+        // Zero pad both to at least n + k so the circular convolution doesn't wrap around, rounding up to a power of two
+        // for the radix-2 FFT when the lengths are known
+        let size = match (n.to_usize(), k.to_usize()) {
+            (Some(n), Some(k)) => Expression::from((n + k).next_power_of_two()),
+            _ => n + k,
+        };
+        let mut signal_padding = vec![(Expression::from(0), Expression::from(0)); n_dims];
+        signal_padding[n_dims - 1].1 = size - n;
+        let mut kernel_padding = vec![(Expression::from(0), Expression::from(0)); n_dims];
+        kernel_padding[n_dims - 1].1 = size - k;
+        let signal = self.pad::<(), _, _>(&signal_padding);
+        let kernel = kernel.pad::<(), _, _>(&kernel_padding);
+
+        // Multiply spectrums
+        let (s_re, s_im) = signal.rfft::<()>();
+        let (k_re, k_im) = kernel.rfft::<()>();
+        let re = s_re * k_re - s_im * k_im;
+        let im = s_re * k_im + s_im * k_re;
+
+        // Back to time domain and cut off the padding
+        let mut out = re.irfft::<()>(im, size);
+        let mut slice = vec![(Expression::from(0), Expression::from(i32::MAX)); n_dims];
+        slice[n_dims - 1].1 = n;
+        out.shape.slice(&slice);
+        GraphTensor::from_id(out.id, out.shape, self.graph())
+    }
+}
+
+fn complex_dft<S: Shape>(
+    real: GraphTensor<S>,
+    imag: GraphTensor<S>,
+    inverse: bool,
+) -> (GraphTensor<S>, GraphTensor<S>) {
+    let n = real.last_dim();
+    if let Some(len) = radix2_len(n) {
+        // The inverse transform is the conjugate of the transform of the conjugate, scaled by 1 / n
+        let imag = if inverse { -imag } else { imag };
+        let (mut re, mut im) = radix2_fft(real.no_shape(), imag.no_shape(), len);
+        if inverse {
+            re = re * (1. / len as f32);
+            im = im * (-1. / len as f32);
+        }
+        return (
+            GraphTensor::from_id(re.id, re.shape, real.graph()),
+            GraphTensor::from_id(im.id, im.shape, real.graph()),
+        );
+    }
+    let angles = real.graph().twiddle_angles(n, n, n);
+    let (cos, mut sin) = (angles.cos(), angles.sin());
+    if !inverse {
+        sin = -sin;
+    }
+    let (re, im) = (real.no_shape(), imag.no_shape());
+    // (re + i * im) * (cos + i * sin)
+    let mut out_re = apply_last_dim(re, cos) - apply_last_dim(im, sin);
+    let mut out_im = apply_last_dim(re, sin) + apply_last_dim(im, cos);
+    if inverse {
+        let scale = real
+            .graph()
+            .constant_expr(n)
+            .recip()
+            .expand_to(out_re.shape);
+        out_re *= scale;
+        out_im *= scale;
+    }
+    (
+        GraphTensor::from_id(out_re.id, out_re.shape, real.graph()),
+        GraphTensor::from_id(out_im.id, out_im.shape, real.graph()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    fn naive_dft(re: &[f32], im: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        let n = re.len();
+        (0..n)
+            .map(|k| {
+                (0..n).fold((0., 0.), |(r, i), j| {
+                    let a = -2. * std::f32::consts::PI * (k * j % n) as f32 / n as f32;
+                    (
+                        r + re[j] * a.cos() - im[j] * a.sin(),
+                        i + re[j] * a.sin() + im[j] * a.cos(),
+                    )
+                })
+            })
+            .unzip()
+    }
+
+    fn fft_matches_dft<const N: usize>() {
+        let mut cx = Graph::new();
+        let (re_data, im_data) = (random_vec(2 * N), random_vec(2 * N));
+        let a = cx.tensor::<R2<2, N>>().set(re_data.clone());
+        let b = cx.tensor::<R2<2, N>>().set(im_data.clone());
+        let (real_re, real_im) = a.fft();
+        let (real_re, real_im) = (real_re.retrieve(), real_im.retrieve());
+        let (c_re, c_im) = a.fft_complex(b);
+        let (c_re, c_im) = (c_re.retrieve(), c_im.retrieve());
+        let (inv_re, inv_im) = c_re.ifft(c_im);
+        let (inv_re, inv_im) = (inv_re.retrieve(), inv_im.retrieve());
+        cx.execute();
+
+        let (mut exp_real_re, mut exp_real_im, mut exp_re, mut exp_im) =
+            (vec![], vec![], vec![], vec![]);
+        for row in 0..2 {
+            let r = &re_data[row * N..(row + 1) * N];
+            let (a, b) = naive_dft(r, &[0.; N]);
+            exp_real_re.extend(a);
+            exp_real_im.extend(b);
+            let (a, b) = naive_dft(r, &im_data[row * N..(row + 1) * N]);
+            exp_re.extend(a);
+            exp_im.extend(b);
+        }
+        assert_close(&real_re.data(), &exp_real_re);
+        assert_close(&real_im.data(), &exp_real_im);
+        assert_close(&c_re.data(), &exp_re);
+        assert_close(&c_im.data(), &exp_im);
+        assert_close(&inv_re.data(), &re_data);
+        assert_close(&inv_im.data(), &im_data);
+    }
+
+    #[test]
+    fn test_fft() {
+        fft_matches_dft::<6>();
+        fft_matches_dft::<8>();
+    }
+
+    #[test]
+    fn test_radix2_fft() {
+        let mut cx = Graph::new();
+        let (re_data, im_data) = (random_vec(6 * 64), random_vec(6 * 64));
+        let a = cx.tensor::<R3<2, 3, 64>>().set(re_data.clone());
+        let b = cx.tensor::<R3<2, 3, 64>>().set(im_data.clone());
+        let (re, im) = a.fft_complex(b);
+        let (re, im) = (re.retrieve(), im.retrieve());
+        // No dense transform matrix is built
+        assert!(!cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::op::SumReduce>()));
+        cx.execute();
+
+        let (mut exp_re, mut exp_im) = (vec![], vec![]);
+        for row in 0..6 {
+            let (a, b) = naive_dft(
+                &re_data[row * 64..(row + 1) * 64],
+                &im_data[row * 64..(row + 1) * 64],
+            );
+            exp_re.extend(a);
+            exp_im.extend(b);
+        }
+        assert_close_precision(&re.data(), &exp_re, 1e-2);
+        assert_close_precision(&im.data(), &exp_im, 1e-2);
+    }
+
+    fn rfft_roundtrip<const N: usize>() {
+        let mut cx = Graph::new();
+        let data = random_vec(N);
+        let a = cx.tensor::<R1<N>>().set(data.clone());
+        let (re, im) = a.rfft::<(Dyn<'-'>,)>();
+        let (re, im) = (re.retrieve(), im.retrieve());
+        let b = re.irfft::<R1<N>>(im, N).retrieve();
+        cx.execute();
+
+        let (exp_re, exp_im) = naive_dft(&data, &[0.; N]);
+        assert_close(&re.data(), &exp_re[..N / 2 + 1]);
+        assert_close(&im.data(), &exp_im[..N / 2 + 1]);
+        assert_close(&b.data(), &data);
+    }
+
+    #[test]
+    fn test_rfft_roundtrip() {
+        rfft_roundtrip::<7>();
+        rfft_roundtrip::<8>();
+    }
+
+    #[test]
+    fn test_fft_conv() {
+        let mut cx = Graph::new();
+        let (signal, kernel) = (random_vec(12), random_vec(8));
+        let a = cx.tensor::<R2<2, 6>>().set(signal.clone());
+        let b = cx.tensor::<R2<2, 4>>().set(kernel.clone());
+        let c = a.fft_conv(b).retrieve();
+        cx.execute();
+
+        let mut expected = vec![0.; 12];
+        for row in 0..2 {
+            for i in 0..6 {
+                for j in 0..4.min(i + 1) {
+                    expected[row * 6 + i] += signal[row * 6 + i - j] * kernel[row * 4 + j];
+                }
+            }
+        }
+        assert_close(&c.data(), &expected);
+    }
+}
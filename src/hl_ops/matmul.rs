@@ -1,4 +1,4 @@
-use crate::prelude::*;
+use crate::{op, prelude::*};
 
 pub trait Matmul<S: Shape> {
     type Output;
@@ -107,6 +107,60 @@ impl<A: Dimension> GraphTensor<(A,)> {
     }
 }
 
+impl<B: Dimension, H: Dimension, Sq: Dimension, D: Dimension> GraphTensor<(B, H, Sq, D)> {
+    /// Scaled dot product attention `softmax(q k^T / sqrt(D) + mask) v` with these as the queries, shaped (batch, heads,
+    /// query seq, head dim). This is a single fused `ScaledDotProductAttention` op, which never materializes the
+    /// query x key score matrix.
+    pub fn sdpa<Sk: Dimension, Dv: Dimension>(
+        self,
+        keys: GraphTensor<(B, H, Sk, D)>,
+        values: GraphTensor<(B, H, Sk, Dv)>,
+        mask: Option<GraphTensor<(B, H, Sq, Sk)>>,
+    ) -> GraphTensor<(B, H, Sq, Dv)> {
+        self.attention(keys, values, mask, None)
+    }
+
+    /// Same as `sdpa`, with the scores multiplied by `scale` instead of `1 / sqrt(D)`
+    pub fn sdpa_with_scale<Sk: Dimension, Dv: Dimension>(
+        self,
+        keys: GraphTensor<(B, H, Sk, D)>,
+        values: GraphTensor<(B, H, Sk, Dv)>,
+        mask: Option<GraphTensor<(B, H, Sq, Sk)>>,
+        scale: f32,
+    ) -> GraphTensor<(B, H, Sq, Dv)> {
+        self.attention(keys, values, mask, Some(scale))
+    }
+
+    fn attention<Sk: Dimension, Dv: Dimension>(
+        self,
+        keys: GraphTensor<(B, H, Sk, D)>,
+        values: GraphTensor<(B, H, Sk, Dv)>,
+        mask: Option<GraphTensor<(B, H, Sq, Sk)>>,
+        scale: Option<f32>,
+    ) -> GraphTensor<(B, H, Sq, Dv)> {
+        let mut op = self
+            .graph()
+            .add_op(op::ScaledDotProductAttention(scale))
+            .input(self.id, 0, self.shape)
+            .input(keys.id, 0, keys.shape)
+            .input(values.id, 0, values.shape);
+        if let Some(mask) = mask {
+            op = op.input(mask.id, 0, mask.shape);
+        }
+        let dim = |shape: ShapeTracker, axis: usize| shape.dims[shape.indexes[axis]];
+        GraphTensor::from_id(
+            op.finish(),
+            ShapeTracker::new(&[
+                dim(self.shape, 0),
+                dim(self.shape, 1),
+                dim(self.shape, 2),
+                dim(values.shape, 3),
+            ]),
+            self.graph(),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     crate::test_imports!();
@@ -214,4 +268,50 @@ mod tests {
 
         assert_close(&c.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_sdpa() {
+        let mut cx = Graph::new();
+        // More keys than fit in one tile
+        let (q_data, k_data, v_data) = (
+            random_vec(2 * 3 * 5 * 4),
+            random_vec(2 * 3 * 4 * 150),
+            random_vec(2 * 3 * 150 * 6),
+        );
+        let q = cx.tensor::<R4<2, 3, 5, 4>>().set(q_data);
+        // Keys stored transposed, so they're read through a permuted view
+        let k = cx
+            .tensor::<R4<2, 3, 4, 150>>()
+            .set(k_data)
+            .permute::<R4<2, 3, 150, 4>, _>();
+        let v = cx.tensor::<R4<2, 3, 150, 6>>().set(v_data);
+        // Hide the last keys from each query, and broadcast the mask over batches and heads
+        let mask = cx.tensor::<R2<5, 150>>().set(
+            (0..5 * 150)
+                .map(|i| {
+                    if i % 150 >= 140 + i / 150 {
+                        f32::NEG_INFINITY
+                    } else {
+                        (i % 7) as f32 * 0.1
+                    }
+                })
+                .collect::<Vec<_>>(),
+        );
+        let mask = mask.expand::<R4<2, 3, 5, 150>, _>();
+
+        let fused = q.sdpa(k, v, Some(mask)).retrieve();
+        let scaled = q.sdpa_with_scale(k, v, None, 0.3).retrieve();
+        let unfused = (q.matmul(k.permute()) * 0.5 + mask)
+            .softmax::<LAxis<3>>()
+            .matmul(v)
+            .retrieve();
+        let unfused_scaled = (q.matmul(k.permute()) * 0.3)
+            .softmax::<LAxis<3>>()
+            .matmul(v)
+            .retrieve();
+        cx.execute();
+
+        assert_close(&fused.data(), &unfused.data());
+        assert_close(&scaled.data(), &unfused_scaled.data());
+    }
 }
@@ -1,5 +1,8 @@
 // The high level interface implemented on GraphTensor. All of these ops get translated to primitive ops.
 pub mod binary;
+pub mod conv;
+pub mod einsum;
+pub mod fft;
 pub mod matmul;
 pub use matmul::*;
 pub mod movement;
@@ -7,7 +7,7 @@ impl<S: Shape> GraphTensor<S> {
     {
         self.shape
             .permute(&Ax::as_array().into_iter().collect::<Vec<_>>());
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     pub fn expand<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
@@ -21,7 +21,7 @@ impl<S: Shape> GraphTensor<S> {
             }
         }
 
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     pub fn expand_to<Dst: Shape>(mut self, shape: ShapeTracker) -> GraphTensor<Dst> {
@@ -31,7 +31,7 @@ impl<S: Shape> GraphTensor<S> {
             }
         }
 
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     pub fn reshape<N: Shape>(mut self) -> GraphTensor<N> {
@@ -40,7 +40,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(
             self.id,
             ShapeTracker::new(&N::realized_shape()),
-            self.graph_ref,
+            self.graph(),
         )
     }
 
@@ -51,7 +51,7 @@ impl<S: Shape> GraphTensor<S> {
             self = self.contiguous();
         }
 
-        GraphTensor::from_id(self.id, ShapeTracker::new(&shape), self.graph_ref)
+        GraphTensor::from_id(self.id, ShapeTracker::new(&shape), self.graph())
     }
 
     pub fn realize<Dst: Shape<Concrete = <<S as HasShape>::Shape as Shape>::Concrete>>(
@@ -60,14 +60,14 @@ impl<S: Shape> GraphTensor<S> {
     where
         S: RealizeShapeTo<Dst>,
     {
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     pub fn sync_shape(self) -> Self {
         GraphTensor::from_id(
             self.id,
             ShapeTracker::new(&S::realized_shape()),
-            self.graph_ref,
+            self.graph(),
         )
     }
 
@@ -80,7 +80,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Contiguous)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// Take a slice of the original tensor. Any dimension with bounds becomes a dynamic dimension
@@ -98,7 +98,7 @@ impl<S: Shape> GraphTensor<S> {
             self = self.contiguous();
         }
         self.shape.slice(&ranges);
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     /// Cut out 'size' elements every 'spacing' elements in the last dimension. 'size' must be smaller than the last dimension
@@ -125,7 +125,7 @@ impl<S: Shape> GraphTensor<S> {
         self = self.contiguous();
 
         self.shape.remove_dim(n_dims);
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
     }
 
     /// Pool elements along the last dimension, pools are exposed as a new dimension
@@ -168,7 +168,7 @@ impl<S: Shape> GraphTensor<S> {
             self = self.contiguous();
             self.excise(1, dilation)
         } else {
-            GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+            GraphTensor::from_id(self.id, self.shape, self.graph())
         }
     }
 
@@ -189,7 +189,37 @@ impl<S: Shape> GraphTensor<S> {
             self = self.contiguous();
         }
         self.shape.pad(&ranges);
-        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        GraphTensor::from_id(self.id, self.shape, self.graph())
+    }
+
+    /// Pad each dimension by `(before, after)` elements, filled according to `mode`. Zero padding only changes the
+    /// shape tracker, so it doesn't copy the tensor.
+    pub fn pad_with<Dst: Shape>(
+        self,
+        ranges: &[(usize, usize)],
+        mode: PaddingMode,
+    ) -> GraphTensor<Dst> {
+        match mode {
+            PaddingMode::Constant(0.) => self.pad(ranges),
+            _ => {
+                let dims = self
+                    .shape
+                    .shape()
+                    .into_iter()
+                    .zip(ranges)
+                    .map(|(d, (before, after))| d.small() + before + after)
+                    .collect::<Vec<_>>();
+                let new_id = self
+                    .graph()
+                    .add_op(op::Pad {
+                        padding: ranges.to_vec(),
+                        mode,
+                    })
+                    .input(self.id, 0, self.shape)
+                    .finish();
+                GraphTensor::from_id(new_id, ShapeTracker::new(&dims), self.graph())
+            }
+        }
     }
 
     pub fn concat_along<Dst: Shape, Ax: Axes<Array = [usize; 1]>, Rhs: Shape>(
@@ -205,6 +235,158 @@ impl<S: Shape> GraphTensor<S> {
         // Pad and add
         (self.pad(&a_padding) + rhs.pad(&b_padding)).sync_shape()
     }
+
+    /// Join any number of tensors along an axis as a single `Concat` op. Their other dimensions must match, and
+    /// unknown dimensions are taken from whichever tensor knows them.
+    pub fn concat<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(
+        tensors: &[GraphTensor<S>],
+    ) -> GraphTensor<Dst> {
+        let dim = Ax::as_array()[0];
+        let first = *tensors
+            .first()
+            .expect("Can't concat an empty list of tensors");
+        let shapes = tensors
+            .iter()
+            .map(|t| {
+                t.shape
+                    .shape()
+                    .into_iter()
+                    .map(|d| d.small())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let dims = (0..shapes[0].len())
+            .map(|d| {
+                if d == dim {
+                    shapes
+                        .iter()
+                        .fold(Expression::from(0), |acc, s| acc + s[dim])
+                } else {
+                    shapes
+                        .iter()
+                        .map(|s| s[d])
+                        .find(|d| !d.is_unknown())
+                        .unwrap_or(shapes[0][d])
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut op = first.graph().add_op(op::Concat(dim));
+        for t in tensors {
+            op = op.input(t.id, 0, t.shape);
+        }
+        GraphTensor::from_id(op.finish(), ShapeTracker::new(&dims), first.graph())
+    }
+
+    /// Join any number of tensors along a new axis, inserted at `Ax`
+    pub fn stack<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(
+        tensors: &[GraphTensor<S>],
+    ) -> GraphTensor<Dst> {
+        let dim = Ax::as_array()[0];
+        let expanded = tensors
+            .iter()
+            .map(|t| {
+                let mut shape = t.shape;
+                shape.expand(dim, 1);
+                GraphTensor::<()>::from_id(t.id, shape, t.graph())
+            })
+            .collect::<Vec<_>>();
+        GraphTensor::<()>::concat::<Dst, Ax>(&expanded)
+    }
+
+    /// Split into pieces of the given sizes along an axis. The pieces are views, so nothing is copied.
+    pub fn split<Dst: Shape, Ax: Axes<Array = [usize; 1]>, E: Into<Expression> + Copy>(
+        self,
+        sizes: &[E],
+    ) -> Vec<GraphTensor<Dst>> {
+        let dim = Ax::as_array()[0];
+        let mut start = Expression::from(0);
+        sizes
+            .iter()
+            .map(|size| {
+                let end = start + (*size).into();
+                let piece = self.slice_along(dim, start, end);
+                start = end;
+                piece
+            })
+            .collect()
+    }
+
+    /// Split into `chunks` equal pieces along an axis. If the axis doesn't divide evenly, the last piece is smaller.
+    pub fn chunk<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(
+        self,
+        chunks: usize,
+    ) -> Vec<GraphTensor<Dst>> {
+        let dim = Ax::as_array()[0];
+        let size = self.shape.shape()[dim].small();
+        let chunk_size = (size + (chunks - 1)) / chunks;
+        (0..chunks)
+            .map(|i| {
+                let start = chunk_size * i;
+                self.slice_along(dim, start, (start + chunk_size).min(size))
+            })
+            .collect()
+    }
+
+    /// Split into every slice along an axis, removing that axis from each
+    pub fn unbind<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(self) -> Vec<GraphTensor<Dst>> {
+        let dim = Ax::as_array()[0];
+        let size = self.shape.shape()[dim]
+            .to_usize()
+            .expect("Can only unbind an axis with a known size");
+        (0..size)
+            .map(|i| {
+                let mut piece = self.slice_along::<()>(dim, i.into(), (i + 1).into());
+                // Removing a dimension drops its slice offset, so the piece needs to be materialized first
+                if piece.shape.is_reshaped() {
+                    piece = piece.contiguous();
+                }
+                piece.shape.remove_dim(dim);
+                GraphTensor::from_id(piece.id, piece.shape, self.graph())
+            })
+            .collect()
+    }
+
+    fn slice_along<Dst: Shape>(
+        mut self,
+        dim: usize,
+        start: Expression,
+        end: Expression,
+    ) -> GraphTensor<Dst> {
+        // Padding and slicing on the same dimension is unsupported
+        let ind = self.shape.indexes[dim];
+        if self.shape.padding[ind].0 != 0 || self.shape.padding[ind].1 != 0 {
+            self = self.contiguous();
+        }
+        let mut ranges = vec![(Expression::from(0), Expression::from(i32::MAX)); self.shape.len()];
+        ranges[dim] = (start, end);
+        self.shape.slice(&ranges);
+        GraphTensor::from_id(self.id, self.shape, self.graph())
+    }
+
+    /// Write `rows` into this preallocated buffer along an axis, starting at `offset`, and return the updated buffer.
+    ///
+    /// Unlike `concat_along`, the buffer is updated in place when possible, so appending to a KV cache costs the same regardless of how full it is. Slice the result to get the valid rows.
+    ///
+    /// If this is the only op reading a kept buffer, the buffer is moved into the result rather than copied, leaving the
+    /// buffer empty after execution. Transfer the result back into it (like `transfer_data_same_graph`) before the next one.
+    pub fn append_at<Ax: Axes<Array = [usize; 1]>, Rhs: Shape>(
+        mut self,
+        rows: GraphTensor<Rhs>,
+        offset: impl Into<BigExpression>,
+    ) -> GraphTensor<S> {
+        if self.shape.is_reshaped() {
+            self = self.contiguous();
+        }
+        let dim = Ax::as_array()[0];
+        let new_id = self
+            .graph()
+            .add_op(op::AppendAt(dim, offset.into(), &self.graph().dyn_map))
+            .input(self.id, 0, self.shape)
+            .input(rows.id, 0, rows.shape)
+            .finish();
+        self.graph().set_attribute(new_id, InPlace);
+        GraphTensor::from_id(new_id, self.shape, self.graph())
+    }
 }
 
 #[cfg(test)]
@@ -253,6 +435,51 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_append_at() {
+        let mut cx = Graph::new();
+        let buffer = cx.tensor::<R3<2, 4, 2>>().set(vec![0.; 16]);
+        let rows_data = random_vec(8);
+        // Rows are permuted to make sure non-contiguous inputs are written correctly
+        let rows = cx
+            .tensor::<(LConst<2>, Dyn<'t'>, LConst<2>)>()
+            .set_dyn(rows_data.clone(), &[2, 2, 2])
+            .permute::<_, LAxes3<2, 1, 0>>();
+        let out = buffer.append_at::<LAxis<1>, _>(rows, 'p').retrieve();
+        cx.set_dyn_dim('p', 1);
+        cx.execute();
+
+        let mut expected = vec![0.; 16];
+        for a in 0..2 {
+            for b in 0..2 {
+                for c in 0..2 {
+                    expected[a * 8 + (b + 1) * 2 + c] = rows_data[c * 4 + b * 2 + a];
+                }
+            }
+        }
+        assert_exact(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_append_at_reuses_kept_buffer() {
+        let mut cx = Graph::new();
+        let buffer = cx.tensor::<R2<4, 2>>().set(vec![0.; 8]).keep();
+        let rows = cx.tensor::<R2<1, 2>>();
+        let out = buffer.append_at::<LAxis<0>, _>(rows, 'p').retrieve();
+
+        let mut pointers = vec![];
+        for step in 0..3 {
+            rows.set(vec![step as f32 + 1.; 2]);
+            cx.set_dyn_dim('p', step);
+            cx.execute();
+            pointers.push(out.data_ref().unwrap().as_ptr());
+            transfer_data_same_graph(out, buffer, &mut cx);
+        }
+        // Every step wrote into the buffer the first one made, without copying it
+        assert!(pointers.iter().all(|p| *p == pointers[0]));
+        assert_exact(&buffer.data(), &[1., 1., 2., 2., 3., 3., 0., 0.]);
+    }
+
     #[test]
     fn test_concat_2d() {
         let mut cx = Graph::new();
@@ -290,6 +517,81 @@ mod tests {
         assert_close(&d.data(), &d_d.as_vec());
     }
 
+    #[test]
+    fn test_concat_many() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 2>>().set(vec![1., 2., 3., 4.]);
+        let b = cx.tensor::<R2<2, 2>>().set(vec![5., 6., 7., 8.]);
+        let c = cx.tensor::<R2<2, 2>>().set(vec![9., 10., 11., 12.]);
+        let rows = GraphTensor::concat::<R2<6, 2>, LAxis<0>>(&[a, b, c]).retrieve();
+        // Strided input
+        let cols = GraphTensor::concat::<R2<2, 6>, LAxis<1>>(&[a, b.permute(), c]).retrieve();
+        let stacked = GraphTensor::stack::<R3<2, 3, 2>, LAxis<1>>(&[a, b, c]).retrieve();
+        let d = cx.tensor::<(Dyn<'a'>, LConst<2>)>();
+        d.set_dyn(vec![0., 0., 1., 1., 2., 2.], &[3, 2]);
+        let dynamic = GraphTensor::concat::<(Dyn<'b'>, LConst<2>), LAxis<0>>(&[d, d]).retrieve();
+        cx.execute();
+
+        assert_exact(
+            &rows.data(),
+            &(1..=12).map(|i| i as f32).collect::<Vec<_>>(),
+        );
+        assert_exact(
+            &cols.data(),
+            &[1., 2., 5., 7., 9., 10., 3., 4., 6., 8., 11., 12.],
+        );
+        assert_exact(
+            &stacked.data(),
+            &[1., 2., 5., 6., 9., 10., 3., 4., 7., 8., 11., 12.],
+        );
+        assert_exact(
+            &dynamic.data(),
+            &[0., 0., 1., 1., 2., 2., 0., 0., 1., 1., 2., 2.],
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set(vec![1., 2., 3., 4., 5., 6., 7., 8., 9., 10.]);
+        let split = a.split::<(LConst<2>, Dyn<'-'>), LAxis<1>, _>(&[1, 3, 1]);
+        let chunks = a.chunk::<(LConst<2>, Dyn<'-'>), LAxis<1>>(2);
+        let rows = a.unbind::<R1<5>, LAxis<0>>();
+        let cols = a.unbind::<R1<2>, LAxis<1>>();
+        let out = [
+            split
+                .into_iter()
+                .map(|t| t.retrieve().no_shape())
+                .collect::<Vec<_>>(),
+            chunks
+                .into_iter()
+                .map(|t| t.retrieve().no_shape())
+                .collect(),
+            rows.into_iter().map(|t| t.retrieve().no_shape()).collect(),
+            cols.into_iter().map(|t| t.retrieve().no_shape()).collect(),
+        ];
+        cx.execute();
+
+        let data =
+            |tensors: &[GraphTensor<_>]| tensors.iter().map(|t| t.data()).collect::<Vec<_>>();
+        assert_eq!(
+            data(&out[0]),
+            vec![vec![1., 6.], vec![2., 3., 4., 7., 8., 9.], vec![5., 10.]]
+        );
+        assert_eq!(
+            data(&out[1]),
+            vec![vec![1., 2., 3., 6., 7., 8.], vec![4., 5., 9., 10.]]
+        );
+        assert_eq!(
+            data(&out[2]),
+            vec![vec![1., 2., 3., 4., 5.], vec![6., 7., 8., 9., 10.]]
+        );
+        assert_eq!(data(&out[3])[3], vec![4., 9.]);
+        assert_eq!(out[3].len(), 5);
+    }
+
     #[test]
     fn test_pad_2d() {
         let mut cx = Graph::new();
@@ -320,6 +622,55 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_pad_with() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+        let constant = a
+            .pad_with::<R2<3, 4>>(&[(1, 0), (0, 1)], PaddingMode::Constant(-1.))
+            .retrieve();
+        let reflect = a
+            .pad_with::<R2<4, 7>>(&[(1, 1), (2, 2)], PaddingMode::Reflect)
+            .retrieve();
+        let replicate = a
+            .pad_with::<R2<3, 5>>(&[(0, 1), (2, 0)], PaddingMode::Replicate)
+            .retrieve();
+        // Strided input
+        let permuted = a
+            .permute::<R2<3, 2>, _>()
+            .pad_with::<R2<3, 4>>(&[(0, 0), (1, 1)], PaddingMode::Reflect)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(
+            &constant.data(),
+            &[-1., -1., -1., -1., 1., 2., 3., -1., 4., 5., 6., -1.],
+        );
+        assert_exact(
+            &reflect.data(),
+            &[
+                [6., 5., 4., 5., 6., 5., 4.],
+                [3., 2., 1., 2., 3., 2., 1.],
+                [6., 5., 4., 5., 6., 5., 4.],
+                [3., 2., 1., 2., 3., 2., 1.],
+            ]
+            .concat(),
+        );
+        assert_exact(
+            &replicate.data(),
+            &[
+                [1., 1., 1., 2., 3.],
+                [4., 4., 4., 5., 6.],
+                [4., 4., 4., 5., 6.],
+            ]
+            .concat(),
+        );
+        assert_exact(
+            &permuted.data(),
+            &[4., 1., 4., 1., 5., 2., 5., 2., 6., 3., 6., 3.],
+        );
+    }
+
     #[test]
     fn test_slice_2d() {
         let mut cx = Graph::new();
@@ -29,13 +29,74 @@ impl<S: Shape> GraphTensor<S> {
             .input(pooled.id, 0, pooled.shape)
             .finish();
         pooled.shape.remove_dim(axis + 1);
-        GraphTensor::from_id(final_id, pooled.shape, self.graph_ref)
+        GraphTensor::from_id(final_id, pooled.shape, self.graph())
     }
 
     /// Cumulative product last dimension
     pub fn cumprod_last_dim(self) -> Self {
         self.ln().cumsum_last_dim().exp()
     }
+
+    /// Cumulative sum along an axis, as a single `CumSum` op
+    pub fn cumsum<Ax: Axes<Array = [usize; 1]>>(self) -> Self {
+        self.scan_op(op::CumSum(Ax::as_array()[0]))
+    }
+
+    /// Cumulative product along an axis, as a single `CumProd` op. Zeros and negative values are fine here
+    pub fn cumprod<Ax: Axes<Array = [usize; 1]>>(self) -> Self {
+        self.scan_op(op::CumProd(Ax::as_array()[0]))
+    }
+
+    /// The linear recurrence `h_k = self_k * h_(k-1) + inputs_k` along an axis starting from a zero state, as a single
+    /// `SelectiveScan` op. `self` holds the decay of each step, like the discretized state matrix of a state space model.
+    pub fn selective_scan<Ax: Axes<Array = [usize; 1]>>(self, inputs: GraphTensor<S>) -> Self {
+        let new_id = self
+            .graph()
+            .add_op(op::SelectiveScan {
+                dim: Ax::as_array()[0],
+                reverse: false,
+            })
+            .input(self.id, 0, self.shape)
+            .input(inputs.id, 0, inputs.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// Rotary position embeddings over the last two dimensions (..., Seq, D), as a single `Rope` op. Each pair of the
+    /// last dimension is rotated by its position times its inverse frequency in `inv_freqs` (D / 2). Positions start
+    /// at `offset`, usually the number of cached tokens.
+    pub fn rope<R: Dimension>(
+        self,
+        inv_freqs: GraphTensor<(R,)>,
+        offset: impl Into<BigExpression>,
+    ) -> Self {
+        self.rope_with_scale(inv_freqs, offset, 1.)
+    }
+
+    /// Same as `rope`, with the output multiplied by `scale`
+    pub fn rope_with_scale<R: Dimension>(
+        self,
+        inv_freqs: GraphTensor<(R,)>,
+        offset: impl Into<BigExpression>,
+        scale: f32,
+    ) -> Self {
+        let new_id = self
+            .graph()
+            .add_op(op::Rope(offset.into(), scale, &self.graph().dyn_map))
+            .input(self.id, 0, self.shape)
+            .input(inv_freqs.id, 0, inv_freqs.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    fn scan_op<O: Operator + 'static>(self, op: O) -> Self {
+        let new_id = self
+            .graph()
+            .add_op(op)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
 }
 
 impl From<f32> for ConstantValue {
@@ -106,6 +167,85 @@ impl Graph {
         }
     }
 
+    /// ARange of N values from `start`, counting up by `step`
+    pub fn arange_step<N: Dimension>(&mut self, start: f32, step: f32) -> GraphTensor<(N,)> {
+        self.arange::<N>() * step + start
+    }
+
+    /// N evenly spaced values from `start` to `end`, including both ends. A single value is just `start`.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.linspace
+    pub fn linspace<N: Dimension>(&mut self, start: f32, end: f32) -> GraphTensor<(N,)> {
+        let intervals = self
+            .constant_expr((N::const_size() - 1).max(1))
+            .expand::<(N,), _>();
+        self.arange::<N>() * (end - start) / intervals + start
+    }
+
+    /// Identity matrix, with 1s on the diagonal and 0s everywhere else
+    pub fn eye<N: Dimension>(&mut self) -> GraphTensor<(N, N)> {
+        let horizontal = self.arange::<N>().expand::<(N, N), Axis<0>>();
+        let vertical = self.arange::<N>().expand::<(N, N), Axis<1>>();
+        horizontal.equals(vertical)
+    }
+
+    /// A tensor filled with `value`, which can be a float or an expression of dynamic dimensions
+    pub fn full<S: Shape>(&mut self, value: impl Into<ConstantValue>) -> GraphTensor<S> {
+        self.constant(value).expand_to(S::to_tracker())
+    }
+
+    /// A tensor filled with 0s
+    pub fn zeros<S: Shape>(&mut self) -> GraphTensor<S> {
+        self.full(0.)
+    }
+
+    /// A tensor filled with 1s
+    pub fn ones<S: Shape>(&mut self) -> GraphTensor<S> {
+        self.full(1.)
+    }
+
+    /// A tensor of random values drawn independently from a distribution. Each execution draws new values, and the
+    /// graph's seed (see `Graph::set_seed`) makes them reproducible.
+    pub fn random<S: Shape>(&mut self, distribution: op::Distribution) -> GraphTensor<S> {
+        let shape = S::to_tracker();
+        let salt = self.graph.node_count() as u64;
+        let id = self
+            .add_op(op::Random::new(
+                distribution,
+                shape.n_elements(),
+                salt,
+                &self.seed,
+                &self.dyn_map,
+            ))
+            .finish();
+        GraphTensor::from_id(id, shape, self)
+    }
+
+    /// Random values uniformly distributed in [0, 1)
+    pub fn rand<S: Shape>(&mut self) -> GraphTensor<S> {
+        self.rand_uniform(0., 1.)
+    }
+
+    /// Random values uniformly distributed in [low, high)
+    pub fn rand_uniform<S: Shape>(&mut self, low: f32, high: f32) -> GraphTensor<S> {
+        self.random(op::Distribution::Uniform { low, high })
+    }
+
+    /// Random values from the standard normal distribution (mean 0, standard deviation 1)
+    pub fn randn<S: Shape>(&mut self) -> GraphTensor<S> {
+        self.rand_normal(0., 1.)
+    }
+
+    /// Random values from a normal distribution
+    pub fn rand_normal<S: Shape>(&mut self, mean: f32, std: f32) -> GraphTensor<S> {
+        self.random(op::Distribution::Normal { mean, std })
+    }
+
+    /// Random 1s with probability `p`, and 0s otherwise
+    pub fn bernoulli<S: Shape>(&mut self, p: f32) -> GraphTensor<S> {
+        self.random(op::Distribution::Bernoulli { p })
+    }
+
     /// Lower left-hand triangle of 1s. Currently required to be square
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
@@ -127,19 +267,166 @@ impl Graph {
     }
 }
 
+impl<S: Shape> GraphTensor<S> {
+    /// Replace elements with `value` where `mask` is nonzero
+    pub fn masked_fill(self, mask: GraphTensor<S>, value: f32) -> GraphTensor<S> {
+        let fill = self.graph().constant(value).expand_to(self.shape);
+        mask.where_(fill, self)
+    }
+
+    /// Zero everything above the `diagonal`th diagonal of the last two dimensions. 0 is the main diagonal, positive
+    /// diagonals are above it and negative ones below it.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
+    pub fn tril(self, diagonal: i32) -> GraphTensor<S> {
+        let offsets = self.diagonal_offsets();
+        let diagonal = self
+            .graph()
+            .constant(diagonal as f32)
+            .expand_to(offsets.shape);
+        self.masked_fill(offsets.gt_mask(diagonal), 0.)
+    }
+
+    /// Zero everything below the `diagonal`th diagonal of the last two dimensions. 0 is the main diagonal, positive
+    /// diagonals are above it and negative ones below it.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.triu
+    pub fn triu(self, diagonal: i32) -> GraphTensor<S> {
+        let offsets = self.diagonal_offsets();
+        let diagonal = self
+            .graph()
+            .constant(diagonal as f32)
+            .expand_to(offsets.shape);
+        self.masked_fill(offsets.lt_mask(diagonal), 0.)
+    }
+
+    /// The diagonal each element of the last two dimensions is on (column - row), broadcast over the other dimensions
+    fn diagonal_offsets(&self) -> GraphTensor<S> {
+        let dims = self
+            .shape
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect_vec();
+        assert!(
+            dims.len() >= 2,
+            "Triangles need at least 2 dimensions, but this tensor has {}",
+            dims.len()
+        );
+        let (rows, cols) = (dims[dims.len() - 2], dims[dims.len() - 1]);
+        let arange = |size| {
+            self.graph()
+                .constant(1.)
+                .expand_to::<()>(ShapeTracker::new(&[size]))
+                .cumsum::<Axis<0>>()
+                - 1.
+        };
+        let (mut row, mut col) = (arange(rows), arange(cols));
+        row.shape.expand(1, cols);
+        col.shape.expand(0, rows);
+        let mut offsets = col - row;
+        for (i, dim) in dims[..dims.len() - 2].iter().enumerate() {
+            offsets.shape.expand(i, *dim);
+        }
+        GraphTensor::from_id(offsets.id, offsets.shape, self.graph())
+    }
+}
+
 impl<S: Dimension, const DIM: usize> GraphTensor<(S, Const<DIM>)> {
     /// Gather a batch of vectors from a matrix
     pub fn gather<B: Dimension>(self, indexes: GraphTensor<(B,)>) -> GraphTensor<(B, Const<DIM>)> {
-        let one_hot = indexes
-            .graph()
-            .arange::<S>()
-            .expand::<(B, S), _>()
-            .equals(indexes.expand());
-        (one_hot.expand::<(B, S, Const<DIM>), _>() * self.expand()).sum_reduce::<_, Axis<1>>()
+        self.index_select::<_, Axis<0>, _>(indexes)
+    }
+
+    /// Scatter-add a batch of vectors into the rows of a matrix. This is the gradient of `gather` with respect to the matrix.
+    pub fn scatter_add<D: Dimension>(
+        self,
+        indexes: GraphTensor<(S,)>,
+    ) -> GraphTensor<(D, Const<DIM>)> {
+        self.graph()
+            .constant(0.)
+            .expand::<(D, Const<DIM>), _>()
+            .scatter_add_along::<Axis<0>, _>(indexes.expand::<_, Axis<1>>(), self)
+    }
+}
+
+impl<S: Dimension> GraphTensor<(S,)> {
+    /// One-hot encode a vector of indexes, giving a 1 at each row's index and 0 everywhere else
+    pub fn one_hot<N: Dimension>(self) -> GraphTensor<(S, N)> {
+        let graph = self.graph();
+        let ones = graph.constant(1.).expand::<(S, Const<1>), _>();
+        graph
+            .constant(0.)
+            .expand::<(S, N), _>()
+            .scatter_along::<Axis<1>, _>(self.expand::<(S, Const<1>), _>(), ones)
     }
 }
 
 impl<S: Shape> GraphTensor<S> {
+    /// Select slices along an axis by a tensor of indexes, see `Gather`. The axis is replaced by the index tensor's
+    /// dimensions. Indexes can be `IndexVec` data (`DType::U32`) or whole number floats.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.index_select, generalized to any index shape
+    pub fn index_select<Dst: Shape, Ax: Axes<Array = [usize; 1]>, I: Shape>(
+        self,
+        indexes: GraphTensor<I>,
+    ) -> GraphTensor<Dst> {
+        let dim = Ax::as_array()[0];
+        let mut shape = self.shape.shape();
+        shape.splice(dim..=dim, indexes.shape.shape());
+        let new_id = self
+            .graph()
+            .add_op(op::Gather(dim))
+            .input(self.id, 0, self.shape)
+            .input(indexes.id, 0, indexes.shape)
+            .finish();
+        GraphTensor::from_id(
+            new_id,
+            ShapeTracker::new(&shape.into_iter().map(|d| d.small()).collect::<Vec<_>>()),
+            self.graph(),
+        )
+    }
+
+    /// Write the elements of `src` into this tensor, moving each one along an axis to the position given by the
+    /// matching element of `indexes`, see `Scatter`
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.Tensor.scatter_
+    pub fn scatter_along<Ax: Axes<Array = [usize; 1]>, I: Shape>(
+        self,
+        indexes: GraphTensor<I>,
+        src: GraphTensor<I>,
+    ) -> GraphTensor<S> {
+        self.scatter_op(op::Scatter(Ax::as_array()[0]), indexes, src)
+    }
+
+    /// Like `scatter_along`, but elements are added to this tensor, summing elements that land on the same position.
+    /// See `ScatterAdd`.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.Tensor.scatter_add_
+    pub fn scatter_add_along<Ax: Axes<Array = [usize; 1]>, I: Shape>(
+        self,
+        indexes: GraphTensor<I>,
+        src: GraphTensor<I>,
+    ) -> GraphTensor<S> {
+        self.scatter_op(op::ScatterAdd(Ax::as_array()[0]), indexes, src)
+    }
+
+    fn scatter_op<I: Shape>(
+        self,
+        op: impl Operator + 'static,
+        indexes: GraphTensor<I>,
+        src: GraphTensor<I>,
+    ) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op)
+            .input(self.id, 0, self.shape)
+            .input(indexes.id, 0, indexes.shape)
+            .input(src.id, 0, src.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
     /// Print the value of this tensor when the graph is ran
     pub fn print<T: ToString>(&self, message: T) {
         let message = message.to_string();
@@ -150,7 +437,7 @@ impl<S: Shape> GraphTensor<S> {
                 Box::new(move |inp| {
                     for (i, (tensor, tracker)) in inp.iter().enumerate() {
                         println!("{message}");
-                        let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                        let d = tensor.borrowed().f32_data().unwrap();
                         println!("{} Data: {:?}", i + 1, &d[..d.len().min(10)]);
                         println!("{} Shape: {:?}", i + 1, tracker);
                     }
@@ -172,7 +459,7 @@ impl<S: Shape> GraphTensor<S> {
                 Box::new(move |mut inp| {
                     // Get tensor data and file data
                     let (tensor, shape) = inp.pop().unwrap();
-                    let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    let d = tensor.borrowed().f32_data().unwrap();
                     let mut data = vec![0.; d.len()];
                     let (ind, val) = (shape.index_expression(), shape.valid_expression());
                     let mut stack = vec![];
@@ -355,6 +642,213 @@ mod tests {
         assert_close(&b.data(), &[3., 6., 30.]);
     }
 
+    #[test]
+    fn test_cumsum_cumprod_axis() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor::<R2<2, 3>>().set(vec![3., -2., 0.5, 0., 4., -1.]);
+        let rows = a.cumsum::<LAxis<1>>().retrieve();
+        let cols = a.cumsum::<LAxis<0>>().retrieve();
+        let prod = a.cumprod::<LAxis<1>>().retrieve();
+        // Strided input
+        let permuted = a.permute::<R2<3, 2>, _>().cumprod::<LAxis<0>>().retrieve();
+        cx.execute();
+
+        assert_close(&rows.data(), &[3., 1., 1.5, 0., 4., 3.]);
+        assert_close(&cols.data(), &[3., -2., 0.5, 3., 2., -0.5]);
+        assert_close(&prod.data(), &[3., -6., -3., 0., 0., 0.]);
+        assert_close(&permuted.data(), &[3., 0., -6., 0., -3., 0.]);
+    }
+
+    #[test]
+    fn test_selective_scan() {
+        let mut cx = Graph::new();
+
+        let decays = random_vec(24);
+        let inputs = random_vec(24);
+        let a = cx.tensor::<R3<2, 4, 3>>().set(decays.clone());
+        let b = cx.tensor::<R3<2, 4, 3>>().set(inputs.clone());
+        let out = a.selective_scan::<LAxis<1>>(b).retrieve();
+        // Strided input
+        let permuted = a
+            .permute::<R3<2, 3, 4>, _>()
+            .selective_scan::<LAxis<2>>(b.permute())
+            .retrieve();
+        cx.execute();
+
+        let mut expected = vec![0.; 24];
+        for i in 0..2 {
+            for j in 0..3 {
+                let mut h = 0.;
+                for k in 0..4 {
+                    let index = i * 12 + k * 3 + j;
+                    h = decays[index] * h + inputs[index];
+                    expected[index] = h;
+                }
+            }
+        }
+        assert_close(&out.data(), &expected);
+        let transposed = (0..24)
+            .map(|n| expected[(n / 12) * 12 + (n % 4) * 3 + (n / 4) % 3])
+            .collect::<Vec<_>>();
+        assert_close(&permuted.data(), &transposed);
+    }
+
+    #[test]
+    fn test_selective_scan_long_sequence() {
+        // Long enough that the sequence is split into chunks scanned in parallel
+        let mut cx = Graph::new();
+
+        let decays = random_vec(2 * 40_000)
+            .into_iter()
+            .map(|d| d.abs())
+            .collect::<Vec<_>>();
+        let inputs = random_vec(2 * 40_000);
+        let a = cx.tensor::<R2<40_000, 2>>().set(decays.clone());
+        let b = cx.tensor::<R2<40_000, 2>>().set(inputs.clone());
+        let out = a.selective_scan::<LAxis<0>>(b).retrieve();
+        cx.execute();
+
+        let mut expected = vec![0.; 2 * 40_000];
+        let mut h = [0.; 2];
+        for (k, (d, x)) in decays.chunks(2).zip(inputs.chunks(2)).enumerate() {
+            for j in 0..2 {
+                h[j] = d[j] * h[j] + x[j];
+                expected[k * 2 + j] = h[j];
+            }
+        }
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_rope() {
+        let mut cx = Graph::new();
+        cx.set_dyn_dim('p', 5);
+
+        let data = random_vec(24);
+        let freqs = cx.tensor::<R1<2>>().set(vec![1., 0.1]);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let rotated = a.rope(freqs, Expression::from('p')).retrieve();
+        // Strided input
+        let b = cx.tensor::<R3<3, 2, 4>>().set(data.clone());
+        let permuted = b
+            .permute::<R3<2, 3, 4>, _>()
+            .rope_with_scale(freqs, 0, 2.)
+            .retrieve();
+        cx.execute();
+
+        let rope = |x: &[f32], offset: usize, scale: f32| {
+            let mut out = vec![];
+            for (i, pair) in x.chunks(2).enumerate() {
+                let pos = (offset + (i / 2) % 3) as f32;
+                let (sin, cos) = (pos * [1., 0.1][i % 2]).sin_cos();
+                out.push((pair[0] * cos - pair[1] * sin) * scale);
+                out.push((pair[0] * sin + pair[1] * cos) * scale);
+            }
+            out
+        };
+        assert_close(&rotated.data(), &rope(&data, 5, 1.));
+        let transposed = (0..24)
+            .map(|i| data[((i / 4) % 3) * 8 + (i / 12) * 4 + i % 4])
+            .collect::<Vec<_>>();
+        assert_close(&permuted.data(), &rope(&transposed, 0, 2.));
+    }
+
+    #[test]
+    fn test_constant_constructors() {
+        let mut cx = Graph::new();
+        let stepped = cx.arange_step::<LConst<4>>(1., 0.5).retrieve();
+        let linspace = cx.linspace::<LConst<5>>(-1., 1.).retrieve();
+        let single = cx.linspace::<LConst<1>>(3., 5.).retrieve();
+        let dyn_linspace = cx.linspace::<Dyn<'n'>>(0., 6.).retrieve();
+        let eye = cx.eye::<LConst<3>>().retrieve();
+        let dyn_eye = cx.eye::<Dyn<'n'>>().retrieve();
+        let zeros = cx.zeros::<R2<2, 2>>().retrieve();
+        let ones = cx.ones::<(Dyn<'n'>, LConst<2>)>().retrieve();
+        let full = cx.full::<R1<3>>(2.5).retrieve();
+        let full_expr = cx.full::<R1<2>>(Expression::from('n')).retrieve();
+        cx.set_dyn_dim('n', 4);
+        cx.execute();
+
+        assert_exact(&stepped.data(), &[1., 1.5, 2., 2.5]);
+        assert_close(&linspace.data(), &[-1., -0.5, 0., 0.5, 1.]);
+        assert_exact(&single.data(), &[3.]);
+        assert_close(&dyn_linspace.data(), &[0., 2., 4., 6.]);
+        assert_exact(&eye.data(), &[1., 0., 0., 0., 1., 0., 0., 0., 1.]);
+        let mut expected_eye = vec![0.; 16];
+        for i in 0..4 {
+            expected_eye[i * 5] = 1.;
+        }
+        assert_exact(&dyn_eye.data(), &expected_eye);
+        assert_exact(&zeros.data(), &[0.; 4]);
+        assert_exact(&ones.data(), &[1.; 8]);
+        assert_exact(&full.data(), &[2.5; 3]);
+        assert_exact(&full_expr.data(), &[4.; 2]);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut cx = Graph::new();
+        cx.set_seed(3);
+        let uniform = cx.rand_uniform::<R1<4000>>(-1., 3.).retrieve();
+        let normal = cx.rand_normal::<R1<4001>>(2., 0.5).retrieve();
+        let coins = cx.bernoulli::<R1<4000>>(0.25).retrieve();
+        let dynamic = cx.randn::<(Dyn<'n'>, LConst<3>)>().retrieve();
+        cx.set_dyn_dim('n', 5);
+        cx.execute();
+
+        let stats = |data: &[f32]| {
+            let mean = data.iter().sum::<f32>() / data.len() as f32;
+            let var = data.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / data.len() as f32;
+            (mean, var.sqrt())
+        };
+        let uniform_data = uniform.data();
+        assert!(uniform_data.iter().all(|x| (-1. ..3.).contains(x)));
+        let (mean, std) = stats(&uniform_data);
+        assert!((mean - 1.).abs() < 0.1 && (std - 4. / 12_f32.sqrt()).abs() < 0.1);
+        let (mean, std) = stats(&normal.data());
+        assert!((mean - 2.).abs() < 0.05 && (std - 0.5).abs() < 0.05);
+        let coins_data = coins.data();
+        assert!(coins_data.iter().all(|x| *x == 0. || *x == 1.));
+        assert!((stats(&coins_data).0 - 0.25).abs() < 0.05);
+        assert_eq!(dynamic.data().len(), 15);
+
+        // Each execution draws new values, and the seed makes them reproducible
+        uniform.drop();
+        cx.set_seed(4);
+        cx.execute();
+        let second = uniform.data();
+        assert_ne!(second, uniform_data);
+        uniform.drop();
+        cx.set_seed(3);
+        cx.execute();
+        assert_exact(&uniform.data(), &uniform_data);
+    }
+
+    #[test]
+    fn test_tensor_triangles() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor::<R3<2, 2, 3>>().set(vec![1.; 12]);
+        let lower = a.tril(0).retrieve();
+        let upper = a.triu(1).retrieve();
+        // A sliding window of 2 over a causal mask
+        let window = a.tril(0).triu(-1).retrieve();
+        let mask = cx
+            .tensor::<R3<2, 2, 3>>()
+            .set(vec![0., 1., 0., 0., 0., 0., 1., 1., 0., 0., 0., 1.]);
+        let filled = a.masked_fill(mask, -5.).retrieve();
+        cx.execute();
+
+        assert_exact(&lower.data(), &[[1., 0., 0., 1., 1., 0.]; 2].concat());
+        assert_exact(&upper.data(), &[[0., 1., 1., 0., 0., 1.]; 2].concat());
+        assert_exact(&window.data(), &[[1., 0., 0., 1., 1., 0.]; 2].concat());
+        assert_exact(
+            &filled.data(),
+            &[1., -5., 1., 1., 1., 1., -5., -5., 1., 1., 1., -5.],
+        );
+    }
+
     #[test]
     fn test_dyn_arange() {
         let mut cx = Graph::new();
@@ -422,4 +916,70 @@ mod tests {
                 .collect::<Vec<_>>(),
         );
     }
+
+    #[test]
+    fn test_index_select() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<2, 3, 2>>()
+            .set((0..12).map(|i| i as f32).collect::<Vec<_>>());
+        let indexes = cx.tensor::<R2<2, 2>>().set(vec![2_u32, 0, 1, 1]);
+        let b = a
+            .index_select::<R4<2, 2, 2, 2>, LAxis<1>, _>(indexes)
+            .retrieve();
+        // Float indexes are rounded when cast to indexes
+        let rows = cx.tensor::<R1<3>>().set(vec![1.8, 0., 1.]).cast(DType::U32);
+        let c = cx
+            .tensor::<R2<3, 2>>()
+            .set((0..6).map(|i| i as f32).collect::<Vec<_>>())
+            .gather(rows)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(
+            &b.data(),
+            &[
+                4., 5., 0., 1., 2., 3., 2., 3., 10., 11., 6., 7., 8., 9., 8., 9.,
+            ],
+        );
+        assert_exact(&c.data(), &[4., 5., 0., 1., 2., 3.]);
+        assert_eq!(indexes.dtype(), DType::U32);
+        assert_eq!(rows.dtype(), DType::U32);
+        assert_eq!(c.dtype(), DType::F32);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::op::Gather>()));
+    }
+
+    #[test]
+    fn test_scatter() {
+        let mut cx = Graph::new();
+        let dest = cx
+            .tensor::<R2<2, 3>>()
+            .set((0..6).map(|i| i as f32).collect::<Vec<_>>());
+        let indexes = cx.tensor::<R2<2, 2>>().set(vec![2_u32, 0, 1, 1]);
+        let src = cx.tensor::<R2<2, 2>>().set(vec![10., 20., 30., 40.]);
+        let a = dest.scatter_along::<LAxis<1>, _>(indexes, src).retrieve();
+        let b = dest
+            .scatter_add_along::<LAxis<1>, _>(indexes, src)
+            .retrieve();
+        let c = cx
+            .tensor::<R1<3>>()
+            .set(vec![2_u32, 0, 1])
+            .one_hot::<LConst<3>>()
+            .retrieve();
+        let d = cx
+            .tensor::<R2<3, 2>>()
+            .set(vec![1., 2., 3., 4., 5., 6.])
+            .scatter_add::<LConst<4>>(cx.tensor::<R1<3>>().set(vec![2., 0., 2.]))
+            .retrieve();
+        cx.execute();
+
+        // The last write to a position wins, while adds accumulate
+        assert_exact(&a.data(), &[20., 1., 10., 3., 40., 5.]);
+        assert_exact(&b.data(), &[20., 1., 12., 3., 74., 5.]);
+        assert_exact(&c.data(), &[0., 0., 1., 1., 0., 0., 0., 1., 0.]);
+        assert_exact(&d.data(), &[3., 4., 0., 0., 6., 8., 0., 0.]);
+    }
 }
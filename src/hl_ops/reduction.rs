@@ -10,56 +10,148 @@ impl<S: Shape> GraphTensor<S> {
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
     {
-        let mut shape = self.shape;
-
-        let mut new_id = self.id;
-        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
-            new_id = self
-                .graph()
-                .add_op(op::SumReduce(dim))
-                .input(new_id, 0, shape)
-                .finish();
-            // Reduce shape
-            shape.remove_dim(dim);
-        }
-        GraphTensor::from_id(new_id, shape, self.graph_ref)
+        self.reduce_axes(&Ax::as_array().into_iter().collect_vec(), op::SumReduce)
     }
 
     pub fn max_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
     {
-        let mut shape = self.shape;
+        self.reduce_axes(&Ax::as_array().into_iter().collect_vec(), op::MaxReduce)
+    }
+
+    pub fn min_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        -(-self).max_reduce::<Dst, Ax>()
+    }
+
+    /// Multiply elements along axes. Unlike going through logs, this handles zeros and negative values.
+    pub fn prod_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.reduce_axes(&Ax::as_array().into_iter().collect_vec(), op::ProdReduce)
+    }
+
+    pub fn mean_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.mean_axes(&Ax::as_array().into_iter().collect_vec())
+    }
+
+    /// The population variance along axes (the mean squared distance from the mean, dividing by the number of elements)
+    pub fn var_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.var_axes(&Ax::as_array().into_iter().collect_vec())
+    }
+
+    /// The population standard deviation along axes
+    pub fn std_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.var_reduce::<Dst, Ax>().sqrt()
+    }
+}
 
+// Keepdim reductions. The reduced axes are expanded back to their original sizes, so the result lines up with the
+// input (like `x - x.mean_keepdim()`).
+impl<S: Shape> GraphTensor<S> {
+    pub fn sum_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        let axes = Ax::as_array().into_iter().collect_vec();
+        self.keep_dims(self.reduce_axes(&axes, op::SumReduce), &axes)
+    }
+
+    pub fn max_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        let axes = Ax::as_array().into_iter().collect_vec();
+        self.keep_dims(self.reduce_axes(&axes, op::MaxReduce), &axes)
+    }
+
+    pub fn min_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        -(-self).max_keepdim::<Ax>()
+    }
+
+    pub fn prod_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        let axes = Ax::as_array().into_iter().collect_vec();
+        self.keep_dims(self.reduce_axes(&axes, op::ProdReduce), &axes)
+    }
+
+    pub fn mean_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        let axes = Ax::as_array().into_iter().collect_vec();
+        self.keep_dims(self.mean_axes(&axes), &axes)
+    }
+
+    pub fn var_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        let axes = Ax::as_array().into_iter().collect_vec();
+        self.keep_dims(self.var_axes(&axes), &axes)
+    }
+
+    pub fn std_keepdim<Ax: Axes>(self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        self.var_keepdim::<Ax>().sqrt()
+    }
+}
+
+// Untyped reductions the typed ones above are built on
+impl<S: Shape> GraphTensor<S> {
+    /// Reduce away `axes` (in ascending order) with a reduce op, going from the last axis to the first
+    fn reduce_axes<Dst: Shape, O: Operator + 'static>(
+        self,
+        axes: &[usize],
+        reduce: impl Fn(usize) -> O,
+    ) -> GraphTensor<Dst> {
+        let mut shape = self.shape;
         let mut new_id = self.id;
-        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+        for dim in axes.iter().rev() {
             new_id = self
                 .graph()
-                .add_op(op::MaxReduce(dim))
+                .add_op(reduce(*dim))
                 .input(new_id, 0, shape)
                 .finish();
             // Reduce shape
-            shape.remove_dim(dim);
+            shape.remove_dim(*dim);
         }
-        GraphTensor::from_id(new_id, shape, self.graph_ref)
+        GraphTensor::from_id(new_id, shape, self.graph())
     }
 
-    pub fn mean_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
-    where
-        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
-    {
+    fn mean_axes<Dst: Shape>(self, axes: &[usize]) -> GraphTensor<Dst> {
         let mut shape = self.shape;
         let mut node_id = self.id;
-        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+        for dim in axes.iter().rev() {
             // Sum reduce
             node_id = self
                 .graph()
-                .add_op(op::SumReduce(dim))
+                .add_op(op::SumReduce(*dim))
                 .input(node_id, 0, shape)
                 .finish();
 
             // Divide by size of dimension
-            let div_tensor = self.graph().constant_expr(shape.remove_dim(dim)).id;
+            let div_tensor = self.graph().constant_expr(shape.remove_dim(*dim)).id;
             let mul_tensor = self
                 .graph()
                 .add_op(op::Recip)
@@ -82,7 +174,143 @@ impl<S: Shape> GraphTensor<S> {
                 )
                 .finish();
         }
-        GraphTensor::from_id(node_id, shape, self.graph_ref)
+        GraphTensor::from_id(node_id, shape, self.graph())
+    }
+
+    fn var_axes<Dst: Shape>(self, axes: &[usize]) -> GraphTensor<Dst> {
+        let centered = self - self.keep_dims(self.mean_axes(axes), axes);
+        (centered * centered).mean_axes(axes)
+    }
+
+    /// Expand the reduced `axes` of a reduction of this tensor back to their original sizes
+    fn keep_dims(self, reduced: GraphTensor<()>, axes: &[usize]) -> GraphTensor<S> {
+        let sizes = self.shape.shape();
+        let mut shape = reduced.shape;
+        for dim in axes {
+            shape.expand(*dim, sizes[*dim].small());
+        }
+        GraphTensor::from_id(reduced.id, shape, self.graph())
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// The `k` largest values along an axis and their indexes, largest first, see `TopK` and `ArgTopK`. The axis
+    /// shrinks to `k`. The indexes are `DType::U32`, ready to use with `gather` or `scatter_along`.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.topk
+    pub fn topk<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(
+        self,
+        k: usize,
+    ) -> (GraphTensor<Dst>, GraphTensor<Dst>) {
+        let dim = Ax::as_array()[0];
+        let mut dims = self
+            .shape
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect::<Vec<_>>();
+        dims[dim] = k.into();
+        let shape = ShapeTracker::new(&dims);
+        let graph = self.graph();
+        let values = graph
+            .add_op(op::TopK { k, dim })
+            .input(self.id, 0, self.shape)
+            .finish();
+        let indexes = graph
+            .add_op(op::ArgTopK { k, dim })
+            .input(self.id, 0, self.shape)
+            .finish();
+        graph.set_attribute(indexes, DType::U32);
+        (
+            GraphTensor::from_id(values, shape, self.graph()),
+            GraphTensor::from_id(indexes, shape, self.graph()),
+        )
+    }
+
+    /// Sum over sliding windows along the last dimension. The windows are reduced away, so the last dimension becomes the number of windows
+    pub fn sliding_window_sum<Dst: Shape>(
+        self,
+        kernel: Expression,
+        stride: Expression,
+    ) -> GraphTensor<Dst> {
+        self.reduce_windows(op::SumReduce(self.shape.len()), kernel, stride)
+    }
+
+    /// Max over sliding windows along the last dimension. The windows are reduced away, so the last dimension becomes the number of windows
+    pub fn sliding_window_max<Dst: Shape>(
+        self,
+        kernel: Expression,
+        stride: Expression,
+    ) -> GraphTensor<Dst> {
+        self.reduce_windows(op::MaxReduce(self.shape.len()), kernel, stride)
+    }
+
+    /// Mean over sliding windows along the last dimension. The windows are reduced away, so the last dimension becomes the number of windows
+    pub fn sliding_window_mean<Dst: Shape>(
+        self,
+        kernel: Expression,
+        stride: Expression,
+    ) -> GraphTensor<Dst> {
+        let sum = self.sliding_window_sum::<Dst>(kernel, stride);
+        sum / self.graph().constant_expr(kernel).expand_to(sum.shape)
+    }
+
+    fn reduce_windows<Dst: Shape, O: Operator + 'static>(
+        self,
+        reduce: O,
+        kernel: Expression,
+        stride: Expression,
+    ) -> GraphTensor<Dst> {
+        let n_dims = self.shape.len();
+        let pooled = self.pool_last_dim::<()>(kernel, stride, 0);
+        let mut shape = pooled.shape;
+        let new_id = self
+            .graph()
+            .add_op(reduce)
+            .input(pooled.id, 0, shape)
+            .finish();
+        shape.remove_dim(n_dims);
+        GraphTensor::from_id(new_id, shape, self.graph())
+    }
+}
+
+impl<N: Dimension, D: Dimension> GraphTensor<(N, D)> {
+    /// Map each row to the index of the segment it falls in, given ascending segment start offsets, as a one-hot (segments, rows) matrix.
+    /// Rows before the first offset don't belong to any segment.
+    fn segment_one_hot<S: Dimension>(self, offsets: GraphTensor<(S,)>) -> GraphTensor<(S, N)> {
+        let rows = self.graph().arange::<N>();
+        // The segment id of a row is the number of segments starting at or before it, minus one
+        let segment_ids = offsets
+            .expand::<(S, N), _>()
+            .less_than_equal(rows.expand())
+            .sum_reduce::<_, Axis<0>>()
+            - 1.;
+        self.graph()
+            .arange::<S>()
+            .expand::<(S, N), _>()
+            .equals(segment_ids.expand())
+    }
+
+    /// Sum rows over variable-length segments. `offsets` are the ascending row indexes each segment starts at, and each segment ends where the next starts.
+    pub fn segment_sum<S: Dimension>(self, offsets: GraphTensor<(S,)>) -> GraphTensor<(S, D)> {
+        self.segment_one_hot(offsets).matmul(self)
+    }
+
+    /// Max rows over variable-length segments. `offsets` are the ascending row indexes each segment starts at, and each segment ends where the next starts.
+    ///
+    /// Empty segments result in `f32::MIN`.
+    pub fn segment_max<S: Dimension>(self, offsets: GraphTensor<(S,)>) -> GraphTensor<(S, D)> {
+        let mask = self.segment_one_hot(offsets).expand::<(S, N, D), _>();
+        // Rows outside the segment get pushed down to the minimum so they never win the max
+        let min = self.graph().constant(f32::MIN).expand();
+        mask.where_(self.expand(), min).max_reduce::<_, Axis<1>>()
+    }
+
+    /// Mean rows over variable-length segments. `offsets` are the ascending row indexes each segment starts at, and each segment ends where the next starts.
+    pub fn segment_mean<S: Dimension>(self, offsets: GraphTensor<(S,)>) -> GraphTensor<(S, D)> {
+        let one_hot = self.segment_one_hot(offsets);
+        let lengths = one_hot.sum_reduce::<(S,), _>().max_f32(1.);
+        one_hot.matmul(self) / lengths.expand()
     }
 }
 
@@ -143,4 +371,140 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_min_prod_var_std_reduce() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(24);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(a_data.clone());
+        let min = a.min_reduce::<R1<3>, LAxes2<0, 2>>().retrieve();
+        let prod = a.prod_reduce::<R2<2, 4>, LAxis<1>>().retrieve();
+        let var = a.var_reduce::<R2<2, 3>, LAxis<2>>().retrieve();
+        let std = a.std_reduce::<R1<4>, LAxes2<0, 1>>().retrieve();
+        // Zeros and negatives are fine
+        let b = cx.tensor::<R1<3>>().set(vec![-2., 0., 3.]);
+        let c = cx.tensor::<R1<3>>().set(vec![-2., -1., 3.]);
+        let (b_prod, c_prod) = (b.prod_reduce().retrieve(), c.prod_reduce().retrieve());
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data.clone(), (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(&min.data(), &d_a.clone().min::<_, DAxes2<0, 2>>().as_vec());
+        assert_close(&var.data(), &d_a.clone().var::<_, DAxis<2>>().as_vec());
+        assert_close(&std.data(), &d_a.stddev::<_, DAxes2<0, 1>>(0.).as_vec());
+        let expected_prod = (0..8)
+            .map(|i| {
+                (0..3)
+                    .map(|j| a_data[(i / 4) * 12 + j * 4 + i % 4])
+                    .product()
+            })
+            .collect::<Vec<f32>>();
+        assert_close(&prod.data(), &expected_prod);
+        assert_exact(&b_prod.data(), &[0.]);
+        assert_exact(&c_prod.data(), &[6.]);
+    }
+
+    #[test]
+    fn test_keepdim_reductions() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 6., -4., 5., 2.]);
+        let sum = a.sum_keepdim::<LAxis<1>>().retrieve();
+        let max = a.max_keepdim::<LAxis<0>>().retrieve();
+        let min = a.min_keepdim::<LAxis<1>>().retrieve();
+        let prod = a.prod_keepdim::<LAxis<0>>().retrieve();
+        let mean = a.mean_keepdim::<LAxis<1>>().retrieve();
+        let var = a.var_keepdim::<LAxis<0>>().retrieve();
+        let std = a.std_keepdim::<LAxis<0>>().retrieve();
+        let total = a.sum_keepdim::<LAxes2<0, 1>>().retrieve();
+        let centered = (a - a.mean_keepdim::<LAxis<1>>()).retrieve();
+        cx.execute();
+
+        assert_close(&sum.data(), &[9., 9., 9., 3., 3., 3.]);
+        assert_close(&max.data(), &[1., 5., 6., 1., 5., 6.]);
+        assert_close(&min.data(), &[1., 1., 1., -4., -4., -4.]);
+        assert_close(&prod.data(), &[-4., 10., 12., -4., 10., 12.]);
+        assert_close(&mean.data(), &[3., 3., 3., 1., 1., 1.]);
+        assert_close(&var.data(), &[6.25, 2.25, 4., 6.25, 2.25, 4.]);
+        assert_close(&std.data(), &[2.5, 1.5, 2., 2.5, 1.5, 2.]);
+        assert_close(&total.data(), &[12.; 6]);
+        assert_close(&centered.data(), &[-2., -1., 3., -5., 4., 1.]);
+    }
+
+    #[test]
+    fn test_sliding_window_reductions() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set(vec![1., 5., 2., 4., 3., -1., 0., -3., 2., 8.]);
+        let sum = a
+            .sliding_window_sum::<R2<2, 2>>(3.into(), 2.into())
+            .retrieve();
+        let max = a
+            .sliding_window_max::<R2<2, 3>>(3.into(), 1.into())
+            .retrieve();
+        let mean = a
+            .sliding_window_mean::<R2<2, 4>>(2.into(), 1.into())
+            .retrieve();
+        cx.execute();
+
+        assert_close(&sum.data(), &[8., 9., -4., 7.]);
+        assert_close(&max.data(), &[5., 5., 4., 0., 2., 8.]);
+        assert_close(&mean.data(), &[3., 3.5, 3., 3.5, -0.5, -1.5, -0.5, 5.]);
+    }
+
+    #[test]
+    fn test_segment_reductions() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<6, 2>>()
+            .set(vec![1., 2., 3., -4., 5., 6., -7., 8., 9., 10., 11., 12.]);
+        // Segments of rows [0, 1), [1, 4), [4, 4), [4, 6)
+        let offsets = cx.tensor::<R1<4>>().set(vec![0., 1., 4., 4.]);
+        let sum = a.segment_sum(offsets).retrieve();
+        let max = a.segment_max(offsets).retrieve();
+        let mean = a.segment_mean(offsets).retrieve();
+        cx.execute();
+
+        assert_close(&sum.data(), &[1., 2., 1., 10., 0., 0., 20., 22.]);
+        assert_close(&max.data(), &[1., 2., 5., 8., f32::MIN, f32::MIN, 11., 12.]);
+        assert_close(&mean.data(), &[1., 2., 1. / 3., 10. / 3., 0., 0., 10., 11.]);
+    }
+
+    #[test]
+    fn test_topk() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 5>>()
+            .set(vec![3., 1., 4., 1., 5., 9., 2., 6., 5., 3.]);
+        let (values, indexes) = a.topk::<R2<2, 3>, LAxis<1>>(3);
+        let (values, indexes) = (values.retrieve(), indexes.retrieve());
+        // Ties go to the lower index
+        let b = cx.tensor::<R2<3, 2>>().set(vec![1., 2., 1., 0., 0., 2.]);
+        let (tie_values, tie_indexes) = b.topk::<R2<2, 2>, LAxis<0>>(2);
+        let (tie_values, tie_indexes) = (tie_values.retrieve(), tie_indexes.retrieve());
+        // Large enough to run in parallel
+        let c_data = random_vec(300 * 300);
+        let (big, _) = cx
+            .tensor::<R2<300, 300>>()
+            .set(c_data.clone())
+            .topk::<R2<300, 10>, LAxis<1>>(10);
+        let big = big.retrieve();
+        cx.execute();
+
+        assert_exact(&values.data(), &[5., 4., 3., 9., 6., 5.]);
+        assert_exact(&indexes.data(), &[4., 2., 0., 0., 2., 3.]);
+        assert_eq!(indexes.dtype(), DType::U32);
+        assert_exact(&tie_values.data(), &[1., 2., 1., 2.]);
+        assert_exact(&tie_indexes.data(), &[0., 0., 1., 2.]);
+        let expected = c_data
+            .chunks(300)
+            .flat_map(|row| {
+                let mut row = row.to_vec();
+                row.sort_by(|a, b| b.total_cmp(a));
+                row.truncate(10);
+                row
+            })
+            .collect::<Vec<_>>();
+        assert_exact(&big.data(), &expected);
+    }
 }
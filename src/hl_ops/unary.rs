@@ -17,7 +17,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Log2)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// Base 2 exp
@@ -27,7 +27,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Exp2)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// Natural exp
@@ -47,7 +47,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Recip)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// The sin(x) function
@@ -57,7 +57,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Sin)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// The cos(x) function
@@ -77,7 +77,7 @@ impl<S: Shape> GraphTensor<S> {
             .add_op(op::Sqrt)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
     }
 
     /// Scale so std is 1.0
@@ -119,12 +119,27 @@ impl<S: Shape> GraphTensor<S> {
         self.mean_norm::<Ax>().std_norm::<Ax, T>(epsilon)
     }
 
-    /// Applies a softmax function along an axis
+    /// Applies a layer norm along the last axis as a single `FusedLayerNorm` op, rather than the chain of reductions
+    /// `layer_norm` builds
+    pub fn fused_layer_norm(self, epsilon: f32) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::FusedLayerNorm(epsilon))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// Applies a softmax function along an axis. A single axis lowers to one fused `Softmax` op, while multiple axes
+    /// are built from max, exp, sum and divide.
     pub fn softmax<Ax: Axes>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
         S: ReduceShape<Ax>,
     {
+        if let [axis] = Ax::as_array().into_iter().collect::<Vec<_>>()[..] {
+            return self.softmax_axis(axis);
+        }
         let m = self
             - self
                 .max_reduce::<<S as ReduceShape<Ax>>::Reduced, _>()
@@ -135,12 +150,31 @@ impl<S: Shape> GraphTensor<S> {
             .expand()
     }
 
-    /// Applies a log softmax function along an axis
+    /// Applies a softmax function along an axis picked at runtime, as a single fused `Softmax` op
+    pub fn softmax_axis(self, axis: usize) -> GraphTensor<S> {
+        assert!(
+            axis < self.shape.len(),
+            "Softmax axis {axis} is out of bounds for a tensor with {} dimensions",
+            self.shape.len()
+        );
+        let new_id = self
+            .graph()
+            .add_op(op::Softmax(axis))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// Applies a log softmax function along an axis. A single axis lowers to one fused `LogSoftmax` op, while multiple
+    /// axes are built from max, exp, sum and log.
     pub fn log_softmax<Ax: Axes>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
         S: ReduceShape<Ax>,
     {
+        if let [axis] = Ax::as_array().into_iter().collect::<Vec<_>>()[..] {
+            return self.log_softmax_axis(axis);
+        }
         let m = self
             - self
                 .max_reduce::<<S as ReduceShape<Ax>>::Reduced, _>()
@@ -152,6 +186,21 @@ impl<S: Shape> GraphTensor<S> {
             .expand()
     }
 
+    /// Applies a log softmax function along an axis picked at runtime, as a single fused `LogSoftmax` op
+    pub fn log_softmax_axis(self, axis: usize) -> GraphTensor<S> {
+        assert!(
+            axis < self.shape.len(),
+            "Log softmax axis {axis} is out of bounds for a tensor with {} dimensions",
+            self.shape.len()
+        );
+        let new_id = self
+            .graph()
+            .add_op(op::LogSoftmax(axis))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
     /// Get the indicies of the max elements along the last axis
     pub fn argmax(self) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
         let x_equal = self.equals(self.max_reduce::<_, S::LastAxis>().expand_to(self.shape));
@@ -165,6 +214,63 @@ impl<S: Shape> GraphTensor<S> {
         (x_equal * r).max_reduce::<_, S::LastAxis>()
     }
 
+    /// Sample token indexes from logits along the last axis, using temperature scaling followed by top-k and top-p filtering.
+    ///
+    /// Runs as a single fused op, so only the sampled indexes need to leave the device. A `temperature` of 0 is greedy, a `top_k` of 0 disables top-k filtering and a `top_p` of 1 disables top-p filtering.
+    pub fn sample(
+        self,
+        temperature: f32,
+        top_k: usize,
+        top_p: f32,
+        seed: u64,
+    ) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
+        let new_id = self
+            .graph()
+            .add_op(op::Sample::new(temperature, top_k, top_p, seed))
+            .input(self.id, 0, self.shape)
+            .finish();
+        let mut shape = self.shape;
+        shape.remove_dim(shape.len() - 1);
+        GraphTensor::from_id(new_id, shape, self.graph())
+    }
+
+    /// Round to a lower precision format, rounding up or down at random in proportion to the distance to each neighbor.
+    ///
+    /// Unlike round-to-nearest this is unbiased in expectation, which keeps small updates from vanishing when training
+    /// in low precision. Values stay f32. The random stream comes from the graph's seed (see `Graph::set_seed`).
+    pub fn stochastic_round(self, format: RoundingFormat) -> GraphTensor<S> {
+        let graph = self.graph();
+        let salt = graph.graph.node_count() as u64;
+        let new_id = graph
+            .add_op(op::StochasticRound::new(format, salt, &graph.seed))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// Randomly zero elements with probability `p` while the graph is training, scaling the rest by `1 / (1 - p)`.
+    /// Outside of training (see `Graph::set_training`) values pass through unchanged. The random stream comes from the
+    /// graph's seed.
+    pub fn dropout(self, p: f32) -> GraphTensor<S> {
+        let graph = self.graph();
+        let salt = graph.graph.node_count() as u64;
+        let mask_id = graph
+            .add_op(op::DropoutMask::new(p, salt, &graph.seed, &graph.training))
+            .input(self.id, 0, self.shape)
+            .finish();
+        self * GraphTensor::from_id(mask_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// Convert to another dtype. Ops on half precision tensors keep their outputs in half precision, computing in f32.
+    pub fn cast(self, dtype: DType) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::Cast(dtype))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
     /// Take the absolute value
     pub fn abs(self) -> GraphTensor<S> {
         self.relu() + (-self).relu()
@@ -196,6 +302,27 @@ impl<S: Shape> GraphTensor<S> {
         (self * 2.0).sigmoid() * 2.0 - 1.0
     }
 
+    /// The Gauss error function
+    pub fn erf(self) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::Erf)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph())
+    }
+
+    /// The Gaussian Error Linear Unit activation function, computed exactly with `erf`
+    pub fn gelu(self) -> GraphTensor<S> {
+        self * 0.5 * (1. + (self * std::f32::consts::FRAC_1_SQRT_2).erf())
+    }
+
+    /// The Gaussian Error Linear Unit activation function, with the tanh approximation used by GPT-2
+    pub fn gelu_tanh(self) -> GraphTensor<S> {
+        let inner = (self + self * self * self * 0.044715) * (2. / std::f32::consts::PI).sqrt();
+        self * 0.5 * (1. + inner.tanh())
+    }
+
     /// The leaky relu activation function
     pub fn leaky_relu(self, neg_slope: f32) -> GraphTensor<S> {
         self.relu() - (self * -neg_slope).relu()
@@ -221,6 +348,24 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_gelu() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6)
+            .into_iter()
+            .map(|i| i * 6. - 3.)
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let exact = a.gelu().retrieve();
+        let approx = a.gelu_tanh().retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        assert_close(&exact.data(), &d_a.clone().accurate_gelu().as_vec());
+        assert_close(&approx.data(), &d_a.fast_gelu().as_vec());
+    }
+
     #[test]
     fn test_layer_norm() {
         let mut cx = Graph::new();
@@ -239,6 +384,30 @@ mod tests {
         assert_close(&c.data(), &d_c.as_vec());
     }
 
+    #[test]
+    fn test_fused_layer_norm() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 3 * 4);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(a_data.clone());
+        let b = a.fused_layer_norm(1e-5).retrieve();
+        // Strided input
+        let c = a
+            .permute::<_, LAxes3<0, 2, 1>>()
+            .fused_layer_norm(1e-5)
+            .retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        let d_b = d_a.clone().normalize::<DAxis<2>>(1e-5);
+        let d_c = d_a
+            .permute::<_, DAxes3<0, 2, 1>>()
+            .normalize::<DAxis<2>>(1e-5);
+
+        assert_close(&b.data(), &d_b.as_vec());
+        assert_close(&c.data(), &d_c.as_vec());
+    }
+
     #[test]
     fn test_softmax() {
         let mut cx = Graph::new();
@@ -256,6 +425,99 @@ mod tests {
         assert_close(&r, &d_b.as_vec());
     }
 
+    #[test]
+    fn test_softmax_axis() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 3 * 4);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(a_data.clone());
+        let middle = a.softmax_axis(1).retrieve();
+        let first = a.softmax::<LAxis<0>>().retrieve();
+        let strided = a.permute::<_, LAxes3<0, 2, 1>>().softmax_axis(2).retrieve();
+        let both = a.softmax::<LAxes2<1, 2>>().retrieve();
+        // Large logits stay finite since the max is subtracted first
+        let big = cx
+            .tensor::<R1<3>>()
+            .set(vec![1000., 1000., 0.])
+            .softmax_axis(0)
+            .retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(&middle.data(), &d_a.clone().softmax::<DAxis<1>>().as_vec());
+        assert_close(&first.data(), &d_a.clone().softmax::<DAxis<0>>().as_vec());
+        assert_close(
+            &strided.data(),
+            &d_a.clone()
+                .permute::<_, DAxes3<0, 2, 1>>()
+                .softmax::<DAxis<2>>()
+                .as_vec(),
+        );
+        assert_close(&both.data(), &d_a.softmax::<DAxes2<1, 2>>().as_vec());
+        assert_close(&big.data(), &[0.5, 0.5, 0.]);
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 3 * 4);
+        let a = cx.tensor::<R3<2, 3, 4>>().set(a_data.clone());
+        let last = a.log_softmax::<LAxis<2>>().retrieve();
+        let middle = a.log_softmax_axis(1).retrieve();
+        let both = a.log_softmax::<LAxes2<0, 2>>().retrieve();
+        // Stays finite where ln(softmax) would underflow
+        let big = cx
+            .tensor::<R1<2>>()
+            .set(vec![200., 0.])
+            .log_softmax_axis(0)
+            .retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(
+            &last.data(),
+            &d_a.clone().log_softmax::<DAxis<2>>().as_vec(),
+        );
+        assert_close(
+            &middle.data(),
+            &d_a.clone().log_softmax::<DAxis<1>>().as_vec(),
+        );
+        assert_close(&both.data(), &d_a.log_softmax::<DAxes2<0, 2>>().as_vec());
+        assert_close(&big.data(), &[0., -200.]);
+    }
+
+    #[test]
+    fn test_dropout() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<1000>>().set(vec![2.; 1000]);
+        let b = a.dropout(0.25).retrieve();
+
+        // Inference passes values through
+        cx.execute();
+        assert_exact(&b.data(), &[2.; 1000]);
+
+        b.drop();
+        cx.set_training(true);
+        cx.set_seed(7);
+        cx.execute();
+        let first = b.data();
+        let kept = first.iter().filter(|x| **x != 0.).count();
+        assert!((650..850).contains(&kept), "Kept {kept} of 1000");
+        assert!(first
+            .iter()
+            .all(|x| *x == 0. || (*x - 2. / 0.75).abs() < 1e-5));
+        // Another seed draws another mask, and going back to the first seed repeats it
+        b.drop();
+        cx.set_seed(8);
+        cx.execute();
+        assert_ne!(b.data(), first);
+        b.drop();
+        cx.set_seed(7);
+        cx.execute();
+        assert_exact(&b.data(), &first);
+    }
+
     #[test]
     fn test_sin() {
         let mut cx = Graph::new();
@@ -288,6 +550,99 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_sample() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(24);
+        let a = cx.tensor::<R2<3, 8>>().set(a_data.clone());
+        let greedy = a.sample(0., 0, 1., 0).retrieve();
+        let top_1 = a.sample(1., 1, 1., 0).retrieve();
+        let top_2 = a.sample(1., 2, 1., 0).retrieve();
+        let argmax = a.argmax().retrieve();
+        cx.execute();
+
+        assert_exact(&greedy.data(), &argmax.data());
+        assert_exact(&top_1.data(), &argmax.data());
+        for (row, ind) in a_data.chunks(8).zip(top_2.data()) {
+            let larger = row.iter().filter(|x| **x > row[ind as usize]).count();
+            assert!(larger < 2);
+        }
+    }
+
+    #[test]
+    fn test_half_precision() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let b = cx.tensor::<R2<2, 3>>().set(
+            a_data
+                .iter()
+                .map(|v| bf16::from_f32(*v))
+                .collect::<Vec<_>>(),
+        );
+        let h = a.cast(DType::F16);
+        let c = (h.exp() * 2.).sum_reduce::<_, LAxis<1>>().retrieve();
+        let d = (b + a).retrieve();
+        let e = h.cast(DType::F32).retrieve();
+        assert_eq!(a.dtype(), DType::F32);
+        assert_eq!(c.dtype(), DType::F16);
+        assert_eq!(d.dtype(), DType::Bf16);
+        assert_eq!(e.dtype(), DType::F32);
+        cx.execute();
+
+        for (id, dtype) in [(c.id, DType::F16), (d.id, DType::Bf16), (e.id, DType::F32)] {
+            assert_eq!(cx.get_tensor_ref(id, 0).unwrap().dtype(), dtype);
+        }
+        let expected_c = a_data
+            .chunks(3)
+            .map(|r| r.iter().map(|v| v.exp() * 2.).sum::<f32>())
+            .collect::<Vec<_>>();
+        assert_close_precision(&c.data(), &expected_c, 1e-2);
+        assert_close_precision(
+            &d.data(),
+            &a_data.iter().map(|v| v * 2.).collect::<Vec<_>>(),
+            1e-2,
+        );
+        assert_close_precision(&e.data(), &a_data, 1e-3);
+    }
+
+    #[test]
+    fn test_stochastic_round() {
+        fn run(seed: u64) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+            let mut cx = Graph::new();
+            cx.set_seed(seed);
+            // Every element sits between the same two neighbors in each format
+            let a = cx.tensor::<R1<1000>>().set(vec![1.3; 1000]);
+            let b = a.stochastic_round(RoundingFormat::BF16).retrieve();
+            let c = a.stochastic_round(RoundingFormat::F16).retrieve();
+            let d = a.stochastic_round(RoundingFormat::Int8).retrieve();
+            cx.execute();
+            (b.data(), c.data(), d.data())
+        }
+        let (bf16_data, f16_data, int8_data) = run(0);
+        for (data, lo, hi) in [
+            (
+                &bf16_data,
+                bf16::from_f32(1.3).to_f32(),
+                bf16::from_bits(bf16::from_f32(1.3).to_bits() + 1).to_f32(),
+            ),
+            (
+                &f16_data,
+                f16::from_f32(1.3).to_f32(),
+                f16::from_bits(f16::from_f32(1.3).to_bits() + 1).to_f32(),
+            ),
+            (&int8_data, 1., 2.),
+        ] {
+            assert!(data.iter().all(|i| *i == lo || *i == hi));
+            // Unbiased in expectation
+            let mean = data.iter().sum::<f32>() / data.len() as f32;
+            assert!((mean - 1.3).abs() < (hi - lo) * 0.1, "{mean} {lo} {hi}");
+        }
+        // Reproducible with the same seed
+        assert_eq!(run(0), (bf16_data.clone(), f16_data, int8_data));
+        assert_ne!(run(1).0, bf16_data);
+    }
+
     #[test]
     fn test_relu() {
         let mut cx = Graph::new();
@@ -0,0 +1,85 @@
+//! Downloading checkpoints from the HuggingFace Hub. Enabled with the `hf-hub` feature.
+//!
+//! Files are cached where the Python libraries keep them (`~/.cache/huggingface/hub`, or under `HF_HOME`), so models
+//! downloaded by either are only fetched once. Gated models like llama need a token, read from `HF_TOKEN` or the one
+//! saved by `huggingface-cli login`.
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use hf_hub::{
+    api::sync::{ApiBuilder, ApiRepo},
+    Repo, RepoType,
+};
+
+use crate::serialization::shard_files;
+
+/// The local copies of a model's files, all in one snapshot directory of the cache
+#[derive(Debug, Clone)]
+pub struct Pretrained {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    /// The `tokenizer_config.json`, if the model has one
+    pub tokenizer_config: Option<PathBuf>,
+    /// The weights to load, either `model.safetensors` or the `model.safetensors.index.json` of a sharded checkpoint
+    /// with its shards downloaded next to it
+    pub weights: PathBuf,
+}
+
+impl Pretrained {
+    /// The directory holding the files, which can be used in place of a manually downloaded model directory
+    pub fn dir(&self) -> &Path {
+        self.config.parent().unwrap()
+    }
+}
+
+/// Download a model's `config.json`, tokenizer and safetensors weights, or find them in the cache. A branch, tag or
+/// commit can be given after an `@`, like `google/gemma-2b@main`.
+pub fn download(model_id: &str) -> Result<Pretrained> {
+    let (model_id, revision) = model_id.split_once('@').unwrap_or((model_id, "main"));
+    let mut api = ApiBuilder::new();
+    if let Ok(token) = std::env::var("HF_TOKEN") {
+        api = api.with_token(Some(token));
+    }
+    let repo = api.build().map_err(Error::other)?.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        revision.to_string(),
+    ));
+    let get = |file: &str| {
+        repo.get(file)
+            .map_err(|e| Error::other(format!("Couldn't download {file} from {model_id}: {e}")))
+    };
+
+    Ok(Pretrained {
+        config: get("config.json")?,
+        tokenizer: get("tokenizer.json")?,
+        tokenizer_config: repo.get("tokenizer_config.json").ok(),
+        weights: download_weights(&repo).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Couldn't download weights from {model_id}: {e}"),
+            )
+        })?,
+    })
+}
+
+/// Download a single file checkpoint, or the index and every shard of a sharded one
+fn download_weights(repo: &ApiRepo) -> Result<PathBuf> {
+    let Ok(index) = repo.get("model.safetensors.index.json") else {
+        return repo.get("model.safetensors").map_err(|e| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Found no model.safetensors or model.safetensors.index.json ({e})"),
+            )
+        });
+    };
+    let dir = index.parent().unwrap();
+    for shard in shard_files(&index)? {
+        let name = shard.strip_prefix(dir).unwrap().to_string_lossy();
+        repo.get(&name).map_err(Error::other)?;
+    }
+    Ok(index)
+}
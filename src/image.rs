@@ -0,0 +1,204 @@
+//! Reading images and preparing them for vision models the way HuggingFace image processors do.
+
+use std::{io::Result, path::Path};
+
+use crate::serialization::invalid_data;
+
+/// An 8-bit RGB image, stored row-major with interleaved channels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Read a binary PPM (P6) or PGM (P5) image. Grayscale images are expanded to RGB.
+///
+/// Other formats can be converted with ImageMagick: `convert image.jpg image.ppm`
+pub fn read_pnm(path: impl AsRef<Path>) -> Result<Image> {
+    let bytes = std::fs::read(path)?;
+    let invalid = |msg: &str| invalid_data(msg.to_string());
+
+    // The header is 4 whitespace separated fields, which can be interleaved with comments
+    let (mut fields, mut pos) = (vec![], 0);
+    while fields.len() < 4 {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if bytes.get(pos) == Some(&b'#') {
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(invalid("Truncated header"));
+        }
+        fields
+            .push(std::str::from_utf8(&bytes[start..pos]).map_err(|_| invalid("Invalid header"))?);
+    }
+    // A single whitespace byte separates the header from the pixels
+    pos += 1;
+
+    let channels = match fields[0] {
+        "P6" => 3,
+        "P5" => 1,
+        _ => {
+            return Err(invalid(
+                "Only binary PPM (P6) and PGM (P5) images are supported",
+            ))
+        }
+    };
+    let parse = |s: &str| s.parse::<usize>().map_err(|_| invalid("Invalid header"));
+    let (width, height, max) = (parse(fields[1])?, parse(fields[2])?, parse(fields[3])?);
+    if max == 0 || max > 255 {
+        return Err(invalid("Only 8-bit images are supported"));
+    }
+    let data = bytes
+        .get(pos..pos + width * height * channels)
+        .ok_or_else(|| invalid("Truncated pixel data"))?;
+    let pixels = data
+        .iter()
+        .flat_map(|&v| {
+            // Rescale to the full 8-bit range
+            let v = (v as usize * 255 / max) as u8;
+            std::iter::repeat_n(v, 3 / channels)
+        })
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// How images are prepared for a vision model, read from a HuggingFace `preprocessor_config.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preprocessor {
+    /// The length the shortest side is resized to, keeping the aspect ratio, before the center crop. If `None`,
+    /// images are resized straight to the crop.
+    pub shortest_edge: Option<usize>,
+    /// The side length of the square image fed to the model
+    pub crop_size: usize,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl Preprocessor {
+    /// ViT's preprocessing: resize straight to the crop, normalizing each channel to [-1, 1]
+    pub fn new(crop_size: usize) -> Self {
+        Self {
+            shortest_edge: None,
+            crop_size,
+            mean: [0.5; 3],
+            std: [0.5; 3],
+        }
+    }
+
+    /// CLIP's preprocessing: resize the shortest side to the crop size, crop the center and normalize with CLIP's
+    /// channel statistics
+    pub fn clip(crop_size: usize) -> Self {
+        Self {
+            shortest_edge: Some(crop_size),
+            crop_size,
+            mean: [0.481_454_66, 0.457_827_5, 0.408_210_73],
+            std: [0.268_629_54, 0.261_302_6, 0.275_777_1],
+        }
+    }
+
+    /// Read the preprocessor config, falling back to `defaults` for missing fields. The crop is always the
+    /// model's image size in `defaults`.
+    pub fn from_hf_config(path: impl AsRef<Path>, defaults: Self) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let triple = |key: &str, default: [f32; 3]| {
+            json.get(key)
+                .and_then(serde_json::Value::as_array)
+                .filter(|v| v.len() == 3)
+                .map(|v| std::array::from_fn(|i| v[i].as_f64().unwrap_or(default[i] as f64) as f32))
+                .unwrap_or(default)
+        };
+        Ok(Self {
+            shortest_edge: json
+                .get("size")
+                .and_then(|s| s.get("shortest_edge"))
+                .and_then(serde_json::Value::as_u64)
+                .map(|s| s as usize)
+                .or(defaults.shortest_edge),
+            crop_size: defaults.crop_size,
+            mean: triple("image_mean", defaults.mean),
+            std: triple("image_std", defaults.std),
+        })
+    }
+
+    /// Resize the image with bilinear interpolation, crop its center, rescale it to [0, 1] and normalize each channel,
+    /// returning (channel, y, x) data
+    pub fn process(&self, image: &Image) -> Vec<f32> {
+        let size = self.crop_size;
+        // The size of the resized image, and where the crop starts in it
+        let (width, height) = match self.shortest_edge {
+            Some(edge) => {
+                let scale = edge as f32 / image.width.min(image.height) as f32;
+                let resized = |len: usize| ((len as f32 * scale).round() as usize).max(size);
+                (resized(image.width), resized(image.height))
+            }
+            None => (size, size),
+        };
+        let (left, top) = ((width - size) / 2, (height - size) / 2);
+        let mut out = vec![0.; 3 * size * size];
+        // Map output pixel centers back to source coordinates, clamped to the edges
+        let source = |i: usize, len: usize, resized: usize| {
+            let x =
+                ((i as f32 + 0.5) * len as f32 / resized as f32 - 0.5).clamp(0., (len - 1) as f32);
+            let lo = x.floor() as usize;
+            (lo, (lo + 1).min(len - 1), x - lo as f32)
+        };
+        let pixel =
+            |x: usize, y: usize, c: usize| image.pixels[(y * image.width + x) * 3 + c] as f32;
+        for y in 0..size {
+            let (y0, y1, fy) = source(top + y, image.height, height);
+            for x in 0..size {
+                let (x0, x1, fx) = source(left + x, image.width, width);
+                for c in 0..3 {
+                    let top = pixel(x0, y0, c) * (1. - fx) + pixel(x1, y0, c) * fx;
+                    let bottom = pixel(x0, y1, c) * (1. - fx) + pixel(x1, y1, c) * fx;
+                    let value = (top * (1. - fy) + bottom * fy) / 255.;
+                    out[(c * size + y) * size + x] = (value - self.mean[c]) / self.std[c];
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_pnm, Preprocessor};
+    use crate::tests::temp_dir;
+
+    #[test]
+    fn test_read_and_process_pnm() {
+        // A 4x2 grayscale image, dark on the left and bright on the right
+        let path = temp_dir("image").join("test.pgm");
+        let mut bytes = b"P5\n# comment\n4 2\n255\n".to_vec();
+        bytes.extend([0, 0, 255, 255, 0, 0, 255, 255]);
+        std::fs::write(&path, bytes).unwrap();
+        let image = read_pnm(&path).unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(&image.pixels[..6], &[0; 6]);
+
+        // Resizing straight to the crop squashes the image, while CLIP keeps the center
+        let squashed = Preprocessor::new(2).process(&image);
+        assert_eq!(&squashed[..2], &[-1., 1.]);
+        let cropped = Preprocessor {
+            mean: [0.; 3],
+            std: [1.; 3],
+            ..Preprocessor::clip(2)
+        }
+        .process(&image);
+        assert_eq!(&cropped[..2], &[0., 1.]);
+    }
+}
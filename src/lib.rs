@@ -1,22 +1,46 @@
+pub mod batch;
+pub mod beam_search;
+pub mod chat;
 pub mod compiler_utils;
+pub mod error;
+pub mod generate;
 pub mod generic_compiler;
+pub mod grammar;
 pub mod graph;
 pub mod graph_tensor;
 pub mod hl_ops;
+#[cfg(feature = "hf-hub")]
+pub mod hub;
+pub mod image;
+pub mod metadata;
 pub mod module;
 pub mod op;
+pub mod pass_registry;
+pub mod serialization;
+pub mod session;
 pub mod shape;
+#[cfg(feature = "tokenizers")]
+pub mod tokenizer;
 
 pub mod tests;
 
 pub mod prelude {
+    pub use crate::batch::*;
+    pub use crate::beam_search::*;
+    pub use crate::chat::*;
     pub use crate::compiler_utils::*;
+    pub use crate::error::LuminalError;
+    pub use crate::generate::*;
     pub use crate::generic_compiler::*;
+    pub use crate::grammar::*;
     pub use crate::graph::*;
     pub use crate::graph_tensor::*;
     pub use crate::hl_ops::*;
+    pub use crate::metadata::*;
     pub use crate::module::*;
     pub use crate::op::*;
+    pub use crate::pass_registry::*;
+    pub use crate::session::*;
     pub use crate::shape::*;
     pub use half::{bf16, f16};
     pub use luminal_symbolic::*;
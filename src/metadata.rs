@@ -0,0 +1,167 @@
+use std::{
+    any::{Any, TypeId},
+    fmt::Debug,
+};
+
+use dyn_clone::{clone_trait_object, DynClone};
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// A typed attribute that can be attached to graph nodes. Implemented for any cloneable, debuggable type.
+pub trait Attribute: Any + Debug + DynClone {
+    fn attribute_as_any(&self) -> &dyn Any;
+}
+
+clone_trait_object!(Attribute);
+
+impl<T: Any + Debug + Clone> Attribute for T {
+    fn attribute_as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The attributes attached to a node, keyed by their type
+#[derive(Debug, Clone, Default)]
+pub struct NodeMetadata(FxHashMap<TypeId, Box<dyn Attribute>>);
+
+impl NodeMetadata {
+    pub fn get<A: Attribute>(&self) -> Option<&A> {
+        self.0
+            .get(&TypeId::of::<A>())
+            .and_then(|a| a.as_ref().attribute_as_any().downcast_ref())
+    }
+
+    pub fn insert<A: Attribute>(&mut self, attribute: A) {
+        self.0.insert(TypeId::of::<A>(), Box::new(attribute));
+    }
+
+    pub fn remove<A: Attribute>(&mut self) -> bool {
+        self.0.remove(&TypeId::of::<A>()).is_some()
+    }
+
+    /// Add any attributes from `other` that aren't already set
+    pub fn merge(&mut self, other: NodeMetadata) {
+        for (k, v) in other.0 {
+            self.0.entry(k).or_insert(v);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The path of the module a weight came from, like `layer0/attention/q_proj`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModulePath(pub String);
+
+/// Keep this node out of multi-node pattern matches, so compilers won't fuse it with its neighbors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoNotFuse;
+
+/// The precision a node would prefer to be computed in. `F64Compiler` computes nodes hinted at anything but F64 in f32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrecisionHint {
+    F64,
+    F32,
+    F16,
+    BF16,
+    Int8,
+}
+
+/// The reusable buffer a node's output is written into, assigned by `MemoryPlanner`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSlot(pub usize);
+
+/// The number of elements in the data set on an input tensor, checked against its shape when dynamic dimensions are
+/// bound with `execute_with_dims`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataLength(pub usize);
+
+/// This node's op updates its first input in place. If the op is the input's only consumer, the input is handed over
+/// even when kept, so it moves into this node's output instead of being copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InPlace;
+
+impl Graph {
+    /// Attach an attribute to a node, replacing any existing attribute of the same type
+    pub fn set_attribute<A: Attribute>(&mut self, node: NodeIndex, attribute: A) {
+        self.metadata.entry(node).or_default().insert(attribute);
+    }
+
+    /// Get an attribute of a node
+    pub fn attribute<A: Attribute>(&self, node: NodeIndex) -> Option<&A> {
+        self.metadata.get(&node).and_then(|m| m.get())
+    }
+
+    /// Check if a node has an attribute
+    pub fn has_attribute<A: Attribute>(&self, node: NodeIndex) -> bool {
+        self.attribute::<A>(node).is_some()
+    }
+
+    /// Remove an attribute from a node, returning whether it was set
+    pub fn remove_attribute<A: Attribute>(&mut self, node: NodeIndex) -> bool {
+        let Some(metadata) = self.metadata.get_mut(&node) else {
+            return false;
+        };
+        let removed = metadata.remove::<A>();
+        if metadata.is_empty() {
+            self.metadata.remove(&node);
+        }
+        removed
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Attach an attribute to the node producing this tensor
+    pub fn with_attribute<A: Attribute>(self, attribute: A) -> Self {
+        self.graph().set_attribute(self.id, attribute);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_do_not_fuse() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let b = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let zero = cx.constant(0.).expand();
+        let mut c = (a + zero).retrieve();
+        let mut d = (b + zero).with_attribute(DoNotFuse).retrieve();
+        assert_eq!(cx.attribute::<DoNotFuse>(d.id), Some(&DoNotFuse));
+        assert_eq!(cx.attribute::<DoNotFuse>(c.id), None);
+
+        cx.compile(ArithmeticElimination, (&mut c, &mut d));
+        // The untagged add was removed, the tagged one survives
+        assert_eq!(c.id, a.id);
+        assert!(cx.check_node_type::<Add>(d.id));
+        assert!(cx.has_attribute::<DoNotFuse>(d.id));
+    }
+
+    #[test]
+    fn test_attributes_follow_remap() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<3>>()
+            .with_attribute(ModulePath("layer0/weight".to_string()))
+            .with_attribute(PrecisionHint::F16);
+        let b = cx.tensor::<R1<3>>().with_attribute(PrecisionHint::F32);
+        remap(a.id, b.id, (), &mut cx);
+        assert_eq!(
+            cx.attribute::<ModulePath>(b.id),
+            Some(&ModulePath("layer0/weight".to_string()))
+        );
+        // Existing attributes aren't overwritten
+        assert_eq!(
+            cx.attribute::<PrecisionHint>(b.id),
+            Some(&PrecisionHint::F32)
+        );
+        assert!(cx.remove_attribute::<PrecisionHint>(b.id));
+        assert!(!cx.has_attribute::<PrecisionHint>(b.id));
+    }
+}
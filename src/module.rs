@@ -32,6 +32,122 @@ pub fn params(model: impl SerializeModule) -> Vec<NodeIndex> {
         .collect()
 }
 
+/// A tensor registered by a module, as listed by `param_info`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamInfo {
+    /// The module path, like `layer0/weight`
+    pub name: String,
+    pub id: NodeIndex,
+    /// The dimensions, with dynamic dimensions resolved where the graph knows them
+    pub shape: Vec<BigExpression>,
+    pub dtype: DType,
+    /// The number of elements, unless a dynamic dimension isn't known yet
+    pub n_elements: Option<usize>,
+}
+
+impl ParamInfo {
+    /// Bytes the tensor takes in its dtype, unless a dynamic dimension isn't known yet
+    pub fn size_bytes(&self) -> Option<usize> {
+        self.n_elements.map(|n| n * self.dtype.size())
+    }
+}
+
+/// Every tensor a model registers through `SerializeModule`, sorted by name
+pub fn param_info(model: impl SerializeModule, graph: &Graph) -> Vec<ParamInfo> {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    s.state
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(name, id)| {
+            let shape = s.shapes[&name];
+            ParamInfo {
+                shape: shape
+                    .shape()
+                    .into_iter()
+                    .map(|d| d.exec(&graph.dyn_map).map(BigExpression::from).unwrap_or(d))
+                    .collect(),
+                dtype: graph.dtype(id),
+                n_elements: shape.n_elements().exec(&graph.dyn_map),
+                name,
+                id,
+            }
+        })
+        .collect()
+}
+
+/// A table of a model's tensors with their shapes, dtypes and sizes
+pub fn summary(model: impl SerializeModule, graph: &Graph) -> ModelSummary {
+    ModelSummary {
+        params: param_info(model, graph),
+    }
+}
+
+/// The tensors of a model, from `summary`. Displays as a table followed by the totals.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelSummary {
+    pub params: Vec<ParamInfo>,
+}
+
+impl ModelSummary {
+    /// Total number of elements across tensors with known sizes
+    pub fn total_params(&self) -> usize {
+        self.params.iter().filter_map(|p| p.n_elements).sum()
+    }
+
+    /// Total bytes across tensors with known sizes
+    pub fn total_bytes(&self) -> usize {
+        self.params.iter().filter_map(|p| p.size_bytes()).sum()
+    }
+}
+
+impl std::fmt::Display for ModelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = self
+            .params
+            .iter()
+            .map(|p| {
+                [
+                    p.name.clone(),
+                    format!("[{}]", p.shape.iter().join(", ")),
+                    format!("{:?}", p.dtype),
+                    p.n_elements.map(|n| n.to_string()).unwrap_or("?".into()),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let header = ["Name", "Shape", "DType", "Params"].map(String::from);
+        let widths = (0..4)
+            .map(|i| {
+                rows.iter()
+                    .chain([&header])
+                    .map(|r| r[i].len())
+                    .max()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for row in [&header].into_iter().chain(&rows) {
+            writeln!(
+                f,
+                "{:w0$}  {:w1$}  {:w2$}  {:>w3$}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3]
+            )?;
+        }
+        writeln!(
+            f,
+            "Total: {} params ({})",
+            self.total_params(),
+            format_bytes(self.total_bytes())
+        )
+    }
+}
+
 /// Transfer data from one set of nodes in one graph to another set in another graph
 pub fn transfer_data(
     srcs: impl ToIds,
@@ -210,6 +326,8 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    /// The shape of each tensor, keyed by the same paths as `state`
+    pub shapes: FxHashMap<String, ShapeTracker>,
 }
 
 impl Serializer {
@@ -218,8 +336,13 @@ impl Serializer {
             // Add new path component
             self.current_path.push(name.to_string());
         }
-        // Insert tensor id
-        self.state.insert(self.current_path.join("/"), tensor.id);
+        // Insert tensor id, and tag the tensor with where it came from
+        let path = self.current_path.join("/");
+        tensor
+            .graph()
+            .set_attribute(tensor.id, ModulePath(path.clone()));
+        self.shapes.insert(path.clone(), tensor.shape);
+        self.state.insert(path, tensor.id);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
@@ -1,8 +1,17 @@
-use std::{any::Any, fmt::Debug};
+use std::{
+    any::{Any, TypeId},
+    borrow::Cow,
+    cell::RefCell,
+    fmt::Debug,
+    mem::ManuallyDrop,
+    sync::OnceLock,
+};
 
 use crate::prelude::*;
 
 use dyn_clone::{clone_trait_object, DynClone};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
 use rustc_hash::FxHashMap;
 
 /// A tensor with data. The data can be anything that implements the Data trait
@@ -17,14 +26,72 @@ impl Tensor {
             data: Box::new(data),
         }
     }
+    /// Index data can be read as a `Vec<f32>`
     pub fn downcast_ref<T: Data>(&self) -> Option<&T> {
-        self.data.as_any().downcast_ref()
+        self.data.as_any().downcast_ref().or_else(|| {
+            self.data
+                .as_any()
+                .downcast_ref::<IndexVec>()
+                .and_then(|i| (i.upcast() as &dyn Any).downcast_ref())
+        })
     }
+    /// Borrowed, quantized, half precision and index data is copied into a `Vec<f32>` before it can be written to
     pub fn downcast_mut<T: Data>(&mut self) -> Option<&mut T> {
+        if TypeId::of::<T>() == TypeId::of::<Vec<f32>>() {
+            if let Some(b) = self.data.as_any().downcast_ref::<BorrowedSlice>() {
+                self.data = Box::new(b.as_slice().to_vec());
+            } else if let Some(q) = self.data.as_any().downcast_ref::<QuantizedVec>() {
+                self.data = Box::new(q.dequantize());
+            } else if let Some(h) = self.data.as_any().downcast_ref::<HalfVec>() {
+                self.data = Box::new(h.to_f32());
+            } else if let Some(i) = self.data.as_any().downcast_ref::<IndexVec>() {
+                self.data = Box::new(i.to_f32());
+            }
+        }
         self.data.as_any_mut().downcast_mut()
     }
     pub fn is<T: Data>(&self) -> bool {
-        self.data.as_any().is::<T>()
+        self.downcast_ref::<T>().is_some()
+    }
+    /// The values of CPU data as f32s. Owned and borrowed f32 data is read in place, other data is converted.
+    pub fn f32_data(&self) -> Option<Cow<'_, [f32]>> {
+        let data = self.data.as_any();
+        if let Some(b) = data.downcast_ref::<BorrowedSlice>() {
+            Some(Cow::Borrowed(b.as_slice()))
+        } else if let Some(h) = data.downcast_ref::<HalfVec>() {
+            Some(Cow::Owned(h.to_f32()))
+        } else if let Some(q) = data.downcast_ref::<QuantizedVec>() {
+            Some(Cow::Owned(q.dequantize()))
+        } else {
+            self.downcast_ref::<Vec<f32>>()
+                .map(|v| Cow::Borrowed(v.as_slice()))
+        }
+    }
+    /// Number of bytes allocated for this tensor
+    pub fn size_bytes(&self) -> usize {
+        self.data.size_bytes()
+    }
+    /// The device this tensor lives on
+    pub fn device(&self) -> &'static str {
+        self.data.device()
+    }
+    /// Pointer to the data if it's stored directly as a `Vec<f32>`
+    pub(crate) fn f32_ptr(&self) -> Option<*const f32> {
+        self.data
+            .as_any()
+            .downcast_ref::<Vec<f32>>()
+            .map(|v| v.as_ptr())
+    }
+    /// The element type of this tensor's data
+    pub fn dtype(&self) -> DType {
+        let data = self.data.as_any();
+        if let Some(h) = data.downcast_ref::<HalfVec>() {
+            h.dtype()
+        } else if data.is::<IndexVec>() {
+            DType::U32
+        } else {
+            DType::F32
+        }
     }
 }
 
@@ -32,6 +99,14 @@ impl Tensor {
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Number of bytes allocated for this data. Types that own heap or device memory should override this.
+    fn size_bytes(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+    /// Name of the device this data lives on
+    fn device(&self) -> &'static str {
+        "CPU"
+    }
 }
 
 clone_trait_object!(Data);
@@ -43,6 +118,440 @@ impl Data for Vec<f32> {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn size_bytes(&self) -> usize {
+        self.len() * std::mem::size_of::<f32>()
+    }
+}
+
+/// Double precision data, used when running graphs in f64 to get high precision baselines.
+///
+/// This is a wrapper rather than `Vec<f64>` so float literals passed to `set` still infer as f32.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct F64Data(pub Vec<f64>);
+
+impl Data for F64Data {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.0.len() * std::mem::size_of::<f64>()
+    }
+}
+
+/// f32 data owned outside of the graph, which is read in place instead of being copied.
+///
+/// Read through `Tensor::f32_data`, and copied into a `Vec<f32>` the first time an op needs to write to it.
+#[derive(Clone)]
+pub struct BorrowedSlice {
+    ptr: *const f32,
+    len: usize,
+}
+
+impl BorrowedSlice {
+    /// # Safety
+    /// `ptr` must point to `len` valid f32s that aren't written to for as long as this data (or any clone of it) is alive.
+    pub unsafe fn new(ptr: *const f32, len: usize) -> Self {
+        Self { ptr, len }
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Debug for BorrowedSlice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BorrowedSlice({:?}, {})", self.ptr, self.len)
+    }
+}
+
+impl Data for BorrowedSlice {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        // The memory is owned by the user, not the graph
+        0
+    }
+}
+
+/// f32 data stored as 8 or 4 bit integers, with an f32 scale for each group of values. Used to shrink tensors that
+/// are held for a long time, like KV caches.
+///
+/// Values are laid out as `slices` runs of rows. Each row starts on a new byte and a new group, and each slice has room
+/// for `capacity` rows, so rows can be appended to every slice without requantizing or moving the existing ones.
+///
+/// Primitive ops dequantize values as they read them. Other ops read it through `Tensor::f32_data`, which dequantizes
+/// it into a buffer that only lives as long as the op.
+#[derive(Debug, Clone)]
+pub struct QuantizedVec {
+    data: Vec<u8>,
+    scales: Vec<f32>,
+    bits: u8,
+    group_size: usize,
+    row_len: usize,
+    row_bytes: usize,
+    row_groups: usize,
+    slices: usize,
+    rows: usize,
+    capacity: usize,
+}
+
+impl QuantizedVec {
+    /// An empty buffer of `slices` runs of rows, each holding `row_len` values quantized to `bits` (8 or 4) bits, with a
+    /// scale for every `group_size` values
+    pub fn new(slices: usize, row_len: usize, bits: u8, group_size: usize) -> Self {
+        assert!(
+            bits == 8 || bits == 4,
+            "Only 8 and 4 bit quantization is supported"
+        );
+        assert!(group_size > 0 && (bits == 8 || group_size.is_multiple_of(2)));
+        Self {
+            data: vec![],
+            scales: vec![],
+            bits,
+            group_size,
+            row_len,
+            row_bytes: (row_len * bits as usize).div_ceil(8),
+            row_groups: row_len.div_ceil(group_size),
+            slices,
+            rows: 0,
+            capacity: 0,
+        }
+    }
+
+    /// Symmetrically quantize values to `bits` (8 or 4) bits, with a scale for every `group_size` values
+    pub fn quantize(values: &[f32], bits: u8, group_size: usize) -> Self {
+        let mut quantized = Self::new(1, values.len().max(1), bits, group_size);
+        quantized.append(values);
+        quantized
+    }
+
+    /// Quantize rows onto the end of every slice. The values are laid out as (slices, new rows, row length).
+    ///
+    /// Only the new rows are quantized. Existing rows are only moved when a slice runs out of room, which doubles its
+    /// capacity.
+    pub fn append(&mut self, values: &[f32]) {
+        let slice_len = self.slices * self.row_len;
+        if slice_len == 0 || values.is_empty() {
+            return;
+        }
+        assert!(
+            values.len().is_multiple_of(slice_len),
+            "Can't append {} values to {} slices of rows of {}",
+            values.len(),
+            self.slices,
+            self.row_len
+        );
+        let new_rows = values.len() / slice_len;
+        if self.rows + new_rows > self.capacity {
+            self.grow((self.rows + new_rows).max(self.capacity * 2));
+        }
+        let max_int = ((1 << (self.bits - 1)) - 1) as f32;
+        for (slice, values) in values.chunks_exact(new_rows * self.row_len).enumerate() {
+            for (i, row) in values.chunks_exact(self.row_len).enumerate() {
+                let r = slice * self.capacity + self.rows + i;
+                let data = &mut self.data[r * self.row_bytes..(r + 1) * self.row_bytes];
+                let scales = &mut self.scales[r * self.row_groups..(r + 1) * self.row_groups];
+                for (g, (group, scale)) in row.chunks(self.group_size).zip(scales).enumerate() {
+                    *scale = group.iter().fold(0_f32, |m, v| m.max(v.abs())) / max_int;
+                    let inv = if *scale == 0. { 0. } else { 1. / *scale };
+                    for (j, v) in group.iter().enumerate() {
+                        let q = (v * inv).round().clamp(-max_int, max_int) as i8;
+                        let col = g * self.group_size + j;
+                        if self.bits == 8 {
+                            data[col] = q as u8;
+                        } else {
+                            // Pack two offset nibbles per byte, low nibble first
+                            data[col / 2] |= ((q + 8) as u8) << (4 * (col % 2));
+                        }
+                    }
+                }
+            }
+        }
+        self.rows += new_rows;
+    }
+
+    /// Make room for `capacity` rows in each slice
+    fn grow(&mut self, capacity: usize) {
+        let mut data = vec![0; self.slices * capacity * self.row_bytes];
+        let mut scales = vec![0.; self.slices * capacity * self.row_groups];
+        for slice in 0..self.slices {
+            let (old, new) = (slice * self.capacity, slice * capacity);
+            data[new * self.row_bytes..(new + self.rows) * self.row_bytes].copy_from_slice(
+                &self.data[old * self.row_bytes..(old + self.rows) * self.row_bytes],
+            );
+            scales[new * self.row_groups..(new + self.rows) * self.row_groups].copy_from_slice(
+                &self.scales[old * self.row_groups..(old + self.rows) * self.row_groups],
+            );
+        }
+        (self.data, self.scales, self.capacity) = (data, scales, capacity);
+    }
+
+    /// Number of bits each value is stored in
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Number of consecutive values in a row sharing a scale
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+
+    /// Number of values in each row
+    pub fn row_len(&self) -> usize {
+        self.row_len
+    }
+
+    /// Number of runs of rows
+    pub fn slices(&self) -> usize {
+        self.slices
+    }
+
+    /// Number of rows in each slice
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn len(&self) -> usize {
+        self.slices * self.rows * self.row_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the value at an index
+    pub fn get(&self, index: usize) -> f32 {
+        let (row, col) = (index / self.row_len, index % self.row_len);
+        let row = row / self.rows * self.capacity + row % self.rows;
+        let byte = self.data[row * self.row_bytes + col * self.bits as usize / 8];
+        let q = if self.bits == 8 {
+            byte as i8
+        } else {
+            ((byte >> (4 * (col % 2))) & 0xF) as i8 - 8
+        };
+        q as f32 * self.scales[row * self.row_groups + col / self.group_size]
+    }
+
+    /// Convert back to f32s
+    pub fn dequantize(&self) -> Vec<f32> {
+        (0..self.len()).map(|i| self.get(i)).collect()
+    }
+}
+
+impl Data for QuantizedVec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.data.len() + self.scales.len() * std::mem::size_of::<f32>()
+    }
+}
+
+/// The element type of tensor data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+    /// Integer indexes, like token ids
+    U32,
+    /// Masks of 0s and 1s, like the results of comparisons. These are stored as f32 data, so any op can read them.
+    Bool,
+}
+
+impl DType {
+    /// Number of bytes each element takes
+    pub fn size(&self) -> usize {
+        match self {
+            DType::F32 | DType::U32 | DType::Bool => 4,
+            DType::F16 | DType::Bf16 => 2,
+        }
+    }
+
+    /// The dtype of the result of combining data of two dtypes. Half precision wins, so f32 constants don't upcast
+    /// half precision tensors. Indexes and masks are promoted to f32 by any arithmetic on them.
+    pub fn combine(self, other: DType) -> DType {
+        let promote = |dtype| {
+            if dtype == DType::U32 || dtype == DType::Bool {
+                DType::F32
+            } else {
+                dtype
+            }
+        };
+        let (a, b) = (promote(self), promote(other));
+        if a == DType::F32 {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+/// Half precision (f16 or bf16) data, stored as raw 16 bit values.
+///
+/// Primitive ops read the 16 bit values directly. Ops without a half precision kernel read it through
+/// `Tensor::f32_data`, which converts it into a buffer that only lives as long as the op.
+#[derive(Debug, Clone)]
+pub struct HalfVec {
+    bits: Vec<u16>,
+    dtype: DType,
+}
+
+impl HalfVec {
+    /// Round f32 values to a half precision dtype
+    pub fn new(values: &[f32], dtype: DType) -> Self {
+        let bits = match dtype {
+            DType::F16 => values.iter().map(|v| f16::from_f32(*v).to_bits()).collect(),
+            DType::Bf16 => values
+                .iter()
+                .map(|v| bf16::from_f32(*v).to_bits())
+                .collect(),
+            DType::F32 | DType::U32 | DType::Bool => {
+                panic!("HalfVec can only hold f16 or bf16 data")
+            }
+        };
+        Self { bits, dtype }
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Get the value at an index
+    pub fn get(&self, index: usize) -> f32 {
+        half_to_f32(self.bits[index], self.dtype)
+    }
+
+    /// Convert to f32s
+    pub fn to_f32(&self) -> Vec<f32> {
+        (0..self.len()).map(|i| self.get(i)).collect()
+    }
+}
+
+impl From<Vec<f16>> for HalfVec {
+    fn from(values: Vec<f16>) -> Self {
+        Self {
+            bits: values.into_iter().map(|v| v.to_bits()).collect(),
+            dtype: DType::F16,
+        }
+    }
+}
+
+impl From<Vec<bf16>> for HalfVec {
+    fn from(values: Vec<bf16>) -> Self {
+        Self {
+            bits: values.into_iter().map(|v| v.to_bits()).collect(),
+            dtype: DType::Bf16,
+        }
+    }
+}
+
+impl Data for HalfVec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u16>()
+    }
+}
+
+/// Integer indexes, like token ids, used by `Gather`. Ops read them directly instead of going through floats, so large
+/// indexes stay exact.
+///
+/// Reads as a `Vec<f32>` through `Tensor::downcast_ref`, which converts it once on first read, so ops without an
+/// index kernel still work on it.
+#[derive(Debug, Clone)]
+pub struct IndexVec {
+    indexes: Vec<u32>,
+    upcast: OnceLock<Vec<f32>>,
+}
+
+impl IndexVec {
+    pub fn new(indexes: Vec<u32>) -> Self {
+        Self {
+            indexes,
+            upcast: OnceLock::new(),
+        }
+    }
+
+    pub fn indexes(&self) -> &[u32] {
+        &self.indexes
+    }
+
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Convert to f32s
+    pub fn to_f32(&self) -> Vec<f32> {
+        self.indexes.iter().map(|i| *i as f32).collect()
+    }
+
+    /// The f32 values, computed on first access and kept for as long as this data is alive
+    pub fn upcast(&self) -> &Vec<f32> {
+        self.upcast.get_or_init(|| self.to_f32())
+    }
+}
+
+impl From<Vec<u32>> for IndexVec {
+    fn from(indexes: Vec<u32>) -> Self {
+        Self::new(indexes)
+    }
+}
+
+impl Data for IndexVec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> usize {
+        self.indexes.len() * std::mem::size_of::<u32>()
+            + self
+                .upcast
+                .get()
+                .map(|d| d.len() * std::mem::size_of::<f32>())
+                .unwrap_or_default()
+    }
+}
+
+fn half_to_f32(bits: u16, dtype: DType) -> f32 {
+    if dtype == DType::F16 {
+        f16::from_bits(bits).to_f32()
+    } else {
+        bf16::from_bits(bits).to_f32()
+    }
 }
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -64,13 +573,72 @@ impl<'a> InputTensor<'a> {
 
     /// Unwrap or clone the tensor, depending on if it's owned or not
     pub fn cloned(self) -> Tensor {
-        match self {
-            InputTensor::Owned(t) => t,
-            InputTensor::Borrowed(t) => t.clone(),
+        // Moving the tensor out means it isn't dropped here, so it never gets recycled
+        let this = ManuallyDrop::new(self);
+        match &*this {
+            InputTensor::Owned(t) => unsafe { std::ptr::read(t) },
+            InputTensor::Borrowed(t) => (*t).clone(),
+        }
+    }
+}
+
+impl Drop for InputTensor<'_> {
+    fn drop(&mut self) {
+        // Hand f32 buffers back to the memory plan instead of freeing them
+        if let InputTensor::Owned(t) = self {
+            if let Some(v) = t.data.as_any_mut().downcast_mut::<Vec<f32>>() {
+                let _ = PLANNED_BUFFERS.try_with(|b| {
+                    let mut b = b.borrow_mut();
+                    if b.active {
+                        b.recycled.push(std::mem::take(v));
+                    }
+                });
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Buffers passed between the executor and the op it's running when the graph has a memory plan
+    static PLANNED_BUFFERS: RefCell<PlannedBuffers> = RefCell::default();
+}
+
+#[derive(Default)]
+struct PlannedBuffers {
+    active: bool,
+    output: Option<Vec<f32>>,
+    recycled: Vec<Vec<f32>>,
+}
+
+/// Allocate a zeroed f32 output for an op, reusing the buffer the memory planner assigned to the op if there is one
+pub fn output_buffer(len: usize) -> Vec<f32> {
+    match PLANNED_BUFFERS.with_borrow_mut(|b| b.output.take()) {
+        Some(mut buffer) => {
+            buffer.clear();
+            buffer.resize(len, 0.);
+            buffer
         }
+        None => vec![0.; len],
     }
 }
 
+/// Start running an op with a memory plan, offering it a buffer to write its output into
+pub(crate) fn begin_planned_op(output: Option<Vec<f32>>) {
+    PLANNED_BUFFERS.with_borrow_mut(|b| {
+        b.active = true;
+        b.output = output;
+    });
+}
+
+/// Finish running an op with a memory plan, getting back the output buffer if it went unused, and the buffers of any
+/// inputs the op dropped
+pub(crate) fn end_planned_op() -> (Option<Vec<f32>>, Vec<Vec<f32>>) {
+    PLANNED_BUFFERS.with_borrow_mut(|b| {
+        b.active = false;
+        (b.output.take(), std::mem::take(&mut b.recycled))
+    })
+}
+
 /// The main operator trait.
 ///
 /// Defines an operator that takes in a vector of input tensors and shapes and produces a vector of output tensors
@@ -150,13 +718,13 @@ impl Operator for Contiguous {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Copy data over to new tensor
         let inp_data = get_vec(&inp[0].0);
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
     }
 }
 
@@ -164,14 +732,14 @@ impl Operator for Contiguous {
 pub struct Log2;
 impl Operator for Log2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i).log2();
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
     }
 }
 
@@ -179,14 +747,14 @@ impl Operator for Log2 {
 pub struct Exp2;
 impl Operator for Exp2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i).exp2();
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
     }
 }
 
@@ -194,14 +762,14 @@ impl Operator for Exp2 {
 pub struct Sin;
 impl Operator for Sin {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i).sin();
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
     }
 }
 
@@ -209,14 +777,14 @@ impl Operator for Sin {
 pub struct Recip;
 impl Operator for Recip {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i).recip();
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
     }
 }
 
@@ -224,14 +792,46 @@ impl Operator for Recip {
 pub struct Sqrt;
 impl Operator for Sqrt {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(inp_data, &expr, &mut stack, i).sqrt();
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, inp_data.dtype())]
+    }
+}
+
+/// The Gauss error function
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Erf;
+impl Operator for Erf {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = libm::erff(get_index(inp_data, &expr, &mut stack, i));
+        }
+        vec![to_tensor(out_data, inp_data.dtype())]
+    }
+}
+
+/// Convert a tensor to another dtype
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cast(pub DType);
+impl Operator for Cast {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = get_index(inp_data, &expr, &mut stack, i);
+        }
+        vec![to_tensor(out_data, self.0)]
     }
 }
 
@@ -245,11 +845,11 @@ impl Operator for Add {
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(lhs, &lexpr, &mut stack, i) + get_index(rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, lhs.dtype().combine(rhs.dtype()))]
     }
 }
 
@@ -258,14 +858,14 @@ pub struct Mul;
 impl Operator for Mul {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(lhs, &lexpr, &mut stack, i) * get_index(rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, lhs.dtype().combine(rhs.dtype()))]
     }
 }
 
@@ -274,14 +874,14 @@ pub struct Mod;
 impl Operator for Mod {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
             *out = get_index(lhs, &lexpr, &mut stack, i) % get_index(rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, lhs.dtype().combine(rhs.dtype()))]
     }
 }
 
@@ -290,7 +890,7 @@ pub struct LessThan;
 impl Operator for LessThan {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
@@ -298,7 +898,135 @@ impl Operator for LessThan {
             *out = (get_index(lhs, &lexpr, &mut stack, i) < get_index(rhs, &rexpr, &mut stack, i))
                 as i32 as f32;
         }
-        vec![Tensor::new(out_data)]
+        vec![to_tensor(out_data, lhs.dtype().combine(rhs.dtype()))]
+    }
+}
+
+// Ternary Ops (A x A x A -> A)
+
+/// Pick elements from the second input where the first input (a mask) is nonzero, and from the third everywhere else
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Where;
+impl Operator for Where {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (cond, lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0), get_vec(&inp[2].0));
+        let cexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let lexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let rexpr = (inp[2].1.index_expression(), inp[2].1.valid_expression());
+        let mut stack = vec![];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        for (i, out) in out_data.iter_mut().enumerate() {
+            *out = if get_index(cond, &cexpr, &mut stack, i) != 0. {
+                get_index(lhs, &lexpr, &mut stack, i)
+            } else {
+                get_index(rhs, &rexpr, &mut stack, i)
+            };
+        }
+        vec![to_tensor(out_data, lhs.dtype().combine(rhs.dtype()))]
+    }
+}
+
+// Fused Ops
+
+/// A step in a `FusedElementwise` program. Arguments are indexes of earlier instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElementwiseInstruction {
+    /// Read the element from an input
+    Input(usize),
+    Constant(f32),
+    Log2(usize),
+    Exp2(usize),
+    Sin(usize),
+    Recip(usize),
+    Sqrt(usize),
+    Add(usize, usize),
+    Mul(usize, usize),
+    Mod(usize, usize),
+    LessThan(usize, usize),
+}
+
+/// A chain of elementwise ops computed in one pass, without writing intermediates to memory. The output is the last
+/// instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedElementwise(pub Vec<ElementwiseInstruction>);
+
+impl FusedElementwise {
+    fn eval(&self, ins: usize, inputs: &[FusedInput], stack: &mut Vec<i64>, index: usize) -> f32 {
+        use ElementwiseInstruction::*;
+        let mut eval = |a| self.eval(a, inputs, stack, index);
+        match self.0[ins] {
+            Input(i) => match &inputs[i].1 {
+                Some(expr) => get_index(inputs[i].0, expr, stack, index),
+                None => inputs[i].0.get(index),
+            },
+            Constant(c) => c,
+            Log2(a) => eval(a).log2(),
+            Exp2(a) => eval(a).exp2(),
+            Sin(a) => eval(a).sin(),
+            Recip(a) => eval(a).recip(),
+            Sqrt(a) => eval(a).sqrt(),
+            Add(a, b) => eval(a) + eval(b),
+            Mul(a, b) => eval(a) * eval(b),
+            Mod(a, b) => eval(a) % eval(b),
+            LessThan(a, b) => (eval(a) < eval(b)) as i32 as f32,
+        }
+    }
+
+    /// Render the program as a single C-style expression, like the ones Metal and CUDA kernels use, given how to read
+    /// each input
+    pub fn expression(&self, input: impl Fn(usize) -> String) -> String {
+        fn render(
+            ins: &[ElementwiseInstruction],
+            i: usize,
+            input: &dyn Fn(usize) -> String,
+        ) -> String {
+            use ElementwiseInstruction::*;
+            let r = |a| render(ins, a, input);
+            match ins[i] {
+                Input(i) => input(i),
+                Constant(c) if c.is_nan() => "NAN".to_string(),
+                Constant(c) if c.is_infinite() => {
+                    format!("{}INFINITY", if c < 0. { "-" } else { "" })
+                }
+                Constant(c) => format!("{c:?}"),
+                Log2(a) => format!("log2({})", r(a)),
+                Exp2(a) => format!("exp2({})", r(a)),
+                Sin(a) => format!("sin({})", r(a)),
+                Recip(a) => format!("(1.0 / {})", r(a)),
+                Sqrt(a) => format!("sqrt({})", r(a)),
+                Add(a, b) => format!("({} + {})", r(a), r(b)),
+                Mul(a, b) => format!("({} * {})", r(a), r(b)),
+                Mod(a, b) => format!("fmod({}, {})", r(a), r(b)),
+                LessThan(a, b) => format!("(float)({} < {})", r(a), r(b)),
+            }
+        }
+        render(&self.0, self.0.len() - 1, &input)
+    }
+}
+
+/// An input to a fused op, with its index expressions if it isn't read in order
+type FusedInput<'a> = (Values<'a>, Option<(BigExpression, BigExpression)>);
+
+impl Operator for FusedElementwise {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inputs = inp
+            .iter()
+            .map(|(t, sh)| {
+                let expr = sh
+                    .is_reshaped()
+                    .then(|| (sh.index_expression(), sh.valid_expression()));
+                (get_vec(t), expr)
+            })
+            .collect::<Vec<_>>();
+        let dtype = inputs
+            .iter()
+            .fold(DType::F32, |acc, (v, _)| acc.combine(v.dtype()));
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        let root = self.0.len() - 1;
+        fill_reduced(&mut out_data, self.0.len(), |i, stack| {
+            self.eval(root, &inputs, stack, i)
+        });
+        vec![to_tensor(out_data, dtype)]
     }
 }
 
@@ -312,19 +1040,21 @@ impl Operator for SumReduce {
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
         let dim_size = sh[self.0];
-        let mut result = vec![0.0; front_size * back_size];
+        let mut result = output_buffer(front_size * back_size);
         let input = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for i in 0..front_size {
-            for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    result[i * back_size + j] += get_index(input, &expr, &mut stack, orig_index);
-                }
-            }
-        }
-        vec![Tensor::new(result)]
+        fill_reduced(&mut result, dim_size, |out, stack| {
+            let (i, j) = (out / back_size, out % back_size);
+            (0..dim_size).fold(0.0, |acc, k| {
+                acc + get_index(
+                    input,
+                    &expr,
+                    stack,
+                    i * dim_size * back_size + k * back_size + j,
+                )
+            })
+        });
+        vec![to_tensor(result, input.dtype())]
     }
 }
 
@@ -336,37 +1066,1643 @@ impl Operator for MaxReduce {
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
         let dim_size = sh[self.0];
-        let mut result = vec![-f32::INFINITY; front_size * back_size];
+        let mut result = output_buffer(front_size * back_size);
+        let input = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        fill_reduced(&mut result, dim_size, |out, stack| {
+            let (i, j) = (out / back_size, out % back_size);
+            (0..dim_size).fold(-f32::INFINITY, |acc, k| {
+                acc.max(get_index(
+                    input,
+                    &expr,
+                    stack,
+                    i * dim_size * back_size + k * back_size + j,
+                ))
+            })
+        });
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProdReduce(pub usize);
+impl Operator for ProdReduce {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.0];
+        let mut result = output_buffer(front_size * back_size);
         let input = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        fill_reduced(&mut result, dim_size, |out, stack| {
+            let (i, j) = (out / back_size, out % back_size);
+            (0..dim_size).fold(1.0, |acc, k| {
+                acc * get_index(
+                    input,
+                    &expr,
+                    stack,
+                    i * dim_size * back_size + k * back_size + j,
+                )
+            })
+        });
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+// Selection Ops (A -> B (dim shrunk to k))
+
+/// The `k` largest values along a dimension, largest first. Ties go to the element with the lower index. See
+/// `ArgTopK` for their indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopK {
+    pub k: usize,
+    pub dim: usize,
+}
+impl Operator for TopK {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let input = get_vec(&inp[0].0);
+        let values = top_k(&inp[0], self.k, self.dim, |row, i| row[i]);
+        vec![to_tensor(values, input.dtype())]
+    }
+}
+
+/// The indexes of the `k` largest values along a dimension, in the same order as `TopK`, as `IndexVec` data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgTopK {
+    pub k: usize,
+    pub dim: usize,
+}
+impl Operator for ArgTopK {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let indexes = top_k(&inp[0], self.k, self.dim, |_, i| i as f32);
+        vec![to_tensor(indexes, DType::U32)]
+    }
+}
+
+/// Run `top_k_indexes` over each row along `dim`, writing out `f(row, index)` for each selected index
+fn top_k(
+    (input, shape): &(InputTensor, ShapeTracker),
+    k: usize,
+    dim: usize,
+    f: impl Fn(&[f32], usize) -> f32 + Sync,
+) -> Vec<f32> {
+    let sh = shape.shape_usize();
+    let dim_size = sh[dim];
+    assert!(
+        k <= dim_size,
+        "Can't take the top {k} of a dimension of size {dim_size}"
+    );
+    let front_size = sh.iter().take(dim).product::<usize>();
+    let back_size = sh.iter().skip(dim + 1).product::<usize>();
+    let data = get_vec(input);
+    let expr = (shape.index_expression(), shape.valid_expression());
+    let select = |r: usize| {
+        let (i, j) = (r / back_size, r % back_size);
         let mut stack = vec![];
+        let row = (0..dim_size)
+            .map(|n| get_index(data, &expr, &mut stack, (i * dim_size + n) * back_size + j))
+            .collect::<Vec<_>>();
+        top_k_indexes(&row, k)
+            .into_iter()
+            .map(|n| f(&row, n))
+            .collect::<Vec<_>>()
+    };
+    let rows = front_size * back_size;
+    let selected = if rows * dim_size >= PARALLEL_THRESHOLD {
+        (0..rows).into_par_iter().map(select).collect::<Vec<_>>()
+    } else {
+        (0..rows).map(select).collect()
+    };
+    let mut result = output_buffer(rows * k);
+    for (r, row) in selected.into_iter().enumerate() {
+        let (i, j) = (r / back_size, r % back_size);
+        for (n, v) in row.into_iter().enumerate() {
+            result[(i * k + n) * back_size + j] = v;
+        }
+    }
+    result
+}
+
+// Scan Ops (A -> A)
+
+/// Cumulative sum along a dimension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CumSum(pub usize);
+impl Operator for CumSum {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let input = get_vec(&inp[0].0);
+        let result = scan(&inp[0], self.0, |acc, x| acc + x);
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+/// Cumulative product along a dimension. Unlike `cumprod_last_dim`, this handles zeros and negative values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CumProd(pub usize);
+impl Operator for CumProd {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let input = get_vec(&inp[0].0);
+        let result = scan(&inp[0], self.0, |acc, x| acc * x);
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+/// Inclusive scan along `dim`, combining each element with the running value before it
+fn scan(
+    (input, shape): &(InputTensor, ShapeTracker),
+    dim: usize,
+    f: impl Fn(f32, f32) -> f32 + Sync,
+) -> Vec<f32> {
+    let sh = shape.shape_usize();
+    let dim_size = sh[dim];
+    let back_size = sh.iter().skip(dim + 1).product::<usize>();
+    let mut result = output_buffer(sh.iter().product());
+    let block_size = dim_size * back_size;
+    if block_size == 0 {
+        return result;
+    }
+    let data = get_vec(input);
+    let expr = (shape.index_expression(), shape.valid_expression());
+    // Each block of the dimensions in front of `dim` scans independently
+    let scan_block = |(i, block): (usize, &mut [f32])| {
+        let mut stack = vec![];
+        for k in 0..dim_size {
+            for j in 0..back_size {
+                let x = get_index(data, &expr, &mut stack, i * block_size + k * back_size + j);
+                block[k * back_size + j] = if k == 0 {
+                    x
+                } else {
+                    f(block[(k - 1) * back_size + j], x)
+                };
+            }
+        }
+    };
+    if result.len() >= PARALLEL_THRESHOLD {
+        result
+            .par_chunks_mut(block_size)
+            .enumerate()
+            .for_each(scan_block);
+    } else {
+        result
+            .chunks_mut(block_size)
+            .enumerate()
+            .for_each(scan_block);
+    }
+    result
+}
+
+/// Linear recurrence along a dimension, `h_k = a_k * h_(k-1) + b_k` starting from a zero state, with the decays `a` as
+/// the first input and `b` as the second. This is the selective scan of state space models like Mamba. With `reverse`,
+/// the recurrence runs from the end of the dimension instead, `h_k = a_k * h_(k+1) + b_k`.
+///
+/// Composing steps `h -> a h + b` is associative, so when there are fewer lanes than threads, long sequences are split
+/// into chunks. Each chunk first scans from a zero state to find its final state and total decay, the state entering
+/// each chunk is carried through the ones before it, then every chunk scans again in parallel from its entering state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectiveScan {
+    pub dim: usize,
+    pub reverse: bool,
+}
+impl Operator for SelectiveScan {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let input = get_vec(&inp[1].0);
+        let result = selective_scan(&inp[0], &inp[1], self.dim, self.reverse);
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+fn selective_scan(
+    (a, a_shape): &(InputTensor, ShapeTracker),
+    (b, b_shape): &(InputTensor, ShapeTracker),
+    dim: usize,
+    reverse: bool,
+) -> Vec<f32> {
+    let sh = a_shape.shape_usize();
+    let dim_size = sh[dim];
+    let back_size = sh.iter().skip(dim + 1).product::<usize>();
+    let mut result = output_buffer(sh.iter().product());
+    let block_size = dim_size * back_size;
+    if block_size == 0 {
+        return result;
+    }
+    let n_blocks = result.len() / block_size;
+    let (a_data, b_data) = (get_vec(a), get_vec(b));
+    let a_expr = (a_shape.index_expression(), a_shape.valid_expression());
+    let b_expr = (b_shape.index_expression(), b_shape.valid_expression());
+    let parallel = result.len() >= PARALLEL_THRESHOLD;
+    let n_chunks = if parallel {
+        (rayon::current_num_threads() / n_blocks).clamp(1, dim_size)
+    } else {
+        1
+    };
+    let chunk_len = dim_size.div_ceil(n_chunks);
+    let n_chunks = dim_size.div_ceil(chunk_len);
 
-        for i in 0..front_size {
+    // Scan the steps of chunk `c` of block `i` from `state`, tracking the product of the decays and writing each state.
+    // Reversed scans count steps and chunks from the end of the dimension.
+    let scan_chunk = |i: usize,
+                      c: usize,
+                      state: &mut [f32],
+                      mut decay: Option<&mut [f32]>,
+                      mut out: Option<&mut [f32]>| {
+        let (mut a_stack, mut b_stack) = (vec![], vec![]);
+        let (start, end) = (c * chunk_len, ((c + 1) * chunk_len).min(dim_size));
+        for step in start..end {
+            let (k, row) = if reverse {
+                (dim_size - 1 - step, end - 1 - step)
+            } else {
+                (step, step - start)
+            };
             for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    let new_index = i * back_size + j;
-                    result[new_index] =
-                        result[new_index].max(get_index(input, &expr, &mut stack, orig_index));
+                let index = i * block_size + k * back_size + j;
+                let a = get_index(a_data, &a_expr, &mut a_stack, index);
+                let b = get_index(b_data, &b_expr, &mut b_stack, index);
+                state[j] = a * state[j] + b;
+                if let Some(decay) = decay.as_deref_mut() {
+                    decay[j] *= a;
+                }
+                if let Some(out) = out.as_deref_mut() {
+                    out[row * back_size + j] = state[j];
+                }
+            }
+        }
+    };
+
+    // The state entering each chunk
+    let mut entering = vec![0.; n_blocks * n_chunks * back_size];
+    if n_chunks > 1 {
+        let summaries = (0..n_blocks * n_chunks)
+            .into_par_iter()
+            .map(|ic| {
+                let (mut state, mut decay) = (vec![0.; back_size], vec![1.; back_size]);
+                scan_chunk(
+                    ic / n_chunks,
+                    ic % n_chunks,
+                    &mut state,
+                    Some(&mut decay),
+                    None,
+                );
+                (state, decay)
+            })
+            .collect::<Vec<_>>();
+        for i in 0..n_blocks {
+            for c in 1..n_chunks {
+                let prev = i * n_chunks + c - 1;
+                let (state, decay) = &summaries[prev];
+                for j in 0..back_size {
+                    entering[(prev + 1) * back_size + j] =
+                        decay[j] * entering[prev * back_size + j] + state[j];
+                }
+            }
+        }
+    }
+    let scan_into = |i: usize, c: usize, out: &mut [f32]| {
+        let start = (i * n_chunks + c) * back_size;
+        let mut state = entering[start..start + back_size].to_vec();
+        scan_chunk(i, c, &mut state, None, Some(out));
+    };
+    if parallel {
+        result
+            .par_chunks_mut(block_size)
+            .enumerate()
+            .for_each(|(i, block)| {
+                if reverse {
+                    block
+                        .par_rchunks_mut(chunk_len * back_size)
+                        .enumerate()
+                        .for_each(|(c, out)| scan_into(i, c, out))
+                } else {
+                    block
+                        .par_chunks_mut(chunk_len * back_size)
+                        .enumerate()
+                        .for_each(|(c, out)| scan_into(i, c, out))
+                }
+            });
+    } else {
+        for (i, block) in result.chunks_mut(block_size).enumerate() {
+            if reverse {
+                for (c, out) in block.rchunks_mut(chunk_len * back_size).enumerate() {
+                    scan_into(i, c, out);
+                }
+            } else {
+                for (c, out) in block.chunks_mut(chunk_len * back_size).enumerate() {
+                    scan_into(i, c, out);
+                }
+            }
+        }
+    }
+    result
+}
+
+// Normalization Ops (A -> A)
+
+/// Layer norm along the last dimension: each row is centered on its mean and scaled by `1 / sqrt(variance + epsilon)`.
+/// This is `layer_norm` over the last axis done in one pass over each row, rather than as a chain of reductions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FusedLayerNorm(pub f32);
+impl Operator for FusedLayerNorm {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let dim_size = sh.last().copied().unwrap_or(1);
+        let input = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut result = output_buffer(sh.iter().product());
+        if dim_size == 0 {
+            return vec![to_tensor(result, input.dtype())];
+        }
+        // Each row reads its elements once, normalizing in place
+        let normalize_row = |(r, row): (usize, &mut [f32])| {
+            let mut stack = vec![];
+            for (k, out) in row.iter_mut().enumerate() {
+                *out = get_index(input, &expr, &mut stack, r * dim_size + k);
+            }
+            let mean = row.iter().sum::<f32>() / dim_size as f32;
+            let var = row.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / dim_size as f32;
+            let scale = (var + self.0).sqrt().recip();
+            for out in row.iter_mut() {
+                *out = (*out - mean) * scale;
+            }
+        };
+        if result.len() >= PARALLEL_THRESHOLD {
+            result
+                .par_chunks_mut(dim_size)
+                .enumerate()
+                .for_each(normalize_row);
+        } else {
+            result
+                .chunks_mut(dim_size)
+                .enumerate()
+                .for_each(normalize_row);
+        }
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+/// Softmax along a dimension. Each lane is shifted by its max before exponentiating so large inputs don't overflow,
+/// and the max, exponentials, sum and division all happen in one pass over the lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Softmax(pub usize);
+impl Operator for Softmax {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        softmax(&inp[0], self.0, false)
+    }
+}
+
+/// Log softmax along a dimension, computed stably as `x - max - ln(sum(exp(x - max)))` in one pass over each lane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogSoftmax(pub usize);
+impl Operator for LogSoftmax {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        softmax(&inp[0], self.0, true)
+    }
+}
+
+/// Softmax or log softmax of each lane along `dim`
+fn softmax(inp: &(InputTensor, ShapeTracker), dim: usize, log: bool) -> Vec<Tensor> {
+    let sh = inp.1.shape_usize();
+    let dim_size = sh[dim];
+    let back_size = sh.iter().skip(dim + 1).product::<usize>();
+    let block_size = dim_size * back_size;
+    let input = get_vec(&inp.0);
+    let expr = (inp.1.index_expression(), inp.1.valid_expression());
+    let mut result = output_buffer(sh.iter().product());
+    if block_size == 0 {
+        return vec![to_tensor(result, input.dtype())];
+    }
+    let softmax_block = |(i, block): (usize, &mut [f32])| {
+        let mut stack = vec![];
+        for (k, out) in block.iter_mut().enumerate() {
+            *out = get_index(input, &expr, &mut stack, i * block_size + k);
+        }
+        for j in 0..back_size {
+            let lane = (0..dim_size).map(|k| k * back_size + j);
+            let max = lane
+                .clone()
+                .map(|k| block[k])
+                .fold(f32::NEG_INFINITY, f32::max);
+            let sum = lane.clone().map(|k| (block[k] - max).exp()).sum::<f32>();
+            let log_sum = sum.ln();
+            for k in lane {
+                block[k] = if log {
+                    block[k] - max - log_sum
+                } else {
+                    (block[k] - max).exp() / sum
+                };
+            }
+        }
+    };
+    if result.len() >= PARALLEL_THRESHOLD {
+        result
+            .par_chunks_mut(block_size)
+            .enumerate()
+            .for_each(softmax_block);
+    } else {
+        result
+            .chunks_mut(block_size)
+            .enumerate()
+            .for_each(softmax_block);
+    }
+    vec![to_tensor(result, input.dtype())]
+}
+
+// Loss Ops (A x B -> 1)
+
+/// Mean cross entropy between logits with classes along the last dimension and integer class indexes, one per row.
+/// Each row's loss is `-(1 - s) * log_softmax(x)[target] - s / C * sum(log_softmax(x))` for label smoothing `s`
+/// over `C` classes, computed stably without materializing the log softmax.
+///
+/// Rows whose target is `ignore_index` don't count towards the loss or the mean. If every row is ignored the loss is
+/// NaN, like PyTorch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrossEntropy {
+    pub label_smoothing: f32,
+    pub ignore_index: Option<usize>,
+}
+impl Operator for CrossEntropy {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let classes = sh.last().copied().unwrap_or(1);
+        let rows = sh.iter().rev().skip(1).product::<usize>();
+        let (logits, targets) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let (ind, val) = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let target = |r, stack: &mut Vec<i64>| {
+            if val.exec_single_var_stack(r, stack) != 0 {
+                targets.get_usize(ind.exec_single_var_stack(r, stack))
+            } else {
+                0
+            }
+        };
+        let mut losses = output_buffer(rows);
+        fill_reduced(&mut losses, classes, |r, stack| {
+            let target = target(r, stack);
+            if self.ignore_index == Some(target) {
+                return 0.;
+            }
+            assert!(
+                target < classes,
+                "Cross entropy target {target} is out of bounds for {classes} classes"
+            );
+            let row = (0..classes)
+                .map(|c| get_index(logits, &expr, stack, r * classes + c))
+                .collect::<Vec<_>>();
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let log_sum = row.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+            let target_loss = log_sum - row[target];
+            let mean_loss = log_sum - row.iter().sum::<f32>() / classes as f32;
+            (1. - self.label_smoothing) * target_loss + self.label_smoothing * mean_loss
+        });
+        let mut stack = vec![];
+        let counted = (0..rows)
+            .filter(|r| self.ignore_index != Some(target(*r, &mut stack)))
+            .count();
+        let total = losses.iter().sum::<f32>();
+        vec![to_tensor(vec![total / counted as f32], logits.dtype())]
+    }
+}
+
+// Position Ops (A x Freqs -> A)
+
+/// Rotary position embeddings for a (..., Seq, D) tensor, with the inverse frequency of each pair of dimensions (D / 2)
+/// as a second input. Each interleaved pair (x0, x1) at position `p` along Seq is rotated by `p * freq`, and the
+/// result is multiplied by a scale. Positions start at a runtime offset, usually the number of cached tokens.
+#[derive(Clone, PartialEq)]
+pub struct Rope(
+    pub BigExpression,
+    pub f32,
+    pub *const FxHashMap<char, usize>,
+);
+impl Debug for Rope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rope({:?}, {})", self.0, self.1)
+    }
+}
+
+impl Operator for Rope {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let (seq, d) = (sh[sh.len() - 2], sh[sh.len() - 1]);
+        let offset = self.0.exec(unsafe { self.2.as_ref().unwrap() }).unwrap();
+        let scale = self.1;
+        let (freqs_data, freqs_expr) = (
+            get_vec(&inp[1].0),
+            (inp[1].1.index_expression(), inp[1].1.valid_expression()),
+        );
+        let mut stack = vec![];
+        let freqs = (0..d / 2)
+            .map(|i| get_index(freqs_data, &freqs_expr, &mut stack, i))
+            .collect::<Vec<_>>();
+        let (data, expr) = (
+            get_vec(&inp[0].0),
+            (inp[0].1.index_expression(), inp[0].1.valid_expression()),
+        );
+        let mut result = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        if d == 0 {
+            return vec![to_tensor(result, data.dtype())];
+        }
+        let rotate_row = |(r, out): (usize, &mut [f32])| {
+            let mut stack = vec![];
+            let pos = (offset + r % seq) as f32;
+            for (c, pair) in out.chunks_exact_mut(2).enumerate() {
+                let (sin, cos) = (pos * freqs[c]).sin_cos();
+                let x0 = get_index(data, &expr, &mut stack, r * d + 2 * c);
+                let x1 = get_index(data, &expr, &mut stack, r * d + 2 * c + 1);
+                pair[0] = (x0 * cos - x1 * sin) * scale;
+                pair[1] = (x0 * sin + x1 * cos) * scale;
+            }
+        };
+        if result.len() >= PARALLEL_THRESHOLD {
+            result.par_chunks_mut(d).enumerate().for_each(rotate_row);
+        } else {
+            result.chunks_mut(d).enumerate().for_each(rotate_row);
+        }
+        vec![to_tensor(result, data.dtype())]
+    }
+}
+
+// Attention Ops (Q x K x V (x Mask) -> O)
+
+/// Scaled dot product attention over the last two dimensions, `softmax(q k^T * scale + mask) v`, for queries
+/// (..., Sq, D), keys (..., Sk, D), values (..., Sk, Dv) and an optional additive mask (..., Sq, Sk) as a fourth input.
+/// The leading dimensions are batch dimensions. The scale defaults to `1 / sqrt(D)`.
+///
+/// Keys are visited in tiles with an online softmax, keeping only a running max, sum and output per query, so the
+/// Sq x Sk score matrix is never materialized. Backends can swap this for a single tiled kernel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaledDotProductAttention(pub Option<f32>);
+
+/// How many keys are scored at once when attending
+const ATTENTION_TILE: usize = 64;
+
+impl Operator for ScaledDotProductAttention {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (q_sh, k_sh, v_sh) = (
+            inp[0].1.shape_usize(),
+            inp[1].1.shape_usize(),
+            inp[2].1.shape_usize(),
+        );
+        let (sq, d) = (q_sh[q_sh.len() - 2], q_sh[q_sh.len() - 1]);
+        let (sk, dv) = (k_sh[k_sh.len() - 2], v_sh[v_sh.len() - 1]);
+        let batch = q_sh.iter().rev().skip(2).product::<usize>();
+        let scale = self.0.unwrap_or(1. / (d as f32).sqrt());
+        // Keys and values are read by every query, so they're made contiguous once
+        let contiguous = |(t, sh): &(InputTensor, ShapeTracker)| {
+            let (data, expr) = (get_vec(t), (sh.index_expression(), sh.valid_expression()));
+            let mut out = vec![0.; sh.n_elements().to_usize().unwrap()];
+            fill_reduced(&mut out, 1, |i, stack| get_index(data, &expr, stack, i));
+            out
+        };
+        let (keys, values) = (contiguous(&inp[1]), contiguous(&inp[2]));
+        let queries = get_vec(&inp[0].0);
+        let q_expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mask = inp
+            .get(3)
+            .map(|(t, sh)| (get_vec(t), (sh.index_expression(), sh.valid_expression())));
+
+        let mut result = output_buffer(batch * sq * dv);
+        if dv == 0 {
+            return vec![to_tensor(result, queries.dtype())];
+        }
+        let attend_row = |(r, out): (usize, &mut [f32])| {
+            let mut stack = vec![];
+            let b = r / sq;
+            let query = (0..d)
+                .map(|c| get_index(queries, &q_expr, &mut stack, r * d + c))
+                .collect::<Vec<_>>();
+            let (mut max, mut sum) = (f32::NEG_INFINITY, 0.);
+            let mut scores = [0.; ATTENTION_TILE];
+            out.fill(0.);
+            for start in (0..sk).step_by(ATTENTION_TILE) {
+                let tile = &mut scores[..ATTENTION_TILE.min(sk - start)];
+                for (j, score) in tile.iter_mut().enumerate() {
+                    let key = &keys[(b * sk + start + j) * d..][..d];
+                    *score = query.iter().zip(key).map(|(a, b)| a * b).sum::<f32>() * scale;
+                    if let Some((mask, expr)) = &mask {
+                        *score += get_index(*mask, expr, &mut stack, r * sk + start + j);
+                    }
+                }
+                let new_max = tile.iter().copied().fold(max, f32::max);
+                if new_max == f32::NEG_INFINITY {
+                    // Nothing visible yet
+                    continue;
+                }
+                // Rescale what's been accumulated so far to the new max
+                let correction = (max - new_max).exp();
+                sum *= correction;
+                out.iter_mut().for_each(|o| *o *= correction);
+                for (j, score) in tile.iter().enumerate() {
+                    let weight = (score - new_max).exp();
+                    sum += weight;
+                    let value = &values[(b * sk + start + j) * dv..][..dv];
+                    out.iter_mut()
+                        .zip(value)
+                        .for_each(|(o, v)| *o += weight * v);
+                }
+                max = new_max;
+            }
+            // Fully masked rows are NaN, like a softmax over only -inf
+            out.iter_mut().for_each(|o| *o /= sum);
+        };
+        if result.len() * sk >= PARALLEL_THRESHOLD {
+            result.par_chunks_mut(dv).enumerate().for_each(attend_row);
+        } else {
+            result.chunks_mut(dv).enumerate().for_each(attend_row);
+        }
+        vec![to_tensor(result, queries.dtype())]
+    }
+}
+
+// Gather Ops (A x B -> C)
+
+/// Select slices of a tensor along a dimension by a tensor of indexes. The dimension is replaced by the index
+/// tensor's dimensions, so gathering rows of an (N, D) matrix with (B, S) indexes gives a (B, S, D) tensor.
+///
+/// Indexes are read exactly from `IndexVec` data, and rounded down from floats otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gather(pub usize);
+impl Operator for Gather {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let rows = sh[self.0];
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>();
+        let n_indexes = inp[1].1.n_elements().to_usize().unwrap();
+        let (data, indexes) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        let data_expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let (ind, val) = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+
+        let front_size = sh.iter().take(self.0).product::<usize>();
+        let mut result = output_buffer(front_size * n_indexes * back_size);
+        fill_reduced(&mut result, 1, |i, stack| {
+            let (f, t, j) = (
+                i / (n_indexes * back_size),
+                (i / back_size) % n_indexes,
+                i % back_size,
+            );
+            let index = if val.exec_single_var_stack(t, stack) != 0 {
+                indexes.get_usize(ind.exec_single_var_stack(t, stack))
+            } else {
+                0
+            };
+            assert!(
+                index < rows,
+                "Gather index {index} is out of range for a dimension of size {rows}"
+            );
+            get_index(data, &data_expr, stack, (f * rows + index) * back_size + j)
+        });
+        vec![to_tensor(result, data.dtype())]
+    }
+}
+
+// Scatter Ops (A x B x C -> A)
+
+/// Write each element of a source tensor into a copy of a destination tensor. Along `dim` the element goes to the
+/// position given by the matching element of an index tensor, and along every other dimension it keeps its position.
+/// When several elements land on the same position the last one wins.
+///
+/// Inputs are (destination, indexes, source). The indexes and source have the same shape, which can't be larger
+/// than the destination outside of `dim`. Same as https://pytorch.org/docs/stable/generated/torch.Tensor.scatter_
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scatter(pub usize);
+impl Operator for Scatter {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![scatter(&inp, self.0, |_, src| src)]
+    }
+}
+
+/// Like `Scatter`, but elements are added to the destination, so elements landing on the same position are summed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScatterAdd(pub usize);
+impl Operator for ScatterAdd {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![scatter(&inp, self.0, |dest, src| dest + src)]
+    }
+}
+
+fn scatter(
+    inp: &[(InputTensor, ShapeTracker)],
+    dim: usize,
+    combine: impl Fn(f32, f32) -> f32,
+) -> Tensor {
+    let (dest_shape, src_shape) = (inp[0].1.shape_usize(), inp[2].1.shape_usize());
+    assert_eq!(
+        inp[1].1.shape_usize(),
+        src_shape,
+        "Scatter indexes and source should have the same shape"
+    );
+    assert!(
+        dest_shape.len() == src_shape.len()
+            && (0..dest_shape.len()).all(|d| d == dim || src_shape[d] <= dest_shape[d]),
+        "Scatter source of shape {src_shape:?} doesn't fit a destination of shape {dest_shape:?}"
+    );
+    let (dest, indexes, src) = (get_vec(&inp[0].0), get_vec(&inp[1].0), get_vec(&inp[2].0));
+    let dest_expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+    let (ind, val) = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+    let src_expr = (inp[2].1.index_expression(), inp[2].1.valid_expression());
+
+    let mut stack = vec![];
+    let mut result = output_buffer(dest_shape.iter().product());
+    for (i, out) in result.iter_mut().enumerate() {
+        *out = get_index(dest, &dest_expr, &mut stack, i);
+    }
+    let mut dest_strides = vec![1; dest_shape.len()];
+    for d in (0..dest_shape.len().saturating_sub(1)).rev() {
+        dest_strides[d] = dest_strides[d + 1] * dest_shape[d + 1];
+    }
+    for i in 0..src_shape.iter().product() {
+        // Padded indexes don't write anything
+        if val.exec_single_var_stack(i, &mut stack) == 0 {
+            continue;
+        }
+        let index = indexes.get_usize(ind.exec_single_var_stack(i, &mut stack));
+        assert!(
+            index < dest_shape[dim],
+            "Scatter index {index} is out of range for a dimension of size {}",
+            dest_shape[dim]
+        );
+        // Keep the element's position in every dimension but `dim`
+        let (mut rem, mut position) = (i, 0);
+        for d in (0..src_shape.len()).rev() {
+            let coord = if d == dim { index } else { rem % src_shape[d] };
+            rem /= src_shape[d];
+            position += coord * dest_strides[d];
+        }
+        result[position] = combine(result[position], get_index(src, &src_expr, &mut stack, i));
+    }
+    to_tensor(result, dest.dtype().combine(src.dtype()))
+}
+
+// Convolution Ops (A x B -> C)
+
+/// 2D convolution of a (batch, channels_in, height, width) input with a (channels_out, channels_in / groups,
+/// kernel_height, kernel_width) weight, giving a (batch, channels_out, out_height, out_width) output.
+///
+/// Strides, padding and dilations are given as (height, width). Inputs are zero padded on both sides, and a dilation
+/// of 0 means a dense kernel, like `pool_last_dim`. With more than one group, the channels are split into `groups`
+/// independent convolutions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Convolution2D {
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+    pub dilation: (usize, usize),
+    pub groups: usize,
+}
+
+impl Default for Convolution2D {
+    fn default() -> Self {
+        Self {
+            stride: (1, 1),
+            padding: (0, 0),
+            dilation: (0, 0),
+            groups: 1,
+        }
+    }
+}
+
+impl Convolution2D {
+    /// The (height, width) of the output for an input and kernel of the given sizes
+    pub fn output_size<E: Into<Expression>>(
+        &self,
+        (height, width): (E, E),
+        (kernel_height, kernel_width): (usize, usize),
+    ) -> (Expression, Expression) {
+        let dim = |size: Expression, kernel: usize, stride: usize, padding: usize, dilation| {
+            (size + 2 * padding - (dilation + 1) * (kernel - 1) - 1) / stride + 1
+        };
+        (
+            dim(
+                height.into(),
+                kernel_height,
+                self.stride.0,
+                self.padding.0,
+                self.dilation.0,
+            ),
+            dim(
+                width.into(),
+                kernel_width,
+                self.stride.1,
+                self.padding.1,
+                self.dilation.1,
+            ),
+        )
+    }
+}
+
+impl Operator for Convolution2D {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let [batch, ch_in, height, width] = inp[0].1.shape_usize()[..] else {
+            panic!("Convolution2D input should be 4D");
+        };
+        let [ch_out, group_ch_in, kh, kw] = inp[1].1.shape_usize()[..] else {
+            panic!("Convolution2D weight should be 4D");
+        };
+        assert_eq!(
+            ch_in,
+            group_ch_in * self.groups,
+            "Mismatched input channels"
+        );
+        let group_ch_out = ch_out / self.groups;
+        let (oh, ow) = self.output_size((height, width), (kh, kw));
+        let (oh, ow) = (oh.to_usize().unwrap(), ow.to_usize().unwrap());
+        let (input, weight) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        let input_expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let weight_expr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+
+        let mut result = output_buffer(batch * ch_out * oh * ow);
+        fill_reduced(&mut result, group_ch_in * kh * kw, |i, stack| {
+            let (n, co, oy, ox) = (
+                i / (ch_out * oh * ow),
+                (i / (oh * ow)) % ch_out,
+                (i / ow) % oh,
+                i % ow,
+            );
+            let group = co / group_ch_out;
+            let mut acc = 0.;
+            for ci in 0..group_ch_in {
+                let input_channel = (n * ch_in + group * group_ch_in + ci) * height;
+                for ky in 0..kh {
+                    let Some(iy) = (oy * self.stride.0 + ky * (self.dilation.0 + 1))
+                        .checked_sub(self.padding.0)
+                        .filter(|iy| *iy < height)
+                    else {
+                        continue;
+                    };
+                    for kx in 0..kw {
+                        let Some(ix) = (ox * self.stride.1 + kx * (self.dilation.1 + 1))
+                            .checked_sub(self.padding.1)
+                            .filter(|ix| *ix < width)
+                        else {
+                            continue;
+                        };
+                        acc +=
+                            get_index(input, &input_expr, stack, (input_channel + iy) * width + ix)
+                                * get_index(
+                                    weight,
+                                    &weight_expr,
+                                    stack,
+                                    ((co * group_ch_in + ci) * kh + ky) * kw + kx,
+                                );
+                    }
+                }
+            }
+            acc
+        });
+        vec![to_tensor(result, input.dtype().combine(weight.dtype()))]
+    }
+}
+
+// Pooling Ops (A -> B)
+
+/// Max pooling over a (batch, channels, height, width) input, taking the largest element of each window. Kernel sizes,
+/// strides and padding are given as (height, width), and padded elements are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxPooling2D {
+    pub kernel: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+}
+
+/// Average pooling over a (batch, channels, height, width) input. Kernel sizes, strides and padding are given as
+/// (height, width), and padded elements count as zeros in the average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AvgPooling2D {
+    pub kernel: (usize, usize),
+    pub stride: (usize, usize),
+    pub padding: (usize, usize),
+}
+
+impl MaxPooling2D {
+    /// The (height, width) of the output for an input of the given size
+    pub fn output_size<E: Into<Expression>>(&self, size: (E, E)) -> (Expression, Expression) {
+        pool_output_size(size, self.kernel, self.stride, self.padding)
+    }
+}
+
+impl AvgPooling2D {
+    /// The (height, width) of the output for an input of the given size
+    pub fn output_size<E: Into<Expression>>(&self, size: (E, E)) -> (Expression, Expression) {
+        pool_output_size(size, self.kernel, self.stride, self.padding)
+    }
+}
+
+fn pool_output_size<E: Into<Expression>>(
+    (height, width): (E, E),
+    kernel: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+) -> (Expression, Expression) {
+    (
+        (height.into() + 2 * padding.0 - kernel.0) / stride.0 + 1,
+        (width.into() + 2 * padding.1 - kernel.1) / stride.1 + 1,
+    )
+}
+
+impl Operator for MaxPooling2D {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![pool_2d(
+            &inp[0],
+            (self.kernel, self.stride, self.padding),
+            f32::NEG_INFINITY,
+            f32::max,
+            |m| m,
+        )]
+    }
+}
+
+impl Operator for AvgPooling2D {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let window = (self.kernel.0 * self.kernel.1) as f32;
+        vec![pool_2d(
+            &inp[0],
+            (self.kernel, self.stride, self.padding),
+            0.,
+            |a, b| a + b,
+            |s| s / window,
+        )]
+    }
+}
+
+/// Fold each pooling window of a (batch, channels, height, width) input, skipping padded elements
+fn pool_2d(
+    (input, shape): &(InputTensor, ShapeTracker),
+    (kernel, stride, padding): ((usize, usize), (usize, usize), (usize, usize)),
+    init: f32,
+    combine: impl Fn(f32, f32) -> f32 + Sync,
+    finish: impl Fn(f32) -> f32 + Sync,
+) -> Tensor {
+    let [batch, channels, height, width] = shape.shape_usize()[..] else {
+        panic!("2D pooling input should be 4D");
+    };
+    let (oh, ow) = pool_output_size((height, width), kernel, stride, padding);
+    let (oh, ow) = (oh.to_usize().unwrap(), ow.to_usize().unwrap());
+    let data = get_vec(input);
+    let expr = (shape.index_expression(), shape.valid_expression());
+    let mut result = output_buffer(batch * channels * oh * ow);
+    fill_reduced(&mut result, kernel.0 * kernel.1, |i, stack| {
+        let (plane, oy, ox) = (i / (oh * ow), (i / ow) % oh, i % ow);
+        let mut acc = init;
+        for ky in 0..kernel.0 {
+            let Some(iy) = (oy * stride.0 + ky)
+                .checked_sub(padding.0)
+                .filter(|iy| *iy < height)
+            else {
+                continue;
+            };
+            for kx in 0..kernel.1 {
+                if let Some(ix) = (ox * stride.1 + kx)
+                    .checked_sub(padding.1)
+                    .filter(|ix| *ix < width)
+                {
+                    let index = (plane * height + iy) * width + ix;
+                    acc = combine(acc, get_index(data, &expr, stack, index));
+                }
+            }
+        }
+        finish(acc)
+    });
+    to_tensor(result, data.dtype())
+}
+
+// Padding Ops (A -> B (dims grown))
+
+/// How the new elements added by `pad_with` are filled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaddingMode {
+    /// A constant value
+    Constant(f32),
+    /// Mirror the elements next to the edge, without repeating the edge element itself: `[1, 2, 3]` padded by 2 on
+    /// both sides is `[3, 2, 1, 2, 3, 2, 1]`
+    Reflect,
+    /// Repeat the edge element: `[1, 2, 3]` padded by 2 on both sides is `[1, 1, 1, 2, 3, 3, 3]`
+    Replicate,
+}
+
+/// Pad each dimension by a number of elements before and after it. Zero padding is better done through the
+/// `ShapeTracker` (see `pad_with`), which doesn't copy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pad {
+    pub padding: Vec<(usize, usize)>,
+    pub mode: PaddingMode,
+}
+impl Operator for Pad {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        assert_eq!(
+            sh.len(),
+            self.padding.len(),
+            "Padding needs an amount for every dimension"
+        );
+        let out_sh = sh
+            .iter()
+            .zip(&self.padding)
+            .map(|(d, (before, after))| d + before + after)
+            .collect::<Vec<_>>();
+        if self.mode == PaddingMode::Reflect {
+            for (d, (before, after)) in sh.iter().zip(&self.padding) {
+                assert!(
+                    before < d && after < d,
+                    "Reflect padding ({before}, {after}) must be smaller than the dimension ({d})"
+                );
+            }
+        }
+        let input = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let mut result = output_buffer(out_sh.iter().product());
+        for (i, out) in result.iter_mut().enumerate() {
+            // Map each output coordinate back to the input, walking the dimensions from the back
+            let (mut rem, mut src, mut stride) = (i, 0, 1);
+            let mut inside = true;
+            for ((d, out_d), (before, _)) in sh.iter().zip(&out_sh).zip(&self.padding).rev() {
+                let (d, c) = (*d as isize, (rem % out_d) as isize - *before as isize);
+                rem /= out_d;
+                let c = if (0..d).contains(&c) {
+                    c
+                } else {
+                    match self.mode {
+                        PaddingMode::Constant(_) => {
+                            inside = false;
+                            0
+                        }
+                        PaddingMode::Reflect if c < 0 => -c,
+                        PaddingMode::Reflect => 2 * (d - 1) - c,
+                        PaddingMode::Replicate => c.clamp(0, d - 1),
+                    }
+                };
+                src += c as usize * stride;
+                stride *= d as usize;
+            }
+            *out = match self.mode {
+                PaddingMode::Constant(v) if !inside => v,
+                _ => get_index(input, &expr, &mut stack, src),
+            };
+        }
+        vec![to_tensor(result, input.dtype())]
+    }
+}
+
+// Concat Ops (A x ... x A -> B (dim grown))
+
+/// Join any number of tensors along a dimension. Their other dimensions must match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Concat(pub usize);
+impl Operator for Concat {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shapes = inp
+            .iter()
+            .map(|(_, sh)| sh.shape_usize())
+            .collect::<Vec<_>>();
+        let sh = &shapes[0];
+        for other in &shapes[1..] {
+            assert!(
+                other.len() == sh.len()
+                    && other
+                        .iter()
+                        .zip(sh)
+                        .enumerate()
+                        .all(|(i, (a, b))| i == self.0 || a == b),
+                "Can't concat shapes {sh:?} and {other:?} along dimension {}",
+                self.0
+            );
+        }
+        let front_size = sh.iter().take(self.0).product::<usize>();
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>();
+        let total_dim = shapes.iter().map(|s| s[self.0]).sum::<usize>();
+        let mut result = output_buffer(front_size * total_dim * back_size);
+        let mut offset = 0;
+        let mut stack = vec![];
+        for ((input, shape), input_sh) in inp.iter().zip(&shapes) {
+            let data = get_vec(input);
+            let expr = (shape.index_expression(), shape.valid_expression());
+            // Each slice of the input is one contiguous run of the output
+            let run = input_sh[self.0] * back_size;
+            for i in 0..front_size {
+                let start = (i * total_dim + offset) * back_size;
+                for (k, out) in result[start..start + run].iter_mut().enumerate() {
+                    *out = get_index(data, &expr, &mut stack, i * run + k);
+                }
+            }
+            offset += input_sh[self.0];
+        }
+        // Joining tensors of one dtype keeps it, so indexes stay indexes
+        let dtype = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().dtype())
+            .reduce(|a, b| if a == b { a } else { a.combine(b) })
+            .unwrap();
+        vec![to_tensor(result, dtype)]
+    }
+}
+
+// Write Ops (A x B -> A)
+
+/// Write a tensor into a buffer along a dimension, starting at a runtime offset. The buffer is updated in place when
+/// this op owns it, so the cost only depends on the size of the written tensor and not the size of the buffer.
+#[derive(Clone, PartialEq)]
+pub struct AppendAt(
+    pub usize,
+    pub BigExpression,
+    pub *const FxHashMap<char, usize>,
+);
+impl Debug for AppendAt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AppendAt({}, {:?})", self.0, self.1)
+    }
+}
+
+impl Operator for AppendAt {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (rows, rows_shape) = inp.pop().unwrap();
+        let (buffer, buffer_shape) = inp.pop().unwrap();
+        let offset = self.1.exec(unsafe { self.2.as_ref().unwrap() }).unwrap();
+        let buffer_sh = buffer_shape.shape_usize();
+        let rows_sh = rows_shape.shape_usize();
+        let back_size = buffer_sh.iter().skip(self.0 + 1).product::<usize>();
+        let (buffer_dim, rows_dim) = (buffer_sh[self.0], rows_sh[self.0]);
+        assert!(
+            offset + rows_dim <= buffer_dim,
+            "Writing {rows_dim} rows at offset {offset} overflows a buffer of {buffer_dim} rows"
+        );
+
+        // Only copies the buffer if we don't own it
+        let mut buffer = buffer.cloned();
+        let out = buffer.downcast_mut::<Vec<f32>>().unwrap();
+        let rows_data = get_vec(&rows);
+        let expr = (rows_shape.index_expression(), rows_shape.valid_expression());
+        let mut stack = vec![];
+        for i in 0..rows_shape.n_elements().to_usize().unwrap() {
+            let (a, b, c) = (
+                i / (rows_dim * back_size),
+                (i / back_size) % rows_dim,
+                i % back_size,
+            );
+            out[a * buffer_dim * back_size + (b + offset) * back_size + c] =
+                get_index(rows_data, &expr, &mut stack, i);
+        }
+        vec![buffer]
+    }
+}
+
+// Rounding Ops (A -> A)
+
+/// The lower precision format values get rounded to by `StochasticRound`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingFormat {
+    BF16,
+    F16,
+    Int8,
+}
+
+/// Round values to a lower precision format, picking the upper neighbor with probability proportional to how close the
+/// value is to it, so the rounding is unbiased in expectation. Values are stored as f32 after rounding.
+///
+/// Random numbers are drawn from a stream seeded by the graph's seed and a per-op salt.
+#[derive(Clone)]
+pub struct StochasticRound {
+    pub format: RoundingFormat,
+    stream: RandomStream,
+}
+
+impl StochasticRound {
+    pub fn new(format: RoundingFormat, salt: u64, seed: *const u64) -> Self {
+        Self {
+            format,
+            stream: RandomStream::new(salt, seed),
+        }
+    }
+}
+
+impl Debug for StochasticRound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StochasticRound({:?})", self.format)
+    }
+}
+
+impl Operator for StochasticRound {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let rng = self.stream.rng();
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let mut out_data = output_buffer(inp[0].1.n_elements().to_usize().unwrap());
+        for (i, out) in out_data.iter_mut().enumerate() {
+            let x = get_index(inp_data, &expr, &mut stack, i);
+            *out = match self.format {
+                RoundingFormat::BF16 => stochastic_round_bf16(x, rng.gen()),
+                RoundingFormat::F16 => stochastic_round_f16(x, rng.gen()),
+                RoundingFormat::Int8 => (x + rng.gen::<f32>()).floor().clamp(-128., 127.),
+            };
+        }
+        vec![Tensor::new(out_data)]
+    }
+}
+
+/// bf16 is the top half of an f32, so adding random low bits before truncating rounds the magnitude up with the right probability
+fn stochastic_round_bf16(x: f32, random: u32) -> f32 {
+    if !x.is_finite() {
+        return x;
+    }
+    f32::from_bits(x.to_bits().wrapping_add(random & 0xFFFF) & 0xFFFF_0000)
+}
+
+fn stochastic_round_f16(x: f32, uniform: f32) -> f32 {
+    let a = x.abs();
+    let nearest = f16::from_f32(a);
+    if !nearest.is_finite() || nearest.to_f32() == a {
+        return nearest.to_f32().copysign(x);
+    }
+    // Find the representable magnitudes on either side
+    let (lo, hi) = if nearest.to_f32() > a {
+        (f16::from_bits(nearest.to_bits() - 1), nearest)
+    } else {
+        (nearest, f16::from_bits(nearest.to_bits() + 1))
+    };
+    if !hi.is_finite() {
+        return lo.to_f32().copysign(x);
+    }
+    let (lo, hi) = (lo.to_f32(), hi.to_f32());
+    let rounded = if uniform < (a - lo) / (hi - lo) {
+        hi
+    } else {
+        lo
+    };
+    rounded.copysign(x)
+}
+
+// Dropout Ops (A -> A)
+
+/// The mask dropout multiplies by, shaped like its input (whose values aren't read). While the graph is training each
+/// element is 0 with probability `p`, and `1 / (1 - p)` otherwise so the expected value is unchanged. Otherwise every
+/// element is 1.
+///
+/// Random numbers are drawn from a stream seeded by the graph's seed and a per-op salt, and the training flag is read
+/// on each execution (see `Graph::set_training`).
+#[derive(Clone)]
+pub struct DropoutMask {
+    pub p: f32,
+    training: *const bool,
+    stream: RandomStream,
+}
+
+impl DropoutMask {
+    pub fn new(p: f32, salt: u64, seed: *const u64, training: *const bool) -> Self {
+        assert!(
+            (0. ..1.).contains(&p),
+            "Dropout probability must be in [0, 1), got {p}"
+        );
+        Self {
+            p,
+            training,
+            stream: RandomStream::new(salt, seed),
+        }
+    }
+}
+
+impl Debug for DropoutMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DropoutMask({})", self.p)
+    }
+}
+
+impl Operator for DropoutMask {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let n_elements = inp[0].1.n_elements().to_usize().unwrap();
+        if !unsafe { *self.training } {
+            return vec![Tensor::new(vec![1.; n_elements])];
+        }
+        let rng = self.stream.rng();
+        let scale = 1. / (1. - self.p);
+        let mask: Vec<f32> = (0..n_elements)
+            .map(|_| if rng.gen::<f32>() < self.p { 0. } else { scale })
+            .collect();
+        vec![Tensor::new(mask)]
+    }
+}
+
+// Random Ops (0 -> A)
+
+/// The distribution `Random` draws values from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// Uniform over `[low, high)`
+    Uniform { low: f32, high: f32 },
+    /// Normal (gaussian) with a mean and standard deviation
+    Normal { mean: f32, std: f32 },
+    /// 1 with probability `p`, otherwise 0
+    Bernoulli { p: f32 },
+}
+
+/// A tensor of random values with `n_elements` elements (which may depend on dynamic dimensions), drawn independently
+/// from a distribution. Each execution draws new values.
+///
+/// Random numbers are drawn from a stream seeded by the graph's seed and a per-op salt.
+#[derive(Clone)]
+pub struct Random {
+    pub distribution: Distribution,
+    pub n_elements: BigExpression,
+    dyn_map: *const FxHashMap<char, usize>,
+    stream: RandomStream,
+}
+
+impl Random {
+    pub fn new(
+        distribution: Distribution,
+        n_elements: BigExpression,
+        salt: u64,
+        seed: *const u64,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        Self {
+            distribution,
+            n_elements,
+            dyn_map,
+            stream: RandomStream::new(salt, seed),
+        }
+    }
+}
+
+impl Debug for Random {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Random({:?})", self.distribution)
+    }
+}
+
+impl Operator for Random {
+    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let n_elements = self
+            .n_elements
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        let rng = self.stream.rng();
+        let mut out = output_buffer(n_elements);
+        match self.distribution {
+            Distribution::Uniform { low, high } => {
+                for o in out.iter_mut() {
+                    *o = low + (high - low) * rng.gen::<f32>();
+                }
+            }
+            Distribution::Normal { mean, std } => {
+                // Box-Muller gives a pair of independent normals from a pair of uniforms
+                for pair in out.chunks_mut(2) {
+                    let radius = (-2. * (1. - rng.gen::<f32>()).ln()).sqrt();
+                    let angle = std::f32::consts::TAU * rng.gen::<f32>();
+                    pair[0] = mean + std * radius * angle.cos();
+                    if let Some(second) = pair.get_mut(1) {
+                        *second = mean + std * radius * angle.sin();
+                    }
                 }
             }
+            Distribution::Bernoulli { p } => {
+                for o in out.iter_mut() {
+                    *o = if rng.gen::<f32>() < p { 1. } else { 0. };
+                }
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// A random number stream seeded by the graph's seed and a per-op salt, so ops draw different numbers from each other.
+/// The stream restarts when the graph's seed changes, and otherwise continues across executions.
+#[derive(Clone)]
+struct RandomStream {
+    salt: u64,
+    seed: *const u64,
+    rng: Option<(u64, StdRng)>,
+}
+
+impl RandomStream {
+    fn new(salt: u64, seed: *const u64) -> Self {
+        Self {
+            salt,
+            seed,
+            rng: None,
+        }
+    }
+
+    fn rng(&mut self) -> &mut StdRng {
+        let seed = unsafe { *self.seed };
+        if self.rng.as_ref().map(|(s, _)| *s != seed).unwrap_or(true) {
+            let stream = seed ^ self.salt.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            self.rng = Some((seed, StdRng::seed_from_u64(stream)));
+        }
+        &mut self.rng.as_mut().unwrap().1
+    }
+}
+
+// Sampling Ops (A -> B (last dim removed))
+
+/// Draw a token id from each row of logits along the last dimension.
+///
+/// Applies temperature scaling, top-k and top-p (nucleus) filtering, then takes a multinomial draw over the
+/// remaining probabilities. A temperature of 0 is greedy (argmax) sampling, a `top_k` of 0 disables top-k filtering
+/// and a `top_p` of 1 disables top-p filtering. Outputs one token id (as a float) per row.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub rng: StdRng,
+}
+
+impl Sample {
+    pub fn new(temperature: f32, top_k: usize, top_p: f32, seed: u64) -> Self {
+        Self {
+            temperature,
+            top_k,
+            top_p,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Operator for Sample {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let vocab = *sh.last().unwrap();
+        let rows = sh.iter().rev().skip(1).product::<usize>();
+        let input = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let mut result = vec![0.0; rows];
+        let mut logits = vec![0.0; vocab];
+        for (row, out) in result.iter_mut().enumerate() {
+            for (i, l) in logits.iter_mut().enumerate() {
+                *l = get_index(input, &expr, &mut stack, row * vocab + i);
+            }
+            *out = sample_row(
+                &logits,
+                self.temperature,
+                self.top_k,
+                self.top_p,
+                self.rng.gen(),
+            ) as f32;
         }
         vec![Tensor::new(result)]
     }
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
+/// Sample an index from a single row of logits given a uniform random number in [0, 1)
+pub(crate) fn sample_row(
+    logits: &[f32],
+    temperature: f32,
+    top_k: usize,
+    top_p: f32,
+    uniform: f32,
+) -> usize {
+    let keep = if temperature <= 0. {
+        1
+    } else if top_k == 0 {
+        logits.len()
+    } else {
+        top_k.min(logits.len())
+    };
+    let order = top_k_indexes(logits, keep);
+    if temperature <= 0. {
+        return order[0];
+    }
+    // Softmax (unnormalized) over the sorted logits
+    let max = logits[order[0]];
+    let probs = order
+        .iter()
+        .map(|i| ((logits[*i] - max) / temperature).exp())
+        .collect::<Vec<_>>();
+    let mut keep = probs.len();
+    if top_p < 1. {
+        let target = top_p * probs[..keep].iter().sum::<f32>();
+        let mut mass = 0.;
+        for (i, p) in probs[..keep].iter().enumerate() {
+            mass += p;
+            if mass >= target {
+                keep = i + 1;
+                break;
+            }
+        }
+    }
+    let mut target = uniform * probs[..keep].iter().sum::<f32>();
+    for (i, p) in probs[..keep].iter().enumerate() {
+        if target < *p {
+            return order[i];
+        }
+        target -= p;
+    }
+    // Rounding can leave a little target over, which goes to the last token with any probability, never a masked one
+    order[probs[..keep].iter().rposition(|p| *p > 0.).unwrap_or(0)]
+}
+
+/// The indexes of the `k` largest values, largest first, with ties going to the lower index. Only the top `k` are
+/// sorted, the rest are split off with a partial selection.
+fn top_k_indexes(values: &[f32], k: usize) -> Vec<usize> {
+    let cmp = |a: &usize, b: &usize| values[*b].total_cmp(&values[*a]).then(a.cmp(b));
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    if k < order.len() {
+        if k > 0 {
+            order.select_nth_unstable_by(k - 1, cmp);
+        }
+        order.truncate(k);
+    }
+    order.sort_unstable_by(cmp);
+    order
+}
+
+/// Ops doing at least this many operations are split across threads
+pub const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+/// Fill each output of a reduction, where each output takes `work` operations. Outputs are computed in parallel when
+/// there's enough work overall.
+fn fill_reduced(result: &mut [f32], work: usize, f: impl Fn(usize, &mut Vec<i64>) -> f32 + Sync) {
+    let chunk_size = (PARALLEL_THRESHOLD / work.max(1)).max(1);
+    let fill = |(c, chunk): (usize, &mut [f32])| {
+        let mut stack = vec![];
+        for (i, out) in chunk.iter_mut().enumerate() {
+            *out = f(c * chunk_size + i, &mut stack);
+        }
+    };
+    if result.len() * work >= PARALLEL_THRESHOLD {
+        result.par_chunks_mut(chunk_size).enumerate().for_each(fill);
+    } else {
+        result.chunks_mut(chunk_size).enumerate().for_each(fill);
+    }
+}
+
+/// Input data to a primitive kernel. Kernels compute in f32, reading half precision, indexes and quantized values
+/// directly.
+#[derive(Clone, Copy)]
+enum Values<'a> {
+    F32(&'a [f32]),
+    Half(&'a [u16], DType),
+    Index(&'a [u32]),
+    Quantized(&'a QuantizedVec),
+}
+
+impl Values<'_> {
+    fn get(&self, index: usize) -> f32 {
+        match self {
+            Values::F32(d) => d[index],
+            Values::Half(d, dtype) => half_to_f32(d[index], *dtype),
+            Values::Index(d) => d[index] as f32,
+            Values::Quantized(q) => q.get(index),
+        }
+    }
+
+    /// Read a value as an index, without going through f32 for index data
+    fn get_usize(&self, index: usize) -> usize {
+        match self {
+            Values::Index(d) => d[index] as usize,
+            _ => self.get(index) as usize,
+        }
+    }
+
+    /// The dtype of results computed from these values. Indexes are promoted to f32.
+    fn dtype(&self) -> DType {
+        match self {
+            Values::F32(_) | Values::Index(_) | Values::Quantized(_) => DType::F32,
+            Values::Half(_, dtype) => *dtype,
+        }
+    }
+}
+
+fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> Values<'a> {
+    let tensor = tensor.borrowed();
+    if let Some(h) = tensor.data.as_any().downcast_ref::<HalfVec>() {
+        Values::Half(&h.bits, h.dtype)
+    } else if let Some(i) = tensor.data.as_any().downcast_ref::<IndexVec>() {
+        Values::Index(&i.indexes)
+    } else if let Some(b) = tensor.data.as_any().downcast_ref::<BorrowedSlice>() {
+        Values::F32(b.as_slice())
+    } else if let Some(q) = tensor.data.as_any().downcast_ref::<QuantizedVec>() {
+        Values::Quantized(q)
+    } else {
+        Values::F32(tensor.downcast_ref::<Vec<f32>>().unwrap())
+    }
+}
+
+/// Store a kernel's f32 results in the given dtype. Values stored as indexes are rounded, with negatives becoming 0.
+/// Values stored as masks become 1 if they're nonzero.
+fn to_tensor(values: Vec<f32>, dtype: DType) -> Tensor {
+    match dtype {
+        DType::F32 => Tensor::new(values),
+        DType::Bool => Tensor::new(
+            values
+                .into_iter()
+                .map(|v| (v != 0.) as i32 as f32)
+                .collect::<Vec<_>>(),
+        ),
+        DType::U32 => Tensor::new(IndexVec::new(
+            values.into_iter().map(|v| v.round() as u32).collect(),
+        )),
+        _ => Tensor::new(HalfVec::new(&values, dtype)),
+    }
 }
 
 fn get_index(
-    data: &[f32],
+    data: Values,
     (ind, val): &(BigExpression, BigExpression),
     stack: &mut Vec<i64>,
     index: usize,
 ) -> f32 {
     if val.exec_single_var_stack(index, stack) != 0 {
-        data[ind.exec_single_var_stack(index, stack)]
+        data.get(ind.exec_single_var_stack(index, stack))
     } else {
         0.0
     }
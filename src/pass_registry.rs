@@ -0,0 +1,244 @@
+// Registration of compiler passes contributed by external crates
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// An object-safe compiler pass that can be registered and ordered by name
+pub trait CompilerPass: Send + Sync {
+    /// The unique name of this pass
+    fn name(&self) -> &str;
+    /// Names of passes that must run before this one, if they're registered
+    fn dependencies(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Run the pass, remapping the given ids
+    fn run(&self, graph: &mut Graph, ids: &mut [&mut NodeIndex]);
+}
+
+/// Wraps any compiler so it can be registered as a named pass
+#[derive(Debug)]
+pub struct Pass<C> {
+    name: String,
+    dependencies: Vec<String>,
+    compiler: C,
+}
+
+impl<C> Pass<C> {
+    pub fn new(name: impl ToString, compiler: C) -> Self {
+        Self {
+            name: name.to_string(),
+            dependencies: vec![],
+            compiler,
+        }
+    }
+
+    /// Run this pass after the named pass
+    pub fn after(mut self, pass: impl ToString) -> Self {
+        self.dependencies.push(pass.to_string());
+        self
+    }
+}
+
+impl<C: Compiler + Send + Sync> CompilerPass for Pass<C> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn dependencies(&self) -> Vec<String> {
+        self.dependencies.clone()
+    }
+    fn run(&self, graph: &mut Graph, ids: &mut [&mut NodeIndex]) {
+        self.compiler.compile(graph, ids);
+    }
+}
+
+/// A set of passes that runs in dependency order when used as a compiler. Passes with no ordering between them run in
+/// the order they were registered.
+#[derive(Default, Clone)]
+pub struct PassRegistry(Vec<Arc<dyn CompilerPass>>);
+
+impl Debug for PassRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|p| p.name()))
+            .finish()
+    }
+}
+
+impl PassRegistry {
+    /// Add a pass, replacing any registered pass with the same name
+    pub fn register(&mut self, pass: impl CompilerPass + 'static) {
+        self.register_arc(Arc::new(pass));
+    }
+
+    fn register_arc(&mut self, pass: Arc<dyn CompilerPass>) {
+        if let Some(existing) = self.0.iter_mut().find(|p| p.name() == pass.name()) {
+            *existing = pass;
+        } else {
+            self.0.push(pass);
+        }
+    }
+
+    /// Remove a pass by name, returning whether it was registered
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|p| p.name() != name);
+        self.0.len() != len
+    }
+
+    /// The names of the registered passes, in the order they'll run
+    pub fn ordered_names(&self) -> Vec<String> {
+        self.ordered()
+            .iter()
+            .map(|p| p.name().to_string())
+            .collect()
+    }
+
+    fn ordered(&self) -> Vec<Arc<dyn CompilerPass>> {
+        let indexes = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.name().to_string(), i))
+            .collect::<FxHashMap<_, _>>();
+        let deps = self
+            .0
+            .iter()
+            .map(|p| {
+                p.dependencies()
+                    .iter()
+                    .filter_map(|d| indexes.get(d).copied())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut done = vec![false; self.0.len()];
+        let mut order = Vec::with_capacity(self.0.len());
+        while order.len() < self.0.len() {
+            // Take the first pass whose dependencies have all run
+            let Some(next) =
+                (0..self.0.len()).find(|i| !done[*i] && deps[*i].iter().all(|d| done[*d]))
+            else {
+                panic!(
+                    "Circular dependency between compiler passes: {:?}",
+                    (0..self.0.len())
+                        .filter(|i| !done[*i])
+                        .map(|i| self.0[i].name())
+                        .collect::<Vec<_>>()
+                );
+            };
+            done[next] = true;
+            order.push(self.0[next].clone());
+        }
+        order
+    }
+}
+
+impl Compiler for PassRegistry {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for pass in self.ordered() {
+            pass.run(graph, &mut ids.to_ids_mut());
+        }
+    }
+}
+
+static GLOBAL_PASSES: Mutex<Vec<Arc<dyn CompilerPass>>> = Mutex::new(Vec::new());
+
+/// Register a pass globally, so it's run after the compiler every time any graph is compiled. Meant to be called by
+/// external crates contributing passes or backend lowerings.
+pub fn register_pass(pass: impl CompilerPass + 'static) {
+    let mut passes = GLOBAL_PASSES.lock().unwrap();
+    let mut registry = PassRegistry(std::mem::take(&mut *passes));
+    registry.register(pass);
+    *passes = registry.0;
+}
+
+/// Remove a globally registered pass by name, returning whether it was registered
+pub fn unregister_pass(name: &str) -> bool {
+    let mut passes = GLOBAL_PASSES.lock().unwrap();
+    let len = passes.len();
+    passes.retain(|p| p.name() != name);
+    passes.len() != len
+}
+
+/// Get a snapshot of the globally registered passes
+pub fn registered_passes() -> PassRegistry {
+    PassRegistry(GLOBAL_PASSES.lock().unwrap().clone())
+}
+
+impl Graph {
+    /// Run the globally registered passes and this graph's own passes together in dependency order. A pass on the
+    /// graph replaces a global pass with the same name.
+    pub(crate) fn run_registered_passes<T: ToIdsMut>(&mut self, ids: T) {
+        let mut passes = registered_passes();
+        for pass in &self.passes.0 {
+            passes.register_arc(pass.clone());
+        }
+        if !passes.0.is_empty() {
+            passes.compile(self, ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    crate::test_imports!();
+
+    static LOG: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    #[derive(Debug)]
+    struct Logger(&'static str);
+    impl Compiler for Logger {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+            LOG.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn test_pass_ordering() {
+        let mut registry = PassRegistry::default();
+        registry.register(Pass::new("lower", Logger("lower")).after("fuse"));
+        registry.register(Pass::new("fuse", Logger("fuse")).after("cse"));
+        registry.register(Pass::new("cse", Logger("cse")).after("not registered"));
+        registry.register(Pass::new("other", Logger("other")));
+        assert_eq!(registry.ordered_names(), ["cse", "fuse", "lower", "other"]);
+
+        // Passes run on every compile after the compiler, and still remap ids. Other tests compile in parallel, so the
+        // global pass leaves graphs alone.
+        register_pass(Pass::new("test_log", Logger("registered")).after("test_cse"));
+        let mut cx = Graph::new();
+        cx.passes
+            .register(Pass::new("test_cse", GenericCompiler::default()));
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = (a.exp() + a.exp()).retrieve();
+        cx.compile((), &mut b);
+        cx.execute();
+        assert_close(&b.data(), &[1., 2., 3.].map(|i: f32| 2. * i.exp()));
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<Exp2>())
+                .count(),
+            1
+        );
+        assert!(LOG.lock().unwrap().contains(&"registered"));
+        assert!(unregister_pass("test_log"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Circular dependency")]
+    fn test_pass_cycle() {
+        let mut registry = PassRegistry::default();
+        registry.register(Pass::new("a", Logger("a")).after("b"));
+        registry.register(Pass::new("b", Logger("b")).after("a"));
+        registry.ordered_names();
+    }
+}
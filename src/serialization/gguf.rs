@@ -0,0 +1,729 @@
+//! Support for the GGUF file format, used by llama.cpp and most quantized LLM checkpoints.
+//!
+//! Spec: https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Result, Seek},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use memmap2::Mmap;
+
+use super::invalid_data;
+use crate::{op::Function, prelude::*};
+
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionedMagic {
+    GgufV1,
+    GgufV2,
+    GgufV3,
+}
+
+impl VersionedMagic {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != 0x46554747 && magic != 0x47475546 {
+            return Err(invalid_data(format!("Unknown magic 0x{magic:08x}")));
+        }
+        match reader.read_u32::<LittleEndian>()? {
+            1 => Ok(Self::GgufV1),
+            2 => Ok(Self::GgufV2),
+            3 => Ok(Self::GgufV3),
+            v => Err(invalid_data(format!("Unsupported GGUF version {v}"))),
+        }
+    }
+
+    /// Read a length or count, which was a u32 in v1 and is a u64 since
+    fn read_len<R: Read>(&self, reader: &mut R) -> Result<usize> {
+        Ok(match self {
+            Self::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
+            Self::GgufV2 | Self::GgufV3 => reader.read_u64::<LittleEndian>()? as usize,
+        })
+    }
+}
+
+pub fn read_string<R: Read>(reader: &mut R, magic: &VersionedMagic) -> Result<String> {
+    let len = magic.read_len(reader)?;
+    let mut v = vec![0u8; len];
+    reader.read_exact(&mut v)?;
+    // GGUF strings are supposed to be non-null terminated but in practice this happens.
+    while let Some(0) = v.last() {
+        v.pop();
+    }
+    // GGUF strings are utf8 encoded but there are cases that don't seem to be valid.
+    Ok(String::from_utf8_lossy(&v).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    Bool,
+    /// A UTF-8 non-null-terminated string, with length prepended
+    String,
+    /// An array of other values, with the length and type prepended. Arrays can be nested.
+    Array,
+}
+
+impl ValueType {
+    pub fn from_u32(v: u32) -> Result<Self> {
+        Ok(match v {
+            0 => Self::U8,
+            1 => Self::I8,
+            2 => Self::U16,
+            3 => Self::I16,
+            4 => Self::U32,
+            5 => Self::I32,
+            6 => Self::F32,
+            7 => Self::Bool,
+            8 => Self::String,
+            9 => Self::Array,
+            10 => Self::U64,
+            11 => Self::I64,
+            12 => Self::F64,
+            v => return Err(invalid_data(format!("Unrecognized value type {v:#08x}"))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn read<R: Read>(
+        reader: &mut R,
+        value_type: ValueType,
+        magic: &VersionedMagic,
+    ) -> Result<Self> {
+        Ok(match value_type {
+            ValueType::U8 => Self::U8(reader.read_u8()?),
+            ValueType::I8 => Self::I8(reader.read_i8()?),
+            ValueType::U16 => Self::U16(reader.read_u16::<LittleEndian>()?),
+            ValueType::I16 => Self::I16(reader.read_i16::<LittleEndian>()?),
+            ValueType::U32 => Self::U32(reader.read_u32::<LittleEndian>()?),
+            ValueType::I32 => Self::I32(reader.read_i32::<LittleEndian>()?),
+            ValueType::U64 => Self::U64(reader.read_u64::<LittleEndian>()?),
+            ValueType::I64 => Self::I64(reader.read_i64::<LittleEndian>()?),
+            ValueType::F32 => Self::F32(reader.read_f32::<LittleEndian>()?),
+            ValueType::F64 => Self::F64(reader.read_f64::<LittleEndian>()?),
+            ValueType::Bool => match reader.read_u8()? {
+                0 => Self::Bool(false),
+                1 => Self::Bool(true),
+                b => return Err(invalid_data(format!("Unexpected bool value {b}"))),
+            },
+            ValueType::String => Self::String(read_string(reader, magic)?),
+            ValueType::Array => {
+                let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+                let len = magic.read_len(reader)?;
+                Self::Array(
+                    (0..len)
+                        .map(|_| Value::read(reader, value_type, magic))
+                        .collect::<Result<_>>()?,
+                )
+            }
+        })
+    }
+
+    /// The value as an unsigned integer, if it is a non-negative integer
+    pub fn to_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as u64),
+            Self::U16(v) => Some(v as u64),
+            Self::U32(v) => Some(v as u64),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, if it is numeric
+    pub fn to_f32(&self) -> Option<f32> {
+        match *self {
+            Self::F32(v) => Some(v),
+            Self::F64(v) => Some(v as f32),
+            Self::I8(v) => Some(v as f32),
+            Self::I16(v) => Some(v as f32),
+            Self::I32(v) => Some(v as f32),
+            Self::I64(v) => Some(v as f32),
+            _ => self.to_u64().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlDType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q8_1,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    Q8K,
+}
+
+impl GgmlDType {
+    pub fn from_u32(u: u32) -> Result<Self> {
+        Ok(match u {
+            0 => Self::F32,
+            1 => Self::F16,
+            2 => Self::Q4_0,
+            3 => Self::Q4_1,
+            6 => Self::Q5_0,
+            7 => Self::Q5_1,
+            8 => Self::Q8_0,
+            9 => Self::Q8_1,
+            10 => Self::Q2K,
+            11 => Self::Q3K,
+            12 => Self::Q4K,
+            13 => Self::Q5K,
+            14 => Self::Q6K,
+            15 => Self::Q8K,
+            _ => return Err(invalid_data(format!("Unknown tensor dtype {u}"))),
+        })
+    }
+
+    /// The number of elements in each block
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::F32 | Self::F16 => 1,
+            Self::Q4_0 | Self::Q4_1 | Self::Q5_0 | Self::Q5_1 | Self::Q8_0 | Self::Q8_1 => 32,
+            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => 256,
+        }
+    }
+
+    /// The number of bytes in each block
+    pub fn type_size(&self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 => 2,
+            Self::Q4_0 => 18,
+            Self::Q4_1 => 20,
+            Self::Q5_0 => 22,
+            Self::Q5_1 => 24,
+            Self::Q8_0 => 34,
+            Self::Q8_1 => 36,
+            Self::Q2K => 84,
+            Self::Q3K => 110,
+            Self::Q4K => 144,
+            Self::Q5K => 176,
+            Self::Q6K => 210,
+            Self::Q8K => 292,
+        }
+    }
+
+    /// Whether `dequantize` supports this dtype
+    pub fn can_dequantize(&self) -> bool {
+        matches!(
+            self,
+            Self::F32 | Self::F16 | Self::Q4_0 | Self::Q8_0 | Self::Q4K
+        )
+    }
+
+    /// Decode blocks of this dtype into f32s
+    pub fn dequantize(&self, bytes: &[u8]) -> Vec<f32> {
+        let blocks = bytes.chunks_exact(self.type_size());
+        match self {
+            Self::F32 => blocks
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+            Self::F16 => blocks.map(|b| f16_at(b, 0)).collect(),
+            Self::Q8_0 => blocks
+                .flat_map(|b| {
+                    let delta = f16_at(b, 0);
+                    b[2..].iter().map(move |q| *q as i8 as f32 * delta)
+                })
+                .collect(),
+            Self::Q4_0 => blocks
+                .flat_map(|b| {
+                    let delta = f16_at(b, 0);
+                    // Low nibbles are the first half of the block, high nibbles the second
+                    let qs = &b[2..];
+                    qs.iter()
+                        .map(|q| q & 0xF)
+                        .chain(qs.iter().map(|q| q >> 4))
+                        .map(move |q| (q as f32 - 8.) * delta)
+                })
+                .collect(),
+            Self::Q4K => blocks
+                .flat_map(|b| {
+                    let (delta, min) = (f16_at(b, 0), f16_at(b, 2));
+                    let (scales, qs) = (&b[4..16], &b[16..]);
+                    // 4 chunks of 32 bytes, each holding two sub-blocks of 32 values with their own scale and min
+                    (0..4).flat_map(move |chunk| {
+                        let q = &qs[chunk * 32..(chunk + 1) * 32];
+                        let (s1, m1) = k_scale_min(2 * chunk, scales);
+                        let (s2, m2) = k_scale_min(2 * chunk + 1, scales);
+                        let (d1, m1) = (delta * s1 as f32, min * m1 as f32);
+                        let (d2, m2) = (delta * s2 as f32, min * m2 as f32);
+                        q.iter()
+                            .map(move |q| d1 * (q & 0xF) as f32 - m1)
+                            .chain(q.iter().map(move |q| d2 * (q >> 4) as f32 - m2))
+                    })
+                })
+                .collect(),
+            _ => panic!("Dequantizing {self:?} isn't supported"),
+        }
+    }
+}
+
+fn f16_at(bytes: &[u8], i: usize) -> f32 {
+    f16::from_le_bytes([bytes[i], bytes[i + 1]]).to_f32()
+}
+
+/// Unpack the 6 bit scale and min of a K-quant sub-block
+fn k_scale_min(j: usize, scales: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (scales[j] & 63, scales[j + 4] & 63)
+    } else {
+        (
+            (scales[j + 4] & 0xF) | ((scales[j - 4] >> 6) << 4),
+            (scales[j + 4] >> 4) | ((scales[j] >> 6) << 4),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    /// The dimensions of the tensor, innermost first
+    pub dims: Vec<usize>,
+    /// Offset from the start of the tensor data
+    pub offset: usize,
+    pub dtype: GgmlDType,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+
+    pub fn n_bytes(&self) -> usize {
+        self.n_elements() / self.dtype.block_size() * self.dtype.type_size()
+    }
+}
+
+#[derive(Debug)]
+pub struct Content {
+    pub magic: VersionedMagic,
+    pub metadata: HashMap<String, Value>,
+    pub tensor_infos: HashMap<String, TensorInfo>,
+    pub tensor_data_offset: u64,
+}
+
+impl Content {
+    pub fn read<R: Seek + Read>(reader: &mut R) -> Result<Self> {
+        let magic = VersionedMagic::read(reader)?;
+        let tensor_count = magic.read_len(reader)?;
+        let metadata_kv_count = magic.read_len(reader)?;
+
+        // Read metadata
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_kv_count {
+            let key = read_string(reader, &magic)?;
+            let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            metadata.insert(key, Value::read(reader, value_type, &magic)?);
+        }
+        // Read tensor infos
+        let mut tensor_infos = HashMap::new();
+        for _ in 0..tensor_count {
+            let tensor_name = read_string(reader, &magic)?;
+            let n_dimensions = reader.read_u32::<LittleEndian>()?;
+            let dims = (0..n_dimensions)
+                .map(|_| magic.read_len(reader))
+                .collect::<Result<Vec<_>>>()?;
+            let dtype = GgmlDType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            let offset = reader.read_u64::<LittleEndian>()? as usize;
+            tensor_infos.insert(
+                tensor_name,
+                TensorInfo {
+                    dims,
+                    offset,
+                    dtype,
+                },
+            );
+        }
+        let position = reader.stream_position()?;
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(Value::to_u64)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        Ok(Self {
+            magic,
+            metadata,
+            tensor_infos,
+            tensor_data_offset: position.div_ceil(alignment) * alignment,
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// The model architecture, like `llama`
+    pub fn architecture(&self) -> Option<&str> {
+        self.metadata.get("general.architecture")?.as_str()
+    }
+
+    /// Read the common transformer hyperparameters from the metadata
+    pub fn hyperparameters(&self) -> Result<Hyperparameters> {
+        let arch = self
+            .architecture()
+            .ok_or_else(|| invalid_data("Missing general.architecture".to_string()))?;
+        let get = |key: &str| self.metadata.get(&format!("{arch}.{key}"));
+        let get_usize = |key: &str| {
+            get(key)
+                .and_then(Value::to_u64)
+                .map(|v| v as usize)
+                .ok_or_else(|| invalid_data(format!("Missing {arch}.{key}")))
+        };
+        let n_heads = get_usize("attention.head_count")?;
+        let vocab_size = match get("vocab_size").and_then(Value::to_u64) {
+            Some(v) => v as usize,
+            None => self
+                .metadata
+                .get("tokenizer.ggml.tokens")
+                .and_then(Value::as_array)
+                .map(|t| t.len())
+                .ok_or_else(|| invalid_data("Missing vocab size".to_string()))?,
+        };
+        Ok(Hyperparameters {
+            architecture: arch.to_string(),
+            n_layers: get_usize("block_count")?,
+            hidden_dim: get_usize("embedding_length")?,
+            mlp_dim: get_usize("feed_forward_length")?,
+            n_heads,
+            n_kv_heads: get_usize("attention.head_count_kv").unwrap_or(n_heads),
+            vocab_size,
+            context_length: get_usize("context_length").ok(),
+            rope_theta: get("rope.freq_base")
+                .and_then(Value::to_f32)
+                .unwrap_or(10000.),
+            rms_norm_epsilon: get("attention.layer_norm_rms_epsilon").and_then(Value::to_f32),
+            expert_count: get_usize("expert_count").ok(),
+            expert_used_count: get_usize("expert_used_count").ok(),
+        })
+    }
+}
+
+/// The hyperparameters of a transformer model stored in a GGUF file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hyperparameters {
+    pub architecture: String,
+    pub n_layers: usize,
+    pub hidden_dim: usize,
+    pub mlp_dim: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub vocab_size: usize,
+    pub context_length: Option<usize>,
+    pub rope_theta: f32,
+    pub rms_norm_epsilon: Option<f32>,
+    /// The number of experts in each mixture of experts layer, for models like Mixtral
+    pub expert_count: Option<usize>,
+    /// The number of experts each token is routed to
+    pub expert_used_count: Option<usize>,
+}
+
+/// Load the weights of a `.gguf` file into a module's tensors, dequantizing them to f32. Module paths like
+/// `blk/0/attn_q/weight` are looked up as `blk.0.attn_q.weight`. The parsed file is returned so its metadata can be read.
+///
+/// The file is only read from when the graph is executed, so weights can be loaded before compiling.
+pub fn load<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<Content> {
+    load_with_names(path, model, graph, |p| p.replace('/', "."))
+}
+
+/// Same as `load`, with a custom mapping from module paths to tensor names in the file
+pub fn load_with_names<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+    name: impl Fn(&str) -> String,
+) -> Result<Content> {
+    let content = Content::from_file(&path)?;
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path_name, node) in s.state {
+        let tensor_name = name(&path_name);
+        let info = content
+            .tensor_infos
+            .get(&tensor_name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{tensor_name} not found")))?;
+        if let Some(n) = s.shapes[&path_name].n_elements().to_usize() {
+            if n != info.n_elements() {
+                return Err(invalid_data(format!(
+                    "{tensor_name} has dims {:?} in the file, which doesn't fit {path_name} ({n} elements)",
+                    info.dims
+                )));
+            }
+        }
+        if !info.dtype.can_dequantize() {
+            return Err(invalid_data(format!(
+                "{tensor_name} has unsupported dtype {:?}",
+                info.dtype
+            )));
+        }
+        if let Some(loading_node) = graph
+            .graph
+            .node_weight_mut(node)
+            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        {
+            let file = path.as_ref().to_owned();
+            let start = content.tensor_data_offset as usize + info.offset;
+            let (end, dtype) = (start + info.n_bytes(), info.dtype);
+            loading_node.1 = Box::new(move |_| {
+                let mmap = unsafe { Mmap::map(&File::open(&file).unwrap()).unwrap() };
+                vec![Tensor::new(dtype.dequantize(&mmap[start..end]))]
+            });
+        }
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{load, Content, GgmlDType, Hyperparameters, Value};
+    crate::test_imports!();
+
+    fn string(s: &str) -> Vec<u8> {
+        [&(s.len() as u64).to_le_bytes(), s.as_bytes()].concat()
+    }
+
+    /// Write a GGUF v3 file with u32/f32/string metadata and f32/f16/quantized tensors
+    fn write_gguf(
+        metadata: &[(&str, Value)],
+        tensors: &[(&str, &[usize], u32, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut out = b"GGUF".to_vec();
+        out.extend(3_u32.to_le_bytes());
+        out.extend((tensors.len() as u64).to_le_bytes());
+        out.extend((metadata.len() as u64).to_le_bytes());
+        for (key, value) in metadata {
+            out.extend(string(key));
+            match value {
+                Value::U32(v) => out.extend([&4_u32.to_le_bytes()[..], &v.to_le_bytes()].concat()),
+                Value::F32(v) => out.extend([&6_u32.to_le_bytes()[..], &v.to_le_bytes()].concat()),
+                Value::String(v) => out.extend([8_u32.to_le_bytes().to_vec(), string(v)].concat()),
+                _ => unimplemented!(),
+            }
+        }
+        let mut offset = 0;
+        for (name, dims, dtype, data) in tensors {
+            out.extend(string(name));
+            out.extend((dims.len() as u32).to_le_bytes());
+            dims.iter()
+                .for_each(|d| out.extend((*d as u64).to_le_bytes()));
+            out.extend(dtype.to_le_bytes());
+            out.extend((offset as u64).to_le_bytes());
+            offset += data.len().div_ceil(32) * 32;
+        }
+        for (_, _, _, data) in tensors {
+            out.resize(out.len().div_ceil(32) * 32, 0);
+            out.extend(data);
+        }
+        out
+    }
+
+    #[test]
+    fn test_gguf_dequantize() {
+        let delta = f16::from_f32(0.5).to_le_bytes();
+        // Q8_0: values are signed bytes times the delta
+        let q8 = [
+            &delta[..],
+            &(0..32).map(|i| (i as i8 - 16) as u8).collect::<Vec<_>>(),
+        ]
+        .concat();
+        assert_exact(
+            &GgmlDType::Q8_0.dequantize(&q8),
+            &(0..32).map(|i| (i - 16) as f32 * 0.5).collect::<Vec<_>>(),
+        );
+        // Q4_0: low nibbles then high nibbles, offset by 8
+        let q4 = [
+            &delta[..],
+            &(0..16).map(|i| i | (15 - i) << 4).collect::<Vec<u8>>(),
+        ]
+        .concat();
+        let expected = (0..16)
+            .chain((0..16).rev())
+            .map(|q| (q as f32 - 8.) * 0.5)
+            .collect::<Vec<_>>();
+        assert_exact(&GgmlDType::Q4_0.dequantize(&q4), &expected);
+        // Q4_K: sub-block j has scale j + 1 and min j, with a super-block delta of 0.5 and min of 0.25
+        let mut scales = [0_u8; 12];
+        for j in 0..8_u8 {
+            let (sc, m) = (j + 1, j);
+            if j < 4 {
+                scales[j as usize] = sc;
+                scales[j as usize + 4] = m;
+            } else {
+                // The low 4 bits go in the last 4 bytes, the high 2 bits in the top of the first 8
+                scales[j as usize + 4] = (sc & 0xF) | ((m & 0xF) << 4);
+                scales[j as usize - 4] |= (sc >> 4) << 6;
+                scales[j as usize] |= (m >> 4) << 6;
+            }
+        }
+        let qs = (0..128).map(|i| (i % 16) as u8 | (((i + 3) % 16) as u8) << 4);
+        let q4k = [
+            &delta[..],
+            &f16::from_f32(0.25).to_le_bytes(),
+            &scales,
+            &qs.collect::<Vec<_>>(),
+        ]
+        .concat();
+        let mut expected = vec![];
+        for chunk in 0..4 {
+            for (j, shift) in [(2 * chunk, 0), (2 * chunk + 1, 3)] {
+                expected.extend((0..32).map(|l| {
+                    0.5 * (j + 1) as f32 * ((chunk * 32 + l + shift) % 16) as f32 - 0.25 * j as f32
+                }));
+            }
+        }
+        assert_exact(&GgmlDType::Q4K.dequantize(&q4k), &expected);
+    }
+
+    #[test]
+    fn test_gguf_load() {
+        struct Model {
+            weight: GraphTensor<R2<2, 32>>,
+            norm: GraphTensor<R1<4>>,
+        }
+        impl SerializeModule for Model {
+            fn serialize(&self, s: &mut Serializer) {
+                s.tensor("blk/0/weight", self.weight);
+                s.tensor("norm", self.norm);
+            }
+        }
+
+        let q8_block = |d: f32| {
+            [
+                &f16::from_f32(d).to_le_bytes()[..],
+                &(0..32).map(|i| i as u8).collect::<Vec<_>>(),
+            ]
+            .concat()
+        };
+        let norm = [1_f32, 2., 3., 4.];
+        let file = write_gguf(
+            &[
+                ("general.architecture", Value::String("llama".to_string())),
+                ("llama.block_count", Value::U32(1)),
+                ("llama.embedding_length", Value::U32(32)),
+                ("llama.feed_forward_length", Value::U32(64)),
+                ("llama.attention.head_count", Value::U32(4)),
+                ("llama.attention.head_count_kv", Value::U32(2)),
+                ("llama.vocab_size", Value::U32(100)),
+                ("llama.rope.freq_base", Value::F32(500000.)),
+            ],
+            &[
+                (
+                    "blk.0.weight",
+                    &[32, 2],
+                    8,
+                    [q8_block(1.), q8_block(0.5)].concat(),
+                ),
+                (
+                    "norm",
+                    &[4],
+                    0,
+                    norm.iter().flat_map(|f| f.to_le_bytes()).collect(),
+                ),
+            ],
+        );
+        let content = Content::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(
+            content.hyperparameters().unwrap(),
+            Hyperparameters {
+                architecture: "llama".to_string(),
+                n_layers: 1,
+                hidden_dim: 32,
+                mlp_dim: 64,
+                n_heads: 4,
+                n_kv_heads: 2,
+                vocab_size: 100,
+                context_length: None,
+                rope_theta: 500000.,
+                rms_norm_epsilon: None,
+                expert_count: None,
+                expert_used_count: None,
+            }
+        );
+
+        let path = std::env::temp_dir().join(format!("luminal_gguf_{}.gguf", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let mut cx = Graph::new();
+        let model = Model {
+            weight: cx.named_tensor("Weight").keep(),
+            norm: cx.named_tensor("Norm").keep(),
+        };
+        load(&path, &model, &mut cx).unwrap();
+        cx.execute();
+        let expected = (0..32)
+            .map(|i| i as f32)
+            .chain((0..32).map(|i| i as f32 * 0.5))
+            .collect::<Vec<_>>();
+        assert_exact(&model.weight.data(), &expected);
+        assert_exact(&model.norm.data(), &norm);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+pub mod gguf;
+pub mod npy;
+pub mod onnx;
+pub mod pytorch;
+pub mod safetensors;
+mod zip;
+
+/// An error for a file or tensor whose contents can't be read
+pub(crate) fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// The files a checkpoint is stored in. A `.index.json` file lists the shards of a sharded checkpoint in its weight
+/// map, and any other file is the whole checkpoint.
+pub(crate) fn shard_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.extension().is_some_and(|e| e == "json") {
+        let index: serde_json::Value = serde_json::from_reader(File::open(path)?)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        index["weight_map"]
+            .as_object()
+            .ok_or_else(|| invalid_data(format!("{} has no weight map", path.display())))?
+            .values()
+            .map(|f| {
+                f.as_str()
+                    .map(|f| dir.join(f))
+                    .ok_or_else(|| invalid_data("Weight map values must be file names".to_string()))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|files| files.into_iter().unique().collect())
+    } else {
+        Ok(vec![path.to_path_buf()])
+    }
+}
+
+/// Tensors read from a file by name, each with its shape
+pub type TensorMap = FxHashMap<String, (Vec<usize>, Vec<f32>)>;
+
+/// Gather a module's weights with their shapes, named by the given mapping from module paths. The weights must
+/// currently be held by the graph as f32s on the CPU.
+pub(crate) fn module_tensors<M: SerializeModule>(
+    model: &M,
+    graph: &Graph,
+    name: impl Fn(&str) -> String,
+) -> Result<TensorMap> {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    let mut tensors = TensorMap::default();
+    for (path, node) in s.state {
+        let data = graph
+            .get_tensor_ref(node, 0)
+            .and_then(|t| t.f32_data())
+            .ok_or_else(|| invalid_data(format!("{path} isn't held by the graph as f32s")))?;
+        let shape = s.shapes[&path]
+            .shape()
+            .into_iter()
+            .map(|e| e.exec(&graph.dyn_map))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid_data(format!("{path} has an unknown dynamic dimension")))?;
+        tensors.insert(name(&path), (shape, data.into_owned()));
+    }
+    Ok(tensors)
+}
@@ -0,0 +1,410 @@
+//! Reading and writing tensors as NumPy `.npy` and `.npz` files, which makes it easy to cross-check outputs against a
+//! Python reference implementation while bringing up a model.
+//!
+//! Arrays of any float, integer or bool dtype are read as f32s, in either byte order or memory layout. Arrays are
+//! always written as little endian f32s.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use itertools::Itertools;
+
+use super::{
+    invalid_data, module_tensors,
+    zip::{read_zip, write_zip},
+    TensorMap,
+};
+use crate::{op::Function, prelude::*};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Read an `.npy` file as f32s along with its shape
+pub fn read_npy(path: impl AsRef<Path>) -> Result<(Vec<usize>, Vec<f32>)> {
+    parse_npy(&fs::read(path)?)
+}
+
+/// Write f32s to an `.npy` file with the given shape
+pub fn write_npy(path: impl AsRef<Path>, shape: &[usize], data: &[f32]) -> Result<()> {
+    fs::write(path, to_npy(shape, data)?)
+}
+
+/// Read every array in an `.npz` file by name, as written by `np.savez`. Files written by `np.savez_compressed`
+/// aren't supported.
+pub fn read_npz(path: impl AsRef<Path>) -> Result<TensorMap> {
+    let bytes = fs::read(path)?;
+    read_zip(&bytes)?
+        .into_iter()
+        .map(|(name, entry)| {
+            let array = parse_npy(&bytes[entry.stored()?])
+                .map_err(|e| Error::new(e.kind(), format!("{name}: {e}")))?;
+            Ok((
+                name.strip_suffix(".npy").unwrap_or(&name).to_string(),
+                array,
+            ))
+        })
+        .collect()
+}
+
+/// Write arrays to an `.npz` file, which `np.load` reads back as a dict
+pub fn write_npz(path: impl AsRef<Path>, tensors: &TensorMap) -> Result<()> {
+    let files = tensors
+        .iter()
+        .sorted_by_key(|(name, _)| *name)
+        .map(|(name, (shape, data))| Ok((format!("{name}.npy"), to_npy(shape, data)?)))
+        .collect::<Result<Vec<_>>>()?;
+    fs::write(path, write_zip(&files)?)
+}
+
+/// Set a tensor to the contents of an `.npy` file. Dynamic dimensions take their sizes from the file.
+pub fn load_tensor<S: Shape>(path: impl AsRef<Path>, tensor: GraphTensor<S>) -> Result<()> {
+    let (shape, data) = read_npy(path)?;
+    let dims = S::realized_shape();
+    if dims.len() != shape.len()
+        || dims
+            .iter()
+            .zip(&shape)
+            .any(|(d, s)| d.to_usize().is_some_and(|d| d != *s))
+    {
+        return Err(invalid_data(format!(
+            "The file has shape {shape:?}, which doesn't fit {dims:?}"
+        )));
+    }
+    tensor.set_dyn(data, &shape);
+    Ok(())
+}
+
+/// Save a tensor's data to an `.npy` file. The tensor must be held by the graph, so mark it with `keep` and execute
+/// first.
+pub fn save_tensor<S: Shape>(path: impl AsRef<Path>, tensor: GraphTensor<S>) -> Result<()> {
+    let graph = tensor.graph();
+    if graph.get_tensor_ref(tensor.id, 0).is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The tensor isn't held by the graph",
+        ));
+    }
+    let shape = tensor
+        .shape
+        .shape()
+        .into_iter()
+        .map(|e| e.exec(&graph.dyn_map))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid_data("The tensor has an unknown dynamic dimension".to_string()))?;
+    write_npy(path, &shape, &tensor.data())
+}
+
+/// Load a module's weights from an `.npz` file. Module paths like `layers/0/weight` are looked up as
+/// `layers.0.weight`.
+pub fn load<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<()> {
+    let mut tensors = read_npz(path)?;
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path, node) in s.state.into_iter().sorted() {
+        let name = path.replace('/', ".");
+        let (shape, data) = tensors
+            .remove(&name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{name} not found")))?;
+        if let Some(n) = s.shapes[&path].n_elements().to_usize() {
+            if n != data.len() {
+                return Err(invalid_data(format!(
+                    "{name} has shape {shape:?} in the file, which doesn't fit {path} ({n} elements)"
+                )));
+            }
+        }
+        if let Some(loading_node) = graph
+            .graph
+            .node_weight_mut(node)
+            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        {
+            loading_node.1 = Box::new(move |_| vec![Tensor::new(data.clone())]);
+        }
+    }
+    Ok(())
+}
+
+/// Save a module's weights to an `.npz` file, named the way `load` looks them up. The weights must currently be held
+/// by the graph as f32s on the CPU, so mark them with `keep` and execute first.
+pub fn save<M: SerializeModule>(path: impl AsRef<Path>, model: &M, graph: &Graph) -> Result<()> {
+    write_npz(
+        path,
+        &module_tensors(model, graph, |p| p.replace('/', "."))?,
+    )
+}
+
+/// Encode an array in the version 1.0 format, with the header padded so the data is 64 byte aligned
+fn to_npy(shape: &[usize], data: &[f32]) -> Result<Vec<u8>> {
+    if shape.iter().product::<usize>() != data.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} elements don't fit shape {shape:?}", data.len()),
+        ));
+    }
+    let shape = match shape {
+        [d] => format!("({d},)"),
+        _ => format!("({})", shape.iter().join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+    let padding = 63 - (MAGIC.len() + 4 + header.len()) % 64;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    let mut bytes = [MAGIC, &[1, 0]].concat();
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    bytes.extend(data.iter().flat_map(|f| f.to_le_bytes()));
+    Ok(bytes)
+}
+
+/// Decode an array in any version of the format
+fn parse_npy(bytes: &[u8]) -> Result<(Vec<usize>, Vec<f32>)> {
+    if !bytes.starts_with(MAGIC) || bytes.len() < 10 {
+        return Err(invalid_data("Not an .npy file".to_string()));
+    }
+    // Version 1 has a 2 byte header length, and later versions 4 bytes
+    let (header_start, header_len) = if bytes[6] == 1 {
+        (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize)
+    } else {
+        let len = bytes
+            .get(8..12)
+            .ok_or_else(|| invalid_data("The header is truncated".to_string()))?;
+        (12, u32::from_le_bytes(len.try_into().unwrap()) as usize)
+    };
+    let header = bytes
+        .get(header_start..header_start + header_len)
+        .map(String::from_utf8_lossy)
+        .ok_or_else(|| invalid_data("The header is truncated".to_string()))?;
+    let data = &bytes[header_start + header_len..];
+
+    let descr = header_value(&header, "descr")?.trim_matches(|c| c == '\'' || c == '"');
+    let fortran_order = header_value(&header, "fortran_order")? == "True";
+    let shape = header_value(&header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.trim_end_matches('L').parse::<usize>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| invalid_data(format!("Invalid shape in header {header}")))?;
+
+    let (big_endian, kind) = match descr.split_at_checked(1) {
+        Some(("<" | "|" | "=", kind)) => (false, kind),
+        Some((">", kind)) => (true, kind),
+        _ => (false, descr),
+    };
+    let read: fn(&[u8]) -> f32 = match kind {
+        "f8" => |b| f64::from_le_bytes(b.try_into().unwrap()) as f32,
+        "f4" => |b| f32::from_le_bytes(b.try_into().unwrap()),
+        "f2" => |b| f16::from_le_bytes([b[0], b[1]]).to_f32(),
+        "i8" => |b| i64::from_le_bytes(b.try_into().unwrap()) as f32,
+        "i4" => |b| i32::from_le_bytes(b.try_into().unwrap()) as f32,
+        "i2" => |b| i16::from_le_bytes([b[0], b[1]]) as f32,
+        "i1" => |b| b[0] as i8 as f32,
+        "u8" => |b| u64::from_le_bytes(b.try_into().unwrap()) as f32,
+        "u4" => |b| u32::from_le_bytes(b.try_into().unwrap()) as f32,
+        "u2" => |b| u16::from_le_bytes([b[0], b[1]]) as f32,
+        "u1" => |b| b[0] as f32,
+        "b1" => |b| (b[0] != 0) as i32 as f32,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported dtype {descr}"),
+            ))
+        }
+    };
+    let size = kind[1..].parse::<usize>().unwrap();
+    let n_elements = shape.iter().product::<usize>();
+    if data.len() < n_elements * size {
+        return Err(invalid_data(format!(
+            "The data is truncated, expected {n_elements} elements of shape {shape:?}"
+        )));
+    }
+    let values = data
+        .chunks_exact(size)
+        .take(n_elements)
+        .map(|chunk| {
+            if big_endian {
+                read(&chunk.iter().rev().copied().collect::<Vec<_>>())
+            } else {
+                read(chunk)
+            }
+        })
+        .collect::<Vec<_>>();
+    if !fortran_order || shape.len() < 2 {
+        return Ok((shape, values));
+    }
+    // Fortran order arrays are stored with the first dimension changing fastest
+    let strides = shape
+        .iter()
+        .scan(1, |stride, d| {
+            let s = *stride;
+            *stride *= d;
+            Some(s)
+        })
+        .collect::<Vec<_>>();
+    let values = (0..n_elements)
+        .map(|mut i| {
+            let mut index = 0;
+            for (d, s) in shape.iter().zip(&strides).rev() {
+                index += (i % d) * s;
+                i /= d;
+            }
+            values[index]
+        })
+        .collect();
+    Ok((shape, values))
+}
+
+/// Find the value of a key in the Python dict literal of a header
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str> {
+    let start = ["'", "\""]
+        .iter()
+        .find_map(|q| header.find(&format!("{q}{key}{q}:")))
+        .ok_or_else(|| invalid_data(format!("The header has no {key}")))?;
+    let value = header[start + key.len() + 3..].trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')').map(|e| e + 1)
+    } else {
+        value.find([',', '}'])
+    };
+    Ok(value[..end.unwrap_or(value.len())].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, load_tensor, parse_npy, read_npz, save, save_tensor, to_npy};
+    crate::test_imports!();
+
+    struct Model {
+        weight: GraphTensor<R2<2, 3>>,
+        inner: Inner,
+    }
+    struct Inner {
+        bias: GraphTensor<R1<3>>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.module("inner", &self.inner);
+        }
+    }
+    impl SerializeModule for Inner {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("bias", self.bias);
+        }
+    }
+    impl InitModule for Model {
+        fn initialize(cx: &mut Graph) -> Self {
+            Self {
+                weight: cx.named_tensor("Weight").keep(),
+                inner: Inner {
+                    bias: cx.named_tensor("Bias").keep(),
+                },
+            }
+        }
+    }
+
+    /// An array as NumPy writes it, with the header padded to 64 bytes
+    fn npy(header: &str, data: &[u8]) -> Vec<u8> {
+        let header = format!("{header:<117}\n");
+        [b"\x93NUMPY\x01\x00", &[118, 0][..], header.as_bytes(), data].concat()
+    }
+
+    #[test]
+    fn test_npy_formats() {
+        let bytes = to_npy(&[2, 3], &[1., 2., 3., 4., 5., 6.]).unwrap();
+        assert_eq!(bytes.len() % 64, 24);
+        assert_eq!(bytes[bytes.len() - 25], b'\n');
+        let (shape, data) = parse_npy(&bytes).unwrap();
+        assert_eq!(shape, vec![2, 3]);
+        assert_exact(&data, &[1., 2., 3., 4., 5., 6.]);
+
+        // Big endian ints in fortran order
+        let data = [1_i32, 4, 2, 5, 3, 6].map(i32::to_be_bytes).concat();
+        let (shape, data) = parse_npy(&npy(
+            "{'descr': '>i4', 'fortran_order': True, 'shape': (2, 3), }",
+            &data,
+        ))
+        .unwrap();
+        assert_eq!(shape, vec![2, 3]);
+        assert_exact(&data, &[1., 2., 3., 4., 5., 6.]);
+
+        // Half precision scalars and bools
+        let (shape, data) = parse_npy(&npy(
+            "{'descr': '<f2', 'fortran_order': False, 'shape': (), }",
+            &f16::from_f32(0.5).to_le_bytes(),
+        ))
+        .unwrap();
+        assert!(shape.is_empty());
+        assert_exact(&data, &[0.5]);
+        let (shape, data) = parse_npy(&npy(
+            "{'descr': '|b1', 'fortran_order': False, 'shape': (3,), }",
+            &[1, 0, 1],
+        ))
+        .unwrap();
+        assert_eq!(shape, vec![3]);
+        assert_exact(&data, &[1., 0., 1.]);
+
+        assert!(parse_npy(&npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (4,), }",
+            &[0; 8],
+        ))
+        .is_err());
+        assert!(parse_npy(&npy(
+            "{'descr': '<c8', 'fortran_order': False, 'shape': (1,), }",
+            &[0; 8],
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_npy_tensors() {
+        let dir = temp_dir("npy_tensors");
+        let file = dir.join("a.npy");
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<(Dyn<'a'>, LConst<2>)>()
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], &[3, 2]);
+        let b = (a * 2.).retrieve();
+        cx.execute();
+        save_tensor(&file, b).unwrap();
+
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'a'>, LConst<2>)>();
+        load_tensor(&file, a).unwrap();
+        let b = a.sum_reduce::<_, LAxis<1>>().retrieve();
+        cx.execute();
+        assert_exact(&b.data(), &[6., 14., 22.]);
+        assert!(load_tensor(&file, cx.tensor::<R2<2, 3>>()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_npz_module() {
+        let dir = temp_dir("npz_module");
+        let file = dir.join("model.npz");
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        model.weight.set(vec![1., 2., 3., 4., 5., 6.]);
+        model.inner.bias.set(vec![0.5, -1., 2.]);
+        cx.execute();
+        save(&file, &model, &cx).unwrap();
+        let tensors = read_npz(&file).unwrap();
+        assert_eq!(tensors["inner.bias"].0, vec![3]);
+        assert_eq!(tensors["weight"].0, vec![2, 3]);
+
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        load(&file, &model, &mut cx).unwrap();
+        cx.execute();
+        assert_exact(&model.weight.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&model.inner.bias.data(), &[0.5, -1., 2.]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1252 @@
+//! Export graphs to ONNX, so models authored in luminal can be deployed through ONNX Runtime, TensorRT or any other
+//! ONNX backend.
+//!
+//! Spec: https://github.com/onnx/onnx/blob/main/docs/IR.md
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+};
+
+use petgraph::{algo::toposort, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    error::{panic_message, UNSET_TENSOR},
+    op::{
+        ArgTopK, Cast, Concat, Constant, ConstantValue, Contiguous, CumSum, DType,
+        ElementwiseInstruction, Erf, Exp2, Function, FusedElementwise, Gather, LessThan, Log2,
+        LogSoftmax, MaxReduce, Mod, ProdReduce, Recip, Sin, Softmax, Sqrt, SumReduce, TopK, Where,
+    },
+    prelude::*,
+};
+
+/// The ONNX operator set exported graphs target
+pub const OPSET_VERSION: i64 = 18;
+/// The ONNX IR version that goes with `OPSET_VERSION`
+const IR_VERSION: i64 = 8;
+
+// TensorProto.DataType
+const FLOAT: i64 = 1;
+const INT64: i64 = 7;
+const BOOL: i64 = 9;
+const FLOAT16: i64 = 10;
+const BFLOAT16: i64 = 16;
+
+impl Graph {
+    /// Export the part of the graph computing `outputs` from `inputs` to an ONNX model file.
+    ///
+    /// The inputs become the model's inputs, named after their tensors, and the outputs are named `output_0`,
+    /// `output_1` and so on. Any other tensor the outputs depend on must hold data, like the weights, which is stored
+    /// in the model. Dynamic dimensions become symbolic dimensions named after their symbol, so a `Dyn<'b'>` batch
+    /// dimension can take any size at runtime. Every dynamic dimension must be the size of some input dimension.
+    ///
+    /// Only luminal's own ops can be exported, so export before compiling for a backend, whose kernels have no ONNX
+    /// equivalent.
+    pub fn export_onnx(
+        &self,
+        path: impl AsRef<Path>,
+        inputs: impl ToIds,
+        outputs: impl ToIds,
+    ) -> Result<()> {
+        fs::write(path, self.to_onnx(inputs, outputs)?)
+    }
+
+    /// Encode the part of the graph computing `outputs` from `inputs` as an ONNX model. See `export_onnx`.
+    pub fn to_onnx(&self, inputs: impl ToIds, outputs: impl ToIds) -> Result<Vec<u8>> {
+        let graph = Exporter::new(self).export(&inputs.to_ids(), &outputs.to_ids())?;
+        Ok(Message::default()
+            .int(1, IR_VERSION)
+            .string(2, "luminal")
+            .message(7, graph)
+            .message(8, Message::default().string(1, "").int(2, OPSET_VERSION))
+            .0)
+    }
+}
+
+/// A protobuf message being encoded
+#[derive(Debug, Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(mut self, mut value: u64) -> Self {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+        self
+    }
+
+    fn key(self, field: u64, wire_type: u64) -> Self {
+        self.varint(field << 3 | wire_type)
+    }
+
+    fn int(self, field: u64, value: i64) -> Self {
+        self.key(field, 0).varint(value as u64)
+    }
+
+    fn bytes(mut self, field: u64, bytes: &[u8]) -> Self {
+        self = self.key(field, 2).varint(bytes.len() as u64);
+        self.0.extend(bytes);
+        self
+    }
+
+    fn string(self, field: u64, value: &str) -> Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(self, field: u64, message: Message) -> Self {
+        self.bytes(field, &message.0)
+    }
+}
+
+/// A node attribute
+enum Attribute {
+    Int(&'static str, i64),
+    Ints(&'static str, Vec<i64>),
+}
+
+impl Attribute {
+    fn encode(self) -> Message {
+        match self {
+            Attribute::Int(name, value) => {
+                Message::default().string(1, name).int(3, value).int(20, 2)
+            }
+            Attribute::Ints(name, values) => values
+                .into_iter()
+                .fold(Message::default().string(1, name), |m, v| m.int(8, v))
+                .int(20, 7),
+        }
+    }
+}
+
+/// The ONNX element type of a dtype. Indexes and masks are stored as f32 data, so they stay floats.
+fn elem_type(dtype: DType) -> i64 {
+    match dtype {
+        DType::F16 => FLOAT16,
+        DType::Bf16 => BFLOAT16,
+        DType::F32 | DType::U32 | DType::Bool => FLOAT,
+    }
+}
+
+fn tensor_proto(name: &str, dims: &[i64], data_type: i64, raw_data: Vec<u8>) -> Message {
+    dims.iter()
+        .fold(Message::default(), |m, d| m.int(1, *d))
+        .int(2, data_type)
+        .string(8, name)
+        .bytes(9, &raw_data)
+}
+
+fn value_info(name: &str, data_type: i64, shape: &[BigExpression]) -> Message {
+    let shape = shape.iter().fold(Message::default(), |m, dim| {
+        m.message(
+            1,
+            match dim.to_usize() {
+                Some(n) => Message::default().int(1, n as i64),
+                None => Message::default().string(2, &dim.to_string()),
+            },
+        )
+    });
+    let tensor_type = Message::default().int(1, data_type).message(2, shape);
+    Message::default()
+        .string(1, name)
+        .message(2, Message::default().message(1, tensor_type))
+}
+
+fn unsupported(message: String) -> Error {
+    Error::new(ErrorKind::Unsupported, message)
+}
+
+/// Translates graph nodes into ONNX nodes one at a time, in topological order
+struct Exporter<'a> {
+    graph: &'a Graph,
+    nodes: Vec<Message>,
+    initializers: Vec<Message>,
+    /// The ONNX value holding each exported node's output
+    values: FxHashMap<NodeIndex, String>,
+    /// The logical shape of each exported node's output, which is how it's laid out in memory
+    shapes: FxHashMap<NodeIndex, Vec<BigExpression>>,
+    types: FxHashMap<NodeIndex, i64>,
+    /// The model input dimension each dynamic dimension is read from
+    dim_sources: FxHashMap<char, (String, usize)>,
+    /// 1 element int64 values holding the size of each dynamic dimension, once one is needed
+    dim_values: FxHashMap<char, String>,
+    n_values: usize,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(graph: &'a Graph) -> Self {
+        Self {
+            graph,
+            nodes: vec![],
+            initializers: vec![],
+            values: FxHashMap::default(),
+            shapes: FxHashMap::default(),
+            types: FxHashMap::default(),
+            dim_sources: FxHashMap::default(),
+            dim_values: FxHashMap::default(),
+            n_values: 0,
+        }
+    }
+
+    fn export(mut self, inputs: &[NodeIndex], outputs: &[NodeIndex]) -> Result<Message> {
+        // Only export what the outputs depend on, stopping at the inputs
+        let mut needed = FxHashSet::default();
+        let mut stack = outputs.to_vec();
+        while let Some(node) = stack.pop() {
+            if needed.insert(node) && !inputs.contains(&node) {
+                stack.extend(self.graph.get_sources(node).into_iter().map(|(n, _, _)| n));
+            }
+        }
+        let order = toposort(&self.graph.graph, None).map_err(|c| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                LuminalError::Cycle(c.node_id()).to_string(),
+            )
+        })?;
+
+        let mut graph_inputs = vec![];
+        let mut names = FxHashSet::default();
+        for &input in inputs {
+            let name = match self.graph.try_get_op::<Function>(input) {
+                Some(Function(name, _)) => name.trim_end_matches(" Load").to_string(),
+                None => format!("input_{}", input.index()),
+            };
+            let name = if names.contains(&name) {
+                format!("{name}_{}", input.index())
+            } else {
+                name
+            };
+            names.insert(name.clone());
+            let shape = self.stored_shape(input)?;
+            let data_type = elem_type(self.graph.dtype(input));
+            graph_inputs.push(value_info(&name, data_type, &shape));
+            for (axis, dim) in shape.iter().enumerate() {
+                if let [Term::Var(c)] = dim.terms[..] {
+                    self.dim_sources.entry(c).or_insert((name.clone(), axis));
+                }
+            }
+            self.values.insert(input, name);
+            self.shapes.insert(input, shape);
+            self.types.insert(input, data_type);
+        }
+        for node in order {
+            if needed.contains(&node) && !self.values.contains_key(&node) {
+                self.export_node(node)?;
+            }
+        }
+
+        let mut graph_outputs = vec![];
+        for (i, output) in outputs.iter().enumerate() {
+            let name = format!("output_{i}");
+            let value = self.values[output].clone();
+            self.add_node("Identity", &[&value], vec![], &[&name]);
+            graph_outputs.push(value_info(&name, self.types[output], &self.shapes[output]));
+        }
+
+        let mut graph = Message::default();
+        for node in self.nodes {
+            graph = graph.message(1, node);
+        }
+        graph = graph.string(2, "luminal");
+        for initializer in self.initializers {
+            graph = graph.message(5, initializer);
+        }
+        for input in graph_inputs {
+            graph = graph.message(11, input);
+        }
+        for output in graph_outputs {
+            graph = graph.message(12, output);
+        }
+        Ok(graph)
+    }
+
+    /// The shape a tensor loaded into the graph is stored with, read from how its consumers view it
+    fn stored_shape(&self, node: NodeIndex) -> Result<Vec<BigExpression>> {
+        self.graph
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .find_map(|e| e.weight().as_data())
+            .map(|(_, _, shape)| {
+                (0..shape.len())
+                    .filter(|i| !shape.fake[*i])
+                    .map(|i| shape.dims[i].big())
+                    .collect()
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Node {} has no consumers to read its shape from",
+                        node.index()
+                    ),
+                )
+            })
+    }
+
+    fn fresh(&mut self, op_type: &str) -> String {
+        self.n_values += 1;
+        format!("{op_type}_{}", self.n_values)
+    }
+
+    fn add_node(
+        &mut self,
+        op_type: &str,
+        inputs: &[&str],
+        attributes: Vec<Attribute>,
+        outputs: &[&str],
+    ) {
+        let mut node = Message::default();
+        for input in inputs {
+            node = node.string(1, input);
+        }
+        for output in outputs {
+            node = node.string(2, output);
+        }
+        node = node.string(3, outputs[0]).string(4, op_type);
+        for attribute in attributes {
+            node = node.message(5, attribute.encode());
+        }
+        self.nodes.push(node);
+    }
+
+    /// Add a node with a single output, returning the output's name
+    fn node(&mut self, op_type: &str, inputs: &[&str], attributes: Vec<Attribute>) -> String {
+        let output = self.fresh(op_type);
+        self.add_node(op_type, inputs, attributes, &[&output]);
+        output
+    }
+
+    fn initializer(&mut self, dims: &[i64], data_type: i64, raw_data: Vec<u8>) -> String {
+        let name = self.fresh("Initializer");
+        self.initializers
+            .push(tensor_proto(&name, dims, data_type, raw_data));
+        name
+    }
+
+    fn cast(&mut self, value: &str, from: i64, to: i64) -> String {
+        if from == to {
+            value.to_string()
+        } else {
+            self.node("Cast", &[value], vec![Attribute::Int("to", to)])
+        }
+    }
+
+    /// A scalar float constant of an element type
+    fn scalar(&mut self, value: f32, data_type: i64) -> String {
+        let scalar = self.initializer(&[], FLOAT, value.to_le_bytes().to_vec());
+        self.cast(&scalar, FLOAT, data_type)
+    }
+
+    /// A 1D int64 value holding some sizes, which may depend on dynamic dimensions
+    fn int64s(&mut self, values: &[BigExpression]) -> Result<String> {
+        if let Some(values) = values
+            .iter()
+            .map(|v| v.to_usize())
+            .collect::<Option<Vec<_>>>()
+        {
+            let data = values.iter().flat_map(|v| (*v as i64).to_le_bytes());
+            return Ok(self.initializer(&[values.len() as i64], INT64, data.collect()));
+        }
+        let values = values
+            .iter()
+            .map(|v| self.expression(v))
+            .collect::<Result<Vec<_>>>()?;
+        let values = values.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+        Ok(self.node("Concat", &values, vec![Attribute::Int("axis", 0)]))
+    }
+
+    /// Compute an expression over dynamic dimensions into a 1 element int64 value
+    fn expression(&mut self, expression: &BigExpression) -> Result<String> {
+        let mut stack: Vec<String> = vec![];
+        for term in &expression.terms {
+            let value = match *term {
+                Term::Num(n) => self.initializer(&[1], INT64, (n as i64).to_le_bytes().to_vec()),
+                Term::Var(c) => self.dim_value(c)?,
+                op => {
+                    let (a, b) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    let (a, b) = (a.as_str(), b.as_str());
+                    match op {
+                        Term::Add => self.node("Add", &[a, b], vec![]),
+                        Term::Sub => self.node("Sub", &[a, b], vec![]),
+                        Term::Mul => self.node("Mul", &[a, b], vec![]),
+                        Term::Div => self.node("Div", &[a, b], vec![]),
+                        Term::Mod => self.node("Mod", &[a, b], vec![]),
+                        Term::Min => self.node("Min", &[a, b], vec![]),
+                        Term::Max => self.node("Max", &[a, b], vec![]),
+                        Term::Lt | Term::Gte => {
+                            let less = self.node("Less", &[a, b], vec![]);
+                            let result = if op == Term::Gte {
+                                self.node("Not", &[&less], vec![])
+                            } else {
+                                less
+                            };
+                            self.cast(&result, BOOL, INT64)
+                        }
+                        _ => {
+                            let op_type = if op == Term::And { "And" } else { "Or" };
+                            let a = self.cast(a, INT64, BOOL);
+                            let b = self.cast(b, INT64, BOOL);
+                            let result = self.node(op_type, &[&a, &b], vec![]);
+                            self.cast(&result, BOOL, INT64)
+                        }
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        Ok(stack.pop().unwrap())
+    }
+
+    /// A 1 element int64 value holding the size of a dynamic dimension, read from the shape of an input
+    fn dim_value(&mut self, dim: char) -> Result<String> {
+        if let Some(value) = self.dim_values.get(&dim) {
+            return Ok(value.clone());
+        }
+        let (input, axis) = self.dim_sources.get(&dim).cloned().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Dynamic dimension {dim} isn't the size of any input dimension"),
+            )
+        })?;
+        let value = self.node(
+            "Shape",
+            &[&input],
+            vec![
+                Attribute::Int("start", axis as i64),
+                Attribute::Int("end", axis as i64 + 1),
+            ],
+        );
+        self.dim_values.insert(dim, value.clone());
+        Ok(value)
+    }
+
+    /// Read a node's output through a shape tracker, giving a value with the tracker's logical shape
+    fn view(&mut self, node: NodeIndex, shape: &ShapeTracker) -> Result<String> {
+        let mut value = self.values[&node].clone();
+        let stored = &self.shapes[&node];
+        let physical = (0..shape.len())
+            .filter(|i| !shape.fake[*i])
+            .map(|i| shape.dims[i].big())
+            .collect::<Vec<_>>();
+        if physical != *stored {
+            // Views can reinterpret the stored elements with a different shape, sometimes covering more or fewer of
+            // them than there are, with the extra elements masked out
+            let n_stored = stored.iter().cloned().product::<BigExpression>().simplify();
+            let n_physical = physical
+                .iter()
+                .cloned()
+                .product::<BigExpression>()
+                .simplify();
+            if n_stored != n_physical {
+                let flat = self.int64s(&[BigExpression::from(-1)])?;
+                value = self.node("Reshape", &[&value, &flat], vec![]);
+                let pads =
+                    self.int64s(&[0.into(), (n_physical.clone() - n_stored).max(0).simplify()])?;
+                value = self.node("Pad", &[&value, &pads], vec![]);
+                let (starts, ends) = (self.int64s(&[0.into()])?, self.int64s(&[n_physical])?);
+                value = self.node("Slice", &[&value, &starts, &ends], vec![]);
+            }
+            let physical = self.int64s(&physical)?;
+            value = self.node("Reshape", &[&value, &physical], vec![]);
+        }
+
+        // Pad, then slice, the real dimensions
+        let real = (0..shape.len())
+            .filter(|i| !shape.fake[*i])
+            .collect::<Vec<_>>();
+        if shape.is_padded() {
+            let (begins, ends): (Vec<_>, Vec<_>) = real
+                .iter()
+                .map(|i| (shape.padding[*i].0.big(), shape.padding[*i].1.big()))
+                .unzip();
+            let pads = self.int64s(&[begins, ends].concat())?;
+            value = self.node("Pad", &[&value, &pads], vec![]);
+        }
+        if shape.is_sliced() {
+            let (starts, ends): (Vec<_>, Vec<_>) = real
+                .iter()
+                .map(|i| (shape.mask[*i].0.big(), shape.mask[*i].1.big()))
+                .unzip();
+            let (starts, ends) = (self.int64s(&starts)?, self.int64s(&ends)?);
+            value = self.node("Slice", &[&value, &starts, &ends], vec![]);
+        }
+
+        // Put in the fake dimensions, then permute and broadcast them out
+        let fakes = (0..shape.len())
+            .filter(|i| shape.fake[*i])
+            .map(|i| i.into())
+            .collect::<Vec<BigExpression>>();
+        if !fakes.is_empty() {
+            let axes = self.int64s(&fakes)?;
+            value = self.node("Unsqueeze", &[&value, &axes], vec![]);
+        }
+        if shape.indexes.iter().enumerate().any(|(a, b)| a != *b) {
+            let perm = shape.indexes.iter().map(|i| *i as i64).collect();
+            value = self.node("Transpose", &[&value], vec![Attribute::Ints("perm", perm)]);
+        }
+        if !fakes.is_empty() {
+            let logical = self.int64s(&shape.shape())?;
+            value = self.node("Expand", &[&value, &logical], vec![]);
+        }
+        Ok(value)
+    }
+
+    fn export_node(&mut self, node: NodeIndex) -> Result<()> {
+        let op = self.graph.graph.node_weight(node).unwrap();
+        let sources = self.graph.get_sources(node);
+        let data_type = elem_type(self.graph.dtype(node));
+
+        if let Some(Function(name, load)) = op.as_any().downcast_ref::<Function>() {
+            // Tensors loaded into the graph that aren't inputs have their data stored in the model
+            if !sources.is_empty() {
+                return Err(unsupported(format!(
+                    "{name} (node {}) is a custom function",
+                    node.index()
+                )));
+            }
+            let loaded;
+            let tensor = match self.graph.get_tensor_ref(node, 0) {
+                Some(tensor) => tensor,
+                None => {
+                    loaded =
+                        catch_unwind(AssertUnwindSafe(|| load(vec![]))).map_err(|payload| {
+                            let message = panic_message(&*payload);
+                            let message = if message == UNSET_TENSOR {
+                                format!(
+                                    "{name} (node {}) isn't an input and holds no data",
+                                    node.index()
+                                )
+                            } else {
+                                format!("Loading {name} (node {}) failed: {message}", node.index())
+                            };
+                            Error::new(ErrorKind::InvalidInput, message)
+                        })?;
+                    &loaded[0]
+                }
+            };
+            let data = tensor.f32_data().ok_or_else(|| {
+                unsupported(format!(
+                    "{name} (node {}) isn't held on the CPU",
+                    node.index()
+                ))
+            })?;
+            let shape = self
+                .stored_shape(node)?
+                .into_iter()
+                .map(|d| d.exec(&self.graph.dyn_map).map(|d| d as i64))
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or(vec![data.len() as i64]);
+            let raw = data.iter().flat_map(|f| f.to_le_bytes()).collect();
+            let value = self.initializer(&shape, FLOAT, raw);
+            let value = self.cast(&value, FLOAT, data_type);
+            self.values.insert(node, value);
+            self.shapes
+                .insert(node, shape.iter().map(|d| (*d as usize).into()).collect());
+            self.types.insert(node, data_type);
+            return Ok(());
+        }
+
+        if let Some((_, output, _)) = sources.iter().find(|(_, o, _)| *o != 0) {
+            return Err(unsupported(format!(
+                "{op:?} (node {}) reads output {output} of a node with several outputs",
+                node.index()
+            )));
+        }
+        let mut inputs = vec![];
+        for (source, _, shape) in &sources {
+            let value = self.view(*source, shape)?;
+            inputs.push(self.cast(&value, self.types[source], data_type));
+        }
+        let inputs = inputs.iter().map(|i| i.as_str()).collect::<Vec<_>>();
+        let mut shape = sources
+            .first()
+            .map(|(_, _, s)| s.shape())
+            .unwrap_or_default();
+
+        let op = op.as_any();
+        let output = if let Some(Constant(value, _)) = op.downcast_ref::<Constant>() {
+            match value {
+                ConstantValue::Float(f) => self.scalar(*f, data_type),
+                ConstantValue::Expression(e) => {
+                    let value = self.expression(e)?;
+                    let value = self.cast(&value, INT64, data_type);
+                    let scalar = self.int64s(&[])?;
+                    self.node("Reshape", &[&value, &scalar], vec![])
+                }
+            }
+        } else if op.is::<Contiguous>() {
+            self.node("Identity", &inputs, vec![])
+        } else if op.is::<Log2>() {
+            let ln = self.node("Log", &inputs, vec![]);
+            let scale = self.scalar(std::f32::consts::LOG2_E, data_type);
+            self.node("Mul", &[&ln, &scale], vec![])
+        } else if op.is::<Exp2>() {
+            let scale = self.scalar(std::f32::consts::LN_2, data_type);
+            let scaled = self.node("Mul", &[inputs[0], &scale], vec![]);
+            self.node("Exp", &[&scaled], vec![])
+        } else if op.is::<Sin>() {
+            self.node("Sin", &inputs, vec![])
+        } else if op.is::<Recip>() {
+            self.node("Reciprocal", &inputs, vec![])
+        } else if op.is::<Sqrt>() {
+            self.node("Sqrt", &inputs, vec![])
+        } else if op.is::<Erf>() {
+            self.node("Erf", &inputs, vec![])
+        } else if op.is::<Cast>() {
+            // The input was already cast to the output's element type
+            self.node("Identity", &inputs, vec![])
+        } else if op.is::<Add>() {
+            self.node("Add", &inputs, vec![])
+        } else if op.is::<Mul>() {
+            self.node("Mul", &inputs, vec![])
+        } else if op.is::<Mod>() {
+            self.node("Mod", &inputs, vec![Attribute::Int("fmod", 1)])
+        } else if op.is::<LessThan>() {
+            let less = self.node("Less", &inputs, vec![]);
+            self.cast(&less, BOOL, data_type)
+        } else if op.is::<Where>() {
+            let condition = self.cast(inputs[0], data_type, BOOL);
+            self.node("Where", &[&condition, inputs[1], inputs[2]], vec![])
+        } else if let Some(fused) = op.downcast_ref::<FusedElementwise>() {
+            self.fused(fused, &inputs, data_type)
+        } else if let Some(dim) = op
+            .downcast_ref::<SumReduce>()
+            .map(|r| r.0)
+            .or_else(|| op.downcast_ref::<MaxReduce>().map(|r| r.0))
+            .or_else(|| op.downcast_ref::<ProdReduce>().map(|r| r.0))
+        {
+            let op_type = if op.is::<SumReduce>() {
+                "ReduceSum"
+            } else if op.is::<MaxReduce>() {
+                "ReduceMax"
+            } else {
+                "ReduceProd"
+            };
+            shape.remove(dim);
+            let axes = self.int64s(&[dim.into()])?;
+            self.node(
+                op_type,
+                &[inputs[0], &axes],
+                vec![Attribute::Int("keepdims", 0)],
+            )
+        } else if let Some(Softmax(dim)) = op.downcast_ref() {
+            self.node(
+                "Softmax",
+                &inputs,
+                vec![Attribute::Int("axis", *dim as i64)],
+            )
+        } else if let Some(LogSoftmax(dim)) = op.downcast_ref() {
+            self.node(
+                "LogSoftmax",
+                &inputs,
+                vec![Attribute::Int("axis", *dim as i64)],
+            )
+        } else if let Some(CumSum(dim)) = op.downcast_ref() {
+            let axis = self.initializer(&[], INT64, (*dim as i64).to_le_bytes().to_vec());
+            self.node("CumSum", &[inputs[0], &axis], vec![])
+        } else if let Some((k, dim)) = op
+            .downcast_ref::<TopK>()
+            .map(|t| (t.k, t.dim))
+            .or_else(|| op.downcast_ref::<ArgTopK>().map(|t| (t.k, t.dim)))
+        {
+            shape[dim] = k.into();
+            let k = self.int64s(&[k.into()])?;
+            let (values, indexes) = (self.fresh("TopK"), self.fresh("TopK"));
+            self.add_node(
+                "TopK",
+                &[inputs[0], &k],
+                vec![Attribute::Int("axis", dim as i64)],
+                &[&values, &indexes],
+            );
+            if op.is::<TopK>() {
+                values
+            } else {
+                self.cast(&indexes, INT64, data_type)
+            }
+        } else if let Some(Gather(dim)) = op.downcast_ref() {
+            let indexes = self.cast(inputs[1], data_type, INT64);
+            shape.splice(*dim..dim + 1, sources[1].2.shape());
+            self.node(
+                "Gather",
+                &[inputs[0], &indexes],
+                vec![Attribute::Int("axis", *dim as i64)],
+            )
+        } else if let Some(Concat(dim)) = op.downcast_ref() {
+            shape[*dim] = sources
+                .iter()
+                .map(|(_, _, s)| s.shape()[*dim].clone())
+                .fold(BigExpression::from(0), |acc, d| acc + d)
+                .simplify();
+            self.node("Concat", &inputs, vec![Attribute::Int("axis", *dim as i64)])
+        } else {
+            return Err(unsupported(format!(
+                "{:?} (node {}) has no ONNX equivalent. Export the graph before compiling it for a backend.",
+                self.graph.graph.node_weight(node).unwrap(),
+                node.index()
+            )));
+        };
+        self.values.insert(node, output);
+        self.shapes.insert(node, shape);
+        self.types.insert(node, data_type);
+        Ok(())
+    }
+
+    /// Unfuse a fused elementwise program into one ONNX node per instruction
+    fn fused(&mut self, fused: &FusedElementwise, inputs: &[&str], data_type: i64) -> String {
+        use ElementwiseInstruction::*;
+        let mut values: Vec<String> = vec![];
+        for instruction in &fused.0 {
+            let v = |i: usize| values[i].clone();
+            let value = match *instruction {
+                Input(i) => inputs[i].to_string(),
+                Constant(c) => self.scalar(c, data_type),
+                Log2(a) => {
+                    let ln = self.node("Log", &[&v(a)], vec![]);
+                    let scale = self.scalar(std::f32::consts::LOG2_E, data_type);
+                    self.node("Mul", &[&ln, &scale], vec![])
+                }
+                Exp2(a) => {
+                    let scale = self.scalar(std::f32::consts::LN_2, data_type);
+                    let scaled = self.node("Mul", &[&v(a), &scale], vec![]);
+                    self.node("Exp", &[&scaled], vec![])
+                }
+                Sin(a) => self.node("Sin", &[&v(a)], vec![]),
+                Recip(a) => self.node("Reciprocal", &[&v(a)], vec![]),
+                Sqrt(a) => self.node("Sqrt", &[&v(a)], vec![]),
+                Add(a, b) => self.node("Add", &[&v(a), &v(b)], vec![]),
+                Mul(a, b) => self.node("Mul", &[&v(a), &v(b)], vec![]),
+                Mod(a, b) => self.node("Mod", &[&v(a), &v(b)], vec![Attribute::Int("fmod", 1)]),
+                LessThan(a, b) => {
+                    let less = self.node("Less", &[&v(a), &v(b)], vec![]);
+                    self.cast(&less, BOOL, data_type)
+                }
+            };
+            values.push(value);
+        }
+        values.pop().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap;
+    use std::io::ErrorKind;
+    crate::test_imports!();
+
+    /// A field of a decoded protobuf message
+    enum Field<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn varint(buf: &mut &[u8]) -> u64 {
+        let (mut value, mut shift) = (0, 0);
+        loop {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+    }
+
+    fn fields(mut buf: &[u8]) -> Vec<(u64, Field<'_>)> {
+        let mut fields = vec![];
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut buf)),
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let (bytes, rest) = buf.split_at(len);
+                    buf = rest;
+                    Field::Bytes(bytes)
+                }
+                t => panic!("Unexpected wire type {t}"),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    fn bytes<'a>(fields: &[(u64, Field<'a>)], n: u64) -> Vec<&'a [u8]> {
+        fields
+            .iter()
+            .filter_map(|(f, v)| match v {
+                Field::Bytes(b) if *f == n => Some(*b),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn ints(fields: &[(u64, Field)], n: u64) -> Vec<i64> {
+        fields
+            .iter()
+            .filter_map(|(f, v)| match v {
+                Field::Varint(i) if *f == n => Some(*i as i64),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn string(fields: &[(u64, Field)], n: u64) -> String {
+        String::from_utf8(bytes(fields, n)[0].to_vec()).unwrap()
+    }
+
+    /// A tensor in the reference evaluator. Every element type is held as f64.
+    #[derive(Debug, Clone)]
+    struct Value {
+        shape: Vec<usize>,
+        data: Vec<f64>,
+    }
+
+    impl Value {
+        fn new(shape: Vec<usize>, data: Vec<f64>) -> Self {
+            assert_eq!(shape.iter().product::<usize>(), data.len());
+            Self { shape, data }
+        }
+
+        fn ints(&self) -> Vec<i64> {
+            self.data.iter().map(|d| *d as i64).collect()
+        }
+
+        /// Build a tensor of a shape from each element's coordinates
+        fn from_fn(shape: Vec<usize>, f: impl Fn(&[usize]) -> f64) -> Self {
+            let n = shape.iter().product::<usize>();
+            let data = (0..n)
+                .map(|mut i| {
+                    let mut coords = vec![0; shape.len()];
+                    for (c, d) in coords.iter_mut().zip(&shape).rev() {
+                        *c = i % d;
+                        i /= d;
+                    }
+                    f(&coords)
+                })
+                .collect();
+            Self::new(shape, data)
+        }
+
+        fn at(&self, coords: &[usize]) -> f64 {
+            let index = coords
+                .iter()
+                .zip(&self.shape)
+                .fold(0, |acc, (c, d)| acc * d + c);
+            self.data[index]
+        }
+
+        /// Broadcast to a shape, numpy style
+        fn expand(&self, shape: &[usize]) -> Self {
+            let offset = shape.len() - self.shape.len();
+            Self::from_fn(shape.to_vec(), |c| {
+                let coords = self
+                    .shape
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| if *d == 1 { 0 } else { c[i + offset] })
+                    .collect::<Vec<_>>();
+                self.at(&coords)
+            })
+        }
+
+        /// Split into (outer, axis, inner) sizes around an axis
+        fn lanes(&self, axis: usize) -> (usize, usize, usize) {
+            let outer = self.shape[..axis].iter().product();
+            let inner = self.shape[axis + 1..].iter().product();
+            (outer, self.shape[axis], inner)
+        }
+    }
+
+    fn broadcast_shape(shapes: &[&[usize]]) -> Vec<usize> {
+        let rank = shapes.iter().map(|s| s.len()).max().unwrap();
+        (0..rank)
+            .map(|i| {
+                shapes
+                    .iter()
+                    .filter_map(|s| (i + s.len()).checked_sub(rank).map(|j| s[j]))
+                    .max()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Run an ONNX model on some inputs with a minimal reference implementation of the ops the exporter uses
+    fn run_onnx(model: &[u8], inputs: &[(&str, Value)]) -> Vec<Value> {
+        let model = fields(model);
+        let graph = fields(bytes(&model, 7)[0]);
+        let mut values = inputs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.clone()))
+            .collect::<FxHashMap<_, _>>();
+        for initializer in bytes(&graph, 5) {
+            let t = fields(initializer);
+            let shape = ints(&t, 1).iter().map(|d| *d as usize).collect();
+            let raw = bytes(&t, 9)[0];
+            let data = match ints(&t, 2)[0] {
+                1 => raw
+                    .chunks(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+                    .collect(),
+                7 => raw
+                    .chunks(8)
+                    .map(|b| i64::from_le_bytes(b.try_into().unwrap()) as f64)
+                    .collect(),
+                t => panic!("Unexpected initializer type {t}"),
+            };
+            values.insert(string(&t, 8), Value::new(shape, data));
+        }
+
+        for node in bytes(&graph, 1) {
+            let node = fields(node);
+            let op_type = string(&node, 4);
+            let attributes = bytes(&node, 5)
+                .into_iter()
+                .map(|a| {
+                    let a = fields(a);
+                    let mut values = ints(&a, 3);
+                    values.extend(ints(&a, 8));
+                    (string(&a, 1), values)
+                })
+                .collect::<FxHashMap<_, _>>();
+            let attr = |name: &str| attributes.get(name).map(|v| v[0]);
+            let input = bytes(&node, 1)
+                .into_iter()
+                .map(|n| values[std::str::from_utf8(n).unwrap()].clone())
+                .collect::<Vec<_>>();
+            let x = &input[0];
+            let elementwise = |f: fn(f64, f64) -> f64| {
+                let shape = broadcast_shape(&[&input[0].shape, &input[1].shape]);
+                let (a, b) = (input[0].expand(&shape), input[1].expand(&shape));
+                let data = a.data.iter().zip(&b.data).map(|(a, b)| f(*a, *b));
+                Value::new(shape, data.collect())
+            };
+            let unary = |f: fn(f64) -> f64| {
+                Value::new(x.shape.clone(), x.data.iter().map(|v| f(*v)).collect())
+            };
+            let mut outputs = vec![match op_type.as_str() {
+                "Identity" => x.clone(),
+                "Cast" => match attr("to").unwrap() {
+                    7 => unary(f64::trunc),
+                    9 => unary(|v| (v != 0.) as i32 as f64),
+                    _ => unary(|v| v as f32 as f64),
+                },
+                "Reshape" => {
+                    let n = x.data.len() as i64;
+                    let shape = input[1].ints();
+                    let known = shape.iter().filter(|d| **d != -1).product::<i64>();
+                    let shape = shape
+                        .iter()
+                        .map(|d| if *d == -1 { n / known } else { *d } as usize);
+                    Value::new(shape.collect(), x.data.clone())
+                }
+                "Pad" => {
+                    let pads = input[1].ints();
+                    let rank = x.shape.len();
+                    let shape = (0..rank)
+                        .map(|i| (x.shape[i] as i64 + pads[i] + pads[i + rank]) as usize)
+                        .collect();
+                    Value::from_fn(shape, |c| {
+                        let coords = (0..rank).map(|i| c[i] as i64 - pads[i]).collect::<Vec<_>>();
+                        if coords
+                            .iter()
+                            .zip(&x.shape)
+                            .all(|(c, d)| *c >= 0 && *c < *d as i64)
+                        {
+                            x.at(&coords.iter().map(|c| *c as usize).collect::<Vec<_>>())
+                        } else {
+                            0.
+                        }
+                    })
+                }
+                "Slice" => {
+                    let (starts, ends) = (input[1].ints(), input[2].ints());
+                    let clamp = |v: i64, d: usize| v.clamp(0, d as i64) as usize;
+                    let starts = starts
+                        .iter()
+                        .zip(&x.shape)
+                        .map(|(s, d)| clamp(*s, *d))
+                        .collect::<Vec<_>>();
+                    let ends = ends.iter().zip(&x.shape).map(|(e, d)| clamp(*e, *d));
+                    let shape = ends.zip(&starts).map(|(e, s)| e - s).collect();
+                    Value::from_fn(shape, |c| {
+                        x.at(&c
+                            .iter()
+                            .zip(&starts)
+                            .map(|(c, s)| c + s)
+                            .collect::<Vec<_>>())
+                    })
+                }
+                "Unsqueeze" => {
+                    let mut shape = x.shape.clone();
+                    let mut axes = input[1].ints();
+                    axes.sort();
+                    for a in axes {
+                        shape.insert(a as usize, 1);
+                    }
+                    Value::new(shape, x.data.clone())
+                }
+                "Transpose" => {
+                    let perm = &attributes["perm"];
+                    let shape = perm.iter().map(|p| x.shape[*p as usize]).collect();
+                    Value::from_fn(shape, |c| {
+                        let mut coords = vec![0; c.len()];
+                        for (i, p) in perm.iter().enumerate() {
+                            coords[*p as usize] = c[i];
+                        }
+                        x.at(&coords)
+                    })
+                }
+                "Expand" => {
+                    let target = input[1]
+                        .ints()
+                        .iter()
+                        .map(|d| *d as usize)
+                        .collect::<Vec<_>>();
+                    x.expand(&broadcast_shape(&[&x.shape, &target]))
+                }
+                "Add" => elementwise(|a, b| a + b),
+                "Sub" => elementwise(|a, b| a - b),
+                "Mul" => elementwise(|a, b| a * b),
+                "Div" => elementwise(|a, b| (a / b).trunc()),
+                "Mod" => elementwise(|a, b| a % b),
+                "Min" => elementwise(f64::min),
+                "Max" => elementwise(f64::max),
+                "Less" => elementwise(|a, b| (a < b) as i32 as f64),
+                "Exp" => unary(f64::exp),
+                "Log" => unary(f64::ln),
+                "Sin" => unary(f64::sin),
+                "Sqrt" => unary(f64::sqrt),
+                "Reciprocal" => unary(f64::recip),
+                "Where" => {
+                    let shape =
+                        broadcast_shape(&[&input[0].shape, &input[1].shape, &input[2].shape]);
+                    let (c, a, b) = (
+                        input[0].expand(&shape),
+                        input[1].expand(&shape),
+                        input[2].expand(&shape),
+                    );
+                    let data = (0..c.data.len()).map(|i| {
+                        if c.data[i] != 0. {
+                            a.data[i]
+                        } else {
+                            b.data[i]
+                        }
+                    });
+                    Value::new(shape, data.collect())
+                }
+                "ReduceSum" | "ReduceMax" | "ReduceProd" => {
+                    let axis = input[1].ints()[0] as usize;
+                    let (outer, n, inner) = x.lanes(axis);
+                    let mut shape = x.shape.clone();
+                    shape.remove(axis);
+                    let data = (0..outer * inner).map(|i| {
+                        let lane =
+                            (0..n).map(|k| x.data[((i / inner) * n + k) * inner + i % inner]);
+                        match op_type.as_str() {
+                            "ReduceSum" => lane.sum(),
+                            "ReduceMax" => lane.fold(f64::NEG_INFINITY, f64::max),
+                            _ => lane.product(),
+                        }
+                    });
+                    Value::new(shape, data.collect())
+                }
+                "Softmax" | "LogSoftmax" | "CumSum" => {
+                    let axis = match attr("axis") {
+                        Some(a) => a as usize,
+                        None => input[1].ints()[0] as usize,
+                    };
+                    let (outer, n, inner) = x.lanes(axis);
+                    let mut data = x.data.clone();
+                    for i in 0..outer * inner {
+                        let index = |k| ((i / inner) * n + k) * inner + i % inner;
+                        let lane = (0..n).map(|k| x.data[index(k)]).collect::<Vec<_>>();
+                        let max = lane.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                        let sum = lane.iter().map(|v| (v - max).exp()).sum::<f64>();
+                        let mut acc = 0.;
+                        for (k, v) in lane.iter().enumerate() {
+                            acc += v;
+                            data[index(k)] = match op_type.as_str() {
+                                "Softmax" => (v - max).exp() / sum,
+                                "LogSoftmax" => v - max - sum.ln(),
+                                _ => acc,
+                            };
+                        }
+                    }
+                    Value::new(x.shape.clone(), data)
+                }
+                "Shape" => {
+                    let (start, end) = (
+                        attr("start").unwrap() as usize,
+                        attr("end").unwrap() as usize,
+                    );
+                    let dims = x.shape[start..end]
+                        .iter()
+                        .map(|d| *d as f64)
+                        .collect::<Vec<_>>();
+                    Value::new(vec![dims.len()], dims)
+                }
+                "Concat" => {
+                    let axis = attr("axis").unwrap() as usize;
+                    let mut shape = x.shape.clone();
+                    shape[axis] = input.iter().map(|v| v.shape[axis]).sum();
+                    Value::from_fn(shape, |c| {
+                        let mut coords = c.to_vec();
+                        for v in &input {
+                            if coords[axis] < v.shape[axis] {
+                                return v.at(&coords);
+                            }
+                            coords[axis] -= v.shape[axis];
+                        }
+                        unreachable!()
+                    })
+                }
+                "Gather" => {
+                    let axis = attr("axis").unwrap() as usize;
+                    let indexes = &input[1];
+                    let rank = indexes.shape.len();
+                    let mut shape = x.shape.clone();
+                    shape.splice(axis..axis + 1, indexes.shape.clone());
+                    Value::from_fn(shape, |c| {
+                        let mut coords = c[..axis].to_vec();
+                        coords.push(indexes.at(&c[axis..axis + rank]) as usize);
+                        coords.extend(&c[axis + rank..]);
+                        x.at(&coords)
+                    })
+                }
+                op => panic!("The reference evaluator doesn't support {op}"),
+            }];
+            for output in bytes(&node, 2).into_iter().rev() {
+                values.insert(
+                    std::str::from_utf8(output).unwrap().to_string(),
+                    outputs.pop().unwrap(),
+                );
+            }
+        }
+        bytes(&graph, 12)
+            .into_iter()
+            .map(|o| values[&string(&fields(o), 1)].clone())
+            .collect()
+    }
+
+    /// The dimensions of a model's inputs or outputs, with symbolic dimensions as their names
+    fn io_dims(model: &[u8], field: u64) -> Vec<(String, Vec<String>)> {
+        let model = fields(model);
+        let graph = fields(bytes(&model, 7)[0]);
+        bytes(&graph, field)
+            .into_iter()
+            .map(|info| {
+                let info = fields(info);
+                let tensor_type = fields(bytes(&fields(bytes(&info, 2)[0]), 1)[0]);
+                let shape = fields(bytes(&tensor_type, 2)[0]);
+                let dims = bytes(&shape, 1)
+                    .into_iter()
+                    .map(|d| {
+                        let d = fields(d);
+                        match ints(&d, 1)[..] {
+                            [n] => n.to_string(),
+                            _ => string(&d, 2),
+                        }
+                    })
+                    .collect();
+                (string(&info, 1), dims)
+            })
+            .collect()
+    }
+
+    const W: [f32; 12] = [
+        0.5, -0.3, 0.8, 0.2, 0.1, -0.6, -0.4, 0.7, 0.3, 0.9, -0.2, 0.4,
+    ];
+
+    #[test]
+    fn test_export_onnx() {
+        let mut cx = Graph::new();
+        let input = cx.named_tensor::<(Dyn<'b'>, LConst<4>)>("Input");
+        let indexes = cx.named_tensor::<(Dyn<'n'>,)>("Indexes");
+        let weight = cx.tensor::<R2<4, 3>>().set(W.to_vec()).keep();
+        let bias = cx.tensor::<R1<3>>().set(vec![0.1, -0.2, 0.3]).keep();
+        // Covers the broadcasts and permutes in matmuls, the padding in concatenating, and the pooling views arange
+        // is built from
+        let hidden = (input.matmul(weight) + bias.expand()).swish();
+        let ramp = cx
+            .arange::<LConst<3>>()
+            .expand::<(Dyn<'b'>, LConst<3>), _>();
+        let mixed = hidden.log_softmax::<LAxis<1>>()
+            + ramp.less_than(hidden) * hidden.max_reduce::<_, LAxis<1>>().expand();
+        let joined = mixed.concat_along::<(Dyn<'b'>, LConst<6>), LAxis<1>, _>(hidden.exp());
+        let rows = joined.gather(indexes).retrieve();
+        let totals = joined.sum_reduce::<_, LAxis<0>>().sqrt().retrieve();
+
+        let model = cx.to_onnx((input, indexes), (rows, totals)).unwrap();
+        assert_eq!(
+            io_dims(&model, 11),
+            [
+                ("Input".to_string(), vec!["b".to_string(), "4".to_string()]),
+                ("Indexes".to_string(), vec!["n".to_string()]),
+            ]
+        );
+        assert_eq!(
+            io_dims(&model, 12),
+            [
+                (
+                    "output_0".to_string(),
+                    vec!["n".to_string(), "6".to_string()]
+                ),
+                ("output_1".to_string(), vec!["6".to_string()]),
+            ]
+        );
+
+        // The exported model takes any batch size
+        for (batch, index_data) in [(3, vec![2., 0.]), (5, vec![4., 1., 1.])] {
+            let data = (0..batch * 4)
+                .map(|i| (i as f32 * 0.37).sin())
+                .collect::<Vec<_>>();
+            input.set_dyn(data.clone(), &[batch, 4]);
+            indexes.set_dyn(index_data.clone(), &[index_data.len()]);
+            cx.execute();
+            let outputs = run_onnx(
+                &model,
+                &[
+                    (
+                        "Input",
+                        Value::new(vec![batch, 4], data.iter().map(|d| *d as f64).collect()),
+                    ),
+                    (
+                        "Indexes",
+                        Value::new(
+                            vec![index_data.len()],
+                            index_data.iter().map(|d| *d as f64).collect(),
+                        ),
+                    ),
+                ],
+            );
+            assert_eq!(outputs[0].shape, [index_data.len(), 6]);
+            assert_eq!(outputs[1].shape, [6]);
+            let as_f32 = |v: &Value| v.data.iter().map(|d| *d as f32).collect::<Vec<_>>();
+            assert_close(&as_f32(&outputs[0]), &rows.data());
+            assert_close(&as_f32(&outputs[1]), &totals.data());
+            rows.drop();
+            totals.drop();
+        }
+    }
+
+    #[test]
+    fn test_export_onnx_errors() {
+        let mut cx = Graph::new();
+        let input = cx.named_tensor::<R1<4>>("Input");
+        let weight = cx.tensor::<R1<4>>();
+        let output = (input * weight).cumprod::<LAxis<0>>();
+
+        // Tensors that aren't inputs need data, and ops need an ONNX equivalent
+        let error = cx.to_onnx(input, input * weight).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        weight.set(vec![1., 2., 3., 4.]);
+        assert!(cx.to_onnx(input, input * weight).is_ok());
+        let error = cx.to_onnx(input, output).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
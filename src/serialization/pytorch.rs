@@ -0,0 +1,746 @@
+//! Loading module weights from PyTorch checkpoints, like the `pytorch_model.bin` files many HuggingFace models are still
+//! distributed as.
+//!
+//! `torch.save` writes a zip archive holding the pickled state dict in `data.pkl`, and the raw bytes of each tensor's
+//! storage in `data/<key>`. Only the small part of pickle that state dicts are written with is understood, and nothing
+//! in the file is ever run, so unlike `torch.load` this is safe to use on checkpoints from anywhere.
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    ops::Range,
+    path::Path,
+};
+
+use itertools::Itertools;
+use memmap2::Mmap;
+use rustc_hash::FxHashMap;
+
+use super::{invalid_data, shard_files, zip::read_zip};
+use crate::{
+    op::{DType, Function, HalfVec},
+    prelude::*,
+};
+
+/// The element type of a storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageType {
+    F64,
+    F32,
+    F16,
+    Bf16,
+    I64,
+    I32,
+    I16,
+    I8,
+    U8,
+    Bool,
+}
+
+impl StorageType {
+    /// The storage type of a storage class, like `FloatStorage`
+    pub fn from_class(class: &str) -> Option<Self> {
+        Some(match class {
+            "DoubleStorage" => Self::F64,
+            "FloatStorage" => Self::F32,
+            "HalfStorage" => Self::F16,
+            "BFloat16Storage" => Self::Bf16,
+            "LongStorage" => Self::I64,
+            "IntStorage" => Self::I32,
+            "ShortStorage" => Self::I16,
+            "CharStorage" => Self::I8,
+            "ByteStorage" => Self::U8,
+            "BoolStorage" => Self::Bool,
+            _ => return None,
+        })
+    }
+
+    /// Number of bytes each element takes
+    pub fn size(&self) -> usize {
+        match self {
+            Self::F64 | Self::I64 => 8,
+            Self::F32 | Self::I32 => 4,
+            Self::F16 | Self::Bf16 | Self::I16 => 2,
+            Self::I8 | Self::U8 | Self::Bool => 1,
+        }
+    }
+
+    /// Read one little endian element as an f32
+    fn read(&self, b: &[u8]) -> f32 {
+        match self {
+            Self::F64 => f64::from_le_bytes(b.try_into().unwrap()) as f32,
+            Self::F32 => f32::from_le_bytes(b.try_into().unwrap()),
+            Self::F16 => f16::from_le_bytes([b[0], b[1]]).to_f32(),
+            Self::Bf16 => bf16::from_le_bytes([b[0], b[1]]).to_f32(),
+            Self::I64 => i64::from_le_bytes(b.try_into().unwrap()) as f32,
+            Self::I32 => i32::from_le_bytes(b.try_into().unwrap()) as f32,
+            Self::I16 => i16::from_le_bytes([b[0], b[1]]) as f32,
+            Self::I8 => b[0] as i8 as f32,
+            Self::U8 => b[0] as f32,
+            Self::Bool => (b[0] != 0) as i32 as f32,
+        }
+    }
+}
+
+/// A tensor in a state dict, which views part of a storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub dtype: StorageType,
+    pub shape: Vec<usize>,
+    /// How many storage elements to step over for each dimension
+    pub stride: Vec<usize>,
+    /// Where the tensor starts in the storage, in elements
+    pub offset: usize,
+    /// The key of the storage in the archive
+    pub storage: String,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// Read the tensor's elements in order as f32s, given the bytes of its storage. Tensors don't have to be
+    /// contiguous, like transposed weights.
+    pub fn to_f32(&self, storage: &[u8]) -> Vec<f32> {
+        let size = self.dtype.size();
+        (0..self.n_elements())
+            .map(|mut i| {
+                let mut index = self.offset;
+                for (dim, stride) in self.shape.iter().zip(&self.stride).rev() {
+                    index += (i % dim) * stride;
+                    i /= dim;
+                }
+                self.dtype.read(&storage[index * size..(index + 1) * size])
+            })
+            .collect()
+    }
+
+    /// The number of storage bytes the tensor reaches into
+    fn storage_bytes(&self) -> usize {
+        let last = self
+            .shape
+            .iter()
+            .zip(&self.stride)
+            .map(|(dim, stride)| dim.saturating_sub(1) * stride)
+            .sum::<usize>();
+        if self.n_elements() == 0 {
+            0
+        } else {
+            (self.offset + last + 1) * self.dtype.size()
+        }
+    }
+}
+
+/// The tensors of a PyTorch checkpoint. Tensors in nested dicts, like the `state_dict` of a training checkpoint, are
+/// named with the keys leading to them joined by dots, like `state_dict.layer.weight`.
+#[derive(Debug, Clone, Default)]
+pub struct StateDict {
+    pub tensors: FxHashMap<String, TensorInfo>,
+    /// Where the bytes of each storage are in the file
+    storages: FxHashMap<String, Range<usize>>,
+}
+
+impl StateDict {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(&unsafe { Mmap::map(&File::open(path)?)? })
+    }
+
+    /// Read the state dict of a checkpoint from the bytes of the whole file
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        if !bytes.starts_with(b"PK\x03\x04") {
+            return Err(invalid_data(
+                "Not a zip archive. Checkpoints saved before PyTorch 1.6 need to be saved again with a newer version"
+                    .to_string(),
+            ));
+        }
+        let entries = read_zip(bytes)?;
+        let pickle = entries
+            .keys()
+            .find(|name| name.ends_with("data.pkl"))
+            .ok_or_else(|| invalid_data("The archive has no data.pkl".to_string()))?;
+        let prefix = pickle.trim_end_matches("data.pkl").to_string();
+        if let Some(byte_order) = entries.get(&format!("{prefix}byteorder")) {
+            if &bytes[byte_order.stored()?] != b"little" {
+                return Err(unsupported("Big endian checkpoints aren't supported"));
+            }
+        }
+
+        let mut tensors = FxHashMap::default();
+        flatten(
+            unpickle(&bytes[entries[pickle].stored()?])?,
+            "",
+            &mut tensors,
+        );
+        let mut storages = FxHashMap::default();
+        for (name, tensor) in &tensors {
+            let entry = entries
+                .get(&format!("{prefix}data/{}", tensor.storage))
+                .ok_or_else(|| invalid_data(format!("The storage of {name} is missing")))?;
+            let range = entry.stored()?;
+            if tensor.storage_bytes() > range.len() {
+                return Err(invalid_data(format!(
+                    "{name} reaches past the end of its storage"
+                )));
+            }
+            storages.insert(tensor.storage.clone(), range);
+        }
+        Ok(Self { tensors, storages })
+    }
+
+    /// Read a tensor's elements in order as f32s, given the bytes of the whole file
+    pub fn tensor_data(&self, name: &str, bytes: &[u8]) -> Option<Vec<f32>> {
+        let tensor = self.tensors.get(name)?;
+        Some(tensor.to_f32(&bytes[self.storages[&tensor.storage].clone()]))
+    }
+}
+
+/// Load the weights of a PyTorch checkpoint into a module's tensors as f32. Module paths like `layers/0/weight` are
+/// looked up as `layers.0.weight`. Sharded checkpoints are loaded through their `pytorch_model.bin.index.json`.
+///
+/// The file is only read from when the graph is executed, so weights can be loaded before compiling.
+pub fn load<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<()> {
+    load_with_names(path, model, graph, |p| p.replace('/', "."))
+}
+
+/// Same as `load`, with a custom mapping from module paths to tensor names in the file
+pub fn load_with_names<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+    name: impl Fn(&str) -> String,
+) -> Result<()> {
+    load_with_dtype(path, model, graph, DType::F32, name)
+}
+
+/// Same as `load_with_names`, converting the weights to f32, f16 or bf16 whatever dtype they're stored in
+pub fn load_with_dtype<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+    dtype: DType,
+    name: impl Fn(&str) -> String,
+) -> Result<()> {
+    if !matches!(dtype, DType::F32 | DType::F16 | DType::Bf16) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Weights can be loaded as f32, f16 or bf16, not {dtype:?}"),
+        ));
+    }
+    // Find where each tensor is stored
+    let mut stored = FxHashMap::default();
+    for file in shard_files(path.as_ref())? {
+        let mut state_dict = StateDict::from_file(&file)?;
+        for (tensor_name, tensor) in state_dict.tensors.drain() {
+            let range = state_dict.storages[&tensor.storage].clone();
+            stored.insert(tensor_name, (file.clone(), tensor, range));
+        }
+    }
+
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path, node) in s.state.into_iter().sorted() {
+        let tensor_name = name(&path);
+        let (file, tensor, range) = stored
+            .remove(&tensor_name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{tensor_name} not found")))?;
+        if let Some(n) = s.shapes[&path].n_elements().to_usize() {
+            if n != tensor.n_elements() {
+                return Err(invalid_data(format!(
+                    "{tensor_name} has shape {:?} in the file, which doesn't fit {path} ({n} elements)",
+                    tensor.shape
+                )));
+            }
+        }
+        if let Some(loading_node) = graph
+            .graph
+            .node_weight_mut(node)
+            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        {
+            loading_node.1 = Box::new(move |_| {
+                let mmap = unsafe { Mmap::map(&File::open(&file).unwrap()).unwrap() };
+                let data = tensor.to_f32(&mmap[range.clone()]);
+                vec![match dtype {
+                    DType::F32 => Tensor::new(data),
+                    _ => Tensor::new(HalfVec::new(&data, dtype)),
+                }]
+            });
+            if dtype == DType::F32 {
+                graph.remove_attribute::<DType>(node);
+            } else {
+                graph.set_attribute(node, dtype);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A value on the pickle machine's stack
+#[derive(Debug, Clone)]
+enum Object {
+    Mark,
+    None,
+    Int(i64),
+    String(String),
+    Tuple(Vec<Object>),
+    List(Vec<Object>),
+    Dict(Vec<(Object, Object)>),
+    /// A class or function, by its full name like `collections.OrderedDict`
+    Global(String),
+    Storage(StorageType, String),
+    Tensor(TensorInfo),
+    /// Anything state dicts don't need the contents of, like floats, bytes, or the result of calling other globals,
+    /// which are never actually called
+    Opaque,
+}
+
+impl Object {
+    fn int(&self) -> Result<usize> {
+        match self {
+            Object::Int(i) if *i >= 0 => Ok(*i as usize),
+            _ => Err(invalid_data(format!("Expected a size, found {self:?}"))),
+        }
+    }
+
+    fn ints(&self) -> Result<Vec<usize>> {
+        match self {
+            Object::Tuple(items) | Object::List(items) => items.iter().map(|i| i.int()).collect(),
+            _ => Err(invalid_data(format!(
+                "Expected a tuple of sizes, found {self:?}"
+            ))),
+        }
+    }
+}
+
+/// Call a global the way `torch.load` would, for the few globals state dicts are built with
+fn call(callable: &Object, args: Vec<Object>) -> Result<Object> {
+    let Object::Global(name) = callable else {
+        return Ok(Object::Opaque);
+    };
+    Ok(match name.as_str() {
+        "torch._utils._rebuild_tensor_v2" | "torch._utils._rebuild_tensor" => {
+            let [Object::Storage(dtype, storage), offset, size, stride, ..] = &args[..] else {
+                return Err(invalid_data(format!(
+                    "Can't rebuild a tensor from {args:?}"
+                )));
+            };
+            Object::Tensor(TensorInfo {
+                dtype: *dtype,
+                shape: size.ints()?,
+                stride: stride.ints()?,
+                offset: offset.int()?,
+                storage: storage.clone(),
+            })
+        }
+        "torch._utils._rebuild_parameter" | "torch._utils._rebuild_parameter_with_state" => {
+            args.into_iter().next().unwrap_or(Object::None)
+        }
+        "collections.OrderedDict" => Object::Dict(vec![]),
+        _ => Object::Opaque,
+    })
+}
+
+/// Read the objects in a pickle, using only the opcodes `torch.save` writes state dicts with
+fn unpickle(bytes: &[u8]) -> Result<Object> {
+    let mut stack = vec![];
+    let mut memo = FxHashMap::default();
+    let mut at = 0;
+    let mut take = |n: usize| {
+        let taken = bytes
+            .get(at..at + n)
+            .ok_or_else(|| invalid_data("The pickle is truncated".to_string()));
+        at += n;
+        taken
+    };
+    let pop = |stack: &mut Vec<Object>| {
+        stack
+            .pop()
+            .ok_or_else(|| invalid_data("The pickle's stack is empty".to_string()))
+    };
+    let pop_mark = |stack: &mut Vec<Object>| {
+        let mark = stack
+            .iter()
+            .rposition(|o| matches!(o, Object::Mark))
+            .ok_or_else(|| invalid_data("The pickle has no mark".to_string()))?;
+        let items = stack.split_off(mark + 1);
+        stack.pop();
+        Ok::<_, Error>(items)
+    };
+    let string = |b: &[u8]| Object::String(String::from_utf8_lossy(b).into_owned());
+    let le = |b: &[u8]| b.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64) as usize;
+
+    loop {
+        let opcode = take(1)?[0];
+        match opcode {
+            // PROTO, FRAME
+            0x80 => drop(take(1)?),
+            0x95 => drop(take(8)?),
+            b'.' => return pop(&mut stack),
+            b'(' => stack.push(Object::Mark),
+            b'N' => stack.push(Object::None),
+            // NEWTRUE, NEWFALSE
+            0x88 | 0x89 => stack.push(Object::Opaque),
+            b'K' => stack.push(Object::Int(take(1)?[0] as i64)),
+            b'M' => stack.push(Object::Int(le(take(2)?) as i64)),
+            b'J' => stack.push(Object::Int(
+                i32::from_le_bytes(take(4)?.try_into().unwrap()) as i64,
+            )),
+            // LONG1, as little endian two's complement
+            0x8a => {
+                let n = take(1)?[0] as usize;
+                if n > 8 {
+                    return Err(invalid_data(format!("Integer of {n} bytes is too large")));
+                }
+                let b = take(n)?;
+                let value = b.iter().rev().fold(0i128, |acc, b| acc << 8 | *b as i128);
+                let value = if n > 0 && b[n - 1] >= 0x80 {
+                    value - (1i128 << (8 * n))
+                } else {
+                    value
+                };
+                stack.push(Object::Int(value as i64));
+            }
+            // BINFLOAT
+            b'G' => {
+                take(8)?;
+                stack.push(Object::Opaque);
+            }
+            0x8c => {
+                let n = take(1)?[0] as usize;
+                stack.push(string(take(n)?));
+            }
+            b'X' => {
+                let n = le(take(4)?);
+                stack.push(string(take(n)?));
+            }
+            0x8d => {
+                let n = le(take(8)?);
+                stack.push(string(take(n)?));
+            }
+            // SHORT_BINBYTES, BINBYTES
+            b'C' | b'B' => {
+                let n = if opcode == b'C' {
+                    take(1)?[0] as usize
+                } else {
+                    le(take(4)?)
+                };
+                take(n)?;
+                stack.push(Object::Opaque);
+            }
+            // GLOBAL, with the module and name on their own lines
+            b'c' => {
+                let mut line = || {
+                    let mut line = vec![];
+                    loop {
+                        match take(1)?[0] {
+                            b'\n' => {
+                                return Ok::<_, Error>(String::from_utf8_lossy(&line).into_owned())
+                            }
+                            c => line.push(c),
+                        }
+                    }
+                };
+                let (module, name) = (line()?, line()?);
+                stack.push(Object::Global(format!("{module}.{name}")));
+            }
+            0x93 => match (pop(&mut stack)?, pop(&mut stack)?) {
+                (Object::String(name), Object::String(module)) => {
+                    stack.push(Object::Global(format!("{module}.{name}")))
+                }
+                _ => return Err(invalid_data("Malformed STACK_GLOBAL".to_string())),
+            },
+            b'}' => stack.push(Object::Dict(vec![])),
+            b']' => stack.push(Object::List(vec![])),
+            b')' => stack.push(Object::Tuple(vec![])),
+            b't' => {
+                let items = pop_mark(&mut stack)?;
+                stack.push(Object::Tuple(items));
+            }
+            0x85..=0x87 => {
+                let items = stack.split_off(stack.len().saturating_sub((opcode - 0x84) as usize));
+                stack.push(Object::Tuple(items));
+            }
+            // BINPUT, LONG_BINPUT, MEMOIZE
+            b'q' | b'r' | 0x94 => {
+                let key = match opcode {
+                    b'q' => take(1)?[0] as usize,
+                    b'r' => le(take(4)?),
+                    _ => memo.len(),
+                };
+                let top = stack
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| invalid_data("Nothing to memoize".to_string()))?;
+                memo.insert(key, top);
+            }
+            // BINGET, LONG_BINGET
+            b'h' | b'j' => {
+                let key = if opcode == b'h' {
+                    take(1)?[0] as usize
+                } else {
+                    le(take(4)?)
+                };
+                let value = memo
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| invalid_data(format!("Memo {key} is missing")))?;
+                stack.push(value);
+            }
+            b'a' | b'e' | b's' | b'u' => {
+                let items = if opcode == b'a' || opcode == b's' {
+                    let n = if opcode == b'a' { 1 } else { 2 };
+                    stack.split_off(stack.len().saturating_sub(n))
+                } else {
+                    pop_mark(&mut stack)?
+                };
+                match stack.last_mut() {
+                    Some(Object::List(list)) if opcode == b'a' || opcode == b'e' => {
+                        list.extend(items)
+                    }
+                    Some(Object::Dict(dict)) if opcode == b's' || opcode == b'u' => {
+                        dict.extend(items.into_iter().tuples())
+                    }
+                    Some(Object::Opaque) => {}
+                    _ => {
+                        return Err(invalid_data(
+                            "Adding items to something that isn't a collection".to_string(),
+                        ))
+                    }
+                }
+            }
+            // REDUCE, NEWOBJ
+            b'R' | 0x81 => {
+                let args = pop(&mut stack)?;
+                let callable = pop(&mut stack)?;
+                let Object::Tuple(args) = args else {
+                    return Err(invalid_data("Call arguments must be a tuple".to_string()));
+                };
+                stack.push(call(&callable, args)?);
+            }
+            // BUILD sets the state of an object, which state dicts only use for metadata
+            b'b' => drop(pop(&mut stack)?),
+            // BINPERSID, which state dicts use for storages
+            b'Q' => {
+                let Object::Tuple(id) = pop(&mut stack)? else {
+                    return Err(invalid_data("Persistent ids must be tuples".to_string()));
+                };
+                let [Object::String(kind), Object::Global(class), Object::String(key), ..] =
+                    &id[..]
+                else {
+                    return Err(invalid_data(format!("Unknown persistent id {id:?}")));
+                };
+                let dtype = (kind == "storage")
+                    .then(|| StorageType::from_class(class.trim_start_matches("torch.")))
+                    .flatten()
+                    .ok_or_else(|| unsupported(&format!("Unsupported storage {class}")))?;
+                stack.push(Object::Storage(dtype, key.clone()));
+            }
+            _ => {
+                return Err(unsupported(&format!(
+                    "Pickle opcode 0x{opcode:02x} isn't used by state dicts"
+                )))
+            }
+        }
+    }
+}
+
+/// Collect the tensors in nested dicts, naming them by their keys joined with dots
+fn flatten(object: Object, prefix: &str, tensors: &mut FxHashMap<String, TensorInfo>) {
+    let Object::Dict(items) = object else {
+        return;
+    };
+    for (key, value) in items {
+        let key = match key {
+            Object::String(s) => s,
+            Object::Int(i) => i.to_string(),
+            _ => continue,
+        };
+        let name = format!("{prefix}{key}");
+        match value {
+            Object::Tensor(tensor) => {
+                tensors.insert(name, tensor);
+            }
+            dict @ Object::Dict(_) => flatten(dict, &format!("{name}."), tensors),
+            _ => {}
+        }
+    }
+}
+
+fn unsupported(message: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, load_with_dtype, load_with_names, StateDict, StorageType};
+    use crate::{op::DType, serialization::zip::write_zip};
+    crate::test_imports!();
+
+    struct Model {
+        weight: GraphTensor<R2<2, 3>>,
+        inner: Inner,
+    }
+    struct Inner {
+        bias: GraphTensor<R1<3>>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.module("inner", &self.inner);
+        }
+    }
+    impl SerializeModule for Inner {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("bias", self.bias);
+        }
+    }
+    impl InitModule for Model {
+        fn initialize(cx: &mut Graph) -> Self {
+            Self {
+                weight: cx.named_tensor("Weight").keep(),
+                inner: Inner {
+                    bias: cx.named_tensor("Bias").keep(),
+                },
+            }
+        }
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        [
+            b"X".to_vec(),
+            (s.len() as u32).to_le_bytes().to_vec(),
+            s.as_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    fn tuple(items: &[usize]) -> Vec<u8> {
+        let mut t = b"(".to_vec();
+        for i in items {
+            t.push(b'K');
+            t.push(*i as u8);
+        }
+        t.push(b't');
+        t
+    }
+
+    /// Pickle a call to `_rebuild_tensor_v2`, with the storage's persistent id already on the stack
+    fn tensor(offset: usize, size: &[usize], stride: &[usize]) -> Vec<u8> {
+        [
+            vec![b'K', offset as u8],
+            tuple(size),
+            tuple(stride),
+            b"\x89ccollections\nOrderedDict\n)RtR".to_vec(),
+        ]
+        .concat()
+    }
+
+    /// A checkpoint like `torch.save(model.state_dict())` would write, with a transposed f32 weight and an f16 bias
+    /// viewing part of a storage shared with an unused tensor
+    fn checkpoint() -> Vec<u8> {
+        let storage = |class: &str, key: &str, numel: usize| {
+            [
+                b"(".to_vec(),
+                string("storage"),
+                format!("ctorch\n{class}\n").into_bytes(),
+                string(key),
+                string("cpu"),
+                vec![b'K', numel as u8],
+                b"tQ".to_vec(),
+            ]
+            .concat()
+        };
+        let rebuild = b"ctorch._utils\n_rebuild_tensor_v2\nq\x01".to_vec();
+        let pickle = [
+            b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x02(".to_vec(),
+            string("weight"),
+            rebuild.clone(),
+            b"(".to_vec(),
+            storage("FloatStorage", "0", 6),
+            tensor(0, &[2, 3], &[1, 2]),
+            string("inner.bias"),
+            b"h\x01(".to_vec(),
+            storage("HalfStorage", "1", 5),
+            b"q\x03".to_vec(),
+            tensor(1, &[3], &[1]),
+            string("inner.unused"),
+            b"h\x01(h\x03".to_vec(),
+            tensor(4, &[1], &[1]),
+            b"u}b.".to_vec(),
+        ]
+        .concat();
+        let weight = [1_f32, 4., 2., 5., 3., 6.].map(f32::to_le_bytes).concat();
+        let bias = [9_f32, 0.5, -1., 2., 7.]
+            .map(|f| f16::from_f32(f).to_le_bytes())
+            .concat();
+        write_zip(&[
+            ("archive/data.pkl".to_string(), pickle),
+            ("archive/byteorder".to_string(), b"little".to_vec()),
+            ("archive/data/0".to_string(), weight),
+            ("archive/data/1".to_string(), bias),
+            ("archive/version".to_string(), b"3\n".to_vec()),
+        ])
+        .unwrap()
+    }
+
+    fn temp_file(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let file = std::env::temp_dir().join(format!("luminal_{name}_{}.bin", std::process::id()));
+        std::fs::write(&file, bytes).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_pytorch_state_dict() {
+        let bytes = checkpoint();
+        let state_dict = StateDict::read(&bytes).unwrap();
+        assert_eq!(state_dict.tensors.len(), 3);
+        let bias = &state_dict.tensors["inner.bias"];
+        assert_eq!(bias.dtype, StorageType::F16);
+        assert_eq!((bias.shape.as_slice(), bias.offset), (&[3][..], 1));
+        assert_exact(
+            &state_dict.tensor_data("weight", &bytes).unwrap(),
+            &[1., 2., 3., 4., 5., 6.],
+        );
+        assert_exact(
+            &state_dict.tensor_data("inner.unused", &bytes).unwrap(),
+            &[7.],
+        );
+
+        // Legacy and truncated checkpoints are rejected
+        assert!(StateDict::read(b"\x80\x02\x8a\x0alegacy").is_err());
+        let mut truncated = bytes.clone();
+        truncated.truncate(bytes.len() - 30);
+        assert!(StateDict::read(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_pytorch_load() {
+        let file = temp_file("pytorch_load", &checkpoint());
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        load(&file, &model, &mut cx).unwrap();
+        cx.execute();
+        assert_exact(&model.weight.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&model.inner.bias.data(), &[0.5, -1., 2.]);
+
+        // Weights can be converted to half precision
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        load_with_dtype(&file, &model, &mut cx, DType::F16, |p| p.replace('/', ".")).unwrap();
+        cx.execute();
+        assert_eq!(cx.dtype(model.weight.id), DType::F16);
+        assert_exact(&model.weight.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&model.inner.bias.data(), &[0.5, -1., 2.]);
+
+        // Missing tensors are reported by name
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        let err = load_with_names(&file, &model, &mut cx, |p| format!("model.{p}")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        std::fs::remove_file(file).unwrap();
+    }
+}
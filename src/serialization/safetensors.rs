@@ -0,0 +1,298 @@
+// Loading and saving module weights in the safetensors format used by HuggingFace checkpoints
+
+use std::{fs::File, path::Path};
+
+use ::safetensors::{
+    serialize_to_file,
+    tensor::{Dtype, TensorView},
+    SafeTensors,
+};
+use itertools::Itertools;
+use memmap2::Mmap;
+use rustc_hash::FxHashMap;
+
+pub use super::TensorMap;
+pub use ::safetensors::SafeTensorError;
+
+use super::{invalid_data, module_tensors, shard_files};
+use crate::{op::Function, prelude::*};
+
+/// Load weights from a `.safetensors` file, or the `.safetensors.index.json` of a sharded checkpoint, into a module's
+/// tensors. Module paths like `layers/0/weight` are looked up as `layers.0.weight`.
+///
+/// The file is only read from when the graph is executed, so weights can be loaded before compiling.
+pub fn load<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+) -> Result<(), SafeTensorError> {
+    load_with_names(path, model, graph, |p| p.replace('/', "."))
+}
+
+/// Same as `load`, with a custom mapping from module paths to tensor names in the file
+pub fn load_with_names<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+    name: impl Fn(&str) -> String,
+) -> Result<(), SafeTensorError> {
+    load_matching(path, model, graph, |p| Some(name(p)))
+}
+
+/// Same as `load_with_names`, skipping the module paths the mapping returns `None` for. Those tensors are left for
+/// something else to load, like weights stored in a separate file.
+pub fn load_matching<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &mut Graph,
+    name: impl Fn(&str) -> Option<String>,
+) -> Result<(), SafeTensorError> {
+    // Find where each tensor is stored
+    let mut stored = FxHashMap::default();
+    for file in shard_files(path.as_ref())? {
+        let mmap = unsafe { Mmap::map(&File::open(&file)?)? };
+        let (header_size, metadata) = SafeTensors::read_metadata(&mmap)?;
+        for (tensor_name, info) in metadata.tensors() {
+            let (start, end) = info.data_offsets;
+            let offsets = (8 + header_size + start, 8 + header_size + end);
+            stored.insert(
+                tensor_name,
+                (file.clone(), info.dtype, info.shape.clone(), offsets),
+            );
+        }
+    }
+
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    for (path, node) in s.state.into_iter().sorted() {
+        let Some(tensor_name) = name(&path) else {
+            continue;
+        };
+        let (file, dtype, shape, (start, end)) = stored
+            .remove(&tensor_name)
+            .ok_or(SafeTensorError::TensorNotFound(tensor_name.clone()))?;
+        if let Some(n) = s.shapes[&path].n_elements().to_usize() {
+            if n != shape.iter().product::<usize>() {
+                return Err(invalid_data(format!(
+                    "{tensor_name} has shape {shape:?} in the file, which doesn't fit {path} ({n} elements)"
+                ))
+                .into());
+            }
+        }
+        if to_f32(dtype, &[]).is_none() {
+            return Err(
+                invalid_data(format!("{tensor_name} has unsupported dtype {dtype:?}")).into(),
+            );
+        }
+        if let Some(loading_node) = graph
+            .graph
+            .node_weight_mut(node)
+            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        {
+            loading_node.1 = Box::new(move |_| {
+                let mmap = unsafe { Mmap::map(&File::open(&file).unwrap()).unwrap() };
+                vec![Tensor::new(to_f32(dtype, &mmap[start..end]).unwrap())]
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Read every tensor in a `.safetensors` file, or the shards of a `.safetensors.index.json`, as f32s along with its
+/// shape. Tensors are read up front, so this is meant for small files like adapters rather than whole checkpoints.
+pub fn read_tensors(path: impl AsRef<Path>) -> Result<TensorMap, SafeTensorError> {
+    let mut tensors = FxHashMap::default();
+    for file in shard_files(path.as_ref())? {
+        let mmap = unsafe { Mmap::map(&File::open(&file)?)? };
+        for (name, view) in SafeTensors::deserialize(&mmap)?.tensors() {
+            let data = to_f32(view.dtype(), view.data()).ok_or_else(|| {
+                invalid_data(format!("{name} has unsupported dtype {:?}", view.dtype()))
+            })?;
+            tensors.insert(name, (view.shape().to_vec(), data));
+        }
+    }
+    Ok(tensors)
+}
+
+/// Save a module's weights to a `.safetensors` file as f32. Module paths like `layers/0/weight` are saved as
+/// `layers.0.weight`.
+///
+/// The weights must currently be held by the graph as f32s on the CPU, so mark them with `keep` and execute first.
+pub fn save<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &Graph,
+) -> Result<(), SafeTensorError> {
+    save_with_names(path, model, graph, |p| p.replace('/', "."))
+}
+
+/// Same as `save`, with a custom mapping from module paths to tensor names in the file
+pub fn save_with_names<M: SerializeModule>(
+    path: impl AsRef<Path>,
+    model: &M,
+    graph: &Graph,
+    name: impl Fn(&str) -> String,
+) -> Result<(), SafeTensorError> {
+    write_tensors(path, &module_tensors(model, graph, name)?)
+}
+
+/// Write tensors to a `.safetensors` file as f32, in order of their names
+pub fn write_tensors(path: impl AsRef<Path>, tensors: &TensorMap) -> Result<(), SafeTensorError> {
+    let bytes = tensors
+        .iter()
+        .sorted_by_key(|(name, _)| *name)
+        .map(|(name, (shape, data))| {
+            let bytes = data
+                .iter()
+                .flat_map(|f| f.to_le_bytes())
+                .collect::<Vec<_>>();
+            (name, shape, bytes)
+        })
+        .collect::<Vec<_>>();
+    let views = bytes
+        .iter()
+        .map(|(name, shape, bytes)| {
+            TensorView::new(Dtype::F32, shape.to_vec(), bytes).map(|v| (name.to_string(), v))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    serialize_to_file(views, &None, path.as_ref())
+}
+
+/// Convert little endian data to f32s, or None if the dtype isn't supported
+fn to_f32(dtype: Dtype, bytes: &[u8]) -> Option<Vec<f32>> {
+    fn convert<const N: usize>(bytes: &[u8], f: impl Fn([u8; N]) -> f32) -> Vec<f32> {
+        bytes
+            .chunks_exact(N)
+            .map(|c| f(c.try_into().unwrap()))
+            .collect()
+    }
+    Some(match dtype {
+        Dtype::F64 => convert(bytes, |b| f64::from_le_bytes(b) as f32),
+        Dtype::F32 => convert(bytes, f32::from_le_bytes),
+        Dtype::F16 => convert(bytes, |b| f16::from_le_bytes(b).to_f32()),
+        Dtype::BF16 => convert(bytes, |b| bf16::from_le_bytes(b).to_f32()),
+        Dtype::I64 => convert(bytes, |b| i64::from_le_bytes(b) as f32),
+        Dtype::I32 => convert(bytes, |b| i32::from_le_bytes(b) as f32),
+        Dtype::I8 => convert(bytes, |b: [u8; 1]| b[0] as i8 as f32),
+        Dtype::U8 => convert(bytes, |b: [u8; 1]| b[0] as f32),
+        Dtype::BOOL => convert(bytes, |b: [u8; 1]| (b[0] != 0) as i32 as f32),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ::safetensors::{
+        serialize_to_file,
+        tensor::{Dtype, TensorView},
+    };
+
+    use super::{load, save};
+    crate::test_imports!();
+
+    struct Model {
+        weight: GraphTensor<R2<2, 3>>,
+        inner: Inner,
+    }
+    struct Inner {
+        bias: GraphTensor<R1<3>>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.module("inner", &self.inner);
+        }
+    }
+    impl SerializeModule for Inner {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("bias", self.bias);
+        }
+    }
+    impl InitModule for Model {
+        fn initialize(cx: &mut Graph) -> Self {
+            Self {
+                weight: cx.named_tensor("Weight").keep(),
+                inner: Inner {
+                    bias: cx.named_tensor("Bias").keep(),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_safetensors_round_trip() {
+        let dir = temp_dir("safetensors_round_trip");
+        let file = dir.join("model.safetensors");
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        model.weight.set(vec![1., 2., 3., 4., 5., 6.]);
+        model.inner.bias.set(vec![0.5, -1., 2.]);
+        cx.execute();
+        save(&file, &model, &cx).unwrap();
+
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        load(&file, &model, &mut cx).unwrap();
+        cx.execute();
+        assert_exact(&model.weight.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&model.inner.bias.data(), &[0.5, -1., 2.]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_safetensors_sharded() {
+        let dir = temp_dir("safetensors_sharded");
+        let weight = [1., 2., 3., 4., 5., 6.].map(bf16::from_f32);
+        let weight_bytes = weight
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        let bias_bytes = [0.5_f32, -1., 2.]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        serialize_to_file(
+            [(
+                "weight",
+                TensorView::new(Dtype::BF16, vec![2, 3], &weight_bytes).unwrap(),
+            )],
+            &None,
+            &dir.join("shard-1.safetensors"),
+        )
+        .unwrap();
+        serialize_to_file(
+            [(
+                "inner.bias",
+                TensorView::new(Dtype::F32, vec![3], &bias_bytes).unwrap(),
+            )],
+            &None,
+            &dir.join("shard-2.safetensors"),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            r#"{"metadata": {}, "weight_map": {"weight": "shard-1.safetensors", "inner.bias": "shard-2.safetensors"}}"#,
+        )
+        .unwrap();
+
+        let mut cx = Graph::new();
+        let model = Model::initialize(&mut cx);
+        load(dir.join("model.safetensors.index.json"), &model, &mut cx).unwrap();
+        cx.execute();
+        assert_exact(&model.weight.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&model.inner.bias.data(), &[0.5, -1., 2.]);
+
+        // Tensors missing from the checkpoint or with the wrong size are errors
+        struct Wrong(GraphTensor<R1<4>>);
+        impl SerializeModule for Wrong {
+            fn serialize(&self, s: &mut Serializer) {
+                s.tensor("weight", self.0);
+            }
+        }
+        let wrong = Wrong(cx.named_tensor("Wrong"));
+        let shard = dir.join("shard-1.safetensors");
+        assert!(load(&shard, &wrong, &mut cx).is_err());
+        assert!(load(&shard, &model, &mut cx).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
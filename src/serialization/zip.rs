@@ -0,0 +1,195 @@
+//! A minimal zip reader and writer for the archives PyTorch checkpoints and NumPy `.npz` files are stored in. Only
+//! uncompressed files are supported, which is what both write by default.
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    ops::Range,
+};
+
+use rustc_hash::FxHashMap;
+
+use super::invalid_data;
+
+/// A file in a zip archive
+pub(crate) struct ZipEntry {
+    pub data: Range<usize>,
+    pub compressed: bool,
+}
+
+impl ZipEntry {
+    /// Where the file's bytes are, if it's stored uncompressed like PyTorch and `np.savez` write files
+    pub fn stored(&self) -> Result<Range<usize>> {
+        if self.compressed {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "Compressed files in the archive aren't supported",
+            ))
+        } else {
+            Ok(self.data.clone())
+        }
+    }
+}
+
+/// Read a little endian unsigned integer of `N` bytes
+fn read_le<const N: usize>(bytes: &[u8], at: usize) -> Result<u64> {
+    let b = bytes
+        .get(at..at + N)
+        .ok_or_else(|| invalid_data("The archive is truncated".to_string()))?;
+    Ok(b.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64))
+}
+
+/// Find the files in a zip archive from its central directory, including zip64 archives over 4GB
+pub(crate) fn read_zip(bytes: &[u8]) -> Result<FxHashMap<String, ZipEntry>> {
+    let eocd = (0..=bytes.len().saturating_sub(22))
+        .rev()
+        .take(22 + u16::MAX as usize)
+        .find(|i| bytes[*i..].starts_with(b"PK\x05\x06"))
+        .ok_or_else(|| invalid_data("The archive has no central directory".to_string()))?;
+    let (mut n_entries, mut offset) = (
+        read_le::<2>(bytes, eocd + 10)?,
+        read_le::<4>(bytes, eocd + 16)?,
+    );
+    if n_entries == 0xFFFF || offset == 0xFFFF_FFFF {
+        let locator = eocd.saturating_sub(20);
+        if !bytes[locator..].starts_with(b"PK\x06\x07") {
+            return Err(invalid_data("The zip64 locator is missing".to_string()));
+        }
+        let record = read_le::<8>(bytes, locator + 8)? as usize;
+        n_entries = read_le::<8>(bytes, record + 32)?;
+        offset = read_le::<8>(bytes, record + 48)?;
+    }
+
+    let mut entries = FxHashMap::default();
+    let mut at = offset as usize;
+    for _ in 0..n_entries {
+        if !bytes
+            .get(at..)
+            .is_some_and(|b| b.starts_with(b"PK\x01\x02"))
+        {
+            return Err(invalid_data("The central directory is corrupt".to_string()));
+        }
+        let method = read_le::<2>(bytes, at + 10)?;
+        let mut size = read_le::<4>(bytes, at + 20)?;
+        let name_len = read_le::<2>(bytes, at + 28)? as usize;
+        let extra_len = read_le::<2>(bytes, at + 30)? as usize;
+        let comment_len = read_le::<2>(bytes, at + 32)? as usize;
+        let mut header = read_le::<4>(bytes, at + 42)?;
+        let name = String::from_utf8_lossy(&bytes[at + 46..at + 46 + name_len]).into_owned();
+
+        // Sizes and offsets too large for 32 bits are in the zip64 extra field, in this order when present
+        let mut extra = at + 46 + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let (id, len) = (read_le::<2>(bytes, extra)?, read_le::<2>(bytes, extra + 2)?);
+            if id == 1 {
+                let mut field = extra + 4;
+                let mut values = [read_le::<4>(bytes, at + 24)?, size, header];
+                for value in &mut values {
+                    if *value == 0xFFFF_FFFF {
+                        *value = read_le::<8>(bytes, field)?;
+                        field += 8;
+                    }
+                }
+                [_, size, header] = values;
+            }
+            extra += 4 + len as usize;
+        }
+
+        let header = header as usize;
+        if !bytes
+            .get(header..)
+            .is_some_and(|b| b.starts_with(b"PK\x03\x04"))
+        {
+            return Err(invalid_data(format!(
+                "The local header of {name} is missing"
+            )));
+        }
+        let start = header
+            + 30
+            + read_le::<2>(bytes, header + 26)? as usize
+            + read_le::<2>(bytes, header + 28)? as usize;
+        let data = start..start + size as usize;
+        if data.end > bytes.len() {
+            return Err(invalid_data(format!("{name} is truncated")));
+        }
+        entries.insert(
+            name,
+            ZipEntry {
+                data,
+                compressed: method != 0,
+            },
+        );
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// Write files to a zip archive without compressing them
+pub(crate) fn write_zip(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    let too_large = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "Archives over 4GB aren't supported",
+        )
+    };
+    let (mut out, mut central) = (vec![], vec![]);
+    for (name, data) in files {
+        let offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        // Version 2.0, no flags, stored, dated 1980-01-01
+        let mut header = [20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0].to_vec();
+        header.extend(crc32(data).to_le_bytes());
+        header.extend([size, size].map(u32::to_le_bytes).concat());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend([0, 0]);
+
+        out.extend(b"PK\x03\x04");
+        out.extend(&header);
+        out.extend(name.as_bytes());
+        out.extend(data);
+        central.extend(b"PK\x01\x02\x14\x00");
+        central.extend(&header);
+        // No comment, disk 0, no attributes
+        central.extend([0; 10]);
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+    }
+    let offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+    let n_files = u16::try_from(files.len()).map_err(|_| too_large())?;
+    out.extend(&central);
+    out.extend(b"PK\x05\x06\0\0\0\0");
+    out.extend([n_files, n_files].map(u16::to_le_bytes).concat());
+    out.extend((central.len() as u32).to_le_bytes());
+    out.extend(offset.to_le_bytes());
+    out.extend([0, 0]);
+    Ok(out)
+}
+
+/// The CRC-32 checksum zip readers verify files with
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, read_zip, write_zip};
+
+    #[test]
+    fn test_zip_round_trip() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let files = [
+            ("a.npy".to_string(), b"first".to_vec()),
+            ("dir/b".to_string(), vec![]),
+        ];
+        let bytes = write_zip(&files).unwrap();
+        let entries = read_zip(&bytes).unwrap();
+        assert_eq!(entries.len(), 2);
+        for (name, data) in &files {
+            assert_eq!(&bytes[entries[name].stored().unwrap()], data.as_slice());
+        }
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::BTreeSet,
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+};
+
+use crate::prelude::{CausalLM, GraphLM, Sample, Sampler, Shape, Tensor};
+
+/// A model that can run a decoding step for many requests at once
+pub trait BatchedModel {
+    /// The state kept for each request between steps, like its KV cache
+    type State;
+
+    /// Create the state for a new request, given its own sampling settings if it was submitted with any. Models that
+    /// don't sample can ignore them.
+    fn new_state(&mut self, sampler: Option<Sample>) -> Self::State;
+
+    /// Whether there's room for another request's state, like a free KV cache slot. Requests wait until there is.
+    fn can_admit(&self) -> bool {
+        true
+    }
+
+    /// Take back the state of a finished or cancelled request, like to free its KV cache slot
+    fn free_state(&mut self, _state: Self::State) {}
+
+    /// Run one step over a batch of requests. Each request is given the tokens it hasn't seen yet (the whole prompt on
+    /// its first step, then the last generated token), and the next token is returned for each request in batch order.
+    fn step(&mut self, batch: &mut [(&mut Self::State, &[u32])]) -> Vec<u32>;
+}
+
+struct Request {
+    prompt: Vec<u32>,
+    max_new_tokens: usize,
+    sampler: Option<Sample>,
+    sender: Sender<u32>,
+}
+
+struct ActiveRequest<S> {
+    state: S,
+    pending: Vec<u32>,
+    remaining: usize,
+    sender: Sender<u32>,
+}
+
+/// A fixed pool of KV cache slots, like the rows of a cache allocated with a batch dimension up front. The lowest free
+/// slot is handed out first, so the rows in use stay packed at the start of the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheSlots {
+    free: BTreeSet<usize>,
+    capacity: usize,
+}
+
+impl CacheSlots {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            free: (0..capacity).collect(),
+            capacity,
+        }
+    }
+
+    /// Take the lowest free slot, if there is one
+    pub fn acquire(&mut self) -> Option<usize> {
+        self.free.pop_first()
+    }
+
+    /// Give a slot back so it can be reused
+    pub fn release(&mut self, slot: usize) {
+        assert!(slot < self.capacity, "Slot {slot} is out of range");
+        assert!(self.free.insert(slot), "Slot {slot} was already free");
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of free slots
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+
+    /// The number of slots handed out
+    pub fn in_use(&self) -> usize {
+        self.capacity - self.free.len()
+    }
+}
+
+/// Serves a compiled `GraphLM` to an `InferenceSession`, keeping each request's KV cache in one of a fixed number of
+/// slots between steps. The graph runs a single sequence, so a step runs it once per request in the batch, moving that
+/// request's cache tensors into the graph first and back into its slot after.
+pub struct SlottedLM<'a, S: Shape, P: Sampler> {
+    lm: GraphLM<'a, S>,
+    /// Picks tokens for requests without their own sampling settings
+    sampler: P,
+    /// The sampling settings of the request in each slot, if it has its own
+    samplers: Vec<Option<Sample>>,
+    slots: CacheSlots,
+    /// The cache tensors of each slot and how many positions they hold. Fresh slots have no tensors, so the graph
+    /// loads its empty caches.
+    caches: Vec<(Vec<Tensor>, usize)>,
+}
+
+impl<'a, S: Shape, P: Sampler> SlottedLM<'a, S, P> {
+    /// Serve `lm` with room for `slots` requests at once, picking next tokens with `sampler` unless a request has
+    /// its own sampling settings
+    pub fn new(mut lm: GraphLM<'a, S>, sampler: P, slots: usize) -> Self {
+        lm.reset();
+        Self {
+            lm,
+            sampler,
+            samplers: vec![None; slots],
+            slots: CacheSlots::new(slots),
+            caches: (0..slots).map(|_| (vec![], 0)).collect(),
+        }
+    }
+
+    pub fn slots(&self) -> &CacheSlots {
+        &self.slots
+    }
+}
+
+impl<S: Shape, P: Sampler> BatchedModel for SlottedLM<'_, S, P> {
+    /// The request's cache slot
+    type State = usize;
+
+    fn new_state(&mut self, sampler: Option<Sample>) -> usize {
+        let slot = self.slots.acquire().expect("No free cache slot");
+        self.samplers[slot] = sampler;
+        slot
+    }
+
+    fn can_admit(&self) -> bool {
+        self.slots.available() > 0
+    }
+
+    fn free_state(&mut self, slot: usize) {
+        self.caches[slot] = (vec![], 0);
+        self.samplers[slot] = None;
+        self.slots.release(slot);
+    }
+
+    fn step(&mut self, batch: &mut [(&mut usize, &[u32])]) -> Vec<u32> {
+        let mut next = Vec::with_capacity(batch.len());
+        for (slot, tokens) in batch.iter() {
+            let (tensors, cached) = std::mem::take(&mut self.caches[**slot]);
+            for (id, tensor) in self.lm.cache_src.iter().zip(tensors) {
+                self.lm.graph.set_tensor(*id, 0, tensor);
+            }
+            self.lm.cached = cached;
+            let logits = self.lm.step(tokens);
+            next.push(match &mut self.samplers[**slot] {
+                Some(sampler) => sampler.sample(&logits),
+                None => self.sampler.sample(&logits),
+            });
+            // Take the updated caches back out, leaving the graph clear for the next request
+            let tensors = self
+                .lm
+                .cache_src
+                .iter()
+                .map(|id| {
+                    self.lm
+                        .graph
+                        .get_tensor(*id, 0)
+                        .expect("The graph should produce every cache")
+                })
+                .collect();
+            self.caches[**slot] = (tensors, self.lm.cached);
+        }
+        next
+    }
+}
+
+/// A cloneable, thread-safe handle for submitting requests to an `InferenceSession`
+#[derive(Clone)]
+pub struct SessionHandle(Sender<Request>);
+
+impl SessionHandle {
+    /// Submit a request, getting back a stream of the generated tokens. Dropping the receiver cancels the request.
+    pub fn generate_stream(&self, prompt: Vec<u32>, max_new_tokens: usize) -> Receiver<u32> {
+        self.submit(prompt, max_new_tokens, None)
+    }
+
+    /// Like `generate_stream`, with the request's own sampling settings rather than the model's
+    pub fn generate_stream_with(
+        &self,
+        prompt: Vec<u32>,
+        max_new_tokens: usize,
+        sampler: Sample,
+    ) -> Receiver<u32> {
+        self.submit(prompt, max_new_tokens, Some(sampler))
+    }
+
+    fn submit(
+        &self,
+        prompt: Vec<u32>,
+        max_new_tokens: usize,
+        sampler: Option<Sample>,
+    ) -> Receiver<u32> {
+        let (sender, receiver) = channel();
+        if !prompt.is_empty() && max_new_tokens > 0 {
+            // If the session is gone the stream just ends
+            let _ = self.0.send(Request {
+                prompt,
+                max_new_tokens,
+                sampler,
+                sender,
+            });
+        }
+        receiver
+    }
+
+    /// Submit a request and block until it's finished, returning the generated tokens
+    pub fn generate(&self, prompt: Vec<u32>, max_new_tokens: usize) -> Vec<u32> {
+        self.generate_stream(prompt, max_new_tokens)
+            .iter()
+            .collect()
+    }
+}
+
+/// Owns a single compiled model and batches concurrent `generate` requests onto it.
+///
+/// Requests come in through `SessionHandle`s, which can be sent to other threads. Every step, waiting requests join
+/// the running batch (up to `max_batch_size`, and while the model `can_admit` them), and finished or cancelled ones
+/// leave it, handing their state back to the model with `free_state`.
+pub struct InferenceSession<M: BatchedModel> {
+    model: M,
+    max_batch_size: usize,
+    stop_tokens: Vec<u32>,
+    sender: Sender<Request>,
+    receiver: Receiver<Request>,
+    active: Vec<ActiveRequest<M::State>>,
+}
+
+impl<M: BatchedModel> InferenceSession<M> {
+    pub fn new(model: M) -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            model,
+            max_batch_size: usize::MAX,
+            stop_tokens: vec![],
+            sender,
+            receiver,
+            active: vec![],
+        }
+    }
+
+    /// Limit how many requests are run in a single step. Extra requests wait until a slot frees up.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        assert!(max_batch_size > 0, "Max batch size must be at least 1");
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Finish a request once it generates one of these tokens. The stop token is still sent to the request.
+    pub fn with_stop_tokens(mut self, stop_tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_tokens = stop_tokens.into_iter().collect();
+        self
+    }
+
+    /// Get a handle to submit requests with
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle(self.sender.clone())
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// The number of requests currently in the running batch
+    pub fn active_requests(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Admit any waiting requests and run a single batched step. Returns false if there was nothing to run.
+    pub fn step(&mut self) -> bool {
+        self.admit(false);
+        self.run_step()
+    }
+
+    /// Serve requests until every handle has been dropped and all requests are finished, then give back the model
+    pub fn run(mut self) -> M {
+        // Only the handles keep the channel open now
+        drop(std::mem::replace(&mut self.sender, channel().0));
+        loop {
+            assert!(
+                !self.active.is_empty() || self.model.can_admit(),
+                "The model can't admit requests even though none are running"
+            );
+            if !self.admit(self.active.is_empty()) && self.active.is_empty() {
+                return self.model;
+            }
+            self.run_step();
+        }
+    }
+
+    /// Move waiting requests into the running batch, optionally blocking for the first one. Returns false once all
+    /// handles are dropped.
+    fn admit(&mut self, block: bool) -> bool {
+        let mut block = block;
+        while self.active.len() < self.max_batch_size && self.model.can_admit() {
+            let request = if block {
+                block = false;
+                match self.receiver.recv() {
+                    Ok(r) => r,
+                    Err(_) => return false,
+                }
+            } else {
+                match self.receiver.try_recv() {
+                    Ok(r) => r,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            };
+            self.active.push(ActiveRequest {
+                state: self.model.new_state(request.sampler),
+                pending: request.prompt,
+                remaining: request.max_new_tokens,
+                sender: request.sender,
+            });
+        }
+        true
+    }
+
+    fn run_step(&mut self) -> bool {
+        if self.active.is_empty() {
+            return false;
+        }
+        let mut batch = self
+            .active
+            .iter_mut()
+            .map(|r| (&mut r.state, r.pending.as_slice()))
+            .collect::<Vec<_>>();
+        let next = self.model.step(&mut batch);
+        assert_eq!(
+            next.len(),
+            self.active.len(),
+            "Model should return one token per request"
+        );
+        let mut finished = vec![];
+        for (i, (request, token)) in self.active.iter_mut().zip(next).enumerate() {
+            request.remaining -= 1;
+            request.pending = vec![token];
+            // A failed send means the receiver was dropped, so the request was cancelled
+            if request.sender.send(token).is_err()
+                || request.remaining == 0
+                || self.stop_tokens.contains(&token)
+            {
+                finished.push(i);
+            }
+        }
+        for i in finished.into_iter().rev() {
+            let request = self.active.remove(i);
+            self.model.free_state(request.state);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::prelude::*;
+
+    /// Each request keeps a running sum of its tokens, and the next token is that sum mod 7
+    struct SumModel {
+        // Boxed so the tensors' graph pointers stay valid when the model moves
+        cx: Box<Graph>,
+        inputs: GraphTensor<(Dyn<'b'>,)>,
+        sums: GraphTensor<(Dyn<'b'>,)>,
+        output: GraphTensor<(Dyn<'b'>,)>,
+        batch_sizes: Vec<usize>,
+    }
+
+    impl SumModel {
+        fn new() -> Self {
+            let mut cx = Box::new(Graph::new());
+            let inputs = cx.named_tensor("Inputs");
+            let sums = cx.named_tensor("Sums");
+            let output = ((inputs + sums) % 7.).retrieve();
+            Self {
+                cx,
+                inputs,
+                sums,
+                output,
+                batch_sizes: vec![],
+            }
+        }
+    }
+
+    impl BatchedModel for SumModel {
+        type State = u32;
+
+        fn new_state(&mut self, _: Option<Sample>) -> u32 {
+            0
+        }
+
+        fn step(&mut self, batch: &mut [(&mut u32, &[u32])]) -> Vec<u32> {
+            self.batch_sizes.push(batch.len());
+            let inputs = batch
+                .iter()
+                .map(|(_, t)| t.iter().sum::<u32>() as f32)
+                .collect::<Vec<_>>();
+            let sums = batch.iter().map(|(s, _)| **s as f32).collect::<Vec<_>>();
+            self.inputs.set_dyn(inputs, &[batch.len()]);
+            self.sums.set_dyn(sums, &[batch.len()]);
+            self.cx.execute();
+            let out = self.output.data();
+            self.output.drop();
+            batch
+                .iter_mut()
+                .zip(out)
+                .map(|((state, tokens), o)| {
+                    **state += tokens.iter().sum::<u32>();
+                    o as u32
+                })
+                .collect()
+        }
+    }
+
+    fn expected(prompt: &[u32], max_new_tokens: usize) -> Vec<u32> {
+        let mut sum = prompt.iter().sum::<u32>();
+        (0..max_new_tokens)
+            .map(|_| {
+                let t = sum % 7;
+                sum += t;
+                t
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_session_batches_requests() {
+        let session = InferenceSession::new(SumModel::new()).with_max_batch_size(3);
+        let prompts = [vec![1, 2], vec![3], vec![4, 5, 6], vec![2], vec![6, 6]];
+        let handles = prompts
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let (handle, prompt) = (session.handle(), p.clone());
+                thread::spawn(move || handle.generate(prompt, 3 + i))
+            })
+            .collect::<Vec<_>>();
+        let model = session.run();
+        for ((handle, prompt), i) in handles.into_iter().zip(&prompts).zip(0..) {
+            assert_eq!(handle.join().unwrap(), expected(prompt, 3 + i));
+        }
+        assert!(model.batch_sizes.iter().all(|b| *b <= 3));
+        assert_eq!(model.batch_sizes.iter().sum::<usize>(), 3 + 4 + 5 + 6 + 7);
+    }
+
+    #[test]
+    fn test_session_stop_and_cancel() {
+        let mut session = InferenceSession::new(SumModel::new()).with_stop_tokens([0]);
+        let handle = session.handle();
+        // 3 -> 3, 6, 5, 3, 6, ... never hits 0, 7 -> 0 stops immediately
+        let running = handle.generate_stream(vec![3], 100);
+        let stopped = handle.generate_stream(vec![7], 100);
+        let cancelled = handle.generate_stream(vec![1], 100);
+        assert!(session.step());
+        assert_eq!(session.active_requests(), 2);
+        assert_eq!(stopped.iter().collect::<Vec<_>>(), vec![0]);
+        drop(cancelled);
+        assert!(session.step());
+        assert_eq!(session.active_requests(), 1);
+        assert_eq!(running.try_iter().collect::<Vec<_>>(), vec![3, 6]);
+    }
+
+    /// Like `SumModel`, but keeping the sums in a fixed number of cache rows, with each request's state being its row
+    struct SlotModel {
+        slots: CacheSlots,
+        sums: Vec<u32>,
+        max_in_use: usize,
+    }
+
+    impl BatchedModel for SlotModel {
+        type State = usize;
+
+        fn new_state(&mut self, _: Option<Sample>) -> usize {
+            let slot = self.slots.acquire().unwrap();
+            self.sums[slot] = 0;
+            self.max_in_use = self.max_in_use.max(self.slots.in_use());
+            slot
+        }
+
+        fn can_admit(&self) -> bool {
+            self.slots.available() > 0
+        }
+
+        fn free_state(&mut self, slot: usize) {
+            self.slots.release(slot);
+        }
+
+        fn step(&mut self, batch: &mut [(&mut usize, &[u32])]) -> Vec<u32> {
+            batch
+                .iter()
+                .map(|(slot, tokens)| {
+                    self.sums[**slot] += tokens.iter().sum::<u32>();
+                    self.sums[**slot] % 7
+                })
+                .collect()
+        }
+    }
+
+    /// A compiled LM whose next token is the one closest to the sum of every token so far, so each request's output
+    /// depends on its whole cache
+    fn sum_lm(cx: &mut Graph) -> GraphLM<'_, (Const<8>,)> {
+        let input = cx.named_tensor::<(Const<1>, Dyn<'s'>)>("Input");
+        let cache_src = cx
+            .named_tensor::<(Const<1>, Dyn<'p'>)>("Cache")
+            .set_dyn(vec![], &[1, 0]);
+        let cache_dest = cache_src
+            .concat_along::<(Const<1>, Dyn<'t'>), Axis<1>, _>(input)
+            .keep();
+        let total = cache_dest.sum_reduce::<R0, _>();
+        let distance = cx.arange::<Const<8>>() - total.expand();
+        let logits = (distance * distance * -1.).retrieve();
+        GraphLM::new(cx, input, logits, cache_src, cache_dest)
+    }
+
+    #[test]
+    fn test_slotted_lm() {
+        let prompts = [vec![1], vec![0, 1], vec![3], vec![2, 2], vec![1, 0, 0]];
+        let mut cx = Graph::new();
+        // Requests without their own sampling settings always get token 7
+        let mut session = InferenceSession::new(SlottedLM::new(sum_lm(&mut cx), |_: &[f32]| 7, 2));
+        let handle = session.handle();
+        let streams = prompts
+            .iter()
+            .enumerate()
+            .map(|(i, p)| handle.generate_stream_with(p.clone(), 2 + i, Sample::new(0., 0, 1., 0)))
+            .collect::<Vec<_>>();
+        let default = handle.generate_stream(vec![1], 3);
+        while session.step() {}
+        assert_eq!(session.model().slots().available(), 2);
+        assert_eq!(default.iter().collect::<Vec<_>>(), [7, 7, 7]);
+
+        // Each request gets what the graph generates for its prompt alone
+        let mut reference_cx = Graph::new();
+        let mut reference = sum_lm(&mut reference_cx);
+        let mut greedy = Sample::new(0., 0, 1., 0);
+        for ((stream, prompt), i) in streams.into_iter().zip(&prompts).zip(0..) {
+            reference.reset();
+            let mut logits = reference.step(prompt);
+            let expected = (0..2 + i)
+                .map(|_| {
+                    let token = greedy.sample(&logits);
+                    logits = reference.step(&[token]);
+                    token
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(stream.iter().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_session_cache_slots() {
+        let mut session = InferenceSession::new(SlotModel {
+            slots: CacheSlots::new(2),
+            sums: vec![0; 2],
+            max_in_use: 0,
+        });
+        let handle = session.handle();
+        let prompts = [vec![1, 2], vec![3], vec![4, 5, 6], vec![2]];
+        let streams = prompts
+            .iter()
+            .enumerate()
+            .map(|(i, p)| handle.generate_stream(p.clone(), 2 + i))
+            .collect::<Vec<_>>();
+        // The first two requests take both slots, so the others wait for them to finish
+        assert!(session.step());
+        assert_eq!(session.active_requests(), 2);
+        while session.step() {}
+        for ((stream, prompt), i) in streams.into_iter().zip(&prompts).zip(0..) {
+            assert_eq!(stream.iter().collect::<Vec<_>>(), expected(prompt, 2 + i));
+        }
+        assert_eq!(session.model().max_in_use, 2);
+        assert_eq!(session.model().slots.available(), 2);
+    }
+}
@@ -84,3 +84,75 @@ fn test_batch_matmul() {
 
     assert_close(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_execute_with_dims() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'b'>, Dyn<'s'>)>();
+    let b = cx.tensor::<(Dyn<'s'>,)>();
+    let c = (a + b.expand::<(Dyn<'b'>, Dyn<'s'>), _>()).retrieve();
+    assert_eq!(
+        cx.dyn_symbols().into_iter().collect::<Vec<_>>(),
+        vec!['b', 's']
+    );
+
+    for (batch, seq) in [(2, 3), (1, 5)] {
+        a.set_dyn(vec![1.; batch * seq], &[batch, seq]);
+        b.set_dyn((0..seq).map(|i| i as f32).collect::<Vec<_>>(), &[seq]);
+        cx.execute_with_dims([('b', batch), ('s', seq)]);
+        let expected = (0..batch)
+            .flat_map(|_| (0..seq).map(|i| i as f32 + 1.))
+            .collect::<Vec<_>>();
+        assert_close(&c.data(), &expected);
+        c.drop();
+    }
+}
+
+#[test]
+#[should_panic(expected = "No size given for dynamic dimensions ['s']")]
+fn test_execute_with_missing_dims() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'b'>, Dyn<'s'>)>();
+    a.exp2().retrieve();
+    cx.execute_with_dims([('b', 2)]);
+}
+
+#[test]
+fn test_execute_with_mismatched_dims() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<(Dyn<'s'>,)>("A");
+    let b = (a * 2.).retrieve();
+    a.set_dyn(vec![1., 2., 3.], &[3]);
+
+    assert_eq!(
+        cx.try_execute_with_dims([('s', 3), ('x', 7)]),
+        Err(LuminalError::UnknownDimensions(vec!['x']))
+    );
+    assert_eq!(
+        cx.try_execute_with_dims([('s', 5)]),
+        Err(LuminalError::ShapeMismatch {
+            node: a.id,
+            name: "A Load".to_string(),
+            expected: 5,
+            found: 3
+        })
+    );
+    // Rejected sizes aren't kept
+    assert_eq!(cx.dyn_map[&'s'], 3);
+
+    cx.try_execute_with_dims([('s', 3)]).unwrap();
+    assert_close(&b.data(), &[2., 4., 6.]);
+
+    // Empty inputs fit a size of 0
+    a.set_dyn(vec![], &[0]);
+    cx.try_set_dyn_dims([('s', 0)]).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "The graph doesn't use dynamic dimensions ['x']")]
+fn test_execute_with_unknown_dims() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'s'>,)>().set_dyn(vec![1., 2., 3.], &[3]);
+    a.exp2().retrieve();
+    cx.execute_with_dims([('s', 3), ('x', 7)]);
+}
@@ -70,6 +70,341 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_memory_report() {
+    struct Model {
+        weight: GraphTensor<R1<4>>,
+        bias: GraphTensor<R1<4>>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.module("inner", &Inner(self.bias));
+        }
+    }
+    struct Inner(GraphTensor<R1<4>>);
+    impl SerializeModule for Inner {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("bias", self.0);
+        }
+    }
+
+    let mut cx = Graph::new();
+    let model = Model {
+        weight: cx.tensor().set(vec![1., 2., 3., 4.]).keep(),
+        bias: cx.tensor().set(vec![1., 2., 3., 4.]).keep(),
+    };
+    let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let b = ((a * model.weight).sin() + model.bias).retrieve();
+    cx.execute();
+
+    let report = cx.memory_report_with_modules(&model);
+    // Two weights and the output are left
+    assert_eq!(report.total(), 3 * 16);
+    assert_eq!(report.devices["CPU"], 3 * 16);
+    assert_eq!(report.tensors[&(b.id, 0)], 16);
+    assert_eq!(report.modules["weight"], 16);
+    assert_eq!(report.modules["inner"], 16);
+    assert_eq!(report.modules["inner/bias"], 16);
+    assert_eq!(
+        cx.attribute::<ModulePath>(model.bias.id),
+        Some(&ModulePath("inner/bias".to_string()))
+    );
+    // The input, product and sin are never all alive at the same time
+    assert_eq!(report.peak_intermediate, 2 * 16);
+}
+
+#[test]
+fn test_summary() {
+    struct Model {
+        weight: GraphTensor<R2<2, 3>>,
+        cache: GraphTensor<(Dyn<'s'>, Const<4>)>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.tensor("cache", self.cache);
+        }
+    }
+
+    let mut cx = Graph::new();
+    let model = Model {
+        weight: cx.tensor(),
+        cache: cx.tensor(),
+    };
+    let params = param_info(&model, &cx);
+    assert_eq!(
+        params.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+        ["cache", "weight"]
+    );
+    assert_eq!(params[1].id, model.weight.id);
+    assert_eq!(
+        params[1]
+            .shape
+            .iter()
+            .map(|d| d.to_usize())
+            .collect::<Vec<_>>(),
+        [Some(2), Some(3)]
+    );
+    assert_eq!(params[1].dtype, DType::F32);
+    assert_eq!(params[1].size_bytes(), Some(24));
+    // The dynamic dimension isn't known yet
+    assert_eq!(params[0].n_elements, None);
+    assert_eq!(summary(&model, &cx).total_params(), 6);
+
+    cx.set_dyn_dim('s', 5);
+    let summary = summary(&model, &cx);
+    assert_eq!(
+        summary.params[0]
+            .shape
+            .iter()
+            .map(|d| d.to_usize())
+            .collect::<Vec<_>>(),
+        [Some(5), Some(4)]
+    );
+    assert_eq!(summary.total_params(), 26);
+    assert_eq!(summary.total_bytes(), 104);
+    assert_eq!(
+        summary.to_string(),
+        "Name    Shape   DType  Params
+cache   [5, 4]  F32        20
+weight  [2, 3]  F32         6
+Total: 26 params (104 B)
+"
+    );
+}
+
+#[test]
+fn test_fallible_execution() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("A");
+    let b = cx.tensor::<(Dyn<'s'>,)>();
+    let c = (a * 2.).retrieve();
+    let d = (b + 1.).retrieve();
+
+    assert_eq!(
+        a.try_set(vec![1., 2.]).unwrap_err(),
+        LuminalError::ShapeMismatch {
+            node: a.id,
+            name: "A Load".to_string(),
+            expected: 3,
+            found: 2
+        }
+    );
+    assert_eq!(
+        b.try_set(vec![1., 2.]).unwrap_err(),
+        LuminalError::MissingDimensions(vec!['s'])
+    );
+    assert_eq!(
+        cx.try_execute(),
+        Err(LuminalError::MissingDimensions(vec!['s']))
+    );
+
+    cx.set_dyn_dim('s', 2);
+    b.try_set(vec![1., 2.]).unwrap();
+    assert_eq!(
+        cx.try_execute(),
+        Err(LuminalError::MissingData {
+            node: a.id,
+            name: "A Load".to_string()
+        })
+    );
+
+    a.try_set(vec![1., 2., 3.]).unwrap();
+    cx.try_execute().unwrap();
+    assert_exact(&c.data(), &[2., 4., 6.]);
+    assert_exact(&d.data(), &[2., 3.]);
+
+    struct Failing;
+    impl Compiler for Failing {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+            panic!("No pass for this graph");
+        }
+    }
+    assert_eq!(
+        cx.try_compile(Failing, ()),
+        Err(LuminalError::CompileFailed(
+            "No pass for this graph".to_string()
+        ))
+    );
+}
+
+#[test]
+fn test_telemetry() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let b = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+    let mut c = (a * b).sin().retrieve();
+    // Nothing is counted until telemetry is turned on
+    cx.execute();
+    assert!(cx.telemetry().ops.is_empty());
+    cx.set_telemetry(true);
+    c.drop();
+    cx.execute();
+    c.drop();
+    cx.execute();
+
+    let telemetry = cx.telemetry();
+    assert_eq!(
+        telemetry.ops["Mul"],
+        OpCounters {
+            launches: 2,
+            bytes_read: 2 * 32,
+            bytes_written: 2 * 16,
+        }
+    );
+    assert_eq!(telemetry.ops["Function"].launches, 4);
+    assert_eq!(telemetry.total().launches, 8);
+
+    // Counters survive compilation replacing nodes
+    c.drop();
+    cx.compile(GenericCompiler::default(), &mut c);
+    cx.execute();
+    assert_eq!(cx.telemetry().ops["Sin"].launches, 3);
+
+    // Kernel lookups count towards the graph being compiled, not other graphs
+    #[derive(Debug)]
+    struct LookUpKernels;
+    impl Compiler for LookUpKernels {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+            record_kernel_cache_lookup(true);
+            record_kernel_cache_lookup(false);
+        }
+    }
+    record_kernel_cache_lookup(true);
+    let mut other = Graph::new();
+    other.compile(LookUpKernels, ());
+    cx.compile(LookUpKernels, ());
+    cx.compile(LookUpKernels, ());
+    let telemetry = cx.telemetry();
+    assert_eq!(telemetry.kernel_cache_hits, 2);
+    assert_eq!(telemetry.kernel_cache_hit_rate(), Some(0.5));
+    assert_eq!(other.telemetry().kernel_cache_hits, 1);
+
+    cx.reset_telemetry();
+    let telemetry = cx.telemetry();
+    assert!(telemetry.ops.is_empty());
+    assert_eq!(telemetry.kernel_cache_hit_rate(), None);
+}
+
+#[test]
+fn test_set_external() {
+    let mut cx = Graph::new();
+    let weight_data: &'static [f32] = Vec::leak(vec![1., 2., 3., 4.]);
+    let w = cx
+        .tensor::<R1<3>>()
+        .set_borrowed(&weight_data[..3])
+        .retrieve();
+    let mut frame = [0.5, 1.5, 2.5];
+    let a = unsafe {
+        cx.tensor::<R1<3>>()
+            .set_external(frame.as_ptr(), frame.len())
+    };
+    let b = (w * a).retrieve();
+    // Writing to the cache copies it instead of touching the user's buffer
+    let cache = cx
+        .tensor::<R1<4>>()
+        .set_borrowed(weight_data)
+        .append_at::<Axis<0>, _>(a.slice((..Expression::from(1),)).realize::<R1<1>>(), 3)
+        .retrieve();
+    cx.execute();
+    assert_exact(&b.data(), &[0.5, 3., 7.5]);
+    assert_exact(&cache.data(), &[1., 2., 3., 0.5]);
+    assert_exact(weight_data, &[1., 2., 3., 4.]);
+    // Borrowed data is read in place
+    assert_eq!(w.data_ref().unwrap().as_ptr(), weight_data.as_ptr());
+
+    // New values are picked up without setting the input again
+    frame.copy_from_slice(&[1., 1., 1.]);
+    b.drop();
+    cache.drop();
+    cx.execute();
+    assert_exact(&b.data(), &[1., 2., 3.]);
+    assert_exact(&cache.data(), &[1., 2., 3., 1.]);
+}
+
+#[test]
+fn test_take_data() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+    let b = (a * 2.).retrieve();
+    let c = b.permute::<_, Axes2<1, 0>>().retrieve();
+    cx.execute();
+
+    let ptr = b.data_ref().unwrap().as_ptr();
+    assert!(c.data_ref().is_none());
+    let mut out = [0.; 6];
+    c.data_into(&mut out);
+    assert_exact(&out, &[2., 8., 4., 10., 6., 12.]);
+    b.data_into(&mut out);
+    assert_exact(&out, &[2., 4., 6., 8., 10., 12.]);
+
+    // Taking the output moves the buffer out of the graph
+    let taken = b.take_data();
+    assert_eq!(taken.as_ptr(), ptr);
+    assert_exact(&taken, &[2., 4., 6., 8., 10., 12.]);
+    assert!(cx.get_tensor_ref(b.id, 0).is_none());
+}
+
+#[test]
+fn test_execute_parallel() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<64, 2048>>().set(random_vec(64 * 2048));
+    let b = cx.tensor::<R2<64, 2048>>().set(random_vec(64 * 2048));
+    // Independent branches, with reductions big enough to be split across threads
+    let mut outputs = (
+        a.sum_reduce::<_, Axis<1>>().retrieve(),
+        b.max_reduce::<_, Axis<0>>().retrieve(),
+        (a * b).sum_reduce::<_, Axis<0>>().retrieve(),
+        (a.exp2() + b.sin()).max_reduce::<_, Axis<1>>().retrieve(),
+    );
+    cx.compile(GenericCompiler::default(), &mut outputs);
+
+    cx.execute();
+    let serial = outputs
+        .to_ids()
+        .into_iter()
+        .map(|id| cx.get_tensor(id, 0).unwrap())
+        .collect::<Vec<_>>();
+    let serial_peak = cx.memory_report().peak_intermediate;
+    cx.execute_parallel();
+    for (id, serial) in outputs.to_ids().into_iter().zip(serial) {
+        assert_exact(
+            cx.get_tensor_ref(id, 0)
+                .unwrap()
+                .downcast_ref::<Vec<f32>>()
+                .unwrap(),
+            serial.downcast_ref::<Vec<f32>>().unwrap(),
+        );
+    }
+    assert!(cx.memory_report().peak_intermediate > 0);
+    assert!(cx.memory_report().peak_intermediate <= 2 * serial_peak);
+}
+
+#[test]
+fn test_to_dot() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<(Dyn<'s'>, Const<4>)>("In \"a\"");
+    let b = cx.tensor::<R1<4>>().keep();
+    let mut c = (a.exp2() + b.expand()).retrieve();
+    cx.set_dyn_dim('s', 3);
+    cx.compile(GenericCompiler::default(), &mut c);
+
+    let dot = cx.to_dot();
+    assert!(dot.starts_with("digraph {") && dot.ends_with("}\n"));
+    assert_eq!(
+        dot.lines().filter(|l| l.contains("[label=")).count(),
+        cx.graph.node_count() + cx.graph.edge_count()
+    );
+    // Dyn dims are annotated with their current value, and quotes are escaped
+    assert!(dot.contains("[s (=3), 4]"));
+    assert!(dot.contains("In \\\"a\\\""));
+    assert!(dot.contains(&format!("{} [label=", c.id.index())) && dot.contains("fillcolor=yellow"));
+    assert!(dot.contains("fillcolor=lightgrey"));
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_close_precision(a_vec, b_vec, 1e-3);
@@ -112,6 +447,13 @@ pub fn random_vec_rng<R: Rng>(n: usize, rng: &mut R) -> Vec<f32> {
     (0..n).map(|_| rng.gen_range(-0.5..0.5)).collect()
 }
 
+/// A directory under the system temp dir for a test to write files into, unique to the test name and process
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("luminal_{name}_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
 #[macro_export]
 macro_rules! test_imports {
     () => {
@@ -133,6 +475,7 @@ macro_rules! test_imports {
                 // harness::{test_compilers_close, test_compilers_exact},
                 random_vec,
                 random_vec_rng,
+                temp_dir,
                 test_graphs,
             },
         };
@@ -1,4 +1,7 @@
-use crate::{prelude::*, tests::assert_close};
+use crate::{
+    prelude::*,
+    tests::{assert_close, assert_exact},
+};
 use dfdx::prelude::*;
 use itertools::Itertools;
 
@@ -259,6 +262,31 @@ fn test_mod() {
     );
 }
 
+#[test]
+fn test_where() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set([1., 5., -2., 3.]);
+    let b = cx.tensor::<R1<4>>().set([2., 5., -3., 0.]);
+    let lt = a.lt_mask(b).retrieve();
+    let gt = a.gt_mask(b).retrieve();
+    let le = a.le_mask(b).retrieve();
+    let ge = a.ge_mask(b).retrieve();
+    let eq = a.eq_mask(b).retrieve();
+    let ne = a.ne_mask(b).retrieve();
+    let c = a.gt_mask(b).where_(a, b * 10.).retrieve();
+    cx.execute();
+
+    assert_eq!(lt.dtype(), crate::op::DType::Bool);
+    assert_eq!(c.dtype(), crate::op::DType::F32);
+    assert_exact(&lt.data(), &[1., 0., 0., 0.]);
+    assert_exact(&gt.data(), &[0., 0., 1., 1.]);
+    assert_exact(&le.data(), &[1., 1., 0., 0.]);
+    assert_exact(&ge.data(), &[0., 1., 1., 1.]);
+    assert_exact(&eq.data(), &[0., 1., 0., 0.]);
+    assert_exact(&ne.data(), &[1., 0., 1., 1.]);
+    assert_exact(&c.data(), &[20., 50., -2., 3.]);
+}
+
 // Reduction op tests
 
 #[test]
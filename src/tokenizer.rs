@@ -0,0 +1,252 @@
+//! A thin wrapper over HF `tokenizers` for encoding prompts and decoding streamed tokens. Enabled with the
+//! `tokenizers` feature.
+//!
+//! SentencePiece models (like llama 2's) work through their `tokenizer.json` export, with byte-fallback tokens
+//! handled by the tokenizer's own decoder.
+
+use std::path::Path;
+
+use tokenizers::Result;
+
+use crate::{
+    chat::{ChatMessage, ChatTemplate},
+    generate::TokenDecoder,
+    serialization::gguf,
+};
+
+/// Encodes text into token ids and decodes them back, adding the BOS token and recognising EOS tokens the way the
+/// model expects.
+#[derive(Clone)]
+pub struct Tokenizer {
+    inner: tokenizers::Tokenizer,
+    bos_token: Option<u32>,
+    add_bos: bool,
+    eos_tokens: Vec<u32>,
+}
+
+impl Tokenizer {
+    /// Load a `tokenizer.json` file. No BOS or EOS tokens are set up, see `with_gguf_config` or the `with_*` methods.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(tokenizers::Tokenizer::from_file(path)?))
+    }
+
+    /// Load a tokenizer from the contents of a `tokenizer.json` file
+    pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self> {
+        Ok(Self::new(tokenizers::Tokenizer::from_bytes(bytes)?))
+    }
+
+    pub fn new(inner: tokenizers::Tokenizer) -> Self {
+        Self {
+            inner,
+            bos_token: None,
+            add_bos: false,
+            eos_tokens: vec![],
+        }
+    }
+
+    /// Set the BOS and EOS tokens from a GGUF file's tokenizer metadata. The BOS token is added when encoding unless
+    /// the file says otherwise.
+    pub fn with_gguf_config(mut self, content: &gguf::Content) -> Self {
+        let get = |key: &str| content.metadata.get(&format!("tokenizer.ggml.{key}"));
+        self.bos_token = get("bos_token_id")
+            .and_then(gguf::Value::to_u64)
+            .map(|t| t as u32);
+        self.add_bos = self.bos_token.is_some()
+            && get("add_bos_token")
+                .and_then(gguf::Value::as_bool)
+                .unwrap_or(true);
+        self.eos_tokens = get("eos_token_id")
+            .and_then(gguf::Value::to_u64)
+            .map(|t| vec![t as u32])
+            .unwrap_or_default();
+        self
+    }
+
+    /// Add this BOS token to the start of every encoded prompt
+    pub fn with_bos_token(mut self, bos_token: u32) -> Self {
+        self.bos_token = Some(bos_token);
+        self.add_bos = true;
+        self
+    }
+
+    /// Tokens that end generation, see `is_eos`
+    pub fn with_eos_tokens(mut self, eos_tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.eos_tokens = eos_tokens.into_iter().collect();
+        self
+    }
+
+    pub fn bos_token(&self) -> Option<u32> {
+        self.bos_token
+    }
+
+    pub fn eos_tokens(&self) -> &[u32] {
+        &self.eos_tokens
+    }
+
+    pub fn is_eos(&self, token: u32) -> bool {
+        self.eos_tokens.contains(&token)
+    }
+
+    /// The underlying HF tokenizer
+    pub fn inner(&self) -> &tokenizers::Tokenizer {
+        &self.inner
+    }
+
+    /// Encode a prompt, adding the BOS token if the model expects one. Special tokens written in the text, like
+    /// `<|eot_id|>`, are encoded as their single token.
+    pub fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        let mut ids = self.inner.encode(text, false)?.get_ids().to_vec();
+        if let (true, Some(bos)) = (self.add_bos, self.bos_token) {
+            if ids.first() != Some(&bos) {
+                ids.insert(0, bos);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Render a conversation with a chat template and encode it, ready for the model to write the assistant's reply
+    pub fn apply_chat_template(
+        &self,
+        template: ChatTemplate,
+        messages: &[ChatMessage],
+    ) -> Result<Vec<u32>> {
+        self.encode(&template.render(messages, true))
+    }
+
+    /// The token ending each message of a chat template, if it's a single token. Add it to the stop tokens when
+    /// generating chat replies.
+    pub fn end_of_turn_token(&self, template: ChatTemplate) -> Option<u32> {
+        self.inner.token_to_id(template.end_of_turn())
+    }
+
+    /// Decode tokens into text, optionally leaving out special tokens like BOS and EOS
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String> {
+        self.inner.decode(ids, skip_special_tokens)
+    }
+
+    /// Start decoding a stream of generated tokens
+    pub fn decode_stream(&self, skip_special_tokens: bool) -> DecodeStream<'_> {
+        DecodeStream {
+            tokenizer: self,
+            skip_special_tokens,
+            ids: vec![],
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+}
+
+/// Incrementally decodes tokens as they're generated, see `Tokenizer::decode_stream`.
+///
+/// Tokens are decoded together with the few before them, since how a token decodes can depend on its neighbours
+/// (like a leading space being stripped). Text is held back while it ends in a partial UTF-8 character, which happens
+/// when a character is split over several byte-fallback tokens.
+pub struct DecodeStream<'a> {
+    tokenizer: &'a Tokenizer,
+    skip_special_tokens: bool,
+    ids: Vec<u32>,
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl DecodeStream<'_> {
+    /// Add a token, getting back the new text it completes, if any
+    pub fn push(&mut self, token: u32) -> Result<Option<String>> {
+        self.ids.push(token);
+        let decode = |ids| self.tokenizer.decode(ids, self.skip_special_tokens);
+        let prefix = decode(&self.ids[self.prefix_offset..self.read_offset])?;
+        let text = decode(&self.ids[self.prefix_offset..])?;
+        if text.len() <= prefix.len() || text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+        let new_text = text[prefix.len()..].to_string();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        Ok(Some(new_text))
+    }
+
+    /// All tokens pushed so far
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+}
+
+// Text that fails to decode is skipped
+impl TokenDecoder for DecodeStream<'_> {
+    fn push(&mut self, token: u32) -> Option<String> {
+        DecodeStream::push(self, token).ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A llama style SentencePiece tokenizer with byte fallback
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            {"id": 0, "content": "<unk>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+            {"id": 1, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+            {"id": 2, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+        ],
+        "normalizer": {"type": "Sequence", "normalizers": [
+            {"type": "Prepend", "prepend": "▁"},
+            {"type": "Replace", "pattern": {"String": " "}, "content": "▁"}
+        ]},
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {"type": "Sequence", "decoders": [
+            {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+            {"type": "ByteFallback"},
+            {"type": "Fuse"},
+            {"type": "Strip", "content": " ", "start": 1, "stop": 0}
+        ]},
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": "<unk>",
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": true,
+            "byte_fallback": true,
+            "vocab": {"<unk>": 0, "<s>": 1, "</s>": 2, "<0xE2>": 3, "<0x82>": 4, "<0xAC>": 5, "▁": 6, "h": 7, "i": 8, "▁h": 9, "▁hi": 10},
+            "merges": ["▁ h", "▁h i"]
+        }
+    }"#;
+
+    #[test]
+    fn test_tokenizer() {
+        let tokenizer = Tokenizer::from_bytes(TOKENIZER)
+            .unwrap()
+            .with_bos_token(1)
+            .with_eos_tokens([2]);
+        let ids = tokenizer.encode("hi €").unwrap();
+        // The euro sign isn't in the vocab, so it falls back to its 3 UTF-8 bytes
+        assert_eq!(ids, vec![1, 10, 6, 3, 4, 5]);
+        assert_eq!(tokenizer.decode(&ids, true).unwrap(), "hi €");
+        assert!(tokenizer.is_eos(2) && !tokenizer.is_eos(1));
+
+        // Streamed text is only emitted once the byte-fallback character is complete
+        let mut stream = tokenizer.decode_stream(true);
+        let chunks = ids
+            .iter()
+            .chain(&[2])
+            .map(|t| stream.push(*t).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                None,
+                Some("hi".to_string()),
+                Some(" ".to_string()),
+                None,
+                None,
+                Some("€".to_string()),
+                None
+            ]
+        );
+    }
+}